@@ -0,0 +1,105 @@
+/* Kapibara Transport C Bindings
+ *
+ * Built from the `ffi` feature of kapibara-transport. Calls block the
+ * calling thread on a runtime shared by the whole process, created on
+ * first use. Handles are generation checked, a closed or freed handle is
+ * rejected with KAPIBARA_ERR_INVALID_HANDLE instead of being reused.
+ *
+ * Every call returns KAPIBARA_OK (0) or a negative error code, read and
+ * write return the byte count on success. The message of the last failed
+ * call on a thread is available from kapibara_last_error.
+ */
+#ifndef KAPIBARA_H
+#define KAPIBARA_H
+
+#include <stddef.h>
+#include <stdint.h>
+#include <sys/types.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+#define KAPIBARA_OK 0
+
+/* binding errors */
+#define KAPIBARA_ERR_INVALID_ARGUMENT -1
+#define KAPIBARA_ERR_INVALID_HANDLE -2
+#define KAPIBARA_ERR_PANIC -3
+#define KAPIBARA_ERR_TIMEOUT -4
+#define KAPIBARA_ERR_RUNTIME -5
+
+/* transport errors, one per kapibara error code */
+#define KAPIBARA_ERR_CONNECT_REFUSED -100
+#define KAPIBARA_ERR_CONNECT_RESET -101
+#define KAPIBARA_ERR_CONNECT_ABORTED -102
+#define KAPIBARA_ERR_CONNECT_TIMEOUT -103
+#define KAPIBARA_ERR_CONNECT_ADDR_UNAVAILABLE -104
+#define KAPIBARA_ERR_CONNECT_FAILED -105
+#define KAPIBARA_ERR_CONNECT_DENIED -106
+#define KAPIBARA_ERR_CONNECT_CIRCUIT_OPEN -107
+#define KAPIBARA_ERR_IO_CLOSED -200
+#define KAPIBARA_ERR_IO_ADDR_IN_USE -201
+#define KAPIBARA_ERR_IO_PERMISSION_DENIED -202
+#define KAPIBARA_ERR_IO_NOT_FOUND -203
+#define KAPIBARA_ERR_IO -299
+#define KAPIBARA_ERR_DNS_EMPTY -300
+#define KAPIBARA_ERR_DNS_TIMEOUT -301
+#define KAPIBARA_ERR_DNS_NO_RECORDS -302
+#define KAPIBARA_ERR_DNS_INIT -303
+#define KAPIBARA_ERR_DNS_PROTOCOL -304
+#define KAPIBARA_ERR_DNS -399
+#define KAPIBARA_ERR_TLS_CERT_INVALID -400
+#define KAPIBARA_ERR_TLS_CERT_UNKNOWN_ISSUER -401
+#define KAPIBARA_ERR_TLS_CERT_NAME_MISMATCH -402
+#define KAPIBARA_ERR_TLS_CERT_EXPIRED -403
+#define KAPIBARA_ERR_TLS_KEY_INVALID -404
+#define KAPIBARA_ERR_TLS_TICKET_KEY_INVALID -405
+#define KAPIBARA_ERR_TLS_ALERT -406
+#define KAPIBARA_ERR_TLS_NO_ALPN -407
+#define KAPIBARA_ERR_TLS_PROTOCOL -408
+#define KAPIBARA_ERR_TLS_NOT_TLS -409
+#define KAPIBARA_ERR_TLS_INSECURE_DISABLED -410
+#define KAPIBARA_ERR_TLS_CONFIG_INVALID -411
+#define KAPIBARA_ERR_TLS -499
+#define KAPIBARA_ERR_WS_HANDSHAKE_STATUS -500
+#define KAPIBARA_ERR_WS_PROTOCOL -501
+#define KAPIBARA_ERR_WS_CAPACITY -502
+#define KAPIBARA_ERR_WS_INVALID_UTF8 -503
+#define KAPIBARA_ERR_WS -599
+#define KAPIBARA_ERR_OPTION_INVALID -600
+#define KAPIBARA_ERR_OPTION_SECRET -601
+#define KAPIBARA_ERR_SERVE_FAILED -700
+
+typedef uint64_t kapibara_client_t;
+typedef uint64_t kapibara_stream_t;
+
+/* Init a client from a TransportClientOption in json. */
+int32_t kapibara_client_new(const char *config_json, kapibara_client_t *out_handle);
+
+/* Free a client, streams it opened stay usable. */
+int32_t kapibara_client_free(kapibara_client_t client);
+
+/* Open a stream, blocking until connected or failed. */
+int32_t kapibara_client_connect(kapibara_client_t client, kapibara_stream_t *out_stream);
+
+/* Read up to len bytes, 0 on end of stream. A timeout_ms of 0 waits forever. */
+ssize_t kapibara_stream_read(kapibara_stream_t stream, uint8_t *buf, size_t len,
+                             uint32_t timeout_ms);
+
+/* Write up to len bytes and flush them, returns the bytes written. */
+ssize_t kapibara_stream_write(kapibara_stream_t stream, const uint8_t *buf, size_t len,
+                              uint32_t timeout_ms);
+
+/* Shut the stream down and free its handle. */
+int32_t kapibara_stream_close(kapibara_stream_t stream);
+
+/* Code of the last failed call on this thread, 0 if none. Its message is
+ * copied into buf, truncated to len - 1 bytes and nul terminated. */
+int32_t kapibara_last_error(char *buf, size_t len);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* KAPIBARA_H */
@@ -0,0 +1,49 @@
+//! Chaos Client
+
+use std::sync::Mutex;
+
+use crate::{ClientError, ClientResult, TransportClientTrait};
+
+use super::{ChaosOption, ChaosRng, ChaosStream};
+
+pub struct ChaosClient<T> {
+    inner: T,
+    opt: ChaosOption,
+    rng: Mutex<ChaosRng>,
+}
+
+impl<T> ChaosClient<T> {
+    pub fn new(inner: T, opt: ChaosOption) -> Self {
+        let rng = Mutex::new(ChaosRng::new(opt.seed));
+        Self { inner, opt, rng }
+    }
+
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    /// Decide the fate of the next connect and the seed of its stream.
+    fn next_attempt(&self) -> (bool, u64) {
+        let mut rng = self.rng.lock().unwrap();
+        (rng.chance(self.opt.connect_failure), rng.next_u64())
+    }
+}
+
+impl<T: TransportClientTrait> TransportClientTrait for ChaosClient<T> {
+    type Stream = ChaosStream<T::Stream>;
+
+    async fn connect(&self) -> ClientResult<Self::Stream> {
+        let (fail, seed) = self.next_attempt();
+
+        if !self.opt.connect_latency.is_zero() {
+            tokio::time::sleep(self.opt.connect_latency).await;
+        }
+
+        if fail {
+            return Err(ClientError::Connect("chaos connect failure".to_owned()));
+        }
+
+        let stream = self.inner.connect().await?;
+        Ok(ChaosStream::new(stream, self.opt.clone(), seed))
+    }
+}
@@ -0,0 +1,150 @@
+//! Chaos Transport
+//!
+//! Fault injecting wrappers for resilience testing.
+
+pub mod option;
+pub use option::ChaosOption;
+
+pub mod client;
+pub use client::ChaosClient;
+
+pub mod stream;
+pub use stream::ChaosStream;
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Small seedable generator (splitmix64), reproducible across platforms.
+#[derive(Debug, Clone)]
+pub struct ChaosRng(u64);
+
+impl ChaosRng {
+    pub fn new(seed: Option<u64>) -> Self {
+        Self(seed.unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_nanos() as u64)
+        }))
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    /// Uniform value in `0.0..1.0`.
+    pub fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    pub fn chance(&mut self, probability: f64) -> bool {
+        probability > 0.0 && self.next_f64() < probability
+    }
+
+    /// Uniform value in `0..=max`.
+    pub fn below(&mut self, max: u64) -> u64 {
+        if max == 0 {
+            0
+        } else {
+            self.next_u64() % (max + 1)
+        }
+    }
+
+    pub fn jitter(&mut self, max: Duration) -> Duration {
+        max.mul_f64(self.next_f64())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        time::Instant,
+    };
+
+    use crate::{empty::EmptyClient, TransportClientTrait};
+
+    use super::*;
+
+    #[test]
+    fn test_chaos_option_deserialize() {
+        let opt: ChaosOption = serde_json::from_str(
+            r#"{"seed": 7, "connect_failure": 0.5, "reset_after": 1024, "rate_limit": 4096}"#,
+        )
+        .unwrap();
+        assert_eq!(opt.seed, Some(7));
+        assert_eq!(opt.reset_after, Some(1024));
+        assert_eq!(opt.rate_limit, Some(4096));
+        assert!(opt.io_latency.is_zero());
+    }
+
+    #[tokio::test]
+    async fn test_chaos_connect_reproducible() {
+        let opt = ChaosOption {
+            seed: Some(42),
+            connect_failure: 0.5,
+            ..Default::default()
+        };
+
+        let mut runs = vec![];
+        for _ in 0..2 {
+            let cli = ChaosClient::new(EmptyClient, opt.clone());
+            let mut outcome = vec![];
+            for _ in 0..32 {
+                outcome.push(cli.connect().await.is_ok());
+            }
+            runs.push(outcome);
+        }
+
+        assert_eq!(runs[0], runs[1]);
+        assert!(runs[0].contains(&true) && runs[0].contains(&false));
+    }
+
+    #[tokio::test]
+    async fn test_chaos_reset_after() {
+        let (a, mut b) = tokio::io::duplex(64 * 1024);
+        let opt = ChaosOption {
+            reset_after: Some(10_000),
+            ..Default::default()
+        };
+        let mut stream = ChaosStream::new(a, opt, 1);
+
+        let mut written = 0;
+        let err = loop {
+            match stream.write(&[0u8; 1024]).await {
+                Ok(n) => written += n,
+                Err(err) => break err,
+            }
+        };
+        assert_eq!(err.kind(), std::io::ErrorKind::ConnectionReset);
+        assert_eq!(written, 10_000);
+
+        let mut buf = vec![0u8; 10_000];
+        b.read_exact(&mut buf).await.unwrap();
+        assert!(stream.read(&mut buf).await.is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_chaos_rate_limit() {
+        let (a, mut b) = tokio::io::duplex(64 * 1024);
+        let opt = ChaosOption {
+            rate_limit: Some(20 * 1024),
+            ..Default::default()
+        };
+        let mut stream = ChaosStream::new(a, opt, 1);
+
+        let start = Instant::now();
+        for _ in 0..5 {
+            stream.write_all(&[0u8; 2048]).await.unwrap();
+        }
+        // the debt of the last write is only paid by the next one
+        assert!(start.elapsed() >= Duration::from_millis(350));
+
+        let mut buf = vec![0u8; 5 * 2048];
+        b.read_exact(&mut buf).await.unwrap();
+    }
+}
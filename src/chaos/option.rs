@@ -0,0 +1,28 @@
+//! Chaos Fault Profile
+
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, rename_all = "snake_case")]
+pub struct ChaosOption {
+    /// Seed of the fault generator, random if unset.
+    pub seed: Option<u64>,
+    /// Probability in `0.0..=1.0` that a connect attempt fails.
+    pub connect_failure: f64,
+    pub connect_latency: Duration,
+    /// Latency added before every read and write.
+    pub io_latency: Duration,
+    /// Upper bound of the random latency added on top of `io_latency`.
+    pub io_jitter: Duration,
+    /// Reset the stream after this many bytes (read and write combined).
+    pub reset_after: Option<u64>,
+    /// Upper bound of the random byte count added on top of `reset_after`.
+    pub reset_jitter: u64,
+    /// Throttle each direction to this many bytes per second.
+    pub rate_limit: Option<u64>,
+    /// Probability in `0.0..=1.0` that an operation stalls for `stall_duration`.
+    pub stall_probability: f64,
+    pub stall_duration: Duration,
+}
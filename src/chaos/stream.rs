@@ -0,0 +1,183 @@
+//! Chaos Stream
+
+use std::{
+    future::Future,
+    io::{Error, ErrorKind},
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use futures_util::ready;
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    time::{sleep, Sleep},
+};
+
+use super::{ChaosOption, ChaosRng};
+
+pub struct ChaosStream<S> {
+    inner: S,
+    opt: ChaosOption,
+    rng: ChaosRng,
+    reset_at: Option<u64>,
+    transferred: u64,
+    read: Gate,
+    write: Gate,
+}
+
+/// Delay paid by one direction before its next operation.
+#[derive(Default)]
+struct Gate {
+    sleep: Option<Pin<Box<Sleep>>>,
+    open: bool,
+    debt: Duration,
+}
+
+impl<S> ChaosStream<S> {
+    pub fn new(inner: S, opt: ChaosOption, seed: u64) -> Self {
+        let mut rng = ChaosRng::new(Some(seed));
+        let reset_at = opt.reset_after.map(|n| n + rng.below(opt.reset_jitter));
+
+        Self {
+            inner,
+            opt,
+            rng,
+            reset_at,
+            transferred: 0,
+            read: Gate::default(),
+            write: Gate::default(),
+        }
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+
+    /// Bytes both directions may still move before the injected reset.
+    fn budget(&self) -> Option<u64> {
+        self.reset_at
+            .map(|reset_at| reset_at.saturating_sub(self.transferred))
+    }
+
+    fn check_reset(&self) -> std::io::Result<()> {
+        if self.budget() == Some(0) {
+            Err(Error::new(ErrorKind::ConnectionReset, "chaos reset"))
+        } else {
+            Ok(())
+        }
+    }
+
+    fn account(&mut self, n: usize, is_read: bool) {
+        self.transferred += n as u64;
+
+        let gate = if is_read {
+            &mut self.read
+        } else {
+            &mut self.write
+        };
+        gate.open = false;
+        if let Some(rate) = self.opt.rate_limit.filter(|rate| *rate > 0) {
+            gate.debt = Duration::from_secs_f64(n as f64 / rate as f64);
+        }
+    }
+}
+
+impl Gate {
+    fn poll_open(
+        &mut self,
+        opt: &ChaosOption,
+        rng: &mut ChaosRng,
+        cx: &mut Context<'_>,
+    ) -> Poll<()> {
+        if self.open {
+            return Poll::Ready(());
+        }
+
+        if self.sleep.is_none() {
+            let mut delay =
+                opt.io_latency + rng.jitter(opt.io_jitter) + std::mem::take(&mut self.debt);
+            if rng.chance(opt.stall_probability) {
+                delay += opt.stall_duration;
+            }
+
+            if delay.is_zero() {
+                self.open = true;
+                return Poll::Ready(());
+            }
+            self.sleep = Some(Box::pin(sleep(delay)));
+        }
+
+        if let Some(sleep) = self.sleep.as_mut() {
+            ready!(sleep.as_mut().poll(cx));
+        }
+        self.sleep = None;
+        self.open = true;
+        Poll::Ready(())
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for ChaosStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        this.check_reset()?;
+        ready!(this.read.poll_open(&this.opt, &mut this.rng, cx));
+
+        let n = match this.budget() {
+            Some(budget) if (budget as usize) < buf.remaining() => {
+                let mut tmp = vec![0u8; budget as usize];
+                let mut tmp_buf = ReadBuf::new(&mut tmp);
+                ready!(Pin::new(&mut this.inner).poll_read(cx, &mut tmp_buf))?;
+                buf.put_slice(tmp_buf.filled());
+                tmp_buf.filled().len()
+            }
+            _ => {
+                let filled = buf.filled().len();
+                ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+                buf.filled().len() - filled
+            }
+        };
+
+        this.account(n, true);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for ChaosStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        this.check_reset()?;
+        ready!(this.write.poll_open(&this.opt, &mut this.rng, cx));
+
+        let len = match this.budget() {
+            Some(budget) => std::cmp::min(budget as usize, buf.len()),
+            None => buf.len(),
+        };
+        let n = ready!(Pin::new(&mut this.inner).poll_write(cx, &buf[..len]))?;
+
+        this.account(n, false);
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        this.check_reset()?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
@@ -1,14 +1,40 @@
 //! Transport client
 
+use std::sync::Arc;
+
+use rustls::pki_types::CertificateDer;
+use tokio::net::TcpStream as TokioTcpStream;
+
+#[cfg(any(test, feature = "test-util"))]
+use crate::memory::{MemoryClient, MemoryStream};
+#[cfg(unix)]
+use crate::unix::{UnixClient, UnixStream};
 use crate::{
-    empty::{EmptyClient, EmptyStream},
+    empty::{
+        BlackholeClient, BlackholeStream, EmptyClient, EmptyStream, GeneratorClient,
+        GeneratorStream,
+    },
+    grpc::{GrpcClient, GrpcStream},
+    http2::{H2Client, H2Stream},
+    io::{FairOptions, FairStream, Framed, FramedOptions},
+    net::{proxy::dial_target, BreakerSnapshot, CircuitBreaker},
     option::ClientOption,
+    pool::{PoolOption, PooledClient},
+    reload::AppliedChanges,
+    retry::{self, RetryOption},
+    stats::CountedStream,
     stream_traits_enum,
     tcp::{TcpClient, TcpStream},
-    websocket::{WebSocketClient, WebSocketClientStream},
-    ClientResult, Resolver, TransportClientOption, TransportClientTrait,
+    tls::TlsInfo,
+    udp::{UdpClient, UdpStream},
+    websocket::{ConnectParams, WebSocketClient, WebSocketClientStream},
+    ClientError, ClientResult, ResolveError, Resolver, TlsError, TransportClientOption,
+    TransportClientTrait,
 };
 
+/// Lookups in flight while pre-resolving in `TransportClient::init_many`.
+const INIT_MANY_CONCURRENCY: usize = 16;
+
 macro_rules! transport_client_enum {
     {
         $(#[$meta:meta])*
@@ -32,6 +58,7 @@ macro_rules! transport_client_enum {
             pub fn name(&self) -> &str {
                 match self {
                     $(
+                        $(#[$item_meta])*
                         $name::$id(_) => stringify!($id),
                     )+
                 }
@@ -45,6 +72,7 @@ macro_rules! transport_client_enum {
             async fn connect(&self) -> ClientResult<Self::Stream> {
                 match self {
                     $(
+                        $(#[$item_meta])*
                         $name::$id(cli) => Ok(cli.connect().await?.into()),
                     )+
                 }
@@ -52,6 +80,7 @@ macro_rules! transport_client_enum {
         }
 
         $(
+            $(#[$item_meta])*
             impl From<$id_ty> for $name {
                 fn from(s: $id_ty) -> $name {
                     $name::$id(s)
@@ -66,13 +95,118 @@ stream_traits_enum! {
         Empty(EmptyStream),
         Tcp(TcpStream),
         Ws(WebSocketClientStream),
+        Udp(UdpStream),
+        H2(H2Stream),
+        Grpc(GrpcStream),
+        Blackhole(BlackholeStream),
+        Generator(GeneratorStream),
+        #[cfg(any(test, feature = "test-util"))]
+        Memory(MemoryStream),
+        #[cfg(unix)]
+        Unix(UnixStream),
+    }
+}
+
+impl std::fmt::Debug for TransportClientStream {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let inner: &dyn std::fmt::Debug = match self {
+            Self::Empty(s) => s,
+            Self::Tcp(s) => s,
+            Self::Ws(s) => s,
+            Self::Udp(s) => s,
+            Self::H2(s) => s,
+            Self::Grpc(s) => s,
+            Self::Blackhole(s) => s,
+            Self::Generator(s) => s,
+            #[cfg(any(test, feature = "test-util"))]
+            Self::Memory(s) => s,
+            #[cfg(unix)]
+            Self::Unix(s) => s,
+        };
+        f.debug_tuple(self.kind()).field(inner).finish()
     }
 }
 
 impl TransportClientStream {
+    /// Length delimited frames over this stream, see [`Framed`].
+    pub fn framed(self, opt: FramedOptions) -> Framed<Self> {
+        Framed::new(self, opt)
+    }
+
+    /// Share a task with other streams fairly, see [`FairStream`].
+    pub fn fair(self, opt: FairOptions) -> FairStream<Self> {
+        FairStream::new(self, opt)
+    }
+
+    /// Count the bytes moved from here on, see [`CountedStream`].
+    pub fn counted(self) -> CountedStream<Self> {
+        CountedStream::new(self)
+    }
+
     pub fn is_emtpy(&self) -> bool {
         matches!(self, Self::Empty(_))
     }
+
+    /// Negotiated alpn protocol.
+    pub fn alpn_protocol(&self) -> Option<&[u8]> {
+        match self {
+            Self::Tcp(s) => s.alpn_protocol(),
+            #[cfg(unix)]
+            Self::Unix(s) => s.alpn_protocol(),
+            _ => None,
+        }
+    }
+
+    /// Tls session info, see `TcpStream::tls_info`, `None` off tcp.
+    pub fn tls_info(&self) -> Option<TlsInfo> {
+        match self {
+            Self::Tcp(s) => s.tls_info(),
+            _ => None,
+        }
+    }
+
+    /// Whether the tls session was resumed, always `false` off tcp.
+    pub fn is_resumed(&self) -> bool {
+        match self {
+            Self::Tcp(s) => s.is_resumed(),
+            _ => false,
+        }
+    }
+
+    /// Certificate chain presented by the server, leaf first.
+    pub fn peer_certificates(&self) -> Option<Vec<CertificateDer<'static>>> {
+        match self {
+            Self::Tcp(s) => s.peer_certificates(),
+            _ => None,
+        }
+    }
+
+    /// Sha256 fingerprint of the server's leaf certificate.
+    pub fn peer_cert_sha256(&self) -> Option<[u8; 32]> {
+        match self {
+            Self::Tcp(s) => s.peer_cert_sha256(),
+            _ => None,
+        }
+    }
+
+    /// Export keying material (RFC 5705), `None` if the tls session is not reachable.
+    pub fn export_keying_material(
+        &self,
+        label: &[u8],
+        context: Option<&[u8]>,
+        output_len: usize,
+    ) -> Option<Result<Vec<u8>, TlsError>> {
+        match self {
+            Self::Tcp(s) if s.is_tls() => {
+                Some(s.export_keying_material(label, context, output_len))
+            }
+            #[cfg(unix)]
+            Self::Unix(s) if s.is_tls() => {
+                Some(s.export_keying_material(label, context, output_len))
+            }
+            _ => None,
+        }
+    }
 }
 
 transport_client_enum! {
@@ -80,17 +214,293 @@ transport_client_enum! {
         Empty(EmptyClient),
         Tcp(TcpClient),
         Ws(WebSocketClient),
+        Udp(UdpClient),
+        H2(H2Client),
+        Grpc(GrpcClient),
+        Blackhole(BlackholeClient),
+        Generator(GeneratorClient),
+        #[cfg(any(test, feature = "test-util"))]
+        Memory(MemoryClient),
+        #[cfg(unix)]
+        Unix(UnixClient),
     }
 }
 
 impl TransportClient {
-    pub fn init(trans_opt: TransportClientOption, resolver: &Resolver) -> ClientResult<Self> {
+    /// Init with the resolver of `trans_opt.dns` or the default resolver.
+    pub fn init_with_default_resolver(trans_opt: TransportClientOption) -> ClientResult<Self> {
+        let resolver = trans_opt.resolver();
+        Self::init(trans_opt, &resolver)
+    }
+
+    pub fn init(mut trans_opt: TransportClientOption, resolver: &Resolver) -> ClientResult<Self> {
+        trans_opt.apply_latency_profile();
+        trans_opt.apply_tls_cache();
+        trans_opt.ensure_valid()?;
+        trans_opt.enforce_policy()?;
+        let source = trans_opt.clone();
+        let probe = trans_opt.post_connect_probe;
+        let profile = trans_opt.latency_profile;
+        let breaker = trans_opt
+            .breaker
+            .map(|opt| Arc::new(CircuitBreaker::new(opt)));
         match trans_opt.opt {
             ClientOption::Empty => Ok(EmptyClient.into()),
-            ClientOption::Tcp(opt) => Ok(TcpClient::init(opt, trans_opt.tls, resolver)?.into()),
+            ClientOption::Tcp(opt) => {
+                let mut cli = TcpClient::init(opt, trans_opt.tls, resolver)?;
+                cli.set_post_connect_probe(probe)?;
+                cli.set_dial_hooks(trans_opt.hooks);
+                cli.set_breaker(breaker);
+                cli.set_latency_profile(profile);
+                cli.set_source(source);
+                Ok(cli.into())
+            }
             ClientOption::Ws(opt) => {
-                Ok(WebSocketClient::init(opt, trans_opt.tls, resolver)?.into())
+                let mut cli = WebSocketClient::init(opt, trans_opt.tls, resolver)?;
+                cli.set_post_connect_probe(probe);
+                cli.set_dial_hooks(trans_opt.hooks);
+                cli.set_breaker(breaker);
+                cli.set_latency_profile(profile);
+                cli.set_source(source);
+                Ok(cli.into())
             }
+            ClientOption::Udp(opt) => {
+                let mut cli = UdpClient::init(opt, trans_opt.tls, resolver)?;
+                cli.set_post_connect_probe(probe)?;
+                Ok(cli.into())
+            }
+            ClientOption::H2(opt) => {
+                let mut cli = H2Client::init(opt, trans_opt.tls, resolver)?;
+                cli.set_post_connect_probe(probe)?;
+                cli.set_latency_profile(profile);
+                Ok(cli.into())
+            }
+            ClientOption::Grpc(opt) => {
+                let mut cli = GrpcClient::init(opt, trans_opt.tls, resolver)?;
+                cli.set_post_connect_probe(probe)?;
+                cli.set_latency_profile(profile);
+                Ok(cli.into())
+            }
+            ClientOption::Blackhole(opt) => Ok(BlackholeClient::new(opt).into()),
+            ClientOption::Generator(opt) => Ok(GeneratorClient::new(opt).into()),
+            #[cfg(any(test, feature = "test-util"))]
+            ClientOption::Memory(opt) => Ok(MemoryClient::new(opt).into()),
+            #[cfg(unix)]
+            ClientOption::Unix(opt) => Ok(UnixClient::init(opt, trans_opt.tls)?.into()),
+        }
+    }
+
+    /// Init a client keeping its streams for reuse, see [`PooledClient`].
+    pub fn pooled(
+        trans_opt: TransportClientOption,
+        pool_opt: PoolOption,
+        resolver: &Resolver,
+    ) -> ClientResult<PooledClient<Self>> {
+        Ok(PooledClient::new(
+            Self::init(trans_opt, resolver)?,
+            pool_opt,
+        ))
+    }
+
+    /// Connect, retrying transient failures with the backoff of `retry`,
+    /// usually the `retry` of the option the client was built from.
+    pub async fn connect_with_retry(
+        &self,
+        retry: &RetryOption,
+    ) -> ClientResult<TransportClientStream> {
+        retry::connect_with_retry(self, retry).await
+    }
+
+    /// Layer the transport over a connected `stream` instead of dialing,
+    /// only tcp and ws clients run over a given stream.
+    pub async fn connect_over(
+        &self,
+        stream: TokioTcpStream,
+    ) -> ClientResult<TransportClientStream> {
+        match self {
+            Self::Tcp(cli) => Ok(cli.connect_over(stream).await?.into()),
+            Self::Ws(cli) => Ok(cli
+                .connect_over(stream, &ConnectParams::default())
+                .await?
+                .into()),
+            _ => Err(ClientError::Option(format!(
+                "{} client does not connect over a stream",
+                self.name()
+            ))),
         }
     }
+
+    /// The circuit breakers of the client, see `TransportClientOption::breaker`.
+    /// Empty before the first connect and for transports other than tcp and ws.
+    pub fn breaker_state(&self) -> Vec<BreakerSnapshot> {
+        match self {
+            Self::Tcp(cli) => cli.breaker_state(),
+            Self::Ws(cli) => cli.breaker_state(),
+            _ => vec![],
+        }
+    }
+
+    /// Apply `new_opt` to the running client without disturbing open streams.
+    ///
+    /// The new option is built in full first, resolving with its `dns`, and
+    /// an error leaves the client untouched. Tcp and ws clients take every
+    /// change from the next connect on, a different transport kind requires
+    /// a restart. The other transports do not apply options live.
+    pub fn apply(&self, new_opt: TransportClientOption) -> ClientResult<AppliedChanges> {
+        let next = Self::init_with_default_resolver(new_opt)?;
+        match (self, next) {
+            (Self::Tcp(cli), Self::Tcp(next)) => Ok(cli.apply(next)),
+            (Self::Ws(cli), Self::Ws(next)) => Ok(cli.apply(next)),
+            (cli, next) if cli.name() != next.name() => Ok(AppliedChanges::kind_changed()),
+            (cli, _) => Err(ClientError::Option(format!(
+                "{} client does not apply options live",
+                cli.name()
+            ))),
+        }
+    }
+
+    /// Init many clients, resolving all their hosts in one batch first.
+    pub fn init_many(
+        trans_opts: Vec<TransportClientOption>,
+        resolver: &Resolver,
+    ) -> Vec<ClientResult<Self>> {
+        // the index of its option stays with each query, an option left out
+        // of the batch does not shift the results of the ones after it
+        let (indices, queries): (Vec<_>, Vec<_>) = trans_opts
+            .iter()
+            .enumerate()
+            .filter_map(|(i, trans_opt)| match trans_opt.opt {
+                ClientOption::Empty | ClientOption::Blackhole(_) | ClientOption::Generator(_) => {
+                    None
+                }
+                #[cfg(any(test, feature = "test-util"))]
+                ClientOption::Memory(_) => None,
+                #[cfg(unix)]
+                ClientOption::Unix(_) => None,
+                // resolved on connect, not part of the batch
+                ClientOption::Tcp(ref opt) if opt.resolve_on_connect => None,
+                ClientOption::Ws(ref opt) if opt.resolve_on_connect => None,
+                ClientOption::Tcp(ref opt) => {
+                    let (host, port) = dial_target(opt.proxy.as_ref(), &opt.addr, opt.port);
+                    Some((i, (host.to_owned(), port)))
+                }
+                ClientOption::Ws(ref opt) => {
+                    let (host, port) = dial_target(opt.proxy.as_ref(), &opt.addr, opt.port);
+                    Some((i, (host.to_owned(), port)))
+                }
+                ClientOption::Udp(ref opt) => Some((i, (opt.addr.clone(), opt.port))),
+                ClientOption::H2(ref opt) => Some((i, (opt.addr.clone(), opt.port))),
+                ClientOption::Grpc(ref opt) => Some((i, (opt.addr.clone(), opt.port))),
+            })
+            .filter(|(_, (host, _))| !host.is_empty())
+            .unzip();
+
+        let mut resolved = match resolver.block_resolve_many(&queries, INIT_MANY_CONCURRENCY) {
+            Ok(results) => {
+                let mut resolved = trans_opts.iter().map(|_| None).collect::<Vec<_>>();
+                for (i, result) in indices.into_iter().zip(results) {
+                    resolved[i] = Some(result);
+                }
+                resolved
+            }
+            Err(e) => {
+                let msg = e.to_string();
+                return trans_opts
+                    .iter()
+                    .map(|_| Err(ResolveError::Initialize(msg.clone()).into()))
+                    .collect();
+            }
+        };
+
+        trans_opts
+            .into_iter()
+            .map(|mut trans_opt| {
+                trans_opt.apply_latency_profile();
+                trans_opt.apply_tls_cache();
+                let policy = trans_opt
+                    .ensure_valid()
+                    .and_then(|_| trans_opt.enforce_policy());
+                (
+                    policy,
+                    trans_opt.latency_profile,
+                    trans_opt.clone(),
+                    trans_opt,
+                )
+            })
+            .enumerate()
+            .map(|(i, (policy, profile, source, trans_opt))| {
+                policy?;
+                let breaker = trans_opt
+                    .breaker
+                    .map(|opt| Arc::new(CircuitBreaker::new(opt)));
+                match trans_opt.opt {
+                    ClientOption::Empty => Ok(EmptyClient.into()),
+                    ClientOption::Tcp(opt) => {
+                        let mut cli = if opt.resolve_on_connect {
+                            TcpClient::init(opt, trans_opt.tls, resolver)?
+                        } else {
+                            let addrs = resolved[i]
+                                .take()
+                                .unwrap_or(Err(ResolveError::EmptyResolved))?;
+                            TcpClient::with_addrs(opt, trans_opt.tls, addrs)?
+                        };
+                        cli.set_post_connect_probe(trans_opt.post_connect_probe)?;
+                        cli.set_dial_hooks(trans_opt.hooks);
+                        cli.set_breaker(breaker);
+                        cli.set_latency_profile(profile);
+                        cli.set_source(source);
+                        Ok(cli.into())
+                    }
+                    ClientOption::Ws(opt) => {
+                        let mut cli = if opt.resolve_on_connect {
+                            WebSocketClient::init(opt, trans_opt.tls, resolver)?
+                        } else {
+                            let addrs = resolved[i]
+                                .take()
+                                .unwrap_or(Err(ResolveError::EmptyResolved))?;
+                            WebSocketClient::with_addrs(opt, trans_opt.tls, addrs)?
+                        };
+                        cli.set_post_connect_probe(trans_opt.post_connect_probe);
+                        cli.set_dial_hooks(trans_opt.hooks);
+                        cli.set_breaker(breaker);
+                        cli.set_latency_profile(profile);
+                        cli.set_source(source);
+                        Ok(cli.into())
+                    }
+                    ClientOption::Udp(opt) => {
+                        let addrs = resolved[i]
+                            .take()
+                            .unwrap_or(Err(ResolveError::EmptyResolved))?;
+                        let mut cli = UdpClient::with_addrs(opt, trans_opt.tls, addrs)?;
+                        cli.set_post_connect_probe(trans_opt.post_connect_probe)?;
+                        Ok(cli.into())
+                    }
+                    ClientOption::H2(opt) => {
+                        let addrs = resolved[i]
+                            .take()
+                            .unwrap_or(Err(ResolveError::EmptyResolved))?;
+                        let mut cli = H2Client::with_addrs(opt, trans_opt.tls, addrs)?;
+                        cli.set_post_connect_probe(trans_opt.post_connect_probe)?;
+                        cli.set_latency_profile(profile);
+                        Ok(cli.into())
+                    }
+                    ClientOption::Grpc(opt) => {
+                        let addrs = resolved[i]
+                            .take()
+                            .unwrap_or(Err(ResolveError::EmptyResolved))?;
+                        let mut cli = GrpcClient::with_addrs(opt, trans_opt.tls, addrs)?;
+                        cli.set_post_connect_probe(trans_opt.post_connect_probe)?;
+                        cli.set_latency_profile(profile);
+                        Ok(cli.into())
+                    }
+                    ClientOption::Blackhole(opt) => Ok(BlackholeClient::new(opt).into()),
+                    ClientOption::Generator(opt) => Ok(GeneratorClient::new(opt).into()),
+                    #[cfg(any(test, feature = "test-util"))]
+                    ClientOption::Memory(opt) => Ok(MemoryClient::new(opt).into()),
+                    #[cfg(unix)]
+                    ClientOption::Unix(opt) => Ok(UnixClient::init(opt, trans_opt.tls)?.into()),
+                }
+            })
+            .collect()
+    }
 }
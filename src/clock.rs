@@ -0,0 +1,258 @@
+//! Time Source
+//!
+//! Timers of the crate read the time and sleep through a [`Clock`]: the
+//! connect deadlines of the `Dialer`, the ws and mux keepalives, the idle
+//! `Reaper`, the buckets of the rate limiter and the backoff of `retry`.
+//!
+//! [`TokioClock`], the default everywhere, is tokio's time and follows
+//! `tokio::time::pause` and `advance` on a current thread runtime. It is a
+//! zero sized type and its calls inline to the tokio ones. [`ManualClock`]
+//! only moves when told to, for tests on a multi thread runtime or with
+//! tasks that must not see the auto advance of a paused runtime.
+
+use std::{future::Future, pin::Pin, time::Duration};
+
+use futures_util::FutureExt;
+use tokio::time::{Instant, Sleep};
+
+/// Reads the time and makes timers, see the module docs.
+pub trait Clock: std::fmt::Debug + Clone + Default + Send + Sync + Unpin + 'static {
+    type Sleep: Future<Output = ()> + Send + Sync;
+
+    fn now(&self) -> Instant;
+
+    fn sleep_until(&self, deadline: Instant) -> Self::Sleep;
+
+    /// Move a sleep to `deadline`, reusing it.
+    fn reset(&self, sleep: Pin<&mut Self::Sleep>, deadline: Instant);
+
+    fn sleep(&self, duration: Duration) -> Self::Sleep {
+        self.sleep_until(self.now() + duration)
+    }
+
+    /// Run `future` for at most `duration`, `None` once it elapsed first.
+    fn timeout<F>(
+        &self,
+        duration: Duration,
+        future: F,
+    ) -> impl Future<Output = Option<F::Output>> + Send
+    where
+        F: Future + Send,
+    {
+        let sleep = self.sleep(duration);
+        async move {
+            tokio::select! {
+                biased;
+                out = future => Some(out),
+                _ = sleep => None,
+            }
+        }
+    }
+}
+
+/// Tokio's time, paused and advanced with `tokio::time::pause` and `advance`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TokioClock;
+
+impl Clock for TokioClock {
+    type Sleep = Sleep;
+
+    #[inline]
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    #[inline]
+    fn sleep_until(&self, deadline: Instant) -> Sleep {
+        tokio::time::sleep_until(deadline)
+    }
+
+    #[inline]
+    fn reset(&self, sleep: Pin<&mut Sleep>, deadline: Instant) {
+        sleep.reset(deadline)
+    }
+
+    #[inline]
+    fn sleep(&self, duration: Duration) -> Sleep {
+        tokio::time::sleep(duration)
+    }
+
+    #[inline]
+    fn timeout<F>(
+        &self,
+        duration: Duration,
+        future: F,
+    ) -> impl Future<Output = Option<F::Output>> + Send
+    where
+        F: Future + Send,
+    {
+        tokio::time::timeout(duration, future).map(Result::ok)
+    }
+}
+
+#[cfg(any(test, feature = "test-util"))]
+pub use manual::{ManualClock, ManualSleep};
+
+#[cfg(any(test, feature = "test-util"))]
+mod manual {
+    use std::{
+        future::Future,
+        pin::Pin,
+        sync::{Arc, Mutex, MutexGuard},
+        task::{Context, Poll, Waker},
+        time::Duration,
+    };
+
+    use tokio::time::Instant;
+
+    use super::Clock;
+
+    /// A clock standing still until `advance`, shared by its clones.
+    #[derive(Debug, Clone, Default)]
+    pub struct ManualClock {
+        state: Arc<Mutex<State>>,
+    }
+
+    #[derive(Debug)]
+    struct State {
+        now: Instant,
+        /// Tasks sleeping on the clock, woken on every advance.
+        sleepers: Vec<Waker>,
+    }
+
+    impl Default for State {
+        fn default() -> Self {
+            Self {
+                now: Instant::now(),
+                sleepers: vec![],
+            }
+        }
+    }
+
+    impl ManualClock {
+        pub fn new() -> Self {
+            Self::default()
+        }
+
+        /// Move the time forward, waking the sleeps that are due.
+        pub fn advance(&self, duration: Duration) {
+            let sleepers = {
+                let mut state = self.lock();
+                state.now += duration;
+                std::mem::take(&mut state.sleepers)
+            };
+            // the ones not due yet register again
+            for waker in sleepers {
+                waker.wake();
+            }
+        }
+
+        fn lock(&self) -> MutexGuard<'_, State> {
+            self.state.lock().unwrap_or_else(|e| e.into_inner())
+        }
+    }
+
+    /// Sleep of a [`ManualClock`].
+    #[derive(Debug)]
+    pub struct ManualSleep {
+        clock: ManualClock,
+        deadline: Instant,
+    }
+
+    impl Future for ManualSleep {
+        type Output = ();
+
+        fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+            let mut state = self.clock.lock();
+            if state.now >= self.deadline {
+                return Poll::Ready(());
+            }
+            if !state.sleepers.iter().any(|w| w.will_wake(cx.waker())) {
+                state.sleepers.push(cx.waker().clone());
+            }
+            Poll::Pending
+        }
+    }
+
+    impl Clock for ManualClock {
+        type Sleep = ManualSleep;
+
+        fn now(&self) -> Instant {
+            self.lock().now
+        }
+
+        fn sleep_until(&self, deadline: Instant) -> ManualSleep {
+            ManualSleep {
+                clock: self.clone(),
+                deadline,
+            }
+        }
+
+        fn reset(&self, mut sleep: Pin<&mut ManualSleep>, deadline: Instant) {
+            sleep.deadline = deadline;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    };
+
+    use super::*;
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_manual_clock() {
+        let clock = ManualClock::new();
+        let start = clock.now();
+        let done = Arc::new(AtomicBool::new(false));
+        let sleeper = tokio::spawn({
+            let (clock, done) = (clock.clone(), done.clone());
+            async move {
+                clock.sleep(Duration::from_secs(10)).await;
+                done.store(true, Ordering::SeqCst);
+            }
+        });
+
+        // real time does not move it
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert_eq!(clock.now(), start);
+        assert!(!done.load(Ordering::SeqCst));
+
+        clock.advance(Duration::from_secs(5));
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(!done.load(Ordering::SeqCst));
+
+        clock.advance(Duration::from_secs(5));
+        sleeper.await.unwrap();
+        assert!(done.load(Ordering::SeqCst));
+        assert_eq!(clock.now() - start, Duration::from_secs(10));
+
+        let out = clock.timeout(Duration::from_secs(1), async { 7 }).await;
+        assert_eq!(out, Some(7));
+        let timeout = tokio::spawn({
+            let clock = clock.clone();
+            async move {
+                clock
+                    .timeout(Duration::from_secs(1), std::future::pending::<()>())
+                    .await
+            }
+        });
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(timeout.await.unwrap(), None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_tokio_clock_paused() {
+        let clock = TokioClock;
+        let start = clock.now();
+        let out = clock
+            .timeout(Duration::from_secs(60), std::future::pending::<()>())
+            .await;
+        assert_eq!(out, None);
+        assert_eq!(clock.now() - start, Duration::from_secs(60));
+    }
+}
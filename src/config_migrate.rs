@@ -0,0 +1,314 @@
+//! Config Schema Migration
+//!
+//! Stored configs carry the schema `version` they were written for. The
+//! migrators rewrite an older config one release at a time up to
+//! `CONFIG_VERSION`, noting every rewrite. Renamed fields stay readable by
+//! serde for one version as a grace period, the migrators are the long
+//! term path.
+
+use std::fmt;
+
+use serde::Serialize;
+use serde_json::{Map, Value};
+use thiserror::Error;
+
+use crate::{
+    dns::option::Strategy, option::CONFIG_VERSION, TransportClientOption, TransportServerOption,
+};
+
+#[derive(Debug, Error)]
+pub enum MigrateError {
+    #[error("unknown config version {0}, the current one is {CONFIG_VERSION}")]
+    UnknownVersion(u32),
+    #[error("config says version {found}, migration asked from {from}")]
+    VersionMismatch { from: u32, found: u64 },
+    #[error("invalid config at {path} ({message})")]
+    Invalid { path: String, message: String },
+    #[error("migrated config does not parse ({0})")]
+    Parse(#[from] serde_json::Error),
+}
+
+/// One rewrite done by a migration step.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct MigrationNote {
+    /// Version the step migrates to.
+    pub version: u32,
+    /// Dotted path of the rewritten field.
+    pub path: String,
+    pub message: String,
+}
+
+impl fmt::Display for MigrationNote {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "v{} {}: {}", self.version, self.path, self.message)
+    }
+}
+
+type StepFn = fn(&mut Map<String, Value>, &mut Notes) -> Result<(), MigrateError>;
+
+/// Rewrites a config of version `to - 1` into version `to`.
+struct Step {
+    to: u32,
+    client: StepFn,
+    server: StepFn,
+}
+
+/// Every schema change, oldest first. A release changing the schema bumps
+/// `CONFIG_VERSION` and appends its step.
+const STEPS: &[Step] = &[Step {
+    to: 2,
+    client: v2_split_dns_strategy,
+    server: unchanged,
+}];
+
+/// Notes of the step running now.
+struct Notes {
+    version: u32,
+    notes: Vec<MigrationNote>,
+}
+
+impl Notes {
+    fn push(&mut self, path: &str, message: String) {
+        self.notes.push(MigrationNote {
+            version: self.version,
+            path: path.into(),
+            message,
+        });
+    }
+}
+
+/// Migrate a client config written for `from_version` to the current schema.
+pub fn migrate_client_option(
+    value: Value,
+    from_version: u32,
+) -> Result<(TransportClientOption, Vec<MigrationNote>), MigrateError> {
+    let (value, notes) = migrate(value, from_version, |step| step.client)?;
+    Ok((serde_json::from_value(value)?, notes))
+}
+
+/// Migrate a server config written for `from_version` to the current schema.
+pub fn migrate_server_option(
+    value: Value,
+    from_version: u32,
+) -> Result<(TransportServerOption, Vec<MigrationNote>), MigrateError> {
+    let (value, notes) = migrate(value, from_version, |step| step.server)?;
+    Ok((serde_json::from_value(value)?, notes))
+}
+
+fn migrate(
+    mut value: Value,
+    from_version: u32,
+    step_fn: impl Fn(&Step) -> StepFn,
+) -> Result<(Value, Vec<MigrationNote>), MigrateError> {
+    if from_version == 0 || from_version > CONFIG_VERSION {
+        return Err(MigrateError::UnknownVersion(from_version));
+    }
+    let Value::Object(config) = &mut value else {
+        return Err(invalid("", "not an object"));
+    };
+    // a config naming its own version has to agree with the caller
+    if let Some(found) = config.get("version") {
+        let found = found
+            .as_u64()
+            .ok_or_else(|| invalid("version", "not an integer"))?;
+        if found != from_version as u64 {
+            return Err(MigrateError::VersionMismatch {
+                from: from_version,
+                found,
+            });
+        }
+    }
+
+    let mut notes = Notes {
+        version: from_version,
+        notes: vec![],
+    };
+    for step in STEPS.iter().filter(|step| step.to > from_version) {
+        notes.version = step.to;
+        step_fn(step)(config, &mut notes)?;
+    }
+    config.insert("version".into(), CONFIG_VERSION.into());
+    Ok((value, notes.notes))
+}
+
+fn invalid(path: &str, message: impl Into<String>) -> MigrateError {
+    MigrateError::Invalid {
+        path: path.into(),
+        message: message.into(),
+    }
+}
+
+fn unchanged(_: &mut Map<String, Value>, _: &mut Notes) -> Result<(), MigrateError> {
+    Ok(())
+}
+
+/// v2 split the dns `strategy` into `query` and `order`.
+fn v2_split_dns_strategy(
+    config: &mut Map<String, Value>,
+    notes: &mut Notes,
+) -> Result<(), MigrateError> {
+    const PATH: &str = "dns.strategy";
+
+    let Some(Value::Object(dns)) = config.get_mut("dns") else {
+        return Ok(());
+    };
+    let Some(strategy) = dns.remove("strategy") else {
+        return Ok(());
+    };
+    let name = strategy.as_str().unwrap_or_default().to_owned();
+    let strategy: Strategy =
+        serde_json::from_value(strategy).map_err(|e| invalid(PATH, e.to_string()))?;
+
+    // fields already set win, as the serde fallback does
+    let (query, order) = strategy.split();
+    let query = dns
+        .entry("query")
+        .or_insert(serde_json::to_value(query)?)
+        .clone();
+    let order = dns
+        .entry("order")
+        .or_insert(serde_json::to_value(order)?)
+        .clone();
+    notes.push(
+        PATH,
+        format!(
+            "strategy {} split into query={} order={}",
+            name,
+            query.as_str().unwrap_or_default(),
+            order.as_str().unwrap_or_default()
+        ),
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::{
+        dns::option::{OrderStrategy, QueryStrategy},
+        option::{ClientOption, ServerOption},
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_migrate_v1_clients() {
+        let configs = [
+            json!({}),
+            json!({
+                "opt": {"tcp": {"addr": "example.com", "port": 443}},
+                "tls": {"server_name": "example.com"},
+                "dns": {"strategy": "ipv6_then_ipv4", "timeout": {"secs": 2, "nanos": 0}},
+            }),
+            json!({
+                "opt": {"ws": {"addr": "example.com", "port": 80, "path": "/tunnel"}},
+                "dns": {"strategy": "ipv4_only", "order": "interleave"},
+            }),
+            json!({"opt": {"blackhole": {"rate": 1024}}}),
+            json!({"opt": {"generator": {"chunk_size": 512}}, "dns": {"servers": []}}),
+        ];
+        let migrated = configs
+            .into_iter()
+            .map(|config| migrate_client_option(config, 1).unwrap())
+            .collect::<Vec<_>>();
+
+        let (opt, notes) = &migrated[0];
+        assert!(matches!(opt.opt, ClientOption::Empty));
+        assert!(notes.is_empty());
+
+        let (opt, notes) = &migrated[1];
+        let ClientOption::Tcp(ref tcp) = opt.opt else {
+            panic!("{:?}", opt.opt)
+        };
+        assert_eq!((tcp.addr.as_str(), tcp.port), ("example.com", 443));
+        let dns = opt.dns.as_ref().unwrap();
+        assert_eq!(dns.query, QueryStrategy::Both);
+        assert_eq!(dns.order, OrderStrategy::V6First);
+        assert_eq!(dns.timeout.as_secs(), 2);
+        assert_eq!(
+            notes.iter().map(|n| n.to_string()).collect::<Vec<_>>(),
+            ["v2 dns.strategy: strategy ipv6_then_ipv4 split into query=both order=v6_first"]
+        );
+
+        // an order set next to the strategy is kept
+        let (opt, notes) = &migrated[2];
+        let ClientOption::Ws(ref ws) = opt.opt else {
+            panic!("{:?}", opt.opt)
+        };
+        assert_eq!(ws.path, "/tunnel");
+        let dns = opt.dns.as_ref().unwrap();
+        assert_eq!(dns.query, QueryStrategy::V4Only);
+        assert_eq!(dns.order, OrderStrategy::Interleave);
+        assert_eq!(
+            notes[0].message,
+            "strategy ipv4_only split into query=v4_only order=interleave"
+        );
+
+        let (opt, notes) = &migrated[3];
+        assert!(matches!(opt.opt, ClientOption::Blackhole(ref b) if b.rate == Some(1024)));
+        assert!(notes.is_empty());
+        let (opt, notes) = &migrated[4];
+        assert!(matches!(opt.opt, ClientOption::Generator(ref g) if g.chunk_size == 512));
+        assert!(notes.is_empty());
+
+        for (opt, _) in &migrated {
+            assert_eq!(opt.version, CONFIG_VERSION);
+            assert_eq!(
+                serde_json::to_value(opt).unwrap()["version"],
+                CONFIG_VERSION
+            );
+        }
+    }
+
+    #[test]
+    fn test_migrate_v1_servers() {
+        let configs = [
+            json!({"opt": {"tcp": {"listen": "127.0.0.1:443", "tls_mode": "optional"}}}),
+            json!({"opt": {"ws": {"listen": "127.0.0.1:80", "path": "/tunnel"}}}),
+            json!({"opt": {"generator": {"connections": 4}}}),
+        ];
+        let migrated = configs
+            .into_iter()
+            .map(|config| migrate_server_option(config, 1).unwrap())
+            .collect::<Vec<_>>();
+        assert!(matches!(migrated[0].0.opt, ServerOption::Tcp(ref t) if t.listen.port() == 443));
+        assert!(matches!(migrated[1].0.opt, ServerOption::Ws(ref w) if w.path == "/tunnel"));
+        assert!(matches!(migrated[2].0.opt, ServerOption::Generator(ref g) if g.connections == 4));
+        for (opt, notes) in &migrated {
+            assert_eq!(opt.version, CONFIG_VERSION);
+            assert!(notes.is_empty());
+        }
+    }
+
+    #[test]
+    fn test_migrate_versions() {
+        // the field defaults to 1 when absent, new configs carry the current one
+        let opt: TransportClientOption = serde_json::from_value(json!({})).unwrap();
+        assert_eq!(opt.version, 1);
+        assert_eq!(TransportClientOption::default().version, CONFIG_VERSION);
+
+        let current = json!({"version": CONFIG_VERSION, "dns": {"query": "v6_only"}});
+        let (opt, notes) = migrate_client_option(current, CONFIG_VERSION).unwrap();
+        assert_eq!(opt.dns.unwrap().query, QueryStrategy::V6Only);
+        assert!(notes.is_empty());
+
+        let err = migrate_client_option(json!({}), CONFIG_VERSION + 1).unwrap_err();
+        assert!(matches!(err, MigrateError::UnknownVersion(_)));
+        assert!(matches!(
+            migrate_client_option(json!({}), 0),
+            Err(MigrateError::UnknownVersion(0))
+        ));
+        let err = migrate_server_option(json!({"version": 2}), 1).unwrap_err();
+        assert!(matches!(
+            err,
+            MigrateError::VersionMismatch { from: 1, found: 2 }
+        ));
+        let err = migrate_client_option(json!({"dns": {"strategy": "fastest"}}), 1).unwrap_err();
+        assert!(matches!(err, MigrateError::Invalid { ref path, .. } if path == "dns.strategy"));
+        assert!(matches!(
+            migrate_client_option(json!([]), 1),
+            Err(MigrateError::Invalid { .. })
+        ));
+    }
+}
@@ -0,0 +1,221 @@
+//! Connection Context
+
+use std::{
+    net::SocketAddr,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+pub use http::Extensions;
+use http::{HeaderMap, Uri};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::tls::{TlsError, TlsExporter, TlsInfo};
+
+static NEXT_CONN_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Accept stage hook, may add extensions, returning false drops the connection.
+pub type AcceptHook = Arc<dyn Fn(&mut ConnContext) -> bool + Send + Sync>;
+
+/// Server name indicated by the client, inserted by tls servers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServerName(pub String);
+
+/// Negotiated alpn protocol, inserted by tls servers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AlpnProtocol(pub Vec<u8>);
+
+/// Whether a tcp connection runs tls, inserted by tcp servers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Security {
+    Tls,
+    Plain,
+}
+
+/// Everything known about a connection before the callback runs.
+#[derive(Debug)]
+pub struct ConnContext {
+    /// Process wide unique connection id.
+    pub id: u64,
+    /// `tcp`, `ws`, `udp`, `mux`, `generator` or `memory`, empty for contexts made in code.
+    pub transport: &'static str,
+    pub peer_addr: Option<SocketAddr>,
+    pub local_addr: Option<SocketAddr>,
+    pub accepted_at: Instant,
+    /// Time spent in the tls or upgrade handshake.
+    pub handshake: Option<Duration>,
+    /// Values added by servers and accept hooks, keyed by type.
+    pub extensions: Extensions,
+}
+
+impl ConnContext {
+    pub fn new(peer_addr: Option<SocketAddr>, local_addr: Option<SocketAddr>) -> Self {
+        Self {
+            id: NEXT_CONN_ID.fetch_add(1, Ordering::Relaxed),
+            transport: "",
+            peer_addr,
+            local_addr,
+            accepted_at: Instant::now(),
+            handshake: None,
+            extensions: Extensions::new(),
+        }
+    }
+
+    pub fn with_transport(mut self, transport: &'static str) -> Self {
+        self.transport = transport;
+        self
+    }
+
+    /// Run hooks in order, stopping at the first one that rejects.
+    pub fn run_hooks(&mut self, hooks: &[AcceptHook]) -> bool {
+        hooks.iter().all(|hook| hook(self))
+    }
+
+    /// Server name indicated by the client of a tls connection.
+    pub fn server_name(&self) -> Option<&str> {
+        self.extensions
+            .get::<ServerName>()
+            .map(|name| name.0.as_str())
+    }
+
+    /// Negotiated alpn protocol of a tls connection.
+    pub fn alpn_protocol(&self) -> Option<&[u8]> {
+        self.extensions
+            .get::<AlpnProtocol>()
+            .map(|proto| proto.0.as_slice())
+    }
+
+    /// Session of a tls connection, as `TcpStream::tls_info`.
+    pub fn tls_info(&self) -> Option<&TlsInfo> {
+        self.extensions.get::<TlsInfo>()
+    }
+
+    /// Keying material (RFC 5705) the server exported from the tls session
+    /// of the connection, see `TcpServer::add_keying_material_export`.
+    pub fn export_keying_material(
+        &self,
+        label: &[u8],
+        context: Option<&[u8]>,
+        output_len: usize,
+    ) -> Result<Vec<u8>, TlsError> {
+        match self.extensions.get::<TlsExporter>() {
+            Some(exporter) => exporter.export_keying_material(label, context, output_len),
+            None => Err(TlsError::NotTls),
+        }
+    }
+
+    /// What the server found out about the connection, gathered from the
+    /// fields and the extensions it inserted.
+    pub fn info(&self) -> ConnectionInfo {
+        let http = self.extensions.get::<Uri>().map(|uri| HttpRequestInfo {
+            path: uri.path().to_owned(),
+            query: uri.query().map(|q| q.to_owned()),
+            headers: self
+                .extensions
+                .get::<HeaderMap>()
+                .cloned()
+                .unwrap_or_default(),
+        });
+        ConnectionInfo {
+            peer_addr: self.peer_addr,
+            local_addr: self.local_addr,
+            transport: self.transport,
+            tls: self.extensions.get::<TlsInfo>().cloned(),
+            http,
+        }
+    }
+}
+
+/// Connection metadata for routing in `TransportServerCallback::handle_ctx`,
+/// see `ConnContext::info`.
+#[derive(Debug, Clone)]
+pub struct ConnectionInfo {
+    pub peer_addr: Option<SocketAddr>,
+    pub local_addr: Option<SocketAddr>,
+    pub transport: &'static str,
+    /// Session of a tls tcp connection.
+    pub tls: Option<TlsInfo>,
+    /// Upgrade request of a ws connection.
+    pub http: Option<HttpRequestInfo>,
+}
+
+#[derive(Debug, Clone)]
+pub struct HttpRequestInfo {
+    pub path: String,
+    pub query: Option<String>,
+    pub headers: HeaderMap,
+}
+
+/// A stream carrying the context it was created with.
+#[derive(Debug)]
+pub struct WithContext<S> {
+    stream: S,
+    ctx: ConnContext,
+}
+
+impl<S> WithContext<S> {
+    pub fn new(stream: S, ctx: ConnContext) -> Self {
+        Self { stream, ctx }
+    }
+
+    pub fn context(&self) -> &ConnContext {
+        &self.ctx
+    }
+
+    pub fn context_mut(&mut self) -> &mut ConnContext {
+        &mut self.ctx
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+
+    pub fn into_parts(self) -> (S, ConnContext) {
+        (self.stream, self.ctx)
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for WithContext<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for WithContext<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.get_mut().stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_shutdown(cx)
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[std::io::IoSlice<'_>],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.get_mut().stream).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.stream.is_write_vectored()
+    }
+}
@@ -1,9 +1,11 @@
 //! Dns Error
 
-use hickory_resolver::error::ResolveError as HickoryResolveError;
+use hickory_resolver::error::{ResolveError as HickoryResolveError, ResolveErrorKind};
 use thiserror::Error;
 use tokio::time::error::Elapsed;
 
+use crate::error::ErrorCode;
+
 #[derive(Debug, Error)]
 pub enum ResolveError {
     #[error("empty resolved")]
@@ -17,3 +19,36 @@ pub enum ResolveError {
     #[error("init error: {0}")]
     Initialize(String),
 }
+
+impl ResolveError {
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            Self::EmptyResolved => ErrorCode::DnsEmpty,
+            Self::Io(e) if e.kind() == std::io::ErrorKind::TimedOut => ErrorCode::DnsTimeout,
+            Self::Io(e) => ErrorCode::of_io(e),
+            Self::Resolve(e) => match e.kind() {
+                ResolveErrorKind::NoRecordsFound { .. } => ErrorCode::DnsNoRecords,
+                ResolveErrorKind::Timeout => ErrorCode::DnsTimeout,
+                ResolveErrorKind::Io(e) => ErrorCode::of_io(e),
+                ResolveErrorKind::Proto(_) => ErrorCode::DnsProtocol,
+                _ => ErrorCode::Dns,
+            },
+            Self::Timeout(_) => ErrorCode::DnsTimeout,
+            Self::Initialize(_) => ErrorCode::DnsInit,
+        }
+    }
+
+    /// Copy of the error for callers sharing one lookup.
+    pub(crate) fn duplicate(&self) -> Self {
+        match self {
+            Self::EmptyResolved => Self::EmptyResolved,
+            Self::Io(e) => Self::Io(std::io::Error::new(e.kind(), e.to_string())),
+            Self::Resolve(e) => Self::Resolve(e.clone()),
+            Self::Timeout(_) => Self::Io(std::io::Error::new(
+                std::io::ErrorKind::TimedOut,
+                "resolve timeout",
+            )),
+            Self::Initialize(e) => Self::Initialize(e.clone()),
+        }
+    }
+}
@@ -0,0 +1,190 @@
+//! Resolver Health
+
+use std::sync::{Arc, RwLock};
+
+use tokio::sync::watch;
+
+use super::ResolveError;
+
+/// Called when a backend becomes degraded and again when it recovers.
+pub type DegradedCallback = Arc<dyn Fn(&ResolverHealthSnapshot) + Send + Sync>;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BackendHealth {
+    pub name: String,
+    pub successes: u64,
+    pub failures: u64,
+    pub consecutive_failures: u32,
+    pub last_error: Option<String>,
+    /// Set once `consecutive_failures` reaches the threshold, cleared on success.
+    pub degraded: bool,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ResolverHealthSnapshot {
+    pub backends: Vec<BackendHealth>,
+}
+
+/// Lookup outcomes of one resolver, shared by its clones.
+pub struct ResolverHealth {
+    threshold: u32,
+    tx: watch::Sender<ResolverHealthSnapshot>,
+    on_degraded: RwLock<Option<DegradedCallback>>,
+}
+
+impl std::fmt::Debug for ResolverHealth {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ResolverHealth")
+            .field("threshold", &self.threshold)
+            .field("snapshot", &*self.tx.borrow())
+            .finish()
+    }
+}
+
+impl ResolverHealth {
+    pub fn new(backends: Vec<String>, threshold: u32) -> Self {
+        let snapshot = ResolverHealthSnapshot {
+            backends: backends
+                .into_iter()
+                .map(|name| BackendHealth {
+                    name,
+                    ..Default::default()
+                })
+                .collect(),
+        };
+
+        Self {
+            threshold: threshold.max(1),
+            tx: watch::Sender::new(snapshot),
+            on_degraded: RwLock::new(None),
+        }
+    }
+
+    pub fn subscribe(&self) -> watch::Receiver<ResolverHealthSnapshot> {
+        self.tx.subscribe()
+    }
+
+    pub fn snapshot(&self) -> ResolverHealthSnapshot {
+        self.tx.borrow().clone()
+    }
+
+    pub fn set_on_degraded(&self, callback: DegradedCallback) {
+        *self.on_degraded.write().unwrap_or_else(|e| e.into_inner()) = Some(callback);
+    }
+
+    /// Record one lookup of `backend`, firing the callback on a state change.
+    pub fn record(&self, backend: usize, result: Result<(), &ResolveError>) {
+        let threshold = self.threshold;
+        let mut changed = false;
+        self.tx.send_modify(|snapshot| {
+            let Some(health) = snapshot.backends.get_mut(backend) else {
+                return;
+            };
+
+            match result {
+                Ok(()) => {
+                    health.successes += 1;
+                    health.consecutive_failures = 0;
+                    changed = health.degraded;
+                    health.degraded = false;
+                }
+                Err(err) => {
+                    health.failures += 1;
+                    health.consecutive_failures += 1;
+                    health.last_error = Some(err.to_string());
+                    changed = !health.degraded && health.consecutive_failures >= threshold;
+                    health.degraded |= changed;
+                }
+            }
+        });
+
+        if changed {
+            let callback = self
+                .on_degraded
+                .read()
+                .unwrap_or_else(|e| e.into_inner())
+                .clone();
+            if let Some(callback) = callback {
+                callback(&self.snapshot());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+
+    #[test]
+    fn test_degraded_threshold() {
+        let health = ResolverHealth::new(vec!["primary".into()], 3);
+        let mut rx = health.subscribe();
+        let events = Arc::new(RwLock::new(vec![]));
+        let events_clone = events.clone();
+        health.set_on_degraded(Arc::new(move |s| {
+            events_clone.write().unwrap().push(s.backends[0].degraded);
+        }));
+
+        let err = ResolveError::EmptyResolved;
+        for _ in 0..2 {
+            health.record(0, Err(&err));
+        }
+        assert!(rx.has_changed().unwrap());
+        assert_eq!(rx.borrow_and_update().backends[0].consecutive_failures, 2);
+        assert!(events.read().unwrap().is_empty());
+
+        health.record(0, Err(&err));
+        health.record(0, Err(&err));
+        assert_eq!(*events.read().unwrap(), [true]);
+
+        health.record(0, Ok(()));
+        health.record(0, Ok(()));
+        assert_eq!(*events.read().unwrap(), [true, false]);
+
+        let snapshot = rx.borrow_and_update().clone();
+        assert_eq!(snapshot.backends[0].successes, 2);
+        assert_eq!(snapshot.backends[0].failures, 4);
+        assert_eq!(snapshot.backends[0].consecutive_failures, 0);
+        assert_eq!(
+            snapshot.backends[0].last_error.as_deref(),
+            Some("empty resolved")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_resolver_health() {
+        use crate::{dns::ResolveOption, Resolver};
+
+        let resolver = Resolver::new(ResolveOption {
+            servers: vec![crate::dns::option::NameServerOption {
+                address: "127.0.0.1:1".parse().unwrap(),
+                protocol: crate::dns::option::Protocol::Tcp,
+            }],
+            timeout: std::time::Duration::from_millis(100),
+            degraded_threshold: 2,
+            ..Default::default()
+        });
+        let fired = Arc::new(AtomicUsize::new(0));
+        let fired_clone = fired.clone();
+        resolver.set_on_degraded(Arc::new(move |_| {
+            fired_clone.fetch_add(1, Ordering::SeqCst);
+        }));
+
+        let mut rx = resolver.subscribe_health();
+        for _ in 0..2 {
+            assert!(resolver.resolve("example.test", 80).await.is_err());
+        }
+        rx.changed().await.unwrap();
+        let snapshot = rx.borrow().clone();
+        assert_eq!(snapshot.backends[0].name, "tcp://127.0.0.1:1");
+        assert_eq!(snapshot.backends[0].failures, 2);
+        assert!(snapshot.backends[0].degraded);
+        assert_eq!(fired.load(Ordering::SeqCst), 1);
+
+        // ip literals never reach a backend
+        assert!(resolver.resolve("127.0.0.1", 80).await.is_ok());
+        assert_eq!(resolver.health().backends[0].successes, 0);
+    }
+}
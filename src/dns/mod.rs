@@ -8,3 +8,88 @@ pub use error::ResolveError;
 
 pub mod resolver;
 pub use resolver::Resolver;
+
+pub mod post;
+pub use post::{AddrOrigin, PostProcess, ResolvedAddr};
+
+pub mod probe;
+pub use probe::{FamilyProbe, UdpProbe};
+
+pub mod health;
+pub use health::{ResolverHealth, ResolverHealthSnapshot};
+
+use std::sync::RwLock;
+
+/// Slot of a default resolver, the process wide one is `DEFAULT_RESOLVER`.
+struct DefaultResolver(RwLock<Option<Resolver>>);
+
+impl DefaultResolver {
+    const fn new() -> Self {
+        Self(RwLock::new(None))
+    }
+
+    fn set(&self, resolver: Resolver) {
+        *self.0.write().unwrap_or_else(|e| e.into_inner()) = Some(resolver);
+    }
+
+    fn get(&self) -> Resolver {
+        self.0
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+            .unwrap_or_default()
+    }
+}
+
+static DEFAULT_RESOLVER: DefaultResolver = DefaultResolver::new();
+
+/// Set the process wide resolver used when none is given explicitly.
+pub fn set_default_resolver(resolver: Resolver) {
+    DEFAULT_RESOLVER.set(resolver);
+}
+
+/// The process wide resolver, `Resolver::default()` if never set.
+pub fn default_resolver() -> Resolver {
+    DEFAULT_RESOLVER.get()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{dns::option::NameServerOption, TransportClientOption};
+
+    use super::*;
+
+    #[test]
+    fn test_resolver_precedence() {
+        let custom = ResolveOption {
+            servers: vec![NameServerOption {
+                address: "127.0.0.1:53".parse().unwrap(),
+                protocol: option::Protocol::Udp,
+            }],
+            ..Default::default()
+        };
+
+        // a slot of its own, the process wide one is shared with running tests
+        let slot = DefaultResolver::new();
+        let mut opt = TransportClientOption::default();
+        assert!(matches!(
+            opt.resolver_with(|| slot.get()),
+            Resolver::Default(..)
+        ));
+
+        slot.set(Resolver::new(custom.clone()));
+        assert!(matches!(
+            opt.resolver_with(|| slot.get()),
+            Resolver::Custom(..)
+        ));
+
+        opt.dns = Some(ResolveOption {
+            servers: vec![],
+            ..custom
+        });
+        assert!(!matches!(
+            opt.resolver_with(|| slot.get()),
+            Resolver::Custom(..)
+        ));
+    }
+}
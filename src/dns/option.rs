@@ -7,20 +7,75 @@ use hickory_resolver::config::{
 };
 use serde::{Deserialize, Serialize};
 
+use super::post::PostProcess;
+
 #[derive(Debug, Clone, Deserialize, Serialize)]
-#[serde(default, rename_all = "snake_case")]
+#[serde(default, rename_all = "snake_case", from = "ResolveOptionCompat")]
 pub struct ResolveOption {
-    pub strategy: Strategy,
+    /// Which record types are queried.
+    pub query: QueryStrategy,
+    /// How the resolved addresses are ordered for connecting.
+    pub order: OrderStrategy,
     pub timeout: Duration,
     pub servers: Vec<NameServerOption>,
+    /// Consecutive failures before a backend is reported degraded.
+    pub degraded_threshold: u32,
+    /// Rewrites the addresses of every lookup, after `query` and `order`.
+    pub post_process: Option<PostProcess>,
 }
 
 impl Default for ResolveOption {
     fn default() -> Self {
+        let (query, order) = Strategy::default().split();
         Self {
-            strategy: Strategy::default(),
+            query,
+            order,
             timeout: Duration::from_secs(5),
             servers: vec![],
+            degraded_threshold: 3,
+            post_process: None,
+        }
+    }
+}
+
+/// Accepts the old single `strategy` field, `query` and `order` take precedence.
+#[derive(Deserialize)]
+#[serde(default)]
+struct ResolveOptionCompat {
+    strategy: Option<Strategy>,
+    query: Option<QueryStrategy>,
+    order: Option<OrderStrategy>,
+    timeout: Duration,
+    servers: Vec<NameServerOption>,
+    degraded_threshold: u32,
+    post_process: Option<PostProcess>,
+}
+
+impl Default for ResolveOptionCompat {
+    fn default() -> Self {
+        let opt = ResolveOption::default();
+        Self {
+            strategy: None,
+            query: None,
+            order: None,
+            timeout: opt.timeout,
+            servers: opt.servers,
+            degraded_threshold: opt.degraded_threshold,
+            post_process: opt.post_process,
+        }
+    }
+}
+
+impl From<ResolveOptionCompat> for ResolveOption {
+    fn from(value: ResolveOptionCompat) -> Self {
+        let (query, order) = value.strategy.unwrap_or_default().split();
+        Self {
+            query: value.query.unwrap_or(query),
+            order: value.order.unwrap_or(order),
+            timeout: value.timeout,
+            servers: value.servers,
+            degraded_threshold: value.degraded_threshold,
+            post_process: value.post_process,
         }
     }
 }
@@ -38,6 +93,15 @@ pub enum Protocol {
     Udp,
 }
 
+impl std::fmt::Display for NameServerOption {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.protocol {
+            Protocol::Tcp => write!(f, "tcp://{}", self.address),
+            Protocol::Udp => write!(f, "udp://{}", self.address),
+        }
+    }
+}
+
 impl From<Protocol> for HickoryProtocol {
     fn from(value: Protocol) -> Self {
         match value {
@@ -47,34 +111,74 @@ impl From<Protocol> for HickoryProtocol {
     }
 }
 
-#[derive(Debug, Copy, Clone, Deserialize, Serialize)]
+/// Combined query and order strategy, kept for older configs.
+#[derive(Debug, Default, Copy, Clone, Deserialize, Serialize)]
 #[serde(rename_all = "snake_case")]
 pub enum Strategy {
     Ipv4Only,
     Ipv6Only,
     Ipv4AndIpv6,
     Ipv6ThenIpv4,
+    #[default]
     Ipv4ThenIpv6,
+    Auto,
 }
 
-impl Default for Strategy {
-    fn default() -> Self {
-        Self::Ipv4ThenIpv6
+impl Strategy {
+    pub fn split(self) -> (QueryStrategy, OrderStrategy) {
+        match self {
+            Strategy::Ipv4Only => (QueryStrategy::V4Only, OrderStrategy::AsReturned),
+            Strategy::Ipv6Only => (QueryStrategy::V6Only, OrderStrategy::AsReturned),
+            Strategy::Ipv4AndIpv6 => (QueryStrategy::Both, OrderStrategy::AsReturned),
+            Strategy::Ipv4ThenIpv6 => (QueryStrategy::Both, OrderStrategy::V4First),
+            Strategy::Ipv6ThenIpv4 => (QueryStrategy::Both, OrderStrategy::V6First),
+            Strategy::Auto => (QueryStrategy::Both, OrderStrategy::Auto),
+        }
     }
 }
 
-impl From<Strategy> for LookupIpStrategy {
-    fn from(value: Strategy) -> Self {
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum QueryStrategy {
+    #[default]
+    Both,
+    V4Only,
+    V6Only,
+}
+
+impl QueryStrategy {
+    pub fn allows(&self, addr: &SocketAddr) -> bool {
+        match self {
+            Self::Both => true,
+            Self::V4Only => addr.is_ipv4(),
+            Self::V6Only => addr.is_ipv6(),
+        }
+    }
+}
+
+impl From<QueryStrategy> for LookupIpStrategy {
+    fn from(value: QueryStrategy) -> Self {
         match value {
-            Strategy::Ipv4Only => Self::Ipv4Only,
-            Strategy::Ipv6Only => Self::Ipv6Only,
-            Strategy::Ipv4AndIpv6 => Self::Ipv4AndIpv6,
-            Strategy::Ipv4ThenIpv6 => Self::Ipv4thenIpv6,
-            Strategy::Ipv6ThenIpv4 => Self::Ipv6thenIpv4,
+            QueryStrategy::Both => Self::Ipv4AndIpv6,
+            QueryStrategy::V4Only => Self::Ipv4Only,
+            QueryStrategy::V6Only => Self::Ipv6Only,
         }
     }
 }
 
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OrderStrategy {
+    #[default]
+    V4First,
+    V6First,
+    /// Alternate families, starting with the family of the first address.
+    Interleave,
+    AsReturned,
+    /// Prefer the families that currently have a route, probed locally.
+    Auto,
+}
+
 impl ResolveOption {
     pub fn custom_config(&self) -> (ResolverConfig, ResolverOpts) {
         let cfg = if self.servers.is_empty() {
@@ -94,7 +198,7 @@ impl ResolveOption {
         };
 
         let mut opt = ResolverOpts::default();
-        opt.ip_strategy = self.strategy.into();
+        opt.ip_strategy = self.query.into();
         opt.timeout = self.timeout;
 
         (cfg, opt)
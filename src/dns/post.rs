@@ -0,0 +1,293 @@
+//! Resolved Address Post Processing
+//!
+//! Runs on the addresses of every lookup once `query` and `order` were
+//! applied, whatever the backend.
+
+use std::{
+    fmt,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+};
+
+use serde::{Deserialize, Deserializer, Serialize};
+
+/// Rewrites the resolved addresses, for split horizon setups.
+pub type PostProcessFn = Arc<dyn Fn(Vec<SocketAddr>) -> Vec<SocketAddr> + Send + Sync>;
+
+/// Prefix length of the well known NAT64 prefix `64:ff9b::/96`.
+pub const DEFAULT_NAT64_PREFIX_LEN: u8 = 96;
+
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PostProcess {
+    /// Synthesize v6 addresses out of v4 only answers, as DNS64 does (RFC 6052).
+    ///
+    /// Answers with any v6 address are left alone. The synthesized
+    /// addresses go first, followed by the v4 ones unless `replace`.
+    Nat64 {
+        prefix: Ipv6Addr,
+        /// One of 32, 40, 48, 56, 64 or 96.
+        #[serde(
+            default = "default_nat64_prefix_len",
+            deserialize_with = "deserialize_prefix_len"
+        )]
+        prefix_len: u8,
+        #[serde(default)]
+        replace: bool,
+    },
+    /// Set in code only.
+    #[serde(skip)]
+    Custom(PostProcessFn),
+}
+
+impl fmt::Debug for PostProcess {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Nat64 {
+                prefix,
+                prefix_len,
+                replace,
+            } => f
+                .debug_struct("Nat64")
+                .field("prefix", prefix)
+                .field("prefix_len", prefix_len)
+                .field("replace", replace)
+                .finish(),
+            Self::Custom(_) => f.write_str("Custom"),
+        }
+    }
+}
+
+fn default_nat64_prefix_len() -> u8 {
+    DEFAULT_NAT64_PREFIX_LEN
+}
+
+fn deserialize_prefix_len<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u8, D::Error> {
+    let len = u8::deserialize(deserializer)?;
+    if nat64_synthesize(Ipv6Addr::UNSPECIFIED, len, Ipv4Addr::UNSPECIFIED).is_none() {
+        return Err(serde::de::Error::custom(format!(
+            "nat64 prefix length {} is not one of 32, 40, 48, 56, 64 or 96",
+            len
+        )));
+    }
+    Ok(len)
+}
+
+/// Where a resolved address came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AddrOrigin {
+    /// Returned by the lookup.
+    Answer,
+    /// Synthesized by `PostProcess::Nat64`.
+    Nat64,
+    /// Added by `PostProcess::Custom`.
+    Rewritten,
+}
+
+/// A resolved address and its origin, as returned by `Resolver::resolve_detailed`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct ResolvedAddr {
+    pub addr: SocketAddr,
+    pub origin: AddrOrigin,
+}
+
+impl ResolvedAddr {
+    pub fn answer(addr: SocketAddr) -> Self {
+        Self {
+            addr,
+            origin: AddrOrigin::Answer,
+        }
+    }
+}
+
+impl fmt::Display for ResolvedAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.origin {
+            AddrOrigin::Answer => write!(f, "{}", self.addr),
+            AddrOrigin::Nat64 => write!(f, "{} (nat64)", self.addr),
+            AddrOrigin::Rewritten => write!(f, "{} (rewritten)", self.addr),
+        }
+    }
+}
+
+impl PostProcess {
+    pub fn apply(&self, addrs: Vec<SocketAddr>) -> Vec<ResolvedAddr> {
+        match self {
+            Self::Nat64 {
+                prefix,
+                prefix_len,
+                replace,
+            } => {
+                if addrs.iter().any(|addr| addr.is_ipv6()) {
+                    return addrs.into_iter().map(ResolvedAddr::answer).collect();
+                }
+                let mut synthesized = vec![];
+                for addr in &addrs {
+                    let IpAddr::V4(v4) = addr.ip() else {
+                        continue;
+                    };
+                    let Some(v6) = nat64_synthesize(*prefix, *prefix_len, v4) else {
+                        log::warn!("nat64 prefix length {} is invalid", prefix_len);
+                        return addrs.into_iter().map(ResolvedAddr::answer).collect();
+                    };
+                    synthesized.push(ResolvedAddr {
+                        addr: SocketAddr::new(v6.into(), addr.port()),
+                        origin: AddrOrigin::Nat64,
+                    });
+                }
+                if !replace {
+                    synthesized.extend(addrs.into_iter().map(ResolvedAddr::answer));
+                }
+                synthesized
+            }
+            Self::Custom(rewrite) => {
+                let answers = addrs.clone();
+                rewrite(addrs)
+                    .into_iter()
+                    .map(|addr| ResolvedAddr {
+                        addr,
+                        origin: if answers.contains(&addr) {
+                            AddrOrigin::Answer
+                        } else {
+                            AddrOrigin::Rewritten
+                        },
+                    })
+                    .collect()
+            }
+        }
+    }
+}
+
+/// Embed `v4` into the NAT64 `prefix` of `prefix_len` bits (RFC 6052 2.2),
+/// `None` for a length the RFC does not define.
+///
+/// Bits 64 to 71 stay zero, the v4 bytes falling there move past them.
+pub fn nat64_synthesize(prefix: Ipv6Addr, prefix_len: u8, v4: Ipv4Addr) -> Option<Ipv6Addr> {
+    if !matches!(prefix_len, 32 | 40 | 48 | 56 | 64 | 96) {
+        return None;
+    }
+    let mut octets = [0u8; 16];
+    let at = prefix_len as usize / 8;
+    octets[..at].copy_from_slice(&prefix.octets()[..at]);
+    let mut pos = at;
+    for byte in v4.octets() {
+        if pos == 8 {
+            pos += 1;
+        }
+        octets[pos] = byte;
+        pos += 1;
+    }
+    Some(octets.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addrs(ips: &[&str]) -> Vec<SocketAddr> {
+        ips.iter()
+            .map(|ip| SocketAddr::new(ip.parse().unwrap(), 443))
+            .collect()
+    }
+
+    #[test]
+    fn test_nat64_synthesize() {
+        // RFC 6052 2.4, 192.0.2.33 under each prefix length
+        let v4 = Ipv4Addr::new(192, 0, 2, 33);
+        let cases = [
+            ("2001:db8::", 32, "2001:db8:c000:221::"),
+            ("2001:db8:100::", 40, "2001:db8:1c0:2:21::"),
+            ("2001:db8:122::", 48, "2001:db8:122:c000:2:2100::"),
+            ("2001:db8:122:300::", 56, "2001:db8:122:3c0:0:221::"),
+            ("2001:db8:122:344::", 64, "2001:db8:122:344:c0:2:2100:0"),
+            ("2001:db8:122:344::", 96, "2001:db8:122:344::c000:221"),
+            ("64:ff9b::", 96, "64:ff9b::c000:221"),
+        ];
+        for (prefix, len, expected) in cases {
+            let v6 = nat64_synthesize(prefix.parse().unwrap(), len, v4).unwrap();
+            assert_eq!(v6, expected.parse::<Ipv6Addr>().unwrap(), "/{}", len);
+        }
+
+        // bits past the prefix length are ignored
+        let v6 = nat64_synthesize("2001:db8::ffff:ffff".parse().unwrap(), 32, v4).unwrap();
+        assert_eq!(v6, "2001:db8:c000:221::".parse::<Ipv6Addr>().unwrap());
+        for len in [0, 33, 128] {
+            assert!(nat64_synthesize(Ipv6Addr::UNSPECIFIED, len, v4).is_none());
+        }
+    }
+
+    #[test]
+    fn test_post_process() {
+        let nat64 = |replace| PostProcess::Nat64 {
+            prefix: "64:ff9b::".parse().unwrap(),
+            prefix_len: 96,
+            replace,
+        };
+        let shown = |resolved: Vec<ResolvedAddr>| {
+            resolved.iter().map(|r| r.to_string()).collect::<Vec<_>>()
+        };
+
+        let v4 = addrs(&["192.0.2.1", "192.0.2.2"]);
+        assert_eq!(
+            shown(nat64(false).apply(v4.clone())),
+            [
+                "[64:ff9b::c000:201]:443 (nat64)",
+                "[64:ff9b::c000:202]:443 (nat64)",
+                "192.0.2.1:443",
+                "192.0.2.2:443"
+            ]
+        );
+        assert_eq!(
+            shown(nat64(true).apply(v4)),
+            [
+                "[64:ff9b::c000:201]:443 (nat64)",
+                "[64:ff9b::c000:202]:443 (nat64)"
+            ]
+        );
+        // a native v6 answer wins over synthesis
+        let dual = addrs(&["192.0.2.1", "2001:db8::1"]);
+        assert_eq!(
+            shown(nat64(true).apply(dual)),
+            ["192.0.2.1:443", "[2001:db8::1]:443"]
+        );
+        assert!(nat64(false).apply(vec![]).is_empty());
+
+        // split horizon, the public address swapped for the internal one
+        let custom = PostProcess::Custom(Arc::new(|addrs: Vec<SocketAddr>| {
+            addrs
+                .into_iter()
+                .map(|addr| match addr.ip().to_string().as_str() {
+                    "203.0.113.7" => SocketAddr::new([10, 0, 0, 7].into(), addr.port()),
+                    _ => addr,
+                })
+                .collect()
+        }));
+        assert_eq!(
+            shown(custom.apply(addrs(&["203.0.113.7", "203.0.113.8"]))),
+            ["10.0.0.7:443 (rewritten)", "203.0.113.8:443"]
+        );
+    }
+
+    #[test]
+    fn test_post_process_deserialize() {
+        let opt: crate::ResolveOption =
+            serde_json::from_str(r#"{"post_process": {"nat64": {"prefix": "64:ff9b::"}}}"#)
+                .unwrap();
+        let Some(PostProcess::Nat64 {
+            prefix_len,
+            replace,
+            ..
+        }) = opt.post_process
+        else {
+            panic!("{:?}", opt.post_process);
+        };
+        assert_eq!((prefix_len, replace), (96, false));
+
+        let err = serde_json::from_str::<PostProcess>(
+            r#"{"nat64": {"prefix": "2001:db8::", "prefix_len": 33}}"#,
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("prefix length 33"), "{}", err);
+    }
+}
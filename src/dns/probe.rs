@@ -0,0 +1,182 @@
+//! Local Address Family Probe
+
+use std::{
+    fmt::Debug,
+    net::{SocketAddr, UdpSocket},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// How long a probe result is trusted before probing again.
+pub const PROBE_TTL: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Families {
+    pub ipv4: bool,
+    pub ipv6: bool,
+}
+
+/// Source of the address families currently usable on this host.
+pub trait FamilyProbe: Debug + Send + Sync {
+    fn probe(&self) -> Families;
+}
+
+/// Probe by connecting a UDP socket to a public address of each family.
+///
+/// Connecting a UDP socket only does a route lookup, no packet is sent.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct UdpProbe;
+
+impl UdpProbe {
+    fn routable(bind: &str, target: &str) -> bool {
+        let target: SocketAddr = target.parse().unwrap();
+        UdpSocket::bind(bind)
+            .and_then(|s| s.connect(target))
+            .is_ok()
+    }
+}
+
+impl FamilyProbe for UdpProbe {
+    fn probe(&self) -> Families {
+        Families {
+            ipv4: Self::routable("0.0.0.0:0", "8.8.8.8:53"),
+            ipv6: Self::routable("[::]:0", "[2001:4860:4860::8888]:53"),
+        }
+    }
+}
+
+/// Cached probe results, shared by clones of a resolver.
+#[derive(Debug)]
+pub struct AutoFamilies {
+    probe: Arc<dyn FamilyProbe>,
+    cached: Mutex<Option<(Instant, Families)>>,
+}
+
+impl AutoFamilies {
+    pub fn new(probe: Arc<dyn FamilyProbe>) -> Self {
+        Self {
+            probe,
+            cached: Mutex::new(None),
+        }
+    }
+
+    /// Families from the cache, probed again once `PROBE_TTL` passed.
+    ///
+    /// The probe runs on the blocking pool without holding the cache, the
+    /// route lookups of `UdpProbe` are blocking calls.
+    pub async fn families(&self) -> Families {
+        if let Some((at, families)) = *self.cached.lock().unwrap_or_else(|e| e.into_inner()) {
+            if at.elapsed() < PROBE_TTL {
+                return families;
+            }
+        }
+        let probe = self.probe.clone();
+        let families = tokio::task::spawn_blocking(move || probe.probe())
+            .await
+            // a probe that panicked leaves the order as resolved
+            .unwrap_or(Families {
+                ipv4: true,
+                ipv6: true,
+            });
+        *self.cached.lock().unwrap_or_else(|e| e.into_inner()) = Some((Instant::now(), families));
+        families
+    }
+
+    pub fn flush(&self) {
+        *self.cached.lock().unwrap_or_else(|e| e.into_inner()) = None;
+    }
+
+    /// Move addresses of working families first, ipv6 ahead when both work.
+    ///
+    /// Nothing is dropped, a wrong probe only costs a failed connect attempt.
+    pub async fn order(&self, mut addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+        let families = self.families().await;
+        let rank = |addr: &SocketAddr| match (addr.is_ipv6(), families.ipv4, families.ipv6) {
+            (true, _, true) => 0,
+            (false, true, _) => 1,
+            _ => 2,
+        };
+        addrs.sort_by_key(rank);
+        addrs
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+
+    #[derive(Debug)]
+    struct FakeProbe {
+        families: Families,
+        calls: AtomicUsize,
+    }
+
+    impl FamilyProbe for FakeProbe {
+        fn probe(&self) -> Families {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            self.families
+        }
+    }
+
+    #[tokio::test]
+    async fn test_auto_order() {
+        let addrs: Vec<SocketAddr> = ["[::1]:80", "10.0.0.1:80", "[::2]:80", "10.0.0.2:80"]
+            .iter()
+            .map(|s| s.parse().unwrap())
+            .collect();
+        let order = |ipv4, ipv6| {
+            let probe = FakeProbe {
+                families: Families { ipv4, ipv6 },
+                calls: AtomicUsize::new(0),
+            };
+            let addrs = addrs.clone();
+            async move {
+                AutoFamilies::new(Arc::new(probe))
+                    .order(addrs)
+                    .await
+                    .iter()
+                    .map(|a| a.to_string())
+                    .collect::<Vec<_>>()
+            }
+        };
+
+        assert_eq!(
+            order(true, false).await,
+            ["10.0.0.1:80", "10.0.0.2:80", "[::1]:80", "[::2]:80"]
+        );
+        assert_eq!(
+            order(false, true).await,
+            ["[::1]:80", "[::2]:80", "10.0.0.1:80", "10.0.0.2:80"]
+        );
+        assert_eq!(
+            order(true, true).await,
+            ["[::1]:80", "[::2]:80", "10.0.0.1:80", "10.0.0.2:80"]
+        );
+        assert_eq!(
+            order(false, false).await,
+            ["[::1]:80", "10.0.0.1:80", "[::2]:80", "10.0.0.2:80"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_auto_cache() {
+        let probe = Arc::new(FakeProbe {
+            families: Families {
+                ipv4: true,
+                ipv6: false,
+            },
+            calls: AtomicUsize::new(0),
+        });
+        let auto = AutoFamilies::new(probe.clone());
+
+        auto.families().await;
+        auto.families().await;
+        assert_eq!(probe.calls.load(Ordering::SeqCst), 1);
+
+        auto.flush();
+        auto.families().await;
+        assert_eq!(probe.calls.load(Ordering::SeqCst), 2);
+    }
+}
@@ -1,62 +1,120 @@
 //! Dns Resolver
 
-use std::{net::SocketAddr, time::Duration};
+use std::{
+    collections::HashMap,
+    future::Future,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, OnceLock},
+    time::Duration,
+};
 
+use futures_util::{stream, StreamExt};
 use hickory_resolver::{system_conf::read_system_conf, TokioAsyncResolver};
 
-use tokio::net::lookup_host;
+use tokio::{net::lookup_host, runtime::RuntimeFlavor, sync::watch};
 
-use super::{option::Strategy, ResolveError, ResolveOption};
+use super::{
+    health::{DegradedCallback, ResolverHealth, ResolverHealthSnapshot},
+    option::{OrderStrategy, QueryStrategy},
+    post::{PostProcess, ResolvedAddr},
+    probe::{AutoFamilies, FamilyProbe, UdpProbe},
+    ResolveError, ResolveOption,
+};
 
 #[derive(Debug, Clone)]
 pub struct DefaultResolveOption {
     timeout: Duration,
-    strategy: Strategy,
+}
+
+/// Settings and health shared by clones of one resolver.
+#[derive(Debug)]
+pub struct ResolverState {
+    query: QueryStrategy,
+    order: OrderStrategy,
+    post_process: Option<PostProcess>,
+    health: ResolverHealth,
 }
 
 #[derive(Debug, Clone)]
 pub enum Resolver {
-    Default(DefaultResolveOption),
-    System(TokioAsyncResolver),
-    Custom(TokioAsyncResolver),
+    Default(DefaultResolveOption, Arc<ResolverState>),
+    System(TokioAsyncResolver, Arc<ResolverState>),
+    Custom(TokioAsyncResolver, Arc<ResolverState>),
+    /// Resolve with the inner resolver, then order by working local families.
+    Auto(Box<Resolver>, Arc<AutoFamilies>),
 }
 
 impl Default for Resolver {
     fn default() -> Self {
-        Self::Default(DefaultResolveOption {
-            timeout: Duration::from_secs(5),
-            strategy: Strategy::default(),
-        })
+        let option = ResolveOption::default();
+        Self::Default(
+            DefaultResolveOption {
+                timeout: option.timeout,
+            },
+            state("getaddrinfo", &option),
+        )
     }
 }
 
+fn state(name: impl Into<String>, option: &ResolveOption) -> Arc<ResolverState> {
+    Arc::new(ResolverState {
+        query: option.query,
+        order: option.order,
+        post_process: option.post_process.clone(),
+        health: ResolverHealth::new(vec![name.into()], option.degraded_threshold),
+    })
+}
+
 impl Resolver {
     pub fn new(option: ResolveOption) -> Self {
+        Self::with_probe(option, Arc::new(UdpProbe))
+    }
+
+    /// Like `new`, with the family probe used by `OrderStrategy::Auto`.
+    pub fn with_probe(option: ResolveOption, probe: Arc<dyn FamilyProbe>) -> Self {
+        if let OrderStrategy::Auto = option.order {
+            let inner = Self::new(ResolveOption {
+                order: OrderStrategy::AsReturned,
+                ..option
+            });
+            return Resolver::Auto(Box::new(inner), Arc::new(AutoFamilies::new(probe)));
+        }
+
         if option.servers.is_empty() {
             #[cfg(any(unix, target_os = "windows"))]
             {
                 match read_system_conf() {
                     Ok((cfg, mut opt)) => {
                         opt.timeout = option.timeout;
-                        opt.ip_strategy = option.strategy.into();
+                        opt.ip_strategy = option.query.into();
                         let resolver = TokioAsyncResolver::tokio(cfg, opt);
-                        Resolver::System(resolver)
+                        Resolver::System(resolver, state("system", &option))
                     }
-                    Err(_) => Resolver::Default(DefaultResolveOption {
-                        timeout: option.timeout,
-                        strategy: option.strategy,
-                    }),
+                    Err(_) => Resolver::Default(
+                        DefaultResolveOption {
+                            timeout: option.timeout,
+                        },
+                        state("getaddrinfo", &option),
+                    ),
                 }
             }
             #[cfg(not(any(unix, target_os = "windows")))]
-            Resolver::Default(DefaultResolveOption {
-                timeout: option.timeout,
-                strategy: option.strategy,
-            })
+            Resolver::Default(
+                DefaultResolveOption {
+                    timeout: option.timeout,
+                },
+                state("getaddrinfo", &option),
+            )
         } else {
             let (cfg, opt) = option.custom_config();
             let resolver = TokioAsyncResolver::tokio(cfg, opt);
-            Resolver::Custom(resolver)
+            let name = option
+                .servers
+                .iter()
+                .map(|s| s.to_string())
+                .collect::<Vec<_>>()
+                .join(",");
+            Resolver::Custom(resolver, state(name, &option))
         }
     }
 
@@ -65,77 +123,260 @@ impl Resolver {
         addr: S,
         port: u16,
     ) -> Result<impl Iterator<Item = SocketAddr>, ResolveError> {
+        let resolved = self.resolve_detailed(addr, port).await?;
+        Ok(resolved.into_iter().map(|resolved| resolved.addr))
+    }
+
+    /// Like `resolve`, telling the answers from the addresses added by
+    /// `post_process`.
+    pub async fn resolve_detailed<S: AsRef<str> + ToString>(
+        &self,
+        addr: S,
+        port: u16,
+    ) -> Result<Vec<ResolvedAddr>, ResolveError> {
+        let answers = self.answers(addr, port).await?.collect::<Vec<_>>();
+        Ok(match self.state().post_process {
+            Some(ref post_process) => post_process.apply(answers),
+            None => answers.into_iter().map(ResolvedAddr::answer).collect(),
+        })
+    }
+
+    /// Addresses of the lookup, with `query` and `order` applied.
+    async fn answers<S: AsRef<str> + ToString>(
+        &self,
+        addr: S,
+        port: u16,
+    ) -> Result<impl Iterator<Item = SocketAddr>, ResolveError> {
+        // ip literals never reach a backend, keep them out of the health stats
+        let literal = addr.as_ref().parse::<IpAddr>().is_ok();
         match self {
-            Self::Default(option) => {
+            Self::Default(option, state) => {
                 let result =
                     tokio::time::timeout(option.timeout, lookup_host((addr.to_string(), port)))
-                        .await??;
-                Ok(Resolved::Default(sort_resolved(result, option.strategy)))
+                        .await
+                        .map_err(ResolveError::from)
+                        .and_then(|r| r.map_err(ResolveError::from));
+                record(state, literal, &result);
+                Ok(Resolved::Default(sort_resolved(
+                    result?,
+                    state.query,
+                    state.order,
+                )))
             }
-            Self::System(resolver) => {
+            Self::System(resolver, state) => {
                 let resolver = resolver.clone();
                 //let result = resolver.lookup_ip(addr.as_ref()).await?;
                 let addr = addr.to_string();
                 let result = tokio::spawn(async move { resolver.lookup_ip(addr).await })
                     .await
-                    .map_err(|e| ResolveError::Initialize(e.to_string()))??;
-                Ok(Resolved::System(
-                    result.into_iter().map(move |ip| SocketAddr::new(ip, port)),
-                ))
+                    .map_err(|e| ResolveError::Initialize(e.to_string()))
+                    .and_then(|r| r.map_err(ResolveError::from));
+                record(state, literal, &result);
+                Ok(Resolved::System(sort_resolved(
+                    result?.into_iter().map(move |ip| SocketAddr::new(ip, port)),
+                    state.query,
+                    state.order,
+                )))
             }
-            Self::Custom(resolver) => {
+            Self::Custom(resolver, state) => {
                 let resolver = resolver.clone();
                 //let result = resolver.lookup_ip(addr.as_ref()).await?;
                 let addr = addr.to_string();
                 let result = tokio::spawn(async move { resolver.lookup_ip(addr).await })
                     .await
-                    .map_err(|e| ResolveError::Initialize(e.to_string()))??;
-                Ok(Resolved::Custom(
-                    result.into_iter().map(move |ip| SocketAddr::new(ip, port)),
-                ))
+                    .map_err(|e| ResolveError::Initialize(e.to_string()))
+                    .and_then(|r| r.map_err(ResolveError::from));
+                record(state, literal, &result);
+                Ok(Resolved::Custom(sort_resolved(
+                    result?.into_iter().map(move |ip| SocketAddr::new(ip, port)),
+                    state.query,
+                    state.order,
+                )))
+            }
+            Self::Auto(inner, families) => {
+                let result = Box::pin(inner.answers(addr, port)).await?.collect();
+                Ok(Resolved::Auto(families.order(result).await.into_iter()))
             }
         }
     }
 
+    /// Drop cached lookups and local family probe results.
+    pub fn flush_cache(&self) {
+        match self {
+            Self::Default(..) => {}
+            Self::System(resolver, _) | Self::Custom(resolver, _) => resolver.clear_cache(),
+            Self::Auto(inner, families) => {
+                families.flush();
+                inner.flush_cache();
+            }
+        }
+    }
+
+    fn state(&self) -> &ResolverState {
+        match self {
+            Self::Default(_, state) | Self::System(_, state) | Self::Custom(_, state) => state,
+            Self::Auto(inner, _) => inner.state(),
+        }
+    }
+
+    fn health_ref(&self) -> &ResolverHealth {
+        &self.state().health
+    }
+
+    /// Watch per backend lookup outcomes, updated as lookups complete.
+    pub fn subscribe_health(&self) -> watch::Receiver<ResolverHealthSnapshot> {
+        self.health_ref().subscribe()
+    }
+
+    pub fn health(&self) -> ResolverHealthSnapshot {
+        self.health_ref().snapshot()
+    }
+
+    /// Shared by all clones of this resolver.
+    pub fn set_on_degraded(&self, callback: DegradedCallback) {
+        self.health_ref().set_on_degraded(callback);
+    }
+
     pub fn block_resolve<S: AsRef<str> + ToString>(
         &self,
         addr: S,
         port: u16,
     ) -> Result<impl Iterator<Item = SocketAddr>, ResolveError> {
-        tokio::task::block_in_place(move || {
-            let result = if let Ok(runtime) = tokio::runtime::Handle::try_current() {
-                runtime.block_on(async move { self.resolve(addr, port).await })?
-            } else {
-                let mut runtime = tokio::runtime::Builder::new_current_thread();
-                runtime.enable_all();
-                let runtime = runtime
-                    .build()
-                    .map_err(|e| ResolveError::Initialize(e.to_string()))?;
-
-                runtime.block_on(async move { self.resolve(addr, port).await })?
-            };
-
-            Ok::<_, ResolveError>(result)
+        let addr = addr.to_string();
+        block_on(async move { self.resolve(addr, port).await })?
+    }
+
+    /// Resolve many `(host, port)` queries with at most `concurrency` lookups
+    /// in flight, looking up each distinct host once.
+    pub async fn resolve_many(
+        &self,
+        queries: &[(String, u16)],
+        concurrency: usize,
+    ) -> Vec<Result<Vec<SocketAddr>, ResolveError>> {
+        resolve_unique(queries, concurrency, |host| async move {
+            Ok(self.resolve(host, 0).await?.map(|a| a.ip()).collect())
         })
+        .await
+    }
+
+    pub fn block_resolve_many(
+        &self,
+        queries: &[(String, u16)],
+        concurrency: usize,
+    ) -> Result<Vec<Result<Vec<SocketAddr>, ResolveError>>, ResolveError> {
+        block_on(self.resolve_many(queries, concurrency))
     }
 }
 
-pub enum Resolved<A, B, C>
+fn record<T>(state: &ResolverState, literal: bool, result: &Result<T, ResolveError>) {
+    if !literal {
+        state.health.record(0, result.as_ref().map(|_| ()));
+    }
+}
+
+/// Run `fut` to completion from sync code, inside a runtime or not.
+///
+/// `block_in_place` is only allowed on a multi thread runtime, a current
+/// thread runtime would also deadlock waiting on its only thread, so the
+/// lookup runs on `blocking_runtime` from a thread of its own there.
+fn block_on<F>(fut: F) -> Result<F::Output, ResolveError>
+where
+    F: Future + Send,
+    F::Output: Send,
+{
+    match tokio::runtime::Handle::try_current() {
+        Ok(handle) if handle.runtime_flavor() == RuntimeFlavor::MultiThread => {
+            Ok(tokio::task::block_in_place(move || handle.block_on(fut)))
+        }
+        Ok(_) => {
+            let runtime = blocking_runtime()?;
+            std::thread::scope(|scope| scope.spawn(move || runtime.block_on(fut)).join())
+                .map_err(|_| ResolveError::Initialize("resolver thread panicked".to_owned()))
+        }
+        Err(_) => Ok(blocking_runtime()?.block_on(fut)),
+    }
+}
+
+/// Runtime of the lookups made outside a multi thread runtime.
+///
+/// It lives as long as the process, connections the resolver opened and
+/// keeps in its pool have their tasks there.
+fn blocking_runtime() -> Result<&'static tokio::runtime::Runtime, ResolveError> {
+    static RUNTIME: OnceLock<Result<tokio::runtime::Runtime, String>> = OnceLock::new();
+    RUNTIME
+        .get_or_init(|| {
+            tokio::runtime::Builder::new_multi_thread()
+                .worker_threads(1)
+                .thread_name("kapibara-resolve")
+                .enable_all()
+                .build()
+                .map_err(|e| e.to_string())
+        })
+        .as_ref()
+        .map_err(|e| ResolveError::Initialize(e.clone()))
+}
+
+async fn resolve_unique<F, Fut>(
+    queries: &[(String, u16)],
+    concurrency: usize,
+    lookup: F,
+) -> Vec<Result<Vec<SocketAddr>, ResolveError>>
+where
+    F: Fn(String) -> Fut,
+    Fut: Future<Output = Result<Vec<IpAddr>, ResolveError>>,
+{
+    let mut hosts = queries
+        .iter()
+        .filter(|(host, _)| host.parse::<IpAddr>().is_err())
+        .map(|(host, _)| host.clone())
+        .collect::<Vec<_>>();
+    hosts.sort_unstable();
+    hosts.dedup();
+
+    let resolved = stream::iter(hosts)
+        .map(|host| {
+            let fut = lookup(host.clone());
+            async move { (host, fut.await) }
+        })
+        .buffer_unordered(concurrency.max(1))
+        .collect::<HashMap<_, _>>()
+        .await;
+
+    queries
+        .iter()
+        .map(|(host, port)| {
+            if let Ok(ip) = host.parse::<IpAddr>() {
+                return Ok(vec![SocketAddr::new(ip, *port)]);
+            }
+
+            match resolved.get(host) {
+                Some(Ok(ips)) => Ok(ips.iter().map(|ip| SocketAddr::new(*ip, *port)).collect()),
+                Some(Err(err)) => Err(err.duplicate()),
+                None => Err(ResolveError::EmptyResolved),
+            }
+        })
+        .collect()
+}
+
+pub enum Resolved<A, B, C, D>
 where
     A: Iterator<Item = SocketAddr>,
     B: Iterator<Item = SocketAddr>,
     C: Iterator<Item = SocketAddr>,
+    D: Iterator<Item = SocketAddr>,
 {
     Default(A),
     System(B),
     Custom(C),
+    Auto(D),
 }
 
-impl<A, B, C> Iterator for Resolved<A, B, C>
+impl<A, B, C, D> Iterator for Resolved<A, B, C, D>
 where
     A: Iterator<Item = SocketAddr>,
     B: Iterator<Item = SocketAddr>,
     C: Iterator<Item = SocketAddr>,
+    D: Iterator<Item = SocketAddr>,
 {
     type Item = SocketAddr;
 
@@ -144,79 +385,93 @@ where
             Self::Default(s) => s.next(),
             Self::System(s) => s.next(),
             Self::Custom(s) => s.next(),
+            Self::Auto(s) => s.next(),
         }
     }
 }
 
-pub enum SortedResolved<A, B, C, D, E>
+pub enum SortedResolved<A>
 where
     A: Iterator<Item = SocketAddr>,
-    B: Iterator<Item = SocketAddr>,
-    C: Iterator<Item = SocketAddr>,
-    D: Iterator<Item = SocketAddr>,
-    E: Iterator<Item = SocketAddr>,
 {
-    Ipv4AndIpv6(A),
-    Ipv4Only(B),
-    Ipv6Only(C),
-    Ipv4thenIpv6(D),
-    Ipv6thenIpv4(E),
+    AsReturned(A),
+    Sorted(std::vec::IntoIter<SocketAddr>),
 }
 
-impl<A, B, C, D, E> Iterator for SortedResolved<A, B, C, D, E>
+impl<A> Iterator for SortedResolved<A>
 where
     A: Iterator<Item = SocketAddr>,
-    B: Iterator<Item = SocketAddr>,
-    C: Iterator<Item = SocketAddr>,
-    D: Iterator<Item = SocketAddr>,
-    E: Iterator<Item = SocketAddr>,
 {
     type Item = SocketAddr;
 
     fn next(&mut self) -> Option<Self::Item> {
         match self {
-            Self::Ipv4AndIpv6(s) => s.next(),
-            Self::Ipv4Only(s) => s.next(),
-            Self::Ipv6Only(s) => s.next(),
-            Self::Ipv4thenIpv6(s) => s.next(),
-            Self::Ipv6thenIpv4(s) => s.next(),
+            Self::AsReturned(s) => s.next(),
+            Self::Sorted(s) => s.next(),
         }
     }
 }
 
+/// Drop addresses `query` excludes and apply `order`, the same for every resolver.
 pub fn sort_resolved(
     result: impl Iterator<Item = SocketAddr>,
-    strategy: Strategy,
+    query: QueryStrategy,
+    order: OrderStrategy,
 ) -> impl Iterator<Item = SocketAddr> {
-    match strategy {
-        Strategy::Ipv4AndIpv6 => SortedResolved::Ipv4AndIpv6(result),
-        Strategy::Ipv4Only => SortedResolved::Ipv4Only(result.filter(|s| s.is_ipv4())),
-        Strategy::Ipv6Only => SortedResolved::Ipv6Only(result.filter(|s| s.is_ipv6())),
-        Strategy::Ipv4ThenIpv6 => {
-            let (ipv4_addrs, ipv6_addrs): (Vec<_>, Vec<_>) = result.partition(|s| s.is_ipv4());
-            SortedResolved::Ipv4thenIpv6(ipv4_addrs.into_iter().chain(ipv6_addrs))
+    let result = result.filter(move |addr| query.allows(addr));
+    match order {
+        // auto ordering is applied by Resolver::Auto
+        OrderStrategy::AsReturned | OrderStrategy::Auto => SortedResolved::AsReturned(result),
+        OrderStrategy::V4First => {
+            let mut addrs = result.collect::<Vec<_>>();
+            addrs.sort_by_key(|addr| addr.is_ipv6());
+            SortedResolved::Sorted(addrs.into_iter())
         }
-        Strategy::Ipv6ThenIpv4 => {
-            let (ipv6_addrs, ipv4_addrs): (Vec<_>, Vec<_>) = result.partition(|s| s.is_ipv6());
-            SortedResolved::Ipv6thenIpv4(ipv6_addrs.into_iter().chain(ipv4_addrs))
+        OrderStrategy::V6First => {
+            let mut addrs = result.collect::<Vec<_>>();
+            addrs.sort_by_key(|addr| addr.is_ipv4());
+            SortedResolved::Sorted(addrs.into_iter())
+        }
+        OrderStrategy::Interleave => SortedResolved::Sorted(interleave(result).into_iter()),
+    }
+}
+
+/// Alternate address families, starting with the family of the first address.
+pub fn interleave(addrs: impl Iterator<Item = SocketAddr>) -> Vec<SocketAddr> {
+    let mut addrs = addrs.peekable();
+    let Some(lead_v6) = addrs.peek().map(|addr| addr.is_ipv6()) else {
+        return vec![];
+    };
+
+    let (lead, other): (Vec<_>, Vec<_>) = addrs.partition(|addr| addr.is_ipv6() == lead_v6);
+    let mut result = Vec::with_capacity(lead.len() + other.len());
+    let (mut lead, mut other) = (lead.into_iter(), other.into_iter());
+    loop {
+        match (lead.next(), other.next()) {
+            (None, None) => return result,
+            (a, b) => result.extend(a.into_iter().chain(b)),
         }
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::net::Ipv6Addr;
+
     use super::*;
 
-    use crate::dns::option::{NameServerOption, Protocol, Strategy};
+    use crate::dns::option::{NameServerOption, Protocol};
 
     #[tokio::test]
     async fn test_dns_resolve() -> Result<(), ResolveError> {
-        let mut dns_option = ResolveOption::default();
-        dns_option.strategy = Strategy::Ipv4ThenIpv6;
-        dns_option.servers = vec![NameServerOption {
-            address: "8.8.8.8:53".parse().unwrap(),
-            protocol: Protocol::Udp,
-        }];
+        let dns_option = ResolveOption {
+            order: OrderStrategy::V4First,
+            servers: vec![NameServerOption {
+                address: "8.8.8.8:53".parse().unwrap(),
+                protocol: Protocol::Udp,
+            }],
+            ..Default::default()
+        };
 
         let resolver = Resolver::new(dns_option.clone());
         let result: Vec<_> = tokio::runtime::Handle::current()
@@ -232,4 +487,400 @@ mod tests {
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn test_resolve_unique() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let queries = vec![
+            ("a.test".to_owned(), 80),
+            ("b.test".to_owned(), 80),
+            ("a.test".to_owned(), 443),
+            ("127.0.0.1".to_owned(), 8080),
+            ("missing.test".to_owned(), 80),
+            ("missing.test".to_owned(), 443),
+        ];
+
+        let lookups = AtomicUsize::new(0);
+        let result = resolve_unique(&queries, 2, |host| {
+            lookups.fetch_add(1, Ordering::SeqCst);
+            async move {
+                match host.as_str() {
+                    "a.test" => Ok(vec!["10.0.0.1".parse().unwrap()]),
+                    "b.test" => Ok(vec!["10.0.0.2".parse().unwrap()]),
+                    _ => Err(ResolveError::EmptyResolved),
+                }
+            }
+        })
+        .await;
+
+        assert_eq!(lookups.load(Ordering::SeqCst), 3);
+        assert_eq!(
+            result[0].as_ref().unwrap(),
+            &["10.0.0.1:80".parse().unwrap()]
+        );
+        assert_eq!(
+            result[1].as_ref().unwrap(),
+            &["10.0.0.2:80".parse().unwrap()]
+        );
+        assert_eq!(
+            result[2].as_ref().unwrap(),
+            &["10.0.0.1:443".parse().unwrap()]
+        );
+        assert_eq!(
+            result[3].as_ref().unwrap(),
+            &["127.0.0.1:8080".parse().unwrap()]
+        );
+        assert!(result[4].is_err() && result[5].is_err());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_init_many() {
+        use crate::{option::ClientOption, tcp::TcpClientOption, TransportClient};
+
+        // no host to look up in the middle, the options after it keep their results
+        let opts = ["localhost", "localhost", "", "127.0.0.1"]
+            .into_iter()
+            .map(|addr| crate::TransportClientOption {
+                opt: ClientOption::Tcp(TcpClientOption {
+                    addr: addr.to_owned(),
+                    port: 80,
+                    tcp_nodelay: false,
+                    connect_timeout: None,
+                    connect_deadline: None,
+                    attempt_delay: None,
+                    resolve_on_connect: false,
+                    proxy: None,
+                    bind_addr: None,
+                    bind_device: None,
+                }),
+                ..Default::default()
+            })
+            .chain([Default::default()])
+            .collect::<Vec<_>>();
+
+        let clients = TransportClient::init_many(opts, &Resolver::default());
+        assert_eq!(clients.len(), 5);
+        assert!(clients[2].is_err());
+        assert!([0, 1, 3, 4].iter().all(|&i| clients[i].is_ok()));
+        assert_eq!(clients[4].as_ref().unwrap().name(), "Empty");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_auto_strategy() {
+        use crate::dns::probe::Families;
+
+        #[derive(Debug)]
+        struct Ipv6Only;
+
+        impl FamilyProbe for Ipv6Only {
+            fn probe(&self) -> Families {
+                Families {
+                    ipv4: false,
+                    ipv6: true,
+                }
+            }
+        }
+
+        let opt = ResolveOption {
+            order: OrderStrategy::Auto,
+            ..Default::default()
+        };
+        let resolver = Resolver::with_probe(opt, Arc::new(Ipv6Only));
+        assert!(matches!(resolver, Resolver::Auto(..)));
+
+        let result = resolver.resolve("localhost", 80).await.unwrap();
+        let result = result.collect::<Vec<_>>();
+        assert!(!result.is_empty());
+        let first_v4 = result
+            .iter()
+            .position(|a| a.is_ipv4())
+            .unwrap_or(result.len());
+        assert!(result[first_v4..].iter().all(|a| a.is_ipv4()));
+        resolver.flush_cache();
+    }
+
+    #[test]
+    fn test_sort_resolved() {
+        let addrs = ["10.0.0.1", "10.0.0.2", "::1", "10.0.0.3", "::2"]
+            .iter()
+            .map(|ip| SocketAddr::new(ip.parse().unwrap(), 80))
+            .collect::<Vec<_>>();
+        let sorted = |query, order| {
+            sort_resolved(addrs.clone().into_iter(), query, order)
+                .map(|addr| addr.ip().to_string())
+                .collect::<Vec<_>>()
+        };
+
+        use OrderStrategy::*;
+        use QueryStrategy::*;
+        assert_eq!(
+            sorted(Both, AsReturned),
+            ["10.0.0.1", "10.0.0.2", "::1", "10.0.0.3", "::2"]
+        );
+        assert_eq!(
+            sorted(Both, V4First),
+            ["10.0.0.1", "10.0.0.2", "10.0.0.3", "::1", "::2"]
+        );
+        assert_eq!(
+            sorted(Both, V6First),
+            ["::1", "::2", "10.0.0.1", "10.0.0.2", "10.0.0.3"]
+        );
+        assert_eq!(
+            sorted(Both, Interleave),
+            ["10.0.0.1", "::1", "10.0.0.2", "::2", "10.0.0.3"]
+        );
+        assert_eq!(sorted(V6Only, Interleave), ["::1", "::2"]);
+        assert_eq!(
+            sorted(V4Only, V6First),
+            ["10.0.0.1", "10.0.0.2", "10.0.0.3"]
+        );
+
+        let v6_lead = interleave(addrs.iter().rev().copied())
+            .iter()
+            .map(|addr| addr.ip().to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(v6_lead, ["::2", "10.0.0.3", "::1", "10.0.0.2", "10.0.0.1"]);
+        assert!(interleave(std::iter::empty()).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_nat64_strategy() {
+        use crate::dns::{AddrOrigin, PostProcess};
+
+        let resolve = |query| async move {
+            let resolver = Resolver::new(ResolveOption {
+                query,
+                post_process: Some(PostProcess::Nat64 {
+                    prefix: "64:ff9b::".parse().unwrap(),
+                    prefix_len: 96,
+                    replace: false,
+                }),
+                ..Default::default()
+            });
+            resolver.resolve_detailed("192.0.2.33", 80).await.unwrap()
+        };
+
+        // synthesis runs after the query filter, v6 only drops the answer first
+        let resolved = resolve(QueryStrategy::V4Only).await;
+        assert_eq!(
+            resolved,
+            [
+                ResolvedAddr {
+                    addr: "[64:ff9b::c000:221]:80".parse().unwrap(),
+                    origin: AddrOrigin::Nat64,
+                },
+                ResolvedAddr::answer("192.0.2.33:80".parse().unwrap()),
+            ]
+        );
+        assert_eq!(resolve(QueryStrategy::Both).await, resolved);
+        assert!(resolve(QueryStrategy::V6Only).await.is_empty());
+    }
+
+    /// Dns server on udp answering every A query with the current `ip`,
+    /// uncached, and nothing else. Runs on a thread, a blocked test runtime
+    /// does not stop it.
+    fn a_record_server(ip: Arc<std::sync::Mutex<std::net::Ipv4Addr>>) -> SocketAddr {
+        use hickory_resolver::proto::{
+            op::{Message, MessageType},
+            rr::{rdata::A, RData, Record, RecordType},
+        };
+
+        let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+        std::thread::spawn(move || {
+            let mut buf = [0u8; 512];
+            while let Ok((n, peer)) = socket.recv_from(&mut buf) {
+                let Ok(query) = Message::from_vec(&buf[..n]) else {
+                    continue;
+                };
+                let mut reply = Message::new();
+                reply
+                    .set_id(query.id())
+                    .set_message_type(MessageType::Response)
+                    .set_recursion_desired(true)
+                    .set_recursion_available(true);
+                for q in query.queries() {
+                    reply.add_query(q.clone());
+                    if q.query_type() == RecordType::A {
+                        let ip = *ip.lock().unwrap();
+                        let record = Record::from_rdata(q.name().clone(), 0, RData::A(A(ip)));
+                        reply.add_answer(record);
+                    }
+                }
+                let _ = socket.send_to(&reply.to_vec().unwrap(), peer);
+            }
+        });
+        addr
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_nat64_connect() {
+        use tokio::net::TcpListener;
+
+        use crate::{
+            dns::{option::NameServerOption, PostProcess},
+            tcp::{TcpClient, TcpClientOption},
+            TransportClientTrait,
+        };
+
+        // under ::/96 the v4 only answer 0.0.0.1 becomes the v6 loopback
+        let listener = TcpListener::bind("[::1]:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = a_record_server(Arc::new(std::sync::Mutex::new([0, 0, 0, 1].into())));
+        let resolver = Resolver::new(ResolveOption {
+            query: QueryStrategy::V4Only,
+            servers: vec![NameServerOption {
+                address: server,
+                protocol: Protocol::Udp,
+            }],
+            post_process: Some(PostProcess::Nat64 {
+                prefix: Ipv6Addr::UNSPECIFIED,
+                prefix_len: 96,
+                replace: true,
+            }),
+            ..Default::default()
+        });
+
+        let opt = TcpClientOption {
+            addr: "v4only.test".into(),
+            port,
+            tcp_nodelay: false,
+            connect_timeout: None,
+            connect_deadline: None,
+            attempt_delay: None,
+            resolve_on_connect: false,
+            proxy: None,
+            bind_addr: None,
+            bind_device: None,
+        };
+        let cli = TcpClient::init(opt, None, &resolver).unwrap();
+        let accept = tokio::spawn(async move { listener.accept().await.unwrap().1 });
+        let stream = cli.connect().await.unwrap();
+        drop(stream);
+        assert!(accept.await.unwrap().is_ipv6());
+    }
+
+    #[tokio::test]
+    async fn test_resolve_on_connect() {
+        use tokio::net::TcpListener;
+
+        use crate::{
+            dns::option::NameServerOption,
+            tcp::{TcpClient, TcpClientOption},
+            TransportClientTrait,
+        };
+
+        let old = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = old.local_addr().unwrap().port();
+        let new = TcpListener::bind(("127.0.0.2", port)).await.unwrap();
+        let ip = Arc::new(std::sync::Mutex::new([127, 0, 0, 1].into()));
+        let server = a_record_server(ip.clone());
+        let resolver = Resolver::new(ResolveOption {
+            query: QueryStrategy::V4Only,
+            servers: vec![NameServerOption {
+                address: server,
+                protocol: Protocol::Udp,
+            }],
+            ..Default::default()
+        });
+
+        let opt = TcpClientOption {
+            addr: "moving.test".into(),
+            port,
+            tcp_nodelay: false,
+            connect_timeout: None,
+            connect_deadline: None,
+            attempt_delay: None,
+            resolve_on_connect: true,
+            proxy: None,
+            bind_addr: None,
+            bind_device: None,
+        };
+        let cli = TcpClient::init(opt, None, &resolver).unwrap();
+        let _stream = cli.connect().await.unwrap();
+        tokio::time::timeout(Duration::from_secs(2), old.accept())
+            .await
+            .unwrap()
+            .unwrap();
+
+        // the record moved, the next connect follows it
+        *ip.lock().unwrap() = [127, 0, 0, 2].into();
+        let _stream = cli.connect().await.unwrap();
+        tokio::time::timeout(Duration::from_secs(2), new.accept())
+            .await
+            .unwrap()
+            .unwrap();
+    }
+
+    /// Init resolves blocking, also from a current thread runtime.
+    #[tokio::test]
+    async fn test_block_resolve_current_thread() {
+        use tokio::net::TcpListener;
+
+        use crate::{
+            dns::option::NameServerOption,
+            tcp::{TcpClient, TcpClientOption},
+            TransportClientTrait,
+        };
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = a_record_server(Arc::new(std::sync::Mutex::new([127, 0, 0, 1].into())));
+        let resolver = Resolver::new(ResolveOption {
+            query: QueryStrategy::V4Only,
+            servers: vec![NameServerOption {
+                address: server,
+                protocol: Protocol::Udp,
+            }],
+            ..Default::default()
+        });
+
+        let opt = TcpClientOption {
+            addr: "blocking.test".into(),
+            port,
+            tcp_nodelay: false,
+            connect_timeout: None,
+            connect_deadline: None,
+            attempt_delay: None,
+            resolve_on_connect: false,
+            proxy: None,
+            bind_addr: None,
+            bind_device: None,
+        };
+        let cli = TcpClient::init(opt, None, &resolver).unwrap();
+        let _stream = cli.connect().await.unwrap();
+        listener.accept().await.unwrap();
+
+        // the resolver keeps working on the test runtime after the blocking lookup
+        let addrs = resolver.resolve("blocking.test", port).await.unwrap();
+        assert_eq!(addrs.collect::<Vec<_>>(), [listener.local_addr().unwrap()]);
+        let many = resolver.block_resolve_many(&[("blocking.test".into(), 0)], 1);
+        let many = many.unwrap().remove(0).unwrap();
+        assert_eq!(many[0].ip(), IpAddr::from([127, 0, 0, 1]));
+    }
+
+    #[test]
+    fn test_strategy_compat() {
+        let opt: ResolveOption = serde_json::from_str(r#"{"strategy": "ipv6_only"}"#).unwrap();
+        assert_eq!(
+            (opt.query, opt.order),
+            (QueryStrategy::V6Only, OrderStrategy::AsReturned)
+        );
+
+        let opt: ResolveOption =
+            serde_json::from_str(r#"{"strategy": "ipv6_then_ipv4", "order": "interleave"}"#)
+                .unwrap();
+        assert_eq!(
+            (opt.query, opt.order),
+            (QueryStrategy::Both, OrderStrategy::Interleave)
+        );
+
+        let opt: ResolveOption = serde_json::from_str("{}").unwrap();
+        assert_eq!(
+            (opt.query, opt.order),
+            (QueryStrategy::Both, OrderStrategy::V4First)
+        );
+        assert_eq!(opt.degraded_threshold, 3);
+    }
 }
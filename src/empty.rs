@@ -1,6 +1,31 @@
 //! Empty Client
+//!
+//! Besides the null `EmptyClient`, synthetic transports moving bytes at memory
+//! speed, to benchmark everything above the transport.
 
-use crate::{ClientResult, TransportClientTrait};
+use std::{
+    future::Future,
+    io::ErrorKind,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll, Waker},
+    time::Duration,
+};
+
+use futures_util::ready;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::{
+    clock::TokioClock,
+    context::{AcceptHook, ConnContext},
+    io::{throttle::Gate, Budget, RateLimiter},
+    limit::ByteCount,
+    stats::{ServerStats, ServerStatsSnapshot},
+    ClientResult, ServerResult, TransportClientTrait, TransportServerCallback,
+    TransportServerTrait,
+};
 
 pub struct EmptyClient;
 
@@ -13,3 +38,507 @@ impl TransportClientTrait for EmptyClient {
         Ok(tokio::io::empty())
     }
 }
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct BlackholeOption {
+    /// Accept writes at this many bytes per second, unlimited by default.
+    #[serde(default)]
+    pub rate: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct GeneratorOption {
+    /// Bytes returned by one read at most.
+    #[serde(default = "default_chunk_size")]
+    pub chunk_size: usize,
+    /// Bytes produced before EOF, endless by default.
+    #[serde(default)]
+    pub limit: Option<u64>,
+}
+
+fn default_chunk_size() -> usize {
+    16 * 1024
+}
+
+impl Default for GeneratorOption {
+    fn default() -> Self {
+        Self {
+            chunk_size: default_chunk_size(),
+            limit: None,
+        }
+    }
+}
+
+/// Byte at `offset` of every generated stream.
+///
+/// The period is prime so it never lines up with power of two chunks.
+pub fn generator_byte(offset: u64) -> u8 {
+    (offset % 251) as u8
+}
+
+/// Accepts every write, never produces a read until shut down.
+pub struct BlackholeStream {
+    count: ByteCount,
+    rate: Option<u64>,
+    gate: Gate<TokioClock>,
+    shutdown: bool,
+    reader: Option<Waker>,
+    /// Unthrottled writes never wait, they yield now and then instead.
+    budget: Budget,
+}
+
+impl std::fmt::Debug for BlackholeStream {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("BlackholeStream")
+            .field("count", &self.count)
+            .field("rate", &self.rate)
+            .field("shutdown", &self.shutdown)
+            .finish()
+    }
+}
+
+impl BlackholeStream {
+    pub fn new(opt: BlackholeOption, count: ByteCount) -> Self {
+        Self {
+            count,
+            rate: opt.rate,
+            gate: Gate::new(
+                opt.rate
+                    .map(|rate| Arc::new(RateLimiter::paced(rate, TokioClock)))
+                    .into_iter()
+                    .collect(),
+                TokioClock,
+            ),
+            shutdown: false,
+            reader: None,
+            budget: Budget::default(),
+        }
+    }
+}
+
+impl AsyncRead for BlackholeStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        _buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        if this.shutdown {
+            return Poll::Ready(Ok(()));
+        }
+        this.reader = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+impl AsyncWrite for BlackholeStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        if this.shutdown {
+            return Poll::Ready(Err(ErrorKind::BrokenPipe.into()));
+        }
+        let n = ready!(this.gate.poll_grant(cx, buf.len()));
+        ready!(this.budget.poll_proceed(cx));
+
+        this.gate.consume(n);
+        this.count.add_tx(n);
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        this.shutdown = true;
+        if let Some(waker) = this.reader.take() {
+            waker.wake();
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Reads the `generator_byte` pattern, discards writes.
+///
+/// Neither ever waits, both yield every `io::budget::POLL_BUDGET` calls.
+#[derive(Debug)]
+pub struct GeneratorStream {
+    count: ByteCount,
+    opt: GeneratorOption,
+    offset: u64,
+    budget: Budget,
+}
+
+impl GeneratorStream {
+    pub fn new(opt: GeneratorOption, count: ByteCount) -> Self {
+        Self {
+            count,
+            opt,
+            offset: 0,
+            budget: Budget::default(),
+        }
+    }
+}
+
+impl AsyncRead for GeneratorStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.budget.poll_proceed(cx));
+        let mut n = buf.remaining().min(this.opt.chunk_size.max(1));
+        if let Some(limit) = this.opt.limit {
+            n = n.min(limit.saturating_sub(this.offset) as usize);
+        }
+
+        let start = this.offset;
+        for (i, byte) in buf.initialize_unfilled_to(n).iter_mut().enumerate() {
+            *byte = generator_byte(start + i as u64);
+        }
+        buf.advance(n);
+        this.offset += n as u64;
+        this.count.add_rx(n);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for GeneratorStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.budget.poll_proceed(cx));
+        this.count.add_tx(buf.len());
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Client of `BlackholeStream`s, counting the bytes of all of them.
+pub struct BlackholeClient {
+    opt: BlackholeOption,
+    count: ByteCount,
+}
+
+impl BlackholeClient {
+    pub fn new(opt: BlackholeOption) -> Self {
+        Self {
+            opt,
+            count: ByteCount::default(),
+        }
+    }
+
+    /// Bytes written to every stream so far, including dropped ones.
+    pub fn count(&self) -> ByteCount {
+        self.count.clone()
+    }
+}
+
+impl TransportClientTrait for BlackholeClient {
+    type Stream = BlackholeStream;
+
+    async fn connect(&self) -> ClientResult<Self::Stream> {
+        Ok(BlackholeStream::new(self.opt, self.count.clone()))
+    }
+}
+
+/// Client of `GeneratorStream`s, counting the bytes of all of them.
+pub struct GeneratorClient {
+    opt: GeneratorOption,
+    count: ByteCount,
+}
+
+impl GeneratorClient {
+    pub fn new(opt: GeneratorOption) -> Self {
+        Self {
+            opt,
+            count: ByteCount::default(),
+        }
+    }
+
+    /// Bytes read from and written to every stream so far, including dropped ones.
+    pub fn count(&self) -> ByteCount {
+        self.count.clone()
+    }
+}
+
+impl TransportClientTrait for GeneratorClient {
+    type Stream = GeneratorStream;
+
+    async fn connect(&self) -> ClientResult<Self::Stream> {
+        Ok(GeneratorStream::new(self.opt, self.count.clone()))
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct GeneratorServerOption {
+    #[serde(default)]
+    pub generator: GeneratorOption,
+    /// Streams handed to the callback at once.
+    pub connections: usize,
+}
+
+/// Runs the callback on generated streams instead of accepted connections.
+///
+/// `serve` returns once every callback returned.
+pub struct GeneratorServer {
+    opt: GeneratorServerOption,
+    count: ByteCount,
+    stats: Arc<ServerStats>,
+    accept_hooks: Vec<AcceptHook>,
+}
+
+/// Peer address reported for generated streams.
+const GENERATOR_PEER: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0);
+
+impl GeneratorServer {
+    pub fn init(opt: GeneratorServerOption) -> ServerResult<Self> {
+        Ok(Self {
+            opt,
+            count: ByteCount::default(),
+            stats: Arc::new(ServerStats::new(GENERATOR_PEER)),
+            accept_hooks: vec![],
+        })
+    }
+
+    pub fn add_accept_hook(&mut self, hook: AcceptHook) {
+        self.accept_hooks.push(hook);
+    }
+
+    pub fn stats(&self) -> ServerStatsSnapshot {
+        self.stats.snapshot()
+    }
+
+    /// Bytes moved by every stream so far.
+    pub fn count(&self) -> ByteCount {
+        self.count.clone()
+    }
+}
+
+impl TransportServerTrait for GeneratorServer {
+    fn local_addr(&self) -> Option<SocketAddr> {
+        None
+    }
+
+    async fn serve<C: TransportServerCallback>(&self, callback: C) -> ServerResult<()> {
+        let mut tasks = self.spawn(callback);
+        while let Some(res) = tasks.join_next().await {
+            log_join(res);
+        }
+        Ok(())
+    }
+
+    /// There is no listener to close, the signal only starts the grace period.
+    async fn serve_with_shutdown<C, F>(
+        &self,
+        callback: C,
+        signal: F,
+        grace: Duration,
+    ) -> ServerResult<()>
+    where
+        C: TransportServerCallback,
+        F: Future<Output = ()> + Send + Sync,
+    {
+        let mut tasks = self.spawn(callback);
+        let join_all = async {
+            while let Some(res) = tasks.join_next().await {
+                log_join(res);
+            }
+        };
+        tokio::pin!(join_all);
+        tokio::select! {
+            _ = &mut join_all => return Ok(()),
+            _ = signal => {}
+        }
+        // the rest is aborted when the set drops
+        let _ = tokio::time::timeout(grace, join_all).await;
+        Ok(())
+    }
+}
+
+fn log_join(res: Result<(), tokio::task::JoinError>) {
+    if let Err(e) = res {
+        log::warn!("generator callback failed: {}", e);
+    }
+}
+
+impl GeneratorServer {
+    fn spawn<C: TransportServerCallback>(&self, callback: C) -> tokio::task::JoinSet<()> {
+        let mut tasks = tokio::task::JoinSet::new();
+        for _ in 0..self.opt.connections {
+            let mut ctx = ConnContext::new(None, None).with_transport("generator");
+            if !ctx.run_hooks(&self.accept_hooks) {
+                continue;
+            }
+
+            let stream = GeneratorStream::new(self.opt.generator, self.count.clone());
+            let stream = self.stats.track(stream, &GENERATOR_PEER);
+            let callback = callback.clone();
+            tasks.spawn(async move { callback.handle_ctx(stream, ctx).await });
+        }
+
+        tasks
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::atomic::{AtomicUsize, Ordering},
+        time::Duration,
+    };
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        time::Instant,
+    };
+
+    use super::*;
+    use crate::{
+        option::{ClientOption, LatencyProfile, ServerOption},
+        TransportClient, TransportClientOption, TransportServer, TransportServerOption,
+    };
+
+    #[tokio::test]
+    async fn test_generator_pattern() {
+        let mut read = vec![];
+        for chunk_size in [1, 100, 4096] {
+            let cli = GeneratorClient::new(GeneratorOption {
+                chunk_size,
+                limit: Some(10_000),
+            });
+            let mut s = cli.connect().await.unwrap();
+            let mut buf = vec![];
+            s.read_to_end(&mut buf).await.unwrap();
+            s.write_all(b"discarded").await.unwrap();
+            drop(s);
+
+            assert_eq!(cli.count().rx(), 10_000);
+            assert_eq!(cli.count().tx(), 9);
+            read.push(buf);
+        }
+        assert!(read.iter().all(|buf| *buf == read[0]));
+        assert!(read[0]
+            .iter()
+            .enumerate()
+            .all(|(i, b)| *b == generator_byte(i as u64)));
+
+        // chunks cap every read
+        let cli = GeneratorClient::new(GeneratorOption {
+            chunk_size: 100,
+            limit: None,
+        });
+        let mut s = cli.connect().await.unwrap();
+        let mut buf = [0u8; 1000];
+        assert_eq!(s.read(&mut buf).await.unwrap(), 100);
+        assert_eq!(s.read(&mut buf).await.unwrap(), 100);
+        assert_eq!(buf[0], generator_byte(100));
+    }
+
+    #[tokio::test]
+    async fn test_blackhole_count() {
+        let trans_opt = TransportClientOption {
+            opt: ClientOption::Blackhole(BlackholeOption { rate: None }),
+            ..Default::default()
+        };
+        let TransportClient::Blackhole(cli) =
+            TransportClient::init_with_default_resolver(trans_opt).unwrap()
+        else {
+            panic!("not a blackhole client");
+        };
+
+        for _ in 0..3 {
+            let mut s = cli.connect().await.unwrap();
+            s.write_all(&[7u8; 1000]).await.unwrap();
+            assert!(
+                tokio::time::timeout(Duration::from_millis(20), s.read(&mut [0u8; 8]))
+                    .await
+                    .is_err()
+            );
+        }
+        assert_eq!(cli.count().tx(), 3000);
+        assert_eq!(cli.count().rx(), 0);
+
+        // a shut down blackhole reads EOF
+        let (mut rd, mut wr) = tokio::io::split(cli.connect().await.unwrap());
+        let reader = tokio::spawn(async move { rd.read(&mut [0u8; 8]).await.unwrap() });
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        wr.shutdown().await.unwrap();
+        assert_eq!(reader.await.unwrap(), 0);
+        assert!(wr.write_all(b"late").await.is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_blackhole_rate() {
+        let cli = BlackholeClient::new(BlackholeOption { rate: Some(10_000) });
+        let mut s = cli.connect().await.unwrap();
+        let start = Instant::now();
+        // paced from the first byte
+        for _ in 0..3 {
+            s.write_all(&[0u8; 500]).await.unwrap();
+        }
+        assert!(start.elapsed() >= Duration::from_millis(100));
+        assert_eq!(cli.count().tx(), 1500);
+    }
+
+    #[derive(Clone)]
+    struct DrainCallback(Arc<AtomicUsize>);
+
+    impl TransportServerCallback for DrainCallback {
+        async fn handle<S>(&self, mut stream: S, addr: Option<SocketAddr>)
+        where
+            S: AsyncRead + AsyncWrite + Unpin + Send + Sync,
+        {
+            assert_eq!(addr, None);
+            let mut buf = vec![];
+            stream.read_to_end(&mut buf).await.unwrap();
+            stream.write_all(&buf[..10]).await.unwrap();
+            self.0.fetch_add(buf.len(), Ordering::Relaxed);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_generator_server() {
+        let trans_opt = TransportServerOption {
+            opt: ServerOption::Generator(GeneratorServerOption {
+                generator: GeneratorOption {
+                    chunk_size: 256,
+                    limit: Some(1000),
+                },
+                connections: 8,
+            }),
+            tls: None,
+            latency_profile: LatencyProfile::Throughput,
+            policy: Default::default(),
+            version: crate::option::CONFIG_VERSION,
+        };
+        let srv = TransportServer::init(trans_opt).unwrap();
+        assert_eq!(srv.local_addr(), None);
+
+        let total = Arc::new(AtomicUsize::new(0));
+        srv.serve(DrainCallback(total.clone())).await.unwrap();
+        assert_eq!(total.load(Ordering::Relaxed), 8000);
+
+        let TransportServer::Generator(srv) = srv else {
+            panic!("not a generator server");
+        };
+        assert_eq!(srv.count().rx(), 8000);
+        assert_eq!(srv.count().tx(), 80);
+    }
+}
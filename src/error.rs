@@ -1,8 +1,12 @@
 //! Kapibara Error Handle
 
+use std::io::ErrorKind;
+
+use serde::Serialize;
 use thiserror::Error;
+use tokio_tungstenite::tungstenite::Error as WsError;
 
-use crate::{ResolveError, TlsError};
+use crate::{net::DialError, ResolveError, TlsError};
 
 #[derive(Debug, Error)]
 pub enum ClientError {
@@ -16,6 +20,62 @@ pub enum ClientError {
     Option(String),
     #[error("connect error ({0})")]
     Connect(String),
+    #[error("connect denied ({0})")]
+    Denied(String),
+    /// The circuit breaker of the destination fails connects until `until`.
+    #[error("connect circuit open")]
+    CircuitOpen { until: tokio::time::Instant },
+    #[error("websocket error ({0})")]
+    Ws(#[source] Box<WsError>),
+}
+
+impl ClientError {
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            Self::Io(e) => ErrorCode::of_io(e),
+            Self::Dns(e) => e.code(),
+            Self::Tls(e) => e.code(),
+            Self::Option(_) => ErrorCode::OptionInvalid,
+            Self::Connect(_) => ErrorCode::ConnectFailed,
+            Self::Denied(_) => ErrorCode::ConnectDenied,
+            Self::CircuitOpen { .. } => ErrorCode::ConnectCircuitOpen,
+            Self::Ws(e) => ErrorCode::of_ws(e),
+        }
+    }
+
+    pub fn to_structured(&self) -> StructuredError {
+        StructuredError::new(self, self.code())
+    }
+
+    /// Whether connecting again may succeed: io failures, timeouts and
+    /// transient dns or server errors. Invalid options, denied targets and
+    /// rejected certificates fail the same way every time, an open circuit
+    /// until it is half open.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Self::Option(_) | Self::Denied(_) | Self::CircuitOpen { .. } => false,
+            _ => self.code().retryable(),
+        }
+    }
+}
+
+impl From<WsError> for ClientError {
+    fn from(e: WsError) -> Self {
+        Self::Ws(Box::new(e))
+    }
+}
+
+impl From<DialError> for ClientError {
+    fn from(e: DialError) -> Self {
+        let message = e.to_string();
+        match e {
+            DialError::NoAddress => Self::Dns(ResolveError::EmptyResolved),
+            // keeps the kind, the error code tells refused from timed out
+            DialError::Failed { last, .. } => Self::Io(std::io::Error::new(last.kind(), message)),
+            DialError::Bind { .. } => Self::Option(message),
+            DialError::CircuitOpen { until } => Self::CircuitOpen { until },
+        }
+    }
 }
 
 #[derive(Debug, Error)]
@@ -31,18 +91,512 @@ pub enum ServerError {
 }
 
 impl ServerError {
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            Self::Io(e) => ErrorCode::of_io(e),
+            Self::Tls(e) => e.code(),
+            Self::Option(_) => ErrorCode::OptionInvalid,
+            Self::Serve(_) => ErrorCode::ServeFailed,
+        }
+    }
+
+    pub fn to_structured(&self) -> StructuredError {
+        StructuredError::new(self, self.code())
+    }
+
     pub fn is_closed(&self) -> bool {
         if let ServerError::Io(err) = self {
-            match err.kind() {
+            matches!(
+                err.kind(),
                 std::io::ErrorKind::UnexpectedEof
-                | std::io::ErrorKind::ConnectionAborted
-                | std::io::ErrorKind::NotConnected
-                | std::io::ErrorKind::BrokenPipe
-                | std::io::ErrorKind::ConnectionReset => true,
-                _ => false,
-            }
+                    | std::io::ErrorKind::ConnectionAborted
+                    | std::io::ErrorKind::NotConnected
+                    | std::io::ErrorKind::BrokenPipe
+                    | std::io::ErrorKind::ConnectionReset
+            )
         } else {
             false
         }
     }
 }
+
+/// Stable machine readable error code, see `as_str` for the mapping.
+#[non_exhaustive]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ErrorCode {
+    /// `connect.refused`, io `ConnectionRefused`.
+    ConnectRefused,
+    /// `connect.reset`, io `ConnectionReset`.
+    ConnectReset,
+    /// `connect.aborted`, io `ConnectionAborted`.
+    ConnectAborted,
+    /// `connect.timeout`, io `TimedOut`.
+    ConnectTimeout,
+    /// `connect.addr_unavailable`, io `AddrNotAvailable`.
+    ConnectAddrUnavailable,
+    /// `connect.failed`, `ClientError::Connect` such as a failed post-connect probe.
+    ConnectFailed,
+    /// `connect.denied`, `ClientError::Denied`, no address passed the `on_resolved` hook.
+    ConnectDenied,
+    /// `connect.circuit_open`, `ClientError::CircuitOpen`, the destination kept failing.
+    ConnectCircuitOpen,
+    /// `io.closed`, io `BrokenPipe`, `UnexpectedEof` and `NotConnected`.
+    IoClosed,
+    /// `io.addr_in_use`, io `AddrInUse`.
+    IoAddrInUse,
+    /// `io.permission_denied`, io `PermissionDenied`.
+    IoPermissionDenied,
+    /// `io.not_found`, io `NotFound`, e.g. a missing certificate file.
+    IoNotFound,
+    /// `io.other`, any other io error kind.
+    Io,
+    /// `dns.empty`, `ResolveError::EmptyResolved`.
+    DnsEmpty,
+    /// `dns.timeout`, a resolve or upstream timeout.
+    DnsTimeout,
+    /// `dns.no_records`, no record for the name.
+    DnsNoRecords,
+    /// `dns.init`, `ResolveError::Initialize`.
+    DnsInit,
+    /// `dns.protocol`, malformed or failed upstream exchange.
+    DnsProtocol,
+    /// `dns.other`, any other resolver error.
+    Dns,
+    /// `tls.cert_invalid`, unparsable or rejected certificate.
+    TlsCertInvalid,
+    /// `tls.cert_unknown_issuer`, chain not anchored in a trusted root.
+    TlsCertUnknownIssuer,
+    /// `tls.cert_name_mismatch`, certificate not valid for the server name.
+    TlsCertNameMismatch,
+    /// `tls.cert_expired`, certificate outside its validity period.
+    TlsCertExpired,
+    /// `tls.key_invalid`, `TlsError::InvalidKey`.
+    TlsKeyInvalid,
+    /// `tls.ticket_key_invalid`, `TlsError::InvalidTicketKey`.
+    TlsTicketKeyInvalid,
+    /// `tls.alert`, fatal alert from the peer.
+    TlsAlert,
+    /// `tls.no_alpn`, no common alpn protocol.
+    TlsNoAlpn,
+    /// `tls.protocol`, peer misbehaved or sent an unexpected message.
+    TlsProtocol,
+    /// `tls.not_tls`, `TlsError::NotTls`.
+    TlsNotTls,
+    /// `tls.insecure_disabled`, `TlsError::InsecureDisabled`.
+    TlsInsecureDisabled,
+    /// `tls.config_invalid`, `TlsError::InvalidConfig`.
+    TlsConfigInvalid,
+    /// `tls.other`, any other tls error.
+    Tls,
+    /// `ws.handshake_status_<status>`, upgrade answered with a non 101 status.
+    ///
+    /// Statuses without their own code map to `ws.handshake_status_other`.
+    WsHandshakeStatus(u16),
+    /// `ws.protocol`, websocket protocol violation.
+    WsProtocol,
+    /// `ws.capacity`, message or frame over the size limit.
+    WsCapacity,
+    /// `ws.invalid_utf8`, Text message with invalid UTF-8.
+    WsInvalidUtf8,
+    /// `ws.other`, any other websocket error.
+    Ws,
+    /// `option.invalid`, `ClientError::Option` and `ServerError::Option`.
+    OptionInvalid,
+    /// `option.secret`, a secret file or env var could not be read.
+    OptionSecret,
+    /// `serve.failed`, `ServerError::Serve`.
+    ServeFailed,
+}
+
+impl ErrorCode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::ConnectRefused => "connect.refused",
+            Self::ConnectReset => "connect.reset",
+            Self::ConnectAborted => "connect.aborted",
+            Self::ConnectTimeout => "connect.timeout",
+            Self::ConnectAddrUnavailable => "connect.addr_unavailable",
+            Self::ConnectFailed => "connect.failed",
+            Self::ConnectDenied => "connect.denied",
+            Self::ConnectCircuitOpen => "connect.circuit_open",
+            Self::IoClosed => "io.closed",
+            Self::IoAddrInUse => "io.addr_in_use",
+            Self::IoPermissionDenied => "io.permission_denied",
+            Self::IoNotFound => "io.not_found",
+            Self::Io => "io.other",
+            Self::DnsEmpty => "dns.empty",
+            Self::DnsTimeout => "dns.timeout",
+            Self::DnsNoRecords => "dns.no_records",
+            Self::DnsInit => "dns.init",
+            Self::DnsProtocol => "dns.protocol",
+            Self::Dns => "dns.other",
+            Self::TlsCertInvalid => "tls.cert_invalid",
+            Self::TlsCertUnknownIssuer => "tls.cert_unknown_issuer",
+            Self::TlsCertNameMismatch => "tls.cert_name_mismatch",
+            Self::TlsCertExpired => "tls.cert_expired",
+            Self::TlsKeyInvalid => "tls.key_invalid",
+            Self::TlsTicketKeyInvalid => "tls.ticket_key_invalid",
+            Self::TlsAlert => "tls.alert",
+            Self::TlsNoAlpn => "tls.no_alpn",
+            Self::TlsProtocol => "tls.protocol",
+            Self::TlsNotTls => "tls.not_tls",
+            Self::TlsInsecureDisabled => "tls.insecure_disabled",
+            Self::TlsConfigInvalid => "tls.config_invalid",
+            Self::Tls => "tls.other",
+            Self::WsHandshakeStatus(status) => match status {
+                400 => "ws.handshake_status_400",
+                401 => "ws.handshake_status_401",
+                403 => "ws.handshake_status_403",
+                404 => "ws.handshake_status_404",
+                405 => "ws.handshake_status_405",
+                426 => "ws.handshake_status_426",
+                429 => "ws.handshake_status_429",
+                500 => "ws.handshake_status_500",
+                502 => "ws.handshake_status_502",
+                503 => "ws.handshake_status_503",
+                504 => "ws.handshake_status_504",
+                _ => "ws.handshake_status_other",
+            },
+            Self::WsProtocol => "ws.protocol",
+            Self::WsCapacity => "ws.capacity",
+            Self::WsInvalidUtf8 => "ws.invalid_utf8",
+            Self::Ws => "ws.other",
+            Self::OptionInvalid => "option.invalid",
+            Self::OptionSecret => "option.secret",
+            Self::ServeFailed => "serve.failed",
+        }
+    }
+
+    /// Whether trying again, possibly elsewhere, may succeed.
+    pub fn retryable(&self) -> bool {
+        match self {
+            Self::ConnectRefused
+            | Self::ConnectReset
+            | Self::ConnectAborted
+            | Self::ConnectTimeout
+            | Self::ConnectFailed
+            | Self::IoClosed
+            | Self::DnsTimeout
+            | Self::DnsProtocol => true,
+            Self::WsHandshakeStatus(status) => *status == 429 || *status >= 500,
+            _ => false,
+        }
+    }
+
+    /// Code of an io error, looking through wrapped tls and websocket errors.
+    pub fn of_io(err: &std::io::Error) -> Self {
+        if let Some(inner) = err.get_ref() {
+            if let Some(err) = inner.downcast_ref::<rustls::Error>() {
+                return Self::of_rustls(err);
+            }
+            if let Some(err) = inner.downcast_ref::<WsError>() {
+                return Self::of_ws(err);
+            }
+        }
+
+        match err.kind() {
+            ErrorKind::ConnectionRefused => Self::ConnectRefused,
+            ErrorKind::ConnectionReset => Self::ConnectReset,
+            ErrorKind::ConnectionAborted => Self::ConnectAborted,
+            ErrorKind::TimedOut => Self::ConnectTimeout,
+            ErrorKind::AddrNotAvailable => Self::ConnectAddrUnavailable,
+            ErrorKind::BrokenPipe | ErrorKind::UnexpectedEof | ErrorKind::NotConnected => {
+                Self::IoClosed
+            }
+            ErrorKind::AddrInUse => Self::IoAddrInUse,
+            ErrorKind::PermissionDenied => Self::IoPermissionDenied,
+            ErrorKind::NotFound => Self::IoNotFound,
+            _ => Self::Io,
+        }
+    }
+
+    pub fn of_rustls(err: &rustls::Error) -> Self {
+        use rustls::CertificateError;
+
+        match err {
+            rustls::Error::InvalidCertificate(err) => match err {
+                CertificateError::UnknownIssuer => Self::TlsCertUnknownIssuer,
+                CertificateError::NotValidForName => Self::TlsCertNameMismatch,
+                CertificateError::Expired | CertificateError::NotValidYet => Self::TlsCertExpired,
+                _ => Self::TlsCertInvalid,
+            },
+            rustls::Error::AlertReceived(_) => Self::TlsAlert,
+            rustls::Error::NoApplicationProtocol => Self::TlsNoAlpn,
+            rustls::Error::InappropriateMessage { .. }
+            | rustls::Error::InappropriateHandshakeMessage { .. }
+            | rustls::Error::InvalidMessage(_)
+            | rustls::Error::PeerMisbehaved(_)
+            | rustls::Error::PeerIncompatible(_) => Self::TlsProtocol,
+            _ => Self::Tls,
+        }
+    }
+
+    pub fn of_ws(err: &WsError) -> Self {
+        use tokio_tungstenite::tungstenite::error::TlsError as WsTlsError;
+
+        match err {
+            WsError::Http(resp) => Self::WsHandshakeStatus(resp.status().as_u16()),
+            WsError::Io(err) => Self::of_io(err),
+            WsError::Tls(WsTlsError::Rustls(err)) => Self::of_rustls(err),
+            WsError::Tls(_) => Self::Tls,
+            WsError::ConnectionClosed | WsError::AlreadyClosed => Self::IoClosed,
+            WsError::Capacity(_) => Self::WsCapacity,
+            WsError::Protocol(_) => Self::WsProtocol,
+            WsError::Utf8 => Self::WsInvalidUtf8,
+            WsError::Url(_) | WsError::HttpFormat(_) => Self::OptionInvalid,
+            _ => Self::Ws,
+        }
+    }
+}
+
+impl std::fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl Serialize for ErrorCode {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+/// An error flattened for callers that cannot hold rust error types.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct StructuredError {
+    pub code: ErrorCode,
+    pub message: String,
+    pub retryable: bool,
+    /// Messages of the nested sources, outermost first.
+    pub source_chain: Vec<String>,
+}
+
+impl StructuredError {
+    fn new(err: &dyn std::error::Error, code: ErrorCode) -> Self {
+        let mut source_chain = vec![];
+        let mut source = err.source();
+        while let Some(err) = source {
+            source_chain.push(err.to_string());
+            source = err.source();
+        }
+
+        Self {
+            code,
+            message: err.to_string(),
+            retryable: code.retryable(),
+            source_chain,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use tokio::net::TcpListener;
+
+    use crate::{
+        tcp::{TcpClient, TcpClientOption},
+        websocket::{
+            wire, ForwardedOption, WebSocketClient, WebSocketClientOption, WebSocketServer,
+            WebSocketServerOption,
+        },
+        Resolver, TlsCertOption, TlsClientOption, TlsServerOption, TransportClientTrait,
+        TransportServerTrait,
+    };
+
+    use super::*;
+
+    fn tcp_client(addr: std::net::SocketAddr, tls: Option<TlsClientOption>) -> TcpClient {
+        let opt = TcpClientOption {
+            addr: "localhost".into(),
+            port: addr.port(),
+            tcp_nodelay: false,
+            connect_timeout: None,
+            connect_deadline: None,
+            attempt_delay: None,
+            resolve_on_connect: false,
+            proxy: None,
+            bind_addr: None,
+            bind_device: None,
+        };
+        TcpClient::with_addrs(opt, tls, vec![addr]).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_connect_error_codes() {
+        let Err(err) = tcp_client(([127, 0, 0, 1], 1).into(), None).connect().await else {
+            panic!("connected to a closed port");
+        };
+        assert_eq!(err.code(), ErrorCode::ConnectRefused);
+        let structured = err.to_structured();
+        assert!(structured.retryable);
+        assert_eq!(structured.source_chain.len(), 1);
+        let json = serde_json::to_value(&structured).unwrap();
+        assert_eq!(json["code"], "connect.refused");
+
+        // a self signed certificate against the default roots
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+        let tls_opt = TlsServerOption {
+            alpn: vec![],
+            require_alpn: false,
+            ticket_keys: None,
+            certificate: TlsCertOption::Text {
+                certs: vec![cert.cert.pem()],
+                key: cert.key_pair.serialize_pem().into(),
+            },
+            client_ca: None,
+            require_client_cert: false,
+            session_tickets: false,
+            min_version: None,
+            max_version: None,
+            cipher_suites: vec![],
+        };
+        let config: rustls::ServerConfig = tls_opt.try_into().unwrap();
+        let acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(config));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (s, _) = listener.accept().await.unwrap();
+            let _ = acceptor.accept(s).await;
+        });
+
+        let Err(err) = tcp_client(addr, Some(TlsClientOption::default()))
+            .connect()
+            .await
+        else {
+            panic!("self signed certificate accepted");
+        };
+        assert_eq!(err.code(), ErrorCode::TlsCertUnknownIssuer);
+        assert_eq!(err.code().as_str(), "tls.cert_unknown_issuer");
+        assert!(!err.code().retryable());
+    }
+
+    #[derive(Debug, Clone)]
+    struct NoopCallback;
+
+    impl crate::TransportServerCallback for NoopCallback {
+        async fn handle<S>(&self, _stream: S, _addr: Option<std::net::SocketAddr>)
+        where
+            S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + Sync,
+        {
+        }
+    }
+
+    #[tokio::test]
+    async fn test_ws_handshake_status_code() {
+        tokio::spawn(async move {
+            let opt = WebSocketServerOption {
+                listen: ([127, 0, 0, 1], 9872).into(),
+                path: "/codes".into(),
+                paths: vec![],
+                path_match: Default::default(),
+                fallback: Default::default(),
+                tcp_nodelay: false,
+                duplex_fairness: false,
+                read_buffer_messages: 1,
+                validate_text: false,
+                text_to_bytes: true,
+                max_message_size: wire::MAX_MESSAGE_SIZE,
+                max_frame_size: wire::MAX_FRAME_SIZE,
+                write_chunk_size: None,
+                max_bytes: None,
+                rate_limit: None,
+                max_connections: None,
+                idle_reap: None,
+                idle_timeout: None,
+                trust_forwarded_headers: false,
+                keepalive_interval: None,
+                keepalive_timeout: Duration::from_secs(30),
+                slow_consumer_policy: None,
+            };
+            let mut srv = WebSocketServer::init(opt, None).unwrap();
+            srv.add_accept_hook(Arc::new(|_| false));
+            srv.serve(NoopCallback).await.unwrap();
+        });
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+
+        let opt = WebSocketClientOption {
+            addr: "127.0.0.1".into(),
+            port: 9872,
+            path: "/codes".into(),
+            tcp_nodelay: false,
+            duplex_fairness: false,
+            read_buffer_messages: 1,
+            validate_text: false,
+            text_to_bytes: true,
+            max_message_size: wire::MAX_MESSAGE_SIZE,
+            max_frame_size: wire::MAX_FRAME_SIZE,
+            write_chunk_size: None,
+            forwarded: ForwardedOption::default(),
+            headers: vec![],
+            host: None,
+            keepalive_interval: None,
+            keepalive_timeout: Duration::from_secs(30),
+            slow_consumer_policy: None,
+            connect_timeout: None,
+            connect_deadline: None,
+            attempt_delay: None,
+            resolve_on_connect: false,
+            proxy: None,
+            bind_addr: None,
+            bind_device: None,
+        };
+        let cli = WebSocketClient::init(opt, None, &Resolver::default()).unwrap();
+        let Err(err) = cli.connect().await else {
+            panic!("upgrade accepted");
+        };
+        assert_eq!(err.code(), ErrorCode::WsHandshakeStatus(403));
+        assert_eq!(err.code().as_str(), "ws.handshake_status_403");
+        assert!(!err.to_structured().retryable);
+    }
+
+    #[tokio::test]
+    async fn test_nested_error_codes() {
+        let elapsed = tokio::time::timeout(
+            std::time::Duration::from_millis(1),
+            std::future::pending::<()>(),
+        )
+        .await
+        .unwrap_err();
+        let err = ClientError::from(ResolveError::from(elapsed));
+        assert_eq!(err.code(), ErrorCode::DnsTimeout);
+        assert!(err.code().retryable());
+        assert_eq!(
+            ClientError::from(ResolveError::EmptyResolved).code(),
+            ErrorCode::DnsEmpty
+        );
+
+        let err = ClientError::Option("unknown address".into());
+        assert_eq!(err.to_string(), "option error (unknown address)");
+        let structured = err.to_structured();
+        assert_eq!(structured.code, ErrorCode::OptionInvalid);
+        assert!(structured.source_chain.is_empty());
+
+        let err = ServerError::from(TlsError::InvalidKey("not found".into()));
+        assert_eq!(err.code().as_str(), "tls.key_invalid");
+        let err = ServerError::from(std::io::Error::from(ErrorKind::AddrInUse));
+        assert_eq!(err.code(), ErrorCode::IoAddrInUse);
+
+        let io = std::io::Error::new(ErrorKind::InvalidData, rustls::Error::NoApplicationProtocol);
+        assert_eq!(ClientError::from(io).code(), ErrorCode::TlsNoAlpn);
+        assert_eq!(
+            ErrorCode::WsHandshakeStatus(418).as_str(),
+            "ws.handshake_status_other"
+        );
+        assert!(ErrorCode::WsHandshakeStatus(503).retryable());
+
+        assert!(err_of(ErrorKind::ConnectionReset).is_retryable());
+        assert!(err_of(ErrorKind::TimedOut).is_retryable());
+        assert!(!ClientError::Option("port".into()).is_retryable());
+        let bad_cert = std::io::Error::new(
+            ErrorKind::InvalidData,
+            rustls::Error::InvalidCertificate(rustls::CertificateError::UnknownIssuer),
+        );
+        assert!(!ClientError::from(bad_cert).is_retryable());
+    }
+
+    fn err_of(kind: ErrorKind) -> ClientError {
+        std::io::Error::from(kind).into()
+    }
+}
@@ -0,0 +1,550 @@
+//! C Bindings for `TransportClient`
+//!
+//! The declarations live in `include/kapibara.h`. Every entry point blocks
+//! on one shared runtime, catches panics and reports failures as negative
+//! codes with the message kept per thread for `kapibara_last_error`.
+
+use std::{
+    cell::RefCell,
+    ffi::{c_char, CStr},
+    panic::{catch_unwind, AssertUnwindSafe},
+    sync::{Arc, Mutex, OnceLock},
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt, ReadHalf, WriteHalf},
+    runtime::Runtime,
+};
+
+use crate::{
+    ClientError, ErrorCode, TransportClient, TransportClientOption, TransportClientStream,
+    TransportClientTrait,
+};
+
+pub const KAPIBARA_OK: i32 = 0;
+pub const KAPIBARA_ERR_INVALID_ARGUMENT: i32 = -1;
+pub const KAPIBARA_ERR_INVALID_HANDLE: i32 = -2;
+pub const KAPIBARA_ERR_PANIC: i32 = -3;
+pub const KAPIBARA_ERR_TIMEOUT: i32 = -4;
+pub const KAPIBARA_ERR_RUNTIME: i32 = -5;
+
+/// How long `kapibara_stream_close` waits for the shutdown to be sent.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// The C code of `code`, negative and stable, grouped by its prefix.
+pub fn error_number(code: ErrorCode) -> i32 {
+    match code {
+        ErrorCode::ConnectRefused => -100,
+        ErrorCode::ConnectReset => -101,
+        ErrorCode::ConnectAborted => -102,
+        ErrorCode::ConnectTimeout => -103,
+        ErrorCode::ConnectAddrUnavailable => -104,
+        ErrorCode::ConnectFailed => -105,
+        ErrorCode::ConnectDenied => -106,
+        ErrorCode::ConnectCircuitOpen => -107,
+        ErrorCode::IoClosed => -200,
+        ErrorCode::IoAddrInUse => -201,
+        ErrorCode::IoPermissionDenied => -202,
+        ErrorCode::IoNotFound => -203,
+        ErrorCode::Io => -299,
+        ErrorCode::DnsEmpty => -300,
+        ErrorCode::DnsTimeout => -301,
+        ErrorCode::DnsNoRecords => -302,
+        ErrorCode::DnsInit => -303,
+        ErrorCode::DnsProtocol => -304,
+        ErrorCode::Dns => -399,
+        ErrorCode::TlsCertInvalid => -400,
+        ErrorCode::TlsCertUnknownIssuer => -401,
+        ErrorCode::TlsCertNameMismatch => -402,
+        ErrorCode::TlsCertExpired => -403,
+        ErrorCode::TlsKeyInvalid => -404,
+        ErrorCode::TlsTicketKeyInvalid => -405,
+        ErrorCode::TlsAlert => -406,
+        ErrorCode::TlsNoAlpn => -407,
+        ErrorCode::TlsProtocol => -408,
+        ErrorCode::TlsNotTls => -409,
+        ErrorCode::TlsInsecureDisabled => -410,
+        ErrorCode::TlsConfigInvalid => -411,
+        ErrorCode::Tls => -499,
+        // the status is in the message
+        ErrorCode::WsHandshakeStatus(_) => -500,
+        ErrorCode::WsProtocol => -501,
+        ErrorCode::WsCapacity => -502,
+        ErrorCode::WsInvalidUtf8 => -503,
+        ErrorCode::Ws => -599,
+        ErrorCode::OptionInvalid => -600,
+        ErrorCode::OptionSecret => -601,
+        ErrorCode::ServeFailed => -700,
+    }
+}
+
+/// A failed call, as handed to C.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct FfiError {
+    code: i32,
+    message: String,
+}
+
+impl FfiError {
+    fn new(code: i32, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+
+    fn invalid_handle(handle: u64) -> Self {
+        Self::new(
+            KAPIBARA_ERR_INVALID_HANDLE,
+            format!("invalid or closed handle {:#x}", handle),
+        )
+    }
+}
+
+impl From<ClientError> for FfiError {
+    fn from(e: ClientError) -> Self {
+        let err = e.to_structured();
+        Self::new(error_number(err.code), err.message)
+    }
+}
+
+impl From<std::io::Error> for FfiError {
+    fn from(e: std::io::Error) -> Self {
+        ClientError::Io(e).into()
+    }
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<FfiError>> = const { RefCell::new(None) };
+}
+
+/// Run one entry point, turning its error or panic into a C code.
+fn entry(f: impl FnOnce() -> Result<i64, FfiError>) -> i64 {
+    let res = catch_unwind(AssertUnwindSafe(f)).unwrap_or_else(|panic| {
+        let message = panic
+            .downcast_ref::<&str>()
+            .map(|s| s.to_string())
+            .or_else(|| panic.downcast_ref::<String>().cloned())
+            .unwrap_or_else(|| "unknown panic".into());
+        Err(FfiError::new(
+            KAPIBARA_ERR_PANIC,
+            format!("panic ({})", message),
+        ))
+    });
+    match res {
+        Ok(n) => n,
+        Err(e) => {
+            let code = e.code;
+            LAST_ERROR.with(|last| *last.borrow_mut() = Some(e));
+            code as i64
+        }
+    }
+}
+
+fn runtime() -> Result<&'static Runtime, FfiError> {
+    static RUNTIME: OnceLock<Runtime> = OnceLock::new();
+    if let Some(rt) = RUNTIME.get() {
+        return Ok(rt);
+    }
+    let rt = tokio::runtime::Builder::new_multi_thread()
+        .thread_name("kapibara-ffi")
+        .enable_all()
+        .build()
+        .map_err(|e| FfiError::new(KAPIBARA_ERR_RUNTIME, e.to_string()))?;
+    // a racing call may have won, its runtime is kept and ours dropped
+    Ok(RUNTIME.get_or_init(|| rt))
+}
+
+/// Slots addressed by `generation << 32 | index`.
+///
+/// Freeing a slot bumps its generation, so a stale handle misses instead
+/// of reaching whatever took the slot next. Generations start at one and
+/// no handle is zero.
+#[derive(Debug)]
+struct HandleTable<T> {
+    slots: Vec<(u32, Option<Arc<T>>)>,
+    free: Vec<u32>,
+}
+
+impl<T> HandleTable<T> {
+    const fn new() -> Self {
+        Self {
+            slots: Vec::new(),
+            free: Vec::new(),
+        }
+    }
+
+    fn insert(&mut self, value: T) -> u64 {
+        let index = match self.free.pop() {
+            Some(index) => index,
+            None => {
+                self.slots.push((1, None));
+                (self.slots.len() - 1) as u32
+            }
+        };
+        let slot = &mut self.slots[index as usize];
+        slot.1 = Some(Arc::new(value));
+        (slot.0 as u64) << 32 | index as u64
+    }
+
+    fn slot(&mut self, handle: u64) -> Option<&mut (u32, Option<Arc<T>>)> {
+        let (generation, index) = ((handle >> 32) as u32, handle as u32);
+        self.slots
+            .get_mut(index as usize)
+            .filter(|slot| slot.0 == generation && slot.1.is_some())
+    }
+
+    fn get(&mut self, handle: u64) -> Option<Arc<T>> {
+        self.slot(handle).and_then(|slot| slot.1.clone())
+    }
+
+    /// Calls already holding the value keep it until they return.
+    fn remove(&mut self, handle: u64) -> Option<Arc<T>> {
+        let slot = self.slot(handle)?;
+        let value = slot.1.take();
+        slot.0 = slot.0.checked_add(1).unwrap_or(1);
+        self.free.push(handle as u32);
+        value
+    }
+}
+
+/// The halves lock apart, so one thread may read while another writes.
+struct FfiStream {
+    read: tokio::sync::Mutex<ReadHalf<TransportClientStream>>,
+    write: tokio::sync::Mutex<WriteHalf<TransportClientStream>>,
+}
+
+static CLIENTS: Mutex<HandleTable<TransportClient>> = Mutex::new(HandleTable::new());
+static STREAMS: Mutex<HandleTable<FfiStream>> = Mutex::new(HandleTable::new());
+
+fn lock<T>(table: &Mutex<HandleTable<T>>) -> std::sync::MutexGuard<'_, HandleTable<T>> {
+    table.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+fn stream(handle: u64) -> Result<Arc<FfiStream>, FfiError> {
+    lock(&STREAMS)
+        .get(handle)
+        .ok_or_else(|| FfiError::invalid_handle(handle))
+}
+
+/// Run `fut` on the shared runtime, bounded by `timeout_ms` unless zero.
+fn block_on_timeout<F, T>(timeout_ms: u32, fut: F) -> Result<T, FfiError>
+where
+    F: std::future::Future<Output = Result<T, FfiError>>,
+{
+    let rt = runtime()?;
+    if timeout_ms == 0 {
+        return rt.block_on(fut);
+    }
+    let timeout = Duration::from_millis(timeout_ms as u64);
+    rt.block_on(async { tokio::time::timeout(timeout, fut).await })
+        .map_err(|_| {
+            FfiError::new(
+                KAPIBARA_ERR_TIMEOUT,
+                format!("timed out after {:?}", timeout),
+            )
+        })?
+}
+
+fn null_argument(name: &str) -> FfiError {
+    FfiError::new(KAPIBARA_ERR_INVALID_ARGUMENT, format!("{} is null", name))
+}
+
+/// Init a client from a `TransportClientOption` in json.
+///
+/// # Safety
+///
+/// `config_json` is a nul terminated string, `out_handle` is writable.
+#[no_mangle]
+pub unsafe extern "C" fn kapibara_client_new(
+    config_json: *const c_char,
+    out_handle: *mut u64,
+) -> i32 {
+    entry(|| {
+        if config_json.is_null() {
+            return Err(null_argument("config_json"));
+        }
+        if out_handle.is_null() {
+            return Err(null_argument("out_handle"));
+        }
+        // SAFETY: non null and nul terminated by the contract above
+        let config = unsafe { CStr::from_ptr(config_json) };
+        let opt: TransportClientOption = config
+            .to_str()
+            .map_err(|e| e.to_string())
+            .and_then(|s| serde_json::from_str(s).map_err(|e| e.to_string()))
+            .map_err(ClientError::Option)?;
+
+        // the resolver blocks on the runtime it finds entered
+        let _enter = runtime()?.enter();
+        let client = TransportClient::init_with_default_resolver(opt)?;
+        let handle = lock(&CLIENTS).insert(client);
+        // SAFETY: non null and writable by the contract above
+        unsafe { out_handle.write(handle) };
+        Ok(0)
+    }) as i32
+}
+
+/// Free a client, streams it opened stay usable.
+#[no_mangle]
+pub extern "C" fn kapibara_client_free(client: u64) -> i32 {
+    entry(|| {
+        lock(&CLIENTS)
+            .remove(client)
+            .ok_or_else(|| FfiError::invalid_handle(client))?;
+        Ok(0)
+    }) as i32
+}
+
+/// Open a stream, blocking until connected or failed.
+///
+/// # Safety
+///
+/// `out_stream` is writable.
+#[no_mangle]
+pub unsafe extern "C" fn kapibara_client_connect(client: u64, out_stream: *mut u64) -> i32 {
+    entry(|| {
+        if out_stream.is_null() {
+            return Err(null_argument("out_stream"));
+        }
+        let cli = lock(&CLIENTS)
+            .get(client)
+            .ok_or_else(|| FfiError::invalid_handle(client))?;
+        let stream = runtime()?.block_on(cli.connect())?;
+        let (read, write) = tokio::io::split(stream);
+        let handle = lock(&STREAMS).insert(FfiStream {
+            read: read.into(),
+            write: write.into(),
+        });
+        // SAFETY: non null and writable by the contract above
+        unsafe { out_stream.write(handle) };
+        Ok(0)
+    }) as i32
+}
+
+/// Read up to `len` bytes, 0 on end of stream.
+///
+/// # Safety
+///
+/// `buf` is writable for `len` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn kapibara_stream_read(
+    stream_handle: u64,
+    buf: *mut u8,
+    len: usize,
+    timeout_ms: u32,
+) -> isize {
+    entry(|| {
+        if buf.is_null() && len != 0 {
+            return Err(null_argument("buf"));
+        }
+        let stream = stream(stream_handle)?;
+        if len == 0 {
+            return Ok(0);
+        }
+        // SAFETY: non null and writable for len bytes by the contract above
+        let buf = unsafe { std::slice::from_raw_parts_mut(buf, len) };
+        block_on_timeout(timeout_ms, async {
+            let n = stream.read.lock().await.read(buf).await?;
+            Ok(n as i64)
+        })
+    }) as isize
+}
+
+/// Write up to `len` bytes and flush them, returns the bytes written.
+///
+/// # Safety
+///
+/// `buf` is readable for `len` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn kapibara_stream_write(
+    stream_handle: u64,
+    buf: *const u8,
+    len: usize,
+    timeout_ms: u32,
+) -> isize {
+    entry(|| {
+        if buf.is_null() && len != 0 {
+            return Err(null_argument("buf"));
+        }
+        let stream = stream(stream_handle)?;
+        if len == 0 {
+            return Ok(0);
+        }
+        // SAFETY: non null and readable for len bytes by the contract above
+        let buf = unsafe { std::slice::from_raw_parts(buf, len) };
+        block_on_timeout(timeout_ms, async {
+            let mut write = stream.write.lock().await;
+            let n = write.write(buf).await?;
+            write.flush().await?;
+            Ok(n as i64)
+        })
+    }) as isize
+}
+
+/// Shut the stream down and free its handle.
+#[no_mangle]
+pub extern "C" fn kapibara_stream_close(stream_handle: u64) -> i32 {
+    entry(|| {
+        let stream = lock(&STREAMS)
+            .remove(stream_handle)
+            .ok_or_else(|| FfiError::invalid_handle(stream_handle))?;
+        // best effort, the handle is gone either way
+        let _ = block_on_timeout(CLOSE_TIMEOUT.as_millis() as u32, async {
+            stream.write.lock().await.shutdown().await?;
+            Ok(())
+        });
+        Ok(0)
+    }) as i32
+}
+
+/// Code of the last failed call on this thread, its message copied into `buf`.
+///
+/// # Safety
+///
+/// `buf` is writable for `len` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn kapibara_last_error(buf: *mut c_char, len: usize) -> i32 {
+    LAST_ERROR.with(|last| {
+        let last = last.borrow();
+        let Some(err) = last.as_ref() else {
+            if !buf.is_null() && len != 0 {
+                // SAFETY: non null and writable for len bytes by the contract above
+                unsafe { buf.write(0) };
+            }
+            return KAPIBARA_OK;
+        };
+        if !buf.is_null() && len != 0 {
+            let n = err.message.len().min(len - 1);
+            // SAFETY: n + 1 <= len bytes, the message and buf never overlap
+            unsafe {
+                std::ptr::copy_nonoverlapping(err.message.as_ptr().cast(), buf, n);
+                buf.add(n).write(0);
+            }
+        }
+        err.code
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        net::TcpListener,
+        process::Command,
+    };
+
+    use super::*;
+
+    fn last_error() -> (i32, String) {
+        let mut buf = [0 as c_char; 256];
+        let code = unsafe { kapibara_last_error(buf.as_mut_ptr(), buf.len()) };
+        let msg = unsafe { CStr::from_ptr(buf.as_ptr()) };
+        (code, msg.to_string_lossy().into_owned())
+    }
+
+    #[test]
+    fn test_handle_table_generations() {
+        let mut table = HandleTable::new();
+        let a = table.insert("a");
+        assert_ne!(a, 0);
+        assert_eq!(table.get(a).as_deref(), Some(&"a"));
+        assert!(table.remove(a).is_some());
+        // a double free and a stale handle both miss
+        assert!(table.remove(a).is_none());
+        let b = table.insert("b");
+        assert_eq!(a as u32, b as u32);
+        assert_ne!(a, b);
+        assert!(table.get(a).is_none());
+        assert_eq!(table.get(b).as_deref(), Some(&"b"));
+        assert!(table.get(0).is_none());
+        assert!(table.get(u64::MAX).is_none());
+    }
+
+    #[test]
+    fn test_entry_errors() {
+        let res = std::thread::spawn(|| {
+            assert_eq!(last_error(), (KAPIBARA_OK, String::new()));
+            assert_eq!(entry(|| panic!("boom")), KAPIBARA_ERR_PANIC as i64);
+            assert_eq!(last_error(), (KAPIBARA_ERR_PANIC, "panic (boom)".into()));
+
+            let code = unsafe { kapibara_client_new(c"{\"opt\": 1}".as_ptr(), &mut 0) };
+            assert_eq!(code, error_number(ErrorCode::OptionInvalid));
+            assert_eq!(kapibara_stream_close(12345), KAPIBARA_ERR_INVALID_HANDLE);
+            let (code, msg) = last_error();
+            assert_eq!(code, KAPIBARA_ERR_INVALID_HANDLE);
+            assert!(msg.contains("0x3039"), "{}", msg);
+
+            // a short buffer truncates and still terminates
+            let mut buf = [1 as c_char; 4];
+            unsafe { kapibara_last_error(buf.as_mut_ptr(), buf.len()) };
+            assert_eq!(unsafe { CStr::from_ptr(buf.as_ptr()) }.to_bytes(), b"inv");
+        });
+        res.join().unwrap();
+    }
+
+    #[test]
+    fn test_c_program() {
+        let manifest_dir = env!("CARGO_MANIFEST_DIR");
+        let target_dir = format!("{}/target/ffi", manifest_dir);
+        // a target dir of its own, the running test holds the default one
+        let out = Command::new(env!("CARGO"))
+            .current_dir(manifest_dir)
+            .env("CARGO_TARGET_DIR", &target_dir)
+            .args(["rustc", "--lib", "--features", "ffi", "--crate-type"])
+            .args(["staticlib", "--", "--print", "native-static-libs"])
+            .output()
+            .unwrap();
+        let stderr = String::from_utf8_lossy(&out.stderr);
+        assert!(out.status.success(), "{}", stderr);
+        let native_libs = stderr
+            .lines()
+            .find_map(|line| line.split_once("native-static-libs: "))
+            .map(|(_, libs)| libs.split_whitespace().collect::<Vec<_>>())
+            .unwrap_or_default();
+
+        let program = format!("{}/kapibara_ffi_echo", target_dir);
+        let status = Command::new(std::env::var("CC").unwrap_or_else(|_| "cc".into()))
+            .current_dir(manifest_dir)
+            .args(["-Wall", "-Werror", "-Iinclude", "tests/c/ffi_echo.c"])
+            .arg(format!("{}/debug/libkapibara_transport.a", target_dir))
+            .args(native_libs)
+            .args(["-o", &program])
+            .status()
+            .unwrap();
+        assert!(status.success());
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let closed_port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        std::thread::spawn(move || {
+            for conn in listener.incoming() {
+                let Ok(mut conn) = conn else { return };
+                std::thread::spawn(move || {
+                    let mut buf = [0u8; 1024];
+                    while let Ok(n @ 1..) = conn.read(&mut buf) {
+                        if conn.write_all(&buf[..n]).is_err() {
+                            return;
+                        }
+                    }
+                });
+            }
+        });
+
+        let out = Command::new(&program)
+            .args([port.to_string(), closed_port.to_string()])
+            .output()
+            .unwrap();
+        assert!(
+            out.status.success(),
+            "{}{}",
+            String::from_utf8_lossy(&out.stdout),
+            String::from_utf8_lossy(&out.stderr)
+        );
+    }
+}
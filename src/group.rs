@@ -0,0 +1,247 @@
+//! Server Groups
+//!
+//! Serve several listeners from one task. Entries without a callback of
+//! their own share the one passed to `serve`, which stays generic; only
+//! entries with their own callback go through the boxed `DynServerCallback`.
+
+use std::{future::Future, net::SocketAddr, pin::Pin, sync::Arc, time::Duration};
+
+use futures_util::{
+    future::{try_join_all, Either},
+    FutureExt,
+};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use crate::{
+    stats::ServerStatsSnapshot, ConnContext, ServerResult, TransportServer,
+    TransportServerCallback, TransportServerOption, TransportServerTrait,
+};
+
+/// Stream handed to a `DynServerCallback`.
+pub trait DynStream: AsyncRead + AsyncWrite + Unpin + Send + Sync {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send + Sync> DynStream for T {}
+
+pub type BoxStream<'a> = Box<dyn DynStream + 'a>;
+
+pub type HandleFuture<'a> = Pin<Box<dyn Future<Output = ()> + Send + Sync + 'a>>;
+
+/// Object safe form of `TransportServerCallback`, implemented for every callback.
+pub trait DynServerCallback: Send + Sync + 'static {
+    fn handle_dyn<'a>(&'a self, stream: BoxStream<'a>, ctx: ConnContext) -> HandleFuture<'a>;
+}
+
+impl<C: TransportServerCallback> DynServerCallback for C {
+    fn handle_dyn<'a>(&'a self, stream: BoxStream<'a>, ctx: ConnContext) -> HandleFuture<'a> {
+        Box::pin(self.handle_ctx(stream, ctx))
+    }
+}
+
+/// A shared `DynServerCallback` usable wherever a callback is expected.
+#[derive(Clone)]
+pub struct SharedCallback(pub Arc<dyn DynServerCallback>);
+
+impl TransportServerCallback for SharedCallback {
+    async fn handle<S>(&self, stream: S, addr: Option<SocketAddr>)
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + Sync,
+    {
+        self.handle_ctx(stream, ConnContext::new(addr, None)).await
+    }
+
+    async fn handle_ctx<S>(&self, stream: S, ctx: ConnContext)
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + Sync,
+    {
+        self.0.handle_dyn(Box::new(stream), ctx).await
+    }
+}
+
+/// One listener of a group, with its own callback if set.
+pub struct GroupEntry {
+    pub server: TransportServer,
+    pub callback: Option<Arc<dyn DynServerCallback>>,
+}
+
+impl From<TransportServer> for GroupEntry {
+    fn from(server: TransportServer) -> Self {
+        Self {
+            server,
+            callback: None,
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct TransportServerGroup {
+    entries: Vec<GroupEntry>,
+}
+
+impl TransportServerGroup {
+    pub fn new(entries: Vec<GroupEntry>) -> Self {
+        Self { entries }
+    }
+
+    /// Init a server per option, all served by the callback given to `serve`.
+    pub fn init(trans_opts: Vec<TransportServerOption>) -> ServerResult<Self> {
+        let entries = trans_opts
+            .into_iter()
+            .map(|opt| TransportServer::init(opt).map(GroupEntry::from))
+            .collect::<ServerResult<_>>()?;
+        Ok(Self { entries })
+    }
+
+    pub fn push(&mut self, server: TransportServer) {
+        self.entries.push(server.into());
+    }
+
+    /// Add a server handled by `callback` instead of the group callback.
+    pub fn push_with_callback(
+        &mut self,
+        server: TransportServer,
+        callback: Arc<dyn DynServerCallback>,
+    ) {
+        self.entries.push(GroupEntry {
+            server,
+            callback: Some(callback),
+        });
+    }
+
+    pub fn entries(&self) -> &[GroupEntry] {
+        &self.entries
+    }
+
+    /// Stats of every server, in entry order.
+    pub fn stats(&self) -> Vec<ServerStatsSnapshot> {
+        self.entries.iter().map(|e| e.server.stats()).collect()
+    }
+
+    pub fn local_addrs(&self) -> Vec<Option<SocketAddr>> {
+        self.entries.iter().map(|e| e.server.local_addr()).collect()
+    }
+
+    /// Serve all entries until one of them fails, stopping the others.
+    pub async fn serve<C: TransportServerCallback>(&self, callback: C) -> ServerResult<()> {
+        try_join_all(self.entries.iter().map(|entry| match entry.callback {
+            Some(ref own) => Either::Left(entry.server.serve(SharedCallback(own.clone()))),
+            None => Either::Right(entry.server.serve(callback.clone())),
+        }))
+        .await?;
+        Ok(())
+    }
+
+    /// Serve all entries until `signal`, each shutting down as its own
+    /// `serve_with_shutdown` does. Returns once every entry finished.
+    pub async fn serve_with_shutdown<C, F>(
+        &self,
+        callback: C,
+        signal: F,
+        grace: Duration,
+    ) -> ServerResult<()>
+    where
+        C: TransportServerCallback,
+        F: Future<Output = ()> + Send + Sync,
+    {
+        let signal = signal.shared();
+        try_join_all(self.entries.iter().map(|entry| {
+            let signal = signal.clone();
+            match entry.callback {
+                Some(ref own) => Either::Left(entry.server.serve_with_shutdown(
+                    SharedCallback(own.clone()),
+                    signal,
+                    grace,
+                )),
+                None => Either::Right(entry.server.serve_with_shutdown(
+                    callback.clone(),
+                    signal,
+                    grace,
+                )),
+            }
+        }))
+        .await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use crate::{
+        option::{ServerOption, CONFIG_VERSION},
+        tcp::{TcpServerOption, TlsMode, DEFAULT_ACCEPT_BATCH, DEFAULT_HANDSHAKE_TIMEOUT},
+    };
+
+    use super::*;
+
+    #[derive(Clone)]
+    struct BannerCallback(&'static [u8]);
+
+    impl TransportServerCallback for BannerCallback {
+        async fn handle<S>(&self, mut stream: S, _addr: Option<SocketAddr>)
+        where
+            S: AsyncRead + AsyncWrite + Unpin + Send + Sync,
+        {
+            let _ = stream.write_all(self.0).await;
+            let _ = stream.shutdown().await;
+        }
+    }
+
+    fn tcp_server(port: u16) -> TransportServer {
+        TransportServer::init(TransportServerOption {
+            opt: ServerOption::Tcp(TcpServerOption {
+                listen: ([127, 0, 0, 1], port).into(),
+                tcp_nodelay: false,
+                tls_mode: TlsMode::Required,
+                sniff: None,
+                max_bytes: None,
+                rate_limit: None,
+                trace_sampling: None,
+                idle_reap: None,
+                idle_timeout: None,
+                accept_batch: DEFAULT_ACCEPT_BATCH,
+                handshake_timeout: DEFAULT_HANDSHAKE_TIMEOUT,
+                max_connections: None,
+            }),
+            tls: None,
+            latency_profile: Default::default(),
+            policy: Default::default(),
+            version: CONFIG_VERSION,
+        })
+        .unwrap()
+    }
+
+    async fn banner(port: u16) -> Vec<u8> {
+        let mut s = tokio::net::TcpStream::connect(("127.0.0.1", port))
+            .await
+            .unwrap();
+        let mut buf = vec![];
+        s.read_to_end(&mut buf).await.unwrap();
+        buf
+    }
+
+    #[tokio::test]
+    async fn test_group_callbacks() {
+        let mut group = TransportServerGroup::default();
+        group.push_with_callback(tcp_server(9895), Arc::new(BannerCallback(b"auth-a")));
+        group.push_with_callback(tcp_server(9896), Arc::new(BannerCallback(b"auth-b")));
+        group.push(tcp_server(9897));
+        assert_eq!(group.local_addrs()[1], Some(([127, 0, 0, 1], 9896).into()));
+
+        let group = Arc::new(group);
+        let serving = group.clone();
+        tokio::spawn(async move { serving.serve(BannerCallback(b"shared")).await });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        assert_eq!(banner(9895).await, b"auth-a");
+        assert_eq!(banner(9896).await, b"auth-b");
+        assert_eq!(banner(9897).await, b"shared");
+        assert_eq!(banner(9895).await, b"auth-a");
+
+        let stats = group.stats();
+        assert_eq!(stats.len(), 3);
+        assert_eq!(stats[0].listen.port(), 9895);
+    }
+}
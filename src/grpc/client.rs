@@ -0,0 +1,139 @@
+//! Grpc Transport client
+
+use std::{io::ErrorKind, net::SocketAddr};
+
+use h2::RecvStream;
+use http::{header, HeaderValue, Response, StatusCode};
+
+use crate::{
+    http2::{
+        option::{DEFAULT_CONNECTION_WINDOW, DEFAULT_STREAM_WINDOW},
+        H2Client, H2ClientOption, H2Stream,
+    },
+    option::{LatencyProfile, ProbeMode},
+    ClientError, ClientResult, Resolver, TlsClientOption, TransportClientTrait,
+};
+
+use super::{check_chunk_size, method_path, GrpcClientOption, GrpcStream, CONTENT_TYPE};
+
+/// Calls the tunnel method once per connect, all calls sharing one http/2
+/// connection.
+pub struct GrpcClient {
+    h2: H2Client,
+    opt: GrpcClientOption,
+}
+
+impl GrpcClient {
+    pub fn init(
+        opt: GrpcClientOption,
+        tls_opt: Option<TlsClientOption>,
+        resolver: &Resolver,
+    ) -> ClientResult<Self> {
+        let h2_opt = h2_option(&opt, &tls_opt)?;
+        Ok(Self {
+            h2: H2Client::init(h2_opt, tls_opt, resolver)?,
+            opt,
+        })
+    }
+
+    /// Init with already resolved addresses of `opt.addr`.
+    pub fn with_addrs(
+        opt: GrpcClientOption,
+        tls_opt: Option<TlsClientOption>,
+        addr: Vec<SocketAddr>,
+    ) -> ClientResult<Self> {
+        let h2_opt = h2_option(&opt, &tls_opt)?;
+        Ok(Self {
+            h2: H2Client::with_addrs(h2_opt, tls_opt, addr)?,
+            opt,
+        })
+    }
+
+    pub fn set_post_connect_probe(&mut self, probe: Option<ProbeMode>) -> ClientResult<()> {
+        self.h2.set_post_connect_probe(probe)
+    }
+
+    /// Socket settings of the profile, the options are overridden by `apply_latency_profile`.
+    pub fn set_latency_profile(&mut self, profile: LatencyProfile) {
+        self.h2.set_latency_profile(profile);
+    }
+}
+
+fn h2_option(
+    opt: &GrpcClientOption,
+    tls_opt: &Option<TlsClientOption>,
+) -> ClientResult<H2ClientOption> {
+    if tls_opt.is_none() {
+        return Err(ClientError::Option(
+            "grpc transport requires tls".to_owned(),
+        ));
+    }
+    check_chunk_size(opt.chunk_size).map_err(ClientError::Option)?;
+    Ok(H2ClientOption {
+        addr: opt.addr.clone(),
+        port: opt.port,
+        path: method_path(&opt.service_name, &opt.method_name).map_err(ClientError::Option)?,
+        tcp_nodelay: opt.tcp_nodelay,
+        connect_timeout: opt.connect_timeout,
+        stream_window: DEFAULT_STREAM_WINDOW,
+        connection_window: DEFAULT_CONNECTION_WINDOW,
+    })
+}
+
+impl TransportClientTrait for GrpcClient {
+    type Stream = GrpcStream;
+
+    /// Returns once the call is sent, servers may wait for the first
+    /// message before answering. A refused call fails the first read.
+    async fn connect(&self) -> ClientResult<Self::Stream> {
+        let request = self
+            .h2
+            .request()
+            .header(header::CONTENT_TYPE, CONTENT_TYPE)
+            .header(header::TE, "trailers")
+            .body(())
+            .map_err(|e| ClientError::Option(e.to_string()))?;
+        let (response, send) = self.h2.send(request).await?;
+
+        let call = format!("{}/{}", self.opt.service_name, self.opt.method_name);
+        let stream = H2Stream::lazy(send, response, Box::new(move |r| check(&call, r)));
+        Ok(GrpcStream::new(stream, self.opt.chunk_size))
+    }
+}
+
+/// Whether the response headers of `call` open the tunnel.
+fn check(call: &str, response: &Response<RecvStream>) -> std::io::Result<()> {
+    let refused = |msg: String| std::io::Error::new(ErrorKind::ConnectionRefused, msg);
+    if response.status() != StatusCode::OK {
+        return Err(refused(format!(
+            "grpc server answered {}",
+            response.status()
+        )));
+    }
+    // a call refused at once ends in its headers
+    let headers = response.headers();
+    if let Some(status) = headers.get("grpc-status") {
+        if status != "0" || response.body().is_end_stream() {
+            return Err(refused(format!(
+                "grpc call {} failed with status {} {}",
+                call,
+                status.to_str().unwrap_or_default(),
+                headers
+                    .get("grpc-message")
+                    .and_then(|m| m.to_str().ok())
+                    .unwrap_or_default()
+            )));
+        }
+    }
+    if !headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.starts_with(CONTENT_TYPE))
+    {
+        return Err(refused(format!(
+            "grpc server answered content type {:?}",
+            headers.get(header::CONTENT_TYPE).map(HeaderValue::as_bytes)
+        )));
+    }
+    Ok(())
+}
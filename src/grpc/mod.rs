@@ -0,0 +1,279 @@
+//! Grpc Transport
+//!
+//! A byte stream tunneled in a bidi streaming grpc call, as the common
+//! "gun" tunnels do: each message is a protobuf whose only field, number
+//! 1, holds a chunk of the stream. Calls are http/2 streams, tls with the
+//! `h2` alpn is required.
+
+pub mod client;
+pub use client::GrpcClient;
+
+pub mod server;
+pub use server::GrpcServer;
+
+pub mod stream;
+pub use stream::GrpcStream;
+
+pub mod option;
+pub use option::{GrpcClientOption, GrpcServerOption};
+
+/// Content type of grpc requests and responses.
+pub const CONTENT_TYPE: &str = "application/grpc";
+
+/// The request path of a method.
+pub(crate) fn method_path(service_name: &str, method_name: &str) -> Result<String, String> {
+    for (what, name) in [("service", service_name), ("method", method_name)] {
+        if name.is_empty() || name.contains(['/', '?', '#']) {
+            return Err(format!(
+                "grpc {} name {:?} is not a path segment",
+                what, name
+            ));
+        }
+    }
+    Ok(format!("/{}/{}", service_name, method_name))
+}
+
+/// Check a chunk size fits a message.
+pub(crate) fn check_chunk_size(chunk_size: usize) -> Result<(), String> {
+    if chunk_size == 0 || chunk_size > stream::MAX_CHUNK_SIZE {
+        return Err(format!(
+            "grpc chunk_size {} is not within 1..={}",
+            chunk_size,
+            stream::MAX_CHUNK_SIZE
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::{BufMut, BytesMut};
+
+    use crate::{
+        option::{ClientOption, ServerOption, CONFIG_VERSION},
+        Resolver, TlsClientOption, TlsServerOption, TransportClient, TransportClientOption,
+        TransportServer, TransportServerOption,
+    };
+    // the grpc transport only runs over tls
+    #[cfg(feature = "insecure-tls")]
+    use crate::{
+        test_util::EchoCallback, TlsCertOption, TransportClientTrait, TransportServerTrait,
+    };
+    #[cfg(feature = "insecure-tls")]
+    use std::{sync::Arc, time::Duration};
+    #[cfg(feature = "insecure-tls")]
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::{
+        option::{DEFAULT_CHUNK_SIZE, DEFAULT_METHOD_NAME, DEFAULT_SERVICE_NAME},
+        stream::{decode, encode},
+        *,
+    };
+
+    #[test]
+    fn test_codec() {
+        let mut buf = BytesMut::new();
+        encode(b"kapi", &mut buf);
+        assert_eq!(&buf[..], b"\x00\x00\x00\x00\x06\x0a\x04kapi");
+        encode(&[7; 300], &mut buf);
+
+        // a message is only taken once complete
+        let mut partial = buf.split_to(8);
+        assert!(decode(&mut partial).unwrap().is_none());
+        partial.unsplit(buf);
+        let mut buf = partial;
+        assert_eq!(&decode(&mut buf).unwrap().unwrap()[..], b"kapi");
+        assert_eq!(decode(&mut buf).unwrap().unwrap(), vec![7; 300]);
+        assert!(buf.is_empty());
+
+        // unknown fields of every wire type are skipped
+        let mut message = BytesMut::new();
+        message.put_slice(b"\x10\x96\x01\x19");
+        message.put_u64(1);
+        message.put_slice(b"\x0a\x02ok\x22\x01x\x2d");
+        message.put_u32(1);
+        let mut buf = BytesMut::new();
+        buf.put_u8(0);
+        buf.put_u32(message.len() as u32);
+        buf.put_slice(&message);
+        assert_eq!(&decode(&mut buf).unwrap().unwrap()[..], b"ok");
+
+        for bad in [
+            &b"\x01\x00\x00\x00\x00"[..],
+            &b"\x00\x00\x00\x00\x03\x0a\x05k"[..],
+            &b"\x00\x00\x00\x00\x01\x0f"[..],
+            &b"\x00\xff\x00\x00\x00"[..],
+        ] {
+            let err = decode(&mut BytesMut::from(bad)).unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::InvalidData, "{:?}", bad);
+        }
+    }
+
+    #[cfg(feature = "insecure-tls")]
+    fn server_tls() -> TlsServerOption {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+        TlsServerOption {
+            alpn: vec![],
+            require_alpn: false,
+            certificate: TlsCertOption::Text {
+                certs: vec![cert.cert.pem()],
+                key: cert.key_pair.serialize_pem().into(),
+            },
+            ticket_keys: None,
+            client_ca: None,
+            require_client_cert: false,
+            session_tickets: false,
+            min_version: None,
+            max_version: None,
+            cipher_suites: vec![],
+        }
+    }
+
+    fn server_option(port: u16, tls: Option<TlsServerOption>) -> TransportServerOption {
+        TransportServerOption {
+            opt: ServerOption::Grpc(GrpcServerOption {
+                listen: ([127, 0, 0, 1], port).into(),
+                service_name: DEFAULT_SERVICE_NAME.into(),
+                method_name: DEFAULT_METHOD_NAME.into(),
+                tcp_nodelay: true,
+                chunk_size: DEFAULT_CHUNK_SIZE,
+                max_concurrent_streams: None,
+                max_connections: None,
+            }),
+            tls,
+            latency_profile: Default::default(),
+            policy: Default::default(),
+            version: CONFIG_VERSION,
+        }
+    }
+
+    fn client_option(port: u16, service_name: &str, tls: bool) -> TransportClientOption {
+        TransportClientOption {
+            opt: ClientOption::Grpc(GrpcClientOption {
+                addr: "127.0.0.1".into(),
+                port,
+                service_name: service_name.into(),
+                method_name: DEFAULT_METHOD_NAME.into(),
+                tcp_nodelay: true,
+                connect_timeout: None,
+                chunk_size: 1000,
+            }),
+            tls: tls.then(|| TlsClientOption {
+                insecure: true,
+                server_name: "localhost".into(),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    #[cfg(feature = "insecure-tls")]
+    #[tokio::test]
+    async fn test_grpc_tunnel() {
+        let srv = Arc::new(TransportServer::init(server_option(9911, Some(server_tls()))).unwrap());
+        assert_eq!(srv.name(), "Grpc");
+        let serving = srv.clone();
+        tokio::spawn(async move { serving.serve(EchoCallback).await });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let cli = TransportClient::init(
+            client_option(9911, DEFAULT_SERVICE_NAME, true),
+            &Resolver::default(),
+        )
+        .unwrap();
+        let mut stream = cli.connect().await.unwrap();
+        let mut line = vec![0; "grpc /GunService/Tun\n".len()];
+        stream.read_exact(&mut line).await.unwrap();
+        assert_eq!(line, b"grpc /GunService/Tun\n");
+
+        // writes are split into messages of chunk_size
+        let payload = (0..64 * 1024).map(|i| i as u8).collect::<Vec<_>>();
+        let (mut rd, mut wr) = tokio::io::split(stream);
+        let write = async {
+            wr.write_all(&payload).await.unwrap();
+            wr.shutdown().await.unwrap();
+        };
+        let mut echoed = vec![];
+        tokio::join!(write, rd.read_to_end(&mut echoed)).1.unwrap();
+        assert_eq!(echoed, payload);
+
+        // a second call rides the same connection, another method is
+        // refused on the first read
+        assert!(cli.connect().await.is_ok());
+        let other = TransportClient::init(client_option(9911, "Other", true), &Resolver::default())
+            .unwrap();
+        let mut stream = other.connect().await.unwrap();
+        let err = stream.read(&mut [0u8; 4]).await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::ConnectionRefused);
+        assert!(err.to_string().contains("status 12"), "{}", err);
+        // and stays failed
+        let err = stream.read(&mut [0u8; 4]).await.unwrap_err();
+        assert!(err.to_string().contains("status 12"), "{}", err);
+        assert_eq!(srv.stats().ipv4.accepted, 2);
+    }
+
+    /// A grpc server answering a call only once its first message arrived.
+    #[cfg(feature = "insecure-tls")]
+    #[tokio::test]
+    async fn test_grpc_client_first() {
+        let tls = TlsServerOption {
+            alpn: vec!["h2".into()],
+            ..server_tls()
+        };
+        let config: rustls::ServerConfig = tls.try_into().unwrap();
+        let acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(config));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (s, _) = listener.accept().await.unwrap();
+            let s = acceptor.accept(s).await.unwrap();
+            let mut conn = h2::server::handshake(s).await.unwrap();
+            let (request, mut respond) = conn.accept().await.unwrap().unwrap();
+            tokio::spawn(async move { while conn.accept().await.is_some() {} });
+
+            let mut body = request.into_body();
+            let message = body.data().await.unwrap().unwrap();
+            let response = http::Response::builder()
+                .header(http::header::CONTENT_TYPE, CONTENT_TYPE)
+                .body(())
+                .unwrap();
+            let mut send = respond.send_response(response, false).unwrap();
+            send.send_data(message, false).unwrap();
+            let _ = std::future::pending::<()>().await;
+            drop(send);
+        });
+
+        let cli = TransportClient::init(
+            client_option(port, DEFAULT_SERVICE_NAME, true),
+            &Resolver::default(),
+        )
+        .unwrap();
+        let connect = tokio::time::timeout(Duration::from_secs(1), cli.connect());
+        let mut stream = connect
+            .await
+            .expect("connect waited for the server")
+            .unwrap();
+        stream.write_all(b"hello").await.unwrap();
+        let mut buf = [0u8; 5];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+    }
+
+    #[test]
+    fn test_grpc_requires_tls() {
+        assert!(TransportServer::init(server_option(9912, None)).is_err());
+        assert!(TransportClient::init(
+            client_option(9912, DEFAULT_SERVICE_NAME, false),
+            &Resolver::default()
+        )
+        .is_err());
+
+        let mut opt = client_option(9912, "Gun/Service", true);
+        assert!(TransportClient::init(opt.clone(), &Resolver::default()).is_err());
+        if let ClientOption::Grpc(ref mut grpc) = opt.opt {
+            grpc.service_name = DEFAULT_SERVICE_NAME.into();
+            grpc.chunk_size = 0;
+        }
+        assert!(TransportClient::init(opt, &Resolver::default()).is_err());
+    }
+}
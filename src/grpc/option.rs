@@ -0,0 +1,66 @@
+//! Transport Grpc Option
+
+use std::{net::SocketAddr, time::Duration};
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GrpcClientOption {
+    pub addr: String,
+    pub port: u16,
+    /// Service of the tunnel method, the call goes to `/{service_name}/{method_name}`.
+    #[serde(default = "default_service_name")]
+    pub service_name: String,
+    #[serde(default = "default_method_name")]
+    pub method_name: String,
+    #[serde(default)]
+    pub tcp_nodelay: bool,
+    /// Give up on one resolved address after this long and try the next.
+    #[serde(default)]
+    pub connect_timeout: Option<Duration>,
+    /// Most bytes one message carries, larger writes are split.
+    #[serde(default = "default_chunk_size")]
+    pub chunk_size: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GrpcServerOption {
+    pub listen: SocketAddr,
+    /// Service of the tunnel method, calls of other methods are unimplemented.
+    #[serde(default = "default_service_name")]
+    pub service_name: String,
+    #[serde(default = "default_method_name")]
+    pub method_name: String,
+    #[serde(default)]
+    pub tcp_nodelay: bool,
+    /// Most bytes one message carries, larger writes are split.
+    #[serde(default = "default_chunk_size")]
+    pub chunk_size: usize,
+    /// Calls one connection may have open at once.
+    #[serde(default)]
+    pub max_concurrent_streams: Option<u32>,
+    /// Connections served at once, see `TcpServerOption::max_connections`.
+    #[serde(default)]
+    pub max_connections: Option<usize>,
+}
+
+/// Default of `service_name`, the one of common grpc tunnels.
+pub const DEFAULT_SERVICE_NAME: &str = "GunService";
+
+/// Default of `method_name`.
+pub const DEFAULT_METHOD_NAME: &str = "Tun";
+
+/// Default of `chunk_size`.
+pub const DEFAULT_CHUNK_SIZE: usize = 16 * 1024;
+
+fn default_service_name() -> String {
+    DEFAULT_SERVICE_NAME.to_owned()
+}
+
+fn default_method_name() -> String {
+    DEFAULT_METHOD_NAME.to_owned()
+}
+
+fn default_chunk_size() -> usize {
+    DEFAULT_CHUNK_SIZE
+}
@@ -0,0 +1,170 @@
+//! Transport Grpc Server
+
+use std::{future::Future, net::SocketAddr, time::Duration};
+
+use bytes::Bytes;
+use h2::{server::SendResponse, RecvStream};
+use http::{header, HeaderMap, HeaderValue, Method, Request, Response, StatusCode};
+
+use crate::{
+    context::AcceptHook,
+    http2::{
+        option::{DEFAULT_CONNECTION_WINDOW, DEFAULT_STREAM_WINDOW},
+        server::{tcp_server, H2Connection, StreamOpener},
+        H2Stream,
+    },
+    option::LatencyProfile,
+    stats::ServerStatsSnapshot,
+    tcp::TcpServer,
+    ServerError, ServerResult, TlsServerOption, TransportServerCallback, TransportServerTrait,
+};
+
+use super::{check_chunk_size, method_path, GrpcServerOption, GrpcStream, CONTENT_TYPE};
+
+/// Serves the tunnel method, handing each call to the callback as a stream.
+///
+/// Accept hooks run once per connection, limits and stats count
+/// connections, not calls.
+pub struct GrpcServer {
+    tcp: TcpServer,
+    opt: GrpcServerOption,
+    path: String,
+}
+
+impl GrpcServer {
+    pub fn init(opt: GrpcServerOption, tls_opt: Option<TlsServerOption>) -> ServerResult<Self> {
+        if tls_opt.is_none() {
+            return Err(ServerError::Option(
+                "grpc transport requires tls".to_owned(),
+            ));
+        }
+        check_chunk_size(opt.chunk_size).map_err(ServerError::Option)?;
+        let path = method_path(&opt.service_name, &opt.method_name).map_err(ServerError::Option)?;
+
+        Ok(Self {
+            tcp: tcp_server(opt.listen, opt.tcp_nodelay, opt.max_connections, tls_opt)?,
+            opt,
+            path,
+        })
+    }
+
+    /// Run `hook` on every accepted connection after the tls handshake.
+    pub fn add_accept_hook(&mut self, hook: AcceptHook) {
+        self.tcp.add_accept_hook(hook);
+    }
+
+    /// Socket settings of the profile, the options are overridden by `apply_latency_profile`.
+    pub fn set_latency_profile(&mut self, profile: LatencyProfile) {
+        self.tcp.set_latency_profile(profile);
+    }
+
+    pub fn stats(&self) -> ServerStatsSnapshot {
+        self.tcp.stats()
+    }
+
+    fn connection_callback<C: TransportServerCallback>(
+        &self,
+        callback: C,
+    ) -> H2Connection<C, CallOpener> {
+        let mut builder = h2::server::Builder::new();
+        builder
+            .initial_window_size(DEFAULT_STREAM_WINDOW)
+            .initial_connection_window_size(DEFAULT_CONNECTION_WINDOW);
+        if let Some(max) = self.opt.max_concurrent_streams {
+            builder.max_concurrent_streams(max);
+        }
+        let opener = CallOpener {
+            path: self.path.clone(),
+            chunk_size: self.opt.chunk_size,
+        };
+        H2Connection::new(callback, builder, opener)
+    }
+}
+
+impl TransportServerTrait for GrpcServer {
+    fn local_addr(&self) -> Option<SocketAddr> {
+        self.tcp.local_addr()
+    }
+
+    async fn serve<C: TransportServerCallback>(&self, callback: C) -> ServerResult<()> {
+        self.tcp.serve(self.connection_callback(callback)).await
+    }
+
+    /// The grace period covers connections, a connection still running
+    /// after it drops its calls with it.
+    async fn serve_with_shutdown<C, F>(
+        &self,
+        callback: C,
+        signal: F,
+        grace: Duration,
+    ) -> ServerResult<()>
+    where
+        C: TransportServerCallback,
+        F: Future<Output = ()> + Send + Sync,
+    {
+        self.tcp
+            .serve_with_shutdown(self.connection_callback(callback), signal, grace)
+            .await
+    }
+}
+
+/// Takes calls of the tunnel method, answering others like a grpc server.
+#[derive(Clone)]
+struct CallOpener {
+    path: String,
+    chunk_size: usize,
+}
+
+/// grpc-status of a method the server does not have.
+const UNIMPLEMENTED: &str = "12";
+
+impl StreamOpener for CallOpener {
+    type Stream = GrpcStream;
+
+    const TRANSPORT: &'static str = "grpc";
+
+    fn open(
+        &self,
+        req: Request<RecvStream>,
+        mut respond: SendResponse<Bytes>,
+    ) -> Option<(GrpcStream, Request<()>)> {
+        let is_grpc = req
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(|v| v.starts_with(CONTENT_TYPE));
+        let refused = if req.method() != Method::POST {
+            Some(Response::builder().status(StatusCode::METHOD_NOT_ALLOWED))
+        } else if !is_grpc {
+            Some(Response::builder().status(StatusCode::UNSUPPORTED_MEDIA_TYPE))
+        } else if req.uri().path() != self.path {
+            Some(
+                Response::builder()
+                    .header(header::CONTENT_TYPE, CONTENT_TYPE)
+                    .header("grpc-status", UNIMPLEMENTED)
+                    .header("grpc-message", "unknown method"),
+            )
+        } else {
+            None
+        };
+        if let Some(response) = refused {
+            log::debug!("grpc {} {} refused", req.method(), req.uri());
+            let _ = respond.send_response(response.body(()).ok()?, true);
+            return None;
+        }
+
+        let response = Response::builder()
+            .header(header::CONTENT_TYPE, CONTENT_TYPE)
+            .body(())
+            .ok()?;
+        let (parts, recv) = req.into_parts();
+        let send = respond.send_response(response, false).ok()?;
+        let mut trailers = HeaderMap::new();
+        trailers.insert("grpc-status", HeaderValue::from_static("0"));
+        let stream = H2Stream::new(send, recv).with_trailers(trailers);
+        Some((
+            GrpcStream::new(stream, self.chunk_size),
+            Request::from_parts(parts, ()),
+        ))
+    }
+}
@@ -0,0 +1,236 @@
+//! Grpc Transport Stream
+
+use std::{
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use futures_util::ready;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::http2::H2Stream;
+
+/// Largest message read, the grpc default receive limit.
+pub const MAX_MESSAGE_SIZE: usize = 4 * 1024 * 1024;
+
+/// Largest `chunk_size`.
+pub const MAX_CHUNK_SIZE: usize = 1024 * 1024;
+
+/// Length prefix of a message, a compressed flag and a big endian length.
+const PREFIX_LEN: usize = 5;
+
+/// Key of field 1 with the length delimited wire type.
+const DATA_KEY: u64 = 1 << 3 | 2;
+
+/// The byte stream of a tunnel call over its http/2 stream.
+///
+/// A write sends at most `chunk_size` bytes as one message, reads hand out
+/// the chunks of received messages in order.
+pub struct GrpcStream {
+    inner: H2Stream,
+    chunk_size: usize,
+    /// Received bytes not yet decoded.
+    read_buf: BytesMut,
+    /// Decoded chunk not yet read.
+    chunk: Bytes,
+    /// Encoded message not yet written to `inner`.
+    write_buf: BytesMut,
+}
+
+impl std::fmt::Debug for GrpcStream {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("GrpcStream")
+            .field("inner", &self.inner)
+            .field("chunk_size", &self.chunk_size)
+            .finish()
+    }
+}
+
+impl GrpcStream {
+    pub(crate) fn new(inner: H2Stream, chunk_size: usize) -> Self {
+        Self {
+            inner,
+            chunk_size,
+            read_buf: BytesMut::new(),
+            chunk: Bytes::new(),
+            write_buf: BytesMut::new(),
+        }
+    }
+
+    fn poll_write_buf(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.write_buf.has_remaining() {
+            let n = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.write_buf))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.write_buf.advance(n);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Append `chunk` as a message.
+pub(crate) fn encode(chunk: &[u8], dst: &mut BytesMut) {
+    let mut field = BytesMut::with_capacity(chunk.len() + 11);
+    put_varint(&mut field, DATA_KEY);
+    put_varint(&mut field, chunk.len() as u64);
+    field.put_slice(chunk);
+
+    dst.reserve(PREFIX_LEN + field.len());
+    dst.put_u8(0);
+    dst.put_u32(field.len() as u32);
+    dst.put_slice(&field);
+}
+
+/// Take the chunk of the first message in `src`, `None` until it is complete.
+pub(crate) fn decode(src: &mut BytesMut) -> io::Result<Option<Bytes>> {
+    if src.len() < PREFIX_LEN {
+        return Ok(None);
+    }
+    if src[0] != 0 {
+        return Err(invalid("compressed message"));
+    }
+    let len = u32::from_be_bytes([src[1], src[2], src[3], src[4]]) as usize;
+    if len > MAX_MESSAGE_SIZE {
+        return Err(invalid(&format!("message of {} bytes", len)));
+    }
+    if src.len() < PREFIX_LEN + len {
+        src.reserve(PREFIX_LEN + len - src.len());
+        return Ok(None);
+    }
+    src.advance(PREFIX_LEN);
+    let mut message = src.split_to(len).freeze();
+
+    // the last field 1 wins, unknown fields are skipped
+    let mut chunk = Bytes::new();
+    while message.has_remaining() {
+        let key = get_varint(&mut message)?;
+        let skip = match key & 7 {
+            0 => {
+                get_varint(&mut message)?;
+                0
+            }
+            1 => 8,
+            2 => {
+                let n = get_varint(&mut message)? as usize;
+                if n > message.len() {
+                    return Err(invalid("truncated field"));
+                }
+                if key == DATA_KEY {
+                    chunk = message.split_to(n);
+                    0
+                } else {
+                    n
+                }
+            }
+            5 => 4,
+            _ => return Err(invalid("unknown wire type")),
+        };
+        if skip > message.len() {
+            return Err(invalid("truncated field"));
+        }
+        message.advance(skip);
+    }
+    Ok(Some(chunk))
+}
+
+fn put_varint(dst: &mut BytesMut, mut v: u64) {
+    while v >= 0x80 {
+        dst.put_u8(v as u8 | 0x80);
+        v >>= 7;
+    }
+    dst.put_u8(v as u8);
+}
+
+fn get_varint(src: &mut Bytes) -> io::Result<u64> {
+    let mut v = 0;
+    for shift in (0..64).step_by(7) {
+        if !src.has_remaining() {
+            return Err(invalid("truncated varint"));
+        }
+        let b = src.get_u8();
+        v |= u64::from(b & 0x7f) << shift;
+        if b < 0x80 {
+            return Ok(v);
+        }
+    }
+    Err(invalid("varint too long"))
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("grpc: {}", msg))
+}
+
+impl AsyncRead for GrpcStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            if buf.remaining() == 0 {
+                return Poll::Ready(Ok(()));
+            }
+            if this.chunk.has_remaining() {
+                let n = this.chunk.len().min(buf.remaining());
+                buf.put_slice(&this.chunk[..n]);
+                this.chunk.advance(n);
+                return Poll::Ready(Ok(()));
+            }
+            if let Some(chunk) = decode(&mut this.read_buf)? {
+                this.chunk = chunk;
+                continue;
+            }
+
+            let mut raw = [0u8; 8192];
+            let mut raw = ReadBuf::new(&mut raw);
+            ready!(Pin::new(&mut this.inner).poll_read(cx, &mut raw))?;
+            if raw.filled().is_empty() {
+                if this.read_buf.has_remaining() {
+                    return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+                }
+                return Poll::Ready(Ok(()));
+            }
+            this.read_buf.extend_from_slice(raw.filled());
+        }
+    }
+}
+
+impl AsyncWrite for GrpcStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        ready!(this.poll_write_buf(cx))?;
+
+        let n = buf.len().min(this.chunk_size);
+        encode(&buf[..n], &mut this.write_buf);
+        // the message is taken, what is left goes out on the next call
+        if let Poll::Ready(Err(e)) = this.poll_write_buf(cx) {
+            return Poll::Ready(Err(e));
+        }
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_buf(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_buf(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+crate::timeout_io_methods!(GrpcStream);
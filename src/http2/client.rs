@@ -0,0 +1,199 @@
+//! Http2 Transport client
+
+use std::net::SocketAddr;
+
+use bytes::Bytes;
+use h2::{
+    client::{ResponseFuture, SendRequest},
+    RecvStream, SendStream,
+};
+use http::{request, Method, Request, Response, StatusCode};
+use tokio::sync::Mutex;
+
+use crate::{
+    option::{LatencyProfile, ProbeMode},
+    tcp::{TcpClient, TcpClientOption},
+    ClientError, ClientResult, Resolver, TlsClientOption, TransportClientTrait,
+};
+
+use super::{stream::io_error, H2ClientOption, H2Stream, ALPN_H2};
+
+/// Keeps one http/2 connection and opens a stream on it per connect.
+///
+/// The connection is dialed on the first connect and again after it
+/// failed. Without tls it speaks http/2 with prior knowledge.
+pub struct H2Client {
+    tcp: TcpClient,
+    opt: H2ClientOption,
+    uri: String,
+    conn: Mutex<Option<SendRequest<Bytes>>>,
+}
+
+impl H2Client {
+    pub fn init(
+        opt: H2ClientOption,
+        tls_opt: Option<TlsClientOption>,
+        resolver: &Resolver,
+    ) -> ClientResult<Self> {
+        let scheme = scheme(&tls_opt);
+        let tcp = TcpClient::init(tcp_option(&opt), tls_option(tls_opt), resolver)?;
+        Self::with_tcp(opt, scheme, tcp)
+    }
+
+    /// Init with already resolved addresses of `opt.addr`.
+    pub fn with_addrs(
+        opt: H2ClientOption,
+        tls_opt: Option<TlsClientOption>,
+        addr: Vec<SocketAddr>,
+    ) -> ClientResult<Self> {
+        let scheme = scheme(&tls_opt);
+        let tcp = TcpClient::with_addrs(tcp_option(&opt), tls_option(tls_opt), addr)?;
+        Self::with_tcp(opt, scheme, tcp)
+    }
+
+    fn with_tcp(opt: H2ClientOption, scheme: &str, tcp: TcpClient) -> ClientResult<Self> {
+        if !opt.path.starts_with('/') {
+            return Err(ClientError::Option(format!(
+                "h2 path {} does not start with /",
+                opt.path
+            )));
+        }
+        let host = match opt.addr.parse::<std::net::Ipv6Addr>() {
+            Ok(_) => format!("[{}]", opt.addr),
+            Err(_) => opt.addr.clone(),
+        };
+        let uri = format!("{}://{}:{}{}", scheme, host, opt.port, opt.path);
+        uri.parse::<http::Uri>()
+            .map_err(|e| ClientError::Option(format!("h2 request uri {} ({})", uri, e)))?;
+
+        Ok(Self {
+            tcp,
+            opt,
+            uri,
+            conn: Mutex::new(None),
+        })
+    }
+
+    /// Streams share one connection, there is no connection per stream to probe.
+    pub fn set_post_connect_probe(&mut self, probe: Option<ProbeMode>) -> ClientResult<()> {
+        if probe.is_some() {
+            return Err(ClientError::Option(
+                "post connect probes need a tcp or ws transport".to_owned(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Socket settings of the profile, the options are overridden by `apply_latency_profile`.
+    pub fn set_latency_profile(&mut self, profile: LatencyProfile) {
+        self.tcp.set_latency_profile(profile);
+    }
+
+    /// A sender ready for a new stream, dialing when there is no live connection.
+    async fn sender(&self) -> ClientResult<SendRequest<Bytes>> {
+        let mut conn = self.conn.lock().await;
+        if let Some(send) = conn.take() {
+            match send.ready().await {
+                Ok(send) => {
+                    *conn = Some(send.clone());
+                    return Ok(send);
+                }
+                Err(e) => log::debug!("h2 connection lost ({}), dialing again", e),
+            }
+        }
+
+        let stream = self.tcp.connect().await?;
+        let (send, connection) = h2::client::Builder::new()
+            .initial_window_size(self.opt.stream_window)
+            .initial_connection_window_size(self.opt.connection_window)
+            .handshake::<_, Bytes>(stream)
+            .await
+            .map_err(|e| ClientError::Connect(format!("h2 handshake failed: {}", e)))?;
+        tokio::spawn(async move {
+            if let Err(e) = connection.await {
+                log::debug!("h2 connection closed ({})", e);
+            }
+        });
+
+        let send = send.ready().await.map_err(io_error)?;
+        *conn = Some(send.clone());
+        Ok(send)
+    }
+
+    /// A POST on the configured uri, for callers adding their headers.
+    pub(crate) fn request(&self) -> request::Builder {
+        Request::builder()
+            .method(Method::POST)
+            .uri(self.uri.as_str())
+    }
+
+    /// Send `request` as a new stream, the response still to come.
+    pub(crate) async fn send(
+        &self,
+        request: Request<()>,
+    ) -> ClientResult<(ResponseFuture, SendStream<Bytes>)> {
+        let mut send = self.sender().await?;
+        Ok(send.send_request(request, false).map_err(io_error)?)
+    }
+
+    /// Send `request` as a new stream and wait for the response headers.
+    pub(crate) async fn open(
+        &self,
+        request: Request<()>,
+    ) -> ClientResult<(Response<RecvStream>, SendStream<Bytes>)> {
+        let (response, send_stream) = self.send(request).await?;
+        let response = response.await.map_err(io_error)?;
+        Ok((response, send_stream))
+    }
+}
+
+fn tcp_option(opt: &H2ClientOption) -> TcpClientOption {
+    TcpClientOption {
+        addr: opt.addr.clone(),
+        port: opt.port,
+        tcp_nodelay: opt.tcp_nodelay,
+        connect_timeout: opt.connect_timeout,
+        connect_deadline: None,
+        attempt_delay: None,
+        resolve_on_connect: false,
+        proxy: None,
+        bind_addr: None,
+        bind_device: None,
+    }
+}
+
+fn scheme(tls_opt: &Option<TlsClientOption>) -> &'static str {
+    if tls_opt.is_some() {
+        "https"
+    } else {
+        "http"
+    }
+}
+
+/// Offer only h2, whatever alpn the option names.
+fn tls_option(tls_opt: Option<TlsClientOption>) -> Option<TlsClientOption> {
+    tls_opt.map(|tls_opt| TlsClientOption {
+        alpn: vec![ALPN_H2.to_owned()],
+        ..tls_opt
+    })
+}
+
+impl TransportClientTrait for H2Client {
+    type Stream = H2Stream;
+
+    async fn connect(&self) -> ClientResult<Self::Stream> {
+        let request = self
+            .request()
+            .body(())
+            .map_err(|e| ClientError::Option(e.to_string()))?;
+        let (response, send_stream) = self.open(request).await?;
+        if response.status() != StatusCode::OK {
+            return Err(ClientError::Connect(format!(
+                "h2 server answered {} on {}",
+                response.status(),
+                self.opt.path
+            )));
+        }
+        Ok(H2Stream::new(send_stream, response.into_body()))
+    }
+}
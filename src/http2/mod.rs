@@ -0,0 +1,200 @@
+//! Http2 Transport
+//!
+//! Many streams over one http/2 connection, each opened by a POST on the
+//! configured path. DATA frames carry the bytes, read and written like a
+//! tcp stream.
+
+pub mod client;
+pub use client::H2Client;
+
+pub mod server;
+pub use server::H2Server;
+
+pub mod stream;
+pub use stream::H2Stream;
+
+pub mod option;
+pub use option::{H2ClientOption, H2ServerOption};
+
+/// The alpn protocol of http/2 over tls.
+pub const ALPN_H2: &str = "h2";
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+
+    use crate::{
+        option::{ClientOption, ProbeMode, ServerOption, CONFIG_VERSION},
+        test_util::EchoCallback,
+        Resolver, TlsClientOption, TlsServerOption, TransportClient, TransportClientOption,
+        TransportClientTrait, TransportServer, TransportServerOption, TransportServerTrait,
+    };
+
+    use super::{
+        option::{DEFAULT_CONNECTION_WINDOW, DEFAULT_STREAM_WINDOW},
+        *,
+    };
+
+    fn server_option(port: u16, tls: Option<TlsServerOption>) -> TransportServerOption {
+        TransportServerOption {
+            opt: ServerOption::H2(H2ServerOption {
+                listen: ([127, 0, 0, 1], port).into(),
+                path: "/tunnel".into(),
+                tcp_nodelay: true,
+                // small windows, larger writes wait for the reader
+                stream_window: 16 * 1024,
+                connection_window: 64 * 1024,
+                max_concurrent_streams: Some(8),
+                max_connections: None,
+            }),
+            tls,
+            latency_profile: Default::default(),
+            policy: Default::default(),
+            version: CONFIG_VERSION,
+        }
+    }
+
+    fn client(port: u16, path: &str, tls: Option<TlsClientOption>) -> TransportClient {
+        let opt = TransportClientOption {
+            opt: ClientOption::H2(H2ClientOption {
+                addr: "127.0.0.1".into(),
+                port,
+                path: path.into(),
+                tcp_nodelay: true,
+                connect_timeout: None,
+                stream_window: 16 * 1024,
+                connection_window: 64 * 1024,
+            }),
+            tls,
+            ..Default::default()
+        };
+        TransportClient::init(opt, &Resolver::default()).unwrap()
+    }
+
+    async fn read_line<S: AsyncRead + Unpin>(stream: &mut S) -> String {
+        let mut line = vec![];
+        let mut byte = [0u8];
+        while stream.read_exact(&mut byte).await.is_ok() && byte[0] != b'\n' {
+            line.push(byte[0]);
+        }
+        String::from_utf8(line).unwrap()
+    }
+
+    #[cfg(feature = "insecure-tls")]
+    #[tokio::test]
+    async fn test_h2_streams() {
+        use crate::TlsCertOption;
+
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+        let certificate = TlsCertOption::Text {
+            certs: vec![cert.cert.pem()],
+            key: cert.key_pair.serialize_pem().into(),
+        };
+        let tls = TlsServerOption {
+            alpn: vec![],
+            require_alpn: false,
+            certificate,
+            ticket_keys: None,
+            client_ca: None,
+            require_client_cert: false,
+            session_tickets: false,
+            min_version: None,
+            max_version: None,
+            cipher_suites: vec![],
+        };
+        let srv =
+            std::sync::Arc::new(TransportServer::init(server_option(9901, Some(tls))).unwrap());
+        assert_eq!(srv.name(), "H2");
+        let serving = srv.clone();
+        tokio::spawn(async move { serving.serve(EchoCallback).await });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let tls = TlsClientOption {
+            insecure: true,
+            server_name: "localhost".into(),
+            ..Default::default()
+        };
+        let cli = client(9901, "/tunnel", Some(tls));
+        let mut streams = vec![];
+        for _ in 0..3 {
+            let mut stream = cli.connect().await.unwrap();
+            assert_eq!(read_line(&mut stream).await, "h2 /tunnel");
+            streams.push(stream);
+        }
+
+        // each stream moves far more than its window, concurrently
+        let payload = (0..256 * 1024).map(|i| i as u8).collect::<Vec<_>>();
+        let echoes = streams.into_iter().map(|stream| {
+            let payload = payload.clone();
+            tokio::spawn(async move {
+                let (mut rd, mut wr) = tokio::io::split(stream);
+                let write = async {
+                    wr.write_all(&payload).await.unwrap();
+                    wr.shutdown().await.unwrap();
+                };
+                let mut echoed = vec![];
+                let read = rd.read_to_end(&mut echoed);
+                tokio::join!(write, read).1.unwrap();
+                assert_eq!(echoed, payload);
+            })
+        });
+        for echo in echoes {
+            echo.await.unwrap();
+        }
+
+        // all streams shared one connection
+        assert_eq!(srv.stats().ipv4.accepted, 1);
+    }
+
+    #[tokio::test]
+    async fn test_h2_plaintext_and_paths() {
+        let srv = TransportServer::init(server_option(9902, None)).unwrap();
+        tokio::spawn(async move { srv.serve(EchoCallback).await });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let mut stream = client(9902, "/tunnel", None).connect().await.unwrap();
+        assert_eq!(read_line(&mut stream).await, "h2 /tunnel");
+        stream.write_all(b"kapi").await.unwrap();
+        let mut buf = [0u8; 4];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"kapi");
+
+        let Err(err) = client(9902, "/other", None).connect().await else {
+            panic!("stream opened on an unserved path");
+        };
+        assert!(err.to_string().contains("404"), "{}", err);
+
+        // a client refused a stream keeps its connection for the next
+        let cli = client(9902, "/tunnel", None);
+        assert!(cli.connect().await.is_ok());
+        assert!(cli.connect().await.is_ok());
+    }
+
+    #[test]
+    fn test_h2_option_errors() {
+        let mut opt = server_option(9903, None);
+        if let ServerOption::H2(ref mut h2) = opt.opt {
+            h2.path = "tunnel".into();
+        }
+        assert!(TransportServer::init(opt).is_err());
+
+        let opt = TransportClientOption {
+            opt: ClientOption::H2(H2ClientOption {
+                addr: "127.0.0.1".into(),
+                port: 443,
+                path: "/".into(),
+                tcp_nodelay: true,
+                connect_timeout: None,
+                stream_window: DEFAULT_STREAM_WINDOW,
+                connection_window: DEFAULT_CONNECTION_WINDOW,
+            }),
+            post_connect_probe: Some(ProbeMode::WsPing {
+                timeout: Duration::from_secs(1),
+            }),
+            ..Default::default()
+        };
+        assert!(TransportClient::init(opt, &Resolver::default()).is_err());
+    }
+}
@@ -0,0 +1,65 @@
+//! Transport Http2 Option
+
+use std::{net::SocketAddr, time::Duration};
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct H2ClientOption {
+    pub addr: String,
+    pub port: u16,
+    /// Path every stream is requested on.
+    #[serde(default = "default_path")]
+    pub path: String,
+    #[serde(default)]
+    pub tcp_nodelay: bool,
+    /// Give up on one resolved address after this long and try the next.
+    #[serde(default)]
+    pub connect_timeout: Option<Duration>,
+    /// Bytes the server may send on one stream before it is read.
+    #[serde(default = "default_stream_window")]
+    pub stream_window: u32,
+    /// Bytes the server may send across all streams before they are read.
+    #[serde(default = "default_connection_window")]
+    pub connection_window: u32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct H2ServerOption {
+    pub listen: SocketAddr,
+    /// Path streams are requested on, other requests get a 404.
+    #[serde(default = "default_path")]
+    pub path: String,
+    #[serde(default)]
+    pub tcp_nodelay: bool,
+    /// Bytes a client may send on one stream before it is read.
+    #[serde(default = "default_stream_window")]
+    pub stream_window: u32,
+    /// Bytes a client may send across all streams before they are read.
+    #[serde(default = "default_connection_window")]
+    pub connection_window: u32,
+    /// Streams one connection may have open at once.
+    #[serde(default)]
+    pub max_concurrent_streams: Option<u32>,
+    /// Connections served at once, see `TcpServerOption::max_connections`.
+    #[serde(default)]
+    pub max_connections: Option<usize>,
+}
+
+/// Default of `stream_window`, the protocol default is 64 KiB.
+pub const DEFAULT_STREAM_WINDOW: u32 = 1024 * 1024;
+
+/// Default of `connection_window`.
+pub const DEFAULT_CONNECTION_WINDOW: u32 = 4 * 1024 * 1024;
+
+fn default_path() -> String {
+    "/".to_owned()
+}
+
+fn default_stream_window() -> u32 {
+    DEFAULT_STREAM_WINDOW
+}
+
+fn default_connection_window() -> u32 {
+    DEFAULT_CONNECTION_WINDOW
+}
@@ -0,0 +1,255 @@
+//! Transport Http2 Server
+
+use std::{future::Future, net::SocketAddr, time::Duration};
+
+use bytes::Bytes;
+use h2::{server::SendResponse, RecvStream};
+use http::{Method, Request, Response, StatusCode};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    task::JoinSet,
+};
+
+use crate::{
+    context::{AcceptHook, ConnContext},
+    option::LatencyProfile,
+    stats::ServerStatsSnapshot,
+    tcp::{TcpServer, TcpServerOption, TlsMode, DEFAULT_ACCEPT_BATCH, DEFAULT_HANDSHAKE_TIMEOUT},
+    tls::TlsInfo,
+    ServerError, ServerResult, TlsServerOption, TransportServerCallback, TransportServerTrait,
+};
+
+use super::{H2ServerOption, H2Stream, ALPN_H2};
+
+/// Serves every request on `path` as a stream, over connections accepted
+/// by a tcp server.
+///
+/// Without tls it speaks http/2 with prior knowledge. Accept hooks run
+/// once per connection, limits and stats count connections, not streams.
+pub struct H2Server {
+    tcp: TcpServer,
+    opt: H2ServerOption,
+}
+
+impl H2Server {
+    pub fn init(opt: H2ServerOption, tls_opt: Option<TlsServerOption>) -> ServerResult<Self> {
+        if !opt.path.starts_with('/') {
+            return Err(ServerError::Option(format!(
+                "h2 path {} does not start with /",
+                opt.path
+            )));
+        }
+        Ok(Self {
+            tcp: tcp_server(opt.listen, opt.tcp_nodelay, opt.max_connections, tls_opt)?,
+            opt,
+        })
+    }
+
+    /// Run `hook` on every accepted connection after the tls handshake.
+    pub fn add_accept_hook(&mut self, hook: AcceptHook) {
+        self.tcp.add_accept_hook(hook);
+    }
+
+    /// Socket settings of the profile, the options are overridden by `apply_latency_profile`.
+    pub fn set_latency_profile(&mut self, profile: LatencyProfile) {
+        self.tcp.set_latency_profile(profile);
+    }
+
+    pub fn stats(&self) -> ServerStatsSnapshot {
+        self.tcp.stats()
+    }
+
+    fn connection_callback<C: TransportServerCallback>(
+        &self,
+        callback: C,
+    ) -> H2Connection<C, PathOpener> {
+        let mut builder = h2::server::Builder::new();
+        builder
+            .initial_window_size(self.opt.stream_window)
+            .initial_connection_window_size(self.opt.connection_window);
+        if let Some(max) = self.opt.max_concurrent_streams {
+            builder.max_concurrent_streams(max);
+        }
+        H2Connection::new(callback, builder, PathOpener(self.opt.path.clone()))
+    }
+}
+
+/// The tcp server http/2 connections are accepted by, tls offering only h2.
+pub(crate) fn tcp_server(
+    listen: SocketAddr,
+    tcp_nodelay: bool,
+    max_connections: Option<usize>,
+    tls_opt: Option<TlsServerOption>,
+) -> ServerResult<TcpServer> {
+    let tcp_opt = TcpServerOption {
+        listen,
+        tcp_nodelay,
+        tls_mode: TlsMode::Required,
+        sniff: None,
+        max_bytes: None,
+        rate_limit: None,
+        trace_sampling: None,
+        idle_reap: None,
+        accept_batch: DEFAULT_ACCEPT_BATCH,
+        handshake_timeout: DEFAULT_HANDSHAKE_TIMEOUT,
+        max_connections,
+        idle_timeout: None,
+    };
+    // a client offering no h2 gets a tls alert instead of garbage
+    let tls_opt = tls_opt.map(|tls_opt| TlsServerOption {
+        alpn: vec![ALPN_H2.to_owned()],
+        ..tls_opt
+    });
+    TcpServer::init(tcp_opt, tls_opt)
+}
+
+/// Takes the stream of an accepted request, or answers and refuses it.
+pub(crate) trait StreamOpener: Clone + Send + Sync + 'static {
+    type Stream: AsyncRead + AsyncWrite + Unpin + Send + Sync + 'static;
+
+    /// Transport named in the context of each stream.
+    const TRANSPORT: &'static str;
+
+    fn open(
+        &self,
+        req: Request<RecvStream>,
+        respond: SendResponse<Bytes>,
+    ) -> Option<(Self::Stream, Request<()>)>;
+}
+
+impl TransportServerTrait for H2Server {
+    fn local_addr(&self) -> Option<SocketAddr> {
+        self.tcp.local_addr()
+    }
+
+    async fn serve<C: TransportServerCallback>(&self, callback: C) -> ServerResult<()> {
+        self.tcp.serve(self.connection_callback(callback)).await
+    }
+
+    /// The grace period covers connections, a connection still running
+    /// after it drops its streams with it.
+    async fn serve_with_shutdown<C, F>(
+        &self,
+        callback: C,
+        signal: F,
+        grace: Duration,
+    ) -> ServerResult<()>
+    where
+        C: TransportServerCallback,
+        F: Future<Output = ()> + Send + Sync,
+    {
+        self.tcp
+            .serve_with_shutdown(self.connection_callback(callback), signal, grace)
+            .await
+    }
+}
+
+/// Runs an http/2 connection in the task of its tcp connection, handing
+/// each stream to `callback` in a task of its own.
+#[derive(Clone)]
+pub(crate) struct H2Connection<C, O> {
+    callback: C,
+    builder: h2::server::Builder,
+    opener: O,
+}
+
+impl<C, O> H2Connection<C, O> {
+    pub(crate) fn new(callback: C, builder: h2::server::Builder, opener: O) -> Self {
+        Self {
+            callback,
+            builder,
+            opener,
+        }
+    }
+}
+
+impl<C: TransportServerCallback, O: StreamOpener> TransportServerCallback for H2Connection<C, O> {
+    async fn handle<S>(&self, stream: S, addr: Option<SocketAddr>)
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + Sync,
+    {
+        self.handle_ctx(stream, ConnContext::new(addr, None)).await
+    }
+
+    async fn handle_ctx<S>(&self, stream: S, ctx: ConnContext)
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + Sync,
+    {
+        let mut conn = match self.builder.handshake::<_, Bytes>(stream).await {
+            Ok(conn) => conn,
+            Err(e) => {
+                log::debug!("h2 handshake with {:?} failed ({})", ctx.peer_addr, e);
+                return;
+            }
+        };
+
+        // dropped with the connection, which the streams can not outlive
+        let mut streams = JoinSet::new();
+        loop {
+            tokio::select! {
+                req = conn.accept() => match req {
+                    Some(Ok((req, respond))) => {
+                        if let Some((stream, req)) = self.opener.open(req, respond) {
+                            let callback = self.callback.clone();
+                            let ctx = stream_context(&ctx, &req, O::TRANSPORT);
+                            streams.spawn(async move { callback.handle_ctx(stream, ctx).await });
+                        }
+                    }
+                    Some(Err(e)) => {
+                        log::debug!("h2 connection from {:?} failed ({})", ctx.peer_addr, e);
+                        break;
+                    }
+                    None => break,
+                },
+                Some(_) = streams.join_next(), if !streams.is_empty() => {}
+            }
+        }
+    }
+}
+
+/// Answers a POST on the path with 200 and takes its stream, refusing
+/// anything else.
+#[derive(Clone)]
+struct PathOpener(String);
+
+impl StreamOpener for PathOpener {
+    type Stream = H2Stream;
+
+    const TRANSPORT: &'static str = "h2";
+
+    fn open(
+        &self,
+        req: Request<RecvStream>,
+        mut respond: SendResponse<Bytes>,
+    ) -> Option<(H2Stream, Request<()>)> {
+        let status = if req.uri().path() != self.0 {
+            StatusCode::NOT_FOUND
+        } else if req.method() != Method::POST {
+            StatusCode::METHOD_NOT_ALLOWED
+        } else {
+            StatusCode::OK
+        };
+        let response = Response::builder().status(status).body(()).ok()?;
+        if status != StatusCode::OK {
+            log::debug!("h2 {} {} refused with {}", req.method(), req.uri(), status);
+            let _ = respond.send_response(response, true);
+            return None;
+        }
+
+        let (parts, recv) = req.into_parts();
+        let send = respond.send_response(response, false).ok()?;
+        Some((H2Stream::new(send, recv), Request::from_parts(parts, ())))
+    }
+}
+
+/// The connection context of one stream, carrying its request like a ws upgrade.
+fn stream_context(conn: &ConnContext, req: &Request<()>, transport: &'static str) -> ConnContext {
+    let mut ctx = ConnContext::new(conn.peer_addr, conn.local_addr).with_transport(transport);
+    ctx.handshake = conn.handshake;
+    if let Some(tls) = conn.extensions.get::<TlsInfo>() {
+        ctx.extensions.insert(tls.clone());
+    }
+    ctx.extensions.insert(req.uri().clone());
+    ctx.extensions.insert(req.headers().clone());
+    ctx
+}
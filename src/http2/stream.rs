@@ -0,0 +1,187 @@
+//! Http2 Transport Stream
+
+use std::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use bytes::{Buf, Bytes};
+use futures_util::ready;
+use h2::{client::ResponseFuture, RecvStream, SendStream};
+use http::{HeaderMap, Response};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// Checks the response headers of a lazily opened stream, an error fails
+/// the read waiting for them and every read after it.
+pub(crate) type ResponseCheck =
+    Box<dyn Fn(&Response<RecvStream>) -> std::io::Result<()> + Send + Sync>;
+
+/// Receiving half, the response of a client stream may still be on its way.
+enum Recv {
+    Pending(ResponseFuture, ResponseCheck),
+    Open(RecvStream),
+    Failed(std::io::ErrorKind, String),
+}
+
+/// One http/2 stream, DATA frames read and written as bytes.
+///
+/// A frame larger than the read buffer is handed out over several reads.
+/// Shutdown ends the stream towards the peer, dropping it resets the stream.
+pub struct H2Stream {
+    send: SendStream<Bytes>,
+    recv: Recv,
+    chunk: Bytes,
+    trailers: Option<HeaderMap>,
+    shutdown: bool,
+}
+
+impl std::fmt::Debug for H2Stream {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("H2Stream")
+            .field("stream_id", &u32::from(self.send.stream_id()))
+            .field("shutdown", &self.shutdown)
+            .finish()
+    }
+}
+
+impl H2Stream {
+    pub(crate) fn new(send: SendStream<Bytes>, recv: RecvStream) -> Self {
+        Self::with_recv(send, Recv::Open(recv))
+    }
+
+    /// A client stream writable before the response arrived, for servers
+    /// that wait for the first message. The first read waits for the
+    /// response headers and runs `check` on them.
+    pub(crate) fn lazy(
+        send: SendStream<Bytes>,
+        response: ResponseFuture,
+        check: ResponseCheck,
+    ) -> Self {
+        Self::with_recv(send, Recv::Pending(response, check))
+    }
+
+    fn with_recv(send: SendStream<Bytes>, recv: Recv) -> Self {
+        Self {
+            send,
+            recv,
+            chunk: Bytes::new(),
+            trailers: None,
+            shutdown: false,
+        }
+    }
+
+    /// End the stream with `trailers` on shutdown instead of an empty DATA frame.
+    pub(crate) fn with_trailers(mut self, trailers: HeaderMap) -> Self {
+        self.trailers = Some(trailers);
+        self
+    }
+}
+
+pub(crate) fn io_error(e: h2::Error) -> std::io::Error {
+    if e.is_io() {
+        return e
+            .into_io()
+            .unwrap_or_else(|| std::io::ErrorKind::Other.into());
+    }
+    std::io::Error::other(e)
+}
+
+impl AsyncRead for H2Stream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            if buf.remaining() == 0 {
+                return Poll::Ready(Ok(()));
+            }
+            if this.chunk.has_remaining() {
+                let n = this.chunk.len().min(buf.remaining());
+                buf.put_slice(&this.chunk[..n]);
+                this.chunk.advance(n);
+                return Poll::Ready(Ok(()));
+            }
+
+            let recv = match this.recv {
+                Recv::Open(ref mut recv) => recv,
+                Recv::Pending(ref mut response, ref check) => {
+                    let res = ready!(Pin::new(response).poll(cx)).map_err(io_error);
+                    match res.and_then(|response| check(&response).map(|_| response)) {
+                        Ok(response) => this.recv = Recv::Open(response.into_body()),
+                        Err(e) => {
+                            this.recv = Recv::Failed(e.kind(), e.to_string());
+                            return Poll::Ready(Err(e));
+                        }
+                    }
+                    continue;
+                }
+                Recv::Failed(kind, ref msg) => {
+                    return Poll::Ready(Err(std::io::Error::new(kind, msg.clone())))
+                }
+            };
+            match ready!(recv.poll_data(cx)) {
+                Some(Ok(data)) => {
+                    // the window opens again as data is taken, not as it is read
+                    recv.flow_control()
+                        .release_capacity(data.len())
+                        .map_err(io_error)?;
+                    this.chunk = data;
+                }
+                Some(Err(e)) => return Poll::Ready(Err(io_error(e))),
+                None => return Poll::Ready(Ok(())),
+            }
+        }
+    }
+}
+
+impl AsyncWrite for H2Stream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+
+        this.send.reserve_capacity(buf.len());
+        loop {
+            let n = this.send.capacity().min(buf.len());
+            if n > 0 {
+                this.send
+                    .send_data(Bytes::copy_from_slice(&buf[..n]), false)
+                    .map_err(io_error)?;
+                return Poll::Ready(Ok(n));
+            }
+            match ready!(this.send.poll_capacity(cx)) {
+                Some(Ok(_)) => {}
+                Some(Err(e)) => return Poll::Ready(Err(io_error(e))),
+                None => return Poll::Ready(Err(std::io::ErrorKind::BrokenPipe.into())),
+            }
+        }
+    }
+
+    /// Frames are written out by the connection task.
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        if !this.shutdown {
+            this.shutdown = true;
+            match this.trailers.take() {
+                Some(trailers) => this.send.send_trailers(trailers),
+                None => this.send.send_data(Bytes::new(), true),
+            }
+            .map_err(io_error)?;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+crate::timeout_io_methods!(H2Stream);
@@ -0,0 +1,91 @@
+//! Cooperative Yielding
+//!
+//! Tokio charges its sockets, channels and pipes against a budget of the
+//! running task and returns `Pending` once it ran out. A stream answering
+//! from its own buffers, or making up its data, never touches them and can
+//! keep a task, and the other tasks of its worker, waiting forever.
+//!
+//! Per poll work of the streams of the crate:
+//!
+//! - `TcpStream`: every read and write reaches the socket, a tls read
+//!   decrypts until it has plaintext, one read of the socket at a time.
+//! - `WebSocketClientStream`, `WebSocketServerStream`: one [`Budget`] unit
+//!   per message pulled from the socket and per read of a buffered one, a
+//!   read pulls at most `read_buffer_messages` messages.
+//! - `UdpStream`, `UdpPeerStream`: at most one datagram, from the socket or
+//!   a tokio channel.
+//! - `MuxStream`: what the session buffered, at most the stream window.
+//! - `GeneratorStream`, `BlackholeStream`: one unit per read and write.
+//! - `MemoryStream`: a tokio duplex pipe.
+
+use std::task::{Context, Poll};
+
+/// Polls answered in a row before a forced yield, as many as tokio allows.
+pub const POLL_BUDGET: u32 = 128;
+
+/// Counts the polls a stream answered without waiting.
+///
+/// Once the units are spent the task is woken at once and the poll returns
+/// `Pending`, so the runtime gets to run other tasks before this one goes on.
+#[derive(Debug, Clone)]
+pub struct Budget {
+    limit: u32,
+    remaining: u32,
+}
+
+impl Default for Budget {
+    fn default() -> Self {
+        Self::new(POLL_BUDGET)
+    }
+}
+
+impl Budget {
+    pub fn new(limit: u32) -> Self {
+        let limit = limit.max(1);
+        Self {
+            limit,
+            remaining: limit,
+        }
+    }
+
+    /// Take one unit, or yield once none is left.
+    pub fn poll_proceed(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        if self.remaining == 0 {
+            self.remaining = self.limit;
+            cx.waker().wake_by_ref();
+            return Poll::Pending;
+        }
+        self.remaining -= 1;
+        Poll::Ready(())
+    }
+
+    /// The stream waited for its source, the task yielded anyway.
+    pub fn reset(&mut self) {
+        self.remaining = self.limit;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::task::noop_waker_ref;
+
+    use super::*;
+
+    #[test]
+    fn test_budget() {
+        let mut cx = Context::from_waker(noop_waker_ref());
+        let mut budget = Budget::new(3);
+        for _ in 0..3 {
+            assert!(budget.poll_proceed(&mut cx).is_ready());
+        }
+        assert!(budget.poll_proceed(&mut cx).is_pending());
+        // the yield refills it
+        assert!(budget.poll_proceed(&mut cx).is_ready());
+
+        budget.reset();
+        for _ in 0..3 {
+            assert!(budget.poll_proceed(&mut cx).is_ready());
+        }
+        assert!(budget.poll_proceed(&mut cx).is_pending());
+    }
+}
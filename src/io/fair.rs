@@ -0,0 +1,309 @@
+//! Fair Scheduling Between Streams Sharing a Task
+
+use std::{
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use futures_util::ready;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// Zero turns a limit off.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FairOptions {
+    /// Most bytes one `poll_read` delivers, however much is buffered.
+    pub fair_read_quantum: usize,
+    /// Consecutive successful writes before the stream yields once.
+    pub yield_after_writes: usize,
+}
+
+/// Keeps one busy stream from monopolizing a task that serves many.
+///
+/// A read filling a whole quantum and every `yield_after_writes`th write
+/// are followed by a single `Pending` with an immediate wake, so a select
+/// loop over several streams, even a biased one, gets to poll the others.
+/// The next poll goes on as normal, a yield never repeats back to back.
+#[derive(Debug)]
+pub struct FairStream<S> {
+    inner: S,
+    opt: FairOptions,
+    read_yield: bool,
+    writes: usize,
+}
+
+impl<S> FairStream<S> {
+    pub fn new(inner: S, opt: FairOptions) -> Self {
+        Self {
+            inner,
+            opt,
+            read_yield: false,
+            writes: 0,
+        }
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+/// Give the task back to the executor, asking to be polled again right away.
+fn yield_now<T>(cx: &mut Context<'_>) -> Poll<T> {
+    cx.waker().wake_by_ref();
+    Poll::Pending
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for FairStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let quantum = this.opt.fair_read_quantum;
+        if quantum == 0 || buf.remaining() == 0 {
+            return Pin::new(&mut this.inner).poll_read(cx, buf);
+        }
+        if std::mem::take(&mut this.read_yield) {
+            return yield_now(cx);
+        }
+
+        let n = if buf.remaining() <= quantum {
+            let filled = buf.filled().len();
+            ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+            buf.filled().len() - filled
+        } else {
+            let mut limited = ReadBuf::new(buf.initialize_unfilled_to(quantum));
+            ready!(Pin::new(&mut this.inner).poll_read(cx, &mut limited))?;
+            let n = limited.filled().len();
+            buf.advance(n);
+            n
+        };
+        // more may be waiting, a partial quantum means the buffer ran dry
+        this.read_yield = n == quantum;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> FairStream<S> {
+    fn poll_counted(
+        &mut self,
+        cx: &mut Context<'_>,
+        write: impl FnOnce(Pin<&mut S>, &mut Context<'_>) -> Poll<io::Result<usize>>,
+    ) -> Poll<io::Result<usize>> {
+        let limit = self.opt.yield_after_writes;
+        if limit != 0 && self.writes >= limit {
+            self.writes = 0;
+            return yield_now(cx);
+        }
+
+        let res = write(Pin::new(&mut self.inner), cx);
+        match res {
+            Poll::Ready(Ok(_)) => self.writes += 1,
+            // the stream waited anyway, its streak is over
+            Poll::Pending => self.writes = 0,
+            Poll::Ready(Err(_)) => {}
+        }
+        res
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for FairStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.get_mut()
+            .poll_counted(cx, |inner, cx| inner.poll_write(cx, buf))
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        self.get_mut()
+            .poll_counted(cx, |inner, cx| inner.poll_write_vectored(cx, bufs))
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        task::{Wake, Waker},
+    };
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use crate::chaos::ChaosRng;
+
+    use super::*;
+
+    #[derive(Default)]
+    struct CountingWaker(AtomicUsize);
+
+    impl Wake for CountingWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.fetch_add(1, Ordering::Relaxed);
+        }
+
+        fn wake_by_ref(self: &Arc<Self>) {
+            self.0.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    #[test]
+    fn test_fair_yield_liveness() {
+        let wakes = Arc::new(CountingWaker::default());
+        let waker = Waker::from(wakes.clone());
+        let mut cx = Context::from_waker(&waker);
+
+        // an always ready source, the worst case for a select loop
+        let opt = FairOptions {
+            fair_read_quantum: 100,
+            yield_after_writes: 3,
+        };
+        let mut s = FairStream::new(tokio::io::repeat(7), opt);
+        let mut storage = [0u8; 1000];
+        let mut polls = vec![];
+        for _ in 0..6 {
+            let mut buf = ReadBuf::new(&mut storage);
+            match Pin::new(&mut s).poll_read(&mut cx, &mut buf) {
+                Poll::Ready(Ok(())) => polls.push(buf.filled().len()),
+                Poll::Ready(Err(e)) => panic!("{}", e),
+                Poll::Pending => polls.push(0),
+            }
+        }
+        // every yield asks for a wake and the next poll reads again
+        assert_eq!(polls, [100, 0, 100, 0, 100, 0]);
+        assert_eq!(wakes.0.load(Ordering::Relaxed), 3);
+
+        let mut s = FairStream::new(tokio::io::sink(), opt);
+        let ready = (0..8)
+            .map(|_| Pin::new(&mut s).poll_write(&mut cx, b"kapi").is_ready())
+            .collect::<Vec<_>>();
+        assert_eq!(ready, [true, true, true, false, true, true, true, false]);
+        assert_eq!(wakes.0.load(Ordering::Relaxed), 5);
+
+        // turned off, nothing changes
+        let mut s = FairStream::new(tokio::io::repeat(7), FairOptions::default());
+        let mut buf = ReadBuf::new(&mut storage);
+        assert!(Pin::new(&mut s).poll_read(&mut cx, &mut buf).is_ready());
+        assert_eq!(buf.filled().len(), 1000);
+    }
+
+    #[tokio::test]
+    async fn test_fair_data_integrity() {
+        let mut rng = ChaosRng::new(Some(11));
+        for _ in 0..16 {
+            let len = rng.below(256 * 1024) as usize;
+            let data = (0..len).map(|_| rng.next_u64() as u8).collect::<Vec<_>>();
+            let opt = FairOptions {
+                fair_read_quantum: 1 + rng.below(4096) as usize,
+                yield_after_writes: 1 + rng.below(4) as usize,
+            };
+
+            let (a, b) = tokio::io::duplex(1 + rng.below(8192) as usize);
+            let mut a = FairStream::new(a, opt);
+            let mut b = FairStream::new(b, opt);
+            let sent = data.clone();
+            let writer = tokio::spawn(async move {
+                for chunk in sent.chunks(1000) {
+                    a.write_all(chunk).await.unwrap();
+                }
+                a.shutdown().await.unwrap();
+            });
+            let mut received = vec![];
+            let mut buf = vec![0u8; 8192];
+            loop {
+                let n = b.read(&mut buf).await.unwrap();
+                if n == 0 {
+                    break;
+                }
+                assert!(n <= opt.fair_read_quantum);
+                received.extend_from_slice(&buf[..n]);
+            }
+            writer.await.unwrap();
+            assert_eq!(received, data);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_fair_select_progress() {
+        const MESSAGES: usize = 50;
+        const MESSAGE: &[u8] = b"0123456789";
+        const TOTAL: usize = MESSAGES * MESSAGE.len();
+
+        /// Bytes read per stream and the most iterations a stream with data
+        /// waited between two reads.
+        async fn run(opt: FairOptions, iterations: usize) -> ([usize; 3], usize) {
+            let mut chatty = FairStream::new(tokio::io::repeat(1), opt);
+            let (mut tx1, rx1) = tokio::io::duplex(64 * 1024);
+            let (mut tx2, rx2) = tokio::io::duplex(64 * 1024);
+            tx1.write_all(&MESSAGE.repeat(MESSAGES)).await.unwrap();
+            tx2.write_all(&MESSAGE.repeat(MESSAGES)).await.unwrap();
+            let (mut q1, mut q2) = (FairStream::new(rx1, opt), FairStream::new(rx2, opt));
+
+            let mut read = [0usize; 3];
+            let mut last = [0usize; 3];
+            let mut max_gap = 0;
+            let (mut b0, mut b1, mut b2) = ([0u8; 1024], [0u8; 1024], [0u8; 1024]);
+            for i in 1..=iterations {
+                // biased puts the chatty stream first on every iteration
+                let s = tokio::select! {
+                    biased;
+                    n = chatty.read(&mut b0) => { read[0] += n.unwrap(); 0 }
+                    n = q1.read(&mut b1) => { read[1] += n.unwrap(); 1 }
+                    n = q2.read(&mut b2) => { read[2] += n.unwrap(); 2 }
+                };
+                last[s] = i;
+                for s in 0..3 {
+                    if s == 0 || read[s] < TOTAL {
+                        max_gap = max_gap.max(i - last[s]);
+                    }
+                }
+            }
+            (read, max_gap)
+        }
+
+        let opt = FairOptions {
+            fair_read_quantum: MESSAGE.len(),
+            yield_after_writes: 0,
+        };
+        let (read, max_gap) = run(opt, 8 * MESSAGES).await;
+        assert_eq!(read[1..], [TOTAL, TOTAL]);
+        assert!(read[0] >= TOTAL);
+        // the yields rotate a biased select through all three in 7 iterations
+        assert!(max_gap < 8, "{}", max_gap);
+
+        // without the adapter the chatty stream takes every iteration
+        let (read, max_gap) = run(FairOptions::default(), 8 * MESSAGES).await;
+        assert_eq!(read[1..], [0, 0]);
+        assert_eq!(max_gap, 8 * MESSAGES);
+    }
+}
@@ -0,0 +1,424 @@
+//! Length Delimited Framing
+
+use std::io;
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+const HEADER_LEN: usize = 4;
+const CRC_LEN: usize = 4;
+
+#[derive(Debug, Clone)]
+pub struct FramedOptions {
+    /// Largest payload sent or accepted, a larger length fails the stream.
+    pub max_frame_size: usize,
+    /// Follow each payload with its CRC32, both peers must agree on it.
+    pub crc: bool,
+}
+
+impl Default for FramedOptions {
+    fn default() -> Self {
+        Self {
+            max_frame_size: 16 << 20,
+            crc: false,
+        }
+    }
+}
+
+/// Payload of the `InvalidData` and `InvalidInput` errors of [`Framed`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameError {
+    /// A frame over `max_frame_size`, sent or announced by the peer.
+    TooLarge { len: u64, max: usize },
+    /// The CRC32 of a received payload did not match.
+    Checksum { expected: u32, actual: u32 },
+}
+
+impl std::fmt::Display for FrameError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::TooLarge { len, max } => write!(f, "frame of {} bytes over {}", len, max),
+            Self::Checksum { expected, actual } => {
+                write!(f, "frame crc {:08x} instead of {:08x}", actual, expected)
+            }
+        }
+    }
+}
+
+impl std::error::Error for FrameError {}
+
+impl FrameError {
+    /// The framing error carried by an io error of [`Framed`].
+    pub fn of(err: &io::Error) -> Option<FrameError> {
+        err.get_ref()?.downcast_ref::<FrameError>().copied()
+    }
+}
+
+/// Frames of a u32 big endian payload length, the payload and, with `crc`,
+/// the big endian CRC32 of the payload.
+///
+/// Both methods are cancel safe. A dropped `recv_frame` keeps the bytes it
+/// read and the next call picks up where it stopped. A dropped `send_frame`
+/// that was polled queued its whole frame, which goes out before the next
+/// one, or on `flush`, and must not be sent again.
+#[derive(Debug)]
+pub struct Framed<S> {
+    inner: S,
+    opt: FramedOptions,
+    read_buf: BytesMut,
+    write_buf: BytesMut,
+}
+
+impl<S> Framed<S> {
+    pub fn new(inner: S, opt: FramedOptions) -> Self {
+        Self {
+            inner,
+            opt,
+            read_buf: BytesMut::new(),
+            write_buf: BytesMut::new(),
+        }
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.inner
+    }
+
+    /// The stream, dropping buffered bytes of a partly received or sent frame.
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+
+    /// Take one complete frame off the read buffer.
+    fn decode(&mut self) -> io::Result<Option<Bytes>> {
+        if self.read_buf.len() < HEADER_LEN {
+            return Ok(None);
+        }
+        let len = u32::from_be_bytes(self.read_buf[..HEADER_LEN].try_into().unwrap()) as usize;
+        self.check_size(len, io::ErrorKind::InvalidData)?;
+
+        let trailer = if self.opt.crc { CRC_LEN } else { 0 };
+        if self.read_buf.len() < HEADER_LEN + len + trailer {
+            self.read_buf
+                .reserve(HEADER_LEN + len + trailer - self.read_buf.len());
+            return Ok(None);
+        }
+
+        self.read_buf.advance(HEADER_LEN);
+        let frame = self.read_buf.split_to(len).freeze();
+        if self.opt.crc {
+            let expected = self.read_buf.get_u32();
+            let actual = crc32fast::hash(&frame);
+            if actual != expected {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    FrameError::Checksum { expected, actual },
+                ));
+            }
+        }
+        Ok(Some(frame))
+    }
+
+    fn check_size(&self, len: usize, kind: io::ErrorKind) -> io::Result<()> {
+        if len > self.opt.max_frame_size || len > u32::MAX as usize {
+            return Err(io::Error::new(
+                kind,
+                FrameError::TooLarge {
+                    len: len as u64,
+                    max: self.opt.max_frame_size,
+                },
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl<S: AsyncRead + Unpin> Framed<S> {
+    /// The next frame, `None` when the stream ended between frames.
+    ///
+    /// Ending inside a frame is `UnexpectedEof`.
+    pub async fn recv_frame(&mut self) -> io::Result<Option<Bytes>> {
+        loop {
+            if let Some(frame) = self.decode()? {
+                return Ok(Some(frame));
+            }
+            // a single read, whatever it returned is in the buffer when dropped after
+            if self.inner.read_buf(&mut self.read_buf).await? == 0 {
+                if self.read_buf.is_empty() {
+                    return Ok(None);
+                }
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> Framed<S> {
+    /// Queue `frame` and write out everything queued.
+    pub async fn send_frame(&mut self, frame: Bytes) -> io::Result<()> {
+        self.check_size(frame.len(), io::ErrorKind::InvalidInput)?;
+
+        self.write_buf.reserve(HEADER_LEN + frame.len() + CRC_LEN);
+        self.write_buf.put_u32(frame.len() as u32);
+        self.write_buf.put_slice(&frame);
+        if self.opt.crc {
+            self.write_buf.put_u32(crc32fast::hash(&frame));
+        }
+        self.flush().await
+    }
+
+    /// Write out the frames queued by cancelled `send_frame` calls.
+    pub async fn flush(&mut self) -> io::Result<()> {
+        while !self.write_buf.is_empty() {
+            match self.inner.write(&self.write_buf).await? {
+                0 => return Err(io::ErrorKind::WriteZero.into()),
+                n => self.write_buf.advance(n),
+            }
+        }
+        self.inner.flush().await
+    }
+
+    /// Flush and shut the stream down.
+    pub async fn shutdown(&mut self) -> io::Result<()> {
+        self.flush().await?;
+        self.inner.shutdown().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::chaos::ChaosRng;
+
+    use super::*;
+
+    fn frame(rng: &mut ChaosRng, max: u64) -> Bytes {
+        let len = rng.below(max);
+        (0..len).map(|_| rng.next_u64() as u8).collect()
+    }
+
+    #[tokio::test]
+    async fn test_framed_random_sizes() {
+        for crc in [false, true] {
+            let mut rng = ChaosRng::new(Some(3));
+            let frames = (0..200).map(|_| frame(&mut rng, 4096)).collect::<Vec<_>>();
+
+            // a small pipe splits frames across many reads and writes
+            let (a, b) = tokio::io::duplex(61);
+            let opt = FramedOptions {
+                crc,
+                ..Default::default()
+            };
+            let mut tx = Framed::new(a, opt.clone());
+            let mut rx = Framed::new(b, opt);
+            let sent = frames.clone();
+            let sender = tokio::spawn(async move {
+                for frame in sent {
+                    tx.send_frame(frame).await.unwrap();
+                }
+                tx.shutdown().await.unwrap();
+            });
+
+            for expect in frames {
+                assert_eq!(rx.recv_frame().await.unwrap().unwrap(), expect);
+            }
+            assert!(rx.recv_frame().await.unwrap().is_none());
+            sender.await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_framed_cancel_safety() {
+        let mut rng = ChaosRng::new(Some(11));
+        let frames = (0..300).map(|_| frame(&mut rng, 2048)).collect::<Vec<_>>();
+
+        let (a, b) = tokio::io::duplex(97);
+        let opt = FramedOptions {
+            crc: true,
+            ..Default::default()
+        };
+        let mut tx = Framed::new(a, opt.clone());
+        let mut rx = Framed::new(b, opt);
+        let sent = frames.clone();
+        let sender = tokio::spawn(async move {
+            let mut rng = ChaosRng::new(Some(12));
+            for frame in sent {
+                // a timed out send already queued its frame, never send it twice
+                let timeout = Duration::from_micros(rng.below(50));
+                let _ = tokio::time::timeout(timeout, tx.send_frame(frame)).await;
+            }
+            tx.shutdown().await.unwrap();
+        });
+
+        let mut rng = ChaosRng::new(Some(13));
+        let mut received = vec![];
+        let mut cancelled = 0;
+        loop {
+            let timeout = Duration::from_micros(rng.below(50));
+            match tokio::time::timeout(timeout, rx.recv_frame()).await {
+                Ok(Ok(Some(frame))) => received.push(frame),
+                Ok(Ok(None)) => break,
+                Ok(Err(e)) => panic!("torn frame {}", e),
+                Err(_) => cancelled += 1,
+            }
+        }
+        sender.await.unwrap();
+
+        assert!(cancelled > 0);
+        assert_eq!(received, frames);
+    }
+
+    #[tokio::test]
+    async fn test_framed_errors() {
+        let opt = FramedOptions {
+            max_frame_size: 8,
+            crc: true,
+        };
+
+        // too large to send, nothing is queued
+        let (a, mut b) = tokio::io::duplex(64);
+        let mut tx = Framed::new(a, opt.clone());
+        let err = tx
+            .send_frame(Bytes::from_static(&[0; 9]))
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(
+            FrameError::of(&err),
+            Some(FrameError::TooLarge { len: 9, max: 8 })
+        );
+        drop(tx);
+        assert_eq!(b.read(&mut [0; 8]).await.unwrap(), 0);
+
+        // too large announced by the peer
+        let (mut a, b) = tokio::io::duplex(64);
+        let mut rx = Framed::new(b, opt.clone());
+        a.write_all(&1000u32.to_be_bytes()).await.unwrap();
+        let err = rx.recv_frame().await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(matches!(
+            FrameError::of(&err),
+            Some(FrameError::TooLarge { len: 1000, .. })
+        ));
+
+        // corrupted payload
+        let (mut a, b) = tokio::io::duplex(64);
+        let mut rx = Framed::new(b, opt.clone());
+        a.write_all(&[0, 0, 0, 2, b'o', b'k']).await.unwrap();
+        a.write_all(&crc32fast::hash(b"no").to_be_bytes())
+            .await
+            .unwrap();
+        let err = rx.recv_frame().await.unwrap_err();
+        assert!(matches!(
+            FrameError::of(&err),
+            Some(FrameError::Checksum { .. })
+        ));
+
+        // ended inside a frame
+        let (mut a, b) = tokio::io::duplex(64);
+        let mut rx = Framed::new(b, opt);
+        a.write_all(&[0, 0, 0, 4, b'k']).await.unwrap();
+        drop(a);
+        let err = rx.recv_frame().await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[derive(Clone)]
+    struct FrameEchoCallback;
+
+    impl crate::TransportServerCallback for FrameEchoCallback {
+        async fn handle<S>(&self, stream: S, _addr: Option<std::net::SocketAddr>)
+        where
+            S: AsyncRead + AsyncWrite + Unpin + Send + Sync,
+        {
+            let mut framed = Framed::new(stream, FramedOptions::default());
+            while let Ok(Some(frame)) = framed.recv_frame().await {
+                if framed.send_frame(frame).await.is_err() {
+                    break;
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_framed_over_ws() {
+        use crate::{
+            websocket::{
+                wire, ForwardedOption, WebSocketClient, WebSocketClientOption, WebSocketServer,
+                WebSocketServerOption,
+            },
+            TransportClientStream, TransportClientTrait, TransportServerTrait,
+        };
+
+        let opt = WebSocketServerOption {
+            listen: "127.0.0.1:9858".parse().unwrap(),
+            path: "/framed".into(),
+            paths: vec![],
+            path_match: Default::default(),
+            fallback: Default::default(),
+            tcp_nodelay: true,
+            duplex_fairness: false,
+            read_buffer_messages: 1,
+            validate_text: false,
+            text_to_bytes: true,
+            max_message_size: wire::MAX_MESSAGE_SIZE,
+            max_frame_size: wire::MAX_FRAME_SIZE,
+            write_chunk_size: None,
+            max_bytes: None,
+            rate_limit: None,
+            max_connections: None,
+            idle_reap: None,
+            idle_timeout: None,
+            trust_forwarded_headers: false,
+            keepalive_interval: None,
+            keepalive_timeout: Duration::from_secs(30),
+            slow_consumer_policy: None,
+        };
+        let srv = WebSocketServer::init(opt, None).unwrap();
+        tokio::spawn(async move { srv.serve(FrameEchoCallback).await });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let opt = WebSocketClientOption {
+            addr: "127.0.0.1".into(),
+            port: 9858,
+            path: "/framed".into(),
+            tcp_nodelay: true,
+            duplex_fairness: false,
+            read_buffer_messages: 1,
+            validate_text: false,
+            text_to_bytes: true,
+            max_message_size: wire::MAX_MESSAGE_SIZE,
+            max_frame_size: wire::MAX_FRAME_SIZE,
+            write_chunk_size: None,
+            forwarded: ForwardedOption::default(),
+            headers: vec![],
+            host: None,
+            keepalive_interval: None,
+            keepalive_timeout: Duration::from_secs(30),
+            slow_consumer_policy: None,
+            connect_timeout: None,
+            connect_deadline: None,
+            attempt_delay: None,
+            resolve_on_connect: false,
+            proxy: None,
+            bind_addr: None,
+            bind_device: None,
+        };
+        let addrs = vec!["127.0.0.1:9858".parse().unwrap()];
+        let cli = WebSocketClient::with_addrs(opt, None, addrs).unwrap();
+        let stream = TransportClientStream::Ws(cli.connect().await.unwrap());
+        let mut framed = stream.framed(FramedOptions::default());
+
+        // frames and ws messages do not line up, frames span and share messages
+        let mut rng = ChaosRng::new(Some(5));
+        for _ in 0..50 {
+            let frame = frame(&mut rng, 100_000);
+            framed.send_frame(frame.clone()).await.unwrap();
+            assert_eq!(framed.recv_frame().await.unwrap().unwrap(), frame);
+        }
+    }
+}
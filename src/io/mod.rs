@@ -0,0 +1,719 @@
+//! Stream Relay, Timeout, Framing, Fairness and Throttling Helpers
+
+pub mod budget;
+pub use budget::Budget;
+
+pub mod fair;
+pub use fair::{FairOptions, FairStream};
+
+pub mod framed;
+pub use framed::{FrameError, Framed, FramedOptions};
+
+pub mod throttle;
+pub use throttle::{RateLimitOption, RateLimiter, RateLimits, ThrottledStream};
+
+pub mod timeout;
+pub use timeout::TimeoutStream;
+
+use std::{
+    future::{poll_fn, Future},
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use futures_util::ready;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+
+#[derive(Debug, Clone)]
+pub struct RelayOptions {
+    /// Give up when neither direction moved a byte for this long.
+    pub idle_timeout: Option<Duration>,
+    /// Copy buffer size per direction.
+    pub buffer_size: usize,
+    /// Keep the other direction open after one side reached EOF.
+    pub half_close: bool,
+}
+
+impl Default for RelayOptions {
+    fn default() -> Self {
+        Self {
+            idle_timeout: None,
+            buffer_size: 64 * 1024,
+            half_close: true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClosedBy {
+    A,
+    B,
+    IdleTimeout,
+}
+
+#[derive(Debug)]
+pub struct RelayResult {
+    pub a_to_b_bytes: u64,
+    pub b_to_a_bytes: u64,
+    pub duration: Duration,
+    /// Side which ended the relay first, by EOF or error.
+    pub closed_by: ClosedBy,
+    /// First error seen, counts above are still accurate.
+    pub error: Option<io::Error>,
+}
+
+enum Fault {
+    Read(io::Error),
+    Write(io::Error),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Running,
+    ShuttingDown,
+    Done,
+}
+
+struct Transfer {
+    buf: Box<[u8]>,
+    pos: usize,
+    cap: usize,
+    amt: u64,
+    read_done: bool,
+    need_flush: bool,
+    state: State,
+}
+
+impl Transfer {
+    fn new(buffer_size: usize) -> Self {
+        Self {
+            buf: vec![0; buffer_size.max(1)].into_boxed_slice(),
+            pos: 0,
+            cap: 0,
+            amt: 0,
+            read_done: false,
+            need_flush: false,
+            state: State::Running,
+        }
+    }
+
+    fn poll_copy<R, W>(
+        &mut self,
+        cx: &mut Context<'_>,
+        mut reader: Pin<&mut R>,
+        mut writer: Pin<&mut W>,
+        progress: &mut bool,
+    ) -> Poll<Result<(), Fault>>
+    where
+        R: AsyncRead + ?Sized,
+        W: AsyncWrite + ?Sized,
+    {
+        loop {
+            if self.pos == self.cap && !self.read_done {
+                let mut buf = ReadBuf::new(&mut self.buf);
+                match reader.as_mut().poll_read(cx, &mut buf) {
+                    Poll::Ready(Ok(())) => {
+                        let n = buf.filled().len();
+                        if n == 0 {
+                            self.read_done = true;
+                        } else {
+                            self.pos = 0;
+                            self.cap = n;
+                            *progress = true;
+                        }
+                    }
+                    Poll::Ready(Err(err)) => return Poll::Ready(Err(Fault::Read(err))),
+                    Poll::Pending => {
+                        // flush what we have before waiting for more
+                        if self.need_flush {
+                            ready!(writer.as_mut().poll_flush(cx)).map_err(Fault::Write)?;
+                            self.need_flush = false;
+                        }
+                        return Poll::Pending;
+                    }
+                }
+            }
+
+            while self.pos < self.cap {
+                let n = ready!(writer
+                    .as_mut()
+                    .poll_write(cx, &self.buf[self.pos..self.cap]))
+                .map_err(Fault::Write)?;
+                if n == 0 {
+                    return Poll::Ready(Err(Fault::Write(io::ErrorKind::WriteZero.into())));
+                }
+                self.pos += n;
+                self.amt += n as u64;
+                self.need_flush = true;
+                *progress = true;
+            }
+
+            if self.read_done {
+                ready!(writer.as_mut().poll_flush(cx)).map_err(Fault::Write)?;
+                return Poll::Ready(Ok(()));
+            }
+        }
+    }
+
+    /// Drive one direction, returns the side that ended it if it just stopped running.
+    fn poll_direction<R, W>(
+        &mut self,
+        cx: &mut Context<'_>,
+        mut reader: Pin<&mut R>,
+        mut writer: Pin<&mut W>,
+        sides: (ClosedBy, ClosedBy),
+        progress: &mut bool,
+    ) -> Option<(ClosedBy, Option<io::Error>)>
+    where
+        R: AsyncRead + ?Sized,
+        W: AsyncWrite + ?Sized,
+    {
+        let mut ended = None;
+        if self.state == State::Running {
+            match self.poll_copy(cx, reader.as_mut(), writer.as_mut(), progress) {
+                Poll::Pending => return None,
+                Poll::Ready(Ok(())) => ended = Some((sides.0, None)),
+                Poll::Ready(Err(Fault::Read(err))) => ended = Some((sides.0, Some(err))),
+                Poll::Ready(Err(Fault::Write(err))) => ended = Some((sides.1, Some(err))),
+            }
+            self.state = State::ShuttingDown;
+        }
+
+        if self.state == State::ShuttingDown {
+            // best effort, the peer may already be gone
+            if writer.as_mut().poll_shutdown(cx).is_ready() {
+                self.state = State::Done;
+            }
+        }
+
+        ended
+    }
+}
+
+/// Copy data both ways between `a` and `b` until both directions finished.
+///
+/// When a side reaches EOF the write half of the other side is shut down,
+/// which sends a Close frame on WebSocket streams. Without `half_close` the
+/// remaining direction stops reading as well and only drains its buffer.
+pub async fn relay<A, B>(a: &mut A, b: &mut B, opt: RelayOptions) -> RelayResult
+where
+    A: AsyncRead + AsyncWrite + Unpin + ?Sized,
+    B: AsyncRead + AsyncWrite + Unpin + ?Sized,
+{
+    let start = Instant::now();
+    let mut a_to_b = Transfer::new(opt.buffer_size);
+    let mut b_to_a = Transfer::new(opt.buffer_size);
+    let mut idle = opt
+        .idle_timeout
+        .map(|timeout| (timeout, Box::pin(tokio::time::sleep(timeout))));
+    let mut closed_by = None;
+    let mut error = None;
+
+    poll_fn(|cx| loop {
+        let before = (a_to_b.state, b_to_a.state);
+        let mut progress = false;
+
+        let ended = [
+            a_to_b.poll_direction(
+                cx,
+                Pin::new(&mut *a),
+                Pin::new(&mut *b),
+                (ClosedBy::A, ClosedBy::B),
+                &mut progress,
+            ),
+            b_to_a.poll_direction(
+                cx,
+                Pin::new(&mut *b),
+                Pin::new(&mut *a),
+                (ClosedBy::B, ClosedBy::A),
+                &mut progress,
+            ),
+        ];
+        for (side, err) in ended.into_iter().flatten() {
+            closed_by.get_or_insert(side);
+            if error.is_none() {
+                error = err;
+            }
+            if !opt.half_close {
+                a_to_b.read_done = true;
+                b_to_a.read_done = true;
+            }
+        }
+
+        if a_to_b.state == State::Done && b_to_a.state == State::Done {
+            return Poll::Ready(());
+        }
+
+        if before != (a_to_b.state, b_to_a.state) {
+            continue;
+        }
+
+        if let Some((timeout, sleep)) = idle.as_mut() {
+            if progress {
+                sleep.as_mut().reset(tokio::time::Instant::now() + *timeout);
+            }
+            if sleep.as_mut().poll(cx).is_ready() {
+                closed_by = Some(ClosedBy::IdleTimeout);
+                error.get_or_insert_with(|| io::ErrorKind::TimedOut.into());
+                return Poll::Ready(());
+            }
+        }
+
+        return Poll::Pending;
+    })
+    .await;
+
+    RelayResult {
+        a_to_b_bytes: a_to_b.amt,
+        b_to_a_bytes: b_to_a.amt,
+        duration: start.elapsed(),
+        closed_by: closed_by.unwrap_or(ClosedBy::A),
+        error,
+    }
+}
+
+/// Payload of the `TimedOut` errors returned by the timeout helpers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Elapsed {
+    /// Bytes moved before the deadline. For `write_all_timeout` they were
+    /// accepted by the stream, but may not be flushed. For `read_exact_timeout`
+    /// they are at the start of the buffer.
+    pub transferred: usize,
+}
+
+impl std::fmt::Display for Elapsed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "deadline elapsed after {} bytes", self.transferred)
+    }
+}
+
+impl std::error::Error for Elapsed {}
+
+impl Elapsed {
+    /// The progress carried by a timeout helper error.
+    pub fn of(err: &io::Error) -> Option<Elapsed> {
+        err.get_ref()?.downcast_ref::<Elapsed>().copied()
+    }
+}
+
+fn elapsed(transferred: usize) -> io::Error {
+    io::Error::new(io::ErrorKind::TimedOut, Elapsed { transferred })
+}
+
+/// One read bounded by `deadline`, cancel safe.
+pub async fn read_until_deadline<S>(
+    stream: &mut S,
+    buf: &mut [u8],
+    deadline: Instant,
+) -> io::Result<usize>
+where
+    S: AsyncRead + Unpin + ?Sized,
+{
+    tokio::time::timeout_at(deadline.into(), stream.read(buf))
+        .await
+        .unwrap_or_else(|_| Err(elapsed(0)))
+}
+
+pub async fn read_timeout<S>(stream: &mut S, buf: &mut [u8], timeout: Duration) -> io::Result<usize>
+where
+    S: AsyncRead + Unpin + ?Sized,
+{
+    read_until_deadline(stream, buf, Instant::now() + timeout).await
+}
+
+/// Fill `buf` before the timeout, `UnexpectedEof` if the stream ends first.
+pub async fn read_exact_timeout<S>(
+    stream: &mut S,
+    buf: &mut [u8],
+    timeout: Duration,
+) -> io::Result<()>
+where
+    S: AsyncRead + Unpin + ?Sized,
+{
+    let deadline = Instant::now() + timeout;
+    let mut filled = 0;
+    while filled < buf.len() {
+        match read_until_deadline(stream, &mut buf[filled..], deadline).await {
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::TimedOut => return Err(elapsed(filled)),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// Write all of `buf` before the timeout, without flushing.
+///
+/// Each write either completes or is dropped before taking any byte, so
+/// [`Elapsed::transferred`] is exactly the prefix the stream accepted.
+pub async fn write_all_timeout<S>(stream: &mut S, buf: &[u8], timeout: Duration) -> io::Result<()>
+where
+    S: AsyncWrite + Unpin + ?Sized,
+{
+    let deadline = Instant::now() + timeout;
+    let mut written = 0;
+    while written < buf.len() {
+        match tokio::time::timeout_at(deadline.into(), stream.write(&buf[written..])).await {
+            Err(_) => return Err(elapsed(written)),
+            Ok(Ok(0)) => return Err(io::ErrorKind::WriteZero.into()),
+            Ok(Ok(n)) => written += n,
+            Ok(Err(e)) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// Inherent timeout helpers for a stream type, see the functions in [`crate::io`].
+#[doc(hidden)]
+#[macro_export]
+macro_rules! timeout_io_methods {
+    ($name:ty) => {
+        impl $name {
+            /// One read, failing with `TimedOut` once `timeout` passed.
+            pub async fn read_timeout(
+                &mut self,
+                buf: &mut [u8],
+                timeout: std::time::Duration,
+            ) -> std::io::Result<usize> {
+                $crate::io::read_timeout(self, buf, timeout).await
+            }
+
+            /// One read, failing with `TimedOut` at `deadline`.
+            pub async fn read_until_deadline(
+                &mut self,
+                buf: &mut [u8],
+                deadline: std::time::Instant,
+            ) -> std::io::Result<usize> {
+                $crate::io::read_until_deadline(self, buf, deadline).await
+            }
+
+            /// Fill `buf`, on `TimedOut` the bytes read are in the `Elapsed` payload.
+            pub async fn read_exact_timeout(
+                &mut self,
+                buf: &mut [u8],
+                timeout: std::time::Duration,
+            ) -> std::io::Result<()> {
+                $crate::io::read_exact_timeout(self, buf, timeout).await
+            }
+
+            /// Write all of `buf`, on `TimedOut` the bytes written are in the `Elapsed` payload.
+            pub async fn write_all_timeout(
+                &mut self,
+                buf: &[u8],
+                timeout: std::time::Duration,
+            ) -> std::io::Result<()> {
+                $crate::io::write_all_timeout(self, buf, timeout).await
+            }
+
+            /// Wrap in a [`TimeoutStream`](crate::io::TimeoutStream) failing
+            /// reads and writes without progress for `idle`.
+            pub fn with_idle_timeout(
+                self,
+                idle: std::time::Duration,
+            ) -> $crate::io::TimeoutStream<Self> {
+                $crate::io::TimeoutStream::idle(self, Some(idle))
+            }
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use crate::chaos::ChaosRng;
+
+    use super::*;
+
+    fn payload(rng: &mut ChaosRng, max: u64) -> Vec<u8> {
+        let len = rng.below(max);
+        (0..len).map(|_| rng.next_u64() as u8).collect()
+    }
+
+    #[tokio::test]
+    async fn test_relay_no_data_loss() {
+        let mut rng = ChaosRng::new(Some(7));
+        for _ in 0..64 {
+            let up = payload(&mut rng, 256 * 1024);
+            let down = payload(&mut rng, 256 * 1024);
+            let a_first = rng.chance(0.5);
+            let buffer_size = 1 + rng.below(128 * 1024) as usize;
+
+            let (a_app, mut a) = tokio::io::duplex(1 + rng.below(32 * 1024) as usize);
+            let (mut b, b_app) = tokio::io::duplex(1 + rng.below(32 * 1024) as usize);
+            let relay = tokio::spawn(async move {
+                let opt = RelayOptions {
+                    buffer_size,
+                    ..Default::default()
+                };
+                relay(&mut a, &mut b, opt).await
+            });
+
+            let (up_clone, down_clone) = (up.clone(), down.clone());
+            let a_task = tokio::spawn(async move {
+                let (mut rd, mut wr) = tokio::io::split(a_app);
+                let write = async move {
+                    if !a_first {
+                        tokio::time::sleep(Duration::from_millis(1)).await;
+                    }
+                    wr.write_all(&up_clone).await.unwrap();
+                    wr.shutdown().await.unwrap();
+                };
+                let mut buf = vec![];
+                let read = rd.read_to_end(&mut buf);
+                let (_, n) = tokio::join!(write, read);
+                n.unwrap();
+                buf
+            });
+            let b_task = tokio::spawn(async move {
+                let (mut rd, mut wr) = tokio::io::split(b_app);
+                let write = async move {
+                    if a_first {
+                        tokio::time::sleep(Duration::from_millis(1)).await;
+                    }
+                    wr.write_all(&down_clone).await.unwrap();
+                    wr.shutdown().await.unwrap();
+                };
+                let mut buf = vec![];
+                let read = rd.read_to_end(&mut buf);
+                let (_, n) = tokio::join!(write, read);
+                n.unwrap();
+                buf
+            });
+
+            assert_eq!(a_task.await.unwrap(), down);
+            assert_eq!(b_task.await.unwrap(), up);
+            let res = relay.await.unwrap();
+            assert!(res.error.is_none(), "{:?}", res.error);
+            assert_eq!(res.a_to_b_bytes, up.len() as u64);
+            assert_eq!(res.b_to_a_bytes, down.len() as u64);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_relay_early_drop() {
+        let mut rng = ChaosRng::new(Some(11));
+        for _ in 0..32 {
+            let data = payload(&mut rng, 128 * 1024);
+            let drop_a = rng.chance(0.5);
+
+            let (a_app, mut a) = tokio::io::duplex(64 * 1024);
+            let (mut b, b_app) = tokio::io::duplex(64 * 1024);
+            let relay =
+                tokio::spawn(async move { relay(&mut a, &mut b, RelayOptions::default()).await });
+
+            // one side writes and vanishes, the other must still see every byte
+            let (mut gone, mut stay) = if drop_a {
+                (a_app, b_app)
+            } else {
+                (b_app, a_app)
+            };
+            let data_clone = data.clone();
+            tokio::spawn(async move {
+                gone.write_all(&data_clone).await.unwrap();
+            });
+            let mut buf = vec![];
+            stay.read_to_end(&mut buf).await.unwrap();
+            assert_eq!(buf, data);
+            drop(stay);
+
+            let res = relay.await.unwrap();
+            let expect = if drop_a { ClosedBy::A } else { ClosedBy::B };
+            assert_eq!(res.closed_by, expect);
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_relay_idle_timeout() {
+        let (_a_app, mut a) = tokio::io::duplex(1024);
+        let (mut b, _b_app) = tokio::io::duplex(1024);
+        let opt = RelayOptions {
+            idle_timeout: Some(Duration::from_millis(50)),
+            ..Default::default()
+        };
+        let res = relay(&mut a, &mut b, opt).await;
+        assert_eq!(res.closed_by, ClosedBy::IdleTimeout);
+        assert_eq!(res.error.unwrap().kind(), io::ErrorKind::TimedOut);
+    }
+
+    #[tokio::test]
+    async fn test_relay_no_half_close() {
+        let (mut a_app, mut a) = tokio::io::duplex(1024);
+        let (mut b, mut b_app) = tokio::io::duplex(1024);
+        let relay = tokio::spawn(async move {
+            let opt = RelayOptions {
+                half_close: false,
+                ..Default::default()
+            };
+            relay(&mut a, &mut b, opt).await
+        });
+
+        a_app.write_all(b"ping").await.unwrap();
+        a_app.shutdown().await.unwrap();
+        let mut buf = vec![];
+        b_app.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, b"ping");
+
+        // b never closes, the relay still finishes and closes a
+        let res = relay.await.unwrap();
+        assert_eq!(res.closed_by, ClosedBy::A);
+        assert_eq!(a_app.read(&mut [0u8; 8]).await.unwrap(), 0);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore = "benchmark, run with --ignored --nocapture"]
+    async fn bench_relay() {
+        const TOTAL: usize = 256 * 1024 * 1024;
+
+        async fn run(use_relay: bool) -> Duration {
+            let (mut a_app, mut a) = tokio::io::duplex(256 * 1024);
+            let (mut b, mut b_app) = tokio::io::duplex(256 * 1024);
+            let relay = tokio::spawn(async move {
+                if use_relay {
+                    relay(&mut a, &mut b, RelayOptions::default()).await;
+                } else {
+                    let _ = tokio::io::copy_bidirectional(&mut a, &mut b).await;
+                }
+            });
+
+            let start = Instant::now();
+            tokio::spawn(async move {
+                let chunk = vec![0u8; 64 * 1024];
+                for _ in 0..TOTAL / chunk.len() {
+                    a_app.write_all(&chunk).await.unwrap();
+                }
+                a_app.shutdown().await.unwrap();
+                let _ = a_app.read(&mut [0u8; 1]).await;
+            });
+            let mut buf = vec![0u8; 64 * 1024];
+            while b_app.read(&mut buf).await.unwrap() > 0 {}
+            drop(b_app);
+            relay.await.unwrap();
+            start.elapsed()
+        }
+
+        for (name, use_relay) in [("copy_bidirectional", false), ("relay", true)] {
+            let elapsed = run(use_relay).await;
+            println!(
+                "{} {} MiB in {:?} ({:.0} MiB/s)",
+                name,
+                TOTAL >> 20,
+                elapsed,
+                (TOTAL >> 20) as f64 / elapsed.as_secs_f64()
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_read_timeout() {
+        let (mut a, mut b) = tokio::io::duplex(64);
+        let mut buf = [0; 16];
+
+        let err = read_timeout(&mut a, &mut buf, Duration::from_millis(20))
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert_eq!(Elapsed::of(&err), Some(Elapsed { transferred: 0 }));
+
+        // ready data wins over a passed deadline
+        b.write_all(b"late").await.unwrap();
+        let past = Instant::now() - Duration::from_millis(1);
+        assert_eq!(
+            read_until_deadline(&mut a, &mut buf, past).await.unwrap(),
+            4
+        );
+
+        drop(b);
+        let err = read_exact_timeout(&mut a, &mut buf, Duration::from_secs(1))
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[tokio::test]
+    async fn test_write_all_timeout_progress() {
+        let mut rng = ChaosRng::new(Some(11));
+        let mut outcomes = (0, 0);
+        for _ in 0..32 {
+            let data = payload(&mut rng, 64 * 1024);
+            let timeout = Duration::from_millis(rng.below(30));
+            let (mut a, mut b) = tokio::io::duplex(1 + rng.below(4096) as usize);
+
+            let mut reader_rng = ChaosRng::new(Some(rng.next_u64()));
+            let reader = tokio::spawn(async move {
+                let mut received = vec![];
+                let mut buf = vec![0; 4096];
+                loop {
+                    let len = 1 + reader_rng.below(4095) as usize;
+                    match b.read(&mut buf[..len]).await.unwrap() {
+                        0 => return received,
+                        n => received.extend_from_slice(&buf[..n]),
+                    }
+                    tokio::time::sleep(reader_rng.jitter(Duration::from_millis(2))).await;
+                }
+            });
+
+            let res = write_all_timeout(&mut a, &data, timeout).await;
+            drop(a);
+            let received = reader.await.unwrap();
+            match res {
+                Ok(()) => {
+                    assert_eq!(received, data);
+                    outcomes.0 += 1;
+                }
+                Err(err) => {
+                    assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+                    let transferred = Elapsed::of(&err).unwrap().transferred;
+                    assert!(transferred < data.len());
+                    assert_eq!(received, data[..transferred]);
+                    outcomes.1 += 1;
+                }
+            }
+        }
+        assert!(outcomes.0 > 0 && outcomes.1 > 0, "{:?}", outcomes);
+    }
+
+    #[tokio::test]
+    async fn test_read_exact_timeout_progress() {
+        let mut rng = ChaosRng::new(Some(13));
+        let mut outcomes = (0, 0);
+        for _ in 0..32 {
+            let data = payload(&mut rng, 64 * 1024);
+            let timeout = Duration::from_millis(rng.below(30));
+            let (mut a, mut b) = tokio::io::duplex(1 + rng.below(4096) as usize);
+
+            let mut writer_rng = ChaosRng::new(Some(rng.next_u64()));
+            let sent = data.clone();
+            tokio::spawn(async move {
+                let mut rest = &sent[..];
+                while !rest.is_empty() {
+                    let len = rest.len().min(1 + writer_rng.below(4095) as usize);
+                    b.write_all(&rest[..len]).await.unwrap();
+                    rest = &rest[len..];
+                    tokio::time::sleep(writer_rng.jitter(Duration::from_millis(2))).await;
+                }
+            });
+
+            let mut buf = vec![0; data.len()];
+            match read_exact_timeout(&mut a, &mut buf, timeout).await {
+                Ok(()) => {
+                    assert_eq!(buf, data);
+                    outcomes.0 += 1;
+                }
+                Err(err) => {
+                    assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+                    let transferred = Elapsed::of(&err).unwrap().transferred;
+                    assert_eq!(buf[..transferred], data[..transferred]);
+                    // nothing is lost to the cancelled read
+                    a.read_exact(&mut buf[transferred..]).await.unwrap();
+                    assert_eq!(buf, data);
+                    outcomes.1 += 1;
+                }
+            }
+        }
+        assert!(outcomes.0 > 0 && outcomes.1 > 0, "{:?}", outcomes);
+    }
+}
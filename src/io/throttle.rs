@@ -0,0 +1,516 @@
+//! Bandwidth Shaping
+//!
+//! Token buckets capping the bytes per second of a stream, for reads and
+//! writes apart. A stream draws from a bucket of its own and from any
+//! number of shared ones, a shared bucket caps all its streams together.
+
+use std::{
+    future::Future,
+    io,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use futures_util::ready;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    time::Instant,
+};
+
+use crate::clock::{Clock, TokioClock};
+
+/// Rate limit of every connection of a server, in each direction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RateLimitOption {
+    /// Bytes per second a connection reads, and writes.
+    pub rate_bytes_per_sec: u64,
+    /// Bytes moved at once after an idle spell, one second of the rate by default.
+    #[serde(default)]
+    pub burst: Option<u64>,
+    /// Bytes per second of all connections together, on top of their own rate.
+    #[serde(default)]
+    pub server_rate_bytes_per_sec: Option<u64>,
+    /// Burst of the server wide buckets, one second of their rate by default.
+    #[serde(default)]
+    pub server_burst: Option<u64>,
+}
+
+/// Waits are at least this long, smaller grants only cost wakeups.
+const MIN_WAIT: Duration = Duration::from_millis(10);
+
+/// A token bucket, one byte per token.
+#[derive(Debug)]
+pub struct RateLimiter<C = TokioClock> {
+    rate: u64,
+    burst: u64,
+    clock: C,
+    bucket: Mutex<Bucket>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    /// Negative once shared users drew more than a check allowed.
+    tokens: f64,
+    updated: Instant,
+}
+
+impl RateLimiter {
+    /// A full bucket refilled at `rate_bytes_per_sec` up to `burst`.
+    pub fn new(rate_bytes_per_sec: u64, burst: u64) -> Self {
+        Self::with_clock(rate_bytes_per_sec, burst, TokioClock)
+    }
+}
+
+impl<C: Clock> RateLimiter<C> {
+    /// A full bucket refilled on `clock`.
+    pub fn with_clock(rate_bytes_per_sec: u64, burst: u64, clock: C) -> Self {
+        let rate = rate_bytes_per_sec.max(1);
+        let burst = burst.max(1);
+        Self {
+            rate,
+            burst,
+            bucket: Mutex::new(Bucket {
+                tokens: burst as f64,
+                updated: clock.now(),
+            }),
+            clock,
+        }
+    }
+
+    /// A bucket holding a second of `rate_bytes_per_sec`, empty so the rate
+    /// applies from the first byte.
+    pub(crate) fn paced(rate_bytes_per_sec: u64, clock: C) -> Self {
+        let limiter = Self::with_clock(rate_bytes_per_sec, rate_bytes_per_sec, clock);
+        limiter.consume(limiter.burst);
+        limiter
+    }
+
+    pub fn rate(&self) -> u64 {
+        self.rate
+    }
+
+    pub fn burst(&self) -> u64 {
+        self.burst
+    }
+
+    /// Whole tokens in the bucket now.
+    pub fn available(&self) -> u64 {
+        self.refill(self.clock.now()).max(0.0) as u64
+    }
+
+    /// Take `n` tokens, going into debt if there are fewer.
+    pub fn consume(&self, n: u64) {
+        self.refill(self.clock.now());
+        self.lock().tokens -= n as f64;
+    }
+
+    /// Time until `n` tokens, at most a burst, are in the bucket.
+    fn wait_for(&self, n: u64) -> Duration {
+        let missing = n.min(self.burst) as f64 - self.refill(self.clock.now());
+        if missing <= 0.0 {
+            return Duration::ZERO;
+        }
+        Duration::from_secs_f64(missing / self.rate as f64)
+    }
+
+    fn refill(&self, now: Instant) -> f64 {
+        let mut bucket = self.lock();
+        let elapsed = now.saturating_duration_since(bucket.updated);
+        bucket.tokens =
+            (bucket.tokens + elapsed.as_secs_f64() * self.rate as f64).min(self.burst as f64);
+        bucket.updated = now;
+        bucket.tokens
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Bucket> {
+        self.bucket.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Read and write buckets shared by streams.
+type SharedBuckets<C> = (Arc<RateLimiter<C>>, Arc<RateLimiter<C>>);
+
+/// The buckets of a server: per connection ones are made for each stream,
+/// the server wide ones are shared by all of them.
+#[derive(Debug, Clone)]
+pub struct RateLimits<C = TokioClock> {
+    opt: RateLimitOption,
+    clock: C,
+    /// Read and write buckets of the whole server.
+    shared: Option<SharedBuckets<C>>,
+}
+
+impl RateLimits {
+    pub fn new(opt: RateLimitOption) -> Self {
+        Self::with_clock(opt, TokioClock)
+    }
+}
+
+impl<C: Clock> RateLimits<C> {
+    /// Buckets refilled and waited for on `clock`.
+    pub fn with_clock(opt: RateLimitOption, clock: C) -> Self {
+        let shared = opt.server_rate_bytes_per_sec.map(|rate| {
+            let burst = opt.server_burst.unwrap_or(rate);
+            (
+                Arc::new(RateLimiter::with_clock(rate, burst, clock.clone())),
+                Arc::new(RateLimiter::with_clock(rate, burst, clock.clone())),
+            )
+        });
+        Self { opt, clock, shared }
+    }
+
+    pub fn option(&self) -> &RateLimitOption {
+        &self.opt
+    }
+
+    /// Throttle `stream` at the connection rate and the server wide one.
+    pub fn wrap<S>(&self, stream: S) -> ThrottledStream<S, C> {
+        let rate = self.opt.rate_bytes_per_sec;
+        let burst = self.opt.burst.unwrap_or(rate);
+        let stream = ThrottledStream::with_clock(stream, rate, burst, self.clock.clone());
+        match self.shared {
+            Some((ref read, ref write)) => stream.with_shared(read.clone(), write.clone()),
+            None => stream,
+        }
+    }
+}
+
+/// Throttle `stream` with `limits` when set, pass it through otherwise.
+pub(crate) fn throttle<S>(stream: S, limits: Option<&RateLimits>) -> ThrottledStream<S> {
+    match limits {
+        Some(limits) => limits.wrap(stream),
+        None => ThrottledStream::unlimited(stream),
+    }
+}
+
+/// Buckets of one direction and the timer of its wait.
+pub(crate) struct Gate<C: Clock> {
+    buckets: Vec<Arc<RateLimiter<C>>>,
+    clock: C,
+    sleep: Option<Pin<Box<C::Sleep>>>,
+}
+
+impl<C: Clock> Gate<C> {
+    pub(crate) fn new(buckets: Vec<Arc<RateLimiter<C>>>, clock: C) -> Self {
+        Self {
+            buckets,
+            clock,
+            sleep: None,
+        }
+    }
+
+    /// Bytes of `want` the buckets allow now, waiting while they allow none.
+    ///
+    /// A wait lasts until every bucket holds a share worth at least
+    /// `MIN_WAIT`, so a slow rate does not wake the task for each byte.
+    pub(crate) fn poll_grant(&mut self, cx: &mut Context<'_>, want: usize) -> Poll<usize> {
+        if self.buckets.is_empty() || want == 0 {
+            return Poll::Ready(want);
+        }
+        loop {
+            if let Some(sleep) = self.sleep.as_mut() {
+                ready!(sleep.as_mut().poll(cx));
+            }
+
+            let available = self.buckets.iter().map(|b| b.available()).min();
+            let grant = available.unwrap_or(0).min(want as u64) as usize;
+            if grant > 0 {
+                self.sleep = None;
+                return Poll::Ready(grant);
+            }
+
+            let wait = self
+                .buckets
+                .iter()
+                .map(|b| {
+                    let share = (b.rate() as f64 * MIN_WAIT.as_secs_f64()).ceil() as u64;
+                    b.wait_for(share.clamp(1, want as u64))
+                })
+                .max()
+                .unwrap_or_default()
+                .max(MIN_WAIT);
+            let deadline = self.clock.now() + wait;
+            match self.sleep.as_mut() {
+                Some(sleep) => self.clock.reset(sleep.as_mut(), deadline),
+                None => self.sleep = Some(Box::pin(self.clock.sleep_until(deadline))),
+            }
+        }
+    }
+
+    pub(crate) fn consume(&self, n: usize) {
+        for bucket in &self.buckets {
+            bucket.consume(n as u64);
+        }
+    }
+
+    /// Whether no bucket limits the direction.
+    pub(crate) fn is_open(&self) -> bool {
+        self.buckets.is_empty()
+    }
+
+    pub(crate) fn push(&mut self, bucket: Arc<RateLimiter<C>>) {
+        self.buckets.push(bucket);
+    }
+}
+
+/// Caps the bytes per second read from and written to `S`.
+///
+/// Reads and writes move what the buckets allow and return short instead
+/// of waiting for room for the whole buffer.
+pub struct ThrottledStream<S, C: Clock = TokioClock> {
+    inner: S,
+    read: Gate<C>,
+    write: Gate<C>,
+}
+
+impl<S> ThrottledStream<S> {
+    /// Each direction at `rate_bytes_per_sec`, moving up to `burst` bytes at once.
+    pub fn new(inner: S, rate_bytes_per_sec: u64, burst: u64) -> Self {
+        Self::with_clock(inner, rate_bytes_per_sec, burst, TokioClock)
+    }
+
+    /// No bucket of its own, limited only by the shared ones added.
+    pub fn unlimited(inner: S) -> Self {
+        Self {
+            inner,
+            read: Gate::new(vec![], TokioClock),
+            write: Gate::new(vec![], TokioClock),
+        }
+    }
+}
+
+impl<S, C: Clock> ThrottledStream<S, C> {
+    /// Like `new`, the buckets refilled and waited for on `clock`.
+    pub fn with_clock(inner: S, rate_bytes_per_sec: u64, burst: u64, clock: C) -> Self {
+        let bucket = || {
+            let limiter = RateLimiter::with_clock(rate_bytes_per_sec, burst, clock.clone());
+            vec![Arc::new(limiter)]
+        };
+        Self {
+            inner,
+            read: Gate::new(bucket(), clock.clone()),
+            write: Gate::new(bucket(), clock),
+        }
+    }
+
+    /// Also draw from `read` and `write`, buckets shared with other streams.
+    pub fn with_shared(mut self, read: Arc<RateLimiter<C>>, write: Arc<RateLimiter<C>>) -> Self {
+        self.read.push(read);
+        self.write.push(write);
+        self
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+impl<S: AsyncRead + Unpin, C: Clock> AsyncRead for ThrottledStream<S, C> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let grant = ready!(this.read.poll_grant(cx, buf.remaining()));
+
+        let n = if grant == buf.remaining() {
+            let filled = buf.filled().len();
+            ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+            buf.filled().len() - filled
+        } else {
+            let mut limited = ReadBuf::new(buf.initialize_unfilled_to(grant));
+            ready!(Pin::new(&mut this.inner).poll_read(cx, &mut limited))?;
+            let n = limited.filled().len();
+            buf.advance(n);
+            n
+        };
+        this.read.consume(n);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin, C: Clock> AsyncWrite for ThrottledStream<S, C> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let grant = ready!(this.write.poll_grant(cx, buf.len()));
+        let n = ready!(Pin::new(&mut this.inner).poll_write(cx, &buf[..grant]))?;
+        this.write.consume(n);
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+    use crate::{
+        tcp::{TcpServer, TcpServerOption},
+        TransportServerCallback, TransportServerTrait,
+    };
+
+    /// Sends 30 KB, then closes.
+    #[derive(Clone)]
+    struct Flood;
+
+    impl TransportServerCallback for Flood {
+        async fn handle<S>(&self, mut stream: S, _addr: Option<SocketAddr>)
+        where
+            S: AsyncRead + AsyncWrite + Unpin + Send + Sync,
+        {
+            let _ = stream.write_all(&[1; 30_000]).await;
+            let _ = stream.shutdown().await;
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_bucket_accounting() {
+        let limiter = RateLimiter::new(1_000, 500);
+        assert_eq!(limiter.available(), 500);
+        limiter.consume(800);
+        // in debt until 300 bytes refilled
+        assert_eq!(limiter.available(), 0);
+        assert_eq!(limiter.wait_for(100), Duration::from_millis(400));
+        tokio::time::advance(Duration::from_millis(400)).await;
+        assert_eq!(limiter.available(), 100);
+        // never above the burst
+        tokio::time::advance(Duration::from_secs(10)).await;
+        assert_eq!(limiter.available(), 500);
+        assert_eq!(limiter.wait_for(1_000), Duration::ZERO);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_throttled_write() {
+        let (a, mut b) = duplex(1 << 20);
+        let mut stream = ThrottledStream::new(a, 100_000, 10_000);
+
+        // a low bucket writes what it holds instead of waiting for the buffer
+        assert_eq!(stream.write(&[1; 40_000]).await.unwrap(), 10_000);
+        assert_eq!(stream.write.buckets[0].available(), 0);
+        let start = Instant::now();
+        stream.write_all(&[1; 20_000]).await.unwrap();
+        // 20 KB refill at 100 KB/s, waits of whole MIN_WAIT shares
+        let elapsed = start.elapsed();
+        assert!(
+            elapsed >= Duration::from_millis(200)
+                && elapsed < Duration::from_millis(200) + MIN_WAIT,
+            "{:?}",
+            elapsed
+        );
+        assert_eq!(stream.write.buckets[0].available(), 0);
+
+        // reads have a bucket of their own
+        b.write_all(&[1; 10_000]).await.unwrap();
+        let start = Instant::now();
+        let mut buf = [0; 10_000];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(start.elapsed(), Duration::ZERO);
+        assert_eq!(stream.read.buckets[0].available(), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_throttled_read_wakeups() {
+        let (a, mut b) = duplex(1 << 20);
+        let mut stream = ThrottledStream::new(a, 1_000, 10);
+        b.write_all(&[1; 300]).await.unwrap();
+
+        let start = Instant::now();
+        let mut buf = [0; 300];
+        let (mut reads, mut total) = (0, 0);
+        while total < 300 {
+            total += stream.read(&mut buf).await.unwrap();
+            reads += 1;
+        }
+        // the burst at once, the other 290 bytes at 1 KB/s
+        assert!(start.elapsed() >= Duration::from_millis(290));
+        // every wait lasts at least MIN_WAIT, not one byte each
+        assert_eq!(reads, 30);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_shared_bucket() {
+        let limits = RateLimits::new(RateLimitOption {
+            rate_bytes_per_sec: 1 << 20,
+            burst: None,
+            server_rate_bytes_per_sec: Some(100_000),
+            server_burst: Some(10_000),
+        });
+
+        let start = Instant::now();
+        let tasks = (0..2)
+            .map(|_| {
+                let (a, mut b) = duplex(1 << 20);
+                let mut stream = limits.wrap(a);
+                tokio::spawn(async move {
+                    stream.write_all(&[1; 15_000]).await.unwrap();
+                    drop(stream);
+                    let mut buf = vec![];
+                    b.read_to_end(&mut buf).await.unwrap();
+                    buf.len()
+                })
+            })
+            .collect::<Vec<_>>();
+        for task in tasks {
+            assert_eq!(task.await.unwrap(), 15_000);
+        }
+        // both streams draw from the one server bucket, 20 KB past its burst
+        let elapsed = start.elapsed();
+        assert!(
+            elapsed >= Duration::from_millis(200) && elapsed < Duration::from_millis(250),
+            "{:?}",
+            elapsed
+        );
+        let (_, write) = limits.shared.as_ref().unwrap();
+        assert_eq!(write.available(), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_tcp_server_rate_limit() {
+        let opt: TcpServerOption = serde_json::from_value(serde_json::json!({
+            "listen": "127.0.0.1:9915",
+            "rate_limit": {"rate_bytes_per_sec": 100_000, "burst": 10_000},
+        }))
+        .unwrap();
+        let srv = TcpServer::init(opt, None).unwrap();
+        let serving = tokio::spawn(async move { srv.serve(Flood).await });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let start = Instant::now();
+        let mut stream = tokio::net::TcpStream::connect("127.0.0.1:9915")
+            .await
+            .unwrap();
+        let mut buf = vec![];
+        stream.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf.len(), 30_000);
+        // a burst at once, the rest at the rate
+        let elapsed = start.elapsed();
+        assert!(
+            elapsed >= Duration::from_millis(200) && elapsed < Duration::from_millis(250),
+            "{:?}",
+            elapsed
+        );
+        serving.abort();
+    }
+}
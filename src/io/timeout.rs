@@ -0,0 +1,251 @@
+//! Read and Write Inactivity Timeouts
+
+use std::{
+    future::Future,
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    time::{Instant, Sleep},
+};
+
+use super::elapsed;
+
+/// Timer of one direction, armed by the first `Pending` after progress.
+#[derive(Debug, Default)]
+struct Idle {
+    timeout: Option<Duration>,
+    sleep: Option<Pin<Box<Sleep>>>,
+    armed: bool,
+}
+
+impl Idle {
+    fn new(timeout: Option<Duration>) -> Self {
+        Self {
+            timeout,
+            ..Default::default()
+        }
+    }
+
+    fn set(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+        self.armed = false;
+    }
+
+    fn poll<T>(&mut self, cx: &mut Context<'_>, res: Poll<io::Result<T>>) -> Poll<io::Result<T>> {
+        if res.is_ready() {
+            self.armed = false;
+            return res;
+        }
+        let Some(timeout) = self.timeout else {
+            return Poll::Pending;
+        };
+        if !self.armed {
+            let deadline = Instant::now() + timeout;
+            match self.sleep {
+                Some(ref mut sleep) => sleep.as_mut().reset(deadline),
+                None => self.sleep = Some(Box::pin(tokio::time::sleep_until(deadline))),
+            }
+            self.armed = true;
+        }
+        let sleep = self.sleep.as_mut().expect("armed without a timer");
+        if sleep.as_mut().poll(cx).is_ready() {
+            self.armed = false;
+            return Poll::Ready(Err(elapsed(0)));
+        }
+        Poll::Pending
+    }
+}
+
+/// Fails reads and writes with `TimedOut` when they make no progress in time.
+///
+/// A timer starts when a read, or a write, flush or shutdown, first returns
+/// `Pending` and stops with any progress, so busy long lived streams never
+/// time out and neither does a stream nobody polls.
+#[derive(Debug)]
+pub struct TimeoutStream<S> {
+    inner: S,
+    read: Idle,
+    write: Idle,
+}
+
+impl<S> TimeoutStream<S> {
+    /// No timeouts until set.
+    pub fn new(inner: S) -> Self {
+        Self {
+            inner,
+            read: Idle::default(),
+            write: Idle::default(),
+        }
+    }
+
+    /// The same `timeout` for both directions.
+    pub fn idle(inner: S, timeout: Option<Duration>) -> Self {
+        Self {
+            inner,
+            read: Idle::new(timeout),
+            write: Idle::new(timeout),
+        }
+    }
+
+    pub fn read_timeout(&self) -> Option<Duration> {
+        self.read.timeout
+    }
+
+    pub fn write_timeout(&self) -> Option<Duration> {
+        self.write.timeout
+    }
+
+    /// Takes effect from the next pending read, a waiting one starts over.
+    pub fn set_read_timeout(&mut self, timeout: Option<Duration>) {
+        self.read.set(timeout);
+    }
+
+    /// Takes effect from the next pending write, a waiting one starts over.
+    pub fn set_write_timeout(&mut self, timeout: Option<Duration>) {
+        self.write.set(timeout);
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for TimeoutStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let res = Pin::new(&mut this.inner).poll_read(cx, buf);
+        this.read.poll(cx, res)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for TimeoutStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let res = Pin::new(&mut this.inner).poll_write(cx, buf);
+        this.write.poll(cx, res)
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let res = Pin::new(&mut this.inner).poll_write_vectored(cx, bufs);
+        this.write.poll(cx, res)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let res = Pin::new(&mut this.inner).poll_flush(cx);
+        this.write.poll(cx, res)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let res = Pin::new(&mut this.inner).poll_shutdown(cx);
+        this.write.poll(cx, res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+    use crate::chaos::{ChaosOption, ChaosStream};
+
+    #[tokio::test]
+    async fn test_timeout_stream() {
+        let (a, mut b) = tokio::io::duplex(4);
+        let mut a = TimeoutStream::idle(a, Some(Duration::from_millis(100)));
+
+        // a trickle keeps the stream alive well past the timeout
+        let writer = tokio::spawn(async move {
+            for _ in 0..5 {
+                tokio::time::sleep(Duration::from_millis(60)).await;
+                b.write_all(b"x").await.unwrap();
+            }
+            b
+        });
+        let mut buf = [0u8; 5];
+        a.read_exact(&mut buf).await.unwrap();
+        let mut b = writer.await.unwrap();
+
+        let start = Instant::now();
+        let err = a.read(&mut buf).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        let waited = start.elapsed();
+        assert!(waited >= Duration::from_millis(100), "{:?}", waited);
+        assert!(waited < Duration::from_millis(500), "{:?}", waited);
+
+        // nobody reads b, the write stalls once the pipe is full
+        let err = a.write_all(&[0u8; 8]).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        b.read_exact(&mut [0u8; 4]).await.unwrap();
+
+        a.set_read_timeout(None);
+        a.set_write_timeout(Some(Duration::from_secs(5)));
+        assert_eq!(a.write_timeout(), Some(Duration::from_secs(5)));
+        let read = tokio::time::timeout(Duration::from_millis(300), a.read(&mut buf)).await;
+        assert!(read.is_err());
+        a.write_all(b"ok").await.unwrap();
+        drop(a);
+        let mut rest = vec![];
+        b.read_to_end(&mut rest).await.unwrap();
+        assert_eq!(rest, b"ok");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_timeout_chaos_stall() {
+        let stall = |stall_duration| ChaosOption {
+            stall_probability: 1.0,
+            stall_duration,
+            ..Default::default()
+        };
+
+        // the bytes are there, the stall outlasts the idle timeout
+        let (a, mut b) = tokio::io::duplex(64);
+        b.write_all(b"late").await.unwrap();
+        let a = ChaosStream::new(a, stall(Duration::from_secs(10)), 1);
+        let mut a = TimeoutStream::idle(a, Some(Duration::from_millis(100)));
+        let start = Instant::now();
+        let err = a.read(&mut [0u8; 4]).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert_eq!(start.elapsed(), Duration::from_millis(100));
+
+        // a stall within the timeout goes through
+        let (a, mut b) = tokio::io::duplex(64);
+        b.write_all(b"late").await.unwrap();
+        let a = ChaosStream::new(a, stall(Duration::from_millis(50)), 1);
+        let mut a = TimeoutStream::idle(a, Some(Duration::from_millis(100)));
+        let mut buf = [0u8; 4];
+        a.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"late");
+    }
+}
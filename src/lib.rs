@@ -1,9 +1,9 @@
 //! Kapibara Transport Library
-use std::net::SocketAddr;
+use std::{future::Future, net::SocketAddr, time::Duration};
 use tokio::io::{AsyncRead, AsyncWrite};
 
 pub mod error;
-pub use error::{ClientError, ServerError};
+pub use error::{ClientError, ErrorCode, ServerError, StructuredError};
 
 pub mod option;
 pub use option::{TransportClientOption, TransportServerOption};
@@ -20,8 +20,46 @@ pub use tls::{TlsCertOption, TlsClientOption, TlsError, TlsServerOption};
 pub mod dns;
 pub use dns::{ResolveError, ResolveOption, Resolver};
 
+pub mod context;
+pub use context::{ConnContext, ConnectionInfo};
+
+pub mod reload;
+pub use reload::AppliedChanges;
+
+#[cfg(any(test, feature = "test-util"))]
+pub mod chaos;
+pub mod clock;
+pub mod config_migrate;
 pub mod empty;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+pub mod group;
+pub mod grpc;
+pub mod http2;
+pub mod io;
+pub mod limit;
+#[cfg(any(test, feature = "test-util"))]
+pub mod memory;
+pub mod mux;
+pub mod net;
+pub mod observe;
+pub mod policy;
+pub mod pool;
+pub mod reap;
+#[cfg(feature = "recorder")]
+pub mod recorder;
+pub mod retry;
+pub mod secret;
+mod shutdown;
+pub mod stats;
 pub mod tcp;
+#[cfg(test)]
+mod test_util;
+pub mod trace;
+pub mod udp;
+#[cfg(unix)]
+pub mod unix;
+pub mod validate;
 pub mod websocket;
 
 pub type ClientResult<T> = std::result::Result<T, ClientError>;
@@ -33,6 +71,21 @@ pub trait LocalTransportServerTrait {
     async fn serve<C>(&self, callback: C) -> ServerResult<()>
     where
         C: TransportServerCallback;
+
+    /// Serve until `signal` completes, then stop accepting and give running
+    /// callbacks `grace` to finish. The streams of callbacks still running
+    /// after it are invalidated, reads see EOF and writes fail with
+    /// `BrokenPipe`, and the callbacks get the kill grace of the server to
+    /// return before they are dropped.
+    async fn serve_with_shutdown<C, F>(
+        &self,
+        callback: C,
+        signal: F,
+        grace: Duration,
+    ) -> ServerResult<()>
+    where
+        C: TransportServerCallback,
+        F: Future<Output = ()> + Send + Sync;
 }
 
 #[trait_variant::make(TransportServerCallback: Send + Sync)]
@@ -40,6 +93,14 @@ pub trait LocalTransportServerCallback: 'static + Clone {
     async fn handle<S>(&self, stream: S, addr: Option<SocketAddr>)
     where
         S: AsyncRead + AsyncWrite + Unpin + Send + Sync;
+
+    /// Called by servers with the full connection context, defaults to `handle`.
+    fn handle_ctx<S>(&self, stream: S, ctx: ConnContext) -> impl Future<Output = ()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + Sync,
+    {
+        self.handle(stream, ctx.peer_addr)
+    }
 }
 
 #[trait_variant::make(TransportClientTrait: Send + Sync)]
@@ -80,6 +141,7 @@ macro_rules! stream_traits_enum {
             ) -> std::task::Poll<std::io::Result<()>> {
                 match self.get_mut() {
                     $(
+                        $(#[$item_meta])*
                         $name::$id(val) => std::pin::Pin::new(val).poll_read(cx, buf),
                     )+
                 }
@@ -96,6 +158,7 @@ macro_rules! stream_traits_enum {
             ) -> std::task::Poll<std::io::Result<usize>> {
                 match self.get_mut() {
                     $(
+                        $(#[$item_meta])*
                         $name::$id(val) => std::pin::Pin::new(val).poll_write(cx, buf),
                     )+
                 }
@@ -108,6 +171,7 @@ macro_rules! stream_traits_enum {
             ) -> std::task::Poll<std::io::Result<()>> {
                 match self.get_mut() {
                     $(
+                        $(#[$item_meta])*
                         $name::$id(val) => std::pin::Pin::new(val).poll_flush(cx),
                     )+
                 }
@@ -120,13 +184,53 @@ macro_rules! stream_traits_enum {
             ) -> std::task::Poll<std::io::Result<()>> {
                 match self.get_mut() {
                     $(
+                        $(#[$item_meta])*
                         $name::$id(val) => std::pin::Pin::new(val).poll_shutdown(cx),
                     )+
                 }
             }
+
+            #[inline]
+            fn poll_write_vectored(
+                self: std::pin::Pin<&mut Self>,
+                cx: &mut std::task::Context<'_>,
+                bufs: &[std::io::IoSlice<'_>],
+            ) -> std::task::Poll<std::io::Result<usize>> {
+                match self.get_mut() {
+                    $(
+                        $(#[$item_meta])*
+                        $name::$id(val) => std::pin::Pin::new(val).poll_write_vectored(cx, bufs),
+                    )+
+                }
+            }
+
+            #[inline]
+            fn is_write_vectored(&self) -> bool {
+                match self {
+                    $(
+                        $(#[$item_meta])*
+                        $name::$id(val) => val.is_write_vectored(),
+                    )+
+                }
+            }
+        }
+
+        $crate::timeout_io_methods!($name);
+
+        impl $name {
+            /// Name of the variant, the transport of a transport stream.
+            pub fn kind(&self) -> &'static str {
+                match self {
+                    $(
+                        $(#[$item_meta])*
+                        $name::$id(_) => stringify!($id),
+                    )+
+                }
+            }
         }
 
         $(
+            $(#[$item_meta])*
             impl From<$id_ty> for $name {
                 fn from(val: $id_ty) -> $name {
                     $name::$id(val)
@@ -135,3 +239,53 @@ macro_rules! stream_traits_enum {
         )+
     };
 }
+
+#[cfg(test)]
+mod tests {
+    /// Optional features, every combination has to build and pass its tests.
+    const FEATURES: &[&str] = &[
+        "insecure-tls",
+        "test-util",
+        "ffi",
+        "recorder",
+        "tracing",
+        "self-signed",
+    ];
+
+    #[test]
+    #[ignore = "feature matrix, run with --ignored --nocapture"]
+    fn feature_matrix() {
+        use std::process::Command;
+
+        let manifest_dir = env!("CARGO_MANIFEST_DIR");
+        // a target dir of its own, the running test holds the default one
+        let target_dir = format!("{}/target/feature-matrix", manifest_dir);
+        for mask in 0..1u32 << FEATURES.len() {
+            let features = FEATURES
+                .iter()
+                .enumerate()
+                .filter(|(i, _)| mask & (1 << i) != 0)
+                .map(|(_, f)| *f)
+                .collect::<Vec<_>>()
+                .join(",");
+            let features = ["--no-default-features", "--features", &features];
+            let runs: [&[&str]; 2] = [
+                &["clippy", "--all-targets", "--", "-D", "warnings"],
+                &["test", "--lib"],
+            ];
+            for run in runs {
+                let (cmd, rest) = run.split_at(1);
+                let status = Command::new(env!("CARGO"))
+                    .current_dir(manifest_dir)
+                    .env("CARGO_TARGET_DIR", &target_dir)
+                    .args(cmd)
+                    .args(features)
+                    .args(rest)
+                    .status()
+                    .unwrap();
+                println!("{} {:?}: {}", cmd[0], features[2], status);
+                assert!(status.success(), "{} failed with {:?}", cmd[0], features);
+            }
+        }
+    }
+}
@@ -0,0 +1,368 @@
+//! Per Connection Byte Limits
+
+use std::{
+    io::{Error, ErrorKind},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+};
+
+use futures_util::ready;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::{
+    clock::{Clock, TokioClock},
+    io::{throttle::Gate, RateLimiter},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MaxBytesOption {
+    /// Bytes read from the peer.
+    #[serde(default)]
+    pub rx: Option<u64>,
+    /// Bytes written to the peer.
+    #[serde(default)]
+    pub tx: Option<u64>,
+    #[serde(default)]
+    pub action: LimitAction,
+}
+
+/// What happens once either direction reaches its limit.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LimitAction {
+    /// Reads return EOF and writes fail with `BrokenPipe`.
+    #[default]
+    Close,
+    /// Keep going at this many bytes per second in each direction, shaped
+    /// like a `ThrottledStream`.
+    Throttle(u64),
+}
+
+/// Bytes moved by one connection, shared with the connection context.
+#[derive(Debug, Clone, Default)]
+pub struct ByteCount(Arc<Counters>);
+
+#[derive(Debug, Default)]
+struct Counters {
+    rx: AtomicU64,
+    tx: AtomicU64,
+    exceeded: AtomicBool,
+}
+
+impl ByteCount {
+    pub fn rx(&self) -> u64 {
+        self.0.rx.load(Ordering::Relaxed)
+    }
+
+    pub fn tx(&self) -> u64 {
+        self.0.tx.load(Ordering::Relaxed)
+    }
+
+    /// Whether a limit was reached.
+    pub fn exceeded(&self) -> bool {
+        self.0.exceeded.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn add_rx(&self, n: usize) {
+        self.0.rx.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn add_tx(&self, n: usize) {
+        self.0.tx.fetch_add(n as u64, Ordering::Relaxed);
+    }
+}
+
+pub struct LimitedStream<S, C: Clock = TokioClock> {
+    inner: S,
+    limit: Option<MaxBytesOption>,
+    count: ByteCount,
+    clock: C,
+    /// Open until a throttling limit is reached.
+    read: Gate<C>,
+    write: Gate<C>,
+}
+
+impl<S> LimitedStream<S> {
+    /// Count the bytes of `inner`, enforcing `limit` when set.
+    pub fn new(inner: S, limit: Option<MaxBytesOption>) -> Self {
+        Self::with_clock(inner, limit, TokioClock)
+    }
+}
+
+impl<S, C: Clock> LimitedStream<S, C> {
+    /// Like `new`, throttled on `clock`.
+    pub fn with_clock(inner: S, limit: Option<MaxBytesOption>, clock: C) -> Self {
+        let count = ByteCount::default();
+        // a zero limit is reached before the first byte moved
+        if limit.is_some_and(|l| l.rx == Some(0) || l.tx == Some(0)) {
+            count.0.exceeded.store(true, Ordering::Relaxed);
+        }
+        let mut stream = Self {
+            inner,
+            limit,
+            count,
+            read: Gate::new(vec![], clock.clone()),
+            write: Gate::new(vec![], clock.clone()),
+            clock,
+        };
+        stream.start_throttle();
+        stream
+    }
+
+    pub fn count(&self) -> ByteCount {
+        self.count.clone()
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+
+    fn action(&self) -> Option<LimitAction> {
+        self.limit
+            .filter(|_| self.count.exceeded())
+            .map(|limit| limit.action)
+    }
+
+    /// Bytes one direction may still move before the close action applies.
+    fn budget(&self, is_read: bool) -> Option<u64> {
+        let limit = self.limit.filter(|l| l.action == LimitAction::Close)?;
+        let (max, used) = if is_read {
+            (limit.rx?, self.count.rx())
+        } else {
+            (limit.tx?, self.count.tx())
+        };
+        Some(max.saturating_sub(used))
+    }
+
+    fn account(&mut self, n: usize, is_read: bool) {
+        let (counter, max) = if is_read {
+            (&self.count.0.rx, self.limit.and_then(|l| l.rx))
+        } else {
+            (&self.count.0.tx, self.limit.and_then(|l| l.tx))
+        };
+        let used = counter.fetch_add(n as u64, Ordering::Relaxed) + n as u64;
+        if max.is_some_and(|max| used >= max) {
+            self.count.0.exceeded.store(true, Ordering::Relaxed);
+        }
+
+        if is_read {
+            self.read.consume(n);
+        } else {
+            self.write.consume(n);
+        }
+        self.start_throttle();
+    }
+
+    /// Put both directions on buckets at the rate of a reached throttle limit.
+    fn start_throttle(&mut self) {
+        let Some(LimitAction::Throttle(rate)) = self.action() else {
+            return;
+        };
+        if self.read.is_open() {
+            self.read
+                .push(Arc::new(RateLimiter::paced(rate, self.clock.clone())));
+            self.write
+                .push(Arc::new(RateLimiter::paced(rate, self.clock.clone())));
+        }
+    }
+}
+
+fn limit_error() -> Error {
+    Error::new(ErrorKind::BrokenPipe, "byte limit exceeded")
+}
+
+impl<S: AsyncRead + Unpin, C: Clock> AsyncRead for LimitedStream<S, C> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        if this.action() == Some(LimitAction::Close) {
+            return Poll::Ready(Ok(()));
+        }
+        let want = match this.budget(true) {
+            Some(budget) => std::cmp::min(budget as usize, buf.remaining()),
+            None => buf.remaining(),
+        };
+        let grant = ready!(this.read.poll_grant(cx, want));
+
+        let n = if grant < buf.remaining() {
+            let mut limited = ReadBuf::new(buf.initialize_unfilled_to(grant));
+            ready!(Pin::new(&mut this.inner).poll_read(cx, &mut limited))?;
+            let n = limited.filled().len();
+            buf.advance(n);
+            n
+        } else {
+            let filled = buf.filled().len();
+            ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+            buf.filled().len() - filled
+        };
+
+        this.account(n, true);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin, C: Clock> AsyncWrite for LimitedStream<S, C> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        if this.action() == Some(LimitAction::Close) {
+            return Poll::Ready(Err(limit_error()));
+        }
+        let want = match this.budget(false) {
+            Some(budget) => std::cmp::min(budget as usize, buf.len()),
+            None => buf.len(),
+        };
+        let len = ready!(this.write.poll_grant(cx, want));
+        let n = ready!(Pin::new(&mut this.inner).poll_write(cx, &buf[..len]))?;
+
+        this.account(n, false);
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::{
+        io::{duplex, AsyncReadExt, AsyncWriteExt},
+        time::Instant,
+    };
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_rx_limit_close() {
+        let (a, mut b) = duplex(1024);
+        let limit = MaxBytesOption {
+            rx: Some(60),
+            tx: None,
+            action: LimitAction::Close,
+        };
+        let mut stream = LimitedStream::new(a, Some(limit));
+        let count = stream.count();
+
+        b.write_all(&[1; 100]).await.unwrap();
+        let mut buf = vec![];
+        stream.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf.len(), 60);
+        assert!(count.exceeded());
+        assert_eq!((count.rx(), count.tx()), (60, 0));
+
+        let err = stream.write_all(b"late").await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::BrokenPipe);
+    }
+
+    #[tokio::test]
+    async fn test_tx_limit_close() {
+        let (a, mut b) = duplex(1024);
+        let limit = MaxBytesOption {
+            rx: None,
+            tx: Some(60),
+            action: LimitAction::Close,
+        };
+        let mut stream = LimitedStream::new(a, Some(limit));
+        let count = stream.count();
+
+        let err = stream.write_all(&[1; 100]).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::BrokenPipe);
+        assert!(count.exceeded());
+        assert_eq!((count.rx(), count.tx()), (0, 60));
+
+        let mut buf = [0; 100];
+        assert_eq!(b.read(&mut buf).await.unwrap(), 60);
+        b.write_all(b"reply").await.unwrap();
+        assert_eq!(stream.read(&mut buf).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_zero_limit_close() {
+        let (a, mut b) = duplex(1024);
+        let limit = MaxBytesOption {
+            rx: None,
+            tx: Some(0),
+            action: LimitAction::Close,
+        };
+        let mut stream = LimitedStream::new(a, Some(limit));
+        let count = stream.count();
+
+        let err = stream.write_all(b"none").await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::BrokenPipe);
+        assert!(count.exceeded());
+        assert_eq!(count.tx(), 0);
+
+        drop(stream);
+        let mut buf = vec![];
+        b.read_to_end(&mut buf).await.unwrap();
+        assert!(buf.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_no_limit_counts() {
+        let (a, mut b) = duplex(1024);
+        let mut stream = LimitedStream::new(a, None);
+        let count = stream.count();
+
+        stream.write_all(&[1; 100]).await.unwrap();
+        b.write_all(&[1; 40]).await.unwrap();
+        let mut buf = [0; 100];
+        stream.read_exact(&mut buf[..40]).await.unwrap();
+        assert_eq!((count.rx(), count.tx()), (40, 100));
+        assert!(!count.exceeded());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_throttle_after_limit() {
+        const RATE: u64 = 32 * 1024;
+
+        let (a, mut b) = duplex(64 * 1024);
+        let limit = MaxBytesOption {
+            rx: None,
+            tx: Some(1024),
+            action: LimitAction::Throttle(RATE),
+        };
+        let mut stream = LimitedStream::new(a, Some(limit));
+        let count = stream.count();
+        tokio::spawn(async move {
+            let mut buf = vec![];
+            b.read_to_end(&mut buf).await.unwrap();
+        });
+
+        let start = Instant::now();
+        stream.write_all(&[1; 1024]).await.unwrap();
+        assert!(start.elapsed() < Duration::from_millis(50));
+        assert!(count.exceeded());
+
+        let start = Instant::now();
+        for _ in 0..16 {
+            stream.write_all(&[1; 1024]).await.unwrap();
+        }
+        let elapsed = start.elapsed();
+        // paced from the limit on, 16 KiB at 32 KiB/s
+        assert!(elapsed >= Duration::from_millis(400), "{:?}", elapsed);
+        assert_eq!(count.tx(), 17 * 1024);
+    }
+}
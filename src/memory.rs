@@ -0,0 +1,377 @@
+//! Memory Transport
+//!
+//! A client and server joined by `tokio::io::duplex` pipes instead of
+//! sockets, for tests of code built on the transport traits. A server
+//! registers its address in a process wide broker when it is built, the
+//! connects of clients naming the same address are routed to it.
+
+use std::{
+    collections::HashMap,
+    future::Future,
+    io::{Error, ErrorKind},
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::{Arc, Mutex, MutexGuard, OnceLock},
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::{io::DuplexStream, sync::mpsc};
+
+use crate::{
+    context::{AcceptHook, ConnContext},
+    limit::LimitedStream,
+    observe::{self, ServeObserver},
+    shutdown::{killable, Tasks, DEFAULT_KILL_GRACE},
+    stats::{ServerStats, ServerStatsSnapshot},
+    ClientResult, ServerResult, TransportClientTrait, TransportServerCallback,
+    TransportServerTrait,
+};
+
+pub type MemoryStream = DuplexStream;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MemoryClientOption {
+    /// Address the server registered, any string.
+    pub addr: String,
+    /// Bytes buffered in each direction before a write waits for the reader.
+    #[serde(default = "default_buffer_size")]
+    pub buffer_size: usize,
+    /// Delay of every connect, a stand in for the round trip of a dial.
+    #[serde(default)]
+    pub latency: Option<Duration>,
+}
+
+fn default_buffer_size() -> usize {
+    64 * 1024
+}
+
+impl MemoryClientOption {
+    pub fn new(addr: impl Into<String>) -> Self {
+        Self {
+            addr: addr.into(),
+            buffer_size: default_buffer_size(),
+            latency: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MemoryServerOption {
+    /// Address clients connect to, unique among the live servers.
+    pub addr: String,
+}
+
+/// Peer and local address reported for memory streams.
+const MEMORY_PEER: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0);
+
+type Registry = HashMap<String, mpsc::UnboundedSender<MemoryStream>>;
+
+fn broker() -> MutexGuard<'static, Registry> {
+    static BROKER: OnceLock<Mutex<Registry>> = OnceLock::new();
+    BROKER
+        .get_or_init(Mutex::default)
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+}
+
+/// Connects to the `MemoryServer` of its address.
+pub struct MemoryClient {
+    opt: MemoryClientOption,
+}
+
+impl MemoryClient {
+    pub fn new(opt: MemoryClientOption) -> Self {
+        Self { opt }
+    }
+}
+
+impl TransportClientTrait for MemoryClient {
+    type Stream = MemoryStream;
+
+    async fn connect(&self) -> ClientResult<Self::Stream> {
+        if let Some(latency) = self.opt.latency {
+            tokio::time::sleep(latency).await;
+        }
+
+        let refused = || {
+            Error::new(
+                ErrorKind::ConnectionRefused,
+                format!("no memory server at {:?}", self.opt.addr),
+            )
+        };
+        let (local, remote) = tokio::io::duplex(self.opt.buffer_size.max(1));
+        let accept = broker().get(&self.opt.addr).cloned().ok_or_else(refused)?;
+        accept.send(remote).map_err(|_| refused())?;
+        Ok(local)
+    }
+}
+
+/// Serves the connects of `MemoryClient`s naming its address.
+///
+/// The address is taken from `init` until the server drops, connects made
+/// before `serve` wait for it like a listen backlog.
+pub struct MemoryServer {
+    opt: MemoryServerOption,
+    accept: tokio::sync::Mutex<mpsc::UnboundedReceiver<MemoryStream>>,
+    sender: mpsc::UnboundedSender<MemoryStream>,
+    stats: Arc<ServerStats>,
+    accept_hooks: Vec<AcceptHook>,
+    kill_grace: Duration,
+    observer: Option<Arc<dyn ServeObserver>>,
+}
+
+impl MemoryServer {
+    pub fn init(opt: MemoryServerOption) -> ServerResult<Self> {
+        let (sender, accept) = mpsc::unbounded_channel();
+        let mut broker = broker();
+        if broker.get(&opt.addr).is_some_and(|s| !s.is_closed()) {
+            return Err(Error::new(
+                ErrorKind::AddrInUse,
+                format!("memory address {:?} is taken", opt.addr),
+            )
+            .into());
+        }
+        broker.insert(opt.addr.clone(), sender.clone());
+        drop(broker);
+
+        Ok(Self {
+            opt,
+            accept: tokio::sync::Mutex::new(accept),
+            sender,
+            stats: Arc::new(ServerStats::new(MEMORY_PEER)),
+            accept_hooks: vec![],
+            kill_grace: DEFAULT_KILL_GRACE,
+            observer: None,
+        })
+    }
+
+    pub fn addr(&self) -> &str {
+        &self.opt.addr
+    }
+
+    /// Time callbacks get to return once `serve_with_shutdown` invalidated
+    /// their stream at the end of the grace period, 1s by default.
+    pub fn set_kill_grace(&mut self, kill_grace: Duration) {
+        self.kill_grace = kill_grace;
+    }
+
+    /// Report accepts, finished callbacks and shutdown exits to `observer`.
+    pub fn set_serve_observer(&mut self, observer: Arc<dyn ServeObserver>) {
+        self.observer = Some(observer);
+    }
+
+    pub fn add_accept_hook(&mut self, hook: AcceptHook) {
+        self.accept_hooks.push(hook);
+    }
+
+    pub fn stats(&self) -> ServerStatsSnapshot {
+        self.stats.snapshot()
+    }
+
+    async fn serve_tracked<C>(&self, callback: C, tasks: Option<&Arc<Tasks>>) -> ServerResult<()>
+    where
+        C: TransportServerCallback,
+    {
+        // a second serve waits for the first to return
+        let mut accept = self.accept.lock().await;
+        while let Some(stream) = accept.recv().await {
+            let mut ctx = ConnContext::new(None, None).with_transport("memory");
+            observe::accepted(self.observer.as_deref(), &ctx, true);
+            if !ctx.run_hooks(&self.accept_hooks) {
+                continue;
+            }
+
+            let id = ctx.id;
+            let guard = tasks.map(|t| t.register(id));
+            let stream = LimitedStream::new(self.stats.track(stream, &MEMORY_PEER), None);
+            ctx.extensions.insert(stream.count());
+            let callback = callback.clone();
+            let observer = self.observer.clone();
+            let handle = tokio::spawn(async move {
+                let stream = killable(stream, guard.as_ref());
+                observe::callback(observer.as_deref(), ctx, true, |ctx| {
+                    callback.handle_ctx(stream, ctx)
+                })
+                .await
+            });
+            if let Some(tasks) = tasks {
+                tasks.set_abort(id, handle.abort_handle());
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for MemoryServer {
+    fn drop(&mut self) {
+        let mut broker = broker();
+        // a later server may have taken the address after this one closed
+        if broker
+            .get(&self.opt.addr)
+            .is_some_and(|s| s.same_channel(&self.sender))
+        {
+            broker.remove(&self.opt.addr);
+        }
+    }
+}
+
+impl TransportServerTrait for MemoryServer {
+    fn local_addr(&self) -> Option<SocketAddr> {
+        None
+    }
+
+    async fn serve<C: TransportServerCallback>(&self, callback: C) -> ServerResult<()> {
+        self.serve_tracked(callback, None).await
+    }
+
+    /// The address stays registered, connects after the signal wait for
+    /// the next `serve`.
+    async fn serve_with_shutdown<C, F>(
+        &self,
+        callback: C,
+        signal: F,
+        grace: Duration,
+    ) -> ServerResult<()>
+    where
+        C: TransportServerCallback,
+        F: Future<Output = ()> + Send + Sync,
+    {
+        let tasks = Tasks::new();
+        tokio::select! {
+            res = self.serve_tracked(callback, Some(&tasks)) => return res,
+            _ = signal => {}
+        }
+        tasks
+            .shutdown(grace, self.kill_grace)
+            .await
+            .finish("memory", self.observer.as_deref());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use tokio::{
+        io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+        time::Instant,
+    };
+
+    use super::*;
+    use crate::{
+        ClientError, TransportClient, TransportClientOption, TransportServer, TransportServerOption,
+    };
+
+    #[derive(Clone)]
+    struct Echo;
+
+    impl TransportServerCallback for Echo {
+        async fn handle<S>(&self, mut stream: S, _addr: Option<SocketAddr>)
+        where
+            S: AsyncRead + AsyncWrite + Unpin + Send + Sync,
+        {
+            let mut buf = [0; 64];
+            while let Ok(n @ 1..) = stream.read(&mut buf).await {
+                if stream.write_all(&buf[..n]).await.is_err() {
+                    break;
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_memory_echo() {
+        let srv = Arc::new(
+            MemoryServer::init(MemoryServerOption {
+                addr: "test_memory_echo".into(),
+            })
+            .unwrap(),
+        );
+        let cli = MemoryClient::new(MemoryClientOption {
+            buffer_size: 16,
+            ..MemoryClientOption::new("test_memory_echo")
+        });
+
+        // queued until the server serves
+        let early = cli.connect().await.unwrap();
+        let serving = tokio::spawn({
+            let srv = srv.clone();
+            async move { srv.serve(Echo).await }
+        });
+
+        let late = cli.connect().await.unwrap();
+        for s in [early, late] {
+            // more than both buffers, so it only passes while read and written at once
+            let msg = (0..100).collect::<Vec<u8>>();
+            let (mut r, mut w) = tokio::io::split(s);
+            let mut echoed = vec![0; msg.len()];
+            let (written, read) = tokio::join!(w.write_all(&msg), r.read_exact(&mut echoed));
+            written.unwrap();
+            read.unwrap();
+            assert_eq!(echoed, msg);
+        }
+        assert_eq!(srv.stats().ipv4.accepted, 2);
+        serving.abort();
+    }
+
+    #[tokio::test]
+    async fn test_memory_refused() {
+        let opt = MemoryServerOption {
+            addr: "test_memory_refused".into(),
+        };
+        let cli = MemoryClient::new(MemoryClientOption::new("test_memory_refused"));
+        let err = cli.connect().await.err().unwrap();
+        assert_eq!(err.code().as_str(), "connect.refused");
+
+        let srv = MemoryServer::init(opt.clone()).unwrap();
+        let err = MemoryServer::init(opt.clone()).err().unwrap();
+        assert!(err.to_string().contains("is taken"), "{}", err);
+        assert!(cli.connect().await.is_ok());
+
+        // the address is free again once the server drops
+        drop(srv);
+        assert!(matches!(cli.connect().await, Err(ClientError::Io(_))));
+        assert!(MemoryServer::init(opt).is_ok());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_memory_enums() {
+        let srv: TransportServerOption = serde_json::from_value(json!({
+            "opt": {"memory": {"addr": "test_memory_enums"}},
+        }))
+        .unwrap();
+        let srv = TransportServer::init(srv).unwrap();
+        let cli: TransportClientOption = serde_json::from_value(json!({
+            "opt": {"memory": {"addr": "test_memory_enums", "latency": {"secs": 0, "nanos": 50_000_000}}},
+        }))
+        .unwrap();
+        let cli = TransportClient::init_with_default_resolver(cli).unwrap();
+        let latency = Duration::from_millis(50);
+        assert_eq!(cli.name(), "Memory");
+
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        let serving = tokio::spawn(async move {
+            srv.serve_with_shutdown(
+                Echo,
+                async {
+                    let _ = rx.await;
+                },
+                Duration::from_millis(100),
+            )
+            .await
+        });
+
+        let start = Instant::now();
+        let s = cli.connect().await.unwrap();
+        assert!(start.elapsed() >= latency);
+        assert_eq!(s.kind(), "Memory");
+        let (mut r, mut w) = tokio::io::split(s);
+        w.write_all(b"ping").await.unwrap();
+        let mut buf = [0u8; 4];
+        r.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+
+        tx.send(()).unwrap();
+        assert!(serving.await.unwrap().is_ok());
+    }
+}
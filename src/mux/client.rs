@@ -0,0 +1,85 @@
+//! Mux Client
+
+use tokio::sync::Mutex;
+
+use crate::{ClientResult, TransportClientTrait, TransportServerCallback};
+
+use super::{MuxOption, MuxSession, MuxStream, Role};
+
+/// Keeps one mux session over `C`, dialing again when it drops.
+pub struct MuxClient<C> {
+    client: C,
+    opt: MuxOption,
+    session: Mutex<Option<MuxSession>>,
+}
+
+impl<C> MuxClient<C>
+where
+    C: TransportClientTrait,
+    C::Stream: 'static,
+{
+    pub fn new(client: C, opt: MuxOption) -> Self {
+        Self {
+            client,
+            opt,
+            session: Mutex::new(None),
+        }
+    }
+
+    /// The live session, dialing a new one if there is none.
+    pub async fn session(&self) -> ClientResult<MuxSession> {
+        let mut session = self.session.lock().await;
+        if let Some(ref s) = *session {
+            if !s.is_closed() {
+                return Ok(s.clone());
+            }
+        }
+
+        let stream = self.client.connect().await?;
+        let (s, driver) = MuxSession::new(stream, Role::Client, &self.opt);
+        tokio::spawn(async move {
+            if let Err(e) = driver.await {
+                log::debug!("mux session closed ({})", e);
+            }
+        });
+        *session = Some(s.clone());
+        Ok(s)
+    }
+
+    pub async fn open_stream(&self) -> ClientResult<MuxStream> {
+        Ok(self.session().await?.open_stream()?)
+    }
+
+    /// Open `n` streams at once, see `MuxSession::open_group`.
+    pub async fn open_group(&self, n: usize, labels: &[&str]) -> ClientResult<Vec<MuxStream>> {
+        Ok(self.session().await?.open_group(n, labels).await?)
+    }
+
+    /// Serve streams opened by the server with `callback`, like a server
+    /// would serve accepted connections. Dials again `redial_delay` after
+    /// the connection dropped or a dial failed, runs until cancelled.
+    pub async fn listen<CB: TransportServerCallback>(&self, callback: CB) {
+        loop {
+            match self.session().await {
+                Ok(session) => {
+                    session.serve(callback.clone()).await;
+                    log::debug!("mux session lost, dialing again");
+                }
+                Err(e) => log::debug!("mux dial failed ({})", e),
+            }
+            tokio::time::sleep(self.opt.redial_delay).await;
+        }
+    }
+}
+
+impl<C> TransportClientTrait for MuxClient<C>
+where
+    C: TransportClientTrait,
+    C::Stream: 'static,
+{
+    type Stream = MuxStream;
+
+    async fn connect(&self) -> ClientResult<Self::Stream> {
+        self.open_stream().await
+    }
+}
@@ -0,0 +1,429 @@
+//! Stream Multiplexing
+//!
+//! Many logical streams over one transport connection, opened by either
+//! end. A client behind NAT can serve streams the server opens, see
+//! `MuxClient::listen`.
+
+pub mod option;
+pub use option::MuxOption;
+
+pub mod session;
+pub use session::{MuxGroup, MuxSession, MuxStream, Role};
+
+pub mod client;
+pub use client::MuxClient;
+
+pub mod server;
+pub use server::MuxAcceptor;
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use crate::{
+        clock::ManualClock,
+        tcp::{
+            TcpClient, TcpClientOption, TcpServer, TcpServerOption, TlsMode, DEFAULT_ACCEPT_BATCH,
+            DEFAULT_HANDSHAKE_TIMEOUT,
+        },
+        Resolver, TransportServerCallback, TransportServerTrait,
+    };
+
+    use super::*;
+
+    #[derive(Debug, Clone)]
+    struct EchoCallback;
+
+    impl TransportServerCallback for EchoCallback {
+        async fn handle<S>(&self, stream: S, _addr: Option<std::net::SocketAddr>)
+        where
+            S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + Sync,
+        {
+            let (mut rd, mut wr) = tokio::io::split(stream);
+            let _ = tokio::io::copy(&mut rd, &mut wr).await;
+            let _ = wr.shutdown().await;
+        }
+    }
+
+    async fn echo(mut stream: MuxStream, data: &[u8]) -> Vec<u8> {
+        let (mut rd, mut wr) = tokio::io::split(&mut stream);
+        let write = async {
+            wr.write_all(data).await.unwrap();
+            wr.shutdown().await.unwrap();
+        };
+        let mut buf = vec![];
+        let read = rd.read_to_end(&mut buf);
+        let (_, n) = tokio::join!(write, read);
+        n.unwrap();
+        buf
+    }
+
+    #[tokio::test]
+    async fn test_mux_streams() {
+        let (a, b) = tokio::io::duplex(4096);
+        let opt = MuxOption::default();
+        let (client, driver) = MuxSession::new(a, Role::Client, &opt);
+        tokio::spawn(driver);
+        let (server, driver) = MuxSession::new(b, Role::Server, &opt);
+        tokio::spawn(driver);
+        tokio::spawn({
+            let client = client.clone();
+            async move { client.serve(EchoCallback).await }
+        });
+        tokio::spawn({
+            let server = server.clone();
+            async move { server.serve(EchoCallback).await }
+        });
+
+        // more than a window each way, in both directions at once
+        let data: Vec<u8> = (0..1024 * 1024).map(|i| (i % 251) as u8).collect();
+        let mut tasks = vec![];
+        for session in [
+            client.clone(),
+            server.clone(),
+            client.clone(),
+            server.clone(),
+        ] {
+            let stream = session.open_stream().unwrap();
+            let data = data.clone();
+            tasks.push(tokio::spawn(async move { echo(stream, &data).await }));
+        }
+        for task in tasks {
+            assert_eq!(task.await.unwrap(), data);
+        }
+
+        server.close();
+        client.closed().await;
+        let Err(e) = client.open_stream() else {
+            panic!("session should be closed");
+        };
+        assert_eq!(e.kind(), std::io::ErrorKind::ConnectionAborted);
+    }
+
+    #[tokio::test]
+    async fn test_mux_max_streams() {
+        let (a, b) = tokio::io::duplex(4096);
+        let (client, driver) = MuxSession::new(a, Role::Client, &MuxOption::default());
+        tokio::spawn(driver);
+        let opt = MuxOption {
+            max_streams: 2,
+            ..Default::default()
+        };
+        let (server, driver) = MuxSession::new(b, Role::Server, &opt);
+        tokio::spawn(driver);
+
+        // two wait to be accepted, the third is over the cap
+        let mut streams = (0..3)
+            .map(|_| client.open_stream().unwrap())
+            .collect::<Vec<_>>();
+        let mut buf = [0u8; 1];
+        let err = streams[2].read(&mut buf).await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::ConnectionReset);
+
+        // a stream the server is done with frees its slot
+        drop(server.accept().await.unwrap());
+        let mut stream = client.open_stream().unwrap();
+        stream.write_all(b"x").await.unwrap();
+        let _second = server.accept().await.unwrap();
+        let mut accepted = server.accept().await.unwrap();
+        assert_eq!(accepted.id(), stream.id());
+        accepted.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"x");
+
+        // a group counts against the cap as a whole
+        let err = client.open_group(2, &[]).await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::ConnectionRefused);
+        drop(accepted);
+        let streams = client.open_group(1, &[]).await.unwrap();
+        assert_eq!(streams.len(), 1);
+    }
+
+    /// Answers with the group id and label of the stream, then echoes.
+    #[derive(Debug, Clone)]
+    struct GroupCallback;
+
+    impl TransportServerCallback for GroupCallback {
+        async fn handle<S>(&self, _stream: S, _addr: Option<std::net::SocketAddr>)
+        where
+            S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + Sync,
+        {
+        }
+
+        async fn handle_ctx<S>(&self, stream: S, ctx: crate::context::ConnContext)
+        where
+            S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + Sync,
+        {
+            let (mut rd, mut wr) = tokio::io::split(stream);
+            if let Some(group) = ctx.extensions.get::<MuxGroup>() {
+                let hello = format!(
+                    "{}:{}/{}:{};",
+                    group.id, group.index, group.size, group.label
+                );
+                let _ = wr.write_all(hello.as_bytes()).await;
+            }
+            let _ = tokio::io::copy(&mut rd, &mut wr).await;
+            let _ = wr.shutdown().await;
+        }
+    }
+
+    fn session_pair(hooks: Vec<crate::context::AcceptHook>) -> (MuxSession, MuxSession) {
+        let (a, b) = tokio::io::duplex(4096);
+        let opt = MuxOption::default();
+        let (client, driver) = MuxSession::new(a, Role::Client, &opt);
+        tokio::spawn(driver);
+        let (server, driver) = MuxSession::with_accept_hooks(b, Role::Server, &opt, hooks);
+        tokio::spawn(driver);
+        tokio::spawn({
+            let server = server.clone();
+            async move { server.serve(GroupCallback).await }
+        });
+        (client, server)
+    }
+
+    async fn wait_drained(session: &MuxSession) {
+        for _ in 0..100 {
+            if session.stream_count() == 0 {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("{} streams left open", session.stream_count());
+    }
+
+    #[tokio::test]
+    async fn test_mux_group() {
+        let (client, server) = session_pair(vec![]);
+
+        let streams = client.open_group(3, &["ctrl", "up", "down"]).await.unwrap();
+        let first = streams[0].id();
+        assert_eq!(server.stream_count(), 3);
+        for (index, stream) in streams.into_iter().enumerate() {
+            let group = stream.group().unwrap().clone();
+            assert_eq!((group.id, group.index, group.size), (first, index, 3));
+            let hello = format!("{}:{}/3:{};", first, index, group.label);
+            assert_eq!(
+                echo(stream, b"data").await,
+                [hello.as_bytes(), b"data"].concat()
+            );
+        }
+
+        // unlabeled, and next to a single stream
+        let single = client.open_stream().unwrap();
+        let streams = client.open_group(2, &[]).await.unwrap();
+        assert!(single.group().is_none());
+        assert_eq!(streams[1].group().unwrap().label, "");
+        assert_eq!(echo(single, b"single").await, b"single");
+        drop(streams);
+
+        for (n, labels) in [(0, &[][..]), (2, &["a"][..])] {
+            let err = client.open_group(n, labels).await.unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+        }
+        wait_drained(&server).await;
+    }
+
+    #[tokio::test]
+    async fn test_mux_group_reject() {
+        let accepted = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let hook: crate::context::AcceptHook = {
+            let accepted = accepted.clone();
+            std::sync::Arc::new(move |ctx| {
+                let group = ctx.extensions.get::<MuxGroup>();
+                if group.is_some_and(|group| group.label == "deny") {
+                    return false;
+                }
+                accepted.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                true
+            })
+        };
+        let (client, server) = session_pair(vec![hook]);
+
+        // the first member passes the hook, the group still fails as a whole
+        let err = client
+            .open_group(3, &["ok", "deny", "ok"])
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::ConnectionRefused);
+        assert_eq!(server.stream_count(), 0);
+        assert_eq!(client.stream_count(), 0);
+
+        let streams = client.open_group(2, &["ok", "ok"]).await.unwrap();
+        assert_eq!(streams.len(), 2);
+        assert_eq!(server.stream_count(), 2);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_mux_group_cancel() {
+        // a slow decision keeps the group negotiating
+        let hook: crate::context::AcceptHook = std::sync::Arc::new(|_| {
+            std::thread::sleep(Duration::from_millis(100));
+            true
+        });
+        let (client, server) = session_pair(vec![hook]);
+
+        let res = tokio::time::timeout(Duration::from_millis(20), client.open_group(3, &[])).await;
+        assert!(res.is_err());
+        // the server accepted before it saw the resets, its members close
+        wait_drained(&server).await;
+        wait_drained(&client).await;
+
+        let streams = client.open_group(1, &["after"]).await.unwrap();
+        let hello = format!("{}:0/1:after;", streams[0].id());
+        let stream = streams.into_iter().next().unwrap();
+        assert_eq!(echo(stream, b"").await, hello.as_bytes());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_mux_keepalive_timeout() {
+        let (a, _b) = tokio::io::duplex(4096);
+        let opt = MuxOption {
+            keepalive_interval: Some(Duration::from_millis(20)),
+            keepalive_timeout: Duration::from_millis(60),
+            ..Default::default()
+        };
+        // the other end never answers a ping
+        let (session, driver) = MuxSession::new(a, Role::Client, &opt);
+        let err = tokio::time::timeout(Duration::from_secs(1), driver)
+            .await
+            .unwrap()
+            .unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+        assert!(session.is_closed());
+    }
+
+    #[tokio::test]
+    async fn test_mux_keepalive_manual_clock() {
+        let (a, _b) = tokio::io::duplex(4096);
+        let opt = MuxOption {
+            keepalive_interval: Some(Duration::from_millis(20)),
+            keepalive_timeout: Duration::from_millis(60),
+            ..Default::default()
+        };
+        let clock = ManualClock::new();
+        let (session, driver) =
+            MuxSession::with_clock(a, Role::Client, &opt, vec![], clock.clone());
+        let driver = tokio::spawn(driver);
+
+        for _ in 0..3 {
+            tokio::task::yield_now().await;
+            clock.advance(Duration::from_millis(20));
+            tokio::task::yield_now().await;
+        }
+        assert!(!driver.is_finished());
+        assert!(!session.is_closed());
+
+        clock.advance(Duration::from_millis(20));
+        let err = tokio::time::timeout(Duration::from_secs(1), driver)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+        assert!(session.is_closed());
+    }
+
+    #[tokio::test]
+    async fn test_mux_answers_bounded() {
+        const PINGS: usize = 10_000;
+        const HEADER: usize = 9;
+
+        let (a, mut peer) = tokio::io::duplex(1024);
+        let (session, driver) = MuxSession::new(a, Role::Server, &MuxOption::default());
+        tokio::spawn(driver);
+
+        // pings from a peer that reads none of the pongs, then an open
+        let mut frames = [5u8, 0, 0, 0, 0, 0, 0, 0, 0].repeat(PINGS);
+        frames.extend_from_slice(&[0, 0, 0, 0, 1, 0, 0, 0, 0]);
+        peer.write_all(&frames).await.unwrap();
+        // the open is read after every ping was answered or dropped
+        let stream = session.accept().await.unwrap();
+        assert_eq!(stream.id(), 1);
+
+        let mut answered = vec![];
+        let mut buf = [0u8; 4096];
+        while let Ok(n) =
+            tokio::time::timeout(Duration::from_millis(100), peer.read(&mut buf)).await
+        {
+            answered.extend_from_slice(&buf[..n.unwrap()]);
+        }
+        let pongs = answered.len() / HEADER;
+        assert!(answered.chunks(HEADER).all(|frame| frame[0] == 6));
+        // what the pipe held, the queue and the frame being written
+        let bound = 1024 / HEADER + session::MAX_ANSWERS + 1;
+        assert!(pongs <= bound, "{} pongs", pongs);
+        assert!(!session.is_closed());
+    }
+
+    #[tokio::test]
+    async fn test_mux_reverse_echo() {
+        let opt = MuxOption {
+            redial_delay: Duration::from_millis(50),
+            ..Default::default()
+        };
+
+        // central side, it never connects out
+        let srv = TcpServer::init(
+            TcpServerOption {
+                listen: "127.0.0.1:9868".parse().unwrap(),
+                tcp_nodelay: true,
+                tls_mode: TlsMode::Required,
+                sniff: None,
+                max_bytes: None,
+                rate_limit: None,
+                trace_sampling: None,
+                idle_reap: None,
+                idle_timeout: None,
+                accept_batch: DEFAULT_ACCEPT_BATCH,
+                handshake_timeout: DEFAULT_HANDSHAKE_TIMEOUT,
+                max_connections: None,
+            },
+            None,
+        )
+        .unwrap();
+        let (acceptor, mut sessions) = MuxAcceptor::new(opt.clone());
+        tokio::spawn(async move { srv.serve(acceptor).await });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        // device side, it only dials out and has no listener of its own
+        let cli = TcpClient::init(
+            TcpClientOption {
+                addr: "127.0.0.1".into(),
+                port: 9868,
+                tcp_nodelay: true,
+                connect_timeout: None,
+                connect_deadline: None,
+                attempt_delay: None,
+                resolve_on_connect: false,
+                proxy: None,
+                bind_addr: None,
+                bind_device: None,
+            },
+            None,
+            &Resolver::default(),
+        )
+        .unwrap();
+        let device = std::sync::Arc::new(MuxClient::new(cli, opt));
+        tokio::spawn({
+            let device = device.clone();
+            async move { device.listen(EchoCallback).await }
+        });
+
+        let session = sessions.recv().await.unwrap();
+        let stream = session.open_stream().unwrap();
+        assert_eq!(
+            echo(stream, b"central to device").await,
+            b"central to device"
+        );
+
+        // losing the connection makes the device dial again
+        session.close();
+        let session = tokio::time::timeout(Duration::from_secs(2), sessions.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        let stream = session.open_stream().unwrap();
+        assert_eq!(echo(stream, b"again").await, b"again");
+    }
+}
@@ -0,0 +1,49 @@
+//! Mux Option
+
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MuxOption {
+    /// Ping the peer this often, `None` disables keepalive.
+    #[serde(default = "default_keepalive_interval")]
+    pub keepalive_interval: Option<Duration>,
+    /// Close the session once nothing arrived from the peer for this long.
+    #[serde(default = "default_keepalive_timeout")]
+    pub keepalive_timeout: Duration,
+    /// Wait before dialing again after the physical connection dropped.
+    #[serde(default = "default_redial_delay")]
+    pub redial_delay: Duration,
+    /// Streams the peer may have open at once, an open past it is reset.
+    /// Also bounds the streams waiting in `accept`.
+    #[serde(default = "default_max_streams")]
+    pub max_streams: usize,
+}
+
+fn default_keepalive_interval() -> Option<Duration> {
+    Some(Duration::from_secs(15))
+}
+
+fn default_keepalive_timeout() -> Duration {
+    Duration::from_secs(45)
+}
+
+fn default_redial_delay() -> Duration {
+    Duration::from_secs(1)
+}
+
+fn default_max_streams() -> usize {
+    256
+}
+
+impl Default for MuxOption {
+    fn default() -> Self {
+        Self {
+            keepalive_interval: default_keepalive_interval(),
+            keepalive_timeout: default_keepalive_timeout(),
+            redial_delay: default_redial_delay(),
+            max_streams: default_max_streams(),
+        }
+    }
+}
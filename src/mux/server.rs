@@ -0,0 +1,57 @@
+//! Mux Server
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    sync::mpsc,
+};
+
+use crate::{context::AcceptHook, TransportServerCallback};
+
+use super::{MuxOption, MuxSession, Role};
+
+/// Server callback running a mux session on every accepted connection.
+///
+/// Sessions are handed out through the receiver returned by `new`, where
+/// `open_stream` reaches the connected client.
+#[derive(Clone)]
+pub struct MuxAcceptor {
+    opt: MuxOption,
+    sessions: mpsc::UnboundedSender<MuxSession>,
+    hooks: Vec<AcceptHook>,
+}
+
+impl MuxAcceptor {
+    pub fn new(opt: MuxOption) -> (Self, mpsc::UnboundedReceiver<MuxSession>) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        (
+            Self {
+                opt,
+                sessions: tx,
+                hooks: vec![],
+            },
+            rx,
+        )
+    }
+
+    /// Run `hook` on every stream the clients open, see
+    /// `MuxSession::with_accept_hooks`.
+    pub fn add_accept_hook(&mut self, hook: AcceptHook) {
+        self.hooks.push(hook);
+    }
+}
+
+impl TransportServerCallback for MuxAcceptor {
+    async fn handle<S>(&self, stream: S, addr: Option<std::net::SocketAddr>)
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + Sync,
+    {
+        let (session, driver) =
+            MuxSession::with_accept_hooks(stream, Role::Server, &self.opt, self.hooks.clone());
+        if self.sessions.send(session).is_err() {
+            return;
+        }
+        if let Err(e) = driver.await {
+            log::debug!("mux session from {:?} closed ({})", addr, e);
+        }
+    }
+}
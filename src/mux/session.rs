@@ -0,0 +1,888 @@
+//! Mux Session
+
+use std::{
+    collections::{HashMap, VecDeque},
+    future::Future,
+    io::{Error, ErrorKind},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll, Waker},
+};
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf},
+    sync::{mpsc, oneshot, Notify},
+    time::Instant,
+};
+
+use crate::{
+    clock::{Clock, TokioClock},
+    context::{AcceptHook, ConnContext},
+    TransportServerCallback,
+};
+
+use super::MuxOption;
+
+/// Credit each side of a stream starts with, and the most it buffers.
+pub const WINDOW: u32 = 256 * 1024;
+/// Largest data frame payload.
+pub const MAX_FRAME: usize = 16 * 1024;
+
+const HEADER_LEN: usize = 9;
+
+/// Pongs and resets queued in answer to the peer, past it more are dropped
+/// until the writer catches up.
+pub const MAX_ANSWERS: usize = 256;
+
+/// Which end of the physical connection a session is, picks the stream id parity.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    /// Opens odd stream ids.
+    Client,
+    /// Opens even stream ids.
+    Server,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+enum Kind {
+    Open = 0,
+    Data = 1,
+    Fin = 2,
+    Reset = 3,
+    Window = 4,
+    Ping = 5,
+    Pong = 6,
+    GroupOpen = 7,
+    GroupAck = 8,
+    GroupReject = 9,
+}
+
+impl Kind {
+    fn from_u8(v: u8) -> Option<Self> {
+        Some(match v {
+            0 => Self::Open,
+            1 => Self::Data,
+            2 => Self::Fin,
+            3 => Self::Reset,
+            4 => Self::Window,
+            5 => Self::Ping,
+            6 => Self::Pong,
+            7 => Self::GroupOpen,
+            8 => Self::GroupAck,
+            9 => Self::GroupReject,
+            _ => return None,
+        })
+    }
+}
+
+struct Frame {
+    kind: Kind,
+    id: u32,
+    payload: Bytes,
+}
+
+impl Frame {
+    fn new(kind: Kind, id: u32) -> Self {
+        Self {
+            kind,
+            id,
+            payload: Bytes::new(),
+        }
+    }
+}
+
+#[derive(Default)]
+struct StreamState {
+    recv: VecDeque<Bytes>,
+    recv_len: usize,
+    /// Bytes read since the last window update.
+    unacked: u32,
+    recv_fin: bool,
+    reset: bool,
+    send_window: u32,
+    read_waker: Option<Waker>,
+    write_waker: Option<Waker>,
+}
+
+impl StreamState {
+    fn wake(&mut self) {
+        if let Some(waker) = self.read_waker.take() {
+            waker.wake();
+        }
+        if let Some(waker) = self.write_waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+type StreamRef = Arc<Mutex<StreamState>>;
+
+/// Membership of a stream opened through `MuxSession::open_group`, the
+/// same on both ends. Inserted into the `ConnContext` of served members.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MuxGroup {
+    /// Stream id of the first member.
+    pub id: u32,
+    /// Empty when the group was opened without labels.
+    pub label: String,
+    /// Position of the stream in the group.
+    pub index: usize,
+    pub size: usize,
+}
+
+/// A group opened towards the peer, waiting for its answer.
+struct PendingGroup {
+    labels: Vec<String>,
+    /// `None` when the peer rejected the group.
+    tx: oneshot::Sender<Option<Vec<MuxStream>>>,
+}
+
+struct Shared {
+    role: Role,
+    next_id: AtomicU32,
+    streams: Mutex<HashMap<u32, StreamRef>>,
+    /// Streams opened by the peer and not dropped yet.
+    peer_streams: AtomicUsize,
+    max_streams: usize,
+    out: mpsc::UnboundedSender<Frame>,
+    /// Frames the peer asks for, bounded since it controls how many.
+    answers: mpsc::Sender<Frame>,
+    accept: Mutex<Option<mpsc::Sender<(MuxStream, ConnContext)>>>,
+    hooks: Vec<AcceptHook>,
+    groups: Mutex<HashMap<u32, PendingGroup>>,
+    closed: AtomicBool,
+    close: Notify,
+    last_seen: Mutex<Instant>,
+}
+
+impl Shared {
+    fn send(&self, frame: Frame) {
+        let _ = self.out.send(frame);
+    }
+
+    /// Queue a frame answering the peer, dropped while `MAX_ANSWERS` wait.
+    ///
+    /// Only for frames no other frame has to follow, a dropped Pong or
+    /// Reset is answered again on the peer's next Ping or frame.
+    fn answer(&self, frame: Frame) {
+        if let Err(mpsc::error::TrySendError::Full(frame)) = self.answers.try_send(frame) {
+            log::debug!(
+                "mux writer backed up, {:?} for {} dropped",
+                frame.kind,
+                frame.id
+            );
+        }
+    }
+
+    fn streams(&self) -> std::sync::MutexGuard<'_, HashMap<u32, StreamRef>> {
+        self.streams.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn stream(&self, id: u32) -> Option<StreamRef> {
+        self.streams().get(&id).cloned()
+    }
+
+    fn groups(&self) -> std::sync::MutexGuard<'_, HashMap<u32, PendingGroup>> {
+        self.groups.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Whether `id` is on the peer's side of the id space.
+    fn is_peer_id(&self, id: u32) -> bool {
+        let peer_parity = match self.role {
+            Role::Client => 0,
+            Role::Server => 1,
+        };
+        id % 2 == peer_parity
+    }
+
+    fn register(self: &Arc<Self>, id: u32, group: Option<MuxGroup>) -> MuxStream {
+        let state = Arc::new(Mutex::new(StreamState {
+            send_window: WINDOW,
+            ..Default::default()
+        }));
+        self.streams().insert(id, state.clone());
+        MuxStream {
+            id,
+            group,
+            state,
+            shared: self.clone(),
+            fin_sent: false,
+        }
+    }
+
+    /// Whether `id` is free for the peer to open.
+    fn peer_id(&self, id: u32) -> bool {
+        self.is_peer_id(id) && !self.streams().contains_key(&id)
+    }
+
+    /// Hand streams the peer opened to `accept`, all of them or none if
+    /// any is over `max_streams`, rejected by the accept hooks or finds
+    /// the accept queue full.
+    fn deliver(self: &Arc<Self>, members: Vec<(u32, Option<MuxGroup>)>) -> bool {
+        if self.peer_streams.load(Ordering::Acquire) + members.len() > self.max_streams {
+            return false;
+        }
+        let mut accepted = Vec::with_capacity(members.len());
+        for (id, group) in members {
+            let mut ctx = ConnContext::new(None, None).with_transport("mux");
+            if let Some(ref group) = group {
+                ctx.extensions.insert(group.clone());
+            }
+            if !ctx.run_hooks(&self.hooks) {
+                return false;
+            }
+            accepted.push((id, group, ctx));
+        }
+        let accept = self.accept.lock().unwrap_or_else(|e| e.into_inner());
+        // nobody accepts fast enough, refuse instead of queueing
+        let Some(permits) = accept
+            .as_ref()
+            .and_then(|tx| tx.try_reserve_many(accepted.len()).ok())
+        else {
+            return false;
+        };
+        for ((id, group, ctx), permit) in accepted.into_iter().zip(permits) {
+            self.peer_streams.fetch_add(1, Ordering::AcqRel);
+            permit.send((self.register(id, group), ctx));
+        }
+        true
+    }
+
+    fn shutdown(&self) {
+        if self.closed.swap(true, Ordering::AcqRel) {
+            return;
+        }
+        self.accept.lock().unwrap_or_else(|e| e.into_inner()).take();
+        // fails the groups still negotiating
+        self.groups().clear();
+        for state in self.streams().values() {
+            lock(state).wake();
+        }
+        self.close.notify_waiters();
+    }
+
+    fn on_frame(self: &Arc<Self>, frame: Frame, now: Instant) -> std::io::Result<()> {
+        *self.last_seen.lock().unwrap_or_else(|e| e.into_inner()) = now;
+        match frame.kind {
+            Kind::Open => {
+                if !self.peer_id(frame.id) {
+                    return Err(Error::new(ErrorKind::InvalidData, "bad mux stream id"));
+                }
+                if !self.deliver(vec![(frame.id, None)]) {
+                    log::debug!("mux stream {} refused, reset", frame.id);
+                    self.answer(Frame::new(Kind::Reset, frame.id));
+                }
+            }
+            Kind::GroupOpen => {
+                let labels = decode_labels(frame.payload)
+                    .ok_or_else(|| Error::new(ErrorKind::InvalidData, "bad mux group frame"))?;
+                let size = labels.len();
+                let mut members = Vec::with_capacity(size);
+                for (index, label) in labels.into_iter().enumerate() {
+                    let id = member_id(frame.id, index)
+                        .filter(|id| self.peer_id(*id))
+                        .ok_or_else(|| Error::new(ErrorKind::InvalidData, "bad mux stream id"))?;
+                    let group = MuxGroup {
+                        id: frame.id,
+                        label,
+                        index,
+                        size,
+                    };
+                    members.push((id, Some(group)));
+                }
+                // answered before any member can carry data
+                if self.deliver(members) {
+                    self.send(Frame::new(Kind::GroupAck, frame.id));
+                } else {
+                    self.send(Frame::new(Kind::GroupReject, frame.id));
+                }
+            }
+            Kind::GroupAck => {
+                // a group cancelled meanwhile was reset towards the peer already
+                let Some(pending) = self.groups().remove(&frame.id) else {
+                    return Ok(());
+                };
+                let size = pending.labels.len();
+                let streams = pending
+                    .labels
+                    .into_iter()
+                    .enumerate()
+                    .map(|(index, label)| {
+                        let group = MuxGroup {
+                            id: frame.id,
+                            label,
+                            index,
+                            size,
+                        };
+                        let id = member_id(frame.id, index).unwrap_or_default();
+                        self.register(id, Some(group))
+                    })
+                    .collect();
+                if let Err(Some(streams)) = pending.tx.send(Some(streams)) {
+                    streams.into_iter().for_each(MuxStream::reset);
+                }
+            }
+            Kind::GroupReject => {
+                if let Some(pending) = self.groups().remove(&frame.id) {
+                    let _ = pending.tx.send(None);
+                }
+            }
+            Kind::Data => match self.stream(frame.id) {
+                Some(state) => {
+                    let mut state = lock(&state);
+                    if state.recv_len + frame.payload.len() > WINDOW as usize {
+                        return Err(Error::new(ErrorKind::InvalidData, "mux window exceeded"));
+                    }
+                    state.recv_len += frame.payload.len();
+                    state.recv.push_back(frame.payload);
+                    state.wake();
+                }
+                // the local end is gone, stop the peer from writing
+                None => self.answer(Frame::new(Kind::Reset, frame.id)),
+            },
+            Kind::Fin => {
+                if let Some(state) = self.stream(frame.id) {
+                    let mut state = lock(&state);
+                    state.recv_fin = true;
+                    state.wake();
+                }
+            }
+            Kind::Reset => {
+                if let Some(state) = self.stream(frame.id) {
+                    let mut state = lock(&state);
+                    state.reset = true;
+                    state.wake();
+                }
+            }
+            Kind::Window => {
+                let mut payload = frame.payload;
+                if payload.len() != 4 {
+                    return Err(Error::new(ErrorKind::InvalidData, "bad mux window frame"));
+                }
+                if let Some(state) = self.stream(frame.id) {
+                    let mut state = lock(&state);
+                    state.send_window = state.send_window.saturating_add(payload.get_u32());
+                    state.wake();
+                }
+            }
+            Kind::Ping => self.answer(Frame {
+                kind: Kind::Pong,
+                id: 0,
+                payload: frame.payload,
+            }),
+            Kind::Pong => {}
+        }
+        Ok(())
+    }
+}
+
+/// Stream id of the `index`th member of the group opened at `first`.
+fn member_id(first: u32, index: usize) -> Option<u32> {
+    u32::try_from(index)
+        .ok()?
+        .checked_mul(2)?
+        .checked_add(first)
+}
+
+/// Group open payload, the member count then each label length prefixed.
+fn encode_labels(labels: &[String]) -> Bytes {
+    let mut buf = BytesMut::new();
+    buf.put_u16(labels.len() as u16);
+    for label in labels {
+        buf.put_u16(label.len() as u16);
+        buf.put_slice(label.as_bytes());
+    }
+    buf.freeze()
+}
+
+fn decode_labels(mut payload: Bytes) -> Option<Vec<String>> {
+    if payload.remaining() < 2 {
+        return None;
+    }
+    let count = payload.get_u16() as usize;
+    let mut labels = Vec::with_capacity(count);
+    for _ in 0..count {
+        if payload.remaining() < 2 {
+            return None;
+        }
+        let len = payload.get_u16() as usize;
+        if payload.remaining() < len {
+            return None;
+        }
+        labels.push(String::from_utf8(payload.split_to(len).to_vec()).ok()?);
+    }
+    (count > 0 && !payload.has_remaining()).then_some(labels)
+}
+
+fn lock(state: &StreamRef) -> std::sync::MutexGuard<'_, StreamState> {
+    state.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Logical streams over one physical connection, either end may open them.
+///
+/// Cloning gives another handle to the same session.
+#[derive(Clone)]
+pub struct MuxSession {
+    shared: Arc<Shared>,
+    accept: Arc<tokio::sync::Mutex<mpsc::Receiver<(MuxStream, ConnContext)>>>,
+}
+
+impl MuxSession {
+    /// Start a session over `io`. The returned future runs it and must be
+    /// polled, it ends when the connection fails, keepalive times out or
+    /// `close` is called.
+    pub fn new<S>(
+        io: S,
+        role: Role,
+        opt: &MuxOption,
+    ) -> (Self, impl Future<Output = std::io::Result<()>> + Send)
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send,
+    {
+        Self::with_accept_hooks(io, role, opt, vec![])
+    }
+
+    /// Like `new`, running `hooks` on the context of every stream the peer
+    /// opens. A rejected stream is reset, a rejected group member fails
+    /// the whole group.
+    pub fn with_accept_hooks<S>(
+        io: S,
+        role: Role,
+        opt: &MuxOption,
+        hooks: Vec<AcceptHook>,
+    ) -> (Self, impl Future<Output = std::io::Result<()>> + Send)
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send,
+    {
+        Self::with_clock(io, role, opt, hooks, TokioClock)
+    }
+
+    /// Like `with_accept_hooks`, keepalive pings sent and timed out on `clock`.
+    pub fn with_clock<S, C: Clock>(
+        io: S,
+        role: Role,
+        opt: &MuxOption,
+        hooks: Vec<AcceptHook>,
+        clock: C,
+    ) -> (Self, impl Future<Output = std::io::Result<()>> + Send)
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send,
+    {
+        let (out_tx, out_rx) = mpsc::unbounded_channel();
+        let (answers_tx, answers_rx) = mpsc::channel(MAX_ANSWERS);
+        let (accept_tx, accept_rx) = mpsc::channel(opt.max_streams.max(1));
+        let shared = Arc::new(Shared {
+            role,
+            next_id: AtomicU32::new(match role {
+                Role::Client => 1,
+                Role::Server => 2,
+            }),
+            streams: Mutex::new(HashMap::new()),
+            peer_streams: AtomicUsize::new(0),
+            max_streams: opt.max_streams,
+            out: out_tx,
+            answers: answers_tx,
+            accept: Mutex::new(Some(accept_tx)),
+            hooks,
+            groups: Mutex::new(HashMap::new()),
+            closed: AtomicBool::new(false),
+            close: Notify::new(),
+            last_seen: Mutex::new(clock.now()),
+        });
+
+        let session = Self {
+            shared: shared.clone(),
+            accept: Arc::new(tokio::sync::Mutex::new(accept_rx)),
+        };
+        let driver = drive(io, shared, out_rx, answers_rx, opt.clone(), clock);
+        (session, driver)
+    }
+
+    /// Open a stream towards the peer, usable right away.
+    pub fn open_stream(&self) -> std::io::Result<MuxStream> {
+        if self.is_closed() {
+            return Err(session_closed());
+        }
+        let id = self.shared.next_id.fetch_add(2, Ordering::Relaxed);
+        let stream = self.shared.register(id, None);
+        self.shared.send(Frame::new(Kind::Open, id));
+        Ok(stream)
+    }
+
+    /// Open `n` streams towards the peer in one exchange, either all of
+    /// them are accepted or none is. `labels` is empty or has a label per
+    /// stream, see `MuxStream::group`.
+    ///
+    /// Dropping the future before the answer resets the members the peer
+    /// may have accepted already.
+    pub async fn open_group(&self, n: usize, labels: &[&str]) -> std::io::Result<Vec<MuxStream>> {
+        if n == 0 || n > u16::MAX as usize || !(labels.is_empty() || labels.len() == n) {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "bad mux group of {} streams with {} labels",
+                    n,
+                    labels.len()
+                ),
+            ));
+        }
+        let labels: Vec<String> = match labels {
+            [] => vec![String::new(); n],
+            labels => labels.iter().map(|label| label.to_string()).collect(),
+        };
+        let payload = encode_labels(&labels);
+        if labels.iter().any(|label| label.len() > u16::MAX as usize) || payload.len() > MAX_FRAME {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "mux group labels too long",
+            ));
+        }
+        if self.is_closed() {
+            return Err(session_closed());
+        }
+
+        let id = self
+            .shared
+            .next_id
+            .fetch_add(2 * n as u32, Ordering::Relaxed);
+        let (tx, rx) = oneshot::channel();
+        self.shared.groups().insert(id, PendingGroup { labels, tx });
+        self.shared.send(Frame {
+            kind: Kind::GroupOpen,
+            id,
+            payload,
+        });
+
+        let mut negotiation = GroupNegotiation {
+            shared: &self.shared,
+            id,
+            n,
+            rx,
+            done: false,
+        };
+        let res = (&mut negotiation.rx).await;
+        negotiation.done = true;
+        match res {
+            Ok(Some(streams)) => Ok(streams),
+            Ok(None) => Err(Error::new(
+                ErrorKind::ConnectionRefused,
+                "mux group rejected",
+            )),
+            Err(_) => Err(session_closed()),
+        }
+    }
+
+    /// Next stream opened by the peer, `None` once the session closed.
+    pub async fn accept(&self) -> Option<MuxStream> {
+        self.accept
+            .lock()
+            .await
+            .recv()
+            .await
+            .map(|(stream, _)| stream)
+    }
+
+    /// Run `callback` on every stream the peer opens until the session closes.
+    pub async fn serve<C: TransportServerCallback>(&self, callback: C) {
+        loop {
+            let Some((stream, ctx)) = self.accept.lock().await.recv().await else {
+                break;
+            };
+            let callback = callback.clone();
+            tokio::spawn(async move { callback.handle_ctx(stream, ctx).await });
+        }
+    }
+
+    /// Streams open on this end of the session.
+    pub fn stream_count(&self) -> usize {
+        self.shared.streams().len()
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.shared.closed.load(Ordering::Acquire)
+    }
+
+    /// Stop the session, failing every open stream.
+    pub fn close(&self) {
+        self.shared.shutdown();
+    }
+
+    /// Wait until the session closed.
+    pub async fn closed(&self) {
+        let closed = self.shared.close.notified();
+        if !self.is_closed() {
+            closed.await;
+        }
+    }
+}
+
+/// Tears down a group whose `open_group` was dropped before the answer.
+struct GroupNegotiation<'a> {
+    shared: &'a Arc<Shared>,
+    id: u32,
+    n: usize,
+    rx: oneshot::Receiver<Option<Vec<MuxStream>>>,
+    done: bool,
+}
+
+impl Drop for GroupNegotiation<'_> {
+    fn drop(&mut self) {
+        if self.done {
+            return;
+        }
+        if self.shared.groups().remove(&self.id).is_some() {
+            // the peer may accept before it sees the resets, they close its members
+            for index in 0..self.n {
+                if let Some(id) = member_id(self.id, index) {
+                    self.shared.send(Frame::new(Kind::Reset, id));
+                }
+            }
+        } else if let Ok(Some(streams)) = self.rx.try_recv() {
+            streams.into_iter().for_each(MuxStream::reset);
+        }
+    }
+}
+
+fn session_closed() -> Error {
+    Error::new(ErrorKind::ConnectionAborted, "mux session closed")
+}
+
+async fn drive<S, C: Clock>(
+    io: S,
+    shared: Arc<Shared>,
+    mut out: mpsc::UnboundedReceiver<Frame>,
+    mut answers: mpsc::Receiver<Frame>,
+    opt: MuxOption,
+    clock: C,
+) -> std::io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    let (mut rd, mut wr) = tokio::io::split(io);
+
+    let read = async {
+        let mut header = [0u8; HEADER_LEN];
+        loop {
+            rd.read_exact(&mut header).await?;
+            let mut h = &header[..];
+            let kind = Kind::from_u8(h.get_u8())
+                .ok_or_else(|| Error::new(ErrorKind::InvalidData, "unknown mux frame"))?;
+            let id = h.get_u32();
+            let len = h.get_u32() as usize;
+            if len > MAX_FRAME {
+                return Err(Error::new(ErrorKind::InvalidData, "mux frame too large"));
+            }
+            let mut payload = BytesMut::zeroed(len);
+            rd.read_exact(&mut payload).await?;
+            shared.on_frame(
+                Frame {
+                    kind,
+                    id,
+                    payload: payload.freeze(),
+                },
+                clock.now(),
+            )?;
+        }
+    };
+
+    let write = async {
+        let mut buf = BytesMut::new();
+        loop {
+            let frame = tokio::select! {
+                Some(frame) = out.recv() => frame,
+                Some(frame) = answers.recv() => frame,
+                else => break,
+            };
+            buf.clear();
+            buf.put_u8(frame.kind as u8);
+            buf.put_u32(frame.id);
+            buf.put_u32(frame.payload.len() as u32);
+            buf.put_slice(&frame.payload);
+            wr.write_all(&buf).await?;
+            if out.is_empty() && answers.is_empty() {
+                wr.flush().await?;
+            }
+        }
+        Ok(())
+    };
+
+    let keepalive = async {
+        let Some(interval) = opt.keepalive_interval else {
+            return std::future::pending().await;
+        };
+        loop {
+            clock.sleep(interval).await;
+            let seen = *shared.last_seen.lock().unwrap_or_else(|e| e.into_inner());
+            if clock.now().saturating_duration_since(seen) > opt.keepalive_timeout {
+                return Err(Error::new(ErrorKind::TimedOut, "mux keepalive timed out"));
+            }
+            shared.send(Frame::new(Kind::Ping, 0));
+        }
+    };
+
+    let closed = shared.close.notified();
+    let res = if shared.closed.load(Ordering::Acquire) {
+        Ok(())
+    } else {
+        tokio::select! {
+            res = read => res,
+            res = write => res,
+            res = keepalive => res,
+            _ = closed => Ok(()),
+        }
+    };
+    shared.shutdown();
+    res
+}
+
+/// One logical stream of a `MuxSession`.
+///
+/// Dropping it half closes the write side like `shutdown`.
+pub struct MuxStream {
+    id: u32,
+    group: Option<MuxGroup>,
+    state: StreamRef,
+    shared: Arc<Shared>,
+    fin_sent: bool,
+}
+
+impl MuxStream {
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    /// The group the stream was opened in, if any.
+    pub fn group(&self) -> Option<&MuxGroup> {
+        self.group.as_ref()
+    }
+
+    /// Drop the stream failing the peer's reads and writes.
+    fn reset(mut self) {
+        self.fin_sent = true;
+        self.shared.send(Frame::new(Kind::Reset, self.id));
+    }
+}
+
+impl std::fmt::Debug for MuxStream {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MuxStream")
+            .field("id", &self.id)
+            .field("group", &self.group)
+            .finish()
+    }
+}
+
+impl AsyncRead for MuxStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        let mut state = lock(&this.state);
+        if state.reset {
+            return Poll::Ready(Err(ErrorKind::ConnectionReset.into()));
+        }
+
+        let mut read = 0;
+        while buf.remaining() > 0 {
+            let Some(chunk) = state.recv.front_mut() else {
+                break;
+            };
+            let n = chunk.len().min(buf.remaining());
+            buf.put_slice(&chunk[..n]);
+            chunk.advance(n);
+            if chunk.is_empty() {
+                state.recv.pop_front();
+            }
+            read += n;
+        }
+
+        if read > 0 {
+            state.recv_len -= read;
+            state.unacked += read as u32;
+            if state.unacked >= WINDOW / 2 {
+                let credit = std::mem::take(&mut state.unacked);
+                this.shared.send(Frame {
+                    kind: Kind::Window,
+                    id: this.id,
+                    payload: Bytes::copy_from_slice(&credit.to_be_bytes()),
+                });
+            }
+            return Poll::Ready(Ok(()));
+        }
+
+        if state.recv_fin {
+            return Poll::Ready(Ok(()));
+        }
+        if this.shared.closed.load(Ordering::Acquire) {
+            return Poll::Ready(Err(session_closed()));
+        }
+        state.read_waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+impl AsyncWrite for MuxStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        let mut state = lock(&this.state);
+        if state.reset || this.fin_sent {
+            return Poll::Ready(Err(ErrorKind::BrokenPipe.into()));
+        }
+        if this.shared.closed.load(Ordering::Acquire) {
+            return Poll::Ready(Err(session_closed()));
+        }
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        if state.send_window == 0 {
+            state.write_waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+
+        let n = buf.len().min(MAX_FRAME).min(state.send_window as usize);
+        state.send_window -= n as u32;
+        this.shared.send(Frame {
+            kind: Kind::Data,
+            id: this.id,
+            payload: Bytes::copy_from_slice(&buf[..n]),
+        });
+        Poll::Ready(Ok(n))
+    }
+
+    /// Frames are flushed by the session as soon as its queue runs empty.
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        if !this.fin_sent {
+            this.fin_sent = true;
+            this.shared.send(Frame::new(Kind::Fin, this.id));
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Drop for MuxStream {
+    fn drop(&mut self) {
+        if !self.fin_sent {
+            self.shared.send(Frame::new(Kind::Fin, self.id));
+        }
+        self.shared.streams().remove(&self.id);
+        if self.shared.is_peer_id(self.id) {
+            self.shared.peer_streams.fetch_sub(1, Ordering::AcqRel);
+        }
+    }
+}
@@ -0,0 +1,283 @@
+//! Connect Circuit Breaker
+//!
+//! Stops a client from dialing a destination that keeps failing. After
+//! `failure_threshold` connect failures in a row the breaker opens and
+//! connects fail at once with `ClientError::CircuitOpen` for
+//! `open_duration`. Then it is half open: one probe attempt goes through,
+//! its success closes the breaker and its failure opens it again.
+//!
+//! Unlike `retry`, which backs off within one connect, a breaker protects
+//! the destination from every caller sharing the client.
+
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{Arc, Mutex, MutexGuard},
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::time::Instant;
+
+use crate::clock::{Clock, TokioClock};
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub struct CircuitBreakerOption {
+    /// Connect failures in a row that open the breaker.
+    pub failure_threshold: u32,
+    /// How long an open breaker fails connects before letting a probe through.
+    pub open_duration: Duration,
+    #[serde(default)]
+    pub scope: BreakerScope,
+}
+
+/// What one breaker covers.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BreakerScope {
+    /// A breaker per resolved address, an open one is skipped while the
+    /// other addresses are still dialed.
+    #[default]
+    Addr,
+    /// One breaker for the whole connect, a failure is a dial where every
+    /// address failed.
+    Endpoint,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BreakerState {
+    Closed,
+    /// Failing connects until the open duration passed.
+    Open,
+    /// The open duration passed, the next attempt is the probe.
+    HalfOpen,
+}
+
+/// A breaker as seen by `CircuitBreaker::state`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BreakerSnapshot {
+    /// The address of an `Addr` scoped breaker, `None` for the endpoint.
+    pub addr: Option<SocketAddr>,
+    pub state: BreakerState,
+    /// Failures in a row so far.
+    pub failures: u32,
+    /// End of the open duration, set unless closed.
+    pub until: Option<Instant>,
+    /// Attempts started and not yet connected or failed, the probe included.
+    pub in_flight: usize,
+}
+
+#[derive(Debug, Default)]
+struct Entry {
+    failures: u32,
+    open_until: Option<Instant>,
+    probing: bool,
+    in_flight: usize,
+}
+
+/// The breakers of one client, shared by its clones and across `apply`.
+#[derive(Debug)]
+pub struct CircuitBreaker<C = TokioClock> {
+    opt: CircuitBreakerOption,
+    clock: C,
+    entries: Mutex<HashMap<Option<SocketAddr>, Entry>>,
+}
+
+impl CircuitBreaker {
+    pub fn new(opt: CircuitBreakerOption) -> Self {
+        Self::with_clock(opt, TokioClock)
+    }
+}
+
+impl<C: Clock> CircuitBreaker<C> {
+    /// Open durations timed on `clock`.
+    pub fn with_clock(opt: CircuitBreakerOption, clock: C) -> Self {
+        Self {
+            opt,
+            clock,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn option(&self) -> &CircuitBreakerOption {
+        &self.opt
+    }
+
+    /// Every destination dialed so far, addresses in order.
+    pub fn state(&self) -> Vec<BreakerSnapshot> {
+        let now = self.clock.now();
+        let mut list = self
+            .lock()
+            .iter()
+            .map(|(&addr, entry)| BreakerSnapshot {
+                addr,
+                state: match entry.open_until {
+                    None => BreakerState::Closed,
+                    Some(until) if now < until => BreakerState::Open,
+                    Some(_) => BreakerState::HalfOpen,
+                },
+                failures: entry.failures,
+                until: entry.open_until,
+                in_flight: entry.in_flight,
+            })
+            .collect::<Vec<_>>();
+        list.sort_by_key(|snapshot| snapshot.addr);
+        list
+    }
+
+    /// Let an attempt on `addr` through, the probe once half open, or
+    /// tell when the breaker stops failing connects.
+    pub(crate) fn admit(
+        self: &Arc<Self>,
+        addr: Option<SocketAddr>,
+    ) -> Result<BreakerPermit<C>, Instant> {
+        let now = self.clock.now();
+        let mut entries = self.lock();
+        let entry = entries.entry(addr).or_default();
+        let probe = match entry.open_until {
+            None => false,
+            Some(until) if now < until || entry.probing => return Err(until),
+            Some(_) => true,
+        };
+        entry.probing |= probe;
+        entry.in_flight += 1;
+        Ok(BreakerPermit {
+            breaker: self.clone(),
+            addr,
+            probe,
+            done: false,
+        })
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<Option<SocketAddr>, Entry>> {
+        self.entries.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// An attempt let through by a breaker, dropping it without an outcome
+/// counts neither way.
+pub(crate) struct BreakerPermit<C: Clock = TokioClock> {
+    breaker: Arc<CircuitBreaker<C>>,
+    addr: Option<SocketAddr>,
+    probe: bool,
+    done: bool,
+}
+
+impl<C: Clock> BreakerPermit<C> {
+    pub(crate) fn success(mut self) {
+        self.finish(|entry, _| {
+            entry.failures = 0;
+            entry.open_until = None;
+        });
+    }
+
+    pub(crate) fn failure(mut self) {
+        let (addr, probe) = (self.addr, self.probe);
+        self.finish(|entry, breaker| {
+            entry.failures = entry.failures.saturating_add(1);
+            // a failed probe opens again, late failures of attempts started
+            // before the breaker opened do not extend it
+            if probe
+                || (entry.open_until.is_none() && entry.failures >= breaker.opt.failure_threshold)
+            {
+                log::debug!(
+                    "circuit breaker of {:?} open after {} failures",
+                    addr,
+                    entry.failures
+                );
+                entry.open_until = Some(breaker.clock.now() + breaker.opt.open_duration);
+            }
+        });
+    }
+
+    fn finish(&mut self, f: impl FnOnce(&mut Entry, &CircuitBreaker<C>)) {
+        self.done = true;
+        let mut entries = self.breaker.lock();
+        let entry = entries.entry(self.addr).or_default();
+        entry.in_flight = entry.in_flight.saturating_sub(1);
+        if self.probe {
+            entry.probing = false;
+        }
+        f(entry, &self.breaker);
+    }
+}
+
+impl<C: Clock> Drop for BreakerPermit<C> {
+    fn drop(&mut self) {
+        if !self.done {
+            // another attempt may probe instead
+            self.finish(|_, _| {});
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+
+    fn breaker(clock: &ManualClock) -> Arc<CircuitBreaker<ManualClock>> {
+        let opt = CircuitBreakerOption {
+            failure_threshold: 2,
+            open_duration: Duration::from_secs(1),
+            scope: BreakerScope::Addr,
+        };
+        Arc::new(CircuitBreaker::with_clock(opt, clock.clone()))
+    }
+
+    fn state(breaker: &CircuitBreaker<ManualClock>) -> (BreakerState, u32) {
+        let snapshot = &breaker.state()[0];
+        (snapshot.state, snapshot.failures)
+    }
+
+    #[test]
+    fn test_breaker_states() {
+        let clock = ManualClock::new();
+        let breaker = breaker(&clock);
+        let addr = Some("127.0.0.1:1".parse().unwrap());
+
+        breaker.admit(addr).unwrap().failure();
+        assert_eq!(state(&breaker), (BreakerState::Closed, 1));
+        // a success in between starts the count again
+        breaker.admit(addr).unwrap().success();
+        breaker.admit(addr).unwrap().failure();
+        breaker.admit(addr).unwrap().failure();
+        assert_eq!(state(&breaker), (BreakerState::Open, 2));
+        let until = breaker.admit(addr).err().unwrap();
+        assert_eq!(until, clock.now() + Duration::from_secs(1));
+
+        // one probe at a time once half open, its failure opens again
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(state(&breaker), (BreakerState::HalfOpen, 2));
+        let probe = breaker.admit(addr).unwrap();
+        assert_eq!(breaker.state()[0].in_flight, 1);
+        assert!(breaker.admit(addr).is_err());
+        probe.failure();
+        assert_eq!(state(&breaker), (BreakerState::Open, 3));
+
+        // a probe dropped without an outcome lets the next one through
+        clock.advance(Duration::from_secs(1));
+        drop(breaker.admit(addr).unwrap());
+        assert_eq!(state(&breaker), (BreakerState::HalfOpen, 3));
+        breaker.admit(addr).unwrap().success();
+        assert_eq!(state(&breaker), (BreakerState::Closed, 0));
+        assert_eq!(breaker.state()[0].until, None);
+    }
+
+    #[test]
+    fn test_breaker_late_failure() {
+        let clock = ManualClock::new();
+        let breaker = breaker(&clock);
+
+        // attempts started before the breaker opened do not push it back
+        let late = breaker.admit(None).unwrap();
+        breaker.admit(None).unwrap().failure();
+        breaker.admit(None).unwrap().failure();
+        let until = breaker.state()[0].until;
+        clock.advance(Duration::from_millis(500));
+        late.failure();
+        assert_eq!(breaker.state()[0].until, until);
+        assert_eq!(breaker.state()[0].addr, None);
+    }
+}
@@ -0,0 +1,86 @@
+//! Busy Polling
+
+use std::io;
+
+use socket2::SockRef;
+
+/// Set `SO_BUSY_POLL` on `socket`, reads spin on the device queue for up to
+/// `usecs` before sleeping. Accepted sockets inherit it from their listener.
+///
+/// Linux only, raising it past `net.core.busy_read` needs `CAP_NET_ADMIN`.
+#[cfg(target_os = "linux")]
+pub fn set_busy_poll(socket: SockRef<'_>, usecs: u32) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    let value = usecs as libc::c_int;
+    // SAFETY: the fd is borrowed for the call and the value outlives it
+    let ret = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_BUSY_POLL,
+            &value as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if ret == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn set_busy_poll(_socket: SockRef<'_>, _usecs: u32) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "busy poll is linux only",
+    ))
+}
+
+/// `set_busy_poll` as a hint, a refusal is logged and the socket used as is.
+pub(crate) fn hint_busy_poll(socket: SockRef<'_>, usecs: u32, label: &dyn std::fmt::Display) {
+    if let Err(e) = set_busy_poll(socket, usecs) {
+        log::debug!("busy poll of {} not set ({})", label, e);
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use std::os::fd::AsRawFd;
+
+    use super::*;
+
+    fn busy_poll(socket: &std::net::TcpStream) -> u32 {
+        let mut value: libc::c_int = 0;
+        let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+        // SAFETY: value and len are valid for the call
+        let ret = unsafe {
+            libc::getsockopt(
+                socket.as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_BUSY_POLL,
+                &mut value as *mut libc::c_int as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        assert_eq!(ret, 0, "{}", io::Error::last_os_error());
+        value as u32
+    }
+
+    #[test]
+    fn test_busy_poll_inherited() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        if let Err(e) = set_busy_poll(SockRef::from(&listener), 50) {
+            // without CAP_NET_ADMIN only lowering it is allowed
+            assert_eq!(e.kind(), io::ErrorKind::PermissionDenied, "{}", e);
+            return;
+        }
+        let client = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (accepted, _) = listener.accept().unwrap();
+        assert_eq!(busy_poll(&accepted), 50);
+
+        set_busy_poll(SockRef::from(&client), 20).unwrap();
+        assert_eq!(busy_poll(&client), 20);
+    }
+}
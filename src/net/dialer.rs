@@ -0,0 +1,1085 @@
+//! Address Dialer
+
+use std::{
+    io::{Error, ErrorKind},
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+
+use futures_util::{stream::FuturesUnordered, StreamExt};
+use socket2::SockRef;
+use thiserror::Error;
+use tokio::{
+    net::{TcpSocket, TcpStream as TokioTcpStream},
+    time::Instant,
+};
+
+use super::{
+    breaker::{BreakerScope, CircuitBreaker},
+    busy_poll::hint_busy_poll,
+};
+use crate::{
+    clock::{Clock, TokioClock},
+    observe::{ConnectObserver, Stage},
+    ClientError, ClientResult, ResolveError, Resolver,
+};
+
+/// Filters and orders the addresses to dial, returning none aborts the connect.
+pub type ResolvedHook =
+    Arc<dyn Fn(&[SocketAddr]) -> Result<Vec<SocketAddr>, ClientError> + Send + Sync>;
+/// Called before each connect attempt.
+pub type AttemptHook = Arc<dyn Fn(SocketAddr) + Send + Sync>;
+/// Called with the attempts so far once an address accepted.
+pub type EstablishedHook = Arc<dyn Fn(SocketAddr, &DialReport) + Send + Sync>;
+
+/// Policy and telemetry hooks of the connect loop, set in code only.
+#[derive(Clone, Default)]
+pub struct DialHooks {
+    /// Also consulted for ip literals, which skip the resolver.
+    pub on_resolved: Option<ResolvedHook>,
+    pub on_attempt: Option<AttemptHook>,
+    pub on_established: Option<EstablishedHook>,
+    /// Told how long each stage of a connect took, the tls and upgrade
+    /// stages included.
+    pub observer: Option<Arc<dyn ConnectObserver>>,
+}
+
+impl std::fmt::Debug for DialHooks {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DialHooks")
+            .field("on_resolved", &self.on_resolved.is_some())
+            .field("on_attempt", &self.on_attempt.is_some())
+            .field("on_established", &self.on_established.is_some())
+            .field("observer", &self.observer.is_some())
+            .finish()
+    }
+}
+
+/// Addresses a client dials, fixed at init or looked up on every connect.
+#[derive(Debug, Clone)]
+pub enum DialAddrs {
+    Resolved(Vec<SocketAddr>),
+    /// Resolve `host` again on every connect, following dns changes.
+    OnConnect {
+        host: String,
+        port: u16,
+        resolver: Box<Resolver>,
+    },
+}
+
+impl DialAddrs {
+    /// Ip literals are used as they are, a host is resolved now, blocking,
+    /// unless `on_connect`.
+    pub fn new(host: &str, port: u16, on_connect: bool, resolver: &Resolver) -> ClientResult<Self> {
+        if let Ok(ip) = host.parse::<IpAddr>() {
+            return Ok(Self::Resolved(vec![(ip, port).into()]));
+        }
+        if on_connect {
+            return Ok(Self::OnConnect {
+                host: host.to_owned(),
+                port,
+                resolver: Box::new(resolver.clone()),
+            });
+        }
+        Ok(Self::Resolved(
+            resolver.block_resolve(host, port)?.collect(),
+        ))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        matches!(self, Self::Resolved(addrs) if addrs.is_empty())
+    }
+
+    /// The addresses to dial now.
+    pub async fn resolve(&self) -> ClientResult<Vec<SocketAddr>> {
+        match self {
+            Self::Resolved(addrs) => Ok(addrs.clone()),
+            Self::OnConnect {
+                host,
+                port,
+                resolver,
+            } => {
+                let addrs = resolver.resolve(host, *port).await?.collect::<Vec<_>>();
+                if addrs.is_empty() {
+                    return Err(ResolveError::EmptyResolved.into());
+                }
+                Ok(addrs)
+            }
+        }
+    }
+}
+
+/// Connection Attempt Delay recommended by RFC 8305.
+pub const DEFAULT_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// Tcp connect loop shared by the clients, trying addresses in order.
+#[derive(Debug, Clone, Default)]
+pub struct Dialer<C = TokioClock> {
+    clock: C,
+    tcp_nodelay: bool,
+    connect_timeout: Option<Duration>,
+    connect_deadline: Option<Duration>,
+    send_buffer_size: Option<u32>,
+    busy_poll: Option<u32>,
+    bind_addr: Option<SocketAddr>,
+    bind_device: Option<String>,
+    attempt_delay: Option<Duration>,
+    breaker: Option<Arc<CircuitBreaker<C>>>,
+    hooks: DialHooks,
+}
+
+/// Outcome of one connect attempt.
+#[derive(Debug, Clone)]
+pub struct DialAttempt {
+    pub addr: SocketAddr,
+    pub elapsed: Duration,
+    /// `None` for the attempt that won.
+    pub error: Option<Arc<Error>>,
+}
+
+/// Attempts of one `dial` in the order they ended, at most one per address
+/// and the winner last. Racing attempts dropped for the winner fail with
+/// `Interrupted`.
+#[derive(Debug, Clone, Default)]
+pub struct DialReport {
+    pub attempts: Vec<DialAttempt>,
+}
+
+impl DialReport {
+    fn failed(&mut self, addr: SocketAddr, elapsed: Duration, e: &Error) {
+        self.attempts.push(DialAttempt {
+            addr,
+            elapsed,
+            error: Some(Arc::new(copy_error(e))),
+        });
+    }
+
+    pub fn winner(&self) -> Option<SocketAddr> {
+        self.attempts
+            .last()
+            .filter(|attempt| attempt.error.is_none())
+            .map(|attempt| attempt.addr)
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum DialError {
+    #[error("no address to dial")]
+    NoAddress,
+    #[error("{} addresses tried, last ({last})", report.attempts.len())]
+    Failed { last: Error, report: DialReport },
+    /// No address would do better, the local side is at fault.
+    #[error("bind to {local} failed ({source})")]
+    Bind { local: String, source: Error },
+    /// Every address, or the endpoint, is behind an open circuit breaker.
+    #[error("circuit breaker open")]
+    CircuitOpen { until: Instant },
+}
+
+/// Error of an address skipped for its open breaker, kept by `copy_error`.
+#[derive(Debug, Clone, Copy, Error)]
+#[error("circuit breaker open")]
+struct CircuitOpen(Instant);
+
+impl CircuitOpen {
+    fn of(e: &Error) -> Option<Self> {
+        e.get_ref()?.downcast_ref::<Self>().copied()
+    }
+}
+
+impl Dialer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl<C: Clock> Dialer<C> {
+    /// Time connect timeouts and the deadline on `clock`.
+    pub fn with_clock(clock: C) -> Self {
+        Self {
+            clock,
+            tcp_nodelay: false,
+            connect_timeout: None,
+            connect_deadline: None,
+            send_buffer_size: None,
+            busy_poll: None,
+            bind_addr: None,
+            bind_device: None,
+            attempt_delay: None,
+            breaker: None,
+            hooks: DialHooks::default(),
+        }
+    }
+
+    pub fn set_tcp_nodelay(&mut self, enable: bool) {
+        self.tcp_nodelay = enable;
+    }
+
+    /// Give up on an address after `timeout` and move to the next one.
+    pub fn set_connect_timeout(&mut self, timeout: Option<Duration>) {
+        self.connect_timeout = timeout;
+    }
+
+    /// Bound on a whole `dial`, the addresses left once it passed are not tried.
+    pub fn set_connect_deadline(&mut self, deadline: Option<Duration>) {
+        self.connect_deadline = deadline;
+    }
+
+    /// Race the addresses, starting the next one when the last did not
+    /// connect within `delay`, `None` tries them one after another. RFC 8305
+    /// recommends `DEFAULT_ATTEMPT_DELAY`.
+    pub fn set_attempt_delay(&mut self, delay: Option<Duration>) {
+        self.attempt_delay = delay;
+    }
+
+    /// Socket send buffer of new connections, `None` keeps the os default.
+    pub fn set_send_buffer_size(&mut self, size: Option<u32>) {
+        self.send_buffer_size = size;
+    }
+
+    /// `SO_BUSY_POLL` of new connections in microseconds, a refusal is only logged.
+    pub fn set_busy_poll(&mut self, usecs: Option<u32>) {
+        self.busy_poll = usecs;
+    }
+
+    /// Bind new connections to `addr` and, on linux, to the interface `device`.
+    pub fn set_bind(
+        &mut self,
+        addr: Option<SocketAddr>,
+        device: Option<String>,
+    ) -> ClientResult<()> {
+        if let Some(ref device) = device {
+            if device.is_empty() {
+                return Err(ClientError::Option("bind_device is empty".to_owned()));
+            }
+            if !cfg!(any(
+                target_os = "android",
+                target_os = "fuchsia",
+                target_os = "linux"
+            )) {
+                return Err(ClientError::Option(format!(
+                    "bind_device {} is not supported on this platform",
+                    device
+                )));
+            }
+        }
+        self.bind_addr = addr;
+        self.bind_device = device;
+        Ok(())
+    }
+
+    /// Fail fast on destinations that keep failing, see `breaker`. The
+    /// breaker is shared, clones of the dialer count into the same one.
+    pub fn set_breaker(&mut self, breaker: Option<Arc<CircuitBreaker<C>>>) {
+        self.breaker = breaker;
+    }
+
+    pub fn breaker(&self) -> Option<&Arc<CircuitBreaker<C>>> {
+        self.breaker.as_ref()
+    }
+
+    pub fn set_hooks(&mut self, hooks: DialHooks) {
+        self.hooks = hooks;
+    }
+
+    pub fn hooks(&self) -> &DialHooks {
+        &self.hooks
+    }
+
+    /// The addresses of `addrs` now, a lookup on connect is told to the observer.
+    pub async fn resolve(&self, addrs: &DialAddrs) -> ClientResult<Vec<SocketAddr>> {
+        let DialAddrs::OnConnect { ref host, .. } = addrs else {
+            return addrs.resolve().await;
+        };
+        let stage = Stage::start(self.hooks.observer.as_deref());
+        let res = addrs.resolve().await;
+        stage.resolve(host, res.as_deref());
+        res
+    }
+
+    /// The addresses to dial out of `addrs`, as chosen by `on_resolved`.
+    pub fn candidates(&self, addrs: &[SocketAddr]) -> ClientResult<Vec<SocketAddr>> {
+        let Some(ref on_resolved) = self.hooks.on_resolved else {
+            return Ok(addrs.to_vec());
+        };
+        let candidates = on_resolved(addrs)?;
+        if candidates.is_empty() {
+            return Err(ClientError::Denied(format!(
+                "every address of {:?} was filtered out",
+                addrs
+            )));
+        }
+        Ok(candidates)
+    }
+
+    /// Connect to the first address that accepts, remembering every failure.
+    ///
+    /// With an attempt delay the addresses race: the next one starts once
+    /// the last did not connect within the delay or failed, the first
+    /// stream wins and the attempts still running are dropped.
+    ///
+    /// Addresses behind an open breaker are skipped and reported as failed
+    /// with no time spent, `CircuitOpen` once none was left to try.
+    pub async fn dial(
+        &self,
+        addrs: &[SocketAddr],
+    ) -> Result<(TokioTcpStream, DialReport), DialError> {
+        let permit = match self.scoped_breaker(BreakerScope::Endpoint) {
+            Some(breaker) => Some(
+                breaker
+                    .admit(None)
+                    .map_err(|until| DialError::CircuitOpen { until })?,
+            ),
+            None => None,
+        };
+        let deadline = self.connect_deadline.map(|d| self.clock.now() + d);
+        let res = match self.attempt_delay {
+            Some(delay) if addrs.len() > 1 => self.race(addrs, deadline, delay).await,
+            _ => self.sequential(addrs, deadline).await,
+        };
+        // a bind error is local, it says nothing of the endpoint
+        match (permit, &res) {
+            (Some(permit), Ok(_)) => permit.success(),
+            (Some(permit), Err(DialError::Failed { .. })) => permit.failure(),
+            _ => {}
+        }
+        res
+    }
+
+    /// One address after the other.
+    async fn sequential(
+        &self,
+        addrs: &[SocketAddr],
+        deadline: Option<Instant>,
+    ) -> Result<(TokioTcpStream, DialReport), DialError> {
+        let mut report = DialReport::default();
+        let mut last = None;
+        for &addr in addrs {
+            let timeout = match self.timeout(deadline) {
+                Ok(timeout) => timeout,
+                Err(e) => {
+                    last = Some(e);
+                    break;
+                }
+            };
+            let (res, elapsed) = self.attempt(addr, timeout).await?;
+            match res {
+                Ok(stream) => return Ok(self.established(stream, addr, elapsed, report)),
+                Err(e) => {
+                    report.failed(addr, elapsed, &e);
+                    last = Some(e);
+                }
+            }
+        }
+
+        Err(exhausted(last, report))
+    }
+
+    /// Happy Eyeballs (RFC 8305), an attempt every `delay` in address order.
+    async fn race(
+        &self,
+        addrs: &[SocketAddr],
+        deadline: Option<Instant>,
+        delay: Duration,
+    ) -> Result<(TokioTcpStream, DialReport), DialError> {
+        let mut report = DialReport::default();
+        let mut last = None;
+        let mut running = FuturesUnordered::new();
+        // id, address and start of the attempts in flight
+        let mut in_flight = vec![];
+        let mut next = 0;
+        let mut timer = Box::pin(self.clock.sleep(delay));
+        loop {
+            // the first address, then one each time the delay passed or
+            // an attempt failed
+            if next < addrs.len() {
+                let addr = addrs[next];
+                match self.timeout(deadline) {
+                    Ok(timeout) => {
+                        let start = self.clock.now();
+                        in_flight.push((next, addr, start));
+                        let id = next;
+                        running.push(async move { (id, self.attempt(addr, timeout).await) });
+                        self.clock.reset(timer.as_mut(), start + delay);
+                        next += 1;
+                    }
+                    Err(e) => {
+                        last = Some(e);
+                        next = addrs.len();
+                    }
+                }
+            }
+            if running.is_empty() {
+                break;
+            }
+
+            tokio::select! {
+                Some((id, res)) = running.next() => {
+                    in_flight.retain(|&(other, ..)| other != id);
+                    let addr = addrs[id];
+                    let (res, elapsed) = res?;
+                    match res {
+                        Ok(stream) => {
+                            // the losers are dropped with `running`
+                            for (_, loser, start) in in_flight {
+                                let e = Error::new(
+                                    ErrorKind::Interrupted,
+                                    format!("connect abandoned, {} connected first", addr),
+                                );
+                                report.failed(loser, self.clock.now() - start, &e);
+                            }
+                            return Ok(self.established(stream, addr, elapsed, report));
+                        }
+                        Err(e) => {
+                            report.failed(addr, elapsed, &e);
+                            last = Some(e);
+                        }
+                    }
+                }
+                _ = timer.as_mut(), if next < addrs.len() => {}
+            }
+        }
+
+        Err(exhausted(last, report))
+    }
+
+    /// Timeout of an attempt starting now, the smaller of its own and the
+    /// time left to the deadline, with whether the deadline set it.
+    fn timeout(&self, deadline: Option<Instant>) -> Result<Option<(Duration, bool)>, Error> {
+        let left = deadline.map(|d| d.saturating_duration_since(self.clock.now()));
+        if left == Some(Duration::ZERO) {
+            return Err(deadline_error());
+        }
+        Ok(match (self.connect_timeout, left) {
+            (Some(timeout), Some(left)) if left < timeout => Some((left, true)),
+            (Some(timeout), _) => Some((timeout, false)),
+            (None, left) => left.map(|left| (left, true)),
+        })
+    }
+
+    /// Connect to `addr` within `timeout`, `Err` when the local side can
+    /// not dial at all.
+    async fn attempt(
+        &self,
+        addr: SocketAddr,
+        timeout: Option<(Duration, bool)>,
+    ) -> Result<(std::io::Result<TokioTcpStream>, Duration), DialError> {
+        if let Some(bind) = self
+            .bind_addr
+            .filter(|bind| bind.is_ipv4() != addr.is_ipv4())
+        {
+            // another address of the right family may still do
+            log::debug!(
+                "skipping {}, not of the family of bind address {}",
+                addr,
+                bind
+            );
+            let e = Error::new(
+                ErrorKind::AddrNotAvailable,
+                format!("{} is not of the family of bind address {}", addr, bind),
+            );
+            return Ok((Err(e), Duration::ZERO));
+        }
+        let permit = match self.scoped_breaker(BreakerScope::Addr) {
+            Some(breaker) => match breaker.admit(Some(addr)) {
+                Ok(permit) => Some(permit),
+                Err(until) => {
+                    log::debug!("skipping {}, its circuit breaker is open", addr);
+                    let e = Error::new(ErrorKind::ConnectionRefused, CircuitOpen(until));
+                    return Ok((Err(e), Duration::ZERO));
+                }
+            },
+            None => None,
+        };
+        let socket = match self.socket(addr) {
+            Ok(socket) => socket,
+            Err(e) => return Ok((Err(e), Duration::ZERO)),
+        };
+        if let Some(ref socket) = socket {
+            if let Err(source) = self.bind(socket) {
+                return Err(DialError::Bind {
+                    local: self.bind_label(),
+                    source,
+                });
+            }
+        }
+
+        if let Some(ref on_attempt) = self.hooks.on_attempt {
+            on_attempt(addr);
+        }
+        let stage = Stage::start(self.hooks.observer.as_deref());
+        let start = self.clock.now();
+        let mut cut_short = false;
+        let res = match timeout {
+            Some((timeout, by_deadline)) => self
+                .clock
+                .timeout(timeout, connect(socket, addr))
+                .await
+                .unwrap_or_else(|| {
+                    cut_short = by_deadline;
+                    Err(if by_deadline {
+                        deadline_error()
+                    } else {
+                        Error::new(ErrorKind::TimedOut, "connect timed out")
+                    })
+                }),
+            None => connect(socket, addr).await,
+        };
+        stage.tcp_connect(addr, res.as_ref().map(|_| ()));
+        if let Err(ref e) = res {
+            log::debug!("connect to {} failed ({})", addr, e);
+        }
+        // an attempt cut short by the deadline of the whole dial counts neither way
+        match (permit, &res) {
+            (Some(permit), Ok(_)) => permit.success(),
+            (Some(permit), Err(_)) if !cut_short => permit.failure(),
+            _ => {}
+        }
+        Ok((res, self.clock.now() - start))
+    }
+
+    fn scoped_breaker(&self, scope: BreakerScope) -> Option<&Arc<CircuitBreaker<C>>> {
+        self.breaker
+            .as_ref()
+            .filter(|breaker| breaker.option().scope == scope)
+    }
+
+    /// Record the winning attempt and tell `on_established`.
+    fn established(
+        &self,
+        stream: TokioTcpStream,
+        addr: SocketAddr,
+        elapsed: Duration,
+        mut report: DialReport,
+    ) -> (TokioTcpStream, DialReport) {
+        if self.tcp_nodelay {
+            let _ = stream.set_nodelay(true);
+        }
+        report.attempts.push(DialAttempt {
+            addr,
+            elapsed,
+            error: None,
+        });
+        if let Some(ref on_established) = self.hooks.on_established {
+            on_established(addr, &report);
+        }
+        (stream, report)
+    }
+
+    /// A socket for `addr` set up as configured, `None` when a plain connect does.
+    fn socket(&self, addr: SocketAddr) -> std::io::Result<Option<TcpSocket>> {
+        if self.send_buffer_size.is_none()
+            && self.busy_poll.is_none()
+            && self.bind_addr.is_none()
+            && self.bind_device.is_none()
+        {
+            return Ok(None);
+        }
+
+        let socket = if addr.is_ipv4() {
+            TcpSocket::new_v4()?
+        } else {
+            TcpSocket::new_v6()?
+        };
+        if let Some(size) = self.send_buffer_size {
+            socket.set_send_buffer_size(size)?;
+        }
+        if let Some(usecs) = self.busy_poll {
+            hint_busy_poll(SockRef::from(&socket), usecs, &addr);
+        }
+        Ok(Some(socket))
+    }
+
+    fn bind(&self, socket: &TcpSocket) -> std::io::Result<()> {
+        #[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+        if let Some(ref device) = self.bind_device {
+            socket.bind_device(Some(device.as_bytes()))?;
+        }
+        if let Some(addr) = self.bind_addr {
+            socket.bind(addr)?;
+        }
+        Ok(())
+    }
+
+    fn bind_label(&self) -> String {
+        match (self.bind_addr, &self.bind_device) {
+            (Some(addr), Some(device)) => format!("{} on {}", addr, device),
+            (Some(addr), None) => addr.to_string(),
+            (None, Some(device)) => device.clone(),
+            (None, None) => "any address".to_owned(),
+        }
+    }
+}
+
+async fn connect(socket: Option<TcpSocket>, addr: SocketAddr) -> std::io::Result<TokioTcpStream> {
+    match socket {
+        Some(socket) => socket.connect(addr).await,
+        None => TokioTcpStream::connect(addr).await,
+    }
+}
+
+/// Why a dial without a winner failed, `CircuitOpen` when every address
+/// was skipped for its breaker.
+fn exhausted(last: Option<Error>, report: DialReport) -> DialError {
+    let Some(last) = last else {
+        return DialError::NoAddress;
+    };
+    let open = report
+        .attempts
+        .iter()
+        .map(|attempt| CircuitOpen::of(attempt.error.as_deref()?))
+        .collect::<Option<Vec<_>>>();
+    match open.and_then(|open| open.into_iter().map(|CircuitOpen(until)| until).min()) {
+        Some(until) => DialError::CircuitOpen { until },
+        None => DialError::Failed { last, report },
+    }
+}
+
+fn deadline_error() -> Error {
+    Error::new(ErrorKind::TimedOut, "connect deadline exceeded")
+}
+
+/// Connect errors are os errors, which copy exactly.
+fn copy_error(e: &Error) -> Error {
+    if let Some(open) = CircuitOpen::of(e) {
+        return Error::new(e.kind(), open);
+    }
+    match e.raw_os_error() {
+        Some(code) => Error::from_raw_os_error(code),
+        None => Error::new(e.kind(), e.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::test_util::Blackhole;
+
+    async fn listener() -> (TcpListener, SocketAddr) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        (listener, addr)
+    }
+
+    /// An address nothing listens on.
+    async fn closed_addr() -> SocketAddr {
+        listener().await.1
+    }
+
+    #[tokio::test]
+    async fn test_dial_order() {
+        let (_a, a) = listener().await;
+        let (_b, b) = listener().await;
+        let refused = closed_addr().await;
+
+        let mut dialer = Dialer::new();
+        dialer.set_tcp_nodelay(true);
+        let (stream, report) = dialer.dial(&[refused, b, a]).await.unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), b);
+        assert!(stream.nodelay().unwrap());
+        assert_eq!(report.winner(), Some(b));
+        assert_eq!(report.attempts.len(), 2);
+        assert_eq!(report.attempts[0].addr, refused);
+        assert_eq!(
+            report.attempts[0].error.as_ref().unwrap().kind(),
+            ErrorKind::ConnectionRefused
+        );
+    }
+
+    #[tokio::test]
+    async fn test_dial_errors() {
+        let dialer = Dialer::new();
+        assert!(matches!(dialer.dial(&[]).await, Err(DialError::NoAddress)));
+
+        let addrs = [closed_addr().await, closed_addr().await];
+        let Err(DialError::Failed { last, report }) = dialer.dial(&addrs).await else {
+            panic!("dial should fail");
+        };
+        assert_eq!(last.kind(), ErrorKind::ConnectionRefused);
+        assert_eq!(report.winner(), None);
+        assert_eq!(
+            report.attempts.iter().map(|a| a.addr).collect::<Vec<_>>(),
+            addrs
+        );
+    }
+
+    #[tokio::test]
+    async fn test_dial_timeout() {
+        let (_listener, addr) = listener().await;
+        let hole = Blackhole::new();
+
+        let mut dialer = Dialer::new();
+        dialer.set_connect_timeout(Some(Duration::from_millis(100)));
+        let (_, report) = dialer.dial(&[hole.addr, addr]).await.unwrap();
+        assert_eq!(report.winner(), Some(addr));
+
+        let first = &report.attempts[0];
+        assert_eq!(first.addr, hole.addr);
+        assert_eq!(first.error.as_ref().unwrap().kind(), ErrorKind::TimedOut);
+        assert!(first.elapsed >= Duration::from_millis(100));
+        assert!(first.elapsed < Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn test_dial_deadline() {
+        let (_listener, addr) = listener().await;
+        let hole = Blackhole::new();
+
+        // the 2nd attempt is cut short by the deadline, the 3rd never starts
+        let mut dialer = Dialer::new();
+        dialer.set_connect_timeout(Some(Duration::from_millis(100)));
+        dialer.set_connect_deadline(Some(Duration::from_millis(150)));
+        let start = Instant::now();
+        let Err(e) = dialer.dial(&[hole.addr, hole.addr, addr]).await else {
+            panic!("dial should miss its deadline");
+        };
+        assert!(start.elapsed() < Duration::from_secs(1));
+        let DialError::Failed {
+            ref last,
+            ref report,
+        } = e
+        else {
+            panic!("{}", e);
+        };
+        assert_eq!(last.to_string(), "connect deadline exceeded");
+        let errors = report
+            .attempts
+            .iter()
+            .map(|a| a.error.as_ref().unwrap().to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(errors, ["connect timed out", "connect deadline exceeded"]);
+        assert!(report.attempts[1].elapsed < Duration::from_millis(100));
+
+        let err = ClientError::from(e);
+        assert_eq!(err.code(), crate::ErrorCode::ConnectTimeout);
+        assert_eq!(
+            err.to_string(),
+            "io error (2 addresses tried, last (connect deadline exceeded))"
+        );
+
+        // a deadline alone bounds each attempt too
+        let mut dialer = Dialer::new();
+        dialer.set_connect_deadline(Some(Duration::from_millis(100)));
+        let Err(DialError::Failed { report, .. }) = dialer.dial(&[hole.addr, addr]).await else {
+            panic!("dial should miss its deadline");
+        };
+        assert_eq!(report.attempts.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_dial_race() {
+        let (_listener, addr) = listener().await;
+        let hole = Blackhole::new();
+        let refused = closed_addr().await;
+
+        // the hanging address gets a head start of the delay, then loses
+        let mut dialer = Dialer::new();
+        dialer.set_attempt_delay(Some(Duration::from_millis(50)));
+        let start = Instant::now();
+        let (stream, report) = dialer.dial(&[hole.addr, addr, refused]).await.unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(50));
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert_eq!(stream.peer_addr().unwrap(), addr);
+        assert_eq!(report.winner(), Some(addr));
+        // the address after the winner never started
+        assert_eq!(report.attempts.len(), 2);
+        let lost = &report.attempts[0];
+        assert_eq!(lost.addr, hole.addr);
+        assert_eq!(lost.error.as_ref().unwrap().kind(), ErrorKind::Interrupted);
+        assert!(lost.elapsed >= Duration::from_millis(50));
+
+        // a failure starts the next address without waiting for the delay
+        dialer.set_attempt_delay(Some(Duration::from_secs(10)));
+        let start = Instant::now();
+        let (_, report) = dialer.dial(&[refused, addr]).await.unwrap();
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert_eq!(report.attempts[0].addr, refused);
+        assert_eq!(report.winner(), Some(addr));
+
+        // the deadline bounds the race as a whole
+        dialer.set_attempt_delay(Some(Duration::from_millis(50)));
+        dialer.set_connect_deadline(Some(Duration::from_millis(150)));
+        let Err(DialError::Failed { last, report }) =
+            dialer.dial(&[hole.addr, hole.addr, refused]).await
+        else {
+            panic!("dial should fail");
+        };
+        assert_eq!(last.to_string(), "connect deadline exceeded");
+        assert_eq!(report.attempts.len(), 3);
+        assert_eq!(report.winner(), None);
+    }
+
+    #[tokio::test]
+    async fn test_tcp_client_race() {
+        use crate::{
+            tcp::{TcpClient, TcpClientOption, TcpStream},
+            TransportClientTrait,
+        };
+
+        let (listener, addr) = listener().await;
+        let hole = Blackhole::new();
+        tokio::spawn(async move { listener.accept().await });
+
+        let opt = TcpClientOption {
+            addr: "localhost".into(),
+            port: addr.port(),
+            tcp_nodelay: false,
+            connect_timeout: None,
+            connect_deadline: None,
+            attempt_delay: Some(Duration::from_millis(50)),
+            resolve_on_connect: false,
+            proxy: None,
+            bind_addr: None,
+            bind_device: None,
+        };
+        let cli = TcpClient::with_addrs(opt, None, vec![hole.addr, addr]).unwrap();
+        let connect = tokio::time::timeout(Duration::from_secs(1), cli.connect());
+        let stream = connect
+            .await
+            .expect("raced past the hanging address")
+            .unwrap();
+        let TcpStream::Raw(stream) = stream else {
+            panic!("tls without a tls option");
+        };
+        assert_eq!(stream.peer_addr().unwrap(), addr);
+    }
+
+    #[tokio::test]
+    async fn test_dial_breaker() {
+        use crate::net::{BreakerScope, CircuitBreakerOption};
+
+        let (_listener, addr) = listener().await;
+        let refused = closed_addr().await;
+        let opt = |scope| CircuitBreakerOption {
+            failure_threshold: 1,
+            open_duration: Duration::from_secs(10),
+            scope,
+        };
+
+        // an open address is skipped, the others are still dialed
+        let mut dialer = Dialer::new();
+        dialer.set_breaker(Some(Arc::new(CircuitBreaker::new(opt(BreakerScope::Addr)))));
+        dialer.dial(&[refused, addr]).await.unwrap();
+        let (_, report) = dialer.dial(&[refused, addr]).await.unwrap();
+        assert_eq!(report.winner(), Some(addr));
+        assert_eq!(report.attempts[0].elapsed, Duration::ZERO);
+        let skipped = report.attempts[0].error.as_ref().unwrap();
+        assert_eq!(skipped.to_string(), "circuit breaker open");
+        let Err(DialError::CircuitOpen { until }) = dialer.dial(&[refused]).await else {
+            panic!("dialed an open address");
+        };
+        let state = dialer.breaker().unwrap().state();
+        assert_eq!(state.len(), 2);
+        let open = state.iter().find(|s| s.addr == Some(refused)).unwrap();
+        assert_eq!(open.until, Some(until));
+
+        // one breaker for all addresses
+        dialer.set_breaker(Some(Arc::new(CircuitBreaker::new(opt(
+            BreakerScope::Endpoint,
+        )))));
+        assert!(matches!(
+            dialer.dial(&[refused]).await,
+            Err(DialError::Failed { .. })
+        ));
+        assert!(matches!(
+            dialer.dial(&[addr]).await,
+            Err(DialError::CircuitOpen { .. })
+        ));
+        let state = dialer.breaker().unwrap().state();
+        assert_eq!(state[0].addr, None);
+        assert_eq!(state[0].state, crate::net::BreakerState::Open);
+    }
+
+    #[tokio::test]
+    async fn test_tcp_client_breaker() {
+        use crate::{
+            net::BreakerState, ErrorCode, TransportClient, TransportClientOption,
+            TransportClientTrait,
+        };
+
+        let dead = closed_addr().await;
+        let opt = TransportClientOption {
+            opt: crate::option::ClientOption::Tcp(crate::tcp::TcpClientOption {
+                addr: dead.ip().to_string(),
+                port: dead.port(),
+                tcp_nodelay: false,
+                connect_timeout: None,
+                connect_deadline: None,
+                attempt_delay: None,
+                resolve_on_connect: false,
+                proxy: None,
+                bind_addr: None,
+                bind_device: None,
+            }),
+            breaker: Some(crate::net::CircuitBreakerOption {
+                failure_threshold: 2,
+                open_duration: Duration::from_millis(200),
+                scope: Default::default(),
+            }),
+            ..Default::default()
+        };
+        let cli = TransportClient::init(opt, &Default::default()).unwrap();
+        for _ in 0..2 {
+            let err = cli.connect().await.err().unwrap();
+            assert_eq!(err.code(), ErrorCode::ConnectRefused);
+        }
+
+        // fails fast while open
+        let Err(err) = cli.connect().await else {
+            panic!("connected to a dead listener");
+        };
+        let ClientError::CircuitOpen { until } = err else {
+            panic!("{}", err);
+        };
+        assert_eq!(err.code(), ErrorCode::ConnectCircuitOpen);
+        assert!(!err.is_retryable());
+        let state = cli.breaker_state();
+        assert_eq!((state[0].state, state[0].failures), (BreakerState::Open, 2));
+
+        // the probe after the open duration finds the listener back
+        let listener = TcpListener::bind(dead).await.unwrap();
+        tokio::spawn(async move { listener.accept().await });
+        tokio::time::sleep_until(until).await;
+        assert_eq!(cli.breaker_state()[0].state, BreakerState::HalfOpen);
+        cli.connect().await.unwrap();
+        let state = cli.breaker_state();
+        assert_eq!(
+            (state[0].state, state[0].failures),
+            (BreakerState::Closed, 0)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_dial_bind() {
+        use crate::{
+            tcp::{TcpClient, TcpClientOption},
+            Resolver, TransportClientTrait,
+        };
+
+        let (_listener, addr) = listener().await;
+        // all of 127/8 is local on linux, elsewhere only the first one may be
+        let local = if cfg!(target_os = "linux") {
+            "127.0.0.2:0"
+        } else {
+            "127.0.0.1:0"
+        };
+        let local: SocketAddr = local.parse().unwrap();
+
+        // the v6 candidate is skipped without a try, it could never bind
+        let mut dialer = Dialer::new();
+        dialer.set_bind(Some(local), None).unwrap();
+        let v6 = "[::1]:80".parse().unwrap();
+        let (stream, report) = dialer.dial(&[v6, addr]).await.unwrap();
+        assert_eq!(stream.local_addr().unwrap().ip(), local.ip());
+        assert_eq!(report.winner(), Some(addr));
+        let skipped = report.attempts[0].error.as_ref().unwrap();
+        assert_eq!(skipped.kind(), ErrorKind::AddrNotAvailable);
+        assert_eq!(report.attempts[0].elapsed, Duration::ZERO);
+
+        assert!(matches!(
+            dialer.set_bind(None, Some(String::new())),
+            Err(ClientError::Option(_))
+        ));
+
+        // an address not of this host fails as a bad option, not per candidate
+        let opt = TcpClientOption {
+            addr: addr.ip().to_string(),
+            port: addr.port(),
+            bind_addr: Some("192.0.2.1:0".parse().unwrap()),
+            ..Default::default()
+        };
+        let cli = TcpClient::init(opt, None, &Resolver::default()).unwrap();
+        let Err(err) = cli.connect().await else {
+            panic!("bound to an address of another host");
+        };
+        assert!(matches!(err, ClientError::Option(_)), "{}", err);
+        assert!(err.to_string().contains("192.0.2.1"), "{}", err);
+    }
+
+    fn is_private(addr: &SocketAddr) -> bool {
+        match addr.ip() {
+            std::net::IpAddr::V4(ip) => ip.is_private(),
+            std::net::IpAddr::V6(_) => false,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_dial_hooks_policy() {
+        use crate::{
+            tcp::{TcpClient, TcpClientOption},
+            ErrorCode, Resolver, TransportClientTrait,
+        };
+
+        let hooks = DialHooks {
+            on_resolved: Some(Arc::new(|addrs: &[SocketAddr]| {
+                Ok(addrs.iter().filter(|a| !is_private(a)).copied().collect())
+            })),
+            ..Default::default()
+        };
+        let opt = |addr: &str| TcpClientOption {
+            addr: addr.to_owned(),
+            port: 80,
+            tcp_nodelay: false,
+            connect_timeout: None,
+            connect_deadline: None,
+            attempt_delay: None,
+            resolve_on_connect: false,
+            proxy: None,
+            bind_addr: None,
+            bind_device: None,
+        };
+
+        // a name resolving to private addresses only
+        let addrs = vec![
+            "10.0.0.1:80".parse().unwrap(),
+            "192.168.1.1:80".parse().unwrap(),
+        ];
+        let mut cli = TcpClient::with_addrs(opt("internal.example"), None, addrs).unwrap();
+        cli.set_dial_hooks(hooks.clone());
+        let Err(err) = cli.connect().await else {
+            panic!("private address dialed");
+        };
+        assert!(matches!(err, ClientError::Denied(_)), "{}", err);
+        assert_eq!(err.code(), ErrorCode::ConnectDenied);
+        assert!(!err.code().retryable());
+
+        // ip literals skip the resolver but not the policy
+        let mut cli = TcpClient::init(opt("172.16.0.1"), None, &Resolver::default()).unwrap();
+        cli.set_dial_hooks(hooks);
+        let Err(err) = cli.connect().await else {
+            panic!("private address dialed");
+        };
+        assert_eq!(err.code(), ErrorCode::ConnectDenied);
+    }
+
+    #[tokio::test]
+    async fn test_dial_hooks_telemetry() {
+        use std::sync::Mutex;
+
+        let (_listener, addr) = listener().await;
+        let refused = [closed_addr().await, closed_addr().await];
+
+        let attempts = Arc::new(Mutex::new(vec![]));
+        let established = Arc::new(Mutex::new(None));
+        let (a, e) = (attempts.clone(), established.clone());
+        let mut dialer = Dialer::new();
+        dialer.set_hooks(DialHooks {
+            on_resolved: None,
+            on_attempt: Some(Arc::new(move |addr| a.lock().unwrap().push(addr))),
+            on_established: Some(Arc::new(move |addr, report: &DialReport| {
+                *e.lock().unwrap() = Some((addr, report.attempts.len()));
+            })),
+            observer: None,
+        });
+
+        let addrs = dialer.candidates(&[refused[0], refused[1], addr]).unwrap();
+        dialer.dial(&addrs).await.unwrap();
+        assert_eq!(*attempts.lock().unwrap(), [refused[0], refused[1], addr]);
+        assert_eq!(*established.lock().unwrap(), Some((addr, 3)));
+    }
+}
@@ -0,0 +1,109 @@
+//! Listening Sockets
+
+use std::{io, net::SocketAddr, sync::Mutex};
+
+use socket2::{Domain, SockRef, Socket, Type};
+use tokio::net::{TcpListener, TcpSocket};
+
+use super::busy_poll::hint_busy_poll;
+
+/// Backlog of `TcpListener::bind`, kept for listeners bound here.
+const BACKLOG: u32 = 1024;
+
+/// Address a server actually listens on, for listen addresses with port 0.
+#[derive(Debug, Default)]
+pub struct BoundAddr(Mutex<Option<SocketAddr>>);
+
+impl BoundAddr {
+    pub fn set(&self, addr: SocketAddr) {
+        *self.0.lock().unwrap_or_else(|e| e.into_inner()) = Some(addr);
+    }
+
+    /// The last bound address, `configured` before the first bind.
+    pub fn get_or(&self, configured: SocketAddr) -> SocketAddr {
+        self.0
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .unwrap_or(configured)
+    }
+}
+
+/// A listener bound by a server's init, so its address is known before `serve`.
+///
+/// Bound outside of any runtime, `serve` takes it and hands it to tokio.
+/// Connections arriving before that wait in the backlog.
+#[derive(Debug, Default)]
+pub struct EarlyListener(Mutex<Option<std::net::TcpListener>>);
+
+impl EarlyListener {
+    pub fn bind(addr: SocketAddr) -> io::Result<Self> {
+        let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+        #[cfg(not(windows))]
+        socket.set_reuse_address(true)?;
+        socket.bind(&addr.into())?;
+        socket.listen(BACKLOG as i32)?;
+        socket.set_nonblocking(true)?;
+        Ok(Self(Mutex::new(Some(socket.into()))))
+    }
+
+    /// `None` once taken or when never bound.
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.lock().as_ref()?.local_addr().ok()
+    }
+
+    /// Send buffer size inherited by the sockets accepted from now on.
+    pub fn set_send_buffer_size(&self, size: u32) -> io::Result<()> {
+        match *self.lock() {
+            Some(ref listener) => SockRef::from(listener).set_send_buffer_size(size as usize),
+            None => Ok(()),
+        }
+    }
+
+    /// Busy poll inherited by the sockets accepted from now on, a refusal is only logged.
+    pub fn set_busy_poll(&self, usecs: u32) {
+        if let Some(ref listener) = *self.lock() {
+            let label = listener
+                .local_addr()
+                .map(|a| a.to_string())
+                .unwrap_or_default();
+            hint_busy_poll(SockRef::from(listener), usecs, &label);
+        }
+    }
+
+    /// The listener for `serve` to accept on, closed once it is dropped.
+    pub fn take(&self) -> Option<std::net::TcpListener> {
+        self.lock().take()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Option<std::net::TcpListener>> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Bind like `TcpListener::bind`, with a send buffer size and busy poll
+/// inherited by accepted sockets.
+pub async fn bind_listener(
+    addr: SocketAddr,
+    send_buffer_size: Option<u32>,
+    busy_poll: Option<u32>,
+) -> io::Result<TcpListener> {
+    if send_buffer_size.is_none() && busy_poll.is_none() {
+        return TcpListener::bind(addr).await;
+    }
+
+    let socket = if addr.is_ipv4() {
+        TcpSocket::new_v4()?
+    } else {
+        TcpSocket::new_v6()?
+    };
+    #[cfg(not(windows))]
+    socket.set_reuseaddr(true)?;
+    if let Some(size) = send_buffer_size {
+        socket.set_send_buffer_size(size)?;
+    }
+    if let Some(usecs) = busy_poll {
+        hint_busy_poll(SockRef::from(&socket), usecs, &addr);
+    }
+    socket.bind(addr)?;
+    socket.listen(BACKLOG)
+}
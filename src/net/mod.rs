@@ -0,0 +1,21 @@
+//! Network Building Blocks
+
+pub mod breaker;
+pub use breaker::{
+    BreakerScope, BreakerSnapshot, BreakerState, CircuitBreaker, CircuitBreakerOption,
+};
+
+pub mod dialer;
+pub use dialer::{
+    AttemptHook, DialAddrs, DialAttempt, DialError, DialHooks, DialReport, Dialer, EstablishedHook,
+    ResolvedHook, DEFAULT_ATTEMPT_DELAY,
+};
+
+pub mod proxy;
+pub use proxy::ProxyTunnel;
+
+pub mod busy_poll;
+pub use busy_poll::set_busy_poll;
+
+pub mod listener;
+pub use listener::{bind_listener, BoundAddr, EarlyListener};
@@ -0,0 +1,669 @@
+//! Outbound Proxy
+//!
+//! The clients dial the proxy instead of their target and ask it to
+//! connect on, before running tls or the websocket upgrade over the
+//! tunnel. The target host is sent as given so the proxy resolves it.
+
+use std::net::IpAddr;
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::{
+    option::{ProxyKind, ProxyOption},
+    ClientError, ClientResult,
+};
+
+/// Longest http proxy response head read before giving up.
+const MAX_HTTP_HEAD: usize = 8192;
+
+impl ProxyOption {
+    /// Check the credentials fit the handshake, reading the password once.
+    pub fn validate(&self) -> ClientResult<()> {
+        let Some((username, password)) = self.credentials()? else {
+            return Ok(());
+        };
+        match self.kind {
+            ProxyKind::Socks5
+                if username.is_empty() || username.len() > 255 || password.len() > 255 =>
+            {
+                Err(ClientError::Option(
+                    "socks5 username and password must be 1 to 255 bytes".to_owned(),
+                ))
+            }
+            ProxyKind::HttpConnect if username.contains(':') => Err(ClientError::Option(
+                "http proxy username must not contain ':'".to_owned(),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    fn credentials(&self) -> ClientResult<Option<(&str, &str)>> {
+        let Some(ref username) = self.username else {
+            return Ok(None);
+        };
+        let password = match self.password {
+            Some(ref password) => password
+                .expose()
+                .map_err(|e| ClientError::Option(e.to_string()))?,
+            None => "",
+        };
+        Ok(Some((username, password)))
+    }
+
+    /// Ask the proxy on `stream` to connect to `host` and `port`, the
+    /// stream then carries the target connection.
+    pub async fn handshake<S>(&self, stream: &mut S, host: &str, port: u16) -> ClientResult<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        match self.kind {
+            ProxyKind::Socks5 => self.socks5(stream, host, port).await,
+            ProxyKind::HttpConnect => self.http_connect(stream, host, port).await,
+        }
+    }
+
+    async fn http_connect<S>(&self, stream: &mut S, host: &str, port: u16) -> ClientResult<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let authority = match host.parse::<IpAddr>() {
+            Ok(IpAddr::V6(ip)) => format!("[{}]:{}", ip, port),
+            _ => format!("{}:{}", host, port),
+        };
+        let mut request = format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n", authority);
+        if let Some((username, password)) = self.credentials()? {
+            let token = STANDARD.encode(format!("{}:{}", username, password));
+            request.push_str(&format!("Proxy-Authorization: Basic {}\r\n", token));
+        }
+        request.push_str("\r\n");
+        stream.write_all(request.as_bytes()).await?;
+        stream.flush().await?;
+
+        // byte by byte, anything past the head belongs to the tunnel
+        let mut head = Vec::with_capacity(256);
+        while !head.ends_with(b"\r\n\r\n") {
+            if head.len() == MAX_HTTP_HEAD {
+                return Err(ClientError::Connect(
+                    "http proxy response head is too long".to_owned(),
+                ));
+            }
+            head.push(stream.read_u8().await?);
+        }
+        let head = String::from_utf8_lossy(&head);
+        let status_line = head.lines().next().unwrap_or_default();
+        let mut parts = status_line.splitn(3, ' ');
+        let (version, code, reason) = (parts.next(), parts.next(), parts.next());
+        let code = match (version, code.and_then(|code| code.parse::<u16>().ok())) {
+            (Some(version), Some(code)) if version.starts_with("HTTP/1.") => code,
+            _ => {
+                return Err(ClientError::Connect(format!(
+                    "http proxy answered {:?}",
+                    status_line
+                )))
+            }
+        };
+        if !(200..300).contains(&code) {
+            return Err(ClientError::Connect(format!(
+                "http proxy connect to {} failed with {} {}",
+                authority,
+                code,
+                reason.unwrap_or_default()
+            )));
+        }
+        Ok(())
+    }
+
+    async fn socks5<S>(&self, stream: &mut S, host: &str, port: u16) -> ClientResult<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let method = if self.username.is_some() {
+            SOCKS5_AUTH_PASSWORD
+        } else {
+            SOCKS5_AUTH_NONE
+        };
+        stream.write_all(&[SOCKS5_VERSION, 1, method]).await?;
+        let mut reply = [0u8; 2];
+        stream.read_exact(&mut reply).await?;
+        if reply[0] != SOCKS5_VERSION {
+            return Err(ClientError::Connect(format!(
+                "socks5 proxy answered with version {}",
+                reply[0]
+            )));
+        }
+        if reply[1] != method {
+            return Err(ClientError::Connect(format!(
+                "socks5 proxy refused auth method {} with {}",
+                method, reply[1]
+            )));
+        }
+
+        if let Some((username, password)) = self.credentials()? {
+            let mut auth = vec![1, username.len() as u8];
+            auth.extend_from_slice(username.as_bytes());
+            auth.push(password.len() as u8);
+            auth.extend_from_slice(password.as_bytes());
+            stream.write_all(&auth).await?;
+            stream.read_exact(&mut reply).await?;
+            if reply[1] != 0 {
+                return Err(ClientError::Connect(format!(
+                    "socks5 auth failed with status {}",
+                    reply[1]
+                )));
+            }
+        }
+
+        let mut request = vec![SOCKS5_VERSION, SOCKS5_CMD_CONNECT, 0];
+        match host.parse::<IpAddr>() {
+            Ok(IpAddr::V4(ip)) => {
+                request.push(SOCKS5_ATYP_V4);
+                request.extend_from_slice(&ip.octets());
+            }
+            Ok(IpAddr::V6(ip)) => {
+                request.push(SOCKS5_ATYP_V6);
+                request.extend_from_slice(&ip.octets());
+            }
+            Err(_) if host.is_empty() || host.len() > 255 => {
+                return Err(ClientError::Option(format!(
+                    "socks5 host {:?} must be 1 to 255 bytes",
+                    host
+                )));
+            }
+            Err(_) => {
+                request.extend_from_slice(&[SOCKS5_ATYP_DOMAIN, host.len() as u8]);
+                request.extend_from_slice(host.as_bytes());
+            }
+        }
+        request.extend_from_slice(&port.to_be_bytes());
+        stream.write_all(&request).await?;
+
+        let mut head = [0u8; 4];
+        stream.read_exact(&mut head).await?;
+        if head[1] != 0 {
+            return Err(ClientError::Connect(format!(
+                "socks5 connect to {}:{} failed with reply {} ({})",
+                host,
+                port,
+                head[1],
+                socks5_reply_text(head[1])
+            )));
+        }
+        // the bound address is of no use to the client
+        let len = match head[3] {
+            SOCKS5_ATYP_V4 => 4,
+            SOCKS5_ATYP_V6 => 16,
+            SOCKS5_ATYP_DOMAIN => stream.read_u8().await? as usize,
+            atyp => {
+                return Err(ClientError::Connect(format!(
+                    "socks5 proxy answered with address type {}",
+                    atyp
+                )))
+            }
+        };
+        let mut bound = vec![0u8; len + 2];
+        stream.read_exact(&mut bound).await?;
+        Ok(())
+    }
+}
+
+const SOCKS5_VERSION: u8 = 5;
+const SOCKS5_AUTH_NONE: u8 = 0;
+const SOCKS5_AUTH_PASSWORD: u8 = 2;
+const SOCKS5_CMD_CONNECT: u8 = 1;
+const SOCKS5_ATYP_V4: u8 = 1;
+const SOCKS5_ATYP_DOMAIN: u8 = 3;
+const SOCKS5_ATYP_V6: u8 = 4;
+
+fn socks5_reply_text(code: u8) -> &'static str {
+    match code {
+        1 => "general failure",
+        2 => "not allowed by ruleset",
+        3 => "network unreachable",
+        4 => "host unreachable",
+        5 => "connection refused",
+        6 => "ttl expired",
+        7 => "command not supported",
+        8 => "address type not supported",
+        _ => "unknown",
+    }
+}
+
+/// A proxy and the target it connects the client to.
+#[derive(Debug, Clone)]
+pub struct ProxyTunnel {
+    proxy: ProxyOption,
+    host: String,
+    port: u16,
+}
+
+impl ProxyTunnel {
+    pub fn new(proxy: ProxyOption, host: &str, port: u16) -> ClientResult<Self> {
+        proxy.validate()?;
+        Ok(Self {
+            proxy,
+            host: host.to_owned(),
+            port,
+        })
+    }
+
+    /// Run the proxy handshake over `stream`, dialed to the proxy.
+    pub async fn open<S>(&self, stream: &mut S) -> ClientResult<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        self.proxy.handshake(stream, &self.host, self.port).await
+    }
+}
+
+/// Host and port a client dials, the proxy's when one is set.
+pub fn dial_target<'a>(proxy: Option<&'a ProxyOption>, host: &'a str, port: u16) -> (&'a str, u16) {
+    match proxy {
+        Some(proxy) => (&proxy.addr, proxy.port),
+        None => (host, port),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::SocketAddr, time::Duration};
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+        sync::mpsc,
+    };
+
+    use crate::{
+        websocket::{
+            wire, ForwardedOption, WebSocketClient, WebSocketClientOption, WebSocketServer,
+            WebSocketServerOption,
+        },
+        Resolver, TransportClientTrait, TransportServerTrait,
+    };
+    // the tcp tests run over tls to an insecure client
+    #[cfg(feature = "insecure-tls")]
+    use crate::{
+        tcp::{TcpClient, TcpClientOption},
+        TlsCertOption, TlsClientOption, TlsServerOption,
+    };
+    #[cfg(feature = "insecure-tls")]
+    use std::sync::Arc;
+
+    use super::*;
+
+    /// SOCKS5 server sending each requested target to the receiver, then
+    /// answering `reply` and relaying on success.
+    async fn spawn_socks5_server(
+        credentials: Option<(&'static str, &'static str)>,
+        reply: u8,
+    ) -> (SocketAddr, mpsc::UnboundedReceiver<(String, u16)>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (tx, rx) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            loop {
+                let (s, _) = listener.accept().await.unwrap();
+                let tx = tx.clone();
+                tokio::spawn(async move {
+                    let _ = socks5_serve(s, credentials, reply, tx).await;
+                });
+            }
+        });
+        (addr, rx)
+    }
+
+    async fn socks5_serve(
+        mut s: TcpStream,
+        credentials: Option<(&str, &str)>,
+        reply: u8,
+        tx: mpsc::UnboundedSender<(String, u16)>,
+    ) -> std::io::Result<()> {
+        let mut head = [0u8; 2];
+        s.read_exact(&mut head).await?;
+        let mut methods = vec![0u8; head[1] as usize];
+        s.read_exact(&mut methods).await?;
+        let method = if credentials.is_some() { 2 } else { 0 };
+        if !methods.contains(&method) {
+            return s.write_all(&[5, 0xff]).await;
+        }
+        s.write_all(&[5, method]).await?;
+
+        if let Some((username, password)) = credentials {
+            s.read_exact(&mut head).await?;
+            let mut user = vec![0u8; head[1] as usize];
+            s.read_exact(&mut user).await?;
+            let mut pass = vec![0u8; s.read_u8().await? as usize];
+            s.read_exact(&mut pass).await?;
+            if user != username.as_bytes() || pass != password.as_bytes() {
+                return s.write_all(&[1, 1]).await;
+            }
+            s.write_all(&[1, 0]).await?;
+        }
+
+        let mut request = [0u8; 4];
+        s.read_exact(&mut request).await?;
+        let host = match request[3] {
+            1 => {
+                let mut ip = [0u8; 4];
+                s.read_exact(&mut ip).await?;
+                IpAddr::from(ip).to_string()
+            }
+            3 => {
+                let mut host = vec![0u8; s.read_u8().await? as usize];
+                s.read_exact(&mut host).await?;
+                String::from_utf8(host).unwrap()
+            }
+            _ => unreachable!(),
+        };
+        let port = s.read_u16().await?;
+        let _ = tx.send((host.clone(), port));
+
+        if reply != 0 {
+            return s.write_all(&[5, reply, 0, 1, 0, 0, 0, 0, 0, 0]).await;
+        }
+        let mut target = TcpStream::connect((host.as_str(), port)).await?;
+        s.write_all(&[5, 0, 0, 1, 127, 0, 0, 1, 0, 0]).await?;
+        tokio::io::copy_bidirectional(&mut s, &mut target).await?;
+        Ok(())
+    }
+
+    fn proxy(addr: SocketAddr, credentials: Option<(&str, &str)>) -> ProxyOption {
+        ProxyOption {
+            kind: ProxyKind::Socks5,
+            addr: addr.ip().to_string(),
+            port: addr.port(),
+            username: credentials.map(|(user, _)| user.to_owned()),
+            password: credentials.map(|(_, pass)| pass.into()),
+        }
+    }
+
+    #[cfg(feature = "insecure-tls")]
+    fn tcp_option(port: u16, proxy: ProxyOption) -> TcpClientOption {
+        TcpClientOption {
+            addr: "localhost".into(),
+            port,
+            proxy: Some(proxy),
+            ..Default::default()
+        }
+    }
+
+    /// Tls echo server answering one read per connection.
+    #[cfg(feature = "insecure-tls")]
+    async fn spawn_tls_echo_server() -> SocketAddr {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+        let tls_opt = TlsServerOption {
+            alpn: vec![],
+            require_alpn: false,
+            ticket_keys: None,
+            certificate: TlsCertOption::Text {
+                certs: vec![cert.cert.pem()],
+                key: cert.key_pair.serialize_pem().into(),
+            },
+            client_ca: None,
+            require_client_cert: false,
+            session_tickets: false,
+            min_version: None,
+            max_version: None,
+            cipher_suites: vec![],
+        };
+        let config: rustls::ServerConfig = tls_opt.try_into().unwrap();
+        let acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(config));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (s, _) = listener.accept().await.unwrap();
+                let acceptor = acceptor.clone();
+                tokio::spawn(async move {
+                    let mut s = acceptor.accept(s).await.unwrap();
+                    let mut buf = [0u8; 4];
+                    s.read_exact(&mut buf).await.unwrap();
+                    s.write_all(&buf).await.unwrap();
+                    s.flush().await.unwrap();
+                });
+            }
+        });
+        addr
+    }
+
+    #[cfg(feature = "insecure-tls")]
+    #[tokio::test]
+    async fn test_socks5_tcp() {
+        let target = spawn_tls_echo_server().await;
+        let credentials = Some(("alice", "hunter2"));
+        let (proxy_addr, mut targets) = spawn_socks5_server(credentials, 0).await;
+
+        let tls_opt = TlsClientOption {
+            insecure: true,
+            ..Default::default()
+        };
+        let opt = tcp_option(target.port(), proxy(proxy_addr, credentials));
+        let cli = TcpClient::init(opt, Some(tls_opt.clone()), &Resolver::default()).unwrap();
+        let mut s = cli.connect().await.unwrap();
+        assert!(s.is_tls());
+        s.write_all(b"kapi").await.unwrap();
+        let mut buf = [0u8; 4];
+        s.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"kapi");
+        // the host goes to the proxy unresolved
+        assert_eq!(
+            targets.recv().await.unwrap(),
+            ("localhost".to_owned(), target.port())
+        );
+
+        let opt = tcp_option(target.port(), proxy(proxy_addr, Some(("alice", "wrong"))));
+        let cli = TcpClient::init(opt, Some(tls_opt), &Resolver::default()).unwrap();
+        let Err(ClientError::Connect(msg)) = cli.connect().await else {
+            panic!("wrong password accepted");
+        };
+        assert!(msg.contains("auth failed"), "{}", msg);
+
+        // the proxy refusing the target
+        let (proxy_addr, _targets) = spawn_socks5_server(None, 5).await;
+        let opt = tcp_option(target.port(), proxy(proxy_addr, None));
+        let cli = TcpClient::init(opt, None, &Resolver::default()).unwrap();
+        let Err(err) = cli.connect().await else {
+            panic!("refused target connected");
+        };
+        assert_eq!(err.code(), crate::error::ErrorCode::ConnectFailed);
+        assert!(
+            err.to_string().contains("reply 5 (connection refused)"),
+            "{}",
+            err
+        );
+
+        let mut opt = proxy(proxy_addr, Some(("", "")));
+        assert!(matches!(opt.validate(), Err(ClientError::Option(_))));
+        opt.username = None;
+        assert!(opt.validate().is_ok());
+    }
+
+    /// HTTP CONNECT proxy sending each request head to the receiver,
+    /// requiring `authorization` when set.
+    #[cfg(feature = "insecure-tls")]
+    async fn spawn_http_proxy(
+        authorization: Option<&'static str>,
+    ) -> (SocketAddr, mpsc::UnboundedReceiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (tx, rx) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            loop {
+                let (mut s, _) = listener.accept().await.unwrap();
+                let tx = tx.clone();
+                tokio::spawn(async move {
+                    let mut head = vec![];
+                    while !head.ends_with(b"\r\n\r\n") {
+                        head.push(s.read_u8().await.unwrap());
+                    }
+                    let head = String::from_utf8(head).unwrap();
+                    let _ = tx.send(head.clone());
+                    if let Some(authorization) = authorization {
+                        let expected = format!("Proxy-Authorization: {}\r\n", authorization);
+                        if !head.contains(&expected) {
+                            let response = "HTTP/1.1 407 Proxy Authentication Required\r\n\r\n";
+                            let _ = s.write_all(response.as_bytes()).await;
+                            return;
+                        }
+                    }
+                    let target = head.split(' ').nth(1).unwrap().to_owned();
+                    let Ok(mut target) = TcpStream::connect(target).await else {
+                        let _ = s.write_all(b"HTTP/1.1 502 Bad Gateway\r\n\r\n").await;
+                        return;
+                    };
+                    s.write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+                        .await
+                        .unwrap();
+                    let _ = tokio::io::copy_bidirectional(&mut s, &mut target).await;
+                });
+            }
+        });
+        (addr, rx)
+    }
+
+    #[cfg(feature = "insecure-tls")]
+    #[tokio::test]
+    async fn test_http_connect() {
+        let target = spawn_tls_echo_server().await;
+        // base64 of alice:hunter2
+        let (proxy_addr, mut heads) = spawn_http_proxy(Some("Basic YWxpY2U6aHVudGVyMg==")).await;
+        let http_proxy = |credentials| ProxyOption {
+            kind: ProxyKind::HttpConnect,
+            ..proxy(proxy_addr, credentials)
+        };
+
+        let tls_opt = TlsClientOption {
+            insecure: true,
+            ..Default::default()
+        };
+        let opt = tcp_option(target.port(), http_proxy(Some(("alice", "hunter2"))));
+        let cli = TcpClient::init(opt, Some(tls_opt.clone()), &Resolver::default()).unwrap();
+        let mut s = cli.connect().await.unwrap();
+        s.write_all(b"kapi").await.unwrap();
+        let mut buf = [0u8; 4];
+        s.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"kapi");
+        let head = heads.recv().await.unwrap();
+        let request_line = format!("CONNECT localhost:{} HTTP/1.1\r\n", target.port());
+        assert!(head.starts_with(&request_line), "{}", head);
+
+        let opt = tcp_option(target.port(), http_proxy(Some(("alice", "wrong"))));
+        let cli = TcpClient::init(opt, Some(tls_opt), &Resolver::default()).unwrap();
+        let Err(ClientError::Connect(msg)) = cli.connect().await else {
+            panic!("wrong password accepted");
+        };
+        assert!(
+            msg.contains("failed with 407 Proxy Authentication Required"),
+            "{}",
+            msg
+        );
+
+        // an unreachable target, the proxy answers for it
+        let (proxy_addr, _heads) = spawn_http_proxy(None).await;
+        let opt = tcp_option(
+            1,
+            ProxyOption {
+                kind: ProxyKind::HttpConnect,
+                ..proxy(proxy_addr, None)
+            },
+        );
+        let cli = TcpClient::init(opt, None, &Resolver::default()).unwrap();
+        let Err(err) = cli.connect().await else {
+            panic!("unreachable target connected");
+        };
+        assert!(err.to_string().contains("502 Bad Gateway"), "{}", err);
+
+        let invalid = ProxyOption {
+            username: Some("al:ice".into()),
+            ..http_proxy(None)
+        };
+        assert!(matches!(invalid.validate(), Err(ClientError::Option(_))));
+    }
+
+    #[derive(Debug, Clone)]
+    struct EchoCallback;
+
+    impl crate::TransportServerCallback for EchoCallback {
+        async fn handle<S>(&self, mut stream: S, _addr: Option<SocketAddr>)
+        where
+            S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + Sync,
+        {
+            let mut buf = [0u8; 4];
+            if stream.read_exact(&mut buf).await.is_ok() {
+                let _ = stream.write_all(&buf).await;
+                let _ = stream.flush().await;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_socks5_ws() {
+        let opt = WebSocketServerOption {
+            listen: ([127, 0, 0, 1], 9847).into(),
+            path: "/proxied".into(),
+            paths: vec![],
+            path_match: Default::default(),
+            fallback: Default::default(),
+            tcp_nodelay: false,
+            duplex_fairness: false,
+            read_buffer_messages: 1,
+            validate_text: false,
+            text_to_bytes: true,
+            max_message_size: wire::MAX_MESSAGE_SIZE,
+            max_frame_size: wire::MAX_FRAME_SIZE,
+            write_chunk_size: None,
+            max_bytes: None,
+            rate_limit: None,
+            max_connections: None,
+            idle_reap: None,
+            idle_timeout: None,
+            trust_forwarded_headers: false,
+            keepalive_interval: None,
+            keepalive_timeout: Duration::from_secs(30),
+            slow_consumer_policy: None,
+        };
+        let srv = WebSocketServer::init(opt, None).unwrap();
+        tokio::spawn(async move { srv.serve(EchoCallback).await });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let (proxy_addr, mut targets) = spawn_socks5_server(None, 0).await;
+        let opt = WebSocketClientOption {
+            addr: "localhost".into(),
+            port: 9847,
+            path: "/proxied".into(),
+            tcp_nodelay: false,
+            duplex_fairness: false,
+            read_buffer_messages: 1,
+            validate_text: false,
+            text_to_bytes: true,
+            max_message_size: wire::MAX_MESSAGE_SIZE,
+            max_frame_size: wire::MAX_FRAME_SIZE,
+            write_chunk_size: None,
+            forwarded: ForwardedOption::default(),
+            headers: vec![],
+            host: None,
+            keepalive_interval: None,
+            keepalive_timeout: Duration::from_secs(30),
+            slow_consumer_policy: None,
+            connect_timeout: None,
+            connect_deadline: None,
+            attempt_delay: None,
+            resolve_on_connect: false,
+            proxy: Some(proxy(proxy_addr, None)),
+            bind_addr: None,
+            bind_device: None,
+        };
+        let cli = WebSocketClient::init(opt, None, &Resolver::default()).unwrap();
+        let mut s = cli.connect().await.unwrap();
+        s.write_all(b"kapi").await.unwrap();
+        s.flush().await.unwrap();
+        let mut buf = [0u8; 4];
+        s.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"kapi");
+        assert_eq!(
+            targets.recv().await.unwrap(),
+            ("localhost".to_owned(), 9847)
+        );
+    }
+}
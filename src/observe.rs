@@ -0,0 +1,701 @@
+//! Connection Events
+//!
+//! How long each stage of a connect took, resolve, tcp connect, tls and
+//! upgrade, and what happened to each served connection. A client reports
+//! to the `ConnectObserver` of its `DialHooks`, a server to the
+//! `ServeObserver` set on it. Without an observer nothing is timed.
+//!
+//! With the `tracing` feature every connect and served connection also runs
+//! in a `tracing` span, its stages logged as debug events. Under a server's
+//! `trace_sampling` only sampled connections get the span and events, an
+//! unsampled one kept for `always_on_error` logs its handshake failure.
+
+use std::{
+    future::Future,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use crate::{limit::ByteCount, ClientError, ConnContext};
+
+/// Stages of a client connect, each told once it finished.
+///
+/// The methods run inline on the connecting task and should return quickly.
+pub trait ConnectObserver: Send + Sync {
+    /// `name` was resolved, only for clients resolving on every connect.
+    fn on_resolve(
+        &self,
+        _name: &str,
+        _result: Result<&[SocketAddr], &ClientError>,
+        _elapsed: Duration,
+    ) {
+    }
+
+    /// One connect attempt to `addr`, several when addresses fail.
+    fn on_tcp_connect(
+        &self,
+        _addr: SocketAddr,
+        _result: Result<(), &std::io::Error>,
+        _elapsed: Duration,
+    ) {
+    }
+
+    fn on_tls_handshake(&self, _result: Result<(), &std::io::Error>, _elapsed: Duration) {}
+
+    /// The http upgrade of a ws client, after its tls handshake.
+    fn on_ws_upgrade(&self, _result: Result<(), &ClientError>, _elapsed: Duration) {}
+}
+
+/// Connections of a server, from accept until the callback returned.
+pub trait ServeObserver: Send + Sync {
+    /// A connection was accepted, before its handshake and accept hooks.
+    fn on_accept(&self, _ctx: &ConnContext) {}
+
+    /// The tls handshake or ws upgrade failed, the connection is dropped.
+    fn on_handshake_failed(
+        &self,
+        _peer_addr: Option<SocketAddr>,
+        _error: &dyn std::error::Error,
+        _elapsed: Duration,
+    ) {
+    }
+
+    fn on_callback_done(&self, _done: &CallbackDone) {}
+
+    /// How a connection still running at the shutdown signal ended, told
+    /// once `serve_with_shutdown` is done.
+    fn on_shutdown_exit(&self, _id: u64, _exit: ShutdownExit) {}
+}
+
+/// How a callback running at the shutdown signal ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum ShutdownExit {
+    /// Returned within the grace period.
+    Drained,
+    /// Returned once its stream was invalidated, within the kill grace.
+    Invalidated,
+    /// Still running after the kill grace, its task was aborted.
+    Aborted,
+}
+
+/// A connection whose callback returned.
+#[derive(Debug, Clone)]
+pub struct CallbackDone {
+    /// `ConnContext::id` of the connection.
+    pub id: u64,
+    pub transport: &'static str,
+    pub peer_addr: Option<SocketAddr>,
+    /// Bytes the callback read and wrote.
+    pub bytes: ByteCount,
+    /// Time since the connection was accepted.
+    pub elapsed: Duration,
+}
+
+/// One timed stage of a connect, reported when it ends.
+pub(crate) struct Stage<'a> {
+    observer: Option<&'a dyn ConnectObserver>,
+    start: Option<Instant>,
+}
+
+impl<'a> Stage<'a> {
+    pub(crate) fn start(observer: Option<&'a dyn ConnectObserver>) -> Self {
+        let on = observer.is_some() || cfg!(feature = "tracing");
+        Self {
+            observer,
+            start: on.then(Instant::now),
+        }
+    }
+
+    pub(crate) fn resolve(self, name: &str, result: Result<&[SocketAddr], &ClientError>) {
+        let Some(elapsed) = self.start.map(|start| start.elapsed()) else {
+            return;
+        };
+        #[cfg(feature = "tracing")]
+        match result {
+            Ok(addrs) => tracing::debug!(name, ?addrs, ?elapsed, "resolved"),
+            Err(e) => tracing::debug!(name, error = %e, ?elapsed, "resolve failed"),
+        }
+        if let Some(observer) = self.observer {
+            observer.on_resolve(name, result, elapsed);
+        }
+    }
+
+    pub(crate) fn tcp_connect(self, addr: SocketAddr, result: Result<(), &std::io::Error>) {
+        let Some(elapsed) = self.start.map(|start| start.elapsed()) else {
+            return;
+        };
+        #[cfg(feature = "tracing")]
+        match result {
+            Ok(()) => tracing::debug!(%addr, ?elapsed, "tcp connected"),
+            Err(e) => tracing::debug!(%addr, error = %e, ?elapsed, "tcp connect failed"),
+        }
+        if let Some(observer) = self.observer {
+            observer.on_tcp_connect(addr, result, elapsed);
+        }
+    }
+
+    pub(crate) fn tls_handshake(self, result: Result<(), &std::io::Error>) {
+        let Some(elapsed) = self.start.map(|start| start.elapsed()) else {
+            return;
+        };
+        #[cfg(feature = "tracing")]
+        match result {
+            Ok(()) => tracing::debug!(?elapsed, "tls handshake done"),
+            Err(e) => tracing::debug!(error = %e, ?elapsed, "tls handshake failed"),
+        }
+        if let Some(observer) = self.observer {
+            observer.on_tls_handshake(result, elapsed);
+        }
+    }
+
+    pub(crate) fn ws_upgrade(self, result: Result<(), &ClientError>) {
+        let Some(elapsed) = self.start.map(|start| start.elapsed()) else {
+            return;
+        };
+        #[cfg(feature = "tracing")]
+        match result {
+            Ok(()) => tracing::debug!(?elapsed, "ws upgraded"),
+            Err(e) => tracing::debug!(error = %e, ?elapsed, "ws upgrade failed"),
+        }
+        if let Some(observer) = self.observer {
+            observer.on_ws_upgrade(result, elapsed);
+        }
+    }
+}
+
+/// Tell `observer` about an accepted connection, and `tracing` when `traced`.
+#[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+pub(crate) fn accepted(observer: Option<&dyn ServeObserver>, ctx: &ConnContext, traced: bool) {
+    #[cfg(feature = "tracing")]
+    if traced {
+        tracing::debug!(conn = ctx.id, peer = ?ctx.peer_addr, transport = ctx.transport, "accepted");
+    }
+    if let Some(observer) = observer {
+        observer.on_accept(ctx);
+    }
+}
+
+#[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+pub(crate) fn handshake_failed(
+    observer: Option<&dyn ServeObserver>,
+    peer_addr: Option<SocketAddr>,
+    error: &dyn std::error::Error,
+    accepted_at: Instant,
+    traced: bool,
+) {
+    #[cfg(feature = "tracing")]
+    if traced {
+        tracing::debug!(peer = ?peer_addr, error = %error, elapsed = ?accepted_at.elapsed(), "handshake failed");
+    }
+    if let Some(observer) = observer {
+        observer.on_handshake_failed(peer_addr, error, accepted_at.elapsed());
+    }
+}
+
+/// Run the callback through `handle`, then tell `observer` how it went.
+/// `ctx` carries the `ByteCount` of the stream the callback gets.
+pub(crate) async fn callback<F: Future<Output = ()>>(
+    observer: Option<&dyn ServeObserver>,
+    ctx: ConnContext,
+    traced: bool,
+    handle: impl FnOnce(ConnContext) -> F,
+) {
+    let traced = traced && cfg!(feature = "tracing");
+    let done = (observer.is_some() || traced)
+        .then(|| ctx.extensions.get::<ByteCount>().cloned())
+        .flatten()
+        .map(|bytes| CallbackDone {
+            id: ctx.id,
+            transport: ctx.transport,
+            peer_addr: ctx.peer_addr,
+            bytes,
+            elapsed: Duration::ZERO,
+        });
+    let accepted_at = ctx.accepted_at;
+    handle(ctx).await;
+
+    let Some(mut done) = done else {
+        return;
+    };
+    done.elapsed = accepted_at.elapsed();
+    #[cfg(feature = "tracing")]
+    if traced {
+        tracing::debug!(
+            rx = done.bytes.rx(),
+            tx = done.bytes.tx(),
+            elapsed = ?done.elapsed,
+            "callback done"
+        );
+    }
+    if let Some(observer) = observer {
+        observer.on_callback_done(&done);
+    }
+}
+
+/// `fut` in a `connect` span of `transport` under the `tracing` feature.
+#[cfg(feature = "tracing")]
+pub(crate) fn connect_span<F: Future>(
+    transport: &'static str,
+    fut: F,
+) -> impl Future<Output = F::Output> {
+    use tracing::Instrument;
+    fut.instrument(tracing::debug_span!("connect", transport))
+}
+
+#[cfg(not(feature = "tracing"))]
+pub(crate) fn connect_span<F: Future>(_transport: &'static str, fut: F) -> F {
+    fut
+}
+
+/// `fut` in a `serve` span of the connection `id` under the `tracing`
+/// feature, outside of any span when not `traced`.
+#[cfg(feature = "tracing")]
+pub(crate) fn serve_span<F: Future>(
+    transport: &'static str,
+    id: u64,
+    peer: Option<SocketAddr>,
+    traced: bool,
+    fut: F,
+) -> impl Future<Output = F::Output> {
+    use tracing::Instrument;
+    let span = if traced {
+        tracing::debug_span!("serve", transport, conn = id, peer = ?peer)
+    } else {
+        tracing::Span::none()
+    };
+    fut.instrument(span)
+}
+
+#[cfg(not(feature = "tracing"))]
+pub(crate) fn serve_span<F: Future>(
+    _transport: &'static str,
+    _id: u64,
+    _peer: Option<SocketAddr>,
+    _traced: bool,
+    fut: F,
+) -> F {
+    fut
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use serde_json::json;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use crate::{
+        tcp::TcpServer,
+        websocket::{WebSocketClient, WebSocketServer},
+        TransportClientTrait, TransportServerCallback, TransportServerTrait,
+    };
+
+    use super::*;
+
+    /// Every event as a line, timings left out.
+    #[derive(Default)]
+    struct Recorder(Mutex<Vec<String>>);
+
+    impl Recorder {
+        fn push(&self, event: String) {
+            self.0.lock().unwrap().push(event);
+        }
+
+        fn take(&self) -> Vec<String> {
+            std::mem::take(&mut self.0.lock().unwrap())
+        }
+    }
+
+    fn outcome<T, E>(result: Result<T, E>) -> &'static str {
+        if result.is_ok() {
+            "ok"
+        } else {
+            "err"
+        }
+    }
+
+    impl ConnectObserver for Recorder {
+        fn on_tcp_connect(
+            &self,
+            addr: SocketAddr,
+            result: Result<(), &std::io::Error>,
+            _elapsed: Duration,
+        ) {
+            self.push(format!("tcp {} {}", addr.port(), outcome(result)));
+        }
+
+        fn on_tls_handshake(&self, result: Result<(), &std::io::Error>, _elapsed: Duration) {
+            self.push(format!("tls {}", outcome(result)));
+        }
+
+        fn on_ws_upgrade(&self, result: Result<(), &ClientError>, _elapsed: Duration) {
+            self.push(format!("ws {}", outcome(result)));
+        }
+    }
+
+    impl ServeObserver for Recorder {
+        fn on_accept(&self, ctx: &ConnContext) {
+            self.push(format!("accept {}", ctx.transport));
+        }
+
+        fn on_handshake_failed(
+            &self,
+            _peer_addr: Option<SocketAddr>,
+            _error: &dyn std::error::Error,
+            _elapsed: Duration,
+        ) {
+            self.push("handshake failed".into());
+        }
+
+        fn on_callback_done(&self, done: &CallbackDone) {
+            let (rx, tx) = (done.bytes.rx(), done.bytes.tx());
+            self.push(format!("done {} rx {} tx {}", done.transport, rx, tx));
+        }
+
+        fn on_shutdown_exit(&self, _id: u64, exit: ShutdownExit) {
+            self.push(format!("shutdown {:?}", exit));
+        }
+    }
+
+    #[derive(Clone)]
+    struct EchoOnce;
+
+    impl TransportServerCallback for EchoOnce {
+        async fn handle<S>(&self, mut stream: S, _addr: Option<SocketAddr>)
+        where
+            S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + Sync,
+        {
+            let mut buf = [0u8; 16];
+            let n = stream.read(&mut buf).await.unwrap();
+            stream.write_all(&buf[..n]).await.unwrap();
+            stream.flush().await.unwrap();
+        }
+    }
+
+    /// Wait for the callback of the last connection to be reported.
+    async fn settle(recorder: &Recorder) -> Vec<String> {
+        for _ in 0..50 {
+            if recorder
+                .0
+                .lock()
+                .unwrap()
+                .iter()
+                .any(|e| e.starts_with("done"))
+            {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        recorder.take()
+    }
+
+    #[cfg(feature = "insecure-tls")]
+    #[tokio::test]
+    async fn test_observe_tcp() {
+        use crate::{tcp::TcpClient, TlsClientOption, TlsServerOption};
+
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+        let tls_opt: TlsServerOption = serde_json::from_value(json!({
+            "certificate": {"text": {
+                "certs": [cert.cert.pem()], "key": cert.key_pair.serialize_pem()
+            }},
+        }))
+        .unwrap();
+        let opt = serde_json::from_value(json!({"listen": "127.0.0.1:9913"})).unwrap();
+        let mut srv = TcpServer::init(opt, Some(tls_opt)).unwrap();
+        let served = Arc::new(Recorder::default());
+        srv.set_serve_observer(served.clone());
+        tokio::spawn(async move { srv.serve(EchoOnce).await });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        // a closed port first, each attempt is told
+        let closed = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let opt = serde_json::from_value(json!({"addr": "127.0.0.1", "port": 9913})).unwrap();
+        let tls_opt = TlsClientOption {
+            insecure: true,
+            server_name: "localhost".into(),
+            ..Default::default()
+        };
+        let addrs = vec![closed, "127.0.0.1:9913".parse().unwrap()];
+        let mut cli = TcpClient::with_addrs(opt, Some(tls_opt), addrs).unwrap();
+        let connects = Arc::new(Recorder::default());
+        cli.set_connect_observer(connects.clone());
+
+        let mut stream = cli.connect().await.unwrap();
+        stream.write_all(b"hello").await.unwrap();
+        let mut buf = [0u8; 5];
+        stream.read_exact(&mut buf).await.unwrap();
+        drop(stream);
+        assert_eq!(
+            connects.take(),
+            [
+                format!("tcp {} err", closed.port()),
+                "tcp 9913 ok".to_owned(),
+                "tls ok".to_owned(),
+            ]
+        );
+        assert_eq!(settle(&served).await, ["accept tcp", "done tcp rx 5 tx 5"]);
+
+        // plaintext to the tls listener
+        let mut plain = tokio::net::TcpStream::connect("127.0.0.1:9913")
+            .await
+            .unwrap();
+        plain.write_all(b"GET / HTTP/1.1\r\n\r\n").await.unwrap();
+        let _ = plain.read(&mut [0u8; 16]).await;
+        assert_eq!(served.take(), ["accept tcp", "handshake failed"]);
+    }
+
+    #[tokio::test]
+    async fn test_observe_ws() {
+        let opt =
+            serde_json::from_value(json!({"listen": "127.0.0.1:9914", "path": "/ws"})).unwrap();
+        let mut srv = WebSocketServer::init(opt, None).unwrap();
+        let served = Arc::new(Recorder::default());
+        srv.set_serve_observer(served.clone());
+        tokio::spawn(async move { srv.serve(EchoOnce).await });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let opt = serde_json::from_value(json!({
+            "addr": "127.0.0.1",
+            "port": 9914,
+            "path": "/ws",
+        }))
+        .unwrap();
+        let mut cli = WebSocketClient::init(opt, None, &Default::default()).unwrap();
+        let connects = Arc::new(Recorder::default());
+        cli.set_connect_observer(connects.clone());
+
+        let mut stream = cli.connect().await.unwrap();
+        stream.write_all(b"hi").await.unwrap();
+        stream.flush().await.unwrap();
+        let mut buf = [0u8; 2];
+        stream.read_exact(&mut buf).await.unwrap();
+        drop(stream);
+        // no tls stage for a plain ws client
+        assert_eq!(connects.take(), ["tcp 9914 ok", "ws ok"]);
+        assert_eq!(settle(&served).await, ["accept ws", "done ws rx 2 tx 2"]);
+    }
+
+    /// Spans and events seen while it is the default subscriber.
+    #[cfg(feature = "tracing")]
+    #[derive(Clone, Default)]
+    struct Spans(Arc<Mutex<Vec<String>>>);
+
+    #[cfg(feature = "tracing")]
+    impl tracing::Subscriber for Spans {
+        fn enabled(&self, _metadata: &tracing::Metadata<'_>) -> bool {
+            true
+        }
+
+        fn new_span(&self, span: &tracing::span::Attributes<'_>) -> tracing::span::Id {
+            let mut seen = self.0.lock().unwrap();
+            seen.push(format!("span {}", span.metadata().name()));
+            tracing::span::Id::from_u64(seen.len() as u64)
+        }
+
+        fn record(&self, _span: &tracing::span::Id, _values: &tracing::span::Record<'_>) {}
+
+        fn record_follows_from(&self, _span: &tracing::span::Id, _follows: &tracing::span::Id) {}
+
+        fn event(&self, _event: &tracing::Event<'_>) {
+            self.0.lock().unwrap().push("event".into());
+        }
+
+        fn enter(&self, _span: &tracing::span::Id) {}
+
+        fn exit(&self, _span: &tracing::span::Id) {}
+    }
+
+    #[cfg(feature = "tracing")]
+    #[tokio::test]
+    async fn test_observe_sampled_spans() {
+        async fn serve_one(ratio: f64) -> Vec<String> {
+            let spans = Spans::default();
+            let _default = tracing::subscriber::set_default(spans.clone());
+            let opt = serde_json::from_value(json!({
+                "listen": "127.0.0.1:0",
+                "trace_sampling": {"ratio": ratio},
+            }))
+            .unwrap();
+            let mut srv = TcpServer::init(opt, None).unwrap();
+            let served = Arc::new(Recorder::default());
+            srv.set_serve_observer(served.clone());
+            let addr = srv.local_addr().unwrap();
+            tokio::spawn(async move { srv.serve(EchoOnce).await });
+
+            let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+            stream.write_all(b"hi").await.unwrap();
+            stream.read_exact(&mut [0u8; 2]).await.unwrap();
+            // the observer is told either way
+            assert_eq!(settle(&served).await, ["accept tcp", "done tcp rx 2 tx 2"]);
+            let seen = spans.0.lock().unwrap().clone();
+            seen
+        }
+
+        let sampled = serve_one(1.0).await;
+        assert_eq!(sampled.iter().filter(|s| *s == "span serve").count(), 1);
+        assert!(sampled.iter().any(|s| s == "event"), "{:?}", sampled);
+
+        assert_eq!(serve_one(0.0).await, Vec::<String>::new());
+    }
+
+    /// Reads until the stream ends, then writes its goodbye.
+    #[derive(Clone)]
+    struct Stubborn;
+
+    impl TransportServerCallback for Stubborn {
+        async fn handle<S>(&self, mut stream: S, _addr: Option<SocketAddr>)
+        where
+            S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + Sync,
+        {
+            let mut buf = vec![];
+            let _ = stream.read_to_end(&mut buf).await;
+            let err = stream.write_all(b"bye").await.unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::BrokenPipe);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_observe_shutdown() {
+        let opt = serde_json::from_value(json!({"listen": "127.0.0.1:9918"})).unwrap();
+        let mut srv = TcpServer::init(opt, None).unwrap();
+        let served = Arc::new(Recorder::default());
+        srv.set_serve_observer(served.clone());
+        srv.set_kill_grace(Duration::from_secs(5));
+        let (stop, signal) = tokio::sync::oneshot::channel::<()>();
+        let serve = tokio::spawn(async move {
+            srv.serve_with_shutdown(
+                Stubborn,
+                async move {
+                    let _ = signal.await;
+                },
+                Duration::from_millis(50),
+            )
+            .await
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        // the peer stays connected and silent through the shutdown
+        let mut peer = tokio::net::TcpStream::connect("127.0.0.1:9918")
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        stop.send(()).unwrap();
+        serve.await.unwrap().unwrap();
+        assert_eq!(
+            served.take(),
+            ["accept tcp", "done tcp rx 0 tx 0", "shutdown Invalidated"]
+        );
+        // invalidating closed the connection
+        let mut buf = vec![];
+        assert_eq!(peer.read_to_end(&mut buf).await.unwrap(), 0);
+    }
+
+    /// Reads until the stream ends.
+    #[derive(Clone)]
+    struct ReadToEnd;
+
+    impl TransportServerCallback for ReadToEnd {
+        async fn handle<S>(&self, mut stream: S, _addr: Option<SocketAddr>)
+        where
+            S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + Sync,
+        {
+            let _ = stream.read_to_end(&mut vec![]).await;
+        }
+    }
+
+    /// Serve `callback` on `srv`, with the peer from `connect` staying
+    /// connected and silent through the shutdown.
+    async fn shutdown_with<S, C, P>(srv: S, callback: C, connect: impl Future<Output = P>) -> P
+    where
+        S: TransportServerTrait + Send + Sync + 'static,
+        C: TransportServerCallback,
+    {
+        let (stop, signal) = tokio::sync::oneshot::channel::<()>();
+        let serve = tokio::spawn(async move {
+            srv.serve_with_shutdown(
+                callback,
+                async move {
+                    let _ = signal.await;
+                },
+                Duration::from_millis(50),
+            )
+            .await
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let peer = connect.await;
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        stop.send(()).unwrap();
+        serve.await.unwrap().unwrap();
+        peer
+    }
+
+    #[tokio::test]
+    async fn test_observe_shutdown_exits() {
+        use crate::{
+            memory::{MemoryClient, MemoryClientOption, MemoryServer},
+            udp::UdpServer,
+        };
+
+        let opt = serde_json::from_value(json!({"listen": "127.0.0.1:9919"})).unwrap();
+        let mut srv = UdpServer::init(opt, None).unwrap();
+        let served = Arc::new(Recorder::default());
+        srv.set_serve_observer(served.clone());
+        let _peer = shutdown_with(srv, ReadToEnd, async {
+            let peer = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            peer.send_to(b"hi", "127.0.0.1:9919").await.unwrap();
+            peer
+        })
+        .await;
+        // peer streams end with the serve future, the callback drains
+        assert_eq!(
+            served.take(),
+            ["accept udp", "done udp rx 2 tx 0", "shutdown Drained"]
+        );
+
+        let opt = serde_json::from_value(json!({"addr": "test_observe_shutdown_exits"})).unwrap();
+        let mut srv = MemoryServer::init(opt).unwrap();
+        let served = Arc::new(Recorder::default());
+        srv.set_serve_observer(served.clone());
+        let cli = MemoryClient::new(MemoryClientOption::new("test_observe_shutdown_exits"));
+        let _peer = shutdown_with(srv, Stubborn, async { cli.connect().await.unwrap() }).await;
+        assert_eq!(
+            served.take(),
+            [
+                "accept memory",
+                "done memory rx 0 tx 0",
+                "shutdown Invalidated"
+            ]
+        );
+
+        #[cfg(unix)]
+        {
+            use crate::unix::{UnixServer, UnixServerOption};
+
+            let path =
+                std::env::temp_dir().join(format!("kapibara-observe-{}.sock", std::process::id()));
+            let _ = std::fs::remove_file(&path);
+            let opt = UnixServerOption {
+                path: path.clone(),
+                unlink_on_bind: false,
+            };
+            let mut srv = UnixServer::init(opt, None).unwrap();
+            let served = Arc::new(Recorder::default());
+            srv.set_serve_observer(served.clone());
+            let mut peer = shutdown_with(srv, Stubborn, async {
+                tokio::net::UnixStream::connect(&path).await.unwrap()
+            })
+            .await;
+            assert_eq!(
+                served.take(),
+                ["accept unix", "done unix rx 0 tx 0", "shutdown Invalidated"]
+            );
+            let mut buf = vec![];
+            assert_eq!(peer.read_to_end(&mut buf).await.unwrap(), 0);
+        }
+    }
+}
@@ -1,20 +1,314 @@
 //! Transport Option
 
+use std::{future::Future, net::SocketAddr, time::Duration};
+
 use serde::{Deserialize, Serialize};
 
+#[cfg(any(test, feature = "test-util"))]
+use crate::memory::{MemoryClientOption, MemoryServerOption};
+#[cfg(unix)]
+use crate::unix::{UnixClientOption, UnixServerOption};
 use crate::{
-    tcp::{TcpClientOption, TcpServerOption},
+    dns,
+    empty::{BlackholeOption, GeneratorOption, GeneratorServerOption},
+    grpc::{GrpcClientOption, GrpcServerOption},
+    http2::{H2ClientOption, H2ServerOption},
+    net::{CircuitBreakerOption, DialHooks},
+    policy::PolicyOption,
+    retry::RetryOption,
+    secret::Secret,
+    tcp::{TcpClientOption, TcpServerOption, TlsMode},
+    tls,
+    udp::{UdpClientOption, UdpServerOption},
     websocket::{WebSocketClientOption, WebSocketServerOption},
-    TlsClientOption, TlsServerOption,
+    ClientError, ClientResult, ResolveOption, Resolver, TlsClientOption, TlsServerOption,
 };
 
-#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "snake_case")]
 pub struct TransportClientOption {
     #[serde(default)]
     pub opt: ClientOption,
     #[serde(default)]
     pub tls: Option<TlsClientOption>,
+    /// Dedicated resolver for this client instead of the default one.
+    #[serde(default)]
+    pub dns: Option<ResolveOption>,
+    /// Check the path carries data before `connect` returns.
+    #[serde(default)]
+    pub post_connect_probe: Option<ProbeMode>,
+    /// Connect policy and telemetry, tcp and ws transports only.
+    #[serde(skip)]
+    pub hooks: DialHooks,
+    /// Overrides conflicting transport options, see `LatencyProfile`.
+    #[serde(default)]
+    pub latency_profile: LatencyProfile,
+    /// Share the tls config with clients of an equal tls option, turn off
+    /// when hooks mutate the built config.
+    #[serde(default = "default_tls_cache")]
+    pub tls_cache: bool,
+    /// Fail connects fast to destinations that keep failing, tcp and ws
+    /// transports only.
+    #[serde(default)]
+    pub breaker: Option<CircuitBreakerOption>,
+    /// Checks for dangerous option combinations, see `policy`.
+    #[serde(default)]
+    pub policy: PolicyOption,
+    /// Backoff of `TransportClient::connect_with_retry`, plain `connect`
+    /// tries once.
+    #[serde(default)]
+    pub retry: RetryOption,
+    /// Schema version the config was written for, see `config_migrate`.
+    #[serde(default = "default_version")]
+    pub version: u32,
+}
+
+impl Default for TransportClientOption {
+    fn default() -> Self {
+        Self {
+            opt: ClientOption::default(),
+            tls: None,
+            dns: None,
+            post_connect_probe: None,
+            hooks: DialHooks::default(),
+            latency_profile: LatencyProfile::default(),
+            tls_cache: default_tls_cache(),
+            breaker: None,
+            policy: PolicyOption::default(),
+            retry: RetryOption::default(),
+            version: CONFIG_VERSION,
+        }
+    }
+}
+
+fn default_tls_cache() -> bool {
+    true
+}
+
+/// Schema version of the options in this release.
+pub const CONFIG_VERSION: u32 = 2;
+
+/// Configs without a version predate the field, they are version 1.
+fn default_version() -> u32 {
+    1
+}
+
+impl TransportClientOption {
+    /// Resolver from the client config, falling back to the default resolver.
+    pub fn resolver(&self) -> Resolver {
+        self.resolver_with(dns::default_resolver)
+    }
+
+    /// Resolver from the client config, falling back to `default`.
+    pub(crate) fn resolver_with(&self, default: impl FnOnce() -> Resolver) -> Resolver {
+        match self.dns {
+            Some(ref opt) => Resolver::new(opt.clone()),
+            None => default(),
+        }
+    }
+
+    /// Override the transport options conflicting with the latency profile,
+    /// warning about and returning each change.
+    pub fn apply_latency_profile(&mut self) -> Vec<String> {
+        let overridden = self.latency_profile.apply_client(&mut self.opt);
+        warn_overridden(self.latency_profile, &overridden);
+        overridden
+    }
+
+    /// Carry `tls_cache` into the tls option, an opt-out on either side wins.
+    pub fn apply_tls_cache(&mut self) {
+        if let Some(ref mut tls) = self.tls {
+            tls.cache &= self.tls_cache;
+        }
+    }
+}
+
+/// Per connection trade between throughput and latency, set once for the whole stack.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LatencyProfile {
+    /// Transport options apply as configured.
+    #[default]
+    Throughput,
+    /// Nodelay, one ws message read at a time, no ws write buffer with a
+    /// flush after every write, small socket send buffers and, on linux,
+    /// busy polling where the process may enable it.
+    ///
+    /// Wins over the transport options, so changing their defaults cannot
+    /// slow down a connection that asked for this.
+    LowLatency,
+}
+
+/// Socket send buffer of `LowLatency`, a few packets queue at most.
+pub const LOW_LATENCY_SEND_BUFFER: u32 = 16 * 1024;
+
+/// `SO_BUSY_POLL` of `LowLatency` in microseconds, a hint the os may refuse.
+pub const LOW_LATENCY_BUSY_POLL: u32 = 50;
+
+impl LatencyProfile {
+    pub fn is_low_latency(&self) -> bool {
+        *self == LatencyProfile::LowLatency
+    }
+
+    /// Socket send buffer size to set, `None` keeps the os default.
+    pub fn send_buffer_size(&self) -> Option<u32> {
+        self.is_low_latency().then_some(LOW_LATENCY_SEND_BUFFER)
+    }
+
+    /// Socket busy poll in microseconds to set, `None` keeps the os default.
+    pub fn busy_poll(&self) -> Option<u32> {
+        self.is_low_latency().then_some(LOW_LATENCY_BUSY_POLL)
+    }
+
+    /// Override the options of `opt` conflicting with the profile, returning the changes.
+    pub fn apply_client(&self, opt: &mut ClientOption) -> Vec<String> {
+        let mut overridden = vec![];
+        if !self.is_low_latency() {
+            return overridden;
+        }
+        match opt {
+            ClientOption::Tcp(opt) => {
+                enforce("tcp_nodelay", &mut opt.tcp_nodelay, true, &mut overridden)
+            }
+            ClientOption::H2(opt) => {
+                enforce("tcp_nodelay", &mut opt.tcp_nodelay, true, &mut overridden)
+            }
+            ClientOption::Grpc(opt) => {
+                enforce("tcp_nodelay", &mut opt.tcp_nodelay, true, &mut overridden)
+            }
+            ClientOption::Ws(opt) => {
+                enforce("tcp_nodelay", &mut opt.tcp_nodelay, true, &mut overridden);
+                enforce(
+                    "read_buffer_messages",
+                    &mut opt.read_buffer_messages,
+                    1,
+                    &mut overridden,
+                );
+            }
+            ClientOption::Empty
+            | ClientOption::Udp(_)
+            | ClientOption::Blackhole(_)
+            | ClientOption::Generator(_) => {}
+            #[cfg(any(test, feature = "test-util"))]
+            ClientOption::Memory(_) => {}
+            #[cfg(unix)]
+            ClientOption::Unix(_) => {}
+        }
+        overridden
+    }
+
+    /// Override the options of `opt` conflicting with the profile, returning the changes.
+    pub fn apply_server(&self, opt: &mut ServerOption) -> Vec<String> {
+        let mut overridden = vec![];
+        if !self.is_low_latency() {
+            return overridden;
+        }
+        match opt {
+            ServerOption::Tcp(opt) => {
+                enforce("tcp_nodelay", &mut opt.tcp_nodelay, true, &mut overridden)
+            }
+            ServerOption::H2(opt) => {
+                enforce("tcp_nodelay", &mut opt.tcp_nodelay, true, &mut overridden)
+            }
+            ServerOption::Grpc(opt) => {
+                enforce("tcp_nodelay", &mut opt.tcp_nodelay, true, &mut overridden)
+            }
+            ServerOption::Ws(opt) => {
+                enforce("tcp_nodelay", &mut opt.tcp_nodelay, true, &mut overridden);
+                enforce(
+                    "read_buffer_messages",
+                    &mut opt.read_buffer_messages,
+                    1,
+                    &mut overridden,
+                );
+            }
+            ServerOption::Udp(_) | ServerOption::Generator(_) => {}
+            #[cfg(any(test, feature = "test-util"))]
+            ServerOption::Memory(_) => {}
+            #[cfg(unix)]
+            ServerOption::Unix(_) => {}
+        }
+        overridden
+    }
+}
+
+fn enforce<T>(name: &str, field: &mut T, value: T, overridden: &mut Vec<String>)
+where
+    T: PartialEq + std::fmt::Debug,
+{
+    if *field != value {
+        overridden.push(format!("{} {:?} -> {:?}", name, field, value));
+        *field = value;
+    }
+}
+
+fn warn_overridden(profile: LatencyProfile, overridden: &[String]) {
+    if !overridden.is_empty() {
+        log::warn!(
+            "latency profile {:?} overrides {}",
+            profile,
+            overridden.join(", ")
+        );
+    }
+}
+
+/// Probe run on a fresh connection, a failure moves on to the next address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProbeMode {
+    /// Send a Ping and wait for its Pong, ws transport only.
+    WsPing { timeout: Duration },
+    /// Wait for `n` bytes of a server that speaks first, they stay readable.
+    ExpectServerBytes { n: usize, timeout: Duration },
+}
+
+impl ProbeMode {
+    pub fn timeout(&self) -> Duration {
+        match *self {
+            ProbeMode::WsPing { timeout } | ProbeMode::ExpectServerBytes { timeout, .. } => timeout,
+        }
+    }
+
+    /// Run `probe` within the timeout, failing the connect attempt otherwise.
+    pub(crate) async fn run<F>(&self, probe: F) -> ClientResult<()>
+    where
+        F: Future<Output = std::io::Result<()>>,
+    {
+        let reason = match tokio::time::timeout(self.timeout(), probe).await {
+            Ok(Ok(())) => return Ok(()),
+            Ok(Err(e)) => e.to_string(),
+            Err(_) => "timeout".to_owned(),
+        };
+        Err(ClientError::Connect(format!(
+            "post-connect probe failed: {}",
+            reason
+        )))
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProxyKind {
+    /// SOCKS5 (RFC 1928), with username and password auth (RFC 1929).
+    #[default]
+    Socks5,
+    /// HTTP `CONNECT`, with basic auth.
+    HttpConnect,
+}
+
+/// Proxy the tcp and ws clients connect through, see `net::proxy`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ProxyOption {
+    #[serde(default)]
+    pub kind: ProxyKind,
+    /// Host or ip of the proxy, resolved like the client's own `addr`.
+    pub addr: String,
+    pub port: u16,
+    /// Authenticate with `username` and `password` when set.
+    #[serde(default)]
+    pub username: Option<String>,
+    #[serde(default)]
+    pub password: Option<Secret>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -23,20 +317,49 @@ pub struct TransportServerOption {
     pub opt: ServerOption,
     #[serde(default)]
     pub tls: Option<TlsServerOption>,
+    /// Overrides conflicting transport options, see `LatencyProfile`.
+    #[serde(default)]
+    pub latency_profile: LatencyProfile,
+    /// Checks for dangerous option combinations, see `policy`.
+    #[serde(default)]
+    pub policy: PolicyOption,
+    /// Schema version the config was written for, see `config_migrate`.
+    #[serde(default = "default_version")]
+    pub version: u32,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+impl TransportServerOption {
+    /// Override the transport options conflicting with the latency profile,
+    /// warning about and returning each change.
+    pub fn apply_latency_profile(&mut self) -> Vec<String> {
+        let overridden = self.latency_profile.apply_server(&mut self.opt);
+        warn_overridden(self.latency_profile, &overridden);
+        overridden
+    }
+}
+
+// built once per client, the ws variant being the largest costs nothing
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 #[serde(rename_all = "snake_case")]
 pub enum ClientOption {
+    #[default]
     Empty,
     Tcp(TcpClientOption),
     Ws(WebSocketClientOption),
-}
-
-impl Default for ClientOption {
-    fn default() -> Self {
-        Self::Empty
-    }
+    Udp(UdpClientOption),
+    H2(H2ClientOption),
+    Grpc(GrpcClientOption),
+    /// Streams that swallow writes, for benchmarks.
+    Blackhole(BlackholeOption),
+    /// Streams that read a generated pattern, for benchmarks.
+    Generator(GeneratorOption),
+    /// Pipes to a `MemoryServer` in this process, for tests.
+    #[cfg(any(test, feature = "test-util"))]
+    Memory(MemoryClientOption),
+    /// Unix domain socket at a path.
+    #[cfg(unix)]
+    Unix(UnixClientOption),
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -44,12 +367,878 @@ impl Default for ClientOption {
 pub enum ServerOption {
     Tcp(TcpServerOption),
     Ws(WebSocketServerOption),
+    Udp(UdpServerOption),
+    H2(H2ServerOption),
+    Grpc(GrpcServerOption),
+    /// Serve generated streams instead of listening, for benchmarks.
+    Generator(GeneratorServerOption),
+    /// Serve `MemoryClient`s of this process instead of listening, for tests.
+    #[cfg(any(test, feature = "test-util"))]
+    Memory(MemoryServerOption),
+    /// Listen on a unix domain socket at a path.
+    #[cfg(unix)]
+    Unix(UnixServerOption),
+}
+
+impl ClientOption {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ClientOption::Empty => "empty",
+            ClientOption::Tcp(_) => "tcp",
+            ClientOption::Ws(_) => "ws",
+            ClientOption::Udp(_) => "udp",
+            ClientOption::H2(_) => "h2",
+            ClientOption::Grpc(_) => "grpc",
+            ClientOption::Blackhole(_) => "blackhole",
+            ClientOption::Generator(_) => "generator",
+            #[cfg(any(test, feature = "test-util"))]
+            ClientOption::Memory(_) => "memory",
+            #[cfg(unix)]
+            ClientOption::Unix(_) => "unix",
+        }
+    }
+
+    /// Host and port dialed, `None` for the transports that dial nothing.
+    pub fn target(&self) -> Option<(&str, u16)> {
+        match self {
+            ClientOption::Tcp(opt) => Some((&opt.addr, opt.port)),
+            ClientOption::Ws(opt) => Some((&opt.addr, opt.port)),
+            ClientOption::Udp(opt) => Some((&opt.addr, opt.port)),
+            ClientOption::H2(opt) => Some((&opt.addr, opt.port)),
+            ClientOption::Grpc(opt) => Some((&opt.addr, opt.port)),
+            ClientOption::Empty | ClientOption::Blackhole(_) | ClientOption::Generator(_) => None,
+            #[cfg(any(test, feature = "test-util"))]
+            ClientOption::Memory(_) => None,
+            #[cfg(unix)]
+            ClientOption::Unix(_) => None,
+        }
+    }
 }
 
-/*
 impl ServerOption {
-    pub fn name(&self) -> &str {}
+    pub fn name(&self) -> &'static str {
+        match self {
+            ServerOption::Tcp(_) => "tcp",
+            ServerOption::Ws(_) => "ws",
+            ServerOption::Udp(_) => "udp",
+            ServerOption::H2(_) => "h2",
+            ServerOption::Grpc(_) => "grpc",
+            ServerOption::Generator(_) => "generator",
+            #[cfg(any(test, feature = "test-util"))]
+            ServerOption::Memory(_) => "memory",
+            #[cfg(unix)]
+            ServerOption::Unix(_) => "unix",
+        }
+    }
+
+    /// Listen address, unspecified for the generator and memory servers
+    /// which do not listen and for the unix server listening on a path.
+    pub fn addr(&self) -> SocketAddr {
+        match self {
+            ServerOption::Tcp(opt) => opt.listen,
+            ServerOption::Ws(opt) => opt.listen,
+            ServerOption::Udp(opt) => opt.listen,
+            ServerOption::H2(opt) => opt.listen,
+            ServerOption::Grpc(opt) => opt.listen,
+            ServerOption::Generator(_) => SocketAddr::from(([0, 0, 0, 0], 0)),
+            #[cfg(any(test, feature = "test-util"))]
+            ServerOption::Memory(_) => SocketAddr::from(([0, 0, 0, 0], 0)),
+            #[cfg(unix)]
+            ServerOption::Unix(_) => SocketAddr::from(([0, 0, 0, 0], 0)),
+        }
+    }
+
+    /// Listen address, `None` for the generator and memory servers which
+    /// do not listen and for the unix server.
+    pub fn listen_addr(&self) -> Option<SocketAddr> {
+        match self {
+            ServerOption::Generator(_) => None,
+            #[cfg(any(test, feature = "test-util"))]
+            ServerOption::Memory(_) => None,
+            #[cfg(unix)]
+            ServerOption::Unix(_) => None,
+            _ => Some(self.addr()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    Info,
+    Warning,
+    Error,
+}
+
+impl std::fmt::Display for Severity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Severity::Info => "info",
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        })
+    }
+}
 
-    pub fn addr(&self) -> SocketAddr {}
+/// One finding of `check_compat`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompatIssue {
+    pub severity: Severity,
+    pub message: String,
+}
+
+impl std::fmt::Display for CompatIssue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.severity, self.message)
+    }
+}
+
+#[derive(Default)]
+struct Issues(Vec<CompatIssue>);
+
+impl Issues {
+    fn push(&mut self, severity: Severity, message: String) {
+        self.0.push(CompatIssue { severity, message });
+    }
+}
+
+/// Check that a client built from `client` can talk to a server built from `server`.
+///
+/// Pure analysis of the options, except that the server certificate is loaded
+/// to match its names. Issues come in check order, an empty list means no
+/// mismatch was found.
+pub fn check_compat(
+    client: &TransportClientOption,
+    server: &TransportServerOption,
+) -> Vec<CompatIssue> {
+    let mut issues = Issues::default();
+
+    let (addr, port) = match (&client.opt, &server.opt) {
+        (
+            c @ (ClientOption::Empty | ClientOption::Blackhole(_) | ClientOption::Generator(_)),
+            _,
+        ) => {
+            issues.push(Severity::Error, format!("client transport is {}", c.name()));
+            return issues.0;
+        }
+        #[cfg(any(test, feature = "test-util"))]
+        (ClientOption::Memory(c), ServerOption::Memory(s)) => {
+            if c.addr != s.addr {
+                issues.push(
+                    Severity::Error,
+                    format!("client connects to {:?}, server is at {:?}", c.addr, s.addr),
+                );
+            }
+            // no port and no tls to compare
+            return issues.0;
+        }
+        #[cfg(unix)]
+        (ClientOption::Unix(c), ServerOption::Unix(s)) => {
+            if c.path != s.path {
+                issues.push(
+                    Severity::Error,
+                    format!(
+                        "client connects to {}, server listens on {}",
+                        c.path.display(),
+                        s.path.display()
+                    ),
+                );
+            }
+            // no port, the tls checks below still apply
+            ("", 0)
+        }
+        (ClientOption::Tcp(c), ServerOption::Tcp(_)) => (c.addr.as_str(), c.port),
+        (ClientOption::Udp(c), ServerOption::Udp(_)) => (c.addr.as_str(), c.port),
+        (ClientOption::H2(c), ServerOption::H2(s)) => {
+            if c.path != s.path {
+                issues.push(
+                    Severity::Error,
+                    format!("client path {} differs from server path {}", c.path, s.path),
+                );
+            }
+            (c.addr.as_str(), c.port)
+        }
+        (ClientOption::Grpc(c), ServerOption::Grpc(s)) => {
+            if (&c.service_name, &c.method_name) != (&s.service_name, &s.method_name) {
+                issues.push(
+                    Severity::Error,
+                    format!(
+                        "client calls /{}/{}, server serves /{}/{}",
+                        c.service_name, c.method_name, s.service_name, s.method_name
+                    ),
+                );
+            }
+            (c.addr.as_str(), c.port)
+        }
+        (ClientOption::Ws(c), ServerOption::Ws(s)) => {
+            let path = c.path.split('?').next().unwrap_or_default();
+            match crate::websocket::server::upgrade_paths(s) {
+                Ok(served) if !served.iter().any(|p| p == path) => issues.push(
+                    Severity::Error,
+                    format!(
+                        "client path {} differs from server path {}",
+                        path,
+                        served.join(", ")
+                    ),
+                ),
+                Ok(_) => {}
+                Err(e) => issues.push(Severity::Error, e.to_string()),
+            }
+            (c.addr.as_str(), c.port)
+        }
+        (c, s) => {
+            issues.push(
+                Severity::Error,
+                format!("client uses {} but server uses {}", c.name(), s.name()),
+            );
+            return issues.0;
+        }
+    };
+
+    let listen = server.opt.addr();
+    if port != listen.port() {
+        issues.push(
+            Severity::Warning,
+            format!(
+                "client port {} differs from server listen port {}, only fine behind a forwarder",
+                port,
+                listen.port()
+            ),
+        );
+    }
+
+    let server_tls = match server.opt {
+        ServerOption::Tcp(ref s) if s.tls_mode == TlsMode::Disabled => None,
+        _ => server.tls.as_ref(),
+    };
+    match (&client.tls, server_tls) {
+        (None, None) => {}
+        (None, Some(_)) => match server.opt {
+            ServerOption::Tcp(ref s) if s.tls_mode == TlsMode::Optional => {}
+            ServerOption::Tcp(ref s) if s.sniff.is_some() => issues.push(
+                Severity::Warning,
+                "client has no tls option, served only if the server sniffs it as plaintext"
+                    .to_owned(),
+            ),
+            _ => issues.push(
+                Severity::Error,
+                "server requires tls but client has no tls option".to_owned(),
+            ),
+        },
+        (Some(_), None) => issues.push(
+            Severity::Error,
+            "client uses tls but server has no tls option".to_owned(),
+        ),
+        (Some(c), Some(s)) => {
+            // the unix client sends server_name like the tcp one
+            let tcp = match client.opt {
+                ClientOption::Tcp(_) => true,
+                #[cfg(unix)]
+                ClientOption::Unix(_) => true,
+                _ => false,
+            };
+            check_tls(c, s, addr, tcp, &mut issues);
+        }
+    }
+
+    issues.0
+}
+
+fn check_tls(
+    client: &TlsClientOption,
+    server: &TlsServerOption,
+    addr: &str,
+    tcp: bool,
+    issues: &mut Issues,
+) {
+    if !client.alpn.is_empty()
+        && !server.alpn.is_empty()
+        && !client.alpn.iter().any(|p| server.alpn.contains(p))
+    {
+        issues.push(
+            Severity::Error,
+            format!(
+                "alpn sets are disjoint, client {:?}, server {:?}",
+                client.alpn, server.alpn
+            ),
+        );
+    }
+    if tcp && client.alpn.is_empty() && server.require_alpn && !server.alpn.is_empty() {
+        issues.push(
+            Severity::Error,
+            "server requires alpn but client offers none".to_owned(),
+        );
+    }
+
+    // only the tcp client sends server_name, the ws client uses its addr
+    let name = if tcp && !client.server_name.is_empty() {
+        client.server_name.as_str()
+    } else {
+        if !tcp && !client.server_name.is_empty() && client.server_name != addr {
+            issues.push(
+                Severity::Warning,
+                format!(
+                    "ws client ignores server_name {}, {} is verified instead",
+                    client.server_name, addr
+                ),
+            );
+        }
+        addr
+    };
+
+    let certs = match server.certificate.load() {
+        Ok((certs, _)) => certs,
+        Err(e) => {
+            issues.push(
+                Severity::Error,
+                format!("server certificate cannot be loaded: {}", e),
+            );
+            return;
+        }
+    };
+
+    if client.insecure {
+        if tls::option::cert_verifies(client, &certs, name) {
+            issues.push(
+                Severity::Info,
+                format!(
+                    "client is insecure but the certificate verifies for {}",
+                    name
+                ),
+            );
+        }
+        return;
+    }
+
+    let names = tls::option::cert_san_names(&certs[0]);
+    if !names.iter().any(|san| tls::option::san_matches(san, name)) {
+        issues.push(
+            Severity::Error,
+            format!(
+                "server name {} is not covered by the certificate names {:?}",
+                name, names
+            ),
+        );
+    } else if !tls::option::cert_verifies(client, &certs, name) {
+        issues.push(
+            Severity::Error,
+            format!(
+                "certificate for {} does not chain to a trusted root, the client needs insecure",
+                name
+            ),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        tcp::{
+            SniffOption, TcpClientOption, TcpServerOption, DEFAULT_ACCEPT_BATCH,
+            DEFAULT_HANDSHAKE_TIMEOUT,
+        },
+        websocket::{wire, ForwardedOption, WebSocketClientOption, WebSocketServerOption},
+        TlsCertOption,
+    };
+
+    use super::*;
+
+    fn tls_server(names: &[&str], alpn: &[&str], require_alpn: bool) -> TlsServerOption {
+        let names = names.iter().map(|n| n.to_string()).collect::<Vec<_>>();
+        let cert = rcgen::generate_simple_self_signed(names).unwrap();
+        TlsServerOption {
+            alpn: alpn.iter().map(|p| p.to_string()).collect(),
+            require_alpn,
+            ticket_keys: None,
+            certificate: TlsCertOption::Text {
+                certs: vec![cert.cert.pem()],
+                key: cert.key_pair.serialize_pem().into(),
+            },
+            client_ca: None,
+            require_client_cert: false,
+            session_tickets: false,
+            min_version: None,
+            max_version: None,
+            cipher_suites: vec![],
+        }
+    }
+
+    fn tls_client(server_name: &str, alpn: &[&str], insecure: bool) -> TlsClientOption {
+        TlsClientOption {
+            insecure,
+            alpn: alpn.iter().map(|p| p.to_string()).collect(),
+            server_name: server_name.to_owned(),
+            ..Default::default()
+        }
+    }
+
+    fn tcp_client(addr: &str, tls: Option<TlsClientOption>) -> TransportClientOption {
+        TransportClientOption {
+            opt: ClientOption::Tcp(TcpClientOption {
+                addr: addr.to_owned(),
+                port: 443,
+                tcp_nodelay: false,
+                connect_timeout: None,
+                connect_deadline: None,
+                attempt_delay: None,
+                resolve_on_connect: false,
+                proxy: None,
+                bind_addr: None,
+                bind_device: None,
+            }),
+            tls,
+            dns: None,
+            post_connect_probe: None,
+            hooks: DialHooks::default(),
+            latency_profile: LatencyProfile::Throughput,
+            tls_cache: true,
+            breaker: None,
+            policy: Default::default(),
+            retry: Default::default(),
+            version: CONFIG_VERSION,
+        }
+    }
+
+    fn tcp_server(tls: Option<TlsServerOption>) -> TransportServerOption {
+        TransportServerOption {
+            opt: ServerOption::Tcp(TcpServerOption {
+                listen: "0.0.0.0:443".parse().unwrap(),
+                tcp_nodelay: false,
+                tls_mode: TlsMode::Required,
+                sniff: None,
+                max_bytes: None,
+                rate_limit: None,
+                trace_sampling: None,
+                idle_reap: None,
+                idle_timeout: None,
+                accept_batch: DEFAULT_ACCEPT_BATCH,
+                handshake_timeout: DEFAULT_HANDSHAKE_TIMEOUT,
+                max_connections: None,
+            }),
+            tls,
+            latency_profile: LatencyProfile::Throughput,
+            policy: Default::default(),
+            version: CONFIG_VERSION,
+        }
+    }
+
+    fn ws_client(addr: &str, path: &str, tls: Option<TlsClientOption>) -> TransportClientOption {
+        TransportClientOption {
+            opt: ClientOption::Ws(WebSocketClientOption {
+                addr: addr.to_owned(),
+                port: 443,
+                path: path.to_owned(),
+                tcp_nodelay: false,
+                duplex_fairness: false,
+                read_buffer_messages: 1,
+                validate_text: false,
+                text_to_bytes: true,
+                max_message_size: wire::MAX_MESSAGE_SIZE,
+                max_frame_size: wire::MAX_FRAME_SIZE,
+                write_chunk_size: None,
+                forwarded: ForwardedOption::default(),
+                headers: vec![],
+                host: None,
+                keepalive_interval: None,
+                keepalive_timeout: Duration::from_secs(30),
+                slow_consumer_policy: None,
+                connect_timeout: None,
+                connect_deadline: None,
+                attempt_delay: None,
+                resolve_on_connect: false,
+                proxy: None,
+                bind_addr: None,
+                bind_device: None,
+            }),
+            tls,
+            dns: None,
+            post_connect_probe: None,
+            hooks: DialHooks::default(),
+            latency_profile: LatencyProfile::Throughput,
+            tls_cache: true,
+            breaker: None,
+            policy: Default::default(),
+            retry: Default::default(),
+            version: CONFIG_VERSION,
+        }
+    }
+
+    fn ws_server(path: &str, tls: Option<TlsServerOption>) -> TransportServerOption {
+        TransportServerOption {
+            opt: ServerOption::Ws(WebSocketServerOption {
+                listen: "0.0.0.0:443".parse().unwrap(),
+                path: path.to_owned(),
+                paths: vec![],
+                path_match: Default::default(),
+                fallback: Default::default(),
+                tcp_nodelay: false,
+                duplex_fairness: false,
+                read_buffer_messages: 1,
+                validate_text: false,
+                text_to_bytes: true,
+                max_message_size: wire::MAX_MESSAGE_SIZE,
+                max_frame_size: wire::MAX_FRAME_SIZE,
+                write_chunk_size: None,
+                max_bytes: None,
+                rate_limit: None,
+                max_connections: None,
+                idle_reap: None,
+                idle_timeout: None,
+                trust_forwarded_headers: false,
+                keepalive_interval: None,
+                keepalive_timeout: Duration::from_secs(30),
+                slow_consumer_policy: None,
+            }),
+            tls,
+            latency_profile: LatencyProfile::Throughput,
+            policy: Default::default(),
+            version: CONFIG_VERSION,
+        }
+    }
+
+    fn severities(issues: &[CompatIssue]) -> Vec<Severity> {
+        issues.iter().map(|i| i.severity).collect()
+    }
+
+    #[test]
+    fn test_compat_transport() {
+        assert!(check_compat(&tcp_client("example.com", None), &tcp_server(None)).is_empty());
+        assert!(check_compat(
+            &ws_client("example.com", "/ws", None),
+            &ws_server("/ws", None)
+        )
+        .is_empty());
+
+        let issues = check_compat(&ws_client("example.com", "/ws", None), &tcp_server(None));
+        assert_eq!(
+            issues[0].to_string(),
+            "error: client uses ws but server uses tcp"
+        );
+        assert_eq!(issues.len(), 1);
+
+        let issues = check_compat(&TransportClientOption::default(), &tcp_server(None));
+        assert_eq!(severities(&issues), [Severity::Error]);
+
+        let issues = check_compat(
+            &ws_client("example.com", "/a", None),
+            &ws_server("/b", None),
+        );
+        assert_eq!(
+            issues[0].message,
+            "client path /a differs from server path /b"
+        );
+        let mut server = ws_server("/b", None);
+        if let ServerOption::Ws(ref mut opt) = server.opt {
+            opt.paths = vec!["/a".into()];
+        }
+        assert!(check_compat(&ws_client("example.com", "/a?room=7", None), &server).is_empty());
+
+        let mut client = tcp_client("example.com", None);
+        if let ClientOption::Tcp(ref mut opt) = client.opt {
+            opt.port = 8443;
+        }
+        let issues = check_compat(&client, &tcp_server(None));
+        assert_eq!(severities(&issues), [Severity::Warning]);
+    }
+
+    #[test]
+    fn test_option_accessors() {
+        let client = tcp_client("example.com", None);
+        assert_eq!(client.opt.name(), "tcp");
+        assert_eq!(client.opt.target(), Some(("example.com", 443)));
+        assert_eq!(ClientOption::Empty.target(), None);
+
+        let server = tcp_server(None);
+        assert_eq!(server.opt.name(), "tcp");
+        assert_eq!(server.opt.listen_addr(), Some(server.opt.addr()));
+    }
+
+    #[test]
+    fn test_compat_tls_presence() {
+        let server = tls_server(&["localhost"], &[], false);
+        let client = tls_client("", &[], true);
+
+        let issues = check_compat(
+            &tcp_client("localhost", None),
+            &tcp_server(Some(server.clone())),
+        );
+        assert_eq!(
+            issues[0].message,
+            "server requires tls but client has no tls option"
+        );
+
+        let issues = check_compat(
+            &tcp_client("localhost", Some(client.clone())),
+            &tcp_server(None),
+        );
+        assert_eq!(
+            issues[0].message,
+            "client uses tls but server has no tls option"
+        );
+
+        let mut sniffing = tcp_server(Some(server));
+        if let ServerOption::Tcp(ref mut opt) = sniffing.opt {
+            opt.sniff = Some(SniffOption {
+                bytes: 1,
+                timeout: std::time::Duration::from_millis(100),
+                default_route: Default::default(),
+            });
+        }
+        let issues = check_compat(&tcp_client("localhost", None), &sniffing);
+        assert_eq!(severities(&issues), [Severity::Warning]);
+
+        let mut optional = tcp_server(Some(tls_server(&["localhost"], &[], false)));
+        if let ServerOption::Tcp(ref mut opt) = optional.opt {
+            opt.tls_mode = TlsMode::Optional;
+        }
+        assert!(check_compat(&tcp_client("localhost", None), &optional).is_empty());
+
+        let mut disabled = tcp_server(Some(tls_server(&["localhost"], &[], false)));
+        if let ServerOption::Tcp(ref mut opt) = disabled.opt {
+            opt.tls_mode = TlsMode::Disabled;
+        }
+        assert!(check_compat(&tcp_client("localhost", None), &disabled).is_empty());
+        let issues = check_compat(&tcp_client("localhost", Some(client)), &disabled);
+        assert_eq!(
+            issues[0].message,
+            "client uses tls but server has no tls option"
+        );
+    }
+
+    #[test]
+    fn test_compat_alpn() {
+        let server = tcp_server(Some(tls_server(&["localhost"], &["h2", "kapi"], true)));
+
+        for (alpn, expect) in [
+            (&["kapi"][..], None),
+            (&["http/1.1", "h2"][..], None),
+            (&["http/1.1"][..], Some("alpn sets are disjoint")),
+            (&[][..], Some("server requires alpn but client offers none")),
+        ] {
+            let client = tcp_client("localhost", Some(tls_client("", alpn, true)));
+            let issues = check_compat(&client, &server);
+            match expect {
+                Some(expect) => assert!(issues[0].message.starts_with(expect), "{:?}", issues),
+                None => assert!(issues.is_empty(), "{:?}", issues),
+            }
+        }
+
+        // require_alpn only applies to the tcp transport
+        let client = ws_client("localhost", "/ws", Some(tls_client("", &[], true)));
+        let server = ws_server("/ws", Some(tls_server(&["localhost"], &["h2"], true)));
+        assert!(check_compat(&client, &server).is_empty());
+    }
+
+    #[test]
+    fn test_compat_server_name() {
+        let server = tcp_server(Some(tls_server(&["*.example.com", "10.0.0.1"], &[], false)));
+
+        for (addr, server_name, covered) in [
+            ("www.example.com", "", true),
+            ("10.0.0.1", "", true),
+            ("10.0.0.1", "api.example.com", true),
+            ("example.com", "", false),
+            ("a.b.example.com", "", false),
+            ("www.example.com", "10.0.0.2", false),
+        ] {
+            let client = tcp_client(addr, Some(tls_client(server_name, &[], false)));
+            let issues = check_compat(&client, &server);
+            assert_eq!(issues.len(), 1, "{:?}", issues);
+            let expect = if covered {
+                "does not chain to a trusted root"
+            } else {
+                "is not covered by the certificate names"
+            };
+            assert!(issues[0].message.contains(expect), "{:?}", issues);
+
+            // insecure skips name checks, the self signed cert never verifies
+            let client = tcp_client(addr, Some(tls_client(server_name, &[], true)));
+            assert!(check_compat(&client, &server).is_empty());
+        }
+
+        // the ws client verifies its addr
+        let client = ws_client(
+            "www.example.com",
+            "/ws",
+            Some(tls_client("other.test", &[], false)),
+        );
+        let server = ws_server("/ws", Some(tls_server(&["*.example.com"], &[], false)));
+        let issues = check_compat(&client, &server);
+        assert_eq!(severities(&issues), [Severity::Warning, Severity::Error]);
+        assert!(issues[1].message.contains("www.example.com"));
+    }
+
+    #[test]
+    fn test_compat_cert_load_error() {
+        let mut tls = tls_server(&["localhost"], &[], false);
+        tls.certificate = TlsCertOption::Text {
+            certs: vec![],
+            key: String::new().into(),
+        };
+        let client = tcp_client("localhost", Some(tls_client("", &[], false)));
+        let issues = check_compat(&client, &tcp_server(Some(tls)));
+        assert_eq!(severities(&issues), [Severity::Error]);
+        assert!(issues[0]
+            .message
+            .starts_with("server certificate cannot be loaded"));
+    }
+
+    #[test]
+    fn test_latency_profile_overrides() {
+        let opt: TransportClientOption =
+            serde_json::from_str(r#"{"opt": {"tcp": {"addr": "localhost", "port": 80}}}"#).unwrap();
+        assert_eq!(opt.latency_profile, LatencyProfile::Throughput);
+
+        // throughput leaves every option as configured
+        let mut cli = ws_client("127.0.0.1", "/", None);
+        let ClientOption::Ws(ref mut ws) = cli.opt else {
+            unreachable!()
+        };
+        ws.read_buffer_messages = 4;
+        let before = format!("{:?}", cli);
+        assert!(cli.apply_latency_profile().is_empty());
+        assert_eq!(format!("{:?}", cli), before);
+
+        cli.latency_profile = LatencyProfile::LowLatency;
+        assert_eq!(
+            cli.apply_latency_profile(),
+            vec!["tcp_nodelay false -> true", "read_buffer_messages 4 -> 1"]
+        );
+        let ClientOption::Ws(ref ws) = cli.opt else {
+            unreachable!()
+        };
+        assert!(ws.tcp_nodelay);
+        assert_eq!(ws.read_buffer_messages, 1);
+        // already consistent, nothing left to override
+        assert!(cli.apply_latency_profile().is_empty());
+
+        let mut srv = tcp_server(None);
+        srv.latency_profile = LatencyProfile::LowLatency;
+        assert_eq!(
+            srv.apply_latency_profile(),
+            vec!["tcp_nodelay false -> true"]
+        );
+        assert_eq!(
+            LatencyProfile::LowLatency.send_buffer_size(),
+            Some(16 * 1024)
+        );
+        assert_eq!(LatencyProfile::Throughput.send_buffer_size(), None);
+        assert_eq!(LatencyProfile::LowLatency.busy_poll(), Some(50));
+        assert_eq!(LatencyProfile::Throughput.busy_poll(), None);
+    }
+
+    #[test]
+    fn test_tls_cache_opt_out() {
+        let opt: TransportClientOption = serde_json::from_str(
+            r#"{"opt": {"tcp": {"addr": "localhost", "port": 443}}, "tls": {}}"#,
+        )
+        .unwrap();
+        assert!(opt.tls_cache);
+        assert!(opt.tls.as_ref().unwrap().cache);
+
+        let mut opt = TransportClientOption {
+            tls_cache: false,
+            ..opt
+        };
+        opt.apply_tls_cache();
+        assert!(!opt.tls.unwrap().cache);
+    }
+
+    #[derive(Clone)]
+    struct EchoCallback;
+
+    impl crate::TransportServerCallback for EchoCallback {
+        async fn handle<S>(&self, mut stream: S, _addr: Option<SocketAddr>)
+        where
+            S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + Sync,
+        {
+            use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+            let mut buf = [0u8; 64];
+            while stream.read_exact(&mut buf).await.is_ok() {
+                if stream.write_all(&buf).await.is_err() || stream.flush().await.is_err() {
+                    break;
+                }
+            }
+        }
+    }
+
+    /// Round trips of 64 byte messages over one connection, sorted.
+    async fn round_trips(cli: TransportClientOption, count: usize) -> Vec<Duration> {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        use crate::{TransportClient, TransportClientTrait};
+
+        let cli = TransportClient::init_with_default_resolver(cli).unwrap();
+        let mut stream = cli.connect().await.unwrap();
+        let mut buf = [0u8; 64];
+        let mut rtts = Vec::with_capacity(count);
+        for _ in 0..count {
+            let start = std::time::Instant::now();
+            stream.write_all(&buf).await.unwrap();
+            stream.flush().await.unwrap();
+            stream.read_exact(&mut buf).await.unwrap();
+            rtts.push(start.elapsed());
+        }
+        rtts.sort();
+        rtts
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore = "benchmark, run with --ignored --nocapture"]
+    async fn bench_latency_profile() {
+        use crate::{TransportServer, TransportServerTrait};
+
+        const ROUND_TRIPS: usize = 5000;
+        let profiles = [LatencyProfile::Throughput, LatencyProfile::LowLatency];
+        for (i, profile) in profiles.into_iter().enumerate() {
+            let tcp_port = 9857 - 2 * i as u16;
+            let ws_port = tcp_port - 1;
+
+            let mut tcp = tcp_server(Some(tls_server(&["localhost"], &[], false)));
+            let ServerOption::Tcp(ref mut opt) = tcp.opt else {
+                unreachable!()
+            };
+            opt.listen = ([127, 0, 0, 1], tcp_port).into();
+            let mut ws = ws_server("/echo", None);
+            let ServerOption::Ws(ref mut opt) = ws.opt else {
+                unreachable!()
+            };
+            opt.listen = ([127, 0, 0, 1], ws_port).into();
+            // the configured options are what a throughput deployment ships with
+            opt.read_buffer_messages = 4;
+
+            for mut srv in [tcp, ws] {
+                srv.latency_profile = profile;
+                tokio::spawn(async move {
+                    let srv = TransportServer::init(srv).unwrap();
+                    srv.serve(EchoCallback).await.unwrap();
+                });
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+
+            let mut tcp = tcp_client("127.0.0.1", Some(tls_client("localhost", &[], true)));
+            let ClientOption::Tcp(ref mut opt) = tcp.opt else {
+                unreachable!()
+            };
+            opt.port = tcp_port;
+            let mut ws = ws_client("127.0.0.1", "/echo", None);
+            let ClientOption::Ws(ref mut opt) = ws.opt else {
+                unreachable!()
+            };
+            opt.port = ws_port;
+            opt.read_buffer_messages = 4;
+
+            for (name, mut cli) in [("tcp+tls", tcp), ("ws", ws)] {
+                cli.latency_profile = profile;
+                let rtts = round_trips(cli, ROUND_TRIPS).await;
+                println!(
+                    "{:?} {}: p50 {:?} p99 {:?}",
+                    profile,
+                    name,
+                    rtts[ROUND_TRIPS / 2],
+                    rtts[ROUND_TRIPS * 99 / 100]
+                );
+            }
+        }
+    }
 }
-*/
@@ -0,0 +1,430 @@
+//! Option Policy
+//!
+//! Named checks for option combinations that work but are quietly
+//! dangerous, run by `TransportClient::init` and `TransportServer::init`.
+//! `PolicyOption.strictness` picks whether a violation is ignored, logged
+//! or fails the init, `allow` accepts specific risks by rule id.
+
+use std::net::IpAddr;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    option::{ClientOption, ServerOption},
+    tcp::TlsMode,
+    ClientError, ServerError, TransportClientOption, TransportServerOption,
+};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Strictness {
+    /// Violations are not checked.
+    #[default]
+    Permissive,
+    /// Every violation is logged as a warning, the init goes on.
+    Warn,
+    /// Any violation fails the init, listing all of them.
+    Strict,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, rename_all = "snake_case")]
+pub struct PolicyOption {
+    pub strictness: Strictness,
+    /// Ids of the rules not to enforce, see `CLIENT_RULES` and `SERVER_RULES`.
+    pub allow: Vec<String>,
+}
+
+/// One named check over an option.
+pub struct Rule<T> {
+    /// Id accepted in `PolicyOption.allow`.
+    pub id: &'static str,
+    /// Dotted paths of the fields the rule looks at.
+    pub fields: &'static [&'static str],
+    /// Why the option is dangerous, `None` when the rule does not fire.
+    pub check: fn(&T) -> Option<String>,
+}
+
+/// A rule firing on an option.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Violation {
+    pub rule: &'static str,
+    pub fields: &'static [&'static str],
+    pub message: String,
+}
+
+impl std::fmt::Display for Violation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}: {} ({})",
+            self.rule,
+            self.message,
+            self.fields.join(", ")
+        )
+    }
+}
+
+pub const CLIENT_RULES: &[Rule<TransportClientOption>] = &[
+    Rule {
+        id: "insecure_tls",
+        fields: &["tls.insecure"],
+        check: |opt| {
+            opt.tls
+                .as_ref()
+                .filter(|tls| tls.insecure)
+                .map(|_| "server certificate is not verified".to_owned())
+        },
+    },
+    Rule {
+        id: "insecure_alpn",
+        fields: &["tls.insecure", "tls.alpn"],
+        check: |opt| {
+            opt.tls
+                .as_ref()
+                .filter(|tls| tls.insecure && !tls.alpn.is_empty())
+                .map(|tls| {
+                    format!(
+                        "alpn {:?} is negotiated with an unverified server",
+                        tls.alpn
+                    )
+                })
+        },
+    },
+    Rule {
+        id: "sni_disabled",
+        fields: &["tls.enable_sni"],
+        check: |opt| {
+            opt.tls
+                .as_ref()
+                .filter(|tls| !tls.enable_sni)
+                .map(|_| "no server name is sent, servers routing by sni fail".to_owned())
+        },
+    },
+    Rule {
+        id: "ech_fallback",
+        fields: &["tls.ech_config", "tls.ech_grease", "tls.require_ech"],
+        check: |opt| {
+            opt.tls
+                .as_ref()
+                .filter(|tls| (tls.ech_config.is_some() || tls.ech_grease) && !tls.require_ech)
+                .map(|_| "ech is unavailable, the ClientHello is sent in the clear".to_owned())
+        },
+    },
+    Rule {
+        id: "no_connect_timeout",
+        fields: &["opt.connect_timeout", "opt.connect_deadline"],
+        check: |opt| {
+            let timeouts = match opt.opt {
+                ClientOption::Tcp(ref tcp) => (tcp.connect_timeout, tcp.connect_deadline),
+                ClientOption::Ws(ref ws) => (ws.connect_timeout, ws.connect_deadline),
+                _ => return None,
+            };
+            (timeouts == (None, None))
+                .then(|| "connect waits on an unresponsive address for the os timeout".to_owned())
+        },
+    },
+    Rule {
+        id: "plaintext_ws",
+        fields: &["tls", "opt.addr"],
+        check: |opt| match opt.opt {
+            ClientOption::Ws(ref ws) if opt.tls.is_none() && !is_local_host(&ws.addr) => {
+                Some(format!(
+                    "ws to {} runs without tls beyond the local network",
+                    ws.addr
+                ))
+            }
+            _ => None,
+        },
+    },
+];
+
+pub const SERVER_RULES: &[Rule<TransportServerOption>] = &[
+    Rule {
+        id: "plaintext_ws",
+        fields: &["tls", "opt.listen"],
+        check: |opt| match opt.opt {
+            ServerOption::Ws(ref ws) if opt.tls.is_none() && !is_local_ip(ws.listen.ip()) => {
+                Some(format!(
+                    "ws on {} runs without tls beyond the local network",
+                    ws.listen
+                ))
+            }
+            _ => None,
+        },
+    },
+    Rule {
+        id: "tls_ignored",
+        fields: &["tls", "opt.tls_mode"],
+        check: |opt| match opt.opt {
+            ServerOption::Tcp(ref tcp)
+                if opt.tls.is_some() && tcp.tls_mode == TlsMode::Disabled =>
+            {
+                Some("tls option is set but tls_mode disables it".to_owned())
+            }
+            _ => None,
+        },
+    },
+    Rule {
+        id: "no_idle_timeout",
+        fields: &["opt.idle_reap"],
+        check: |opt| {
+            let idle_reap = match opt.opt {
+                ServerOption::Tcp(ref tcp) => tcp.idle_reap,
+                ServerOption::Ws(ref ws) => ws.idle_reap,
+                _ => return None,
+            };
+            idle_reap
+                .is_none()
+                .then(|| "idle connections are kept until the peer closes them".to_owned())
+        },
+    },
+];
+
+/// Loopback or private, where plaintext does not cross the internet.
+fn is_local_ip(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(v4) => v4.is_loopback() || v4.is_private() || v4.is_link_local(),
+        IpAddr::V6(v6) => v6.is_loopback() || (v6.segments()[0] & 0xfe00) == 0xfc00,
+    }
+}
+
+fn is_local_host(host: &str) -> bool {
+    match host.parse() {
+        Ok(ip) => is_local_ip(ip),
+        Err(_) => host == "localhost" || host.ends_with(".localhost"),
+    }
+}
+
+/// The rules of `rules` firing on `opt`, less those in `allow`.
+pub fn check<T>(rules: &[Rule<T>], opt: &T, allow: &[String]) -> Vec<Violation> {
+    for id in allow {
+        if !rules.iter().any(|rule| rule.id == id) {
+            log::warn!("policy allows unknown rule {}", id);
+        }
+    }
+    rules
+        .iter()
+        .filter(|rule| !allow.iter().any(|id| id == rule.id))
+        .filter_map(|rule| {
+            (rule.check)(opt).map(|message| Violation {
+                rule: rule.id,
+                fields: rule.fields,
+                message,
+            })
+        })
+        .collect()
+}
+
+/// Apply `strictness` to `violations`, `Err` with all of them joined when strict.
+fn enforce(kind: &str, strictness: Strictness, violations: Vec<Violation>) -> Result<(), String> {
+    if violations.is_empty() {
+        return Ok(());
+    }
+    match strictness {
+        Strictness::Permissive => Ok(()),
+        Strictness::Warn => {
+            for violation in violations {
+                log::warn!("{} option policy: {}", kind, violation);
+            }
+            Ok(())
+        }
+        Strictness::Strict => Err(format!(
+            "{} option policy violated: {}",
+            kind,
+            violations
+                .iter()
+                .map(|v| v.to_string())
+                .collect::<Vec<_>>()
+                .join("; ")
+        )),
+    }
+}
+
+impl TransportClientOption {
+    /// Violations of `CLIENT_RULES` not allowed by the policy, whatever its strictness.
+    pub fn policy_violations(&self) -> Vec<Violation> {
+        check(CLIENT_RULES, self, &self.policy.allow)
+    }
+
+    /// Check the option against the policy, see `Strictness`.
+    pub fn enforce_policy(&self) -> Result<(), ClientError> {
+        if self.policy.strictness == Strictness::Permissive {
+            return Ok(());
+        }
+        enforce("client", self.policy.strictness, self.policy_violations())
+            .map_err(ClientError::Option)
+    }
+}
+
+impl TransportServerOption {
+    /// Violations of `SERVER_RULES` not allowed by the policy, whatever its strictness.
+    pub fn policy_violations(&self) -> Vec<Violation> {
+        check(SERVER_RULES, self, &self.policy.allow)
+    }
+
+    /// Check the option against the policy, see `Strictness`.
+    pub fn enforce_policy(&self) -> Result<(), ServerError> {
+        if self.policy.strictness == Strictness::Permissive {
+            return Ok(());
+        }
+        enforce("server", self.policy.strictness, self.policy_violations())
+            .map_err(ServerError::Option)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+
+    use crate::{TlsCertOption, TlsServerOption, TransportClient, TransportServer};
+
+    use super::*;
+
+    fn client(opt: Value, tls: Value) -> TransportClientOption {
+        serde_json::from_value(json!({"opt": opt, "tls": tls})).unwrap()
+    }
+
+    fn server(opt: Value) -> TransportServerOption {
+        serde_json::from_value(json!({"opt": opt})).unwrap()
+    }
+
+    fn tls_server() -> TlsServerOption {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+        TlsServerOption {
+            alpn: vec![],
+            require_alpn: false,
+            ticket_keys: None,
+            certificate: TlsCertOption::Text {
+                certs: vec![cert.cert.pem()],
+                key: cert.key_pair.serialize_pem().into(),
+            },
+            client_ca: None,
+            require_client_cert: false,
+            session_tickets: false,
+            min_version: None,
+            max_version: None,
+            cipher_suites: vec![],
+        }
+    }
+
+    fn ids(violations: &[Violation]) -> Vec<&'static str> {
+        violations.iter().map(|v| v.rule).collect()
+    }
+
+    #[test]
+    fn test_client_rules() {
+        let timeout = json!({"secs": 5, "nanos": 0});
+        let tcp = json!({"tcp": {"addr": "127.0.0.1", "port": 443, "connect_timeout": timeout}});
+        assert!(client(tcp.clone(), Value::Null)
+            .policy_violations()
+            .is_empty());
+
+        let cases = [
+            ("insecure_tls", tcp.clone(), json!({"insecure": true})),
+            (
+                "insecure_alpn",
+                tcp.clone(),
+                json!({"insecure": true, "alpn": ["h2"]}),
+            ),
+            ("sni_disabled", tcp.clone(), json!({"enable_sni": false})),
+            ("ech_fallback", tcp.clone(), json!({"ech_grease": true})),
+            (
+                "no_connect_timeout",
+                json!({"tcp": {"addr": "127.0.0.1", "port": 443}}),
+                Value::Null,
+            ),
+            (
+                "plaintext_ws",
+                json!({"ws": {
+                    "addr": "example.com", "port": 80, "path": "/", "connect_timeout": timeout
+                }}),
+                Value::Null,
+            ),
+        ];
+        for (id, opt, tls) in cases {
+            assert!(CLIENT_RULES.iter().any(|rule| rule.id == id));
+            let mut opt = client(opt, tls);
+            assert!(ids(&opt.policy_violations()).contains(&id), "{}", id);
+            opt.policy.allow = vec![id.to_owned()];
+            assert!(!ids(&opt.policy_violations()).contains(&id), "{}", id);
+        }
+
+        // plaintext is fine on the local network
+        let local = json!({"ws": {
+            "addr": "10.0.0.1", "port": 80, "path": "/", "connect_timeout": timeout
+        }});
+        assert!(client(local, Value::Null).policy_violations().is_empty());
+    }
+
+    #[test]
+    fn test_server_rules() {
+        let idle = json!({"secs": 60, "nanos": 0});
+        let tcp = json!({"tcp": {"listen": "0.0.0.0:443", "idle_reap": idle}});
+        assert!(server(tcp.clone()).policy_violations().is_empty());
+
+        let mut tls_ignored = server(json!({"tcp": {
+            "listen": "0.0.0.0:443", "idle_reap": idle, "tls_mode": "disabled"
+        }}));
+        tls_ignored.tls = Some(tls_server());
+        let cases = [
+            (
+                "plaintext_ws",
+                server(json!({"ws": {"listen": "0.0.0.0:80", "path": "/", "idle_reap": idle}})),
+            ),
+            ("tls_ignored", tls_ignored),
+            (
+                "no_idle_timeout",
+                server(json!({"tcp": {"listen": "0.0.0.0:443"}})),
+            ),
+        ];
+        for (id, mut opt) in cases {
+            assert!(SERVER_RULES.iter().any(|rule| rule.id == id));
+            assert!(ids(&opt.policy_violations()).contains(&id), "{}", id);
+            opt.policy.allow = vec![id.to_owned()];
+            assert!(!ids(&opt.policy_violations()).contains(&id), "{}", id);
+        }
+    }
+
+    #[test]
+    fn test_strictness() {
+        let mut opt = client(
+            json!({"tcp": {"addr": "127.0.0.1", "port": 443}}),
+            json!({"enable_sni": false}),
+        );
+        let resolver = crate::Resolver::default();
+        for strictness in [Strictness::Permissive, Strictness::Warn] {
+            opt.policy.strictness = strictness;
+            assert!(TransportClient::init(opt.clone(), &resolver).is_ok());
+        }
+
+        // every violation at once
+        opt.policy.strictness = Strictness::Strict;
+        let Err(ClientError::Option(msg)) = TransportClient::init(opt.clone(), &resolver) else {
+            panic!("strict init should fail");
+        };
+        assert!(msg.contains("sni_disabled: "), "{}", msg);
+        assert!(msg.contains("no_connect_timeout: "), "{}", msg);
+        assert!(msg.contains("(opt.connect_timeout, opt.connect_deadline)"));
+
+        let strict = opt.clone();
+        opt.policy.allow = vec!["sni_disabled".into(), "no_connect_timeout".into()];
+        assert!(TransportClient::init(opt.clone(), &resolver).is_ok());
+        // a violation fails its own client only
+        let many = TransportClient::init_many(vec![strict, opt], &resolver);
+        assert!(matches!(many[0], Err(ClientError::Option(_))));
+        assert!(many[1].is_ok());
+
+        let mut opt: TransportServerOption = serde_json::from_value(json!({
+            "opt": {"tcp": {"listen": "127.0.0.1:0"}},
+            "policy": {"strictness": "strict"}
+        }))
+        .unwrap();
+        let Err(ServerError::Option(msg)) = TransportServer::init(opt.clone()) else {
+            panic!("strict init should fail");
+        };
+        assert!(msg.contains("no_idle_timeout"), "{}", msg);
+        opt.policy.allow = vec!["no_idle_timeout".into()];
+        assert!(TransportServer::init(opt).is_ok());
+    }
+}
@@ -0,0 +1,67 @@
+//! Pooled Client
+
+use std::sync::{atomic::Ordering, Arc};
+
+use crate::{
+    reload::AppliedChanges, ClientResult, TransportClient, TransportClientOption,
+    TransportClientTrait,
+};
+
+use super::{Pool, PoolOption, PoolStats, PooledStream};
+
+/// Hands out the idle streams of its pool before connecting `inner`.
+pub struct PooledClient<T: TransportClientTrait> {
+    inner: T,
+    pool: Arc<Pool<T::Stream>>,
+}
+
+impl<T: TransportClientTrait> PooledClient<T> {
+    pub fn new(inner: T, opt: PoolOption) -> Self {
+        Self {
+            inner,
+            pool: Arc::new(Pool::new(opt)),
+        }
+    }
+
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    pub fn option(&self) -> &PoolOption {
+        &self.pool.opt
+    }
+
+    pub fn stats(&self) -> PoolStats {
+        self.pool.stats()
+    }
+
+    /// Drop the idle streams, streams handed out can still be released to it.
+    pub fn clear(&self) {
+        self.pool.clear();
+    }
+}
+
+impl PooledClient<TransportClient> {
+    /// `TransportClient::apply`, the idle streams were connected with the
+    /// old option and are dropped when anything changed.
+    pub fn apply(&self, new_opt: TransportClientOption) -> ClientResult<AppliedChanges> {
+        let changes = self.inner.apply(new_opt)?;
+        if !changes.is_empty() {
+            self.clear();
+        }
+        Ok(changes)
+    }
+}
+
+impl<T: TransportClientTrait> TransportClientTrait for PooledClient<T> {
+    type Stream = PooledStream<T::Stream>;
+
+    async fn connect(&self) -> ClientResult<Self::Stream> {
+        if let Some(stream) = self.pool.checkout() {
+            return Ok(PooledStream::new(stream, &self.pool, true));
+        }
+        let stream = self.inner.connect().await?;
+        self.pool.connected.fetch_add(1, Ordering::Relaxed);
+        Ok(PooledStream::new(stream, &self.pool, false))
+    }
+}
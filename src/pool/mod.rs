@@ -0,0 +1,346 @@
+//! Connection Pool
+//!
+//! Reuses the streams of a client instead of resolving, dialing and
+//! handshaking on every connect. A stream goes back to the pool when its
+//! `PooledStream` is released after a finished exchange, unless it was shut
+//! down, failed or reached eof, and is probed before it is handed out
+//! again: an idle stream with something to read, eof included, is dropped.
+//! A `PooledStream` dropped without a release, a cancelled request for one,
+//! is closed, its answer could otherwise reach the next borrower.
+//!
+//! Only for protocols running one exchange after another over a stream,
+//! the pool cannot tell whether the peer expects a fresh connection.
+
+pub mod option;
+pub use option::PoolOption;
+
+pub mod client;
+pub use client::PooledClient;
+
+pub mod stream;
+pub use stream::PooledStream;
+
+use std::{
+    collections::VecDeque,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex, MutexGuard,
+    },
+    task::Context,
+};
+
+use futures_util::task::noop_waker_ref;
+use serde::Serialize;
+use tokio::{
+    io::{AsyncRead, ReadBuf},
+    time::Instant,
+};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct PoolStats {
+    /// Idle streams not expired yet.
+    pub idle: usize,
+    /// Connects handed an idle stream.
+    pub reused: u64,
+    /// Connects that connected a new stream.
+    pub connected: u64,
+}
+
+struct Idle<S> {
+    stream: S,
+    since: Instant,
+}
+
+/// The idle streams of one client, shared with the streams it handed out.
+pub(crate) struct Pool<S> {
+    opt: PoolOption,
+    idle: Mutex<VecDeque<Idle<S>>>,
+    reused: AtomicU64,
+    connected: AtomicU64,
+}
+
+impl<S> Pool<S> {
+    fn new(opt: PoolOption) -> Self {
+        Self {
+            opt,
+            idle: Mutex::new(VecDeque::new()),
+            reused: AtomicU64::new(0),
+            connected: AtomicU64::new(0),
+        }
+    }
+
+    fn stats(&self) -> PoolStats {
+        let now = Instant::now();
+        PoolStats {
+            idle: self
+                .lock()
+                .iter()
+                .filter(|idle| !self.expired(idle, now))
+                .count(),
+            reused: self.reused.load(Ordering::Relaxed),
+            connected: self.connected.load(Ordering::Relaxed),
+        }
+    }
+
+    fn clear(&self) {
+        let idle = std::mem::take(&mut *self.lock());
+        drop(idle);
+    }
+
+    /// Keep a returned stream, the newest are handed out first.
+    fn checkin(&self, stream: S) {
+        let now = Instant::now();
+        let mut idle = self.lock();
+        idle.retain(|idle| !self.expired(idle, now));
+        idle.push_back(Idle { stream, since: now });
+        while idle.len() > self.opt.max_idle {
+            idle.pop_front();
+        }
+    }
+
+    fn expired(&self, idle: &Idle<S>, now: Instant) -> bool {
+        self.opt
+            .idle_timeout
+            .is_some_and(|timeout| now.duration_since(idle.since) >= timeout)
+    }
+
+    fn lock(&self) -> MutexGuard<'_, VecDeque<Idle<S>>> {
+        self.idle.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl<S: AsyncRead + Unpin> Pool<S> {
+    /// The newest idle stream still alive, dropping the dead and expired.
+    fn checkout(&self) -> Option<S> {
+        let now = Instant::now();
+        let mut idle = self.lock();
+        while let Some(mut next) = idle.pop_back() {
+            if self.expired(&next, now) {
+                // the older ones expired too
+                idle.clear();
+                break;
+            }
+            if is_alive(&mut next.stream) {
+                self.reused.fetch_add(1, Ordering::Relaxed);
+                return Some(next.stream);
+            }
+        }
+        None
+    }
+}
+
+/// An idle peer has nothing to send, so a stream is alive while a read
+/// would block. Data, eof and errors all mean it cannot be reused.
+fn is_alive<S: AsyncRead + Unpin>(stream: &mut S) -> bool {
+    let mut cx = Context::from_waker(noop_waker_ref());
+    let mut byte = [0; 1];
+    let mut buf = ReadBuf::new(&mut byte);
+    Pin::new(stream).poll_read(&mut cx, &mut buf).is_pending()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::SocketAddr,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::*;
+    use crate::{
+        option::ClientOption, tcp::TcpClientOption, Resolver, TransportClient,
+        TransportClientOption, TransportClientTrait,
+    };
+
+    /// Echoes on every accepted stream, closing it on a `q`.
+    async fn echo_server() -> (SocketAddr, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let accepted = Arc::new(AtomicUsize::new(0));
+        let count = accepted.clone();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                count.fetch_add(1, Ordering::Relaxed);
+                tokio::spawn(async move {
+                    let mut buf = vec![0; 1024];
+                    loop {
+                        match stream.read(&mut buf).await {
+                            Ok(0) | Err(_) => break,
+                            Ok(n) if buf[..n].contains(&b'q') => break,
+                            Ok(n) => stream.write_all(&buf[..n]).await.unwrap(),
+                        }
+                    }
+                });
+            }
+        });
+        (addr, accepted)
+    }
+
+    fn client(addr: SocketAddr, opt: PoolOption) -> PooledClient<TransportClient> {
+        let trans_opt = TransportClientOption {
+            opt: ClientOption::Tcp(TcpClientOption {
+                addr: addr.ip().to_string(),
+                port: addr.port(),
+                tcp_nodelay: false,
+                connect_timeout: None,
+                connect_deadline: None,
+                attempt_delay: None,
+                resolve_on_connect: false,
+                proxy: None,
+                bind_addr: None,
+                bind_device: None,
+            }),
+            ..Default::default()
+        };
+        TransportClient::pooled(trans_opt, opt, &Resolver::default()).unwrap()
+    }
+
+    async fn ping<S>(stream: &mut PooledStream<S>)
+    where
+        S: AsyncRead + tokio::io::AsyncWrite + Unpin,
+    {
+        stream.write_all(b"ping").await.unwrap();
+        let mut buf = [0; 4];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+    }
+
+    #[tokio::test]
+    async fn test_pool_reuse() {
+        let (addr, accepted) = echo_server().await;
+        let opt = PoolOption {
+            max_idle: 1,
+            ..Default::default()
+        };
+        let cli = client(addr, opt);
+
+        let mut stream = cli.connect().await.unwrap();
+        assert!(!stream.is_reused());
+        ping(&mut stream).await;
+        stream.release();
+        assert_eq!(cli.stats().idle, 1);
+
+        let mut stream = cli.connect().await.unwrap();
+        assert!(stream.is_reused());
+        ping(&mut stream).await;
+        // the pool is empty while the stream is out
+        let mut other = cli.connect().await.unwrap();
+        assert!(!other.is_reused());
+        ping(&mut other).await;
+        assert_eq!(accepted.load(Ordering::Relaxed), 2);
+
+        // one kept, the stream returned last
+        other.release();
+        stream.release();
+        let stats = cli.stats();
+        assert_eq!(
+            stats,
+            PoolStats {
+                idle: 1,
+                reused: 1,
+                connected: 2,
+            }
+        );
+        cli.clear();
+        assert_eq!(cli.stats().idle, 0);
+    }
+
+    #[tokio::test]
+    async fn test_pool_not_returned() {
+        let (addr, _) = echo_server().await;
+        let cli = client(addr, PoolOption::default());
+
+        let mut stream = cli.connect().await.unwrap();
+        stream.shutdown().await.unwrap();
+        assert!(!stream.is_reusable());
+        stream.release();
+        let mut stream = cli.connect().await.unwrap();
+        stream.discard();
+        stream.release();
+        // into_inner takes it out for good
+        drop(cli.connect().await.unwrap().into_inner());
+        assert_eq!(cli.stats().idle, 0);
+        assert_eq!(cli.stats().connected, 3);
+    }
+
+    #[tokio::test]
+    async fn test_pool_probe() {
+        let (addr, accepted) = echo_server().await;
+        let cli = client(addr, PoolOption::default());
+
+        let mut unread = cli.connect().await.unwrap();
+        let mut closed = cli.connect().await.unwrap();
+        // an echo left unread and a stream closed by the peer while idle
+        unread.write_all(b"x").await.unwrap();
+        closed.write_all(b"q").await.unwrap();
+        unread.release();
+        closed.release();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(cli.stats().idle, 2);
+
+        let mut stream = cli.connect().await.unwrap();
+        assert!(!stream.is_reused());
+        ping(&mut stream).await;
+        assert_eq!(cli.stats().idle, 0);
+        assert_eq!(accepted.load(Ordering::Relaxed), 3);
+    }
+
+    #[tokio::test]
+    async fn test_pool_dropped_mid_exchange() {
+        let (addr, accepted) = echo_server().await;
+        let cli = client(addr, PoolOption::default());
+
+        // the request is given up while its echo is on the way
+        let mut stream = cli.connect().await.unwrap();
+        let mut buf = [0; 4];
+        let exchange = async {
+            stream.write_all(b"late").await.unwrap();
+            stream.read_exact(&mut buf).await.unwrap();
+        };
+        let _ = tokio::time::timeout(Duration::ZERO, exchange).await;
+        drop(stream);
+        assert_eq!(cli.stats().idle, 0);
+
+        // the next borrower never sees the late echo
+        let mut stream = cli.connect().await.unwrap();
+        assert!(!stream.is_reused());
+        ping(&mut stream).await;
+        stream.release();
+        assert_eq!(cli.stats().idle, 1);
+        assert_eq!(accepted.load(Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test]
+    async fn test_pool_idle_timeout() {
+        let (addr, _) = echo_server().await;
+        let opt = PoolOption {
+            idle_timeout: Some(Duration::from_millis(50)),
+            ..Default::default()
+        };
+        let cli = client(addr, opt);
+
+        cli.connect().await.unwrap().release();
+        assert_eq!(cli.stats().idle, 1);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(cli.stats().idle, 0);
+        assert!(!cli.connect().await.unwrap().is_reused());
+        assert_eq!(cli.stats().reused, 0);
+    }
+
+    #[test]
+    fn test_pool_option_deserialize() {
+        let opt: PoolOption = serde_json::from_str(r#"{"max_idle": 2}"#).unwrap();
+        assert_eq!(opt.max_idle, 2);
+        assert_eq!(opt.idle_timeout, PoolOption::default().idle_timeout);
+    }
+}
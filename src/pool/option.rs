@@ -0,0 +1,25 @@
+//! Connection Pool Option
+
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, rename_all = "snake_case")]
+pub struct PoolOption {
+    /// Idle streams kept, the oldest is dropped when another comes back.
+    /// 0 keeps none.
+    pub max_idle: usize,
+    /// Idle streams older than this are dropped instead of handed out.
+    /// Unset keeps them until the probe finds them closed.
+    pub idle_timeout: Option<Duration>,
+}
+
+impl Default for PoolOption {
+    fn default() -> Self {
+        Self {
+            max_idle: 8,
+            idle_timeout: Some(Duration::from_secs(90)),
+        }
+    }
+}
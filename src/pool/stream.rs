@@ -0,0 +1,154 @@
+//! Pooled Stream
+
+use std::{
+    io::IoSlice,
+    pin::Pin,
+    sync::{Arc, Weak},
+    task::{Context, Poll},
+};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use super::Pool;
+
+/// A stream of a `PooledClient`, back to the pool on `release` unless it
+/// was shut down, failed, reached eof or was discarded. Dropped, it closes.
+pub struct PooledStream<S> {
+    inner: S,
+    pool: Weak<Pool<S>>,
+    reusable: bool,
+    reused: bool,
+}
+
+impl<S> PooledStream<S> {
+    pub(crate) fn new(inner: S, pool: &Arc<Pool<S>>, reused: bool) -> Self {
+        Self {
+            inner,
+            pool: Arc::downgrade(pool),
+            reusable: true,
+            reused,
+        }
+    }
+
+    /// Whether the stream came from the pool. A request failing on a reused
+    /// stream may have met a peer closing it meanwhile and is worth a retry.
+    pub fn is_reused(&self) -> bool {
+        self.reused
+    }
+
+    /// Whether the stream goes back to the pool on `release`.
+    pub fn is_reusable(&self) -> bool {
+        self.reusable
+    }
+
+    /// Keep the stream out of the pool, for one left in the middle of an
+    /// exchange.
+    pub fn discard(&mut self) {
+        self.reusable = false;
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.inner
+    }
+
+    /// Hand the stream back to the pool once its exchange is done, the
+    /// whole answer read. Closes it instead when it is not reusable.
+    pub fn release(self) {
+        if !self.reusable {
+            return;
+        }
+        if let Some(pool) = self.pool.upgrade() {
+            pool.checkin(self.inner);
+        }
+    }
+
+    /// Take the stream out of the pool for good.
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+
+    fn pinned(&mut self) -> Pin<&mut S>
+    where
+        S: Unpin,
+    {
+        Pin::new(&mut self.inner)
+    }
+
+    fn track<T>(&mut self, res: Poll<std::io::Result<T>>) -> Poll<std::io::Result<T>> {
+        if let Poll::Ready(Err(_)) = res {
+            self.reusable = false;
+        }
+        res
+    }
+}
+
+impl<S: std::fmt::Debug> std::fmt::Debug for PooledStream<S> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PooledStream")
+            .field("inner", &self.inner)
+            .field("reusable", &self.reusable)
+            .field("reused", &self.reused)
+            .finish()
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for PooledStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        let (filled, remaining) = (buf.filled().len(), buf.remaining());
+        let res = this.pinned().poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = res {
+            if remaining > 0 && buf.filled().len() == filled {
+                // eof, the peer is done with the stream
+                this.reusable = false;
+            }
+        }
+        this.track(res)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for PooledStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        let res = this.pinned().poll_write(cx, buf);
+        this.track(res)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        let res = this.pinned().poll_flush(cx);
+        this.track(res)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        this.reusable = false;
+        this.pinned().poll_shutdown(cx)
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        let res = this.pinned().poll_write_vectored(cx, bufs);
+        this.track(res)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.get_ref().is_write_vectored()
+    }
+}
@@ -0,0 +1,366 @@
+//! Idle Connection Reaping
+
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    task::{AbortHandle, JoinHandle},
+    time::Instant,
+};
+
+use crate::{
+    clock::{Clock, TokioClock},
+    stats::ServerStats,
+};
+
+/// Marks a timestamp the reaper claimed, no activity can be recorded after it.
+const REAPED: u64 = u64::MAX;
+
+/// Last activity of one connection, in milliseconds since the reaper started.
+#[derive(Debug, Clone)]
+pub struct Activity<C = TokioClock> {
+    clock: C,
+    base: Instant,
+    last: Arc<AtomicU64>,
+}
+
+impl<C: Clock> Activity<C> {
+    fn touch(&self) {
+        let now = millis_since(self.base, self.clock.now());
+        // a claimed timestamp stays claimed, the connection is going away
+        let _ = self
+            .last
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |last| {
+                (last != REAPED).then_some(now)
+            });
+    }
+}
+
+struct Conn {
+    last: Arc<AtomicU64>,
+    abort: Option<AbortHandle>,
+    peer: Option<SocketAddr>,
+}
+
+/// Connections of one server with their last activity, closing idle ones.
+pub struct Reaper<C = TokioClock> {
+    idle: Duration,
+    clock: C,
+    base: Instant,
+    stats: Arc<ServerStats>,
+    conns: Mutex<HashMap<u64, Conn>>,
+}
+
+impl Reaper {
+    pub fn new(idle: Duration, stats: Arc<ServerStats>) -> Arc<Self> {
+        Self::with_clock(idle, stats, TokioClock)
+    }
+}
+
+impl<C: Clock> Reaper<C> {
+    /// A reaper measuring idle time and scanning on `clock`.
+    pub fn with_clock(idle: Duration, stats: Arc<ServerStats>, clock: C) -> Arc<Self> {
+        Arc::new(Self {
+            idle,
+            base: clock.now(),
+            clock,
+            stats,
+            conns: Mutex::default(),
+        })
+    }
+
+    /// Track connection `id` until the returned guard drops.
+    ///
+    /// It is only reaped once its task is known through `set_abort`.
+    pub fn register(self: &Arc<Self>, id: u64, peer: Option<SocketAddr>) -> Registration<C> {
+        let last = Arc::new(AtomicU64::new(self.now()));
+        self.lock().insert(
+            id,
+            Conn {
+                last: last.clone(),
+                abort: None,
+                peer,
+            },
+        );
+
+        Registration {
+            reaper: self.clone(),
+            id,
+            activity: Activity {
+                clock: self.clock.clone(),
+                base: self.base,
+                last,
+            },
+        }
+    }
+
+    /// The task serving connection `id`, aborted when it is reaped.
+    pub fn set_abort(&self, id: u64, abort: AbortHandle) {
+        if let Some(conn) = self.lock().get_mut(&id) {
+            conn.abort = Some(abort);
+        }
+    }
+
+    /// Close every connection idle for longer than the threshold, returning their count.
+    pub fn reap(&self) -> usize {
+        let now = self.now();
+        let idle = self.idle.as_millis() as u64;
+        let mut reaped = vec![];
+        {
+            let mut conns = self.lock();
+            conns.retain(|id, conn| {
+                let Some(ref abort) = conn.abort else {
+                    return true;
+                };
+                let last = conn.last.load(Ordering::Relaxed);
+                if now.saturating_sub(last) < idle {
+                    return true;
+                }
+                // fails if the connection moved data since the first read
+                if conn
+                    .last
+                    .compare_exchange(last, REAPED, Ordering::Relaxed, Ordering::Relaxed)
+                    .is_err()
+                {
+                    return true;
+                }
+                abort.abort();
+                reaped.push((*id, conn.peer, now - last));
+                false
+            });
+        }
+
+        for (id, peer, idle) in reaped.iter() {
+            self.stats.record_reaped();
+            log::info!(
+                "connection {} from {:?} reaped after {}ms idle",
+                id,
+                peer,
+                idle
+            );
+        }
+        reaped.len()
+    }
+
+    /// Scan a few times per threshold until the returned task is aborted.
+    pub fn spawn(self: &Arc<Self>) -> ReaperTask {
+        let period = (self.idle / 4).clamp(Duration::from_millis(10), Duration::from_secs(1));
+        let reaper = self.clone();
+        ReaperTask(tokio::spawn(async move {
+            loop {
+                reaper.reap();
+                reaper.clock.sleep(period).await;
+            }
+        }))
+    }
+
+    pub fn len(&self) -> usize {
+        self.lock().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn now(&self) -> u64 {
+        millis_since(self.base, self.clock.now())
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<u64, Conn>> {
+        self.conns.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+fn millis_since(base: Instant, now: Instant) -> u64 {
+    now.saturating_duration_since(base).as_millis() as u64
+}
+
+/// Scan task of a serving server, stopped on drop.
+pub struct ReaperTask(JoinHandle<()>);
+
+impl Drop for ReaperTask {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/// A tracked connection, untracked on drop.
+pub struct Registration<C: Clock = TokioClock> {
+    reaper: Arc<Reaper<C>>,
+    id: u64,
+    activity: Activity<C>,
+}
+
+impl<C: Clock> Registration<C> {
+    pub fn activity(&self) -> Activity<C> {
+        self.activity.clone()
+    }
+}
+
+impl<C: Clock> Drop for Registration<C> {
+    fn drop(&mut self) {
+        self.reaper.lock().remove(&self.id);
+    }
+}
+
+/// Stream recording its reads and writes as activity.
+pub struct IdleStream<S, C = TokioClock> {
+    inner: S,
+    activity: Option<Activity<C>>,
+}
+
+impl<S, C: Clock> IdleStream<S, C> {
+    pub fn new(inner: S, activity: Option<Activity<C>>) -> Self {
+        Self { inner, activity }
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    fn touch(&self) {
+        if let Some(ref activity) = self.activity {
+            activity.touch();
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin, C: Clock> AsyncRead for IdleStream<S, C> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        let filled = buf.filled().len();
+        let res = Pin::new(&mut this.inner).poll_read(cx, buf);
+        if buf.filled().len() > filled {
+            this.touch();
+        }
+        res
+    }
+}
+
+impl<S: AsyncWrite + Unpin, C: Clock> AsyncWrite for IdleStream<S, C> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        let res = Pin::new(&mut this.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = res {
+            if n > 0 {
+                this.touch();
+            }
+        }
+        res
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_reap_idle_only() {
+        let stats = Arc::new(ServerStats::new("127.0.0.1:0".parse().unwrap()));
+        let reaper = Reaper::new(Duration::from_millis(100), stats.clone());
+        let _task = reaper.spawn();
+
+        let mut conns = vec![];
+        for (id, active) in [(1, false), (2, true)] {
+            let (mut client, server) = tokio::io::duplex(64);
+            let registration = reaper.register(id, None);
+            let mut stream = IdleStream::new(server, Some(registration.activity()));
+            let handle = tokio::spawn(async move {
+                let _registration = registration;
+                let mut buf = [0u8; 8];
+                while let Ok(n) = stream.read(&mut buf).await {
+                    if n == 0 {
+                        break;
+                    }
+                    stream.write_all(&buf[..n]).await.unwrap();
+                }
+            });
+            reaper.set_abort(id, handle.abort_handle());
+
+            // the idle client stays open without writing
+            let pinger = if active {
+                tokio::spawn(async move {
+                    let mut buf = [0u8; 1];
+                    loop {
+                        client.write_all(b"p").await.unwrap();
+                        client.read_exact(&mut buf).await.unwrap();
+                        tokio::time::sleep(Duration::from_millis(20)).await;
+                    }
+                })
+            } else {
+                tokio::spawn(async move {
+                    std::future::pending::<()>().await;
+                    drop(client);
+                })
+            };
+            conns.push((handle, pinger));
+        }
+        assert_eq!(reaper.len(), 2);
+
+        tokio::time::sleep(Duration::from_millis(400)).await;
+        let (idle, holder) = conns.remove(0);
+        holder.abort();
+        assert!(idle.await.unwrap_err().is_cancelled());
+        assert!(!conns[0].0.is_finished());
+        assert_eq!(reaper.len(), 1);
+        assert_eq!(stats.snapshot().reaped, 1);
+
+        let (active, pinger) = conns.remove(0);
+        pinger.abort();
+        active.abort();
+        let _ = active.await;
+        assert!(reaper.is_empty());
+    }
+
+    #[test]
+    fn test_reap_check_then_close() {
+        let stats = Arc::new(ServerStats::new("127.0.0.1:0".parse().unwrap()));
+        let reaper = Reaper::new(Duration::ZERO, stats.clone());
+        let registration = reaper.register(1, None);
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        let handle = rt.spawn(std::future::pending::<()>());
+
+        // not reaped before its task is known
+        assert_eq!(reaper.reap(), 0);
+        reaper.set_abort(1, handle.abort_handle());
+
+        // a claimed connection records no more activity
+        assert_eq!(reaper.reap(), 1);
+        registration.activity().touch();
+        assert_eq!(registration.activity().last.load(Ordering::Relaxed), REAPED);
+        assert_eq!(reaper.reap(), 0);
+        assert_eq!(stats.snapshot().reaped, 1);
+        assert!(reaper.is_empty());
+        drop(registration);
+    }
+}
@@ -0,0 +1,268 @@
+//! Recording Client
+
+use std::{
+    fmt,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::Instant,
+};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::{ClientResult, TransportClientTrait};
+
+use super::{
+    DialLog, RecordPolicy, RecordedError, SessionDetails, SessionRecording, StreamEvent, StreamOp,
+    DIAL_LOG,
+};
+
+/// Receives a recording once its connect failed or its stream was dropped.
+pub type RecordingSink = Arc<dyn Fn(SessionRecording) + Send + Sync>;
+
+/// Wraps a client, recording every connect and the stream it returns.
+///
+/// Resolved addresses and attempts are only recorded with the dial hooks
+/// of the wrapped client set through `recording_hooks`.
+pub struct RecordingClient<T> {
+    inner: T,
+    policy: Arc<RecordPolicy>,
+    sink: RecordingSink,
+}
+
+impl<T> RecordingClient<T> {
+    pub fn new(inner: T, policy: RecordPolicy, sink: RecordingSink) -> Self {
+        Self {
+            inner,
+            policy: Arc::new(policy),
+            sink,
+        }
+    }
+
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+}
+
+impl<T> TransportClientTrait for RecordingClient<T>
+where
+    T: TransportClientTrait,
+    T::Stream: SessionDetails,
+{
+    type Stream = RecordingStream<T::Stream>;
+
+    async fn connect(&self) -> ClientResult<Self::Stream> {
+        let started = Instant::now();
+        let log = Arc::new(Mutex::new(DialLog {
+            started: Some(started),
+            ..Default::default()
+        }));
+        let res = DIAL_LOG.scope(log.clone(), self.inner.connect()).await;
+
+        let log = std::mem::take(&mut *log.lock().unwrap_or_else(|e| e.into_inner()));
+        let mut recording = SessionRecording {
+            resolved: log.resolved,
+            attempts: log.attempts,
+            connect_elapsed: started.elapsed(),
+            ..Default::default()
+        };
+        match res {
+            Ok(stream) => {
+                recording.peer_addr = stream.peer_addr();
+                recording.tls = stream.tls_details();
+                let session = Session {
+                    started,
+                    policy: self.policy.clone(),
+                    recording,
+                    read_closed: false,
+                    write_closed: false,
+                };
+                Ok(RecordingStream {
+                    inner: stream,
+                    session,
+                    sink: self.sink.clone(),
+                })
+            }
+            Err(e) => {
+                recording.connect_error = Some(RecordedError::of_client(&e, &self.policy));
+                (self.sink)(recording);
+                Err(e)
+            }
+        }
+    }
+}
+
+/// The recording of one stream while it is used.
+struct Session {
+    started: Instant,
+    policy: Arc<RecordPolicy>,
+    recording: SessionRecording,
+    read_closed: bool,
+    write_closed: bool,
+}
+
+impl Session {
+    fn push(&mut self, op: StreamOp) {
+        let end = matches!(
+            op,
+            StreamOp::Eof | StreamOp::ReadError { .. } | StreamOp::WriteError { .. }
+        );
+        let events = &mut self.recording.events;
+        if events.len() < self.policy.max_events || end {
+            events.push(StreamEvent {
+                at: self.started.elapsed(),
+                op,
+            });
+        } else {
+            self.recording.dropped_events += 1;
+        }
+    }
+
+    fn on_read(&mut self, res: &std::io::Result<()>, data: &[u8], buf_full: bool) {
+        if self.read_closed {
+            return;
+        }
+        match res {
+            Ok(()) if data.is_empty() && !buf_full => {
+                self.read_closed = true;
+                self.push(StreamOp::Eof);
+            }
+            Ok(()) if data.is_empty() => {}
+            Ok(()) => {
+                capture(
+                    &mut self.recording.received,
+                    data,
+                    self.policy.capture_bytes,
+                );
+                self.push(StreamOp::Read { len: data.len() });
+            }
+            Err(e) => {
+                self.read_closed = true;
+                let error = RecordedError::of_io(e, &self.policy);
+                self.push(StreamOp::ReadError { error });
+            }
+        }
+    }
+
+    fn on_write(&mut self, res: &std::io::Result<usize>, buf: &[u8]) {
+        if self.write_closed {
+            return;
+        }
+        match res {
+            Ok(n) => {
+                capture(
+                    &mut self.recording.sent,
+                    &buf[..*n],
+                    self.policy.capture_bytes,
+                );
+                self.push(StreamOp::Write { len: *n });
+            }
+            Err(e) => self.on_write_error(e),
+        }
+    }
+
+    fn on_write_error(&mut self, e: &std::io::Error) {
+        if !self.write_closed {
+            self.write_closed = true;
+            let error = RecordedError::of_io(e, &self.policy);
+            self.push(StreamOp::WriteError { error });
+        }
+    }
+
+    /// The recording so far, with the captured bytes redacted.
+    fn snapshot(&self) -> SessionRecording {
+        let mut recording = self.recording.clone();
+        self.policy.redact(&mut recording.sent);
+        self.policy.redact(&mut recording.received);
+        recording
+    }
+}
+
+fn capture(captured: &mut Vec<u8>, data: &[u8], cap: usize) {
+    let room = cap.saturating_sub(captured.len());
+    captured.extend_from_slice(&data[..data.len().min(room)]);
+}
+
+/// Stream of a `RecordingClient`, handing its recording to the sink on drop.
+pub struct RecordingStream<S> {
+    inner: S,
+    session: Session,
+    sink: RecordingSink,
+}
+
+impl<S> fmt::Debug for RecordingStream<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RecordingStream")
+            .field("events", &self.session.recording.events.len())
+            .finish()
+    }
+}
+
+impl<S> RecordingStream<S> {
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    /// The recording so far.
+    pub fn recording(&self) -> SessionRecording {
+        self.session.snapshot()
+    }
+}
+
+impl<S> Drop for RecordingStream<S> {
+    fn drop(&mut self) {
+        (self.sink)(self.session.snapshot());
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for RecordingStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        let filled = buf.filled().len();
+        let buf_full = buf.remaining() == 0;
+        let res = std::task::ready!(Pin::new(&mut this.inner).poll_read(cx, buf));
+        this.session
+            .on_read(&res, &buf.filled()[filled..], buf_full);
+        Poll::Ready(res)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for RecordingStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        let res = std::task::ready!(Pin::new(&mut this.inner).poll_write(cx, buf));
+        this.session.on_write(&res, buf);
+        Poll::Ready(res)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        let res = std::task::ready!(Pin::new(&mut this.inner).poll_flush(cx));
+        if let Err(ref e) = res {
+            this.session.on_write_error(e);
+        }
+        Poll::Ready(res)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        let res = std::task::ready!(Pin::new(&mut this.inner).poll_shutdown(cx));
+        match res {
+            Ok(()) if !this.session.write_closed => {
+                this.session.write_closed = true;
+                this.session.push(StreamOp::Shutdown);
+            }
+            Ok(()) => {}
+            Err(ref e) => this.session.on_write_error(e),
+        }
+        Poll::Ready(res)
+    }
+}
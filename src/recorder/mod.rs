@@ -0,0 +1,515 @@
+//! Session Recorder
+//!
+//! `RecordingClient` records what a client did per connect, at the stream
+//! API boundary above tls: the resolved and dialed addresses, the tls
+//! parameters, the leading bytes of each direction and the timing of every
+//! read, write and error. `ReplayServer` plays a recording back as an in
+//! memory peer, so a failure seen in the field can be reproduced locally.
+
+pub mod option;
+pub use option::RecordPolicy;
+
+pub mod client;
+pub use client::{RecordingClient, RecordingSink, RecordingStream};
+
+pub mod replay;
+pub use replay::{ReplayServer, ReplayStream};
+
+use std::{
+    io::{Error, ErrorKind},
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::net::TcpStream as TokioTcpStream;
+
+use crate::{net::DialHooks, tcp::TcpStream, ClientError, TransportClientStream};
+
+/// Everything recorded about one connect and the stream it returned.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SessionRecording {
+    /// Addresses handed to the dialer, seen by `recording_hooks`.
+    pub resolved: Vec<SocketAddr>,
+    /// Connect attempts in order, seen by `recording_hooks`.
+    pub attempts: Vec<RecordedAttempt>,
+    pub peer_addr: Option<SocketAddr>,
+    pub tls: Option<TlsDetails>,
+    /// Time the connect took, successful or not.
+    pub connect_elapsed: Duration,
+    pub connect_error: Option<RecordedError>,
+    pub events: Vec<StreamEvent>,
+    /// Leading bytes written by the client, redacted.
+    #[serde(with = "base64_bytes")]
+    pub sent: Vec<u8>,
+    /// Leading bytes read by the client, redacted.
+    #[serde(with = "base64_bytes")]
+    pub received: Vec<u8>,
+    /// Stream events left out once `max_events` was reached.
+    pub dropped_events: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RecordedAttempt {
+    pub addr: SocketAddr,
+    /// Time since the connect started.
+    pub at: Duration,
+    /// Known for the attempts lost before an address accepted.
+    pub error: Option<String>,
+}
+
+/// Negotiated tls parameters.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TlsDetails {
+    pub version: Option<String>,
+    pub cipher_suite: Option<String>,
+    pub alpn: Option<String>,
+    pub resumed: bool,
+    /// Hex sha256 of the peer's leaf certificate.
+    pub peer_cert_sha256: Option<String>,
+}
+
+/// One operation on the stream, `at` is the time since the connect started.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StreamEvent {
+    pub at: Duration,
+    #[serde(flatten)]
+    pub op: StreamOp,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum StreamOp {
+    Read {
+        len: usize,
+    },
+    Write {
+        len: usize,
+    },
+    Shutdown,
+    /// The peer closed its side, a read returned no bytes.
+    Eof,
+    /// A read failed, nothing is read after it.
+    ReadError {
+        error: RecordedError,
+    },
+    /// A write, flush or shutdown failed, nothing is written after it.
+    WriteError {
+        error: RecordedError,
+    },
+}
+
+/// An error as the application saw it, redacted.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RecordedError {
+    /// `ErrorCode` of the error.
+    pub code: String,
+    /// Io error kind, for io errors.
+    pub kind: Option<String>,
+    /// Message of the io error, or of the whole client error.
+    pub message: String,
+}
+
+impl RecordedError {
+    fn of_io(err: &Error, policy: &RecordPolicy) -> Self {
+        Self {
+            code: crate::ErrorCode::of_io(err).as_str().to_owned(),
+            kind: Some(format!("{:?}", err.kind())),
+            message: policy.redact_str(&err.to_string()),
+        }
+    }
+
+    fn of_client(err: &ClientError, policy: &RecordPolicy) -> Self {
+        match err {
+            ClientError::Io(err) => Self::of_io(err, policy),
+            err => Self {
+                code: err.code().as_str().to_owned(),
+                kind: None,
+                message: policy.redact_str(&err.to_string()),
+            },
+        }
+    }
+
+    /// The io error to replay, kinds unknown to this build become `Other`.
+    pub fn to_io(&self) -> Error {
+        let kind = self
+            .kind
+            .as_deref()
+            .and_then(|name| IO_KINDS.iter().find(|k| format!("{:?}", k) == name))
+            .copied()
+            .unwrap_or(ErrorKind::Other);
+        Error::new(kind, self.message.clone())
+    }
+
+    /// The client error to replay.
+    ///
+    /// Io errors come back as they were, denied and option errors by their
+    /// code, any other error as `ClientError::Connect` with its message.
+    pub fn to_client(&self) -> ClientError {
+        match (self.kind.as_ref(), self.code.as_str()) {
+            (Some(_), _) => ClientError::Io(self.to_io()),
+            (None, "connect.denied") => ClientError::Denied(self.message.clone()),
+            (None, "option.invalid") => ClientError::Option(self.message.clone()),
+            (None, _) => ClientError::Connect(self.message.clone()),
+        }
+    }
+}
+
+const IO_KINDS: &[ErrorKind] = &[
+    ErrorKind::NotFound,
+    ErrorKind::PermissionDenied,
+    ErrorKind::ConnectionRefused,
+    ErrorKind::ConnectionReset,
+    ErrorKind::ConnectionAborted,
+    ErrorKind::NotConnected,
+    ErrorKind::AddrInUse,
+    ErrorKind::AddrNotAvailable,
+    ErrorKind::BrokenPipe,
+    ErrorKind::AlreadyExists,
+    ErrorKind::WouldBlock,
+    ErrorKind::InvalidInput,
+    ErrorKind::InvalidData,
+    ErrorKind::TimedOut,
+    ErrorKind::WriteZero,
+    ErrorKind::Interrupted,
+    ErrorKind::Unsupported,
+    ErrorKind::UnexpectedEof,
+    ErrorKind::OutOfMemory,
+];
+
+/// Connection details of a stream, recorded once it connected.
+pub trait SessionDetails {
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        None
+    }
+
+    fn tls_details(&self) -> Option<TlsDetails> {
+        None
+    }
+}
+
+impl SessionDetails for TokioTcpStream {
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        TokioTcpStream::peer_addr(self).ok()
+    }
+}
+
+impl SessionDetails for TcpStream {
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        TcpStream::peer_addr(self).ok()
+    }
+
+    fn tls_details(&self) -> Option<TlsDetails> {
+        let Self::Tls(s) = self else {
+            return None;
+        };
+        let conn = s.get_ref().1;
+        Some(TlsDetails {
+            version: conn.protocol_version().map(|v| format!("{:?}", v)),
+            cipher_suite: conn
+                .negotiated_cipher_suite()
+                .map(|suite| format!("{:?}", suite.suite())),
+            alpn: self
+                .alpn_protocol()
+                .map(|p| String::from_utf8_lossy(p).into_owned()),
+            resumed: self.is_resumed(),
+            peer_cert_sha256: self
+                .peer_cert_sha256()
+                .map(|hash| hash.iter().map(|b| format!("{:02x}", b)).collect()),
+        })
+    }
+}
+
+/// Tls details only off tcp, as with `TransportClientStream::alpn_protocol`.
+impl SessionDetails for TransportClientStream {
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        match self {
+            Self::Tcp(s) => s.peer_addr().ok(),
+            _ => None,
+        }
+    }
+
+    fn tls_details(&self) -> Option<TlsDetails> {
+        match self {
+            Self::Tcp(s) => s.tls_details(),
+            _ => None,
+        }
+    }
+}
+
+impl SessionDetails for tokio::io::DuplexStream {}
+
+#[cfg(any(test, feature = "test-util"))]
+impl<S: SessionDetails> SessionDetails for crate::chaos::ChaosStream<S> {
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        self.get_ref().peer_addr()
+    }
+
+    fn tls_details(&self) -> Option<TlsDetails> {
+        self.get_ref().tls_details()
+    }
+}
+
+/// Dial progress of the connect running in the current task.
+#[derive(Default)]
+struct DialLog {
+    started: Option<Instant>,
+    resolved: Vec<SocketAddr>,
+    attempts: Vec<RecordedAttempt>,
+}
+
+tokio::task_local! {
+    static DIAL_LOG: Arc<Mutex<DialLog>>;
+}
+
+fn log_dial(f: impl FnOnce(&mut DialLog)) {
+    let _ = DIAL_LOG.try_with(|log| f(&mut log.lock().unwrap_or_else(|e| e.into_inner())));
+}
+
+/// `hooks` also recording the dial into the `RecordingClient` connect
+/// running them, to set on the wrapped tcp or ws client.
+///
+/// Dials outside a recorded connect only run `hooks`.
+pub fn recording_hooks(hooks: DialHooks) -> DialHooks {
+    let DialHooks {
+        on_resolved,
+        on_attempt,
+        on_established,
+        observer,
+    } = hooks;
+    DialHooks {
+        on_resolved: Some(Arc::new(move |addrs: &[SocketAddr]| {
+            let candidates = match on_resolved {
+                Some(ref on_resolved) => on_resolved(addrs)?,
+                None => addrs.to_vec(),
+            };
+            log_dial(|log| log.resolved = candidates.clone());
+            Ok(candidates)
+        })),
+        on_attempt: Some(Arc::new(move |addr| {
+            log_dial(|log| {
+                let at = log.started.map_or(Duration::ZERO, |s| s.elapsed());
+                log.attempts.push(RecordedAttempt {
+                    addr,
+                    at,
+                    error: None,
+                });
+            });
+            if let Some(ref on_attempt) = on_attempt {
+                on_attempt(addr);
+            }
+        })),
+        on_established: Some(Arc::new(move |addr, report| {
+            log_dial(|log| {
+                for (attempt, dialed) in log.attempts.iter_mut().zip(&report.attempts) {
+                    attempt.error = dialed.error.as_ref().map(|e| e.to_string());
+                }
+            });
+            if let Some(ref on_established) = on_established {
+                on_established(addr, report);
+            }
+        })),
+        observer,
+    }
+}
+
+mod base64_bytes {
+    use base64::{engine::general_purpose::STANDARD, Engine};
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&STANDARD.encode(bytes))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        let text = String::deserialize(deserializer)?;
+        STANDARD.decode(text).map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::ErrorKind;
+
+    use tokio::{
+        io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use crate::{
+        chaos::{ChaosOption, ChaosStream},
+        tcp::{TcpClient, TcpClientOption},
+        Resolver, TransportClientTrait,
+    };
+
+    use super::*;
+
+    /// The application under test, 64 byte request and echo exchanges until
+    /// the stream fails.
+    async fn exchanges<S: AsyncRead + AsyncWrite + Unpin>(mut stream: S) -> (usize, Error) {
+        let mut buf = [0u8; 64];
+        for n in 0.. {
+            let mut request = format!("token=1234&n={}", n).into_bytes();
+            request.resize(buf.len(), b' ');
+            if let Err(e) = stream.write_all(&request).await {
+                return (n, e);
+            }
+            if let Err(e) = stream.read_exact(&mut buf).await {
+                return (n, e);
+            }
+        }
+        unreachable!()
+    }
+
+    /// Echo server resetting each connection after about 1000 bytes.
+    async fn flaky_server() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let opt = ChaosOption {
+            seed: Some(7),
+            reset_after: Some(1000),
+            reset_jitter: 200,
+            ..Default::default()
+        };
+        tokio::spawn(async move {
+            for seed in 0.. {
+                let (stream, _) = listener.accept().await.unwrap();
+                let mut stream = ChaosStream::new(stream, opt.clone(), seed);
+                tokio::spawn(async move {
+                    let mut buf = [0u8; 64];
+                    while stream.read_exact(&mut buf).await.is_ok() {
+                        if stream.write_all(&buf).await.is_err() {
+                            break;
+                        }
+                    }
+                });
+            }
+        });
+        addr
+    }
+
+    fn recording_client(
+        addr: SocketAddr,
+        policy: RecordPolicy,
+    ) -> (
+        RecordingClient<TcpClient>,
+        Arc<Mutex<Vec<SessionRecording>>>,
+    ) {
+        let opt = TcpClientOption {
+            addr: addr.ip().to_string(),
+            port: addr.port(),
+            tcp_nodelay: true,
+            connect_timeout: None,
+            connect_deadline: None,
+            attempt_delay: None,
+            resolve_on_connect: false,
+            proxy: None,
+            bind_addr: None,
+            bind_device: None,
+        };
+        let mut cli = TcpClient::init(opt, None, &Resolver::default()).unwrap();
+        cli.set_dial_hooks(recording_hooks(DialHooks::default()));
+        let recordings = Arc::new(Mutex::new(vec![]));
+        let sink_recordings = recordings.clone();
+        let sink: RecordingSink = Arc::new(move |recording| {
+            sink_recordings.lock().unwrap().push(recording);
+        });
+        (RecordingClient::new(cli, policy, sink), recordings)
+    }
+
+    #[tokio::test]
+    async fn test_record_replay() {
+        let addr = flaky_server().await;
+        let policy = RecordPolicy {
+            capture_bytes: 256,
+            ..Default::default()
+        };
+        let (cli, recordings) = recording_client(addr, policy);
+        let (done, err) = exchanges(cli.connect().await.unwrap()).await;
+        assert!(done > 4, "{} exchanges", done);
+
+        let recording = recordings.lock().unwrap().pop().unwrap();
+        assert_eq!(recording.resolved, [addr]);
+        assert_eq!(recording.attempts[0].addr, addr);
+        assert_eq!(recording.peer_addr, Some(addr));
+        assert!(recording.tls.is_none());
+        assert_eq!(recording.sent.len(), 256);
+        assert!(recording.sent.starts_with(b"token=****&n=0 "));
+        assert!(recording.received.starts_with(b"token=****&n=0 "));
+        let end = recording.events.last().unwrap();
+        assert!(matches!(end.op, StreamOp::Eof | StreamOp::ReadError { .. }));
+
+        // replays from its serialized form to the same application error
+        let json = serde_json::to_string(&recording).unwrap();
+        let recording: SessionRecording = serde_json::from_str(&json).unwrap();
+        let mut replay = ReplayServer::new(recording);
+        replay.set_time_scale(0.0);
+        let (replayed, replayed_err) = exchanges(replay.connect().await.unwrap()).await;
+        assert_eq!(replayed, done);
+        assert_eq!(replayed_err.kind(), err.kind());
+        assert_eq!(replayed_err.to_string(), err.to_string());
+
+        // past max_events only the end is kept
+        let policy = RecordPolicy {
+            max_events: 4,
+            ..Default::default()
+        };
+        let (cli, recordings) = recording_client(addr, policy);
+        exchanges(cli.connect().await.unwrap()).await;
+        let recording = recordings.lock().unwrap().pop().unwrap();
+        assert_eq!(recording.events.len(), 5);
+        assert!(recording.dropped_events > 0);
+    }
+
+    #[tokio::test]
+    async fn test_replay_connect_error() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+
+        let (cli, recordings) = recording_client(addr, RecordPolicy::default());
+        let Err(err) = cli.connect().await else {
+            panic!("connect should be refused");
+        };
+        let recording = recordings.lock().unwrap().pop().unwrap();
+        let error = recording.connect_error.as_ref().unwrap();
+        assert_eq!(error.code, "connect.refused");
+        assert_eq!(error.kind.as_deref(), Some("ConnectionRefused"));
+        assert_eq!(recording.attempts.len(), 1);
+
+        let Err(replayed) = ReplayServer::new(recording).connect().await else {
+            panic!("replayed connect should fail");
+        };
+        assert_eq!(replayed.code(), err.code());
+        assert_eq!(replayed.to_string(), err.to_string());
+    }
+
+    #[test]
+    fn test_redact() {
+        let policy = RecordPolicy::default();
+        let mut data = b"GET / HTTP/1.1\r\nAuthorization: Bearer abc\r\nX-Token:x\r\n".to_vec();
+        policy.redact(&mut data);
+        assert_eq!(
+            data,
+            b"GET / HTTP/1.1\r\nAuthorization: **********\r\nX-Token:*\r\n"
+        );
+
+        let mut data = br#"{"user": "kapi", "password": "hunter2"}"#.to_vec();
+        policy.redact(&mut data);
+        assert_eq!(data, br#"{"user": "kapi", "password": "*******"}"#);
+
+        let message = policy.redact_str("bad query ?a=1&secret=s3cr3t");
+        assert_eq!(message, "bad query ?a=1&secret=******");
+        assert_eq!(
+            RecordedError {
+                code: "io.other".into(),
+                kind: Some("NoSuchKind".into()),
+                message: "x".into(),
+            }
+            .to_io()
+            .kind(),
+            ErrorKind::Other
+        );
+    }
+}
@@ -0,0 +1,76 @@
+//! Recording Policy
+
+use serde::{Deserialize, Serialize};
+
+/// Bytes kept per direction by default.
+pub const DEFAULT_CAPTURE_BYTES: usize = 4096;
+/// Stream events kept per recording by default.
+pub const DEFAULT_MAX_EVENTS: usize = 1024;
+
+/// What a recording keeps, bounding its size and masking secrets.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, rename_all = "snake_case")]
+pub struct RecordPolicy {
+    /// Leading bytes kept per direction, the rest is recorded by length only.
+    pub capture_bytes: usize,
+    /// Stream events kept, later ones are only counted. The end of each
+    /// direction is always kept.
+    pub max_events: usize,
+    /// Keys whose values are masked in the captured bytes and in error
+    /// messages, matched without case, as in `authorization: <value>` or
+    /// `token=<value>`.
+    pub redact_keys: Vec<String>,
+}
+
+impl Default for RecordPolicy {
+    fn default() -> Self {
+        Self {
+            capture_bytes: DEFAULT_CAPTURE_BYTES,
+            max_events: DEFAULT_MAX_EVENTS,
+            redact_keys: ["authorization", "cookie", "password", "secret", "token"]
+                .map(String::from)
+                .to_vec(),
+        }
+    }
+}
+
+impl RecordPolicy {
+    /// Mask the value after every redacted key in `data`, keeping its length.
+    ///
+    /// A value starts after the `:`, `=`, quotes and blanks following the
+    /// key and ends at a line break or the next `&`, `;`, `,` or `"`.
+    pub fn redact(&self, data: &mut [u8]) {
+        for key in &self.redact_keys {
+            let key = key.as_bytes();
+            if key.is_empty() {
+                continue;
+            }
+            let mut from = 0;
+            while let Some(pos) = find_ignore_case(&data[from..], key) {
+                let mut i = from + pos + key.len();
+                while i < data.len() && matches!(data[i], b':' | b'=' | b'"' | b' ' | b'\t') {
+                    i += 1;
+                }
+                while i < data.len()
+                    && !matches!(data[i], b'\r' | b'\n' | b'&' | b';' | b',' | b'"')
+                {
+                    data[i] = b'*';
+                    i += 1;
+                }
+                from = i;
+            }
+        }
+    }
+
+    pub(crate) fn redact_str(&self, message: &str) -> String {
+        let mut bytes = message.as_bytes().to_vec();
+        self.redact(&mut bytes);
+        // masking only swaps ascii bytes in, cut multi byte chars show lossy
+        String::from_utf8_lossy(&bytes).into_owned()
+    }
+}
+
+fn find_ignore_case(data: &[u8], key: &[u8]) -> Option<usize> {
+    data.windows(key.len())
+        .position(|window| window.eq_ignore_ascii_case(key))
+}
@@ -0,0 +1,205 @@
+//! Recording Replay
+
+use std::{
+    collections::VecDeque,
+    future::Future,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    time::{Instant, Sleep},
+};
+
+use crate::{ClientResult, TransportClientTrait};
+
+use super::{RecordedError, SessionRecording, StreamOp};
+
+/// Scripted peer playing back a `SessionRecording`, connected to in memory.
+///
+/// Every connect replays the recorded connect, its error or a stream
+/// delivering the recorded reads at their recorded times and failing
+/// writes where the recorded ones failed. Bytes past the captured ones are
+/// replayed as zeros, the application's writes are taken and dropped.
+#[derive(Debug, Clone)]
+pub struct ReplayServer {
+    recording: Arc<SessionRecording>,
+    time_scale: f64,
+}
+
+impl ReplayServer {
+    pub fn new(recording: SessionRecording) -> Self {
+        Self {
+            recording: Arc::new(recording),
+            time_scale: 1.0,
+        }
+    }
+
+    /// Stretch the recorded timing, `0.0` replays without waiting.
+    pub fn set_time_scale(&mut self, scale: f64) {
+        self.time_scale = scale.max(0.0);
+    }
+}
+
+impl TransportClientTrait for ReplayServer {
+    type Stream = ReplayStream;
+
+    async fn connect(&self) -> ClientResult<Self::Stream> {
+        let started = Instant::now();
+        let elapsed = self.recording.connect_elapsed.mul_f64(self.time_scale);
+        tokio::time::sleep(elapsed).await;
+        if let Some(ref error) = self.recording.connect_error {
+            return Err(error.to_client());
+        }
+        Ok(ReplayStream::new(
+            self.recording.clone(),
+            started,
+            self.time_scale,
+        ))
+    }
+}
+
+enum ReadStep {
+    Data(usize),
+    Eof,
+    Error(RecordedError),
+}
+
+/// Stream of a `ReplayServer`.
+///
+/// Reads past the end of the recording stay pending, as the peer sent
+/// nothing more before the stream was dropped.
+pub struct ReplayStream {
+    recording: Arc<SessionRecording>,
+    started: Instant,
+    time_scale: f64,
+    reads: VecDeque<(Duration, ReadStep)>,
+    /// Bytes of the current read step still to deliver.
+    pending: usize,
+    received: usize,
+    /// The read step every later read repeats.
+    read_end: Option<ReadStep>,
+    timer: Option<Pin<Box<Sleep>>>,
+    written: u64,
+    /// Bytes the recording wrote before its write failed, and the error.
+    write_error: Option<(u64, RecordedError)>,
+}
+
+impl ReplayStream {
+    fn new(recording: Arc<SessionRecording>, started: Instant, time_scale: f64) -> Self {
+        let mut reads = VecDeque::new();
+        let mut written = 0;
+        let mut write_error = None;
+        for event in &recording.events {
+            match event.op {
+                StreamOp::Read { len } => reads.push_back((event.at, ReadStep::Data(len))),
+                StreamOp::Eof => reads.push_back((event.at, ReadStep::Eof)),
+                StreamOp::ReadError { ref error } => {
+                    reads.push_back((event.at, ReadStep::Error(error.clone())))
+                }
+                StreamOp::Write { len } => written += len as u64,
+                StreamOp::WriteError { ref error } => {
+                    write_error.get_or_insert((written, error.clone()));
+                }
+                StreamOp::Shutdown => {}
+            }
+        }
+
+        Self {
+            recording,
+            started,
+            time_scale,
+            reads,
+            pending: 0,
+            received: 0,
+            read_end: None,
+            timer: None,
+            written: 0,
+            write_error,
+        }
+    }
+
+    /// Wait for the next read step to be due.
+    fn poll_due(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        let Some((at, _)) = self.reads.front() else {
+            return Poll::Pending;
+        };
+        let due = self.started + at.mul_f64(self.time_scale);
+        if due <= Instant::now() {
+            self.timer = None;
+            return Poll::Ready(());
+        }
+        let timer = self
+            .timer
+            .get_or_insert_with(|| Box::pin(tokio::time::sleep_until(due)));
+        timer.as_mut().poll(cx)
+    }
+
+    fn fill(&mut self, buf: &mut ReadBuf<'_>) {
+        let n = self.pending.min(buf.remaining());
+        let captured = &self.recording.received;
+        let from = self.received.min(captured.len());
+        let to = (self.received + n).min(captured.len());
+        buf.put_slice(&captured[from..to]);
+        buf.put_slice(&vec![0; n - (to - from)]);
+        self.pending -= n;
+        self.received += n;
+    }
+}
+
+impl AsyncRead for ReplayStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            if this.pending > 0 {
+                this.fill(buf);
+                return Poll::Ready(Ok(()));
+            }
+            match this.read_end {
+                Some(ReadStep::Error(ref error)) => return Poll::Ready(Err(error.to_io())),
+                Some(_) => return Poll::Ready(Ok(())),
+                None => {}
+            }
+            std::task::ready!(this.poll_due(cx));
+            match this.reads.pop_front().map(|(_, step)| step) {
+                Some(ReadStep::Data(len)) => this.pending = len,
+                Some(step) => this.read_end = Some(step),
+                None => return Poll::Pending,
+            }
+        }
+    }
+}
+
+impl AsyncWrite for ReplayStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        let mut n = buf.len() as u64;
+        if let Some((limit, ref error)) = this.write_error {
+            if this.written >= limit {
+                return Poll::Ready(Err(error.to_io()));
+            }
+            n = n.min(limit - this.written);
+        }
+        this.written += n;
+        Poll::Ready(Ok(n as usize))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
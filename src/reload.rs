@@ -0,0 +1,230 @@
+//! Live Option Reload
+//!
+//! `apply` on `TransportClient` and `TransportServer` builds the new option
+//! in full before touching the running transport, so an option failing
+//! validation changes nothing. The parts read per connection are then
+//! swapped in, streams already open keep the settings they started with.
+
+use serde::Serialize;
+use serde_json::Value;
+
+/// Outcome of an `apply`, as dotted paths into the option.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct AppliedChanges {
+    /// Changes in effect from the next connection on.
+    pub applied: Vec<String>,
+    /// Changes ignored until the transport is recreated.
+    pub requires_restart: Vec<String>,
+}
+
+impl AppliedChanges {
+    pub fn is_empty(&self) -> bool {
+        self.applied.is_empty() && self.requires_restart.is_empty()
+    }
+
+    /// A change of the transport kind, nothing else is compared.
+    pub(crate) fn kind_changed() -> Self {
+        Self {
+            applied: vec![],
+            requires_restart: vec!["opt".into()],
+        }
+    }
+
+    /// Split `paths` into those under one of `restart` and the rest.
+    pub(crate) fn split(paths: Vec<String>, restart: &[&str]) -> Self {
+        let (requires_restart, applied) = paths
+            .into_iter()
+            .partition(|path| restart.iter().any(|prefix| is_under(path, prefix)));
+        Self {
+            applied,
+            requires_restart,
+        }
+    }
+}
+
+fn is_under(path: &str, prefix: &str) -> bool {
+    path.strip_prefix(prefix)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with('.'))
+}
+
+/// Dotted paths of the fields differing between `old` and `new`.
+///
+/// Fields skipped by serde, like hooks, never show. Secrets compare by
+/// their source, not their value.
+pub(crate) fn changed_paths<T: Serialize>(old: &T, new: &T) -> Vec<String> {
+    let (Ok(old), Ok(new)) = (serde_json::to_value(old), serde_json::to_value(new)) else {
+        return vec![];
+    };
+    let mut paths = vec![];
+    diff("", &old, &new, &mut paths);
+    paths
+}
+
+fn diff(path: &str, old: &Value, new: &Value, paths: &mut Vec<String>) {
+    match (old, new) {
+        (Value::Object(old), Value::Object(new)) => {
+            let mut keys = old.keys().chain(new.keys()).collect::<Vec<_>>();
+            keys.sort();
+            keys.dedup();
+            for key in keys {
+                let path = match path {
+                    "" => key.clone(),
+                    _ => format!("{}.{}", path, key),
+                };
+                let old = old.get(key).unwrap_or(&Value::Null);
+                let new = new.get(key).unwrap_or(&Value::Null);
+                diff(&path, old, new, paths);
+            }
+        }
+        (old, new) if old != new => paths.push(path.to_owned()),
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::ErrorKind, net::SocketAddr, sync::Arc, time::Duration};
+
+    use serde_json::json;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpSocket, TcpStream},
+    };
+
+    use crate::{
+        ClientError, TransportClient, TransportClientTrait, TransportServer, TransportServerTrait,
+    };
+
+    use super::*;
+
+    #[derive(Clone)]
+    struct EchoCallback;
+
+    impl crate::TransportServerCallback for EchoCallback {
+        async fn handle<S>(&self, mut stream: S, _addr: Option<SocketAddr>)
+        where
+            S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + Sync,
+        {
+            let mut buf = [0u8; 4];
+            while stream.read_exact(&mut buf).await.is_ok() {
+                if stream.write_all(&buf).await.is_err() {
+                    break;
+                }
+            }
+        }
+    }
+
+    /// A listener with a full accept queue, connects to it hang.
+    async fn blackhole() -> (TcpListener, Vec<TcpStream>, SocketAddr) {
+        let full = TcpSocket::new_v4().unwrap();
+        full.bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let full = full.listen(0).unwrap();
+        let addr = full.local_addr().unwrap();
+        let mut queued = vec![];
+        for _ in 0..16 {
+            let connect = TcpStream::connect(addr);
+            match tokio::time::timeout(Duration::from_millis(100), connect).await {
+                Ok(stream) => queued.push(stream.unwrap()),
+                Err(_) => break,
+            }
+        }
+        (full, queued, addr)
+    }
+
+    fn client_option(opt: serde_json::Value) -> crate::TransportClientOption {
+        serde_json::from_value(json!({"opt": opt})).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_apply_client() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (_full, _queued, blackhole) = blackhole().await;
+
+        let opt = client_option(json!({"tcp": {"addr": "127.0.0.1", "port": port}}));
+        let cli = TransportClient::init_with_default_resolver(opt.clone()).unwrap();
+        cli.connect().await.unwrap();
+        assert!(cli.apply(opt).unwrap().is_empty());
+
+        let timeout = json!({"secs": 0, "nanos": 100_000_000});
+        let opt = client_option(json!({"tcp": {
+            "addr": "127.0.0.1",
+            "port": blackhole.port(),
+            "connect_timeout": timeout,
+        }}));
+        let changes = cli.apply(opt).unwrap();
+        assert_eq!(changes.applied, ["opt.tcp.connect_timeout", "opt.tcp.port"]);
+        assert!(changes.requires_restart.is_empty());
+        let connect_timed_out = || async {
+            let connect = tokio::time::timeout(Duration::from_secs(2), cli.connect());
+            let Err(ClientError::Io(e)) = connect.await.unwrap() else {
+                panic!("connect should time out");
+            };
+            assert_eq!(e.kind(), ErrorKind::TimedOut);
+        };
+        connect_timed_out().await;
+
+        // an option failing to build leaves the client as it was
+        let mut bad = client_option(json!({"tcp": {"addr": "127.0.0.1", "port": port}}));
+        bad.tls = serde_json::from_value(json!({
+            "server_name": "localhost",
+            "certificate": {"file": {"cert": "/nonexistent.pem", "key": "/nonexistent.pem"}},
+        }))
+        .unwrap();
+        assert!(cli.apply(bad).is_err());
+        connect_timed_out().await;
+
+        let ws = client_option(json!({"ws": {"addr": "127.0.0.1", "port": port, "path": "/"}}));
+        assert_eq!(cli.apply(ws).unwrap(), AppliedChanges::kind_changed());
+    }
+
+    #[tokio::test]
+    async fn test_apply_server() {
+        let opt = json!({"opt": {"tcp": {"listen": "127.0.0.1:0"}}});
+        let srv = TransportServer::init(serde_json::from_value(opt).unwrap()).unwrap();
+        let srv = Arc::new(srv);
+        tokio::spawn({
+            let srv = srv.clone();
+            async move { srv.serve(EchoCallback).await }
+        });
+        let addr = srv.local_addr().unwrap();
+
+        let opt = json!({"opt": {"tcp": {"listen": "127.0.0.1:1", "tcp_nodelay": true}}});
+        let changes = srv.apply(serde_json::from_value(opt).unwrap()).unwrap();
+        assert_eq!(changes.applied, ["opt.tcp.tcp_nodelay"]);
+        assert_eq!(changes.requires_restart, ["opt.tcp.listen"]);
+
+        // the running listener keeps serving, now with nodelay
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(b"ping").await.unwrap();
+        let mut buf = [0u8; 4];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+    }
+
+    #[test]
+    fn test_changed_paths() {
+        let old = json!({
+            "opt": {"tcp": {"addr": "a.test", "port": 443, "connect_timeout": null}},
+            "tls": null,
+            "alpn": ["h2"],
+        });
+        let new = json!({
+            "opt": {"tcp": {"addr": "a.test", "port": 8443, "connect_timeout": {"secs": 1, "nanos": 0}}},
+            "tls": {"server_name": "a.test"},
+            "alpn": ["h2", "http/1.1"],
+        });
+        assert_eq!(
+            changed_paths(&old, &new),
+            ["alpn", "opt.tcp.connect_timeout", "opt.tcp.port", "tls"]
+        );
+        assert!(changed_paths(&old, &old).is_empty());
+
+        let changes = AppliedChanges::split(
+            changed_paths(&old, &new),
+            &["opt.tcp.port", "opt.tcp.connect"],
+        );
+        assert_eq!(changes.requires_restart, ["opt.tcp.port"]);
+        assert_eq!(changes.applied, ["alpn", "opt.tcp.connect_timeout", "tls"]);
+    }
+}
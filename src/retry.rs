@@ -0,0 +1,283 @@
+//! Connect Retry
+//!
+//! Retry a whole connect, dial, tls and upgrade, with exponential backoff.
+//! `ClientError::is_retryable` tells transient failures from ones that
+//! fail again the same way, which abort at once.
+
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    clock::{Clock, TokioClock},
+    ClientError, ClientResult, TransportClientTrait,
+};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, rename_all = "snake_case")]
+pub struct RetryOption {
+    /// Connects tried in all, 1 does not retry.
+    pub max_attempts: u32,
+    /// Wait before the first retry, doubling for each further one.
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    /// Share of a backoff added or taken at random, 0.0 to 1.0.
+    pub jitter: f64,
+    pub retry_on: RetryOn,
+}
+
+impl Default for RetryOption {
+    fn default() -> Self {
+        Self {
+            max_attempts: 1,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(5),
+            jitter: 0.2,
+            retry_on: RetryOn::default(),
+        }
+    }
+}
+
+/// Which failures are retried.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RetryOn {
+    /// Errors `ClientError::is_retryable` accepts.
+    #[default]
+    Retryable,
+    /// Errors of these `ErrorCode` strings, like `connect.refused`.
+    Codes(Vec<String>),
+}
+
+impl RetryOption {
+    fn retries(&self, err: &ClientError) -> bool {
+        match self.retry_on {
+            RetryOn::Retryable => err.is_retryable(),
+            RetryOn::Codes(ref codes) => codes.iter().any(|code| code == err.code().as_str()),
+        }
+    }
+
+    /// Wait after the failed `attempt`, counted from 1, before the next.
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let doubled = self
+            .initial_backoff
+            .saturating_mul(1 << attempt.saturating_sub(1).min(31));
+        let backoff = doubled.min(self.max_backoff);
+        let jitter = self.jitter.clamp(0.0, 1.0);
+        if jitter == 0.0 {
+            return backoff;
+        }
+
+        let mut bytes = [0u8; 4];
+        if aws_lc_rs::rand::fill(&mut bytes).is_err() {
+            return backoff;
+        }
+        // uniform in -1.0..=1.0
+        let unit = u32::from_le_bytes(bytes) as f64 / u32::MAX as f64 * 2.0 - 1.0;
+        backoff.mul_f64(1.0 + jitter * unit)
+    }
+}
+
+/// Connect with `client`, retrying as `opt` allows.
+///
+/// A non retried error is returned as is. Once the attempts run out, the
+/// error of the last one is returned with every attempt listed.
+pub async fn connect_with_retry<C: TransportClientTrait>(
+    client: &C,
+    opt: &RetryOption,
+) -> ClientResult<C::Stream> {
+    connect_with_retry_clock(client, opt, &TokioClock).await
+}
+
+/// Like `connect_with_retry`, waiting out the backoff on `clock`.
+pub async fn connect_with_retry_clock<C, K>(
+    client: &C,
+    opt: &RetryOption,
+    clock: &K,
+) -> ClientResult<C::Stream>
+where
+    C: TransportClientTrait,
+    K: Clock,
+{
+    let mut failures = vec![];
+    let mut attempt = 0;
+    loop {
+        attempt += 1;
+        let err = match client.connect().await {
+            Ok(stream) => return Ok(stream),
+            Err(err) => err,
+        };
+        if !opt.retries(&err) {
+            return Err(err);
+        }
+        failures.push(format!("attempt {}: {}", attempt, err));
+        if attempt >= opt.max_attempts {
+            if failures.len() == 1 {
+                return Err(err);
+            }
+            return Err(exhausted(err, &failures));
+        }
+
+        let backoff = opt.backoff(attempt);
+        log::debug!(
+            "connect attempt {} failed ({}), retry in {:?}",
+            attempt,
+            err,
+            backoff
+        );
+        clock.sleep(backoff).await;
+    }
+}
+
+/// The last error carrying the summary, keeping its kind and so its code.
+fn exhausted(last: ClientError, failures: &[String]) -> ClientError {
+    let summary = format!(
+        "{} attempts failed: {}",
+        failures.len(),
+        failures.join("; ")
+    );
+    match last {
+        ClientError::Io(e) => ClientError::Io(std::io::Error::new(e.kind(), summary)),
+        _ => ClientError::Connect(summary),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicU32, Ordering},
+        Mutex,
+    };
+
+    use tokio::io::Empty;
+
+    use super::*;
+    use crate::chaos::{ChaosClient, ChaosOption};
+
+    /// Fails with the queued errors, then connects.
+    struct Flaky {
+        errors: Mutex<Vec<ClientError>>,
+        attempts: AtomicU32,
+    }
+
+    impl Flaky {
+        fn new(errors: Vec<ClientError>) -> Self {
+            Self {
+                errors: Mutex::new(errors),
+                attempts: AtomicU32::new(0),
+            }
+        }
+    }
+
+    impl TransportClientTrait for Flaky {
+        type Stream = Empty;
+
+        async fn connect(&self) -> ClientResult<Empty> {
+            self.attempts.fetch_add(1, Ordering::Relaxed);
+            match self.errors.lock().unwrap().pop() {
+                Some(err) => Err(err),
+                None => Ok(tokio::io::empty()),
+            }
+        }
+    }
+
+    fn refused() -> ClientError {
+        std::io::Error::from(std::io::ErrorKind::ConnectionRefused).into()
+    }
+
+    fn retry(max_attempts: u32) -> RetryOption {
+        RetryOption {
+            max_attempts,
+            initial_backoff: Duration::from_millis(1),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_retry() {
+        let client = Flaky::new(vec![refused(), refused()]);
+        assert!(connect_with_retry(&client, &retry(3)).await.is_ok());
+        assert_eq!(client.attempts.load(Ordering::Relaxed), 3);
+
+        // exhausted, the code of the last error is kept
+        let client = Flaky::new(vec![refused(), refused(), refused()]);
+        let err = connect_with_retry(&client, &retry(2)).await.err().unwrap();
+        assert_eq!(err.code().as_str(), "connect.refused");
+        assert!(
+            err.to_string().contains("2 attempts failed: attempt 1: "),
+            "{}",
+            err
+        );
+
+        // fatal errors abort at once
+        let client = Flaky::new(vec![ClientError::Option("bad".into()), refused()]);
+        let err = connect_with_retry(&client, &retry(5)).await.err().unwrap();
+        assert!(matches!(err, ClientError::Option(_)));
+        assert_eq!(client.attempts.load(Ordering::Relaxed), 2);
+
+        let only_timeouts = RetryOption {
+            retry_on: RetryOn::Codes(vec!["connect.timeout".into()]),
+            ..retry(5)
+        };
+        let client = Flaky::new(vec![refused()]);
+        assert!(connect_with_retry(&client, &only_timeouts).await.is_err());
+        assert_eq!(client.attempts.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn test_retry_chaos_connect() {
+        let chaos = |connect_failure| ChaosOption {
+            seed: Some(7),
+            connect_failure,
+            ..Default::default()
+        };
+
+        // half the connects fail, retry reaches the inner client once
+        let client = ChaosClient::new(Flaky::new(vec![]), chaos(0.5));
+        assert!(connect_with_retry(&client, &retry(20)).await.is_ok());
+        assert_eq!(client.get_ref().attempts.load(Ordering::Relaxed), 1);
+
+        // every connect fails, the chaos error is the one reported
+        let client = ChaosClient::new(Flaky::new(vec![]), chaos(1.0));
+        let err = connect_with_retry(&client, &retry(3)).await.err().unwrap();
+        assert!(err.to_string().contains("3 attempts failed"), "{}", err);
+        assert!(err.to_string().contains("chaos connect failure"), "{}", err);
+        assert_eq!(client.get_ref().attempts.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn test_backoff() {
+        let opt = RetryOption {
+            max_attempts: 10,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(1),
+            jitter: 0.0,
+            retry_on: RetryOn::Retryable,
+        };
+        let backoffs = (1..=6)
+            .map(|n| opt.backoff(n).as_millis())
+            .collect::<Vec<_>>();
+        assert_eq!(backoffs, [100, 200, 400, 800, 1000, 1000]);
+        assert_eq!(opt.backoff(100), Duration::from_secs(1));
+
+        let jittered = RetryOption { jitter: 0.5, ..opt };
+        for _ in 0..100 {
+            let backoff = jittered.backoff(1);
+            assert!(backoff >= Duration::from_millis(50) && backoff <= Duration::from_millis(150));
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_retry_waits_backoff() {
+        let opt = RetryOption {
+            max_attempts: 4,
+            initial_backoff: Duration::from_secs(1),
+            jitter: 0.0,
+            ..Default::default()
+        };
+        let client = Flaky::new(vec![refused(), refused(), refused()]);
+        let start = tokio::time::Instant::now();
+        assert!(connect_with_retry(&client, &opt).await.is_ok());
+        assert_eq!(start.elapsed(), Duration::from_secs(1 + 2 + 4));
+    }
+}
@@ -0,0 +1,176 @@
+//! Secret Option Values
+
+use std::{path::PathBuf, sync::OnceLock};
+
+use serde::{Deserialize, Serialize, Serializer};
+use thiserror::Error;
+
+use crate::error::ErrorCode;
+
+/// Written instead of the value by `Debug` and `Serialize`.
+pub const REDACTED: &str = "***";
+
+#[derive(Debug, Error)]
+pub enum SecretError {
+    #[error("secret file {0}: {1}")]
+    File(PathBuf, #[source] std::io::Error),
+    #[error("secret env var {0} is not set or not unicode")]
+    Env(String),
+    #[error("secret was redacted on output, the value is not in this config")]
+    Redacted,
+}
+
+impl SecretError {
+    pub fn code(&self) -> ErrorCode {
+        ErrorCode::OptionSecret
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Deserialize)]
+#[serde(untagged)]
+enum Source {
+    Literal(String),
+    File { file: PathBuf },
+    Env { env: String },
+}
+
+/// Key material or a token, inline, `{ file: path }` or `{ env: NAME }`.
+///
+/// Indirections are read on first use and kept, so clones of a resolved
+/// secret never touch the source again. Output is always `"***"`.
+#[derive(Clone, Deserialize)]
+#[serde(try_from = "Source")]
+pub struct Secret {
+    source: Source,
+    value: OnceLock<String>,
+}
+
+impl Secret {
+    pub fn from_file(path: impl Into<PathBuf>) -> Self {
+        Self::new(Source::File { file: path.into() })
+    }
+
+    pub fn from_env(name: impl Into<String>) -> Self {
+        Self::new(Source::Env { env: name.into() })
+    }
+
+    fn new(source: Source) -> Self {
+        Self {
+            source,
+            value: OnceLock::new(),
+        }
+    }
+
+    /// The value, reading the file or env var the first time.
+    pub fn expose(&self) -> Result<&str, SecretError> {
+        if let Some(value) = self.value.get() {
+            return Ok(value);
+        }
+        let value = match self.source {
+            Source::Literal(ref value) => value.clone(),
+            Source::File { ref file } => {
+                std::fs::read_to_string(file).map_err(|e| SecretError::File(file.clone(), e))?
+            }
+            Source::Env { ref env } => {
+                std::env::var(env).map_err(|_| SecretError::Env(env.clone()))?
+            }
+        };
+        Ok(self.value.get_or_init(|| value))
+    }
+}
+
+impl TryFrom<Source> for Secret {
+    type Error = SecretError;
+
+    fn try_from(source: Source) -> Result<Self, Self::Error> {
+        // taking the placeholder as the value would silently replace the secret
+        if source == Source::Literal(REDACTED.to_owned()) {
+            return Err(SecretError::Redacted);
+        }
+        Ok(Self::new(source))
+    }
+}
+
+impl From<String> for Secret {
+    fn from(value: String) -> Self {
+        Self::new(Source::Literal(value))
+    }
+}
+
+impl From<&str> for Secret {
+    fn from(value: &str) -> Self {
+        value.to_owned().into()
+    }
+}
+
+impl PartialEq for Secret {
+    fn eq(&self, other: &Self) -> bool {
+        self.source == other.source
+    }
+}
+
+impl std::fmt::Debug for Secret {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(REDACTED)
+    }
+}
+
+impl Serialize for Secret {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(REDACTED)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_secret_sources() {
+        let literal: Secret = serde_json::from_str(r#""hunter2""#).unwrap();
+        assert_eq!(literal.expose().unwrap(), "hunter2");
+
+        let path = std::env::temp_dir().join(format!("kapibara-secret-{}", std::process::id()));
+        std::fs::write(&path, "from file").unwrap();
+        let json = serde_json::json!({ "file": path });
+        let file: Secret = serde_json::from_value(json).unwrap();
+        assert_eq!(file.expose().unwrap(), "from file");
+        // kept after the source is gone
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(file.clone().expose().unwrap(), "from file");
+
+        std::env::set_var("KAPIBARA_TEST_SECRET", "from env");
+        let env: Secret = serde_json::from_str(r#"{"env": "KAPIBARA_TEST_SECRET"}"#).unwrap();
+        assert_eq!(env.expose().unwrap(), "from env");
+    }
+
+    #[test]
+    fn test_secret_missing() {
+        let file = Secret::from_file("/nonexistent/kapibara/secret");
+        let err = file.expose().unwrap_err();
+        assert!(matches!(err, SecretError::File(..)), "{}", err);
+        assert_eq!(err.code().as_str(), "option.secret");
+
+        let env = Secret::from_env("KAPIBARA_TEST_SECRET_UNSET");
+        assert!(matches!(env.expose().unwrap_err(), SecretError::Env(_)));
+
+        // a redacted config is rejected instead of loading the placeholder
+        let err = serde_json::from_str::<Secret>(r#""***""#).unwrap_err();
+        assert!(err.to_string().contains("redacted"), "{}", err);
+    }
+
+    #[test]
+    fn test_secret_redacted() {
+        let secret = Secret::from("hunter2");
+        assert_eq!(format!("{:?}", secret), "***");
+        assert_eq!(serde_json::to_string(&secret).unwrap(), r#""***""#);
+
+        let file = Secret::from_file("/run/secrets/key");
+        assert_eq!(format!("{:?}", Some(file)), "Some(***)");
+
+        // output is redacted, the value in memory is not
+        let copy = secret.clone();
+        let _ = serde_json::to_string(&copy).unwrap();
+        assert_eq!(copy.expose().unwrap(), "hunter2");
+    }
+}
@@ -1,12 +1,26 @@
 //! Transport Server
-use std::net::SocketAddr;
+use std::{future::Future, net::SocketAddr, time::Duration};
 
+#[cfg(any(test, feature = "test-util"))]
+use crate::memory::MemoryServer;
+#[cfg(unix)]
+use crate::unix::UnixServer;
 use crate::{
+    context::AcceptHook,
+    empty::GeneratorServer,
+    grpc::{GrpcServer, GrpcStream},
+    http2::{H2Server, H2Stream},
+    io::{FairOptions, FairStream, Framed, FramedOptions},
     option::ServerOption,
+    reload::AppliedChanges,
+    stats::{CountedStream, ServerStatsSnapshot},
     stream_traits_enum,
     tcp::{TcpServer, TcpStream},
+    tls::TlsInfo,
+    udp::{UdpPeerStream, UdpServer},
     websocket::{WebSocketServer, WebSocketServerStream},
-    ServerResult, TransportServerCallback, TransportServerOption, TransportServerTrait,
+    ServerError, ServerResult, TlsError, TlsServerOption, TransportServerCallback,
+    TransportServerOption, TransportServerTrait,
 };
 
 macro_rules! transport_server_enum {
@@ -32,10 +46,29 @@ macro_rules! transport_server_enum {
             pub fn name(&self) -> &str {
                 match self {
                     $(
+                        $(#[$item_meta])*
                         $name::$id(_) => stringify!($id),
                     )+
                 }
             }
+
+            pub fn stats(&self) -> ServerStatsSnapshot {
+                match self {
+                    $(
+                        $(#[$item_meta])*
+                        $name::$id(svc) => svc.stats(),
+                    )+
+                }
+            }
+
+            pub fn add_accept_hook(&mut self, hook: AcceptHook) {
+                match self {
+                    $(
+                        $(#[$item_meta])*
+                        $name::$id(svc) => svc.add_accept_hook(hook),
+                    )+
+                }
+            }
         }
 
         impl TransportServerTrait for $name
@@ -43,6 +76,7 @@ macro_rules! transport_server_enum {
             fn local_addr(&self) -> Option<SocketAddr> {
                 match self {
                     $(
+                        $(#[$item_meta])*
                         $name::$id(svc) => svc.local_addr(),
                     )+
                 }
@@ -51,13 +85,33 @@ macro_rules! transport_server_enum {
             async fn serve<C: TransportServerCallback>(&self, callback: C) -> ServerResult<()> {
                 match self {
                     $(
+                        $(#[$item_meta])*
                         $name::$id(svc) => svc.serve(callback).await,
                     )+
                 }
             }
+
+            async fn serve_with_shutdown<C, F>(
+                &self,
+                callback: C,
+                signal: F,
+                grace: Duration,
+            ) -> ServerResult<()>
+            where
+                C: TransportServerCallback,
+                F: Future<Output = ()> + Send + Sync,
+            {
+                match self {
+                    $(
+                        $(#[$item_meta])*
+                        $name::$id(svc) => svc.serve_with_shutdown(callback, signal, grace).await,
+                    )+
+                }
+            }
         }
 
         $(
+            $(#[$item_meta])*
             impl From<$id_ty> for $name {
                 fn from(s: $id_ty) -> $name {
                     $name::$id(s)
@@ -68,9 +122,91 @@ macro_rules! transport_server_enum {
 }
 
 stream_traits_enum! {
+    // wraps the streams above as they are, the tcp one being the largest
+    #[allow(clippy::large_enum_variant)]
     pub enum TransportServerStream {
         Tcp(TcpStream),
         Ws(WebSocketServerStream),
+        Udp(UdpPeerStream),
+        H2(H2Stream),
+        Grpc(GrpcStream),
+    }
+}
+
+impl std::fmt::Debug for TransportServerStream {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let inner: &dyn std::fmt::Debug = match self {
+            Self::Tcp(s) => s,
+            Self::Ws(s) => s,
+            Self::Udp(s) => s,
+            Self::H2(s) => s,
+            Self::Grpc(s) => s,
+        };
+        f.debug_tuple(self.kind()).field(inner).finish()
+    }
+}
+
+impl TransportServerStream {
+    /// Length delimited frames over this stream, see [`Framed`].
+    pub fn framed(self, opt: FramedOptions) -> Framed<Self> {
+        Framed::new(self, opt)
+    }
+
+    /// Share a task with other streams fairly, see [`FairStream`].
+    pub fn fair(self, opt: FairOptions) -> FairStream<Self> {
+        FairStream::new(self, opt)
+    }
+
+    /// Count the bytes moved from here on, see [`CountedStream`].
+    pub fn counted(self) -> CountedStream<Self> {
+        CountedStream::new(self)
+    }
+
+    /// Server name indicated by the client during the tls handshake.
+    pub fn server_name(&self) -> Option<&str> {
+        match self {
+            Self::Tcp(s) => s.server_name(),
+            _ => None,
+        }
+    }
+
+    /// Negotiated alpn protocol.
+    pub fn alpn_protocol(&self) -> Option<&[u8]> {
+        match self {
+            Self::Tcp(s) => s.alpn_protocol(),
+            _ => None,
+        }
+    }
+
+    /// Tls session info, see `TcpStream::tls_info`, `None` off tcp.
+    pub fn tls_info(&self) -> Option<TlsInfo> {
+        match self {
+            Self::Tcp(s) => s.tls_info(),
+            _ => None,
+        }
+    }
+
+    /// Whether the tls session was resumed, always `false` off tcp.
+    pub fn is_resumed(&self) -> bool {
+        match self {
+            Self::Tcp(s) => s.is_resumed(),
+            _ => false,
+        }
+    }
+
+    /// Export keying material (RFC 5705), `None` if the tls session is not reachable.
+    pub fn export_keying_material(
+        &self,
+        label: &[u8],
+        context: Option<&[u8]>,
+        output_len: usize,
+    ) -> Option<Result<Vec<u8>, TlsError>> {
+        match self {
+            Self::Tcp(s) if s.is_tls() => {
+                Some(s.export_keying_material(label, context, output_len))
+            }
+            _ => None,
+        }
     }
 }
 
@@ -78,14 +214,101 @@ transport_server_enum! {
     pub enum TransportServer {
         Tcp(TcpServer),
         Ws(WebSocketServer),
+        Udp(UdpServer),
+        H2(H2Server),
+        Grpc(GrpcServer),
+        Generator(GeneratorServer),
+        #[cfg(any(test, feature = "test-util"))]
+        Memory(MemoryServer),
+        #[cfg(unix)]
+        Unix(UnixServer),
     }
 }
 
 impl TransportServer {
+    /// Build the server, tcp based ones bind their listen address now.
     pub fn init(trans_opt: TransportServerOption) -> ServerResult<Self> {
+        Self::build(trans_opt, true)
+    }
+
+    /// Tcp and ws servers built with `bind` false bind when served.
+    fn build(mut trans_opt: TransportServerOption, bind: bool) -> ServerResult<Self> {
+        trans_opt.apply_latency_profile();
+        trans_opt.ensure_valid()?;
+        trans_opt.enforce_policy()?;
+        let source = trans_opt.clone();
+        let profile = trans_opt.latency_profile;
         match trans_opt.opt {
-            ServerOption::Tcp(opt) => Ok(TcpServer::init(opt, trans_opt.tls)?.into()),
-            ServerOption::Ws(opt) => Ok(WebSocketServer::init(opt, trans_opt.tls)?.into()),
+            ServerOption::Tcp(opt) => {
+                let mut srv = if bind {
+                    TcpServer::init(opt, trans_opt.tls)?
+                } else {
+                    TcpServer::unbound(opt, trans_opt.tls)?
+                };
+                srv.set_latency_profile(profile);
+                srv.set_source(source);
+                Ok(srv.into())
+            }
+            ServerOption::Ws(opt) => {
+                let mut srv = if bind {
+                    WebSocketServer::init(opt, trans_opt.tls)?
+                } else {
+                    WebSocketServer::unbound(opt, trans_opt.tls)?
+                };
+                srv.set_latency_profile(profile);
+                srv.set_source(source);
+                Ok(srv.into())
+            }
+            ServerOption::Udp(opt) => Ok(UdpServer::init(opt, trans_opt.tls)?.into()),
+            ServerOption::H2(opt) => {
+                let mut srv = H2Server::init(opt, trans_opt.tls)?;
+                srv.set_latency_profile(profile);
+                Ok(srv.into())
+            }
+            ServerOption::Grpc(opt) => {
+                let mut srv = GrpcServer::init(opt, trans_opt.tls)?;
+                srv.set_latency_profile(profile);
+                Ok(srv.into())
+            }
+            ServerOption::Generator(opt) => Ok(GeneratorServer::init(opt)?.into()),
+            #[cfg(any(test, feature = "test-util"))]
+            ServerOption::Memory(opt) => Ok(MemoryServer::init(opt)?.into()),
+            #[cfg(unix)]
+            ServerOption::Unix(opt) => Ok(UnixServer::init(opt, trans_opt.tls)?.into()),
+        }
+    }
+
+    /// Rebuild the tls config of a tcp or ws server from `tls_opt`, used
+    /// from the next handshake on. See `TcpServer::reload_tls`.
+    pub fn reload_tls(&self, tls_opt: TlsServerOption) -> Result<(), TlsError> {
+        match self {
+            Self::Tcp(srv) => srv.reload_tls(tls_opt),
+            Self::Ws(srv) => srv.reload_tls(tls_opt),
+            srv => Err(TlsError::InvalidConfig(format!(
+                "{} server does not reload tls",
+                srv.name()
+            ))),
+        }
+    }
+
+    /// Apply `new_opt` to the running server without disturbing the listener
+    /// or open streams.
+    ///
+    /// The new option is built in full first and an error leaves the server
+    /// untouched. Tcp and ws servers take tls and per connection settings
+    /// from the next accept on, what `serve` binds at start is reported as
+    /// requiring a restart. The other transports do not apply options live.
+    pub fn apply(&self, new_opt: TransportServerOption) -> ServerResult<AppliedChanges> {
+        // the running server holds the listen address
+        let next = Self::build(new_opt, false)?;
+        match (self, next) {
+            (Self::Tcp(srv), Self::Tcp(next)) => Ok(srv.apply(next)),
+            (Self::Ws(srv), Self::Ws(next)) => Ok(srv.apply(next)),
+            (srv, next) if srv.name() != next.name() => Ok(AppliedChanges::kind_changed()),
+            (srv, _) => Err(ServerError::Option(format!(
+                "{} server does not apply options live",
+                srv.name()
+            ))),
         }
     }
 }
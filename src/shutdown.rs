@@ -0,0 +1,494 @@
+//! Graceful Shutdown
+//!
+//! Servers track the handler tasks started by `serve_with_shutdown`. Once
+//! the signal fires and the listener is gone, the tasks get a grace period
+//! to finish. The streams of the ones still running are then invalidated:
+//! reads see EOF and writes fail with `BrokenPipe`, and the callbacks get
+//! the kill grace to notice and return. Only the rest are aborted.
+
+use std::{
+    collections::HashMap,
+    io,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use futures_util::{ready, task::AtomicWaker};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    sync::Notify,
+    task::AbortHandle,
+    time::Instant,
+};
+
+use crate::observe::{ServeObserver, ShutdownExit};
+
+/// Time callbacks get to return once their stream was invalidated.
+pub(crate) const DEFAULT_KILL_GRACE: Duration = Duration::from_secs(1);
+
+/// Where the shutdown is, decides how a finishing task is recorded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Phase {
+    Serving,
+    Draining,
+    Invalidated,
+    Done,
+}
+
+struct Task {
+    abort: Option<AbortHandle>,
+    kill: Arc<KillSwitch>,
+}
+
+struct Active {
+    phase: Phase,
+    tasks: HashMap<u64, Task>,
+    exits: Vec<(u64, ShutdownExit)>,
+}
+
+/// Handler tasks of one server, keyed by connection id.
+pub(crate) struct Tasks {
+    active: Mutex<Active>,
+    empty: Notify,
+}
+
+/// How the tasks running at the signal ended, by connection id.
+#[derive(Debug, Default)]
+pub(crate) struct ShutdownReport {
+    pub(crate) exits: Vec<(u64, ShutdownExit)>,
+}
+
+impl ShutdownReport {
+    pub(crate) fn count(&self, exit: ShutdownExit) -> usize {
+        self.exits.iter().filter(|(_, e)| *e == exit).count()
+    }
+
+    /// One debug line for the server if any task had to be cut short, then
+    /// every exit told to `observer`.
+    pub(crate) fn finish(self, transport: &str, observer: Option<&dyn ServeObserver>) {
+        let (invalidated, aborted) = (
+            self.count(ShutdownExit::Invalidated),
+            self.count(ShutdownExit::Aborted),
+        );
+        if invalidated + aborted > 0 {
+            log::debug!(
+                "{} shutdown: {} drained, {} invalidated, {} aborted",
+                transport,
+                self.count(ShutdownExit::Drained),
+                invalidated,
+                aborted
+            );
+        }
+        if let Some(observer) = observer {
+            for (id, exit) in self.exits {
+                observer.on_shutdown_exit(id, exit);
+            }
+        }
+    }
+}
+
+impl Tasks {
+    pub(crate) fn new() -> Arc<Self> {
+        Arc::new(Self {
+            active: Mutex::new(Active {
+                phase: Phase::Serving,
+                tasks: HashMap::new(),
+                exits: vec![],
+            }),
+            empty: Notify::new(),
+        })
+    }
+
+    /// Track the task of connection `id` until the returned guard drops.
+    ///
+    /// Register before spawning, it is only aborted once known through `set_abort`.
+    pub(crate) fn register(self: &Arc<Self>, id: u64) -> TaskGuard {
+        let kill = Arc::new(KillSwitch::default());
+        self.lock().tasks.insert(
+            id,
+            Task {
+                abort: None,
+                kill: kill.clone(),
+            },
+        );
+        TaskGuard {
+            tasks: self.clone(),
+            id,
+            kill,
+        }
+    }
+
+    pub(crate) fn set_abort(&self, id: u64, abort: AbortHandle) {
+        if let Some(task) = self.lock().tasks.get_mut(&id) {
+            task.abort = Some(abort);
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.lock().tasks.len()
+    }
+
+    /// Wait for every task to finish until `grace` passed, then invalidate
+    /// the streams of the rest and wait `kill_grace` more before aborting
+    /// what is still running.
+    pub(crate) async fn shutdown(&self, grace: Duration, kill_grace: Duration) -> ShutdownReport {
+        self.lock().phase = Phase::Draining;
+        if !self.wait_until(Instant::now() + grace).await {
+            {
+                let mut active = self.lock();
+                active.phase = Phase::Invalidated;
+                for task in active.tasks.values() {
+                    task.kill.kill();
+                }
+            }
+
+            if !self.wait_until(Instant::now() + kill_grace).await {
+                let mut active = self.lock();
+                active.phase = Phase::Done;
+                let Active { tasks, exits, .. } = &mut *active;
+                for (id, task) in tasks.iter() {
+                    if let Some(ref abort) = task.abort {
+                        abort.abort();
+                    }
+                    log::warn!(
+                        "connection {} aborted, its callback ignored the dead stream",
+                        id
+                    );
+                    exits.push((*id, ShutdownExit::Aborted));
+                }
+            }
+        }
+
+        let mut active = self.lock();
+        active.phase = Phase::Done;
+        ShutdownReport {
+            exits: std::mem::take(&mut active.exits),
+        }
+    }
+
+    /// Wait until no task is left or `deadline`, true if none is left.
+    async fn wait_until(&self, deadline: Instant) -> bool {
+        loop {
+            // created before the check so a removal in between is not missed
+            let empty = self.empty.notified();
+            if self.len() == 0 {
+                return true;
+            }
+            if tokio::time::timeout_at(deadline, empty).await.is_err() {
+                return false;
+            }
+        }
+    }
+
+    fn remove(&self, id: u64) {
+        let mut active = self.lock();
+        if active.tasks.remove(&id).is_none() {
+            return;
+        }
+        let exit = match active.phase {
+            Phase::Draining => {
+                log::debug!("connection {} exited on drain", id);
+                Some(ShutdownExit::Drained)
+            }
+            Phase::Invalidated => {
+                log::info!("connection {} exited after its stream was invalidated", id);
+                Some(ShutdownExit::Invalidated)
+            }
+            Phase::Serving | Phase::Done => None,
+        };
+        if let Some(exit) = exit {
+            active.exits.push((id, exit));
+        }
+        if active.tasks.is_empty() {
+            self.empty.notify_waiters();
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Active> {
+        self.active.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Keeps a task tracked, held by the task itself.
+pub(crate) struct TaskGuard {
+    tasks: Arc<Tasks>,
+    id: u64,
+    kill: Arc<KillSwitch>,
+}
+
+/// Wrap the stream handed to the callback so the shutdown tracking `guard`
+/// can invalidate it, a stream of an untracked task is never killed.
+pub(crate) fn killable<S>(stream: S, guard: Option<&TaskGuard>) -> Killable<S> {
+    Killable::new(stream, guard.map(|g| g.kill.clone()))
+}
+
+impl Drop for TaskGuard {
+    fn drop(&mut self) {
+        self.tasks.remove(self.id);
+    }
+}
+
+/// Flipped once by the shutdown, waking the reads and writes waiting on it.
+#[derive(Debug, Default)]
+pub(crate) struct KillSwitch {
+    killed: AtomicBool,
+    read: AtomicWaker,
+    write: AtomicWaker,
+}
+
+impl KillSwitch {
+    fn kill(&self) {
+        self.killed.store(true, Ordering::Release);
+        self.read.wake();
+        self.write.wake();
+    }
+
+    fn is_killed(&self) -> bool {
+        self.killed.load(Ordering::Acquire)
+    }
+}
+
+/// A stream the shutdown can invalidate: reads then see EOF and writes fail
+/// with `BrokenPipe`. The polls after the kill first drive the shutdown of
+/// the inner stream to its end, so a tls session flushes its close_notify.
+pub(crate) struct Killable<S> {
+    inner: S,
+    kill: Option<Arc<KillSwitch>>,
+    closed: bool,
+}
+
+impl<S> Killable<S> {
+    pub(crate) fn new(inner: S, kill: Option<Arc<KillSwitch>>) -> Self {
+        Self {
+            inner,
+            kill,
+            closed: false,
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> Killable<S> {
+    /// True once killed and the inner stream closed, registers the read or
+    /// write waker until the kill.
+    fn poll_killed(&mut self, cx: &mut Context<'_>, read: bool) -> Poll<bool> {
+        let Some(ref kill) = self.kill else {
+            return Poll::Ready(false);
+        };
+        if !kill.is_killed() {
+            let waker = if read { &kill.read } else { &kill.write };
+            waker.register(cx.waker());
+            // a kill between the check and the register has woken nobody
+            if !kill.is_killed() {
+                return Poll::Ready(false);
+            }
+        }
+        if !self.closed {
+            // best effort, the stream is dead whatever the shutdown returns
+            let _ = ready!(Pin::new(&mut self.inner).poll_shutdown(cx));
+            self.closed = true;
+        }
+        Poll::Ready(true)
+    }
+}
+
+fn broken_pipe() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::BrokenPipe,
+        "stream invalidated by the server shutdown",
+    )
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for Killable<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if ready!(self.poll_killed(cx, true)) {
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Killable<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        if ready!(self.poll_killed(cx, false)) {
+            return Poll::Ready(Err(broken_pipe()));
+        }
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if ready!(self.poll_killed(cx, false)) {
+            return Poll::Ready(Err(broken_pipe()));
+        }
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if ready!(self.poll_killed(cx, false)) {
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        if ready!(self.poll_killed(cx, false)) {
+            return Poll::Ready(Err(broken_pipe()));
+        }
+        Pin::new(&mut self.inner).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicBool;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_tasks_shutdown() {
+        const GRACE: Duration = Duration::from_millis(200);
+
+        let tasks = Tasks::new();
+        let report = tasks
+            .shutdown(Duration::from_secs(1), DEFAULT_KILL_GRACE)
+            .await;
+        assert!(report.exits.is_empty());
+
+        let tasks = Tasks::new();
+        let cleaned_up = Arc::new(AtomicBool::new(false));
+        let mut peers = vec![];
+        for id in 1..=3 {
+            let (a, b) = tokio::io::duplex(64);
+            peers.push(b);
+            let guard = tasks.register(id);
+            let cleaned_up = cleaned_up.clone();
+            let handle = tokio::spawn(async move {
+                let mut stream = killable(a, Some(&guard));
+                let mut buf = [0u8; 16];
+                match id {
+                    // cooperative, done within the grace period
+                    1 => tokio::time::sleep(Duration::from_millis(50)).await,
+                    // stubborn, reads until the stream dies, then cleans up
+                    2 => {
+                        assert_eq!(stream.read(&mut buf).await.unwrap(), 0);
+                        let err = stream.write_all(b"audit").await.unwrap_err();
+                        assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+                        cleaned_up.store(true, Ordering::SeqCst);
+                    }
+                    // pathological, ignores everything
+                    _ => std::future::pending().await,
+                }
+                drop(guard);
+            });
+            tasks.set_abort(id, handle.abort_handle());
+        }
+        assert_eq!(tasks.len(), 3);
+
+        let start = Instant::now();
+        let mut report = tasks.shutdown(GRACE, DEFAULT_KILL_GRACE).await;
+        assert_eq!(start.elapsed(), GRACE + DEFAULT_KILL_GRACE);
+        report.exits.sort();
+        assert_eq!(
+            report.exits,
+            [
+                (1, ShutdownExit::Drained),
+                (2, ShutdownExit::Invalidated),
+                (3, ShutdownExit::Aborted)
+            ]
+        );
+        assert!(cleaned_up.load(Ordering::SeqCst));
+        // the aborted task drops its guard
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert_eq!(tasks.len(), 0);
+
+        // the stubborn one closed its side before failing
+        let mut buf = vec![];
+        peers[1].read_to_end(&mut buf).await.unwrap();
+        assert!(buf.is_empty());
+    }
+
+    /// Needs a few polls to shut down, like a tls session flushing its close_notify.
+    struct SlowShutdown {
+        inner: tokio::io::DuplexStream,
+        polls: usize,
+        shut: bool,
+    }
+
+    impl AsyncRead for SlowShutdown {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.inner).poll_read(cx, buf)
+        }
+    }
+
+    impl AsyncWrite for SlowShutdown {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            Pin::new(&mut self.inner).poll_write(cx, buf)
+        }
+
+        fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.inner).poll_flush(cx)
+        }
+
+        fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            self.polls += 1;
+            if self.polls < 3 {
+                cx.waker().wake_by_ref();
+                return Poll::Pending;
+            }
+            self.shut = true;
+            Pin::new(&mut self.inner).poll_shutdown(cx)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_killable_finishes_shutdown() {
+        let (a, mut b) = tokio::io::duplex(64);
+        let kill = Arc::new(KillSwitch::default());
+        let inner = SlowShutdown {
+            inner: a,
+            polls: 0,
+            shut: false,
+        };
+        let mut stream = Killable::new(inner, Some(kill.clone()));
+        kill.kill();
+
+        // eof only once the inner shutdown completed
+        assert_eq!(stream.read(&mut [0u8; 4]).await.unwrap(), 0);
+        assert!(stream.inner.shut);
+        assert_eq!(stream.inner.polls, 3);
+        let err = stream.write_all(b"late").await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+        assert_eq!(stream.inner.polls, 3);
+        assert_eq!(b.read(&mut [0u8; 4]).await.unwrap(), 0);
+    }
+}
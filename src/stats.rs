@@ -0,0 +1,391 @@
+//! Server and Stream Statistics
+//!
+//! Fixed per address family counters, no per-peer cardinality, and the
+//! byte counters of single streams.
+
+use std::{
+    net::{IpAddr, SocketAddr},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// Upper bounds of the handshake duration buckets, the last bucket is unbounded.
+pub const HANDSHAKE_BUCKETS: [Duration; 7] = [
+    Duration::from_millis(1),
+    Duration::from_millis(5),
+    Duration::from_millis(10),
+    Duration::from_millis(50),
+    Duration::from_millis(100),
+    Duration::from_millis(500),
+    Duration::from_secs(1),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AddrFamily {
+    Ipv4,
+    Ipv6,
+}
+
+impl AddrFamily {
+    /// Family of a peer, ipv4-mapped ipv6 addresses count as ipv4.
+    pub fn of(addr: &SocketAddr) -> Self {
+        match addr.ip().to_canonical() {
+            IpAddr::V4(_) => Self::Ipv4,
+            IpAddr::V6(_) => Self::Ipv6,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct FamilyStats {
+    accepted: AtomicU64,
+    active: AtomicU64,
+    bytes_read: AtomicU64,
+    bytes_written: AtomicU64,
+    handshake_buckets: [AtomicU64; HANDSHAKE_BUCKETS.len() + 1],
+    handshake_micros: AtomicU64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct FamilyStatsSnapshot {
+    pub accepted: u64,
+    pub active: u64,
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+    /// Handshake counts per `HANDSHAKE_BUCKETS` bound, plus the overflow bucket.
+    pub handshake_buckets: Vec<u64>,
+    pub handshake_total: Duration,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ServerStatsSnapshot {
+    pub listen: SocketAddr,
+    pub ipv4: FamilyStatsSnapshot,
+    pub ipv6: FamilyStatsSnapshot,
+    /// Upgrades admitted but not yet handed to the callback.
+    pub pending_upgrades: u64,
+    /// Connections refused because `max_connections` was reached.
+    pub over_limit: u64,
+    /// Connections closed for being idle longer than `idle_reap`.
+    pub reaped: u64,
+    /// Connections given up on by a ws `slow_consumer_policy`.
+    pub slow_consumers: u64,
+}
+
+impl ServerStatsSnapshot {
+    pub fn family(&self, family: AddrFamily) -> &FamilyStatsSnapshot {
+        match family {
+            AddrFamily::Ipv4 => &self.ipv4,
+            AddrFamily::Ipv6 => &self.ipv6,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ServerStats {
+    listen: SocketAddr,
+    ipv4: FamilyStats,
+    ipv6: FamilyStats,
+    pending_upgrades: AtomicU64,
+    over_limit: AtomicU64,
+    reaped: AtomicU64,
+    slow_consumers: AtomicU64,
+}
+
+impl ServerStats {
+    pub fn new(listen: SocketAddr) -> Self {
+        Self {
+            listen,
+            ipv4: FamilyStats::default(),
+            ipv6: FamilyStats::default(),
+            pending_upgrades: AtomicU64::new(0),
+            over_limit: AtomicU64::new(0),
+            reaped: AtomicU64::new(0),
+            slow_consumers: AtomicU64::new(0),
+        }
+    }
+
+    /// Count an upgrade as pending until the returned guard drops.
+    pub fn pending_upgrade(self: &Arc<Self>) -> PendingUpgrade {
+        self.pending_upgrades.fetch_add(1, Ordering::Relaxed);
+        PendingUpgrade(self.clone())
+    }
+
+    pub fn record_over_limit(&self) {
+        self.over_limit.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_reaped(&self) {
+        self.reaped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_slow_consumer(&self) {
+        self.slow_consumers.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn family(&self, family: AddrFamily) -> &FamilyStats {
+        match family {
+            AddrFamily::Ipv4 => &self.ipv4,
+            AddrFamily::Ipv6 => &self.ipv6,
+        }
+    }
+
+    pub fn record_handshake(&self, peer: &SocketAddr, elapsed: Duration) {
+        let stats = self.family(AddrFamily::of(peer));
+        let bucket = HANDSHAKE_BUCKETS
+            .iter()
+            .position(|bound| elapsed <= *bound)
+            .unwrap_or(HANDSHAKE_BUCKETS.len());
+        stats.handshake_buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        stats
+            .handshake_micros
+            .fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    /// Count an accepted connection, active until the returned stream drops.
+    pub fn track<S>(self: &Arc<Self>, stream: S, peer: &SocketAddr) -> StatsStream<S> {
+        let family = AddrFamily::of(peer);
+        let stats = self.family(family);
+        stats.accepted.fetch_add(1, Ordering::Relaxed);
+        stats.active.fetch_add(1, Ordering::Relaxed);
+
+        StatsStream {
+            inner: stream,
+            stats: self.clone(),
+            family,
+        }
+    }
+
+    pub fn snapshot(&self) -> ServerStatsSnapshot {
+        let snapshot = |stats: &FamilyStats| FamilyStatsSnapshot {
+            accepted: stats.accepted.load(Ordering::Relaxed),
+            active: stats.active.load(Ordering::Relaxed),
+            bytes_read: stats.bytes_read.load(Ordering::Relaxed),
+            bytes_written: stats.bytes_written.load(Ordering::Relaxed),
+            handshake_buckets: stats
+                .handshake_buckets
+                .iter()
+                .map(|n| n.load(Ordering::Relaxed))
+                .collect(),
+            handshake_total: Duration::from_micros(stats.handshake_micros.load(Ordering::Relaxed)),
+        };
+
+        ServerStatsSnapshot {
+            listen: self.listen,
+            ipv4: snapshot(&self.ipv4),
+            ipv6: snapshot(&self.ipv6),
+            pending_upgrades: self.pending_upgrades.load(Ordering::Relaxed),
+            over_limit: self.over_limit.load(Ordering::Relaxed),
+            reaped: self.reaped.load(Ordering::Relaxed),
+            slow_consumers: self.slow_consumers.load(Ordering::Relaxed),
+        }
+    }
+}
+
+pub struct PendingUpgrade(Arc<ServerStats>);
+
+impl Drop for PendingUpgrade {
+    fn drop(&mut self) {
+        self.0.pending_upgrades.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+pub struct StatsStream<S> {
+    inner: S,
+    stats: Arc<ServerStats>,
+    family: AddrFamily,
+}
+
+impl<S> Drop for StatsStream<S> {
+    fn drop(&mut self) {
+        self.stats
+            .family(self.family)
+            .active
+            .fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for StatsStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        let filled = buf.filled().len();
+        let res = Pin::new(&mut this.inner).poll_read(cx, buf);
+        let n = buf.filled().len() - filled;
+        if n > 0 {
+            this.stats
+                .family(this.family)
+                .bytes_read
+                .fetch_add(n as u64, Ordering::Relaxed);
+        }
+        res
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for StatsStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        let res = Pin::new(&mut this.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = res {
+            this.stats
+                .family(this.family)
+                .bytes_written
+                .fetch_add(n as u64, Ordering::Relaxed);
+        }
+        res
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+/// Bytes moved by one stream, shared by its `CountedStream` and any handles.
+#[derive(Debug)]
+pub struct StreamStats {
+    read_bytes: AtomicU64,
+    written_bytes: AtomicU64,
+    established_at: Instant,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StreamStatsSnapshot {
+    pub read_bytes: u64,
+    pub written_bytes: u64,
+    pub established_at: Instant,
+}
+
+impl Default for StreamStats {
+    fn default() -> Self {
+        Self {
+            read_bytes: AtomicU64::new(0),
+            written_bytes: AtomicU64::new(0),
+            established_at: Instant::now(),
+        }
+    }
+}
+
+impl StreamStats {
+    pub fn read_bytes(&self) -> u64 {
+        self.read_bytes.load(Ordering::Relaxed)
+    }
+
+    pub fn written_bytes(&self) -> u64 {
+        self.written_bytes.load(Ordering::Relaxed)
+    }
+
+    /// When the stream was wrapped, after the handshakes of its transport.
+    pub fn established_at(&self) -> Instant {
+        self.established_at
+    }
+
+    pub fn snapshot(&self) -> StreamStatsSnapshot {
+        StreamStatsSnapshot {
+            read_bytes: self.read_bytes(),
+            written_bytes: self.written_bytes(),
+            established_at: self.established_at,
+        }
+    }
+}
+
+/// Counts the bytes read from and written to `inner`.
+///
+/// Counts what the stream hands out and accepts, so tls records and ws
+/// frame headers are not included.
+#[derive(Debug)]
+pub struct CountedStream<S> {
+    inner: S,
+    stats: Arc<StreamStats>,
+}
+
+impl<S> CountedStream<S> {
+    pub fn new(inner: S) -> Self {
+        Self {
+            inner,
+            stats: Arc::default(),
+        }
+    }
+
+    pub fn stats(&self) -> StreamStatsSnapshot {
+        self.stats.snapshot()
+    }
+
+    /// The live counters, still readable after the stream moved or dropped.
+    pub fn stats_handle(&self) -> Arc<StreamStats> {
+        self.stats.clone()
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for CountedStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        let filled = buf.filled().len();
+        let res = Pin::new(&mut this.inner).poll_read(cx, buf);
+        let n = buf.filled().len() - filled;
+        if n > 0 {
+            this.stats.read_bytes.fetch_add(n as u64, Ordering::Relaxed);
+        }
+        res
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for CountedStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        let res = Pin::new(&mut this.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = res {
+            this.stats
+                .written_bytes
+                .fetch_add(n as u64, Ordering::Relaxed);
+        }
+        res
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
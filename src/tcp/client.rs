@@ -1,25 +1,41 @@
 //! Tcp Transport client
 
-use std::{
-    net::{IpAddr, SocketAddr},
-    str::FromStr,
-    sync::Arc,
-};
+use std::{net::SocketAddr, sync::Arc};
 
-use rustls::{pki_types::ServerName, ClientConfig as TlsClientConfig};
+use arc_swap::ArcSwap;
+use rustls::pki_types::ServerName;
 use tokio::net::TcpStream as TokioTcpStream;
 use tokio_rustls::{TlsConnector, TlsStream};
 
 use crate::{
-    ClientError, ClientResult, ResolveError, Resolver, TlsClientOption, TransportClientTrait,
+    net::{
+        proxy::dial_target, BreakerSnapshot, CircuitBreaker, DialAddrs, DialHooks, Dialer,
+        ProxyTunnel,
+    },
+    observe::{self, ConnectObserver, Stage},
+    option::{ClientOption, LatencyProfile, ProbeMode},
+    reload::{changed_paths, AppliedChanges},
+    ClientError, ClientResult, Resolver, TlsClientOption, TransportClientOption,
+    TransportClientTrait,
 };
 
 use super::{TcpClientOption, TcpStream};
 
 pub struct TcpClient {
-    addr: Vec<SocketAddr>,
+    state: ArcSwap<State>,
+}
+
+/// Everything a connect reads, swapped as a whole by `apply`.
+#[derive(Clone)]
+struct State {
+    addr: DialAddrs,
+    /// Set when connecting through a proxy, `addr` is then the proxy's.
+    tunnel: Option<ProxyTunnel>,
     tls_conn: Option<(TlsConnector, ServerName<'static>)>,
-    tcp_nodelay: bool,
+    dialer: Dialer,
+    post_connect_probe: Option<ProbeMode>,
+    /// The option the state was built from, diffed by `apply`.
+    source: Arc<TransportClientOption>,
 }
 
 impl TcpClient {
@@ -28,7 +44,27 @@ impl TcpClient {
         tls_opt: Option<TlsClientOption>,
         resolver: &Resolver,
     ) -> ClientResult<Self> {
-        let tls_conn = if let Some(tls_opt) = tls_opt {
+        let (host, port) = dial_target(opt.proxy.as_ref(), &opt.addr, opt.port);
+        let addr = DialAddrs::new(host, port, opt.resolve_on_connect, resolver)?;
+        Self::with_dial_addrs(opt, tls_opt, addr)
+    }
+
+    /// Init with already resolved addresses of `opt.addr`, or of the proxy
+    /// when `opt.proxy` is set.
+    pub fn with_addrs(
+        opt: TcpClientOption,
+        tls_opt: Option<TlsClientOption>,
+        addr: Vec<SocketAddr>,
+    ) -> ClientResult<Self> {
+        Self::with_dial_addrs(opt, tls_opt, DialAddrs::Resolved(addr))
+    }
+
+    fn with_dial_addrs(
+        opt: TcpClientOption,
+        tls_opt: Option<TlsClientOption>,
+        addr: DialAddrs,
+    ) -> ClientResult<Self> {
+        let tls_conn = if let Some(ref tls_opt) = tls_opt {
             let server_name = ServerName::try_from(if tls_opt.server_name.is_empty() {
                 opt.addr.clone()
             } else {
@@ -36,61 +72,185 @@ impl TcpClient {
             })
             .map_err(|e| ClientError::Option(e.to_string()))?;
 
-            let config: TlsClientConfig = tls_opt.try_into()?;
-            let conn = TlsConnector::from(Arc::new(config));
+            let conn =
+                TlsConnector::from(tls_opt.client_config()?).early_data(tls_opt.enable_early_data);
             Some((conn, server_name))
         } else {
             None
         };
 
-        let addr = match IpAddr::from_str(&opt.addr) {
-            Ok(ip) => vec![(ip, opt.port).into()],
-            Err(_) => {
-                let res = resolver.block_resolve(opt.addr, opt.port)?;
-                res.collect()
-            }
-        };
-
         if addr.is_empty() {
             return Err(ClientError::Option("unknown address".to_owned()));
         }
 
+        let tunnel = match opt.proxy {
+            Some(ref proxy) => Some(ProxyTunnel::new(proxy.clone(), &opt.addr, opt.port)?),
+            None => None,
+        };
+
+        let mut dialer = Dialer::new();
+        dialer.set_tcp_nodelay(opt.tcp_nodelay);
+        dialer.set_connect_timeout(opt.connect_timeout);
+        dialer.set_connect_deadline(opt.connect_deadline);
+        dialer.set_bind(opt.bind_addr, opt.bind_device.clone())?;
+        dialer.set_attempt_delay(opt.attempt_delay);
+
+        let source = TransportClientOption {
+            opt: ClientOption::Tcp(opt),
+            tls: tls_opt,
+            ..Default::default()
+        };
         Ok(Self {
-            addr,
-            tls_conn,
-            tcp_nodelay: opt.tcp_nodelay,
+            state: ArcSwap::from_pointee(State {
+                addr,
+                tunnel,
+                tls_conn,
+                dialer,
+                post_connect_probe: None,
+                source: Arc::new(source),
+            }),
         })
     }
+
+    fn update(&mut self, f: impl FnOnce(&mut State)) {
+        let mut state = State::clone(&self.state.load());
+        f(&mut state);
+        self.state.store(Arc::new(state));
+    }
+
+    /// The option `apply` diffs against, by default the tcp and tls options.
+    pub(crate) fn set_source(&mut self, source: TransportClientOption) {
+        self.update(|state| state.source = Arc::new(source));
+    }
+
+    /// Swap in the state of `next`, built from the new option. Dial hooks
+    /// are set in code and stay, so do the breakers of an unchanged option.
+    pub(crate) fn apply(&self, next: TcpClient) -> AppliedChanges {
+        let current = self.state.load_full();
+        let mut next = State::clone(&next.state.load());
+        next.dialer.set_hooks(current.dialer.hooks().clone());
+        if current.source.breaker == next.source.breaker {
+            next.dialer.set_breaker(current.dialer.breaker().cloned());
+        }
+        let paths = changed_paths(&*current.source, &*next.source);
+        self.state.store(Arc::new(next));
+        AppliedChanges::split(paths, &[])
+    }
+
+    /// Probe each new connection, only `ExpectServerBytes` applies to tcp.
+    pub fn set_post_connect_probe(&mut self, probe: Option<ProbeMode>) -> ClientResult<()> {
+        if let Some(ProbeMode::WsPing { .. }) = probe {
+            return Err(ClientError::Option(
+                "ws_ping probe needs a ws transport".to_owned(),
+            ));
+        }
+        self.update(|state| state.post_connect_probe = probe);
+        Ok(())
+    }
+
+    pub fn set_dial_hooks(&mut self, hooks: DialHooks) {
+        self.update(|state| state.dialer.set_hooks(hooks));
+    }
+
+    /// Fail connects fast to destinations that keep failing, `breaker` may
+    /// be shared with other clients.
+    pub fn set_breaker(&mut self, breaker: Option<Arc<CircuitBreaker>>) {
+        self.update(|state| state.dialer.set_breaker(breaker));
+    }
+
+    /// The breakers dialed through so far, empty without a breaker.
+    pub fn breaker_state(&self) -> Vec<BreakerSnapshot> {
+        let state = self.state.load();
+        state
+            .dialer
+            .breaker()
+            .map(|breaker| breaker.state())
+            .unwrap_or_default()
+    }
+
+    /// Report the stages of each connect to `observer`, kept in `DialHooks::observer`.
+    pub fn set_connect_observer(&mut self, observer: Arc<dyn ConnectObserver>) {
+        self.update(|state| {
+            let mut hooks = state.dialer.hooks().clone();
+            hooks.observer = Some(observer);
+            state.dialer.set_hooks(hooks);
+        });
+    }
+
+    /// Socket settings of the profile, the options are overridden by `apply_latency_profile`.
+    pub fn set_latency_profile(&mut self, profile: LatencyProfile) {
+        self.update(|state| {
+            state
+                .dialer
+                .set_send_buffer_size(profile.send_buffer_size());
+            state.dialer.set_busy_poll(profile.busy_poll());
+        });
+    }
+
+    /// Run the tls and probe steps over a connected `stream`, skipping
+    /// resolution, dialing and the proxy. Socket options are left as they are.
+    pub async fn connect_over(&self, stream: TokioTcpStream) -> ClientResult<TcpStream> {
+        let state = self.state.load_full();
+        let mut stream = state.layer(stream).await?;
+        state.probe(&mut stream).await?;
+        Ok(stream)
+    }
 }
 
-impl TransportClientTrait for TcpClient {
-    type Stream = TcpStream;
+impl State {
+    async fn connect(&self) -> ClientResult<TcpStream> {
+        let addrs = self
+            .dialer
+            .candidates(&self.dialer.resolve(&self.addr).await?)?;
+        let mut rest = &addrs[..];
+        loop {
+            let (mut s, report) = self.dialer.dial(rest).await?;
+            let addr = report.winner().expect("dial reports its winner last");
+            // every address up to the last one started was tried
+            rest = &rest[report.attempts.len()..];
 
-    async fn connect(&self) -> ClientResult<Self::Stream> {
-        let mut err = None;
-        for addr in self.addr.iter() {
-            match TokioTcpStream::connect(addr).await {
-                Ok(s) => {
-                    if self.tcp_nodelay {
-                        let _ = s.set_nodelay(true);
-                    }
-                    let stream = if let Some((ref tls_conn, ref server_name)) = self.tls_conn {
-                        let stream = tls_conn.connect(server_name.clone(), s).await?;
-                        TcpStream::Tls(TlsStream::Client(stream))
-                    } else {
-                        TcpStream::Raw(s)
-                    };
-
-                    return Ok(stream);
+            if let Some(ref tunnel) = self.tunnel {
+                tunnel.open(&mut s).await?;
+            }
+
+            let mut stream = self.layer(s).await?;
+            if let Err(e) = self.probe(&mut stream).await {
+                log::debug!("tcp connection to {} {}", addr, e);
+                if rest.is_empty() {
+                    return Err(e);
                 }
-                Err(e) => err = Some(e),
+                continue;
             }
+
+            return Ok(stream);
         }
+    }
 
-        if let Some(err) = err {
-            Err(err.into())
-        } else {
-            Err(ResolveError::EmptyResolved.into())
+    async fn layer(&self, stream: TokioTcpStream) -> ClientResult<TcpStream> {
+        let Some((ref tls_conn, ref server_name)) = self.tls_conn else {
+            return Ok(TcpStream::Raw(stream));
+        };
+        let stage = Stage::start(self.dialer.hooks().observer.as_deref());
+        let res = tls_conn.connect(server_name.clone(), stream).await;
+        stage.tls_handshake(res.as_ref().map(|_| ()));
+        Ok(TcpStream::Tls(TlsStream::Client(res?)))
+    }
+
+    async fn probe(&self, stream: &mut TcpStream) -> ClientResult<()> {
+        match self.post_connect_probe {
+            Some(probe @ ProbeMode::ExpectServerBytes { n, .. }) => {
+                probe.run(stream.wait_readable_bytes(n)).await
+            }
+            _ => Ok(()),
         }
     }
 }
+
+impl TransportClientTrait for TcpClient {
+    type Stream = TcpStream;
+
+    async fn connect(&self) -> ClientResult<Self::Stream> {
+        let state = self.state.load_full();
+        observe::connect_span("tcp", state.connect()).await
+    }
+}
@@ -10,4 +10,2039 @@ pub mod stream;
 pub use stream::TcpStream;
 
 pub mod option;
-pub use option::{TcpClientOption, TcpServerOption};
+pub use option::{
+    Route, SniffOption, TcpClientOption, TcpServerOption, TlsMode, DEFAULT_ACCEPT_BATCH,
+    DEFAULT_HANDSHAKE_TIMEOUT,
+};
+
+#[cfg(test)]
+mod tests {
+    // helpers shared with the tests needing the insecure verifier
+    #![cfg_attr(not(feature = "insecure-tls"), allow(dead_code, unused_imports))]
+
+    use std::sync::Arc;
+
+    use rustls::ServerConfig as TlsServerConfig;
+    use tokio::net::TcpListener;
+    use tokio_rustls::{TlsAcceptor, TlsStream};
+
+    use crate::{
+        Resolver, TlsCertOption, TlsClientOption, TlsServerOption, TransportClientStream,
+        TransportClientTrait, TransportServerStream,
+    };
+
+    use super::*;
+
+    fn test_tls_server_option() -> TlsServerOption {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+        TlsServerOption {
+            alpn: vec![],
+            require_alpn: false,
+            ticket_keys: None,
+            certificate: TlsCertOption::Text {
+                certs: vec![cert.cert.pem()],
+                key: cert.key_pair.serialize_pem().into(),
+            },
+            client_ca: None,
+            require_client_cert: false,
+            session_tickets: false,
+            min_version: None,
+            max_version: None,
+            cipher_suites: vec![],
+        }
+    }
+
+    #[cfg(feature = "insecure-tls")]
+    #[tokio::test]
+    async fn test_tls_export_keying_material() {
+        let config: TlsServerConfig = test_tls_server_option().try_into().unwrap();
+        let acceptor = TlsAcceptor::from(Arc::new(config));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let server = tokio::spawn(async move {
+            let (s, _) = listener.accept().await.unwrap();
+            let s = acceptor.accept(s).await.unwrap();
+            TransportServerStream::Tcp(TcpStream::Tls(TlsStream::Server(s)))
+        });
+
+        let opt = TcpClientOption {
+            addr: addr.ip().to_string(),
+            port: addr.port(),
+            tcp_nodelay: false,
+            connect_timeout: None,
+            connect_deadline: None,
+            attempt_delay: None,
+            resolve_on_connect: false,
+            proxy: None,
+            bind_addr: None,
+            bind_device: None,
+        };
+        let tls_opt = TlsClientOption {
+            insecure: true,
+            server_name: "localhost".into(),
+            ..Default::default()
+        };
+        let cli = TcpClient::init(opt, Some(tls_opt), &Resolver::default()).unwrap();
+        let cli_stream = TransportClientStream::Tcp(cli.connect().await.unwrap());
+        let srv_stream = server.await.unwrap();
+
+        let cli_ekm = cli_stream
+            .export_keying_material(b"EXPORTER-test", Some(b"ctx-a"), 32)
+            .unwrap()
+            .unwrap();
+        let srv_ekm = srv_stream
+            .export_keying_material(b"EXPORTER-test", Some(b"ctx-a"), 32)
+            .unwrap()
+            .unwrap();
+        assert_eq!(cli_ekm.len(), 32);
+        assert_eq!(cli_ekm, srv_ekm);
+
+        let other_ekm = srv_stream
+            .export_keying_material(b"EXPORTER-test", Some(b"ctx-b"), 32)
+            .unwrap()
+            .unwrap();
+        assert_ne!(cli_ekm, other_ekm);
+    }
+
+    #[tokio::test]
+    async fn test_raw_export_keying_material() {
+        use tokio::io::AsyncWrite;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move { listener.accept().await.unwrap().0 });
+
+        let opt = TcpClientOption {
+            addr: addr.ip().to_string(),
+            port: addr.port(),
+            tcp_nodelay: false,
+            connect_timeout: None,
+            connect_deadline: None,
+            attempt_delay: None,
+            resolve_on_connect: false,
+            proxy: None,
+            bind_addr: None,
+            bind_device: None,
+        };
+        let cli = TcpClient::init(opt, None, &Resolver::default()).unwrap();
+        let stream = TransportClientStream::Tcp(cli.connect().await.unwrap());
+        let _ = server.await.unwrap();
+
+        assert!(stream.export_keying_material(b"label", None, 32).is_none());
+        assert_eq!(stream.kind(), "Tcp");
+        // the enums forward vectored writes down to the socket
+        assert!(stream.is_write_vectored());
+        let debug = format!("{:?}", stream);
+        assert!(
+            debug.starts_with(&format!("Tcp(TcpStream {{ peer: Some({}), ", addr))
+                && debug.ends_with("tls: false })"),
+            "{}",
+            debug
+        );
+    }
+
+    #[cfg(feature = "insecure-tls")]
+    #[tokio::test]
+    async fn test_tls_sni_alpn() {
+        let tls_opt = TlsServerOption {
+            alpn: vec!["h2".into(), "kapi".into()],
+            require_alpn: true,
+            ..test_tls_server_option()
+        };
+        let opt = TcpServerOption {
+            listen: "127.0.0.1:0".parse().unwrap(),
+            tcp_nodelay: false,
+            tls_mode: TlsMode::Required,
+            sniff: None,
+            max_bytes: None,
+            rate_limit: None,
+            trace_sampling: None,
+            idle_reap: None,
+            idle_timeout: None,
+            accept_batch: DEFAULT_ACCEPT_BATCH,
+            handshake_timeout: DEFAULT_HANDSHAKE_TIMEOUT,
+            max_connections: None,
+        };
+        let srv = TcpServer::init(opt, Some(tls_opt)).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let server = tokio::spawn(async move {
+            let mut results = vec![];
+            for _ in 0..3 {
+                let (s, _) = listener.accept().await.unwrap();
+                results.push(srv.handshake(s).await.map(|s| {
+                    (
+                        s.server_name().map(|s| s.to_owned()),
+                        s.alpn_protocol().map(|p| p.to_vec()),
+                    )
+                }));
+            }
+            results
+        });
+
+        let mut client_alpn = vec![];
+        for alpn in [vec!["kapi"], vec!["h2", "http/1.1"], vec![]] {
+            let opt = TcpClientOption {
+                addr: addr.ip().to_string(),
+                port: addr.port(),
+                tcp_nodelay: false,
+                connect_timeout: None,
+                connect_deadline: None,
+                attempt_delay: None,
+                resolve_on_connect: false,
+                proxy: None,
+                bind_addr: None,
+                bind_device: None,
+            };
+            let tls_opt = TlsClientOption {
+                insecure: true,
+                alpn: alpn.into_iter().map(|s| s.to_owned()).collect(),
+                server_name: "localhost".into(),
+                ..Default::default()
+            };
+            let cli = TcpClient::init(opt, Some(tls_opt), &Resolver::default()).unwrap();
+            client_alpn.push(
+                cli.connect()
+                    .await
+                    .map(|s| s.alpn_protocol().map(|p| p.to_vec())),
+            );
+        }
+
+        let results = server.await.unwrap();
+        assert_eq!(
+            results[0].as_ref().unwrap(),
+            &(Some("localhost".to_owned()), Some(b"kapi".to_vec()))
+        );
+        assert_eq!(
+            results[1].as_ref().unwrap(),
+            &(Some("localhost".to_owned()), Some(b"h2".to_vec()))
+        );
+        assert!(results[2].is_err());
+
+        assert_eq!(client_alpn[0].as_ref().unwrap(), &Some(b"kapi".to_vec()));
+        assert_eq!(client_alpn[1].as_ref().unwrap(), &Some(b"h2".to_vec()));
+        let err = client_alpn[2].as_ref().unwrap_err().to_string();
+        assert!(err.contains("NoApplicationProtocol"), "{}", err);
+    }
+
+    /// Writes what its context tells about the tls session.
+    #[derive(Clone)]
+    struct SessionCallback;
+
+    impl crate::TransportServerCallback for SessionCallback {
+        async fn handle<S>(&self, _stream: S, _addr: Option<std::net::SocketAddr>)
+        where
+            S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + Sync,
+        {
+            unreachable!("served through handle_ctx");
+        }
+
+        async fn handle_ctx<S>(&self, mut stream: S, ctx: crate::ConnContext)
+        where
+            S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + Sync,
+        {
+            use tokio::io::AsyncWriteExt;
+
+            let info = ctx.tls_info().unwrap();
+            assert_eq!(info.sni.as_deref(), ctx.server_name());
+            let mut out = format!(
+                "{} {}\n",
+                ctx.server_name().unwrap(),
+                String::from_utf8_lossy(ctx.alpn_protocol().unwrap())
+            )
+            .into_bytes();
+            out.extend(
+                ctx.export_keying_material(b"EXPORTER-test", Some(b"ctx-a"), 32)
+                    .unwrap(),
+            );
+            // only what the server asked for at the handshake
+            let other = ctx.export_keying_material(b"EXPORTER-test", Some(b"ctx-b"), 32);
+            assert!(matches!(other, Err(crate::TlsError::NotExported)));
+            stream.write_all(&out).await.unwrap();
+            let _ = stream.shutdown().await;
+        }
+    }
+
+    #[cfg(feature = "insecure-tls")]
+    #[tokio::test]
+    async fn test_tls_session_in_callback() {
+        use std::time::Duration;
+
+        use tokio::io::AsyncReadExt;
+
+        use crate::{tls::ExportRequest, TransportServerTrait};
+
+        let opt = TcpServerOption {
+            listen: "127.0.0.1:0".parse().unwrap(),
+            tcp_nodelay: false,
+            tls_mode: TlsMode::Required,
+            sniff: None,
+            max_bytes: None,
+            rate_limit: None,
+            trace_sampling: None,
+            idle_reap: Some(Duration::from_secs(10)),
+            idle_timeout: None,
+            accept_batch: DEFAULT_ACCEPT_BATCH,
+            handshake_timeout: DEFAULT_HANDSHAKE_TIMEOUT,
+            max_connections: None,
+        };
+        let server_tls = TlsServerOption {
+            alpn: vec!["h2".into()],
+            ..test_tls_server_option()
+        };
+        let mut srv = TcpServer::init(opt, Some(server_tls)).unwrap();
+        srv.add_keying_material_export(ExportRequest {
+            label: b"EXPORTER-test".to_vec(),
+            context: Some(b"ctx-a".to_vec()),
+            output_len: 32,
+        });
+        let addr = srv.local_addr().unwrap();
+        tokio::spawn(async move { srv.serve(SessionCallback).await });
+
+        let opt = TcpClientOption {
+            addr: "127.0.0.1".into(),
+            port: addr.port(),
+            ..Default::default()
+        };
+        let tls_opt = TlsClientOption {
+            insecure: true,
+            alpn: vec!["h2".into()],
+            server_name: "localhost".into(),
+            ..Default::default()
+        };
+        let cli = TcpClient::init(opt, Some(tls_opt), &Resolver::default()).unwrap();
+        let mut stream = cli.connect().await.unwrap();
+        let ekm = stream
+            .export_keying_material(b"EXPORTER-test", Some(b"ctx-a"), 32)
+            .unwrap();
+        let mut buf = vec![];
+        stream.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(&buf[..13], b"localhost h2\n");
+        assert_eq!(buf[13..], ekm);
+    }
+
+    #[cfg(feature = "insecure-tls")]
+    #[tokio::test]
+    async fn test_tls_info() {
+        use rustls::ProtocolVersion;
+
+        use crate::{TransportClientStream, TransportServerStream};
+
+        let server_tls = TlsServerOption {
+            alpn: vec!["h2".into()],
+            ..test_tls_server_option()
+        };
+        let certificate = server_tls.certificate.clone();
+        let opt = TcpServerOption {
+            listen: "127.0.0.1:0".parse().unwrap(),
+            tcp_nodelay: false,
+            tls_mode: TlsMode::Required,
+            sniff: None,
+            max_bytes: None,
+            trace_sampling: None,
+            idle_reap: None,
+            accept_batch: DEFAULT_ACCEPT_BATCH,
+            handshake_timeout: DEFAULT_HANDSHAKE_TIMEOUT,
+            max_connections: None,
+            idle_timeout: None,
+            rate_limit: None,
+        };
+        let srv = TcpServer::init(opt, Some(server_tls)).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (s, _) = listener.accept().await.unwrap();
+            TransportServerStream::Tcp(srv.handshake(s).await.unwrap()).tls_info()
+        });
+
+        let opt = TcpClientOption {
+            addr: addr.ip().to_string(),
+            port: addr.port(),
+            tcp_nodelay: false,
+            connect_timeout: None,
+            connect_deadline: None,
+            attempt_delay: None,
+            resolve_on_connect: false,
+            proxy: None,
+            bind_addr: None,
+            bind_device: None,
+        };
+        let tls_opt = TlsClientOption {
+            insecure: true,
+            alpn: vec!["h2".into(), "http/1.1".into()],
+            server_name: "localhost".into(),
+            ..Default::default()
+        };
+        let cli = TcpClient::init(opt.clone(), Some(tls_opt), &Resolver::default()).unwrap();
+        let stream = TransportClientStream::Tcp(cli.connect().await.unwrap());
+
+        let client_info = stream.tls_info().unwrap();
+        assert_eq!(client_info.alpn_protocol.as_deref(), Some(&b"h2"[..]));
+        assert_eq!(client_info.protocol_version, Some(ProtocolVersion::TLSv1_3));
+        assert_eq!(client_info.sni, None);
+        assert_eq!(
+            client_info.peer_certificates,
+            Some(certificate.load_certs().unwrap())
+        );
+
+        let server_info = server.await.unwrap().unwrap();
+        assert_eq!(server_info.alpn_protocol.as_deref(), Some(&b"h2"[..]));
+        assert_eq!(server_info.sni.as_deref(), Some("localhost"));
+        // no client certificate was asked for
+        assert_eq!(server_info.peer_certificates, None);
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { listener.accept().await });
+        let cli = TcpClient::init(
+            TcpClientOption {
+                port: addr.port(),
+                ..opt
+            },
+            None,
+            &Resolver::default(),
+        )
+        .unwrap();
+        assert!(cli.connect().await.unwrap().tls_info().is_none());
+    }
+
+    #[cfg(feature = "insecure-tls")]
+    #[tokio::test]
+    async fn test_tls_peer_certificates_tofu() {
+        use std::sync::Mutex;
+
+        use crate::tls::cert_sha256;
+
+        let mut acceptors = vec![];
+        for _ in 0..2 {
+            let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+            let der = cert.cert.der().clone();
+            let tls_opt = TlsServerOption {
+                alpn: vec![],
+                require_alpn: false,
+                ticket_keys: None,
+                certificate: TlsCertOption::Text {
+                    certs: vec![cert.cert.pem()],
+                    key: cert.key_pair.serialize_pem().into(),
+                },
+                client_ca: None,
+                require_client_cert: false,
+                session_tickets: false,
+                min_version: None,
+                max_version: None,
+                cipher_suites: vec![],
+            };
+            let config: TlsServerConfig = tls_opt.try_into().unwrap();
+            acceptors.push((TlsAcceptor::from(Arc::new(config)), der));
+        }
+        // the first certificate is presented twice
+        acceptors.insert(0, acceptors[0].clone());
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server_acceptors = acceptors.clone();
+        tokio::spawn(async move {
+            for (acceptor, _) in server_acceptors {
+                let (s, _) = listener.accept().await.unwrap();
+                let _ = acceptor.accept(s).await;
+            }
+        });
+
+        let pinned = Arc::new(Mutex::new(None));
+        let calls = Arc::new(Mutex::new(0));
+        let (pinned_cb, calls_cb) = (pinned.clone(), calls.clone());
+        let tls_opt = TlsClientOption {
+            insecure: true,
+            server_name: "localhost".into(),
+            on_first_seen: Some(Arc::new(move |cert| {
+                *calls_cb.lock().unwrap() += 1;
+                let fingerprint = cert_sha256(cert);
+                *pinned_cb.lock().unwrap().get_or_insert(fingerprint) == fingerprint
+            })),
+            ..Default::default()
+        };
+        let opt = TcpClientOption {
+            addr: addr.ip().to_string(),
+            port: addr.port(),
+            tcp_nodelay: false,
+            connect_timeout: None,
+            connect_deadline: None,
+            attempt_delay: None,
+            resolve_on_connect: false,
+            proxy: None,
+            bind_addr: None,
+            bind_device: None,
+        };
+        let cli = TcpClient::init(opt, Some(tls_opt), &Resolver::default()).unwrap();
+
+        let stream = TransportClientStream::Tcp(cli.connect().await.unwrap());
+        assert_eq!(
+            stream.peer_certificates().unwrap(),
+            vec![acceptors[0].1.clone()]
+        );
+        assert_eq!(
+            stream.peer_cert_sha256(),
+            Some(cert_sha256(&acceptors[0].1))
+        );
+
+        // already seen, the callback is skipped
+        cli.connect().await.unwrap();
+        assert_eq!(*calls.lock().unwrap(), 1);
+
+        // a changed certificate is rejected
+        let err = cli.connect().await.err().unwrap().to_string();
+        assert!(err.contains("rejected by on_first_seen"), "{}", err);
+        assert_eq!(*calls.lock().unwrap(), 2);
+    }
+
+    #[derive(Debug, Clone)]
+    struct DrainServerCallback;
+
+    impl crate::TransportServerCallback for DrainServerCallback {
+        async fn handle<S>(&self, mut stream: S, _addr: Option<std::net::SocketAddr>)
+        where
+            S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + Sync,
+        {
+            let mut buf = vec![];
+            let _ = tokio::io::AsyncReadExt::read_to_end(&mut stream, &mut buf).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_dual_stack_stats() {
+        use tokio::io::AsyncWriteExt;
+
+        use crate::{stats::AddrFamily, TransportServerTrait};
+
+        let opt = TcpServerOption {
+            listen: "[::]:9890".parse().unwrap(),
+            tcp_nodelay: false,
+            tls_mode: TlsMode::Required,
+            sniff: None,
+            max_bytes: None,
+            rate_limit: None,
+            trace_sampling: None,
+            idle_reap: None,
+            idle_timeout: None,
+            accept_batch: DEFAULT_ACCEPT_BATCH,
+            handshake_timeout: DEFAULT_HANDSHAKE_TIMEOUT,
+            max_connections: None,
+        };
+        let srv = Arc::new(TcpServer::init(opt, None).unwrap());
+        let srv_clone = srv.clone();
+        tokio::spawn(async move { srv_clone.serve(DrainServerCallback).await });
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+
+        for (addr, len) in [("127.0.0.1:9890", 3), ("[::1]:9890", 5)] {
+            let mut s = tokio::net::TcpStream::connect(addr).await.unwrap();
+            s.write_all(&b"x".repeat(len)).await.unwrap();
+            s.shutdown().await.unwrap();
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+
+        let stats = srv.stats();
+        let v4 = stats.family(AddrFamily::Ipv4);
+        let v6 = stats.family(AddrFamily::Ipv6);
+        assert_eq!((v4.accepted, v4.active, v4.bytes_read), (1, 0, 3));
+        assert_eq!((v6.accepted, v6.active, v6.bytes_read), (1, 0, 5));
+        assert_eq!(v4.handshake_buckets.iter().sum::<u64>(), 0);
+    }
+
+    #[derive(Debug, Clone, PartialEq)]
+    struct Claims(String);
+
+    #[derive(Clone)]
+    struct ContextCallback(tokio::sync::mpsc::UnboundedSender<(u64, Option<Claims>)>);
+
+    impl crate::TransportServerCallback for ContextCallback {
+        async fn handle<S>(&self, _stream: S, _addr: Option<std::net::SocketAddr>)
+        where
+            S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + Sync,
+        {
+            unreachable!("servers call handle_ctx")
+        }
+
+        async fn handle_ctx<S>(&self, _stream: S, ctx: crate::ConnContext)
+        where
+            S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + Sync,
+        {
+            let claims = ctx.extensions.get::<Claims>().cloned();
+            let _ = self.0.send((ctx.id, claims));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_accept_hook_context() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        use crate::TransportServerTrait;
+
+        let opt = TcpServerOption {
+            listen: "127.0.0.1:9891".parse().unwrap(),
+            tcp_nodelay: false,
+            tls_mode: TlsMode::Required,
+            sniff: None,
+            max_bytes: None,
+            rate_limit: None,
+            trace_sampling: None,
+            idle_reap: None,
+            idle_timeout: None,
+            accept_batch: DEFAULT_ACCEPT_BATCH,
+            handshake_timeout: DEFAULT_HANDSHAKE_TIMEOUT,
+            max_connections: None,
+        };
+        let mut srv = TcpServer::init(opt, None).unwrap();
+        let seen = Arc::new(AtomicUsize::new(0));
+        let seen_clone = seen.clone();
+        srv.add_accept_hook(Arc::new(move |_ctx| {
+            // reject every other connection
+            seen_clone.fetch_add(1, Ordering::SeqCst).is_multiple_of(2)
+        }));
+        srv.add_accept_hook(Arc::new(|ctx| {
+            assert!(ctx.peer_addr.is_some() && ctx.local_addr.is_some());
+            ctx.extensions.insert(Claims("alice".into()));
+            true
+        }));
+
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(async move { srv.serve(ContextCallback(tx)).await });
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+
+        let mut streams = vec![];
+        for _ in 0..4 {
+            streams.push(
+                tokio::net::TcpStream::connect("127.0.0.1:9891")
+                    .await
+                    .unwrap(),
+            );
+        }
+
+        let (id1, claims1) = rx.recv().await.unwrap();
+        let (id2, claims2) = rx.recv().await.unwrap();
+        assert_eq!(claims1, Some(Claims("alice".into())));
+        assert_eq!(claims2, Some(Claims("alice".into())));
+        assert_ne!(id1, id2);
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        assert!(rx.try_recv().is_err());
+        assert_eq!(seen.load(Ordering::SeqCst), 4);
+    }
+
+    #[derive(Debug, Clone)]
+    struct EchoPrefixCallback;
+
+    impl crate::TransportServerCallback for EchoPrefixCallback {
+        async fn handle<S>(&self, mut stream: S, _addr: Option<std::net::SocketAddr>)
+        where
+            S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + Sync,
+        {
+            use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+            let mut prefix = [0u8; 4];
+            if stream.read_exact(&mut prefix).await.is_ok() {
+                let _ = stream.write_all(&prefix).await;
+                let _ = stream.shutdown().await;
+            }
+        }
+    }
+
+    #[cfg(feature = "insecure-tls")]
+    #[tokio::test]
+    async fn test_sniff_routes() {
+        use std::time::Duration;
+
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        use crate::TransportServerTrait;
+
+        let opt = TcpServerOption {
+            listen: "127.0.0.1:9892".parse().unwrap(),
+            tcp_nodelay: false,
+            tls_mode: TlsMode::Required,
+            sniff: Some(SniffOption {
+                bytes: 4,
+                timeout: Duration::from_millis(100),
+                default_route: Route::Plain,
+            }),
+            max_bytes: None,
+            rate_limit: None,
+            trace_sampling: None,
+            idle_reap: None,
+            idle_timeout: None,
+            accept_batch: DEFAULT_ACCEPT_BATCH,
+            handshake_timeout: DEFAULT_HANDSHAKE_TIMEOUT,
+            max_connections: None,
+        };
+        let mut srv = TcpServer::init(opt, Some(test_tls_server_option())).unwrap();
+        srv.set_classifier(Arc::new(|prefix, addr| match prefix {
+            b"DROP" => Route::Drop,
+            _ => server::default_classify(prefix, addr),
+        }));
+        tokio::spawn(async move { srv.serve(EchoPrefixCallback).await });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        // a silent client does not hold up the ones accepted after it
+        let _silent = tokio::net::TcpStream::connect("127.0.0.1:9892")
+            .await
+            .unwrap();
+        let started = tokio::time::Instant::now();
+
+        // plaintext magic, split so the sniffer sees a partial prefix first
+        let mut s = tokio::net::TcpStream::connect("127.0.0.1:9892")
+            .await
+            .unwrap();
+        s.write_all(b"KA").await.unwrap();
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        s.write_all(b"PI").await.unwrap();
+        let mut buf = vec![];
+        s.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, b"KAPI");
+        assert!(started.elapsed() < Duration::from_millis(100));
+
+        // silent past the timeout takes the default route
+        let mut s = tokio::net::TcpStream::connect("127.0.0.1:9892")
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        s.write_all(b"late").await.unwrap();
+        let mut buf = vec![];
+        s.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, b"late");
+
+        let mut s = tokio::net::TcpStream::connect("127.0.0.1:9892")
+            .await
+            .unwrap();
+        s.write_all(b"DROP").await.unwrap();
+        assert_eq!(s.read(&mut [0u8; 4]).await.unwrap_or(0), 0);
+
+        // the client hello is still there for the tls acceptor
+        let opt = TcpClientOption {
+            addr: "127.0.0.1".into(),
+            port: 9892,
+            tcp_nodelay: false,
+            connect_timeout: None,
+            connect_deadline: None,
+            attempt_delay: None,
+            resolve_on_connect: false,
+            proxy: None,
+            bind_addr: None,
+            bind_device: None,
+        };
+        let tls_opt = TlsClientOption {
+            insecure: true,
+            server_name: "localhost".into(),
+            ..Default::default()
+        };
+        let cli = TcpClient::init(opt, Some(tls_opt), &Resolver::default()).unwrap();
+        let mut s = cli.connect().await.unwrap();
+        assert!(s.is_tls());
+        s.write_all(b"tls!").await.unwrap();
+        let mut buf = [0u8; 4];
+        s.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"tls!");
+    }
+
+    #[derive(Clone)]
+    struct SecurityCallback(
+        tokio::sync::mpsc::UnboundedSender<(
+            Option<crate::context::Security>,
+            crate::ConnectionInfo,
+        )>,
+    );
+
+    impl crate::TransportServerCallback for SecurityCallback {
+        async fn handle<S>(&self, _stream: S, _addr: Option<std::net::SocketAddr>)
+        where
+            S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + Sync,
+        {
+            unreachable!("servers call handle_ctx")
+        }
+
+        async fn handle_ctx<S>(&self, stream: S, ctx: crate::ConnContext)
+        where
+            S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + Sync,
+        {
+            let _ = self.0.send((ctx.extensions.get().copied(), ctx.info()));
+            EchoPrefixCallback.handle(stream, ctx.peer_addr).await;
+        }
+    }
+
+    #[cfg(feature = "insecure-tls")]
+    #[tokio::test]
+    async fn test_tls_mode_optional() {
+        use std::time::Duration;
+
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        use crate::{context::Security, TransportServerTrait};
+
+        let opt = TcpServerOption {
+            listen: "127.0.0.1:9869".parse().unwrap(),
+            tcp_nodelay: false,
+            tls_mode: TlsMode::Optional,
+            sniff: None,
+            max_bytes: None,
+            rate_limit: None,
+            trace_sampling: None,
+            idle_reap: None,
+            idle_timeout: None,
+            accept_batch: DEFAULT_ACCEPT_BATCH,
+            handshake_timeout: DEFAULT_HANDSHAKE_TIMEOUT,
+            max_connections: None,
+        };
+        let srv = TcpServer::init(opt, Some(test_tls_server_option())).unwrap();
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(async move { srv.serve(SecurityCallback(tx)).await });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let opt = TcpClientOption {
+            addr: "127.0.0.1".into(),
+            port: 9869,
+            tcp_nodelay: false,
+            connect_timeout: None,
+            connect_deadline: None,
+            attempt_delay: None,
+            resolve_on_connect: false,
+            proxy: None,
+            bind_addr: None,
+            bind_device: None,
+        };
+        let tls_opt = TlsClientOption {
+            insecure: true,
+            server_name: "localhost".into(),
+            ..Default::default()
+        };
+        for (tls, expect) in [(Some(tls_opt), Security::Tls), (None, Security::Plain)] {
+            let cli = TcpClient::init(opt.clone(), tls, &Resolver::default()).unwrap();
+            let mut s = cli.connect().await.unwrap();
+            s.write_all(b"kapi").await.unwrap();
+            let mut buf = [0u8; 4];
+            s.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"kapi");
+            assert_eq!(s.is_tls(), expect == Security::Tls);
+            let (security, info) = rx.recv().await.unwrap();
+            assert_eq!(security, Some(expect));
+            assert_eq!(info.transport, "tcp");
+            assert!(info.http.is_none());
+            let sni = info.tls.and_then(|tls| tls.sni);
+            match expect {
+                Security::Tls => assert_eq!(sni.as_deref(), Some("localhost")),
+                Security::Plain => assert_eq!(sni, None),
+            }
+        }
+
+        // a broken handshake after a positive sniff is dropped, never served as plaintext
+        let mut s = tokio::net::TcpStream::connect("127.0.0.1:9869")
+            .await
+            .unwrap();
+        s.write_all(&[0x16, 0x03, 0x01, 0x00, 0x04, 0xde, 0xad, 0xbe, 0xef])
+            .await
+            .unwrap();
+        let mut buf = vec![];
+        let _ = s.read_to_end(&mut buf).await;
+        assert!(buf.starts_with(&[0x15]) || buf.is_empty(), "{:?}", buf);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[cfg(feature = "insecure-tls")]
+    #[tokio::test]
+    async fn test_handshake_timeout() {
+        use std::time::Duration;
+
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        use crate::TransportServerTrait;
+
+        let opt = TcpServerOption {
+            listen: "127.0.0.1:9849".parse().unwrap(),
+            tcp_nodelay: false,
+            tls_mode: TlsMode::Required,
+            sniff: None,
+            max_bytes: None,
+            rate_limit: None,
+            trace_sampling: None,
+            idle_reap: None,
+            idle_timeout: None,
+            accept_batch: DEFAULT_ACCEPT_BATCH,
+            handshake_timeout: Duration::from_millis(300),
+            max_connections: None,
+        };
+        let srv = TcpServer::init(opt, Some(test_tls_server_option())).unwrap();
+        tokio::spawn(async move { srv.serve(EchoPrefixCallback).await });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        // connects and never says hello
+        let mut stalled = tokio::net::TcpStream::connect("127.0.0.1:9849")
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let opt = TcpClientOption {
+            addr: "127.0.0.1".into(),
+            port: 9849,
+            tcp_nodelay: false,
+            connect_timeout: None,
+            connect_deadline: None,
+            attempt_delay: None,
+            resolve_on_connect: false,
+            proxy: None,
+            bind_addr: None,
+            bind_device: None,
+        };
+        let tls_opt = TlsClientOption {
+            insecure: true,
+            server_name: "localhost".into(),
+            ..Default::default()
+        };
+        let cli = TcpClient::init(opt, Some(tls_opt), &Resolver::default()).unwrap();
+        let mut s = tokio::time::timeout(Duration::from_millis(200), cli.connect())
+            .await
+            .expect("handshake blocked by the stalled connection")
+            .unwrap();
+        s.write_all(b"kapi").await.unwrap();
+        let mut buf = [0u8; 4];
+        s.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"kapi");
+
+        // the stalled connection is dropped once the timeout expires
+        let mut buf = vec![];
+        let read = tokio::time::timeout(Duration::from_secs(1), stalled.read_to_end(&mut buf))
+            .await
+            .expect("stalled connection kept open");
+        assert!(read.map_or(true, |n| n == 0));
+    }
+
+    /// Server that completes handshakes, sends `greeting` and then stays silent.
+    async fn spawn_probe_server(tls: bool, greeting: &'static [u8]) -> std::net::SocketAddr {
+        use tokio::io::AsyncWriteExt;
+
+        let config: TlsServerConfig = test_tls_server_option().try_into().unwrap();
+        let acceptor = TlsAcceptor::from(Arc::new(config));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (s, _) = listener.accept().await.unwrap();
+                let acceptor = acceptor.clone();
+                tokio::spawn(async move {
+                    let mut s = if tls {
+                        TcpStream::Tls(TlsStream::Server(acceptor.accept(s).await.unwrap()))
+                    } else {
+                        TcpStream::Raw(s)
+                    };
+                    s.write_all(greeting).await.unwrap();
+                    s.flush().await.unwrap();
+                    std::future::pending::<()>().await;
+                });
+            }
+        });
+        addr
+    }
+
+    #[cfg(feature = "insecure-tls")]
+    #[tokio::test]
+    async fn test_post_connect_probe() {
+        use std::time::Duration;
+
+        use tokio::io::AsyncReadExt;
+
+        use crate::{option::ProbeMode, ClientError};
+
+        let probe = ProbeMode::ExpectServerBytes {
+            n: 4,
+            timeout: Duration::from_millis(200),
+        };
+        for tls in [false, true] {
+            let silent = spawn_probe_server(tls, b"").await;
+            let greeter = spawn_probe_server(tls, b"helo").await;
+            let tls_opt = tls.then(|| TlsClientOption {
+                insecure: true,
+                server_name: "localhost".into(),
+                ..Default::default()
+            });
+            let opt = TcpClientOption {
+                addr: "127.0.0.1".into(),
+                port: 0,
+                tcp_nodelay: false,
+                connect_timeout: None,
+                connect_deadline: None,
+                attempt_delay: None,
+                resolve_on_connect: false,
+                proxy: None,
+                bind_addr: None,
+                bind_device: None,
+            };
+
+            let mut cli =
+                TcpClient::with_addrs(opt.clone(), tls_opt.clone(), vec![silent]).unwrap();
+            cli.set_post_connect_probe(Some(probe)).unwrap();
+            match cli.connect().await {
+                Err(ClientError::Connect(msg)) => {
+                    assert_eq!(msg, "post-connect probe failed: timeout")
+                }
+                _ => panic!("probe passed on a silent server"),
+            }
+
+            // the failed address is skipped and the probed bytes stay readable
+            let mut cli =
+                TcpClient::with_addrs(opt.clone(), tls_opt.clone(), vec![silent, greeter]).unwrap();
+            cli.set_post_connect_probe(Some(probe)).unwrap();
+            let mut stream = cli.connect().await.unwrap();
+            let mut buf = [0u8; 4];
+            stream.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"helo");
+
+            // more than rustls buffers ahead of the reader
+            const LONG: usize = 64 * 1024;
+            let long = spawn_probe_server(tls, &[7; LONG]).await;
+            let mut cli = TcpClient::with_addrs(opt, tls_opt, vec![long]).unwrap();
+            cli.set_post_connect_probe(Some(ProbeMode::ExpectServerBytes {
+                n: LONG,
+                timeout: Duration::from_secs(5),
+            }))
+            .unwrap();
+            let mut stream = cli.connect().await.unwrap();
+            let mut buf = vec![0u8; LONG];
+            stream.read_exact(&mut buf).await.unwrap();
+            assert!(buf.iter().all(|&b| b == 7));
+        }
+
+        let mut cli = TcpClient::with_addrs(
+            TcpClientOption {
+                addr: "127.0.0.1".into(),
+                port: 0,
+                tcp_nodelay: false,
+                connect_timeout: None,
+                connect_deadline: None,
+                attempt_delay: None,
+                resolve_on_connect: false,
+                proxy: None,
+                bind_addr: None,
+                bind_device: None,
+            },
+            None,
+            vec![([127, 0, 0, 1], 1).into()],
+        )
+        .unwrap();
+        let ws_ping = ProbeMode::WsPing {
+            timeout: Duration::from_millis(200),
+        };
+        assert!(cli.set_post_connect_probe(Some(ws_ping)).is_err());
+    }
+
+    #[cfg(feature = "insecure-tls")]
+    #[tokio::test]
+    async fn test_shared_ticket_keys() {
+        use std::time::Duration;
+
+        use rustls::{pki_types::ServerName, ClientConfig};
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        use tokio_rustls::TlsConnector;
+
+        use crate::{tls::TicketKeyOption, TransportServerTrait};
+
+        let shared = TlsServerOption {
+            ticket_keys: Some(TicketKeyOption {
+                keys: vec!["S2FwaWJhcmEgc2hhcmVkIHRpY2tldCBrZXkgMDAwMDA=".into()],
+                rotate_every: None,
+            }),
+            ..test_tls_server_option()
+        };
+        let servers = [
+            (9867, shared.clone()),
+            (9866, shared),
+            (9865, test_tls_server_option()),
+        ];
+        for (port, tls_opt) in servers.clone() {
+            let opt = TcpServerOption {
+                listen: ([127, 0, 0, 1], port).into(),
+                tcp_nodelay: false,
+                tls_mode: TlsMode::Required,
+                sniff: None,
+                max_bytes: None,
+                rate_limit: None,
+                trace_sampling: None,
+                idle_reap: None,
+                idle_timeout: None,
+                accept_batch: DEFAULT_ACCEPT_BATCH,
+                handshake_timeout: DEFAULT_HANDSHAKE_TIMEOUT,
+                max_connections: None,
+            };
+            let srv = TcpServer::init(opt, Some(tls_opt)).unwrap();
+            assert_eq!(srv.ticket_keys().is_some(), port != 9865);
+            tokio::spawn(async move { srv.serve(EchoPrefixCallback).await });
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let client: ClientConfig = TlsClientOption {
+            insecure: true,
+            server_name: "localhost".into(),
+            ..Default::default()
+        }
+        .try_into()
+        .unwrap();
+        let connector = TlsConnector::from(Arc::new(client));
+        let connect = |port: u16| {
+            let connector = connector.clone();
+            async move {
+                let s = tokio::net::TcpStream::connect(("127.0.0.1", port))
+                    .await
+                    .unwrap();
+                let name = ServerName::try_from("localhost").unwrap();
+                let s = connector.connect(name, s).await.unwrap();
+                let mut s = TcpStream::Tls(TlsStream::Client(s));
+                // the tickets arrive ahead of the echo
+                s.write_all(b"kapi").await.unwrap();
+                let mut buf = [0u8; 4];
+                s.read_exact(&mut buf).await.unwrap();
+                s
+            }
+        };
+
+        assert!(!connect(9867).await.is_resumed());
+        // a ticket from one server resumes on another with the same keys
+        assert!(connect(9866).await.is_resumed());
+        assert!(!connect(9865).await.is_resumed());
+    }
+
+    #[cfg(feature = "insecure-tls")]
+    #[tokio::test]
+    async fn test_tls_session_resumption() {
+        use std::time::Duration;
+
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        use crate::TransportServerTrait;
+
+        let opt = TcpServerOption {
+            listen: ([127, 0, 0, 1], 9839).into(),
+            tcp_nodelay: false,
+            tls_mode: TlsMode::Required,
+            sniff: None,
+            max_bytes: None,
+            rate_limit: None,
+            trace_sampling: None,
+            idle_reap: None,
+            idle_timeout: None,
+            accept_batch: DEFAULT_ACCEPT_BATCH,
+            handshake_timeout: DEFAULT_HANDSHAKE_TIMEOUT,
+            max_connections: None,
+        };
+        let tls_opt = TlsServerOption {
+            session_tickets: true,
+            ..test_tls_server_option()
+        };
+        let srv = TcpServer::init(opt, Some(tls_opt)).unwrap();
+        tokio::spawn(async move { srv.serve(EchoPrefixCallback).await });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let client = |tls_opt: TlsClientOption| {
+            let opt = TcpClientOption {
+                addr: "127.0.0.1".into(),
+                port: 9839,
+                tcp_nodelay: false,
+                connect_timeout: None,
+                connect_deadline: None,
+                attempt_delay: None,
+                resolve_on_connect: false,
+                proxy: None,
+                bind_addr: None,
+                bind_device: None,
+            };
+            let tls_opt = TlsClientOption {
+                insecure: true,
+                server_name: "localhost".into(),
+                cache: false,
+                ..tls_opt
+            };
+            TcpClient::init(opt, Some(tls_opt), &Resolver::default()).unwrap()
+        };
+        async fn connect(cli: &TcpClient) -> bool {
+            let mut s = cli.connect().await.unwrap();
+            // the tickets arrive ahead of the echo
+            s.write_all(b"kapi").await.unwrap();
+            s.read_exact(&mut [0u8; 4]).await.unwrap();
+            s.is_resumed()
+        }
+
+        // the session cache lives as long as the client, across connects
+        let cli = client(TlsClientOption::default());
+        assert!(!connect(&cli).await);
+        assert!(connect(&cli).await);
+        assert!(connect(&cli).await);
+
+        // the server takes no early data, the handshake goes on without it
+        let cli = client(TlsClientOption {
+            enable_early_data: true,
+            ..Default::default()
+        });
+        assert!(!connect(&cli).await);
+        assert!(connect(&cli).await);
+
+        let cli = client(TlsClientOption {
+            session_cache_size: 0,
+            ..Default::default()
+        });
+        assert!(!connect(&cli).await);
+        assert!(!connect(&cli).await);
+    }
+
+    #[cfg(feature = "insecure-tls")]
+    #[tokio::test]
+    async fn test_tls_reload() {
+        use std::time::Duration;
+
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        use crate::{tls::cert_sha256, TlsError, TransportServerTrait};
+
+        let opt = |tls_mode| TcpServerOption {
+            listen: ([127, 0, 0, 1], 9838).into(),
+            tcp_nodelay: false,
+            tls_mode,
+            sniff: None,
+            max_bytes: None,
+            rate_limit: None,
+            trace_sampling: None,
+            idle_reap: None,
+            idle_timeout: None,
+            accept_batch: DEFAULT_ACCEPT_BATCH,
+            handshake_timeout: DEFAULT_HANDSHAKE_TIMEOUT,
+            max_connections: None,
+        };
+        let first = test_tls_server_option();
+        let first_cert = first.certificate.load_certs().unwrap().remove(0);
+        let srv = Arc::new(TcpServer::init(opt(TlsMode::Required), Some(first)).unwrap());
+        let serving = srv.clone();
+        tokio::spawn(async move { serving.serve(EchoPrefixCallback).await });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let tcp_opt = TcpClientOption {
+            addr: "127.0.0.1".into(),
+            port: 9838,
+            tcp_nodelay: false,
+            connect_timeout: None,
+            connect_deadline: None,
+            attempt_delay: None,
+            resolve_on_connect: false,
+            proxy: None,
+            bind_addr: None,
+            bind_device: None,
+        };
+        let tls_opt = TlsClientOption {
+            insecure: true,
+            server_name: "localhost".into(),
+            ..Default::default()
+        };
+        let cli = TcpClient::init(tcp_opt, Some(tls_opt), &Resolver::default()).unwrap();
+        let mut open = cli.connect().await.unwrap();
+        assert_eq!(open.peer_cert_sha256(), Some(cert_sha256(&first_cert)));
+
+        let second = test_tls_server_option();
+        let second_cert = second.certificate.load_certs().unwrap().remove(0);
+        srv.reload_tls(second).unwrap();
+        // a bad certificate fails the reload, not the server
+        let bad = TlsServerOption {
+            certificate: TlsCertOption::Text {
+                certs: vec!["not a pem".into()],
+                key: String::new().into(),
+            },
+            ..test_tls_server_option()
+        };
+        assert!(matches!(srv.reload_tls(bad), Err(TlsError::InvalidCert(_))));
+
+        let mut next = cli.connect().await.unwrap();
+        assert_eq!(next.peer_cert_sha256(), Some(cert_sha256(&second_cert)));
+        for s in [&mut open, &mut next] {
+            s.write_all(b"kapi").await.unwrap();
+            let mut buf = [0u8; 4];
+            s.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"kapi");
+        }
+
+        let plain = TcpServer::init(
+            TcpServerOption {
+                listen: "127.0.0.1:0".parse().unwrap(),
+                ..opt(TlsMode::Disabled)
+            },
+            None,
+        )
+        .unwrap();
+        let err = plain.reload_tls(test_tls_server_option()).unwrap_err();
+        assert!(matches!(err, TlsError::InvalidConfig(_)), "{}", err);
+    }
+
+    #[cfg(feature = "insecure-tls")]
+    #[tokio::test]
+    async fn test_trace_sampling_on_error() {
+        use std::{sync::Mutex, time::Duration};
+
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        use crate::{
+            trace::{SamplingOption, TraceEvent, TraceSink},
+            TransportServerTrait,
+        };
+
+        let opt = TcpServerOption {
+            listen: "127.0.0.1:9864".parse().unwrap(),
+            tcp_nodelay: false,
+            tls_mode: TlsMode::Required,
+            sniff: None,
+            max_bytes: None,
+            rate_limit: None,
+            trace_sampling: Some(SamplingOption {
+                ratio: 0.0,
+                always_on_error: true,
+            }),
+            idle_reap: None,
+            idle_timeout: None,
+            accept_batch: DEFAULT_ACCEPT_BATCH,
+            handshake_timeout: DEFAULT_HANDSHAKE_TIMEOUT,
+            max_connections: None,
+        };
+        let events = Arc::new(Mutex::new(Vec::<TraceEvent>::new()));
+        let sink_events = events.clone();
+        let sink: TraceSink = Arc::new(move |e: &TraceEvent| {
+            sink_events.lock().unwrap().push(e.clone());
+        });
+        let mut srv = TcpServer::init(opt, Some(test_tls_server_option())).unwrap();
+        srv.set_trace_sink(sink);
+        tokio::spawn(async move { srv.serve(EchoPrefixCallback).await });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        // an unsampled connection that closes cleanly emits nothing
+        let opt = TcpClientOption {
+            addr: "127.0.0.1".into(),
+            port: 9864,
+            tcp_nodelay: false,
+            connect_timeout: None,
+            connect_deadline: None,
+            attempt_delay: None,
+            resolve_on_connect: false,
+            proxy: None,
+            bind_addr: None,
+            bind_device: None,
+        };
+        let tls_opt = TlsClientOption {
+            insecure: true,
+            server_name: "localhost".into(),
+            ..Default::default()
+        };
+        let cli = TcpClient::init(opt, Some(tls_opt), &Resolver::default()).unwrap();
+        let mut s = cli.connect().await.unwrap();
+        s.write_all(b"kapi").await.unwrap();
+        let mut buf = vec![];
+        s.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, b"kapi");
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(events.lock().unwrap().is_empty());
+
+        // a failed one emits everything it held back
+        let mut s = tokio::net::TcpStream::connect("127.0.0.1:9864")
+            .await
+            .unwrap();
+        s.write_all(&[0x16, 0x03, 0x01, 0x00, 0x04, 0xde, 0xad, 0xbe, 0xef])
+            .await
+            .unwrap();
+        let _ = s.read_to_end(&mut vec![]).await;
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let events = events.lock().unwrap();
+        let messages = events
+            .iter()
+            .map(|e| e.message.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(messages.len(), 3, "{:?}", messages);
+        assert!(messages[0].starts_with("accepted from"));
+        assert_eq!(messages[1], "routed Tls");
+        assert!(messages[2].starts_with("tls handshake failed"));
+        assert!(events.iter().all(|e| e.retroactive));
+    }
+
+    #[tokio::test]
+    async fn test_idle_reap() {
+        use std::time::Duration;
+
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        use crate::TransportServerTrait;
+
+        let opt = TcpServerOption {
+            listen: "127.0.0.1:9862".parse().unwrap(),
+            tcp_nodelay: false,
+            tls_mode: TlsMode::Disabled,
+            sniff: None,
+            max_bytes: None,
+            rate_limit: None,
+            trace_sampling: None,
+            idle_reap: Some(Duration::from_millis(100)),
+            idle_timeout: None,
+            accept_batch: DEFAULT_ACCEPT_BATCH,
+            handshake_timeout: DEFAULT_HANDSHAKE_TIMEOUT,
+            max_connections: None,
+        };
+        let srv = Arc::new(TcpServer::init(opt, None).unwrap());
+        let serving = srv.clone();
+        tokio::spawn(async move { serving.serve(EchoPrefixCallback).await });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        // an idle connection is closed by the server
+        let mut idle = tokio::net::TcpStream::connect("127.0.0.1:9862")
+            .await
+            .unwrap();
+        let read = tokio::time::timeout(Duration::from_secs(2), idle.read(&mut [0u8; 4])).await;
+        assert!(matches!(read, Ok(Ok(0)) | Ok(Err(_))), "{:?}", read);
+        assert_eq!(srv.stats().reaped, 1);
+
+        // one moving data within the threshold is not
+        let mut active = tokio::net::TcpStream::connect("127.0.0.1:9862")
+            .await
+            .unwrap();
+        for byte in b"kapi" {
+            tokio::time::sleep(Duration::from_millis(40)).await;
+            active.write_all(&[*byte]).await.unwrap();
+        }
+        let mut buf = vec![];
+        active.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, b"kapi");
+        assert_eq!(srv.stats().reaped, 1);
+    }
+
+    #[derive(Clone)]
+    struct GreetCallback;
+
+    impl crate::TransportServerCallback for GreetCallback {
+        async fn handle<S>(&self, mut stream: S, _addr: Option<std::net::SocketAddr>)
+        where
+            S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + Sync,
+        {
+            use tokio::io::AsyncWriteExt;
+
+            let _ = stream.write_all(b"k").await;
+        }
+    }
+
+    fn spawn_greet_server(port: u16, accept_batch: usize) {
+        use crate::TransportServerTrait;
+
+        let opt = TcpServerOption {
+            listen: ([127, 0, 0, 1], port).into(),
+            tcp_nodelay: true,
+            tls_mode: TlsMode::Disabled,
+            sniff: None,
+            max_bytes: None,
+            rate_limit: None,
+            trace_sampling: None,
+            idle_reap: None,
+            idle_timeout: None,
+            accept_batch,
+            handshake_timeout: DEFAULT_HANDSHAKE_TIMEOUT,
+            max_connections: None,
+        };
+        let srv = TcpServer::init(opt, None).unwrap();
+        tokio::spawn(async move { srv.serve(GreetCallback).await });
+    }
+
+    /// Time from connecting until the greeting of each of `count` simultaneous connections.
+    async fn greet_latencies(port: u16, count: usize) -> Vec<std::time::Duration> {
+        use tokio::io::AsyncReadExt;
+
+        let start = std::time::Instant::now();
+        let tasks = (0..count)
+            .map(|_| {
+                tokio::spawn(tokio::time::timeout(
+                    std::time::Duration::from_secs(10),
+                    async move {
+                        let mut s = tokio::net::TcpStream::connect(("127.0.0.1", port)).await?;
+                        s.read_exact(&mut [0u8; 1]).await?;
+                        Ok::<_, std::io::Error>(start.elapsed())
+                    },
+                ))
+            })
+            .collect::<Vec<_>>();
+        let mut latencies = vec![];
+        for task in tasks {
+            // connections lost to a full backlog time out and are left out
+            if let Ok(Ok(elapsed)) = task.await.unwrap() {
+                latencies.push(elapsed);
+            }
+        }
+        latencies.sort();
+        latencies
+    }
+
+    #[tokio::test]
+    async fn test_accept_batch() {
+        spawn_greet_server(9861, 4);
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+
+        assert_eq!(greet_latencies(9861, 50).await.len(), 50);
+
+        let opt: TcpServerOption = serde_json::from_str(r#"{"listen": "127.0.0.1:0"}"#).unwrap();
+        assert_eq!(opt.accept_batch, DEFAULT_ACCEPT_BATCH);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore = "benchmark, run with --ignored --nocapture"]
+    async fn bench_accept_burst() {
+        const BURST: usize = 5000;
+
+        for (port, accept_batch) in [(9860, 1), (9859, DEFAULT_ACCEPT_BATCH)] {
+            spawn_greet_server(port, accept_batch);
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+
+            let latencies = greet_latencies(port, BURST).await;
+            let at = |q: f64| latencies[((latencies.len() - 1) as f64 * q) as usize];
+            println!(
+                "accept_batch={} {}/{} accepted, p50 {:?} p99 {:?} max {:?}",
+                accept_batch,
+                latencies.len(),
+                BURST,
+                at(0.5),
+                at(0.99),
+                at(1.0)
+            );
+        }
+    }
+
+    #[derive(Debug, Clone)]
+    struct StallCallback(tokio::sync::mpsc::UnboundedSender<std::io::Result<usize>>);
+
+    impl crate::TransportServerCallback for StallCallback {
+        async fn handle<S>(&self, mut stream: S, _addr: Option<std::net::SocketAddr>)
+        where
+            S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + Sync,
+        {
+            use tokio::io::AsyncReadExt;
+
+            let mut buf = [0u8; 16];
+            let first = stream.read(&mut buf).await;
+            let _ = self.0.send(first);
+            let _ = self.0.send(stream.read(&mut buf).await);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_idle_timeout() {
+        use std::time::{Duration, Instant};
+
+        use tokio::io::AsyncWriteExt;
+
+        use crate::TransportServerTrait;
+
+        let opt = TcpServerOption {
+            listen: "127.0.0.1:9842".parse().unwrap(),
+            tcp_nodelay: false,
+            tls_mode: TlsMode::Disabled,
+            sniff: None,
+            max_bytes: None,
+            rate_limit: None,
+            trace_sampling: None,
+            idle_reap: None,
+            idle_timeout: Some(Duration::from_millis(200)),
+            accept_batch: DEFAULT_ACCEPT_BATCH,
+            handshake_timeout: DEFAULT_HANDSHAKE_TIMEOUT,
+            max_connections: None,
+        };
+        let srv = TcpServer::init(opt, None).unwrap();
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(async move { srv.serve(StallCallback(tx)).await });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let mut client = tokio::net::TcpStream::connect("127.0.0.1:9842")
+            .await
+            .unwrap();
+        // progress well inside the timeout, then silence
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        client.write_all(b"hi").await.unwrap();
+        assert_eq!(rx.recv().await.unwrap().unwrap(), 2);
+
+        let start = Instant::now();
+        let err = rx.recv().await.unwrap().unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+        assert!(start.elapsed() >= Duration::from_millis(150));
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[derive(Clone)]
+    struct CountCallback(tokio::sync::mpsc::UnboundedSender<Arc<crate::stats::StreamStats>>);
+
+    impl crate::TransportServerCallback for CountCallback {
+        async fn handle<S>(&self, _stream: S, _addr: Option<std::net::SocketAddr>)
+        where
+            S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + Sync,
+        {
+            unreachable!("servers call handle_ctx")
+        }
+
+        async fn handle_ctx<S>(&self, mut stream: S, ctx: crate::ConnContext)
+        where
+            S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + Sync,
+        {
+            use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+            let handle = ctx.extensions.get::<Arc<crate::stats::StreamStats>>();
+            let _ = self.0.send(handle.unwrap().clone());
+            let _ = stream.read_to_end(&mut vec![]).await;
+            let _ = stream.write_all(b"done").await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_stream_stats() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        use crate::TransportServerTrait;
+
+        const MIB: u64 = 1024 * 1024;
+
+        let opt = TcpServerOption {
+            listen: "127.0.0.1:9841".parse().unwrap(),
+            tcp_nodelay: false,
+            tls_mode: TlsMode::Disabled,
+            sniff: None,
+            max_bytes: None,
+            rate_limit: None,
+            trace_sampling: None,
+            idle_reap: None,
+            idle_timeout: None,
+            accept_batch: DEFAULT_ACCEPT_BATCH,
+            handshake_timeout: DEFAULT_HANDSHAKE_TIMEOUT,
+            max_connections: None,
+        };
+        let srv = TcpServer::init(opt, None).unwrap();
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(async move { srv.serve(CountCallback(tx)).await });
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+
+        let opt = TcpClientOption {
+            addr: "127.0.0.1".into(),
+            port: 9841,
+            tcp_nodelay: false,
+            connect_timeout: None,
+            connect_deadline: None,
+            attempt_delay: None,
+            resolve_on_connect: false,
+            proxy: None,
+            bind_addr: None,
+            bind_device: None,
+        };
+        let cli = TcpClient::init(opt, None, &Resolver::default()).unwrap();
+        let mut s = TransportClientStream::from(cli.connect().await.unwrap()).counted();
+        let client_stats = s.stats_handle();
+        let server_stats = rx.recv().await.unwrap();
+
+        // the client moves into a task of its own, the handle stays
+        let sender = tokio::spawn(async move {
+            let chunk = vec![7u8; 64 * 1024];
+            for _ in 0..MIB / chunk.len() as u64 {
+                s.write_all(&chunk).await.unwrap();
+            }
+            s.shutdown().await.unwrap();
+            let mut done = vec![];
+            s.read_to_end(&mut done).await.unwrap();
+            assert_eq!(done, b"done");
+            s.stats()
+        });
+        let stats = sender.await.unwrap();
+        assert_eq!((stats.written_bytes, stats.read_bytes), (MIB, 4));
+        assert_eq!(client_stats.snapshot(), stats);
+
+        let read = server_stats.read_bytes();
+        assert!((MIB..MIB + MIB / 100).contains(&read), "{}", read);
+        assert_eq!(server_stats.written_bytes(), 4);
+        assert!(server_stats.established_at() <= std::time::Instant::now());
+    }
+
+    #[derive(Debug, Clone)]
+    struct HoldCallback;
+
+    impl crate::TransportServerCallback for HoldCallback {
+        async fn handle<S>(&self, mut stream: S, _addr: Option<std::net::SocketAddr>)
+        where
+            S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + Sync,
+        {
+            use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+            // served until the client hangs up
+            let _ = stream.write_all(b"!").await;
+            let _ = stream.read_to_end(&mut vec![]).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_max_connections() {
+        use std::time::Duration;
+
+        use tokio::io::AsyncReadExt;
+
+        use crate::TransportServerTrait;
+
+        let opt = TcpServerOption {
+            listen: "127.0.0.1:9843".parse().unwrap(),
+            tcp_nodelay: false,
+            tls_mode: TlsMode::Disabled,
+            sniff: None,
+            max_bytes: None,
+            rate_limit: None,
+            trace_sampling: None,
+            idle_reap: None,
+            idle_timeout: None,
+            accept_batch: DEFAULT_ACCEPT_BATCH,
+            handshake_timeout: DEFAULT_HANDSHAKE_TIMEOUT,
+            max_connections: Some(2),
+        };
+        let srv = Arc::new(TcpServer::init(opt, None).unwrap());
+        let serving = srv.clone();
+        tokio::spawn(async move { serving.serve(HoldCallback).await });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let mut clients = vec![];
+        for _ in 0..3 {
+            clients.push(
+                tokio::net::TcpStream::connect("127.0.0.1:9843")
+                    .await
+                    .unwrap(),
+            );
+        }
+        let mut buf = [0u8; 1];
+        for client in &mut clients[..2] {
+            client.read_exact(&mut buf).await.unwrap();
+        }
+        // the third waits in the backlog
+        let deferred =
+            tokio::time::timeout(Duration::from_millis(200), clients[2].read_exact(&mut buf)).await;
+        assert!(deferred.is_err());
+        assert_eq!(srv.active_connections(), 2);
+
+        drop(clients.remove(0));
+        tokio::time::timeout(Duration::from_secs(1), clients[1].read_exact(&mut buf))
+            .await
+            .expect("third connection not served after a handler returned")
+            .unwrap();
+        assert_eq!(srv.active_connections(), 2);
+
+        clients.clear();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(srv.active_connections(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_bind_at_init() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        use crate::{ServerError, TransportServerTrait};
+
+        let opt = TcpServerOption {
+            listen: "127.0.0.1:0".parse().unwrap(),
+            tcp_nodelay: false,
+            tls_mode: TlsMode::Disabled,
+            sniff: None,
+            max_bytes: None,
+            rate_limit: None,
+            trace_sampling: None,
+            idle_reap: None,
+            idle_timeout: None,
+            accept_batch: DEFAULT_ACCEPT_BATCH,
+            handshake_timeout: DEFAULT_HANDSHAKE_TIMEOUT,
+            max_connections: None,
+        };
+        let srv = Arc::new(TcpServer::init(opt.clone(), None).unwrap());
+        let addr = srv.local_addr().unwrap();
+        assert_ne!(addr.port(), 0);
+
+        // the port is taken from init on
+        let taken = TcpServerOption {
+            listen: addr,
+            ..opt
+        };
+        let Err(ServerError::Io(e)) = TcpServer::init(taken, None) else {
+            panic!("bound a port in use");
+        };
+        assert_eq!(e.kind(), std::io::ErrorKind::AddrInUse);
+
+        // a connection before serve waits in the backlog
+        let mut early = tokio::net::TcpStream::connect(addr).await.unwrap();
+        early.write_all(b"ping").await.unwrap();
+        tokio::spawn({
+            let srv = srv.clone();
+            async move { srv.serve(EchoPrefixCallback).await }
+        });
+        let mut buf = [0u8; 4];
+        early.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+        assert_eq!(srv.local_addr(), Some(addr));
+    }
+
+    #[tokio::test]
+    async fn test_serve_with_shutdown() {
+        use std::time::{Duration, Instant};
+
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        use crate::TransportServerTrait;
+
+        /// Serve on a free port until the returned sender fires.
+        async fn spawn_server(
+            grace: Duration,
+        ) -> (
+            Arc<TcpServer>,
+            tokio::sync::oneshot::Sender<()>,
+            tokio::task::JoinHandle<crate::ServerResult<()>>,
+        ) {
+            let opt = TcpServerOption {
+                listen: "127.0.0.1:0".parse().unwrap(),
+                tcp_nodelay: false,
+                tls_mode: TlsMode::Disabled,
+                sniff: None,
+                max_bytes: None,
+                rate_limit: None,
+                trace_sampling: None,
+                idle_reap: None,
+                idle_timeout: None,
+                accept_batch: DEFAULT_ACCEPT_BATCH,
+                handshake_timeout: DEFAULT_HANDSHAKE_TIMEOUT,
+                max_connections: None,
+            };
+            let srv = Arc::new(TcpServer::init(opt, None).unwrap());
+            let (tx, rx) = tokio::sync::oneshot::channel();
+            let serving = srv.clone();
+            let handle = tokio::spawn(async move {
+                let signal = async {
+                    let _ = rx.await;
+                };
+                serving
+                    .serve_with_shutdown(EchoPrefixCallback, signal, grace)
+                    .await
+            });
+            (srv, tx, handle)
+        }
+
+        // an active connection finishes within the grace period
+        let (srv, tx, handle) = spawn_server(Duration::from_secs(5)).await;
+        let addr = srv.local_addr().unwrap();
+        let mut active = tokio::net::TcpStream::connect(addr).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        tx.send(()).unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        assert!(tokio::net::TcpStream::connect(addr).await.is_err());
+        assert_eq!(srv.local_addr(), Some(addr));
+        assert!(!handle.is_finished());
+        active.write_all(b"ping").await.unwrap();
+        let mut buf = vec![];
+        active.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, b"ping");
+        let res = tokio::time::timeout(Duration::from_secs(1), handle).await;
+        assert!(res.unwrap().unwrap().is_ok());
+
+        // one outliving the grace period has its stream invalidated
+        let (srv, tx, handle) = spawn_server(Duration::from_millis(100)).await;
+        let mut stuck = tokio::net::TcpStream::connect(srv.local_addr().unwrap())
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let start = Instant::now();
+        tx.send(()).unwrap();
+        handle.await.unwrap().unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(100));
+        assert_eq!(stuck.read(&mut [0u8; 4]).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_from_listener() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        use crate::TransportServerTrait;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        // the listen address of the option is not bound
+        let opt = TcpServerOption {
+            listen: "127.0.0.1:9".parse().unwrap(),
+            tcp_nodelay: true,
+            tls_mode: TlsMode::Disabled,
+            sniff: None,
+            max_bytes: None,
+            rate_limit: None,
+            trace_sampling: None,
+            idle_reap: None,
+            idle_timeout: None,
+            accept_batch: DEFAULT_ACCEPT_BATCH,
+            handshake_timeout: DEFAULT_HANDSHAKE_TIMEOUT,
+            max_connections: None,
+        };
+        let srv = Arc::new(TcpServer::from_listener(listener, opt, None).unwrap());
+        assert_eq!(srv.local_addr(), Some(addr));
+
+        let serving = srv.clone();
+        let handle = tokio::spawn(async move { serving.serve(EchoPrefixCallback).await });
+        let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        stream.write_all(b"ping").await.unwrap();
+        let mut buf = vec![];
+        stream.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, b"ping");
+        assert_eq!(srv.stats().ipv4.accepted, 1);
+
+        // the listener outlives a serve, the next one picks it up
+        handle.abort();
+        let _ = handle.await;
+        let serving = srv.clone();
+        tokio::spawn(async move { serving.serve(EchoPrefixCallback).await });
+        let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        stream.write_all(b"pong").await.unwrap();
+        let mut buf = vec![];
+        stream.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, b"pong");
+    }
+
+    #[cfg(feature = "insecure-tls")]
+    #[tokio::test]
+    async fn test_connect_over() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        use crate::{empty::EmptyClient, ClientError, TransportClient};
+
+        let config: TlsServerConfig = test_tls_server_option().try_into().unwrap();
+        let acceptor = TlsAcceptor::from(Arc::new(config));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (s, _) = listener.accept().await.unwrap();
+            let mut s = acceptor.accept(s).await.unwrap();
+            s.write_all(b"hello").await.unwrap();
+            s.flush().await.unwrap();
+        });
+
+        // the client is pointed elsewhere, only the given stream is used
+        let opt = TcpClientOption {
+            addr: "localhost".into(),
+            port: 1,
+            tcp_nodelay: false,
+            connect_timeout: None,
+            connect_deadline: None,
+            attempt_delay: None,
+            resolve_on_connect: false,
+            proxy: None,
+            bind_addr: None,
+            bind_device: None,
+        };
+        let tls_opt = TlsClientOption {
+            insecure: true,
+            server_name: "localhost".into(),
+            ..Default::default()
+        };
+        let unused = vec![(std::net::Ipv4Addr::LOCALHOST, 1).into()];
+        let cli: TransportClient = TcpClient::with_addrs(opt, Some(tls_opt), unused)
+            .unwrap()
+            .into();
+        let s = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let mut stream = cli.connect_over(s).await.unwrap();
+        let TransportClientStream::Tcp(ref tcp) = stream else {
+            panic!("not a tcp stream");
+        };
+        assert!(tcp.is_tls());
+        let mut buf = [0u8; 5];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let s = tokio::net::TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let Err(err) = TransportClient::from(EmptyClient).connect_over(s).await else {
+            panic!("empty client connected over a stream");
+        };
+        assert!(matches!(err, ClientError::Option(_)), "{}", err);
+    }
+
+    #[cfg(feature = "insecure-tls")]
+    #[tokio::test]
+    async fn test_tls_client_auth() {
+        use rcgen::{BasicConstraints, CertificateParams, ExtendedKeyUsagePurpose, IsCa, KeyPair};
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let ca_key = KeyPair::generate().unwrap();
+        let mut params = CertificateParams::new(vec![]).unwrap();
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca = params.self_signed(&ca_key).unwrap();
+        let key = KeyPair::generate().unwrap();
+        let mut params = CertificateParams::new(vec!["edge.test".into()]).unwrap();
+        params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ClientAuth];
+        let cert = params.signed_by(&key, &ca, &ca_key).unwrap();
+
+        let config: TlsServerConfig = TlsServerOption {
+            client_ca: Some(TlsCertOption::Text {
+                certs: vec![ca.pem()],
+                key: String::new().into(),
+            }),
+            require_client_cert: true,
+            ..test_tls_server_option()
+        }
+        .try_into()
+        .unwrap();
+        let acceptor = TlsAcceptor::from(Arc::new(config));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let mut verified = vec![];
+            for _ in 0..2 {
+                let (s, _) = listener.accept().await.unwrap();
+                match acceptor.accept(s).await {
+                    Ok(mut s) => {
+                        let certs = s.get_ref().1.peer_certificates().unwrap().len();
+                        s.write_all(b"ok").await.unwrap();
+                        s.flush().await.unwrap();
+                        verified.push(Some(certs));
+                    }
+                    Err(_) => verified.push(None),
+                }
+            }
+            verified
+        });
+
+        let client = |certificate| {
+            let opt = TcpClientOption {
+                addr: addr.ip().to_string(),
+                port: addr.port(),
+                tcp_nodelay: false,
+                connect_timeout: None,
+                connect_deadline: None,
+                attempt_delay: None,
+                resolve_on_connect: false,
+                proxy: None,
+                bind_addr: None,
+                bind_device: None,
+            };
+            let tls_opt = TlsClientOption {
+                insecure: true,
+                server_name: "localhost".into(),
+                certificate,
+                ..Default::default()
+            };
+            TcpClient::init(opt, Some(tls_opt), &Resolver::default()).unwrap()
+        };
+
+        let with_cert = client(Some(TlsCertOption::Text {
+            certs: vec![cert.pem()],
+            key: key.serialize_pem().into(),
+        }));
+        let mut stream = with_cert.connect().await.unwrap();
+        let mut buf = [0u8; 2];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ok");
+
+        // tls 1.3 clients finish first, the rejection shows on the first read
+        let without = client(None);
+        let res = match without.connect().await {
+            Ok(mut stream) => stream.read_exact(&mut buf).await.map(|_| ()),
+            Err(e) => Err(std::io::Error::other(e)),
+        };
+        assert!(res.is_err());
+
+        assert_eq!(server.await.unwrap(), [Some(1), None]);
+    }
+}
@@ -1,15 +1,43 @@
 //! Transport Tcp Option
 
-use std::net::SocketAddr;
+use std::{net::SocketAddr, time::Duration};
 
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+use crate::{
+    io::RateLimitOption, limit::MaxBytesOption, option::ProxyOption, trace::SamplingOption,
+};
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct TcpClientOption {
     pub addr: String,
     pub port: u16,
     #[serde(default)]
     pub tcp_nodelay: bool,
+    /// Give up on one resolved address after this long and try the next.
+    #[serde(default)]
+    pub connect_timeout: Option<Duration>,
+    /// Bound on the whole connect across every resolved address.
+    #[serde(default)]
+    pub connect_deadline: Option<Duration>,
+    /// Race the resolved addresses, starting the next one when the last
+    /// did not connect within this long, see `Dialer::set_attempt_delay`.
+    #[serde(default)]
+    pub attempt_delay: Option<Duration>,
+    /// Resolve `addr` on every connect instead of once at init.
+    #[serde(default)]
+    pub resolve_on_connect: bool,
+    /// Connect through this proxy, which resolves `addr` itself.
+    #[serde(default)]
+    pub proxy: Option<ProxyOption>,
+    /// Source address of outbound connections, resolved addresses of the
+    /// other family are skipped.
+    #[serde(default)]
+    pub bind_addr: Option<SocketAddr>,
+    /// Interface outbound connections go out of, with `SO_BINDTODEVICE`.
+    /// Linux only.
+    #[serde(default)]
+    pub bind_device: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -17,4 +45,84 @@ pub struct TcpServerOption {
     pub listen: SocketAddr,
     #[serde(default)]
     pub tcp_nodelay: bool,
+    /// How the tls option is applied to accepted connections.
+    #[serde(default)]
+    pub tls_mode: TlsMode,
+    /// Peek at the first bytes to choose between tls and plaintext.
+    #[serde(default)]
+    pub sniff: Option<SniffOption>,
+    /// Per connection byte limits.
+    #[serde(default)]
+    pub max_bytes: Option<MaxBytesOption>,
+    /// Bandwidth of each connection, and of all of them together.
+    #[serde(default)]
+    pub rate_limit: Option<RateLimitOption>,
+    /// Trace a share of the connections, see `Sampler`.
+    #[serde(default)]
+    pub trace_sampling: Option<SamplingOption>,
+    /// Close connections that moved no data for this long.
+    #[serde(default)]
+    pub idle_reap: Option<Duration>,
+    /// Connections accepted per wakeup before yielding, 1 accepts one at a time.
+    #[serde(default = "default_accept_batch")]
+    pub accept_batch: usize,
+    /// Drop connections whose tls handshake did not complete in time.
+    #[serde(default = "default_handshake_timeout")]
+    pub handshake_timeout: Duration,
+    /// Connections served at once, counted from accept until the callback
+    /// returns. Further connections wait in the listen backlog.
+    #[serde(default)]
+    pub max_connections: Option<usize>,
+    /// Fail reads and writes of the callback stream with `TimedOut` when
+    /// they make no progress for this long, see `TimeoutStream`.
+    #[serde(default)]
+    pub idle_timeout: Option<Duration>,
+}
+
+/// Default of `accept_batch`.
+pub const DEFAULT_ACCEPT_BATCH: usize = 32;
+
+/// Default of `handshake_timeout`.
+pub const DEFAULT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+fn default_accept_batch() -> usize {
+    DEFAULT_ACCEPT_BATCH
+}
+
+fn default_handshake_timeout() -> Duration {
+    DEFAULT_HANDSHAKE_TIMEOUT
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TlsMode {
+    /// Run the tls acceptor on every connection, unless `sniff` routes it elsewhere.
+    #[default]
+    Required,
+    /// Sniff every connection and only run the tls acceptor on a client hello.
+    ///
+    /// Uses `sniff` when set, otherwise the first byte with a one second timeout.
+    Optional,
+    /// Serve plaintext, ignoring the tls option.
+    Disabled,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SniffOption {
+    /// Bytes to peek before classifying, fewer if the timeout hits first.
+    pub bytes: usize,
+    pub timeout: Duration,
+    /// Route taken when nothing arrived within the timeout.
+    #[serde(default)]
+    pub default_route: Route,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Route {
+    /// Run the tls acceptor, plaintext if the server has no tls config.
+    #[default]
+    Tls,
+    Plain,
+    Drop,
 }
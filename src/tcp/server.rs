@@ -1,82 +1,719 @@
 //! Transport Tcp Server
 
-use std::{net::SocketAddr, sync::Arc};
+use std::{
+    future::Future,
+    mem::MaybeUninit,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 
-use rustls::ServerConfig as TlsServerConfig;
-use tokio::net::TcpListener;
-use tokio_rustls::{TlsAcceptor, TlsStream};
+use arc_swap::ArcSwap;
+use futures_util::FutureExt;
+use rustls::server::Acceptor;
+use socket2::SockRef;
+use tokio::{
+    io::{AsyncWriteExt, Interest},
+    net::{TcpListener, TcpStream as TokioTcpStream},
+    sync::{OwnedSemaphorePermit, Semaphore},
+};
+use tokio_rustls::{LazyConfigAcceptor, TlsAcceptor, TlsStream};
 
 use crate::{
-    ServerError, ServerResult, TlsServerOption, TransportServerCallback, TransportServerTrait,
+    context::{AcceptHook, AlpnProtocol, ConnContext, Security, ServerName},
+    io::{throttle::throttle, RateLimits, TimeoutStream},
+    limit::{LimitedStream, MaxBytesOption},
+    net::{bind_listener, BoundAddr, EarlyListener},
+    observe::{self, ServeObserver},
+    option::{LatencyProfile, ServerOption, CONFIG_VERSION},
+    reap::{IdleStream, Reaper},
+    reload::{changed_paths, AppliedChanges},
+    shutdown::{killable, Tasks, DEFAULT_KILL_GRACE},
+    stats::{CountedStream, ServerStats, ServerStatsSnapshot},
+    tls::{
+        watch::{self, WatchTask},
+        ExportRequest, TicketKeys, TlsExporter, WATCH_INTERVAL,
+    },
+    trace::{ConnTrace, Sampler, TraceSink, TracedStream},
+    ServerError, ServerResult, TlsError, TlsServerOption, TransportServerCallback,
+    TransportServerOption, TransportServerTrait,
 };
 
-use super::{TcpServerOption, TcpStream};
+use super::{Route, SniffOption, TcpServerOption, TcpStream, TlsMode};
+
+/// Picks a route from the sniffed prefix and the peer address.
+pub type Classifier = Arc<dyn Fn(&[u8], SocketAddr) -> Route + Send + Sync>;
+
+/// Tls records start with a handshake content type, anything else is plaintext.
+pub fn default_classify(prefix: &[u8], _addr: SocketAddr) -> Route {
+    match prefix.first() {
+        Some(0x16) => Route::Tls,
+        _ => Route::Plain,
+    }
+}
 
 pub struct TcpServer {
     local_addr: SocketAddr,
+    bound_addr: BoundAddr,
+    /// Listener bound by `init`, taken by the first `serve`.
+    early: EarlyListener,
+    /// Listener passed in by `from_listener`, `serve` binds `local_addr` otherwise.
+    listener: Option<Arc<TcpListener>>,
+    live: Arc<ArcSwap<Live>>,
+    stats: Arc<ServerStats>,
+    accept_hooks: Vec<AcceptHook>,
+    exports: Vec<ExportRequest>,
+    classifier: Classifier,
+    sampler: Option<Sampler>,
+    observer: Option<Arc<dyn ServeObserver>>,
+    reaper: Option<Arc<Reaper>>,
+    accept_batch: usize,
+    send_buffer_size: Option<u32>,
+    busy_poll: Option<u32>,
+    kill_grace: Duration,
+    connection_limit: Option<Arc<Semaphore>>,
+    active: Arc<AtomicUsize>,
+}
+
+/// Settings read per accepted connection, swapped as a whole by `apply`.
+#[derive(Clone)]
+struct Live {
     tls_acceptor: Option<TlsAcceptor>,
+    ticket_keys: Option<Arc<TicketKeys>>,
+    require_alpn: bool,
     tcp_nodelay: bool,
+    sniff: Option<SniffOption>,
+    max_bytes: Option<MaxBytesOption>,
+    rate_limit: Option<RateLimits>,
+    handshake_timeout: Duration,
+    idle_timeout: Option<Duration>,
+    /// The option the settings were built from, diffed by `apply`.
+    source: Arc<TransportServerOption>,
 }
 
+/// Option paths bound when `serve` starts.
+const RESTART_PATHS: &[&str] = &[
+    "opt.tcp.listen",
+    "opt.tcp.accept_batch",
+    "opt.tcp.max_connections",
+    "opt.tcp.idle_reap",
+    "opt.tcp.trace_sampling",
+    "latency_profile",
+];
+
+/// Fatal `no_application_protocol` alert record.
+const NO_APPLICATION_PROTOCOL_ALERT: [u8; 7] = [0x15, 0x03, 0x03, 0x00, 0x02, 0x02, 0x78];
+
 impl TcpServer {
+    /// Build the server and bind `opt.listen`, `local_addr` then tells the
+    /// port of a listen address with port 0.
     pub fn init(opt: TcpServerOption, tls_opt: Option<TlsServerOption>) -> ServerResult<Self> {
+        let mut srv = Self::unbound(opt, tls_opt)?;
+        srv.early = EarlyListener::bind(srv.local_addr)?;
+        if let Some(addr) = srv.early.local_addr() {
+            srv.bound_addr.set(addr);
+        }
+        Ok(srv)
+    }
+
+    /// Build the server without binding, `serve` binds `opt.listen` when it
+    /// starts. For `apply`, which only takes the settings of the new server.
+    pub(crate) fn unbound(
+        opt: TcpServerOption,
+        tls_opt: Option<TlsServerOption>,
+    ) -> ServerResult<Self> {
+        let source = TransportServerOption {
+            opt: ServerOption::Tcp(opt.clone()),
+            tls: tls_opt.clone(),
+            latency_profile: LatencyProfile::default(),
+            policy: Default::default(),
+            version: CONFIG_VERSION,
+        };
+        let mut require_alpn = false;
+        let mut ticket_keys = None;
+        let tls_opt = tls_opt.filter(|_| opt.tls_mode != TlsMode::Disabled);
         let tls_acceptor = if let Some(tls_opt) = tls_opt {
-            let config: TlsServerConfig = tls_opt.try_into()?;
+            require_alpn = tls_opt.require_alpn && !tls_opt.alpn.is_empty();
+            let (config, keys) = tls_opt.build()?;
+            ticket_keys = keys;
             Some(TlsAcceptor::from(Arc::new(config)))
         } else {
             None
         };
+        let live = Live {
+            tls_acceptor,
+            ticket_keys,
+            require_alpn,
+            tcp_nodelay: opt.tcp_nodelay,
+            sniff: match opt.tls_mode {
+                TlsMode::Optional => Some(opt.sniff.unwrap_or(SniffOption {
+                    bytes: 1,
+                    timeout: Duration::from_secs(1),
+                    default_route: Route::Plain,
+                })),
+                _ => opt.sniff,
+            },
+            max_bytes: opt.max_bytes,
+            rate_limit: opt.rate_limit.map(RateLimits::new),
+            handshake_timeout: opt.handshake_timeout,
+            idle_timeout: opt.idle_timeout,
+            source: Arc::new(source),
+        };
 
+        let stats = Arc::new(ServerStats::new(opt.listen));
         Ok(Self {
             local_addr: opt.listen,
-            tls_acceptor,
-            tcp_nodelay: opt.tcp_nodelay,
+            bound_addr: BoundAddr::default(),
+            early: EarlyListener::default(),
+            listener: None,
+            live: Arc::new(ArcSwap::from_pointee(live)),
+            reaper: opt.idle_reap.map(|idle| Reaper::new(idle, stats.clone())),
+            stats,
+            accept_batch: opt.accept_batch,
+            send_buffer_size: None,
+            busy_poll: None,
+            kill_grace: DEFAULT_KILL_GRACE,
+            connection_limit: opt.max_connections.map(|n| Arc::new(Semaphore::new(n))),
+            active: Arc::default(),
+            accept_hooks: vec![],
+            exports: vec![],
+            classifier: Arc::new(default_classify),
+            sampler: opt.trace_sampling.as_ref().map(Sampler::new),
+            observer: None,
         })
     }
+
+    /// Serve the connections of a listener bound elsewhere, such as one
+    /// passed by systemd socket activation, instead of binding `opt.listen`.
+    ///
+    /// The listener is used as it is, the latency profile does not change
+    /// its send buffer size. It stays open when `serve` returns, for the next
+    /// `serve` to accept on.
+    pub fn from_listener(
+        listener: TcpListener,
+        mut opt: TcpServerOption,
+        tls_opt: Option<TlsServerOption>,
+    ) -> ServerResult<Self> {
+        opt.listen = listener.local_addr()?;
+        let mut srv = Self::unbound(opt, tls_opt)?;
+        srv.bound_addr.set(srv.local_addr);
+        srv.listener = Some(Arc::new(listener));
+        Ok(srv)
+    }
+
+    /// The option `apply` diffs against, by default the tcp and tls options.
+    pub(crate) fn set_source(&mut self, source: TransportServerOption) {
+        let mut live = Live::clone(&self.live.load());
+        live.source = Arc::new(source);
+        self.live.store(Arc::new(live));
+    }
+
+    /// Swap in the per connection settings of `next`, built from the new
+    /// option. Changes to what `serve` binds at start are reported once and
+    /// left out, the listener keeps running.
+    pub(crate) fn apply(&self, next: TcpServer) -> AppliedChanges {
+        let next = next.live.load_full();
+        let paths = changed_paths(&*self.live.load().source, &*next.source);
+        self.live.store(next);
+        AppliedChanges::split(paths, RESTART_PATHS)
+    }
+
+    /// Session ticket keys from `ticket_keys` in the tls option, to swap at
+    /// runtime. A tls change applied live brings new keys.
+    pub fn ticket_keys(&self) -> Option<Arc<TicketKeys>> {
+        self.live.load().ticket_keys.clone()
+    }
+
+    /// Rebuild the tls config from `tls_opt` and use it from the next accept
+    /// on, open connections keep theirs. A failing option leaves the running
+    /// config in place, a server without tls can not turn it on.
+    pub fn reload_tls(&self, tls_opt: TlsServerOption) -> Result<(), TlsError> {
+        reload_tls(&self.live, tls_opt)
+    }
+
+    /// Reload the certificate while serving when its files are watched.
+    fn watch_tls(&self) -> Option<WatchTask> {
+        let (current, reload) = (self.live.clone(), self.live.clone());
+        watch::spawn(
+            WATCH_INTERVAL,
+            Arc::new(move || {
+                let live = current.load();
+                live.tls_acceptor.as_ref()?;
+                live.source.tls.clone()
+            }),
+            Arc::new(move |tls_opt| reload_tls(&reload, tls_opt)),
+        )
+    }
+
+    /// Socket settings of the profile, the options are overridden by `apply_latency_profile`.
+    pub fn set_latency_profile(&mut self, profile: LatencyProfile) {
+        self.send_buffer_size = profile.send_buffer_size();
+        self.busy_poll = profile.busy_poll();
+        if let Some(size) = self.send_buffer_size {
+            if let Err(e) = self.early.set_send_buffer_size(size) {
+                log::warn!("send buffer size of {} not set ({})", self.local_addr, e);
+            }
+        }
+        if let Some(usecs) = self.busy_poll {
+            self.early.set_busy_poll(usecs);
+        }
+    }
+
+    /// Send sampled connection events to `sink` instead of the log.
+    pub fn set_trace_sink(&mut self, sink: TraceSink) {
+        if let Some(ref mut sampler) = self.sampler {
+            sampler.set_sink(sink);
+        }
+    }
+
+    /// Report accepts, failed handshakes, finished callbacks and shutdown
+    /// exits to `observer`.
+    pub fn set_serve_observer(&mut self, observer: Arc<dyn ServeObserver>) {
+        self.observer = Some(observer);
+    }
+
+    /// Replace `default_classify`, only used when `sniff` is configured.
+    pub fn set_classifier(&mut self, classifier: Classifier) {
+        self.classifier = classifier;
+    }
+
+    /// Time callbacks get to return once `serve_with_shutdown` invalidated
+    /// their stream at the end of the grace period, 1s by default. Callbacks
+    /// still running after it are aborted.
+    pub fn set_kill_grace(&mut self, kill_grace: Duration) {
+        self.kill_grace = kill_grace;
+    }
+
+    /// Run `hook` on every accepted connection after the tls handshake.
+    pub fn add_accept_hook(&mut self, hook: AcceptHook) {
+        self.accept_hooks.push(hook);
+    }
+
+    /// Export `request` from every tls session right after the handshake,
+    /// callbacks read it with `ConnContext::export_keying_material`.
+    pub fn add_keying_material_export(&mut self, request: ExportRequest) {
+        self.exports.push(request);
+    }
+
+    pub fn stats(&self) -> ServerStatsSnapshot {
+        self.stats.snapshot()
+    }
+
+    /// Connections from accept until their callback returned, handshakes included.
+    pub fn active_connections(&self) -> usize {
+        self.active.load(Ordering::Relaxed)
+    }
+
+    #[cfg(all(test, feature = "insecure-tls"))]
+    pub(crate) async fn handshake(&self, stream: TokioTcpStream) -> std::io::Result<TcpStream> {
+        self.live.load_full().handshake(stream).await
+    }
 }
 
 impl TransportServerTrait for TcpServer {
     fn local_addr(&self) -> Option<SocketAddr> {
-        Some(self.local_addr)
+        Some(self.bound_addr.get_or(self.local_addr))
     }
 
     async fn serve<C: TransportServerCallback>(&self, callback: C) -> ServerResult<()> {
-        let listener = TcpListener::bind(self.local_addr).await?;
+        self.serve_tracked(callback, None).await
+    }
+
+    async fn serve_with_shutdown<C, F>(
+        &self,
+        callback: C,
+        signal: F,
+        grace: Duration,
+    ) -> ServerResult<()>
+    where
+        C: TransportServerCallback,
+        F: Future<Output = ()> + Send + Sync,
+    {
+        let tasks = Tasks::new();
+        tokio::select! {
+            res = self.serve_tracked(callback, Some(&tasks)) => return res,
+            _ = signal => {}
+        }
+        // the listener closed with the serve future
+        tasks
+            .shutdown(grace, self.kill_grace)
+            .await
+            .finish("tcp", self.observer.as_deref());
+        Ok(())
+    }
+}
+
+impl TcpServer {
+    async fn serve_tracked<C: TransportServerCallback>(
+        &self,
+        callback: C,
+        tasks: Option<&Arc<Tasks>>,
+    ) -> ServerResult<()> {
+        let listener = match (&self.listener, self.early.take()) {
+            (Some(listener), _) => listener.clone(),
+            (None, Some(early)) => Arc::new(TcpListener::from_std(early)?),
+            // served before or built unbound, bind the port it listened on
+            (None, None) => Arc::new(
+                bind_listener(
+                    self.bound_addr.get_or(self.local_addr),
+                    self.send_buffer_size,
+                    self.busy_poll,
+                )
+                .await?,
+            ),
+        };
+        self.bound_addr.set(listener.local_addr()?);
+        let _reaper = self.reaper.as_ref().map(|r| r.spawn());
+        let _watch = self.watch_tls();
+
+        let accept_hooks: Arc<[AcceptHook]> = self.accept_hooks.clone().into();
+        let exports: Arc<[ExportRequest]> = self.exports.clone().into();
+        let batch_size = self.accept_batch.max(1);
+        let mut batch = Vec::with_capacity(batch_size);
+        let accepted = |res: std::io::Result<(TokioTcpStream, SocketAddr)>| {
+            res.map(|(s, a)| {
+                let ctx = ConnContext::new(Some(a), s.local_addr().ok()).with_transport("tcp");
+                (s, a, ctx)
+            })
+        };
 
         loop {
-            let (stream, peer_addr) = match listener.accept().await {
-                Ok((s, a)) => {
-                    if self.tcp_nodelay {
-                        let _ = s.set_nodelay(true);
-                    }
-                    let s = if let Some(ref acceptor) = self.tls_acceptor {
-                        match acceptor.accept(s).await {
-                            Ok(s) => TcpStream::Tls(TlsStream::Server(s)),
-                            Err(e) => {
-                                log::warn!("tls handshake failed {}", e);
-                                continue;
-                            }
+            // at the limit, new connections wait in the backlog until a handler returns
+            let permit = match self.connection_limit {
+                Some(ref limit) => limit.clone().acquire_owned().await.ok(),
+                None => None,
+            };
+            batch.push((accepted(listener.accept().await), permit));
+            // drain what the kernel already queued, an empty queue only registers the waker
+            while batch.len() < batch_size {
+                let permit = match self.connection_limit {
+                    Some(ref limit) => match limit.clone().try_acquire_owned() {
+                        Ok(permit) => Some(permit),
+                        Err(_) => break,
+                    },
+                    None => None,
+                };
+                match listener.accept().now_or_never() {
+                    Some(res) => batch.push((accepted(res), permit)),
+                    None => break,
+                }
+            }
+            let full = batch.len() == batch_size;
+
+            for (accepted, permit) in batch.drain(..) {
+                let (s, a, ctx) = match accepted {
+                    Ok(accepted) => accepted,
+                    Err(err) => {
+                        let err: ServerError = err.into();
+                        if err.is_closed() {
+                            return Err(err);
                         }
-                    } else {
-                        TcpStream::Raw(s)
-                    };
 
-                    (s, a)
+                        log::error!("tcp server error: {}", err);
+                        continue;
+                    }
+                };
+                let live = self.live.load_full();
+                if live.tcp_nodelay {
+                    let _ = s.set_nodelay(true);
+                }
+                let id = ctx.id;
+                let mut trace = self.sampler.as_ref().map(|s| s.conn(id));
+                // unsampled connections stay out of tracing, their trace still tells failures
+                let traced = trace.as_ref().is_none_or(ConnTrace::sampled);
+                observe::accepted(self.observer.as_deref(), &ctx, traced);
+                if let Some(ref mut trace) = trace {
+                    trace.event(|| format!("accepted from {}", a));
+                }
+
+                // idle reaping and shutdown cover the handshake already
+                let registration = self.reaper.as_ref().map(|r| r.register(id, Some(a)));
+                let guard = tasks.map(|t| t.register(id));
+                let slot = ActiveSlot::new(self.active.clone(), permit);
+                // sniffing and the handshake wait on the client, keep them off the accept loop
+                let mut conn = Conn {
+                    live,
+                    classifier: self.classifier.clone(),
+                    accept_hooks: accept_hooks.clone(),
+                    exports: exports.clone(),
+                    stats: self.stats.clone(),
+                    trace,
+                    observer: self.observer.clone(),
+                };
+                let callback = callback.clone();
+                let span = observe::serve_span("tcp", id, Some(a), traced, async move {
+                    let _slot = slot;
+                    let Some((stream, mut ctx)) = conn.establish(s, a, ctx).await else {
+                        return;
+                    };
+                    let stream =
+                        IdleStream::new(stream, registration.as_ref().map(|r| r.activity()));
+                    let stream = throttle(stream, conn.live.rate_limit.as_ref());
+                    let stream =
+                        LimitedStream::new(conn.stats.track(stream, &a), conn.live.max_bytes);
+                    ctx.extensions.insert(stream.count());
+                    let stream = CountedStream::new(stream);
+                    ctx.extensions.insert(stream.stats_handle());
+                    let stream = TimeoutStream::idle(stream, conn.live.idle_timeout);
+                    let stream = killable(stream, guard.as_ref());
+                    observe::callback(conn.observer.as_deref(), ctx, traced, |ctx| {
+                        callback.handle_ctx(stream, ctx)
+                    })
+                    .await
+                });
+                let handle = tokio::spawn(span);
+                if let Some(ref reaper) = self.reaper {
+                    reaper.set_abort(id, handle.abort_handle());
                 }
-                Err(err) => {
-                    let err: ServerError = err.into();
-                    if err.is_closed() {
-                        return Err(err);
+                if let Some(tasks) = tasks {
+                    tasks.set_abort(id, handle.abort_handle());
+                }
+            }
+
+            // a full batch means more are waiting, let other tasks run first
+            if full && batch_size > 1 {
+                tokio::task::yield_now().await;
+            }
+        }
+    }
+}
+
+impl Live {
+    /// Peek at the first bytes without consuming them and pick a route.
+    async fn route(
+        &self,
+        classifier: &Classifier,
+        stream: &TokioTcpStream,
+        peer: SocketAddr,
+    ) -> Route {
+        let Some(ref sniff) = self.sniff else {
+            return Route::Tls;
+        };
+
+        let len = sniff.bytes.max(1);
+        // waiting only counts the bytes, they are copied out once below
+        let mut scratch = vec![MaybeUninit::uninit(); len];
+        let mut peeked = 0;
+        let full = async {
+            loop {
+                stream.readable().await?;
+                // a short prefix is reported as WouldBlock to clear the
+                // readiness, the next wait then lasts until more data arrived
+                let res = stream.try_io(Interest::READABLE, || {
+                    peeked = SockRef::from(stream).peek(&mut scratch)?;
+                    if peeked > 0 && peeked < len {
+                        return Err(std::io::ErrorKind::WouldBlock.into());
                     }
+                    Ok(peeked)
+                });
+                match res {
+                    Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => continue,
+                    res => return res,
+                }
+            }
+        };
+        match tokio::time::timeout(sniff.timeout, full).await {
+            // eof before anything useful arrived
+            Ok(Ok(0)) | Ok(Err(_)) => return Route::Drop,
+            Ok(Ok(_)) | Err(_) => {}
+        }
+
+        if peeked == 0 {
+            return sniff.default_route;
+        }
+        // the bytes are queued already, so this returns right away
+        let mut buf = vec![0u8; peeked];
+        match stream.peek(&mut buf).await {
+            Ok(n) if n > 0 => classifier(&buf[..n], peer),
+            _ => Route::Drop,
+        }
+    }
+
+    async fn handshake(&self, stream: TokioTcpStream) -> std::io::Result<TcpStream> {
+        let Some(ref acceptor) = self.tls_acceptor else {
+            return Ok(TcpStream::Raw(stream));
+        };
+
+        if !self.require_alpn {
+            let stream = acceptor.accept(stream).await?;
+            return Ok(TcpStream::Tls(TlsStream::Server(stream)));
+        }
+
+        // rustls only rejects clients offering no matching protocol, not clients offering none
+        let start = LazyConfigAcceptor::new(Acceptor::default(), stream).await?;
+        let offered = start.client_hello().alpn().is_some();
+        let mut accept = start.into_stream(acceptor.config().clone());
+        if !offered {
+            if let Some(io) = accept.get_mut() {
+                io.write_all(&NO_APPLICATION_PROTOCOL_ALERT).await?;
+            }
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "client offered no application protocol",
+            ));
+        }
 
-                    log::error!("tcp server error: {}", err);
-                    continue;
+        Ok(TcpStream::Tls(TlsStream::Server(accept.await?)))
+    }
+}
+
+fn reload_tls(live: &ArcSwap<Live>, tls_opt: TlsServerOption) -> Result<(), TlsError> {
+    if live.load().tls_acceptor.is_none() {
+        return Err(TlsError::reload_off());
+    }
+    let require_alpn = tls_opt.require_alpn && !tls_opt.alpn.is_empty();
+    let (config, ticket_keys) = tls_opt.clone().build()?;
+    let acceptor = TlsAcceptor::from(Arc::new(config));
+    live.rcu(|current| {
+        let mut source = TransportServerOption::clone(&current.source);
+        source.tls = Some(tls_opt.clone());
+        Live {
+            tls_acceptor: Some(acceptor.clone()),
+            ticket_keys: ticket_keys.clone(),
+            require_alpn,
+            source: Arc::new(source),
+            ..Live::clone(current)
+        }
+    });
+    Ok(())
+}
+
+/// A connection's place in `max_connections` and `active_connections`.
+struct ActiveSlot {
+    active: Arc<AtomicUsize>,
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+impl ActiveSlot {
+    fn new(active: Arc<AtomicUsize>, permit: Option<OwnedSemaphorePermit>) -> Self {
+        active.fetch_add(1, Ordering::Relaxed);
+        Self {
+            active,
+            _permit: permit,
+        }
+    }
+}
+
+impl Drop for ActiveSlot {
+    fn drop(&mut self) {
+        self.active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// What the task of one accepted connection needs before the callback runs.
+struct Conn {
+    live: Arc<Live>,
+    classifier: Classifier,
+    accept_hooks: Arc<[AcceptHook]>,
+    exports: Arc<[ExportRequest]>,
+    stats: Arc<ServerStats>,
+    trace: Option<ConnTrace>,
+    observer: Option<Arc<dyn ServeObserver>>,
+}
+
+impl Conn {
+    fn event(&mut self, message: impl FnOnce() -> String) {
+        if let Some(ref mut trace) = self.trace {
+            trace.event(message);
+        }
+    }
+
+    /// Route, run the tls handshake within `handshake_timeout` and the
+    /// accept hooks, `None` drops the connection.
+    async fn establish(
+        &mut self,
+        s: TokioTcpStream,
+        a: SocketAddr,
+        mut ctx: ConnContext,
+    ) -> Option<(TracedStream<TcpStream>, ConnContext)> {
+        let route = self.live.route(&self.classifier, &s, a).await;
+        self.event(|| format!("routed {:?}", route));
+        let s = match route {
+            Route::Tls => {
+                match tokio::time::timeout(self.live.handshake_timeout, self.live.handshake(s))
+                    .await
+                {
+                    Ok(res) => res,
+                    Err(_) => Err(std::io::Error::new(
+                        std::io::ErrorKind::TimedOut,
+                        "handshake timed out",
+                    )),
                 }
-            };
+            }
+            Route::Plain => Ok(TcpStream::Raw(s)),
+            Route::Drop => {
+                log::debug!("connection from {} dropped by classifier", a);
+                return None;
+            }
+        };
+        let s = match s {
+            Ok(s) if s.is_tls() => {
+                let elapsed = ctx.accepted_at.elapsed();
+                self.stats.record_handshake(&a, elapsed);
+                ctx.handshake = Some(elapsed);
+                if let Some(name) = s.server_name() {
+                    ctx.extensions.insert(ServerName(name.to_owned()));
+                }
+                if let Some(proto) = s.alpn_protocol() {
+                    ctx.extensions.insert(AlpnProtocol(proto.to_vec()));
+                }
+                if let Some(info) = s.tls_info() {
+                    ctx.extensions.insert(info);
+                }
+                let exported = TlsExporter::capture(&self.exports, |label, context, len| {
+                    s.export_keying_material(label, context, len)
+                });
+                match exported {
+                    Ok(exporter) => ctx.extensions.insert(exporter),
+                    Err(e) => {
+                        log::warn!("keying material export from {} failed {}", a, e);
+                        return None;
+                    }
+                };
+                self.event(|| {
+                    format!(
+                        "tls handshake in {:?}, sni {:?}, resumed {}",
+                        elapsed,
+                        s.server_name(),
+                        s.is_resumed()
+                    )
+                });
+                s
+            }
+            Ok(s) => s,
+            // never retried as plaintext, that would allow a downgrade
+            Err(e) => {
+                log::warn!("tls handshake from {} failed {}", a, e);
+                if let Some(ref mut trace) = self.trace {
+                    trace.fail(|| format!("tls handshake failed: {}", e));
+                }
+                // a failed trace emits, only connections left off entirely stay quiet
+                let traced = self.trace.as_ref().is_none_or(ConnTrace::sampled);
+                observe::handshake_failed(
+                    self.observer.as_deref(),
+                    Some(a),
+                    &e,
+                    ctx.accepted_at,
+                    traced,
+                );
+                return None;
+            }
+        };
 
-            let callback_clone = callback.clone();
-            let stream: TcpStream = stream.into();
-            tokio::spawn(async move { callback_clone.handle(stream, Some(peer_addr)).await });
+        ctx.extensions.insert(if s.is_tls() {
+            Security::Tls
+        } else {
+            Security::Plain
+        });
+
+        if !ctx.run_hooks(&self.accept_hooks) {
+            log::debug!("connection from {} rejected by accept hook", a);
+            return None;
         }
+
+        Some((TracedStream::new(s, self.trace.take()), ctx))
     }
 }
@@ -1,13 +1,240 @@
 //! Transport Tcp Stream
 
-use tokio::net::TcpStream as TokioTcpStream;
+use std::{io::ErrorKind, mem::MaybeUninit, net::SocketAddr};
+
+use rustls::{pki_types::CertificateDer, ConnectionCommon, HandshakeKind};
+use socket2::SockRef;
+use tokio::{io::Interest, net::TcpStream as TokioTcpStream};
 use tokio_rustls::TlsStream;
 
-use crate::stream_traits_enum;
+use crate::{
+    stream_traits_enum,
+    tls::{cert_sha256, TlsInfo},
+    TlsError,
+};
 
 stream_traits_enum! {
+    // one per connection and moved rarely, boxing the tls state would add a
+    // pointer chase to every read
+    #[allow(clippy::large_enum_variant)]
     pub enum TcpStream {
         Raw(TokioTcpStream),
         Tls(TlsStream<TokioTcpStream>),
     }
 }
+
+impl std::fmt::Debug for TcpStream {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut d = f.debug_struct("TcpStream");
+        d.field("peer", &self.peer_addr().ok())
+            .field("local", &self.local_addr().ok())
+            .field("tls", &self.is_tls());
+        if let Some(name) = self.server_name() {
+            d.field("server_name", &name);
+        }
+        d.finish()
+    }
+}
+
+impl TcpStream {
+    pub fn is_tls(&self) -> bool {
+        matches!(self, Self::Tls(_))
+    }
+
+    /// The socket under the tls session, if any.
+    pub fn socket(&self) -> &TokioTcpStream {
+        match self {
+            Self::Raw(s) => s,
+            Self::Tls(s) => s.get_ref().0,
+        }
+    }
+
+    pub fn peer_addr(&self) -> std::io::Result<SocketAddr> {
+        self.socket().peer_addr()
+    }
+
+    pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.socket().local_addr()
+    }
+
+    /// Server name indicated by the client, only known on the accepting side.
+    pub fn server_name(&self) -> Option<&str> {
+        match self {
+            Self::Tls(TlsStream::Server(s)) => s.get_ref().1.server_name(),
+            _ => None,
+        }
+    }
+
+    /// Negotiated alpn protocol.
+    pub fn alpn_protocol(&self) -> Option<&[u8]> {
+        match self {
+            Self::Raw(_) => None,
+            Self::Tls(s) => s.get_ref().1.alpn_protocol(),
+        }
+    }
+
+    /// Alpn, version, sni and peer chain of the tls session, `None` on a raw stream.
+    pub fn tls_info(&self) -> Option<TlsInfo> {
+        match self {
+            Self::Raw(_) => None,
+            Self::Tls(TlsStream::Client(s)) => Some(TlsInfo::new(s.get_ref().1, None)),
+            Self::Tls(TlsStream::Server(s)) => {
+                let conn = s.get_ref().1;
+                Some(TlsInfo::new(conn, conn.server_name()))
+            }
+        }
+    }
+
+    /// Whether the tls session was resumed from a ticket or cached session.
+    pub fn is_resumed(&self) -> bool {
+        match self {
+            Self::Raw(_) => false,
+            Self::Tls(s) => s.get_ref().1.handshake_kind() == Some(HandshakeKind::Resumed),
+        }
+    }
+
+    /// Certificate chain presented by the peer, leaf first.
+    pub fn peer_certificates(&self) -> Option<Vec<CertificateDer<'static>>> {
+        match self {
+            Self::Raw(_) => None,
+            Self::Tls(s) => s.get_ref().1.peer_certificates().map(|c| c.to_vec()),
+        }
+    }
+
+    /// Sha256 fingerprint of the peer's leaf certificate.
+    pub fn peer_cert_sha256(&self) -> Option<[u8; 32]> {
+        match self {
+            Self::Raw(_) => None,
+            Self::Tls(s) => s
+                .get_ref()
+                .1
+                .peer_certificates()
+                .and_then(|c| c.first())
+                .map(cert_sha256),
+        }
+    }
+
+    /// Export keying material (RFC 5705) from the established tls session.
+    pub fn export_keying_material(
+        &self,
+        label: &[u8],
+        context: Option<&[u8]>,
+        output_len: usize,
+    ) -> Result<Vec<u8>, TlsError> {
+        let output = vec![0u8; output_len];
+        let output = match self {
+            Self::Raw(_) => return Err(TlsError::NotTls),
+            Self::Tls(TlsStream::Client(s)) => s
+                .get_ref()
+                .1
+                .export_keying_material(output, label, context)?,
+            Self::Tls(TlsStream::Server(s)) => s
+                .get_ref()
+                .1
+                .export_keying_material(output, label, context)?,
+        };
+
+        Ok(output)
+    }
+
+    /// Wait until `n` bytes from the peer are readable without consuming them.
+    ///
+    /// Over tls at most `MAX_PLAINTEXT_WAIT` bytes are waited for, rustls
+    /// buffers no more plaintext ahead of the reader.
+    pub async fn wait_readable_bytes(&mut self, n: usize) -> std::io::Result<()> {
+        match self {
+            Self::Raw(s) => wait_peekable(s, n).await,
+            Self::Tls(TlsStream::Client(s)) => {
+                let (io, conn) = s.get_mut();
+                wait_plaintext(io, conn, n.min(MAX_PLAINTEXT_WAIT)).await
+            }
+            Self::Tls(TlsStream::Server(s)) => {
+                let (io, conn) = s.get_mut();
+                wait_plaintext(io, conn, n.min(MAX_PLAINTEXT_WAIT)).await
+            }
+        }
+    }
+}
+
+/// Received plaintext rustls holds before it stops reading records.
+const MAX_PLAINTEXT_WAIT: usize = 16 * 1024;
+
+/// Wait until `n` bytes can be peeked from `io`.
+async fn wait_peekable(io: &TokioTcpStream, n: usize) -> std::io::Result<()> {
+    let mut buf = vec![MaybeUninit::uninit(); n.max(1)];
+    loop {
+        io.readable().await?;
+        // too few bytes are reported as WouldBlock to clear the readiness,
+        // the next wait then lasts until more data arrived
+        let res = io.try_io(Interest::READABLE, || {
+            match SockRef::from(io).peek(&mut buf)? {
+                got if got > 0 && got < n => Err(ErrorKind::WouldBlock.into()),
+                got => Ok(got),
+            }
+        });
+        match res {
+            Ok(0) => return Err(ErrorKind::UnexpectedEof.into()),
+            Ok(_) => return Ok(()),
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Feed records into the session until `n` plaintext bytes are buffered,
+/// sending what the session answers, like a key update.
+///
+/// The stream reads buffered plaintext first, so nothing is lost.
+async fn wait_plaintext<D>(
+    io: &TokioTcpStream,
+    conn: &mut ConnectionCommon<D>,
+    n: usize,
+) -> std::io::Result<()> {
+    loop {
+        let state = conn
+            .process_new_packets()
+            .map_err(|e| std::io::Error::new(ErrorKind::InvalidData, e))?;
+        let (buffered, closed) = (state.plaintext_bytes_to_read(), state.peer_has_closed());
+        while conn.wants_write() {
+            io.writable().await?;
+            match conn.write_tls(&mut TryIo(io)) {
+                Ok(_) => {}
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+                Err(e) => return Err(e),
+            }
+        }
+        if buffered >= n {
+            return Ok(());
+        }
+        if closed {
+            return Err(ErrorKind::UnexpectedEof.into());
+        }
+
+        io.readable().await?;
+        match conn.read_tls(&mut TryIo(io)) {
+            Ok(0) => return Err(ErrorKind::UnexpectedEof.into()),
+            Ok(_) => {}
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Non blocking io on the socket for rustls.
+struct TryIo<'a>(&'a TokioTcpStream);
+
+impl std::io::Read for TryIo<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.0.try_read(buf)
+    }
+}
+
+impl std::io::Write for TryIo<'_> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.try_write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,79 @@
+//! Test Helpers
+//!
+//! Addresses, peers and callbacks shared by the tests of several transports.
+
+use std::{
+    net::{Ipv4Addr, SocketAddr, TcpStream},
+    time::Duration,
+};
+
+use socket2::{Domain, Socket, Type};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::{ConnContext, TransportServerCallback};
+
+/// A listener that never accepts, its backlog full so the syn of any
+/// further connect is dropped and the connect hangs until given up on.
+pub(crate) struct Blackhole {
+    pub(crate) addr: SocketAddr,
+    _listener: Socket,
+    _queued: Vec<TcpStream>,
+}
+
+impl Blackhole {
+    pub(crate) fn new() -> Self {
+        let listener = Socket::new(Domain::IPV4, Type::STREAM, None).unwrap();
+        listener
+            .bind(&SocketAddr::from((Ipv4Addr::LOCALHOST, 0)).into())
+            .unwrap();
+        listener.listen(0).unwrap();
+        let addr = listener.local_addr().unwrap().as_socket().unwrap();
+
+        // connect until one hangs, the connections queued before it fill the backlog
+        let mut queued = vec![];
+        for _ in 0..8 {
+            match TcpStream::connect_timeout(&addr, Duration::from_millis(200)) {
+                Ok(stream) => queued.push(stream),
+                Err(_) => {
+                    return Self {
+                        addr,
+                        _listener: listener,
+                        _queued: queued,
+                    }
+                }
+            }
+        }
+        panic!("backlog of {} never filled", addr);
+    }
+}
+
+/// Echoes until eof, after a line naming the transport and path.
+#[derive(Clone)]
+pub(crate) struct EchoCallback;
+
+impl TransportServerCallback for EchoCallback {
+    async fn handle<S>(&self, _stream: S, _addr: Option<SocketAddr>)
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + Sync,
+    {
+        unreachable!("served through handle_ctx");
+    }
+
+    async fn handle_ctx<S>(&self, mut stream: S, ctx: ConnContext)
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + Sync,
+    {
+        let info = ctx.info();
+        let path = info.http.map(|http| http.path).unwrap_or_default();
+        let line = format!("{} {}\n", info.transport, path);
+        stream.write_all(line.as_bytes()).await.unwrap();
+        let mut buf = vec![0; 8192];
+        loop {
+            match stream.read(&mut buf).await {
+                Ok(0) | Err(_) => break,
+                Ok(n) => stream.write_all(&buf[..n]).await.unwrap(),
+            }
+        }
+        let _ = stream.shutdown().await;
+    }
+}
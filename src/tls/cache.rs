@@ -0,0 +1,218 @@
+//! Tls Client Config Cache
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, OnceLock},
+};
+
+use rustls::ClientConfig;
+
+use super::{TlsClientOption, TlsError, TlsVersion};
+
+/// The parts of a `TlsClientOption` that end up in its `ClientConfig`.
+///
+/// `server_name` is chosen per connection and stays out, so clients of
+/// different hosts share one config.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct CacheKey {
+    insecure: bool,
+    enable_sni: bool,
+    alpn: Vec<String>,
+    session_cache_size: usize,
+    enable_early_data: bool,
+    min_version: Option<TlsVersion>,
+    max_version: Option<TlsVersion>,
+    cipher_suites: Vec<String>,
+    ech_config: Option<String>,
+    ech_grease: bool,
+    require_ech: bool,
+}
+
+impl CacheKey {
+    /// `None` when the option carries programmatic hooks or a client
+    /// certificate, those are never shared.
+    fn of(opt: &TlsClientOption) -> Option<Self> {
+        if opt.on_first_seen.is_some() || opt.certificate.is_some() {
+            return None;
+        }
+        Some(Self {
+            insecure: opt.insecure,
+            enable_sni: opt.enable_sni,
+            alpn: opt.alpn.clone(),
+            session_cache_size: opt.session_cache_size,
+            enable_early_data: opt.enable_early_data,
+            min_version: opt.min_version,
+            max_version: opt.max_version,
+            cipher_suites: opt.cipher_suites.clone(),
+            ech_config: opt.ech_config.clone(),
+            ech_grease: opt.ech_grease,
+            require_ech: opt.require_ech,
+        })
+    }
+}
+
+/// Shared client configs of identical `TlsClientOption`s.
+///
+/// Building a config clones the whole webpki root store, so many clients
+/// of one option share a single `Arc<ClientConfig>` and its session cache.
+#[derive(Debug, Default)]
+pub struct TlsConfigCache {
+    entries: Mutex<HashMap<CacheKey, Arc<ClientConfig>>>,
+}
+
+impl TlsConfigCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The process wide cache used by the tcp and ws clients.
+    pub fn global() -> &'static TlsConfigCache {
+        static GLOBAL: OnceLock<TlsConfigCache> = OnceLock::new();
+        GLOBAL.get_or_init(TlsConfigCache::new)
+    }
+
+    /// The config of `opt`, built on the first call for an equal option.
+    pub fn get(&self, opt: &TlsClientOption) -> Result<Arc<ClientConfig>, TlsError> {
+        let Some(key) = CacheKey::of(opt) else {
+            return opt.clone().try_into().map(Arc::new);
+        };
+
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(config) = entries.get(&key) {
+            return Ok(config.clone());
+        }
+        let config = Arc::new(ClientConfig::try_from(opt.clone())?);
+        entries.insert(key, config.clone());
+        Ok(config)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap_or_else(|e| e.into_inner()).len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Drop every entry, clients already built keep their config.
+    pub fn clear(&self) {
+        self.entries
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_config_cache_keys() {
+        let cache = TlsConfigCache::new();
+        let opt = TlsClientOption {
+            server_name: "a.example".into(),
+            alpn: vec!["h2".into()],
+            ..Default::default()
+        };
+        let first = cache.get(&opt).unwrap();
+        // the server name is not part of the config
+        let other_host = TlsClientOption {
+            server_name: "b.example".into(),
+            ..opt.clone()
+        };
+        assert!(Arc::ptr_eq(&first, &cache.get(&other_host).unwrap()));
+        assert_eq!(cache.len(), 1);
+
+        let other_alpn = TlsClientOption {
+            alpn: vec!["http/1.1".into()],
+            ..opt.clone()
+        };
+        let second = cache.get(&other_alpn).unwrap();
+        assert!(!Arc::ptr_eq(&first, &second));
+        assert_eq!(second.alpn_protocols, vec![b"http/1.1".to_vec()]);
+        let no_sni = TlsClientOption {
+            enable_sni: false,
+            ..opt.clone()
+        };
+        assert!(!Arc::ptr_eq(&first, &cache.get(&no_sni).unwrap()));
+        assert_eq!(cache.len(), 3);
+
+        // a hook may carry per client state, never shared
+        #[cfg(feature = "insecure-tls")]
+        {
+            let hooked = TlsClientOption {
+                insecure: true,
+                on_first_seen: Some(Arc::new(|_| true)),
+                ..opt.clone()
+            };
+            let a = cache.get(&hooked).unwrap();
+            assert!(!Arc::ptr_eq(&a, &cache.get(&hooked).unwrap()));
+            assert_eq!(cache.len(), 3);
+        }
+
+        cache.clear();
+        assert!(cache.is_empty());
+        assert!(!Arc::ptr_eq(&first, &cache.get(&opt).unwrap()));
+    }
+
+    #[test]
+    #[ignore = "benchmark, run with --ignored --nocapture"]
+    fn bench_init_many_tls() {
+        use std::time::Instant;
+
+        use crate::{
+            option::ClientOption, tcp::TcpClientOption, Resolver, TransportClient,
+            TransportClientOption,
+        };
+
+        const CLIENTS: usize = 800;
+        // each uncached config owns a copy of the anchor list, the der is static
+        let roots = std::mem::size_of_val(webpki_roots::TLS_SERVER_ROOTS);
+
+        for tls_cache in [false, true] {
+            TlsConfigCache::global().clear();
+            let opts = (0..CLIENTS)
+                .map(|i| TransportClientOption {
+                    opt: ClientOption::Tcp(TcpClientOption {
+                        addr: "127.0.0.1".into(),
+                        port: 1000 + i as u16,
+                        tcp_nodelay: false,
+                        connect_timeout: None,
+                        connect_deadline: None,
+                        attempt_delay: None,
+                        resolve_on_connect: false,
+                        proxy: None,
+                        bind_addr: None,
+                        bind_device: None,
+                    }),
+                    tls: Some(TlsClientOption {
+                        server_name: format!("host{}.example", i),
+                        alpn: vec!["h2".into()],
+                        ..Default::default()
+                    }),
+                    tls_cache,
+                    ..Default::default()
+                })
+                .collect::<Vec<_>>();
+
+            let start = Instant::now();
+            let clients = TransportClient::init_many(opts, &Resolver::default());
+            let elapsed = start.elapsed();
+            assert!(clients.iter().all(|c| c.is_ok()));
+            let configs = if tls_cache {
+                TlsConfigCache::global().len()
+            } else {
+                CLIENTS
+            };
+            println!(
+                "tls_cache={} {} clients in {:?}, {} configs, {} KiB of root stores",
+                tls_cache,
+                CLIENTS,
+                elapsed,
+                configs,
+                configs * roots / 1024
+            );
+        }
+    }
+}
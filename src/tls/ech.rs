@@ -0,0 +1,219 @@
+//! Tls Encrypted Client Hello
+//!
+//! The rustls this crate links has no encrypted client hello, so an ech
+//! option is validated at init and the handshake falls back to a plain
+//! ClientHello, or the init fails under `require_ech`.
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+
+use super::{TlsClientOption, TlsError};
+
+/// The ECHConfig version of the ech draft, other versions are skipped.
+pub const ECH_VERSION: u16 = 0xfe0d;
+
+/// One ECHConfig of a list, the parts useful to name it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EchConfig {
+    pub config_id: u8,
+    pub kem_id: u16,
+    /// The name the outer ClientHello is sent to.
+    pub public_name: String,
+}
+
+/// Parse a base64 ECHConfigList, as published in the `ech` parameter of an
+/// HTTPS record, keeping the configs of `ECH_VERSION`.
+pub fn parse_config_list(b64: &str) -> Result<Vec<EchConfig>, TlsError> {
+    let invalid = |msg: &str| TlsError::InvalidConfig(format!("ech_config: {}", msg));
+
+    let der = STANDARD
+        .decode(b64.trim())
+        .map_err(|e| invalid(&format!("not base64 ({})", e)))?;
+    let mut list = Reader(&der);
+    let mut configs = Reader(list.vec16().ok_or_else(|| invalid("truncated list"))?);
+    if !list.0.is_empty() {
+        return Err(invalid("trailing bytes after the list"));
+    }
+
+    let mut supported = vec![];
+    while !configs.0.is_empty() {
+        let (version, contents) = configs
+            .u16()
+            .zip(configs.vec16())
+            .ok_or_else(|| invalid("truncated config"))?;
+        if version != ECH_VERSION {
+            continue;
+        }
+        supported.push(parse_config(contents).ok_or_else(|| invalid("malformed config"))?);
+    }
+    if supported.is_empty() {
+        return Err(invalid(&format!(
+            "no config of version {:#06x}",
+            ECH_VERSION
+        )));
+    }
+
+    Ok(supported)
+}
+
+/// ECHConfigContents: key config, maximum name length, public name, extensions.
+fn parse_config(contents: &[u8]) -> Option<EchConfig> {
+    let mut r = Reader(contents);
+    let config_id = r.u8()?;
+    let kem_id = r.u16()?;
+    let public_key = r.vec16()?;
+    let suites = r.vec16()?;
+    if public_key.is_empty() || suites.is_empty() || suites.len() % 4 != 0 {
+        return None;
+    }
+    let _max_name_len = r.u8()?;
+    let public_name = r.vec8()?;
+    let _extensions = r.vec16()?;
+    if public_name.is_empty() || !r.0.is_empty() {
+        return None;
+    }
+
+    Some(EchConfig {
+        config_id,
+        kem_id,
+        public_name: String::from_utf8(public_name.to_vec()).ok()?,
+    })
+}
+
+/// Check the ech settings of `opt` before its config is built.
+///
+/// Lacking ech support, a requested ech falls back to a plain ClientHello
+/// with a warning, `require_ech` fails instead.
+pub(crate) fn check(opt: &TlsClientOption) -> Result<(), TlsError> {
+    let configs = match opt.ech_config {
+        Some(ref b64) => parse_config_list(b64)?,
+        None => vec![],
+    };
+    if configs.is_empty() && !opt.ech_grease && !opt.require_ech {
+        return Ok(());
+    }
+    if opt.require_ech {
+        return Err(TlsError::InvalidConfig(
+            "require_ech is set but this build has no encrypted client hello".to_owned(),
+        ));
+    }
+    log::warn!(
+        "encrypted client hello is not available, {} sends a plain ClientHello",
+        configs
+            .first()
+            .map_or("the client", |c| c.public_name.as_str())
+    );
+    Ok(())
+}
+
+/// Tls presentation language vectors over a byte slice.
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.0.len() < n {
+            return None;
+        }
+        let (head, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Some(head)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|b| b[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.take(2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    fn vec8(&mut self) -> Option<&'a [u8]> {
+        let n = self.u8()?;
+        self.take(n.into())
+    }
+
+    fn vec16(&mut self) -> Option<&'a [u8]> {
+        let n = self.u16()?;
+        self.take(n.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An ECHConfigList of one x25519 config for `public_name`, with a
+    /// config of an unknown version in front.
+    fn config_list(public_name: &str) -> String {
+        let mut contents = vec![7, 0x00, 0x20];
+        contents.extend_from_slice(&32u16.to_be_bytes());
+        contents.extend_from_slice(&[0x11; 32]);
+        contents.extend_from_slice(&4u16.to_be_bytes());
+        contents.extend_from_slice(&[0x00, 0x01, 0x00, 0x01]);
+        contents.push(0);
+        contents.push(public_name.len() as u8);
+        contents.extend_from_slice(public_name.as_bytes());
+        contents.extend_from_slice(&0u16.to_be_bytes());
+
+        let mut configs = vec![0xfe, 0x0a, 0x00, 0x01, 0xff];
+        configs.extend_from_slice(&ECH_VERSION.to_be_bytes());
+        configs.extend_from_slice(&(contents.len() as u16).to_be_bytes());
+        configs.extend_from_slice(&contents);
+
+        let mut list = (configs.len() as u16).to_be_bytes().to_vec();
+        list.extend_from_slice(&configs);
+        STANDARD.encode(list)
+    }
+
+    #[test]
+    fn test_parse_config_list() {
+        let configs = parse_config_list(&config_list("cover.example")).unwrap();
+        assert_eq!(
+            configs,
+            [EchConfig {
+                config_id: 7,
+                kem_id: 0x20,
+                public_name: "cover.example".into(),
+            }]
+        );
+
+        let full = STANDARD.decode(config_list("cover.example")).unwrap();
+        for (b64, expect) in [
+            ("%%".to_owned(), "not base64"),
+            (STANDARD.encode(&full[..full.len() - 1]), "truncated list"),
+            (STANDARD.encode([0, 0]), "no config of version 0xfe0d"),
+            (
+                STANDARD.encode([&full[..], &[0]].concat()),
+                "trailing bytes",
+            ),
+        ] {
+            let err = parse_config_list(&b64).unwrap_err().to_string();
+            assert!(err.contains(expect), "{}", err);
+        }
+    }
+
+    #[test]
+    fn test_check_fallback() {
+        let opt = TlsClientOption {
+            ech_config: Some(config_list("cover.example")),
+            ..Default::default()
+        };
+        assert!(check(&opt).is_ok());
+        assert!(rustls::ClientConfig::try_from(opt.clone()).is_ok());
+
+        let required = TlsClientOption {
+            require_ech: true,
+            ..opt
+        };
+        let err = check(&required).unwrap_err();
+        assert!(err.to_string().contains("require_ech"), "{}", err);
+
+        let bad = TlsClientOption {
+            ech_config: Some("AAE=".into()),
+            ..Default::default()
+        };
+        assert!(matches!(
+            rustls::ClientConfig::try_from(bad),
+            Err(TlsError::InvalidConfig(_))
+        ));
+    }
+}
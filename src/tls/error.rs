@@ -2,6 +2,8 @@
 
 use thiserror::Error;
 
+use crate::{error::ErrorCode, secret::SecretError};
+
 #[derive(Debug, Error)]
 pub enum TlsError {
     #[error("io error: {0}")]
@@ -10,4 +12,40 @@ pub enum TlsError {
     InvalidCert(String),
     #[error("invalid private key: {0}")]
     InvalidKey(String),
+    #[error("invalid ticket key: {0}")]
+    InvalidTicketKey(String),
+    #[error("invalid tls config: {0}")]
+    InvalidConfig(String),
+    #[error("{0}")]
+    Secret(#[from] SecretError),
+    #[error("rustls error: {0}")]
+    Rustls(#[from] rustls::Error),
+    #[error("not a tls stream")]
+    NotTls,
+    #[error("keying material was not exported at the handshake")]
+    NotExported,
+    #[error("insecure tls is not available, this build excludes the insecure-tls feature")]
+    InsecureDisabled,
+}
+
+impl TlsError {
+    /// Reloading tls on a server started without it.
+    pub(crate) fn reload_off() -> Self {
+        Self::InvalidConfig("the server runs without tls, turning it on needs a restart".to_owned())
+    }
+
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            Self::Io(e) => ErrorCode::of_io(e),
+            Self::InvalidCert(_) => ErrorCode::TlsCertInvalid,
+            Self::InvalidKey(_) => ErrorCode::TlsKeyInvalid,
+            Self::InvalidTicketKey(_) => ErrorCode::TlsTicketKeyInvalid,
+            Self::InvalidConfig(_) => ErrorCode::TlsConfigInvalid,
+            Self::Secret(e) => e.code(),
+            Self::Rustls(e) => ErrorCode::of_rustls(e),
+            Self::NotTls => ErrorCode::TlsNotTls,
+            Self::NotExported => ErrorCode::Tls,
+            Self::InsecureDisabled => ErrorCode::TlsInsecureDisabled,
+        }
+    }
 }
@@ -0,0 +1,61 @@
+//! Keying Material Of Served Sessions
+//!
+//! Callbacks only see an opaque stream, so servers export the keying
+//! material they were asked for right after the handshake and hand it to
+//! the callback in the `ConnContext`.
+
+use std::sync::Arc;
+
+use crate::TlsError;
+
+/// Keying material (RFC 5705) a server exports from every tls session it
+/// accepts, see `TcpServer::add_keying_material_export`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExportRequest {
+    pub label: Vec<u8>,
+    pub context: Option<Vec<u8>>,
+    pub output_len: usize,
+}
+
+/// Keying material exported at the handshake of a served connection,
+/// inserted by tls servers, see `ConnContext::export_keying_material`.
+#[derive(Debug, Clone, Default)]
+pub struct TlsExporter {
+    exported: Arc<[(ExportRequest, Vec<u8>)]>,
+}
+
+impl TlsExporter {
+    /// Run every request through `export`, the exporter of the session.
+    pub(crate) fn capture<F>(requests: &[ExportRequest], export: F) -> Result<Self, TlsError>
+    where
+        F: Fn(&[u8], Option<&[u8]>, usize) -> Result<Vec<u8>, TlsError>,
+    {
+        let exported = requests
+            .iter()
+            .map(|req| {
+                let material = export(&req.label, req.context.as_deref(), req.output_len)?;
+                Ok((req.clone(), material))
+            })
+            .collect::<Result<_, TlsError>>()?;
+        Ok(Self { exported })
+    }
+
+    /// The material of a request the server was given, the session itself
+    /// is out of reach once the callback runs.
+    pub fn export_keying_material(
+        &self,
+        label: &[u8],
+        context: Option<&[u8]>,
+        output_len: usize,
+    ) -> Result<Vec<u8>, TlsError> {
+        self.exported
+            .iter()
+            .find(|(req, _)| {
+                req.label == label
+                    && req.context.as_deref() == context
+                    && req.output_len == output_len
+            })
+            .map(|(_, material)| material.clone())
+            .ok_or(TlsError::NotExported)
+    }
+}
@@ -0,0 +1,25 @@
+//! Established Session Info
+
+use rustls::{pki_types::CertificateDer, CommonState, ProtocolVersion};
+
+/// Parameters of an established tls session, see `TcpStream::tls_info`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TlsInfo {
+    pub alpn_protocol: Option<Vec<u8>>,
+    pub protocol_version: Option<ProtocolVersion>,
+    /// Server name indicated by the client, only known on the accepting side.
+    pub sni: Option<String>,
+    /// Chain presented by the peer, leaf first.
+    pub peer_certificates: Option<Vec<CertificateDer<'static>>>,
+}
+
+impl TlsInfo {
+    pub(crate) fn new(state: &CommonState, sni: Option<&str>) -> Self {
+        Self {
+            alpn_protocol: state.alpn_protocol().map(|p| p.to_vec()),
+            protocol_version: state.protocol_version(),
+            sni: sni.map(|s| s.to_owned()),
+            peer_certificates: state.peer_certificates().map(|c| c.to_vec()),
+        }
+    }
+}
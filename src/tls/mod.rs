@@ -1,7 +1,28 @@
 //! Tls
 
 pub mod option;
-pub use option::{TlsCertOption, TlsClientOption, TlsServerOption};
+pub use option::{
+    cert_sha256, CertSeenCallback, TlsCertOption, TlsClientOption, TlsServerOption, TlsVersion,
+    DEFAULT_SESSION_CACHE_SIZE,
+};
+
+pub mod cache;
+pub use cache::TlsConfigCache;
+
+pub mod ech;
+pub use ech::EchConfig;
 
 pub mod error;
 pub use error::TlsError;
+
+pub mod export;
+pub use export::{ExportRequest, TlsExporter};
+
+pub mod info;
+pub use info::TlsInfo;
+
+pub mod ticket;
+pub use ticket::{TicketKeyOption, TicketKeys};
+
+pub mod watch;
+pub use watch::WATCH_INTERVAL;
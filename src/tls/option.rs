@@ -1,31 +1,86 @@
 //! Tls Option
 
+#[cfg(feature = "self-signed")]
+use std::sync::OnceLock;
+#[cfg(feature = "insecure-tls")]
+use std::{collections::HashSet, sync::Mutex};
 use std::{
     fs,
-    io::{BufReader, Cursor},
+    io::{BufReader, Cursor, Read},
+    net::IpAddr,
     path::PathBuf,
     sync::Arc,
 };
 
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 
+#[cfg(feature = "insecure-tls")]
 use rustls::{
-    client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
-    pki_types::{CertificateDer, PrivateKeyDer},
-    ClientConfig, ServerConfig, SignatureScheme,
+    client::danger::{HandshakeSignatureValid, ServerCertVerified},
+    SignatureScheme,
+};
+use rustls::{
+    client::{danger::ServerCertVerifier, Resumption, WantsClientCert, WebPkiServerVerifier},
+    crypto::CryptoProvider,
+    pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime},
+    server::WebPkiClientVerifier,
+    ClientConfig, ConfigBuilder, RootCertStore, ServerConfig, SupportedProtocolVersion,
+    WantsVerifier,
 };
 
-use super::TlsError;
+use crate::secret::Secret;
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+use super::{TicketKeyOption, TicketKeys, TlsConfigCache, TlsError};
+
+/// Upper bound of a pem input, certificate chains are far smaller.
+pub const MAX_PEM_SIZE: u64 = 1024 * 1024;
+
+/// Called with a server certificate not seen before, `false` aborts the handshake.
+pub type CertSeenCallback = Arc<dyn Fn(&CertificateDer<'_>) -> bool + Send + Sync>;
+
+#[derive(Clone, Serialize, Deserialize)]
 #[serde(default, rename_all = "snake_case")]
 pub struct TlsClientOption {
+    /// Skip certificate verification, builds without the `insecure-tls`
+    /// feature reject it at init.
     pub insecure: bool,
     pub alpn: Vec<String>,
     pub enable_sni: bool,
     pub server_name: String,
+    /// Certificate presented when the server asks for one (mTLS).
+    pub certificate: Option<TlsCertOption>,
+    /// Trust on first use hook for `insecure` mode.
+    #[serde(skip)]
+    pub on_first_seen: Option<CertSeenCallback>,
+    /// Share the built config through `TlsConfigCache::global`, set from
+    /// `TransportClientOption.tls_cache`.
+    #[serde(skip)]
+    pub cache: bool,
+    /// Sessions kept to resume later handshakes, shared by every connect of
+    /// a client. 0 turns resumption off.
+    pub session_cache_size: usize,
+    /// Send the first write as tls 1.3 early data when resuming with a
+    /// server that takes it. Early data can be replayed, keep it idempotent.
+    pub enable_early_data: bool,
+    /// Oldest version offered, tls 1.2 when unset.
+    pub min_version: Option<TlsVersion>,
+    /// Newest version offered, tls 1.3 when unset.
+    pub max_version: Option<TlsVersion>,
+    /// Suites offered, by their iana name in any case. Empty offers the
+    /// defaults of the crypto provider.
+    pub cipher_suites: Vec<String>,
+    /// Base64 ECHConfigList to encrypt the ClientHello with, see `tls::ech`.
+    pub ech_config: Option<String>,
+    /// Send a GREASE ech extension when no `ech_config` is given.
+    pub ech_grease: bool,
+    /// Fail instead of falling back to a plain ClientHello.
+    pub require_ech: bool,
 }
 
+/// Sessions a client keeps by default, the rustls default.
+pub const DEFAULT_SESSION_CACHE_SIZE: usize = 256;
+
 impl Default for TlsClientOption {
     fn default() -> Self {
         Self {
@@ -33,89 +88,461 @@ impl Default for TlsClientOption {
             alpn: vec![],
             enable_sni: true,
             server_name: String::new(),
+            certificate: None,
+            on_first_seen: None,
+            cache: true,
+            session_cache_size: DEFAULT_SESSION_CACHE_SIZE,
+            enable_early_data: false,
+            min_version: None,
+            max_version: None,
+            cipher_suites: vec![],
+            ech_config: None,
+            ech_grease: false,
+            require_ech: false,
         }
     }
 }
 
+impl std::fmt::Debug for TlsClientOption {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TlsClientOption")
+            .field("insecure", &self.insecure)
+            .field("alpn", &self.alpn)
+            .field("enable_sni", &self.enable_sni)
+            .field("server_name", &self.server_name)
+            .field("certificate", &self.certificate)
+            .field("on_first_seen", &self.on_first_seen.is_some())
+            .field("cache", &self.cache)
+            .field("session_cache_size", &self.session_cache_size)
+            .field("enable_early_data", &self.enable_early_data)
+            .field("min_version", &self.min_version)
+            .field("max_version", &self.max_version)
+            .field("cipher_suites", &self.cipher_suites)
+            .field("ech_config", &self.ech_config)
+            .field("ech_grease", &self.ech_grease)
+            .field("require_ech", &self.require_ech)
+            .finish()
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "snake_case")]
 pub struct TlsServerOption {
     #[serde(default)]
     pub alpn: Vec<String>,
+    /// Reject clients that negotiate no alpn protocol (tcp transport only).
+    #[serde(default)]
+    pub require_alpn: bool,
     pub certificate: TlsCertOption,
+    /// Encrypt session tickets with these keys instead of per process ones.
+    #[serde(default)]
+    pub ticket_keys: Option<TicketKeyOption>,
+    /// Roots client certificates are verified against, its key is not read.
+    #[serde(default)]
+    pub client_ca: Option<TlsCertOption>,
+    /// Reject clients without a certificate, otherwise one is only
+    /// verified when presented. Needs `client_ca`.
+    #[serde(default)]
+    pub require_client_cert: bool,
+    /// Issue stateless session tickets under per process keys, which rustls
+    /// rotates. Implied by `ticket_keys`, without either sessions resume
+    /// from a server side cache only.
+    #[serde(default)]
+    pub session_tickets: bool,
+    /// Oldest version accepted, tls 1.2 when unset.
+    #[serde(default)]
+    pub min_version: Option<TlsVersion>,
+    /// Newest version accepted, tls 1.3 when unset.
+    #[serde(default)]
+    pub max_version: Option<TlsVersion>,
+    /// Suites accepted, by their iana name in any case. Empty accepts the
+    /// defaults of the crypto provider.
+    #[serde(default)]
+    pub cipher_suites: Vec<String>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-#[serde(rename_all = "snake_case")]
-pub enum TlsCertOption {
-    File { cert: PathBuf, key: PathBuf },
-    Text { certs: Vec<String>, key: String },
+/// Tls protocol versions, oldest first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TlsVersion {
+    Tls12,
+    Tls13,
 }
 
-impl TryFrom<TlsClientOption> for rustls::ClientConfig {
-    type Error = TlsError;
+impl TlsVersion {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Tls12 => "tls12",
+            Self::Tls13 => "tls13",
+        }
+    }
 
-    fn try_from(opt: TlsClientOption) -> Result<Self, Self::Error> {
-        let mut config = if opt.insecure {
-            ClientConfig::builder()
-                .dangerous()
-                .with_custom_certificate_verifier(Arc::new(NoServerCertVerifier))
-                .with_no_client_auth()
-        } else {
-            let root_store = rustls::RootCertStore {
-                roots: webpki_roots::TLS_SERVER_ROOTS.iter().cloned().collect(),
-            };
-            ClientConfig::builder()
-                .with_root_certificates(root_store)
-                .with_no_client_auth()
+    fn rustls(self) -> &'static SupportedProtocolVersion {
+        match self {
+            Self::Tls12 => &rustls::version::TLS12,
+            Self::Tls13 => &rustls::version::TLS13,
+        }
+    }
+}
+
+impl std::fmt::Display for TlsVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// The versions and suites of an option, resolved against the crypto provider.
+struct Protocols {
+    provider: Arc<CryptoProvider>,
+    versions: Vec<&'static SupportedProtocolVersion>,
+}
+
+impl Protocols {
+    fn new(
+        min: Option<TlsVersion>,
+        max: Option<TlsVersion>,
+        suites: &[String],
+    ) -> Result<Self, TlsError> {
+        let (min, max) = (
+            min.unwrap_or(TlsVersion::Tls12),
+            max.unwrap_or(TlsVersion::Tls13),
+        );
+        if min > max {
+            return Err(TlsError::InvalidConfig(format!(
+                "min_version {} is above max_version {}",
+                min, max
+            )));
+        }
+        let versions = [TlsVersion::Tls12, TlsVersion::Tls13]
+            .into_iter()
+            .filter(|v| (min..=max).contains(v))
+            .map(TlsVersion::rustls)
+            .collect::<Vec<_>>();
+
+        // the process default when one is installed, as the plain builders do
+        let mut provider = CryptoProvider::get_default()
+            .map(|p| CryptoProvider::clone(p))
+            .unwrap_or_else(rustls::crypto::aws_lc_rs::default_provider);
+        if !suites.is_empty() {
+            provider.cipher_suites = suites
+                .iter()
+                .map(|name| {
+                    provider
+                        .cipher_suites
+                        .iter()
+                        .find(|s| {
+                            s.suite()
+                                .as_str()
+                                .is_some_and(|n| n.eq_ignore_ascii_case(name))
+                        })
+                        .copied()
+                        .ok_or_else(|| {
+                            TlsError::InvalidConfig(format!("unknown cipher suite {}", name))
+                        })
+                })
+                .collect::<Result<_, _>>()?;
+        }
+        if !provider
+            .cipher_suites
+            .iter()
+            .any(|s| versions.iter().any(|v| v.version == s.version().version))
+        {
+            return Err(TlsError::InvalidConfig(format!(
+                "no cipher suite of {} to {}",
+                min, max
+            )));
+        }
+
+        Ok(Self {
+            provider: Arc::new(provider),
+            versions,
+        })
+    }
+
+    fn client(&self) -> Result<ConfigBuilder<ClientConfig, WantsVerifier>, TlsError> {
+        ClientConfig::builder_with_provider(self.provider.clone())
+            .with_protocol_versions(&self.versions)
+            .map_err(|e| TlsError::InvalidConfig(e.to_string()))
+    }
+
+    fn server(&self) -> Result<ConfigBuilder<ServerConfig, WantsVerifier>, TlsError> {
+        ServerConfig::builder_with_provider(self.provider.clone())
+            .with_protocol_versions(&self.versions)
+            .map_err(|e| TlsError::InvalidConfig(e.to_string()))
+    }
+}
+
+impl TlsServerOption {
+    /// Build the rustls config, also returning the ticket keys to rotate them later.
+    pub fn build(self) -> Result<(ServerConfig, Option<Arc<TicketKeys>>), TlsError> {
+        let (certs, key) = self.certificate.load()?;
+
+        let subject = cert_subject(&certs[0]).unwrap_or_else(|| "unknown".to_owned());
+        log::debug!("loaded {} certificates for {}", certs.len(), subject);
+
+        let protocols = Protocols::new(self.min_version, self.max_version, &self.cipher_suites)?;
+        let builder = protocols.server()?;
+        let builder = match self.client_ca {
+            Some(ref ca) => {
+                let mut roots = RootCertStore::empty();
+                for cert in ca.load_certs()? {
+                    roots
+                        .add(cert)
+                        .map_err(|e| TlsError::InvalidCert(format!("client ca ({})", e)))?;
+                }
+                let verifier = WebPkiClientVerifier::builder_with_provider(
+                    Arc::new(roots),
+                    protocols.provider.clone(),
+                );
+                let verifier = if self.require_client_cert {
+                    verifier
+                } else {
+                    verifier.allow_unauthenticated()
+                };
+                let verifier = verifier
+                    .build()
+                    .map_err(|e| TlsError::InvalidCert(format!("client ca ({})", e)))?;
+                builder.with_client_cert_verifier(verifier)
+            }
+            None if self.require_client_cert => {
+                return Err(TlsError::InvalidCert(
+                    "require_client_cert without a client_ca".to_owned(),
+                ))
+            }
+            None => builder.with_no_client_auth(),
         };
 
-        config.enable_sni = opt.enable_sni;
+        let mut config = builder
+            .with_single_cert(certs, key)
+            .map_err(|e| TlsError::InvalidCert(format!("{} (subject {})", e, subject)))?;
 
-        if !opt.alpn.is_empty() {
-            config.alpn_protocols = opt
+        if !self.alpn.is_empty() {
+            config.alpn_protocols = self
                 .alpn
                 .into_iter()
                 .map(|s| s.into_bytes())
                 .collect::<Vec<_>>();
         }
 
-        Ok(config)
+        let ticket_keys = match self.ticket_keys {
+            Some(ref opt) => {
+                let keys = Arc::new(TicketKeys::new(opt)?);
+                config.ticketer = keys.clone();
+                Some(keys)
+            }
+            None => {
+                if self.session_tickets {
+                    config.ticketer = rustls::crypto::aws_lc_rs::Ticketer::new()?;
+                }
+                None
+            }
+        };
+
+        Ok((config, ticket_keys))
     }
 }
 
-impl TryFrom<TlsServerOption> for ServerConfig {
-    type Error = TlsError;
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TlsCertOption {
+    File {
+        cert: PathBuf,
+        key: PathBuf,
+        /// Reload running servers when the files change, see `tls::watch`.
+        #[serde(default)]
+        watch: bool,
+    },
+    Text {
+        certs: Vec<String>,
+        key: Secret,
+    },
+    /// A certificate and key generated on first load, for tests and quick
+    /// deployments. Built with `TlsCertOption::self_signed`.
+    #[cfg(feature = "self-signed")]
+    SelfSigned {
+        /// Dns names and ip addresses of the certificate, the first is its subject.
+        hosts: Vec<String>,
+        validity_days: u32,
+        #[serde(skip)]
+        generated: SelfSignedCert,
+    },
+}
 
-    fn try_from(option: TlsServerOption) -> Result<Self, Self::Error> {
-        let (certs, key) = match option.certificate {
-            TlsCertOption::File { cert, key } => {
-                let mut cert_reader = BufReader::new(fs::File::open(&cert)?);
-                let mut key_reader = BufReader::new(fs::File::open(&key)?);
+/// The pem certificate and key of a `SelfSigned` option, shared by its
+/// clones so servers and clients built from one option see the same pair.
+#[cfg(feature = "self-signed")]
+#[derive(Clone, Default)]
+pub struct SelfSignedCert(Arc<OnceLock<(String, Secret)>>);
+
+#[cfg(feature = "self-signed")]
+impl std::fmt::Debug for SelfSignedCert {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SelfSignedCert")
+            .field("generated", &self.0.get().is_some())
+            .finish()
+    }
+}
+
+#[cfg(feature = "self-signed")]
+impl SelfSignedCert {
+    fn get(&self, hosts: &[String], validity_days: u32) -> Result<&(String, Secret), TlsError> {
+        if let Some(pair) = self.0.get() {
+            return Ok(pair);
+        }
+        let pair = generate_self_signed(hosts, validity_days)?;
+        Ok(self.0.get_or_init(|| pair))
+    }
+}
+
+#[cfg(feature = "self-signed")]
+fn generate_self_signed(
+    hosts: &[String],
+    validity_days: u32,
+) -> Result<(String, Secret), TlsError> {
+    let Some(subject) = hosts.first() else {
+        return Err(TlsError::InvalidConfig(
+            "self signed certificate without hosts".to_owned(),
+        ));
+    };
+    if validity_days == 0 {
+        return Err(TlsError::InvalidConfig(
+            "self signed certificate valid for 0 days".to_owned(),
+        ));
+    }
+    let invalid = |e: rcgen::Error| TlsError::InvalidCert(format!("self signed ({})", e));
+
+    let mut params = rcgen::CertificateParams::new(hosts.to_vec()).map_err(invalid)?;
+    params
+        .distinguished_name
+        .push(rcgen::DnType::CommonName, subject.as_str());
+    // an hour back for clocks of peers running behind
+    let now = time::OffsetDateTime::now_utc();
+    params.not_before = now - time::Duration::hours(1);
+    params.not_after = now + time::Duration::days(validity_days.into());
+
+    let key = rcgen::KeyPair::generate().map_err(invalid)?;
+    let cert = params.self_signed(&key).map_err(invalid)?;
+    log::debug!(
+        "generated a self signed certificate for {:?}, valid {} days",
+        hosts,
+        validity_days
+    );
+    Ok((cert.pem(), key.serialize_pem().into()))
+}
+
+impl TlsCertOption {
+    /// A certificate for `hosts` generated when first loaded.
+    #[cfg(feature = "self-signed")]
+    pub fn self_signed(hosts: Vec<String>, validity_days: u32) -> Self {
+        Self::SelfSigned {
+            hosts,
+            validity_days,
+            generated: SelfSignedCert::default(),
+        }
+    }
 
-                (
-                    load_certs(&mut cert_reader)?,
-                    load_priv_key(&mut key_reader)?,
-                )
+    /// The pem certificate of a `SelfSigned` option, generating it if not
+    /// loaded yet, for clients to trust or pin. `None` for other options.
+    #[cfg(feature = "self-signed")]
+    pub fn self_signed_pem(&self) -> Result<Option<&str>, TlsError> {
+        match self {
+            TlsCertOption::SelfSigned {
+                hosts,
+                validity_days,
+                generated,
+            } => Ok(Some(&generated.get(hosts, *validity_days)?.0)),
+            _ => Ok(None),
+        }
+    }
+
+    /// Read and parse the certificates only, leaving the key unread.
+    pub fn load_certs(&self) -> Result<Vec<CertificateDer<'static>>, TlsError> {
+        match self {
+            TlsCertOption::File { cert, .. } => {
+                load_certs(&mut BufReader::new(fs::File::open(cert)?))
+            }
+            TlsCertOption::Text { certs, .. } => {
+                load_certs(&mut BufReader::new(Cursor::new(certs.join("\n"))))
+            }
+            #[cfg(feature = "self-signed")]
+            TlsCertOption::SelfSigned {
+                hosts,
+                validity_days,
+                generated,
+            } => {
+                let (cert, _) = generated.get(hosts, *validity_days)?;
+                load_certs(&mut BufReader::new(Cursor::new(cert.as_bytes())))
             }
-            TlsCertOption::Text { certs, key } => {
-                let mut cert_reader = BufReader::new(Cursor::new(certs.join("\n")));
-                let mut key_reader = BufReader::new(Cursor::new(key));
+        }
+    }
 
-                (
-                    load_certs(&mut cert_reader)?,
-                    load_priv_key(&mut key_reader)?,
-                )
+    /// Read and parse the certificate chain and private key.
+    pub fn load(&self) -> Result<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>), TlsError> {
+        let certs = self.load_certs()?;
+        let key = match self {
+            TlsCertOption::File { key, .. } => {
+                load_priv_key(&mut BufReader::new(fs::File::open(key)?))?
+            }
+            TlsCertOption::Text { key, .. } => {
+                load_priv_key(&mut BufReader::new(Cursor::new(key.expose()?.as_bytes())))?
+            }
+            #[cfg(feature = "self-signed")]
+            TlsCertOption::SelfSigned {
+                hosts,
+                validity_days,
+                generated,
+            } => {
+                let (_, key) = generated.get(hosts, *validity_days)?;
+                load_priv_key(&mut BufReader::new(Cursor::new(key.expose()?.as_bytes())))?
             }
         };
+        Ok((certs, key))
+    }
+}
 
-        let mut config = ServerConfig::builder()
-            .with_no_client_auth()
-            .with_single_cert(certs, key)
-            .map_err(|e| TlsError::InvalidCert(e.to_string()))?;
+impl TlsClientOption {
+    /// The client config, shared with equal options unless `cache` is off.
+    pub fn client_config(&self) -> Result<Arc<ClientConfig>, TlsError> {
+        if self.cache {
+            TlsConfigCache::global().get(self)
+        } else {
+            ClientConfig::try_from(self.clone()).map(Arc::new)
+        }
+    }
+}
 
-        if !option.alpn.is_empty() {
-            config.alpn_protocols = option
+impl TryFrom<TlsClientOption> for rustls::ClientConfig {
+    type Error = TlsError;
+
+    fn try_from(opt: TlsClientOption) -> Result<Self, Self::Error> {
+        super::ech::check(&opt)?;
+        let builder =
+            Protocols::new(opt.min_version, opt.max_version, &opt.cipher_suites)?.client()?;
+        let builder = if opt.insecure {
+            insecure_config(builder, opt.on_first_seen)?
+        } else {
+            builder.with_root_certificates(client_roots())
+        };
+        let mut config = match opt.certificate {
+            Some(ref cert) => {
+                let (certs, key) = cert.load()?;
+                builder
+                    .with_client_auth_cert(certs, key)
+                    .map_err(|e| TlsError::InvalidCert(format!("client certificate ({})", e)))?
+            }
+            None => builder.with_no_client_auth(),
+        };
+
+        config.enable_sni = opt.enable_sni;
+        config.resumption = if opt.session_cache_size == 0 {
+            Resumption::disabled()
+        } else {
+            Resumption::in_memory_sessions(opt.session_cache_size)
+        };
+        config.enable_early_data = opt.enable_early_data;
+
+        if !opt.alpn.is_empty() {
+            config.alpn_protocols = opt
                 .alpn
                 .into_iter()
                 .map(|s| s.into_bytes())
@@ -126,12 +553,59 @@ impl TryFrom<TlsServerOption> for ServerConfig {
     }
 }
 
+#[cfg(feature = "insecure-tls")]
+fn insecure_config(
+    builder: ConfigBuilder<ClientConfig, WantsVerifier>,
+    on_first_seen: Option<CertSeenCallback>,
+) -> Result<ConfigBuilder<ClientConfig, WantsClientCert>, TlsError> {
+    Ok(builder
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(NoServerCertVerifier {
+            on_first_seen,
+            seen: Mutex::new(HashSet::new()),
+        })))
+}
+
+/// Fails loudly, a build without the verifier must never fall back to verifying.
+#[cfg(not(feature = "insecure-tls"))]
+fn insecure_config(
+    _builder: ConfigBuilder<ClientConfig, WantsVerifier>,
+    _on_first_seen: Option<CertSeenCallback>,
+) -> Result<ConfigBuilder<ClientConfig, WantsClientCert>, TlsError> {
+    Err(TlsError::InsecureDisabled)
+}
+
+impl TryFrom<TlsServerOption> for ServerConfig {
+    type Error = TlsError;
+
+    fn try_from(option: TlsServerOption) -> Result<Self, Self::Error> {
+        option.build().map(|(config, _)| config)
+    }
+}
+
 pub fn load_certs<R: std::io::Read>(
     reader: &mut BufReader<R>,
 ) -> Result<Vec<CertificateDer<'static>>, TlsError> {
-    let certs = rustls_pemfile::certs(reader)
-        .collect::<std::result::Result<Vec<_>, _>>()
-        .map_err(|e| TlsError::InvalidCert(e.to_string()))?;
+    let pem = read_pem(reader).map_err(TlsError::InvalidCert)?;
+
+    let mut certs = vec![];
+    for item in rustls_pemfile::read_all(&mut pem.as_bytes()) {
+        match item.map_err(|e| TlsError::InvalidCert(e.to_string()))? {
+            rustls_pemfile::Item::X509Certificate(cert) => certs.push(cert),
+            rustls_pemfile::Item::Pkcs1Key(_)
+            | rustls_pemfile::Item::Pkcs8Key(_)
+            | rustls_pemfile::Item::Sec1Key(_) => {
+                return Err(TlsError::InvalidCert(
+                    "private key found in certificate list, move it to 'key'".to_owned(),
+                ))
+            }
+            _ => {}
+        }
+    }
+
+    if certs.is_empty() {
+        return Err(TlsError::InvalidCert("no certificate found".to_owned()));
+    }
 
     Ok(certs)
 }
@@ -139,25 +613,246 @@ pub fn load_certs<R: std::io::Read>(
 pub fn load_priv_key<R: std::io::Read>(
     reader: &mut BufReader<R>,
 ) -> Result<PrivateKeyDer<'static>, TlsError> {
-    let key = rustls_pemfile::private_key(reader)
+    let pem = read_pem(reader).map_err(TlsError::InvalidKey)?;
+    let key = rustls_pemfile::private_key(&mut pem.as_bytes())
         .map_err(|e| TlsError::InvalidKey(e.to_string()))?
         .ok_or(TlsError::InvalidKey("not found".to_string()))?;
 
     Ok(key)
 }
 
-#[derive(Debug)]
-struct NoServerCertVerifier;
+/// Read a bounded pem input, normalizing line endings and indentation.
+fn read_pem<R: std::io::Read>(reader: &mut BufReader<R>) -> Result<String, String> {
+    let mut buf = vec![];
+    reader
+        .take(MAX_PEM_SIZE + 1)
+        .read_to_end(&mut buf)
+        .map_err(|e| e.to_string())?;
+    if buf.len() as u64 > MAX_PEM_SIZE {
+        return Err(format!("pem input exceeds {} bytes", MAX_PEM_SIZE));
+    }
+
+    let text = String::from_utf8(buf).map_err(|_| "pem input is not utf-8".to_owned())?;
+    let mut pem = text.lines().map(str::trim).collect::<Vec<_>>().join("\n");
+    pem.push('\n');
 
+    Ok(pem)
+}
+
+/// Fields of the tbs certificate following the optional version.
+fn tbs_fields<'a>(cert: &'a CertificateDer<'_>) -> Option<&'a [u8]> {
+    let (_, cert, _) = der_read(cert)?;
+    let (_, tbs, _) = der_read(cert)?;
+    if tbs.first() == Some(&0xa0) {
+        return Some(der_read(tbs)?.2);
+    }
+    Some(tbs)
+}
+
+/// Common name of a certificate subject, read with a minimal der walk.
+fn cert_subject(cert: &CertificateDer<'_>) -> Option<String> {
+    const COMMON_NAME: &[u8] = &[0x55, 0x04, 0x03];
+
+    let mut tbs = tbs_fields(cert)?;
+    // serial, signature, issuer, validity
+    for _ in 0..4 {
+        tbs = der_read(tbs)?.2;
+    }
+
+    let (_, mut subject, _) = der_read(tbs)?;
+    while !subject.is_empty() {
+        let (_, set, rest) = der_read(subject)?;
+        let (_, attr, _) = der_read(set)?;
+        let (_, oid, value) = der_read(attr)?;
+        if oid == COMMON_NAME {
+            let (_, value, _) = der_read(value)?;
+            return Some(format!("CN={}", String::from_utf8_lossy(value)));
+        }
+        subject = rest;
+    }
+
+    None
+}
+
+/// Dns names and ip addresses of the subject alternative name extension.
+///
+/// The subject common name is not included, verifiers ignore it.
+pub fn cert_san_names(cert: &CertificateDer<'_>) -> Vec<String> {
+    cert_san_names_inner(cert).unwrap_or_default()
+}
+
+fn cert_san_names_inner(cert: &CertificateDer<'_>) -> Option<Vec<String>> {
+    const SUBJECT_ALT_NAME: &[u8] = &[0x55, 0x1d, 0x11];
+    const DNS_NAME: u8 = 0x82;
+    const IP_ADDRESS: u8 = 0x87;
+
+    let mut tbs = tbs_fields(cert)?;
+    // serial, signature, issuer, validity, subject, public key
+    for _ in 0..6 {
+        tbs = der_read(tbs)?.2;
+    }
+
+    let mut names = vec![];
+    while !tbs.is_empty() {
+        let (tag, field, rest) = der_read(tbs)?;
+        tbs = rest;
+        if tag != 0xa3 {
+            continue;
+        }
+
+        let (_, mut extensions, _) = der_read(field)?;
+        while !extensions.is_empty() {
+            let (_, ext, rest) = der_read(extensions)?;
+            extensions = rest;
+            let (_, oid, mut ext) = der_read(ext)?;
+            if oid != SUBJECT_ALT_NAME {
+                continue;
+            }
+            // skip the critical flag
+            if ext.first() == Some(&0x01) {
+                ext = der_read(ext)?.2;
+            }
+            let (_, value, _) = der_read(ext)?;
+            let (_, mut general_names, _) = der_read(value)?;
+            while !general_names.is_empty() {
+                let (tag, name, rest) = der_read(general_names)?;
+                general_names = rest;
+                match tag {
+                    DNS_NAME => names.push(String::from_utf8_lossy(name).into_owned()),
+                    IP_ADDRESS => match name.len() {
+                        4 => names.push(IpAddr::from(<[u8; 4]>::try_from(name).ok()?).to_string()),
+                        16 => {
+                            names.push(IpAddr::from(<[u8; 16]>::try_from(name).ok()?).to_string())
+                        }
+                        _ => {}
+                    },
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    Some(names)
+}
+
+/// Whether a subject alternative name covers `name`.
+///
+/// A leading `*.` covers exactly one non empty label and needs at least two
+/// labels after it, ip addresses only match themselves.
+pub fn san_matches(pattern: &str, name: &str) -> bool {
+    if let Ok(ip) = name.parse::<IpAddr>() {
+        return pattern.parse::<IpAddr>() == Ok(ip);
+    }
+
+    let pattern = pattern.trim_end_matches('.').to_ascii_lowercase();
+    let name = name.trim_end_matches('.').to_ascii_lowercase();
+    match pattern.strip_prefix("*.") {
+        Some(suffix) => {
+            suffix.contains('.')
+                && name
+                    .split_once('.')
+                    .is_some_and(|(label, rest)| !label.is_empty() && rest == suffix)
+        }
+        None => pattern == name,
+    }
+}
+
+/// Roots the client configs verify servers against.
+fn client_roots() -> RootCertStore {
+    RootCertStore {
+        roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+    }
+}
+
+/// Whether `certs` verify for `name` as a client built from `opt` would
+/// verify them, with its roots and crypto provider.
+pub fn cert_verifies(opt: &TlsClientOption, certs: &[CertificateDer<'_>], name: &str) -> bool {
+    let Some((end_entity, intermediates)) = certs.split_first() else {
+        return false;
+    };
+    let Ok(name) = ServerName::try_from(name.to_owned()) else {
+        return false;
+    };
+    let Ok(protocols) = Protocols::new(opt.min_version, opt.max_version, &opt.cipher_suites) else {
+        return false;
+    };
+    let verifier =
+        WebPkiServerVerifier::builder_with_provider(Arc::new(client_roots()), protocols.provider);
+    let Ok(verifier) = verifier.build() else {
+        return false;
+    };
+
+    verifier
+        .verify_server_cert(end_entity, intermediates, &name, &[], UnixTime::now())
+        .is_ok()
+}
+
+/// Split one der element into tag, contents and the remaining input.
+fn der_read(input: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, input) = input.split_first()?;
+    let (&len, mut input) = input.split_first()?;
+
+    let len = if len & 0x80 == 0 {
+        len as usize
+    } else {
+        let n = (len & 0x7f) as usize;
+        if n == 0 || n > std::mem::size_of::<usize>() || input.len() < n {
+            return None;
+        }
+        let (bytes, rest) = input.split_at(n);
+        input = rest;
+        bytes.iter().fold(0usize, |acc, b| (acc << 8) | *b as usize)
+    };
+
+    if input.len() < len {
+        return None;
+    }
+    let (contents, rest) = input.split_at(len);
+    Some((tag, contents, rest))
+}
+
+/// Sha256 fingerprint of a der encoded certificate.
+pub fn cert_sha256(cert: &CertificateDer<'_>) -> [u8; 32] {
+    Sha256::digest(cert).into()
+}
+
+#[cfg(feature = "insecure-tls")]
+struct NoServerCertVerifier {
+    on_first_seen: Option<CertSeenCallback>,
+    seen: Mutex<HashSet<[u8; 32]>>,
+}
+
+#[cfg(feature = "insecure-tls")]
+impl std::fmt::Debug for NoServerCertVerifier {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("NoServerCertVerifier")
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(feature = "insecure-tls")]
 impl ServerCertVerifier for NoServerCertVerifier {
     fn verify_server_cert(
         &self,
-        _end_entity: &rustls::pki_types::CertificateDer<'_>,
+        end_entity: &rustls::pki_types::CertificateDer<'_>,
         _intermediates: &[rustls::pki_types::CertificateDer<'_>],
         _server_name: &rustls::pki_types::ServerName<'_>,
         _ocsp_response: &[u8],
         _now: rustls::pki_types::UnixTime,
     ) -> std::result::Result<ServerCertVerified, rustls::Error> {
+        if let Some(ref on_first_seen) = self.on_first_seen {
+            let fingerprint = cert_sha256(end_entity);
+            let mut seen = self.seen.lock().unwrap_or_else(|e| e.into_inner());
+            if !seen.contains(&fingerprint) {
+                if !on_first_seen(end_entity) {
+                    return Err(rustls::Error::General(
+                        "certificate rejected by on_first_seen".to_owned(),
+                    ));
+                }
+                seen.insert(fingerprint);
+            }
+        }
+
         Ok(ServerCertVerified::assertion())
     }
 
@@ -197,3 +892,357 @@ impl ServerCertVerifier for NoServerCertVerifier {
         ]
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn self_signed(name: &str) -> (String, String) {
+        let cert = rcgen::generate_simple_self_signed(vec![name.into()]).unwrap();
+        (cert.cert.pem(), cert.key_pair.serialize_pem())
+    }
+
+    fn server_option(certs: Vec<String>, key: String) -> TlsServerOption {
+        TlsServerOption {
+            alpn: vec![],
+            require_alpn: false,
+            ticket_keys: None,
+            certificate: TlsCertOption::Text {
+                certs,
+                key: key.into(),
+            },
+            client_ca: None,
+            require_client_cert: false,
+            session_tickets: false,
+            min_version: None,
+            max_version: None,
+            cipher_suites: vec![],
+        }
+    }
+
+    fn server_error(certs: Vec<String>, key: String) -> String {
+        match ServerConfig::try_from(server_option(certs, key)) {
+            Ok(_) => panic!("invalid tls option accepted"),
+            Err(e) => e.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_pem_crlf_and_whitespace() {
+        let (cert, key) = self_signed("localhost");
+        let cert = cert
+            .trim_end()
+            .lines()
+            .map(|l| format!("    {}\r\n", l))
+            .collect::<String>();
+        let key = format!("\n  {}", key.replace('\n', "\r\n").trim_end());
+
+        ServerConfig::try_from(server_option(vec![cert], key)).unwrap();
+    }
+
+    #[test]
+    fn test_pem_key_in_certs() {
+        let (cert, key) = self_signed("localhost");
+        let err = server_error(vec![cert, key.clone()], key);
+        assert!(
+            err.contains("private key found in certificate list"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn test_pem_no_certs() {
+        let (_, key) = self_signed("localhost");
+        let err = server_error(vec![], key.clone());
+        assert!(err.contains("no certificate found"), "{}", err);
+
+        let err = server_error(vec!["not a pem".into()], key);
+        assert!(err.contains("no certificate found"), "{}", err);
+    }
+
+    #[test]
+    fn test_pem_key_mismatch() {
+        let (cert, _) = self_signed("kapibara.test");
+        let (_, key) = self_signed("other.test");
+        let err = server_error(vec![cert], key);
+        assert!(err.contains("CN=rcgen self signed cert"), "{}", err);
+    }
+
+    #[test]
+    fn test_pem_invalid_key() {
+        let (cert, _) = self_signed("localhost");
+        let err = server_error(vec![cert.clone()], "garbage".into());
+        assert!(err.contains("invalid private key: not found"), "{}", err);
+
+        let err = server_error(vec![cert.clone()], cert);
+        assert!(err.contains("invalid private key"), "{}", err);
+    }
+
+    #[test]
+    fn test_pem_oversized() {
+        let (cert, key) = self_signed("localhost");
+        let padding = " ".repeat(MAX_PEM_SIZE as usize);
+        let err = server_error(vec![cert, padding], key);
+        assert!(err.contains("exceeds"), "{}", err);
+    }
+
+    #[test]
+    fn test_client_ca() {
+        let (ca, ca_key) = self_signed("ca.test");
+        let (cert, key) = self_signed("localhost");
+
+        let path = std::env::temp_dir().join(format!("kapibara-ca-{}.pem", std::process::id()));
+        std::fs::write(&path, &ca).unwrap();
+        let file = TlsCertOption::File {
+            cert: path.clone(),
+            key: PathBuf::new(),
+            watch: false,
+        };
+        let text = TlsCertOption::Text {
+            certs: vec![ca],
+            key: ca_key.into(),
+        };
+        for (client_ca, require_client_cert) in [(file, true), (text, false)] {
+            let opt = TlsServerOption {
+                client_ca: Some(client_ca),
+                require_client_cert,
+                ..server_option(vec![cert.clone()], key.clone())
+            };
+            ServerConfig::try_from(opt).unwrap();
+        }
+        std::fs::remove_file(path).unwrap();
+
+        // a bad bundle fails at init, not at the first handshake
+        let opt = TlsServerOption {
+            client_ca: Some(TlsCertOption::Text {
+                certs: vec!["not a pem".into()],
+                key: String::new().into(),
+            }),
+            require_client_cert: true,
+            ..server_option(vec![cert.clone()], key.clone())
+        };
+        let err = ServerConfig::try_from(opt).unwrap_err();
+        assert!(matches!(err, TlsError::InvalidCert(_)), "{}", err);
+
+        let opt = TlsServerOption {
+            require_client_cert: true,
+            ..server_option(vec![cert], key)
+        };
+        let err = ServerConfig::try_from(opt).unwrap_err();
+        assert!(matches!(err, TlsError::InvalidCert(_)), "{}", err);
+    }
+
+    #[test]
+    fn test_version_suite_errors() {
+        let (cert, key) = self_signed("localhost");
+        let client = |json: &str| {
+            let opt: TlsClientOption = serde_json::from_str(json).unwrap();
+            ClientConfig::try_from(opt).unwrap_err()
+        };
+
+        let err = client(r#"{"cipher_suites": ["TLS13_AES_128_GCM_SHA256", "TLS_KAPI"]}"#);
+        assert!(matches!(err, TlsError::InvalidConfig(_)), "{}", err);
+        assert!(err.to_string().contains("TLS_KAPI"), "{}", err);
+        assert_eq!(err.code().as_str(), "tls.config_invalid");
+
+        let err = client(r#"{"min_version": "tls13", "max_version": "tls12"}"#);
+        assert_eq!(
+            err.to_string(),
+            "invalid tls config: min_version tls13 is above max_version tls12"
+        );
+
+        // suites of another version leave nothing to offer
+        let opt = TlsServerOption {
+            max_version: Some(TlsVersion::Tls12),
+            cipher_suites: vec!["tls13_aes_256_gcm_sha384".into()],
+            ..server_option(vec![cert], key)
+        };
+        let err = ServerConfig::try_from(opt).unwrap_err();
+        assert!(err.to_string().contains("no cipher suite"), "{}", err);
+
+        assert!(serde_json::from_str::<TlsClientOption>(r#"{"min_version": "TLS13"}"#).is_err());
+    }
+
+    /// Handshake in memory, the version and suite agreed on.
+    #[cfg(feature = "insecure-tls")]
+    fn handshake(
+        client: ClientConfig,
+        server: ServerConfig,
+    ) -> Result<(rustls::ProtocolVersion, rustls::CipherSuite), rustls::Error> {
+        let name = ServerName::try_from("localhost").unwrap();
+        let mut client = rustls::ClientConnection::new(Arc::new(client), name)?;
+        let mut server = rustls::ServerConnection::new(Arc::new(server))?;
+        for _ in 0..8 {
+            if !client.is_handshaking() && !server.is_handshaking() {
+                let suite = client.negotiated_cipher_suite().unwrap().suite();
+                return Ok((client.protocol_version().unwrap(), suite));
+            }
+            let mut buf = vec![];
+            client.write_tls(&mut buf).unwrap();
+            server.read_tls(&mut buf.as_slice()).unwrap();
+            server.process_new_packets()?;
+            buf.clear();
+            server.write_tls(&mut buf).unwrap();
+            client.read_tls(&mut buf.as_slice()).unwrap();
+            client.process_new_packets()?;
+        }
+        panic!("handshake did not finish");
+    }
+
+    #[cfg(feature = "insecure-tls")]
+    #[test]
+    fn test_versions_and_suites() {
+        use rustls::{CipherSuite, ProtocolVersion};
+
+        let (cert, key) = self_signed("localhost");
+        let client = |json: &str| {
+            let mut opt: TlsClientOption = serde_json::from_str(json).unwrap();
+            opt.insecure = true;
+            ClientConfig::try_from(opt).unwrap()
+        };
+        let server = |opt: TlsServerOption| ServerConfig::try_from(opt).unwrap();
+        let defaults = || server_option(vec![cert.clone()], key.clone());
+
+        let tls12 = server(TlsServerOption {
+            max_version: Some(TlsVersion::Tls12),
+            ..defaults()
+        });
+        let (version, _) = handshake(client("{}"), tls12.clone()).unwrap();
+        assert_eq!(version, ProtocolVersion::TLSv1_2);
+        assert!(handshake(client(r#"{"min_version": "tls13"}"#), tls12).is_err());
+
+        // suite names match in any case
+        let pinned = client(r#"{"cipher_suites": ["tls13_aes_128_gcm_sha256"]}"#);
+        let (version, suite) = handshake(pinned, server(defaults())).unwrap();
+        assert_eq!(version, ProtocolVersion::TLSv1_3);
+        assert_eq!(suite, CipherSuite::TLS13_AES_128_GCM_SHA256);
+
+        let aes256 = server(TlsServerOption {
+            cipher_suites: vec!["TLS13_AES_256_GCM_SHA384".into()],
+            ..defaults()
+        });
+        let pinned = client(r#"{"cipher_suites": ["TLS13_AES_128_GCM_SHA256"]}"#);
+        assert!(handshake(pinned, aes256).is_err());
+    }
+
+    #[test]
+    fn test_san_matches() {
+        for (pattern, name, expect) in [
+            ("example.com", "example.com", true),
+            ("Example.COM", "example.com.", true),
+            ("example.com", "www.example.com", false),
+            ("*.example.com", "www.example.com", true),
+            ("*.example.com", "WWW.example.com", true),
+            ("*.example.com", "example.com", false),
+            ("*.example.com", "a.b.example.com", false),
+            ("*.example.com", ".example.com", false),
+            ("*.com", "example.com", false),
+            ("www.*.com", "www.example.com", false),
+            ("127.0.0.1", "127.0.0.1", true),
+            ("::1", "0:0:0:0:0:0:0:1", true),
+            ("*.0.0.1", "127.0.0.1", false),
+            ("localhost", "127.0.0.1", false),
+        ] {
+            assert_eq!(san_matches(pattern, name), expect, "{} {}", pattern, name);
+        }
+    }
+
+    #[test]
+    fn test_cert_san_names() {
+        let names = vec![
+            "localhost".to_owned(),
+            "*.example.com".to_owned(),
+            "127.0.0.1".to_owned(),
+            "::1".to_owned(),
+        ];
+        let cert = rcgen::generate_simple_self_signed(names.clone()).unwrap();
+        let opt = TlsCertOption::Text {
+            certs: vec![cert.cert.pem()],
+            key: cert.key_pair.serialize_pem().into(),
+        };
+        let (certs, _) = opt.load().unwrap();
+        assert_eq!(cert_san_names(&certs[0]), names);
+        assert!(!cert_verifies(
+            &TlsClientOption::default(),
+            &certs,
+            "localhost"
+        ));
+    }
+
+    #[cfg(feature = "self-signed")]
+    #[test]
+    fn test_self_signed() {
+        let names = vec!["localhost".to_owned(), "127.0.0.1".to_owned()];
+        let opt: TlsCertOption = serde_json::from_str(
+            r#"{"self_signed": {"hosts": ["localhost", "127.0.0.1"], "validity_days": 30}}"#,
+        )
+        .unwrap();
+        let copy = opt.clone();
+        let (certs, _) = opt.load().unwrap();
+        assert_eq!(cert_san_names(&certs[0]), names);
+        assert_eq!(cert_subject(&certs[0]).unwrap(), "CN=localhost");
+
+        // clones share the generated pair
+        let pem = copy.self_signed_pem().unwrap().unwrap();
+        let pinned = load_certs(&mut BufReader::new(Cursor::new(pem.as_bytes()))).unwrap();
+        assert_eq!(cert_sha256(&pinned[0]), cert_sha256(&certs[0]));
+        assert_ne!(
+            TlsCertOption::self_signed(names.clone(), 30)
+                .load_certs()
+                .unwrap(),
+            certs
+        );
+        let server = TlsServerOption {
+            alpn: vec![],
+            require_alpn: false,
+            certificate: copy,
+            ticket_keys: None,
+            client_ca: None,
+            require_client_cert: false,
+            session_tickets: false,
+            min_version: None,
+            max_version: None,
+            cipher_suites: vec![],
+        };
+        assert!(ServerConfig::try_from(server).is_ok());
+
+        for opt in [
+            TlsCertOption::self_signed(vec![], 30),
+            TlsCertOption::self_signed(names, 0),
+        ] {
+            assert!(matches!(opt.load(), Err(TlsError::InvalidConfig(_))));
+        }
+        let text = TlsCertOption::Text {
+            certs: vec![],
+            key: String::new().into(),
+        };
+        assert!(text.self_signed_pem().unwrap().is_none());
+    }
+
+    #[cfg(not(feature = "insecure-tls"))]
+    #[test]
+    fn test_insecure_disabled() {
+        use crate::{
+            tcp::{TcpClient, TcpClientOption},
+            Resolver,
+        };
+
+        // shared configs still parse
+        let opt: TlsClientOption = serde_json::from_str(r#"{"insecure": true}"#).unwrap();
+        let err = ClientConfig::try_from(opt.clone()).unwrap_err();
+        assert!(matches!(err, TlsError::InsecureDisabled), "{}", err);
+
+        // init fails instead of building a verifying config
+        let tcp = TcpClientOption {
+            addr: "127.0.0.1".into(),
+            port: 443,
+            ..Default::default()
+        };
+        let Err(err) = TcpClient::init(tcp, Some(opt), &Resolver::default()) else {
+            panic!("insecure client built without the insecure-tls feature");
+        };
+        assert_eq!(err.code().as_str(), "tls.insecure_disabled");
+        assert!(err.to_string().contains("insecure-tls"), "{}", err);
+    }
+}
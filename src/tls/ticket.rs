@@ -0,0 +1,309 @@
+//! Tls Session Ticket Keys
+
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use arc_swap::ArcSwap;
+use aws_lc_rs::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use rustls::server::ProducesTickets;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::secret::Secret;
+
+use super::TlsError;
+
+const NAME_LEN: usize = 16;
+/// Ticket lifetime hint when keys are not rotated here.
+const STATIC_LIFETIME: Duration = Duration::from_secs(6 * 3600);
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct TicketKeyOption {
+    /// Base64 keys shared across servers. The first one encrypts new tickets,
+    /// all of them decrypt. 32 bytes of key, or a 16 byte name followed by it.
+    #[serde(default)]
+    pub keys: Vec<Secret>,
+    /// Without `keys`, generate a key and replace it this often, the
+    /// previous one still decrypts. Such keys are never shared, so this only
+    /// resumes sessions on the same server.
+    #[serde(default)]
+    pub rotate_every: Option<Duration>,
+}
+
+struct TicketKey {
+    name: [u8; NAME_LEN],
+    key: LessSafeKey,
+}
+
+impl TicketKey {
+    fn new(bytes: &[u8]) -> Result<Self, TlsError> {
+        let (name, key) = match bytes.len() {
+            32 => {
+                let digest = Sha256::digest(bytes);
+                let mut name = [0u8; NAME_LEN];
+                name.copy_from_slice(&digest[..NAME_LEN]);
+                (name, bytes)
+            }
+            48 => {
+                let mut name = [0u8; NAME_LEN];
+                name.copy_from_slice(&bytes[..NAME_LEN]);
+                (name, &bytes[NAME_LEN..])
+            }
+            n => {
+                return Err(TlsError::InvalidTicketKey(format!(
+                    "{} bytes, expected 32 or 48",
+                    n
+                )))
+            }
+        };
+        let key = UnboundKey::new(&AES_256_GCM, key)
+            .map_err(|_| TlsError::InvalidTicketKey("rejected by aes-256-gcm".to_owned()))?;
+
+        Ok(Self {
+            name,
+            key: LessSafeKey::new(key),
+        })
+    }
+
+    fn generate() -> Result<Self, TlsError> {
+        let mut bytes = [0u8; 32];
+        aws_lc_rs::rand::fill(&mut bytes)
+            .map_err(|_| TlsError::InvalidTicketKey("no randomness".to_owned()))?;
+        Self::new(&bytes)
+    }
+}
+
+struct Rotation {
+    every: Duration,
+    last: Mutex<Instant>,
+}
+
+/// Session ticket encryption with keys that can be replaced at runtime.
+///
+/// Tickets are the key name, a random nonce and the AES-256-GCM sealed state.
+pub struct TicketKeys {
+    keys: ArcSwap<Vec<Arc<TicketKey>>>,
+    rotation: Option<Rotation>,
+    lifetime: u32,
+}
+
+impl std::fmt::Debug for TicketKeys {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TicketKeys")
+            .field("keys", &self.keys.load().len())
+            .field("rotating", &self.rotation.is_some())
+            .finish()
+    }
+}
+
+impl TicketKeys {
+    pub fn new(opt: &TicketKeyOption) -> Result<Self, TlsError> {
+        if opt.keys.is_empty() {
+            let every = opt.rotate_every.unwrap_or(STATIC_LIFETIME);
+            return Ok(Self {
+                keys: ArcSwap::from_pointee(vec![Arc::new(TicketKey::generate()?)]),
+                rotation: opt.rotate_every.map(|every| Rotation {
+                    every,
+                    last: Mutex::new(Instant::now()),
+                }),
+                // a ticket stays valid for at most two periods
+                lifetime: (every * 2).as_secs().min(u32::MAX as u64) as u32,
+            });
+        }
+
+        let keys = Self {
+            keys: ArcSwap::from_pointee(vec![]),
+            rotation: None,
+            lifetime: opt
+                .rotate_every
+                .unwrap_or(STATIC_LIFETIME)
+                .as_secs()
+                .min(u32::MAX as u64) as u32,
+        };
+        let secrets = opt
+            .keys
+            .iter()
+            .map(|key| key.expose().map(str::to_owned))
+            .collect::<Result<Vec<_>, _>>()?;
+        keys.set_keys(&secrets)?;
+        Ok(keys)
+    }
+
+    /// Replace the keys, for example when the fleet rotates them.
+    pub fn set_keys(&self, keys: &[String]) -> Result<(), TlsError> {
+        if keys.is_empty() {
+            return Err(TlsError::InvalidTicketKey("no key".to_owned()));
+        }
+        let keys = keys
+            .iter()
+            .map(|key| {
+                let bytes = STANDARD
+                    .decode(key.trim())
+                    .map_err(|e| TlsError::InvalidTicketKey(e.to_string()))?;
+                TicketKey::new(&bytes).map(Arc::new)
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        self.keys.store(Arc::new(keys));
+        Ok(())
+    }
+
+    /// Put a new generated key first, keeping the previous one for decryption.
+    fn rotate(&self) {
+        let Some(ref rotation) = self.rotation else {
+            return;
+        };
+        let mut last = rotation.last.lock().unwrap_or_else(|e| e.into_inner());
+        if last.elapsed() < rotation.every {
+            return;
+        }
+        let Ok(key) = TicketKey::generate() else {
+            return;
+        };
+        // a server idle for more than a period keeps no stale key around
+        let stale = last.elapsed() >= rotation.every * 2;
+        *last = Instant::now();
+
+        let previous = self.keys.load().first().filter(|_| !stale).cloned();
+        self.keys.store(Arc::new(
+            [Some(Arc::new(key)), previous]
+                .into_iter()
+                .flatten()
+                .collect(),
+        ));
+    }
+}
+
+impl ProducesTickets for TicketKeys {
+    fn enabled(&self) -> bool {
+        true
+    }
+
+    fn lifetime(&self) -> u32 {
+        self.lifetime
+    }
+
+    fn encrypt(&self, plain: &[u8]) -> Option<Vec<u8>> {
+        self.rotate();
+        let keys = self.keys.load();
+        let key = keys.first()?;
+
+        let mut nonce = [0u8; NONCE_LEN];
+        aws_lc_rs::rand::fill(&mut nonce).ok()?;
+        let mut sealed = plain.to_vec();
+        key.key
+            .seal_in_place_append_tag(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::from(key.name),
+                &mut sealed,
+            )
+            .ok()?;
+
+        let mut ticket = Vec::with_capacity(NAME_LEN + NONCE_LEN + sealed.len());
+        ticket.extend_from_slice(&key.name);
+        ticket.extend_from_slice(&nonce);
+        ticket.extend_from_slice(&sealed);
+        Some(ticket)
+    }
+
+    fn decrypt(&self, cipher: &[u8]) -> Option<Vec<u8>> {
+        self.rotate();
+        if cipher.len() < NAME_LEN + NONCE_LEN {
+            return None;
+        }
+        let (name, rest) = cipher.split_at(NAME_LEN);
+        let (nonce, sealed) = rest.split_at(NONCE_LEN);
+
+        let keys = self.keys.load();
+        let key = keys.iter().find(|k| k.name[..] == *name)?;
+        let mut buf = sealed.to_vec();
+        let plain = key
+            .key
+            .open_in_place(
+                Nonce::try_assume_unique_for_key(nonce).ok()?,
+                Aad::from(key.name),
+                &mut buf,
+            )
+            .ok()?;
+        Some(plain.to_vec())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(byte: u8, len: usize) -> Secret {
+        STANDARD.encode(vec![byte; len]).into()
+    }
+
+    #[test]
+    fn test_ticket_key_overlap() {
+        let old = TicketKeys::new(&TicketKeyOption {
+            keys: vec![key(1, 32)],
+            rotate_every: None,
+        })
+        .unwrap();
+        let ticket = old.encrypt(b"session").unwrap();
+        assert_eq!(old.decrypt(&ticket).unwrap(), b"session");
+
+        // the fleet moves to a new key, keeping the old one for decryption
+        let new = TicketKeys::new(&TicketKeyOption {
+            keys: vec![key(2, 48), key(1, 32)],
+            rotate_every: None,
+        })
+        .unwrap();
+        assert_eq!(new.decrypt(&ticket).unwrap(), b"session");
+        let fresh = new.encrypt(b"session").unwrap();
+        assert_ne!(fresh[..NAME_LEN], ticket[..NAME_LEN]);
+        assert!(old.decrypt(&fresh).is_none());
+
+        new.set_keys(&[STANDARD.encode([2; 48])]).unwrap();
+        assert!(new.decrypt(&ticket).is_none());
+        assert_eq!(new.decrypt(&fresh).unwrap(), b"session");
+
+        let mut forged = fresh.clone();
+        *forged.last_mut().unwrap() ^= 1;
+        assert!(new.decrypt(&forged).is_none());
+        assert!(new.decrypt(&fresh[..NAME_LEN]).is_none());
+    }
+
+    #[test]
+    fn test_ticket_key_invalid() {
+        for bad in [key(1, 16), "not base64".into()] {
+            let opt = TicketKeyOption {
+                keys: vec![bad],
+                rotate_every: None,
+            };
+            let err = TicketKeys::new(&opt).unwrap_err();
+            assert_eq!(err.code().as_str(), "tls.ticket_key_invalid");
+        }
+
+        let keys = TicketKeys::new(&TicketKeyOption::default()).unwrap();
+        assert!(keys.set_keys(&[]).is_err());
+        assert!(keys.encrypt(b"a").is_some());
+    }
+
+    #[test]
+    fn test_ticket_key_rotation() {
+        let every = Duration::from_millis(50);
+        let keys = TicketKeys::new(&TicketKeyOption {
+            keys: vec![],
+            rotate_every: Some(every),
+        })
+        .unwrap();
+        assert_eq!(keys.lifetime(), 0);
+
+        let first = keys.encrypt(b"a").unwrap();
+        std::thread::sleep(every);
+        let second = keys.encrypt(b"b").unwrap();
+        assert_ne!(first[..NAME_LEN], second[..NAME_LEN]);
+        assert_eq!(keys.decrypt(&first).unwrap(), b"a");
+
+        std::thread::sleep(every);
+        assert!(keys.decrypt(&first).is_none());
+        assert_eq!(keys.decrypt(&second).unwrap(), b"b");
+    }
+}
@@ -0,0 +1,177 @@
+//! Tls Certificate File Watch
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use tokio::task::JoinHandle;
+
+use super::{TlsCertOption, TlsError, TlsServerOption};
+
+/// How often the files of a `watch`ed certificate are checked.
+pub const WATCH_INTERVAL: Duration = Duration::from_secs(5);
+
+/// The tls option a server currently runs with.
+pub(crate) type CurrentTls = Arc<dyn Fn() -> Option<TlsServerOption> + Send + Sync>;
+/// Rebuilds and swaps in the tls config of a server, as `reload_tls` does.
+pub(crate) type ReloadTls = Arc<dyn Fn(TlsServerOption) -> Result<(), TlsError> + Send + Sync>;
+
+/// Modification time and length of a file, `None` while it is missing.
+type Stamp = Option<(SystemTime, u64)>;
+
+fn stamp(path: &Path) -> Stamp {
+    let meta = fs::metadata(path).ok()?;
+    Some((meta.modified().ok()?, meta.len()))
+}
+
+/// The certificate and key paths of a watched file certificate.
+fn watched(opt: &TlsServerOption) -> Option<(&PathBuf, &PathBuf)> {
+    match opt.certificate {
+        TlsCertOption::File {
+            ref cert,
+            ref key,
+            watch: true,
+        } => Some((cert, key)),
+        _ => None,
+    }
+}
+
+/// A running watch, stopped on drop.
+pub struct WatchTask(JoinHandle<()>);
+
+impl Drop for WatchTask {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/// Reload the certificate when its files change, if the current option
+/// watches them.
+///
+/// A change is applied once the files stayed the same for one more
+/// `interval`, so a renewal writing the certificate and key one after the
+/// other reloads once. A failed reload keeps the running config and is
+/// retried on the next change.
+pub(crate) fn spawn(
+    interval: Duration,
+    current: CurrentTls,
+    reload: ReloadTls,
+) -> Option<WatchTask> {
+    let opt = current()?;
+    let (cert, key) = watched(&opt)?;
+    let mut applied = (cert.clone(), key.clone(), stamp(cert), stamp(key));
+    let mut pending = None;
+
+    Some(WatchTask(tokio::spawn(async move {
+        let mut ticks = tokio::time::interval(interval);
+        ticks.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        ticks.tick().await;
+        loop {
+            ticks.tick().await;
+            let Some(opt) = current() else {
+                continue;
+            };
+            let Some((cert, key)) = watched(&opt) else {
+                continue;
+            };
+            let now = (cert.clone(), key.clone(), stamp(cert), stamp(key));
+            if now == applied {
+                pending = None;
+                continue;
+            }
+            if pending.as_ref() != Some(&now) {
+                pending = Some(now);
+                continue;
+            }
+            match reload(opt.clone()) {
+                Ok(()) => log::info!("reloaded tls certificate {}", cert.display()),
+                Err(e) => log::warn!(
+                    "keeping the running tls config, reloading {} failed: {}",
+                    cert.display(),
+                    e
+                ),
+            }
+            applied = now;
+            pending = None;
+        }
+    })))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_watch_reload() {
+        let dir = std::env::temp_dir().join(format!("kapibara-watch-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let (cert, key) = (dir.join("cert.pem"), dir.join("key.pem"));
+        fs::write(&cert, "cert 1").unwrap();
+        fs::write(&key, "key 1").unwrap();
+
+        let opt = |watch| TlsServerOption {
+            alpn: vec![],
+            require_alpn: false,
+            certificate: TlsCertOption::File {
+                cert: cert.clone(),
+                key: key.clone(),
+                watch,
+            },
+            ticket_keys: None,
+            client_ca: None,
+            require_client_cert: false,
+            session_tickets: false,
+            min_version: None,
+            max_version: None,
+            cipher_suites: vec![],
+        };
+        let unwatched = opt(false);
+        let reload: ReloadTls = Arc::new(|_| Ok(()));
+        assert!(spawn(
+            Duration::from_millis(20),
+            Arc::new(move || Some(unwatched.clone())),
+            reload
+        )
+        .is_none());
+
+        // the first reload fails, the running config stays and the next change is tried
+        let reloads = Arc::new(Mutex::new(vec![]));
+        let seen = reloads.clone();
+        let watched = opt(true);
+        let _task = spawn(
+            Duration::from_millis(20),
+            Arc::new(move || Some(watched.clone())),
+            Arc::new(move |opt: TlsServerOption| {
+                let TlsCertOption::File { ref cert, .. } = opt.certificate else {
+                    unreachable!();
+                };
+                let content = fs::read_to_string(cert).unwrap();
+                let mut seen = seen.lock().unwrap();
+                seen.push(content);
+                match seen.len() {
+                    1 => Err(TlsError::InvalidCert("renewal half written".into())),
+                    _ => Ok(()),
+                }
+            }),
+        )
+        .unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(reloads.lock().unwrap().is_empty());
+
+        fs::write(&cert, "cert 22").unwrap();
+        fs::write(&key, "key 22").unwrap();
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        assert_eq!(*reloads.lock().unwrap(), ["cert 22"]);
+
+        fs::write(&cert, "cert 333").unwrap();
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        assert_eq!(*reloads.lock().unwrap(), ["cert 22", "cert 333"]);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,432 @@
+//! Connection Trace Sampling
+
+use std::{
+    collections::VecDeque,
+    fmt,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// Events held per unsampled connection, the oldest are dropped first.
+pub const RING_EVENTS: usize = 32;
+/// Message bytes held per unsampled connection.
+pub const RING_BYTES: usize = 4096;
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct SamplingOption {
+    /// Share of connections traced, from 0.0 to 1.0.
+    pub ratio: f64,
+    /// Buffer events of unsampled connections and emit them if the connection fails.
+    #[serde(default)]
+    pub always_on_error: bool,
+}
+
+/// One event of a traced connection.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceEvent {
+    pub conn: u64,
+    /// Time since the connection was accepted.
+    pub at: Duration,
+    pub message: String,
+    /// Held back and emitted once the unsampled connection failed.
+    pub retroactive: bool,
+}
+
+/// Receives the emitted events.
+pub type TraceSink = Arc<dyn Fn(&TraceEvent) + Send + Sync>;
+
+/// Debug logs under the `kapibara_transport::trace` target.
+pub fn log_sink() -> TraceSink {
+    Arc::new(|event: &TraceEvent| {
+        log::debug!(
+            target: "kapibara_transport::trace",
+            "conn {} +{:?}{} {}",
+            event.conn,
+            event.at,
+            if event.retroactive { " (late)" } else { "" },
+            event.message
+        )
+    })
+}
+
+/// Decides per connection id whether to trace.
+///
+/// The decision only depends on the id, so both ends of a connection that
+/// share an id sample the same way.
+#[derive(Clone)]
+pub struct Sampler {
+    threshold: u128,
+    always_on_error: bool,
+    sink: TraceSink,
+}
+
+impl fmt::Debug for Sampler {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Sampler")
+            .field("threshold", &self.threshold)
+            .field("always_on_error", &self.always_on_error)
+            .finish()
+    }
+}
+
+impl Sampler {
+    pub fn new(opt: &SamplingOption) -> Self {
+        Self::with_sink(opt, log_sink())
+    }
+
+    pub fn with_sink(opt: &SamplingOption, sink: TraceSink) -> Self {
+        let ratio = if opt.ratio.is_nan() {
+            0.0
+        } else {
+            opt.ratio.clamp(0.0, 1.0)
+        };
+        Self {
+            threshold: (ratio * (1u128 << 64) as f64) as u128,
+            always_on_error: opt.always_on_error,
+            sink,
+        }
+    }
+
+    /// Send events to `sink` instead.
+    pub fn set_sink(&mut self, sink: TraceSink) {
+        self.sink = sink;
+    }
+
+    pub fn sampled(&self, id: u64) -> bool {
+        (mix(id) as u128) < self.threshold
+    }
+
+    /// Trace state for a new connection.
+    pub fn conn(&self, id: u64) -> ConnTrace {
+        let state = if self.sampled(id) {
+            State::Emit
+        } else if self.always_on_error {
+            State::Buffer(EventRing::default())
+        } else {
+            State::Off
+        };
+
+        ConnTrace {
+            id,
+            start: Instant::now(),
+            state,
+            sink: self.sink.clone(),
+        }
+    }
+}
+
+/// Splitmix64 finalizer, sequential ids spread evenly over the range.
+fn mix(id: u64) -> u64 {
+    let mut z = id.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+/// Bounded event buffer of one connection.
+#[derive(Debug, Default)]
+struct EventRing {
+    events: VecDeque<(Duration, String)>,
+    bytes: usize,
+    dropped: usize,
+}
+
+impl EventRing {
+    fn push(&mut self, at: Duration, message: String) {
+        if message.len() > RING_BYTES {
+            self.dropped += 1;
+            return;
+        }
+        while self.events.len() >= RING_EVENTS || self.bytes + message.len() > RING_BYTES {
+            let Some((_, old)) = self.events.pop_front() else {
+                break;
+            };
+            self.bytes -= old.len();
+            self.dropped += 1;
+        }
+        self.bytes += message.len();
+        self.events.push_back((at, message));
+    }
+}
+
+#[derive(Debug)]
+enum State {
+    Emit,
+    Buffer(EventRing),
+    Off,
+}
+
+/// Events of one connection, emitted, held back or ignored as sampled.
+pub struct ConnTrace {
+    id: u64,
+    start: Instant,
+    state: State,
+    sink: TraceSink,
+}
+
+impl fmt::Debug for ConnTrace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ConnTrace")
+            .field("id", &self.id)
+            .field("state", &self.state)
+            .finish()
+    }
+}
+
+impl ConnTrace {
+    /// Whether events are emitted right away.
+    pub fn sampled(&self) -> bool {
+        matches!(self.state, State::Emit)
+    }
+
+    /// Record an event, `message` is not built for ignored connections.
+    pub fn event(&mut self, message: impl FnOnce() -> String) {
+        let at = self.start.elapsed();
+        match self.state {
+            State::Emit => self.emit(at, message(), false),
+            State::Buffer(ref mut ring) => ring.push(at, message()),
+            State::Off => {}
+        }
+    }
+
+    /// Record the error ending the connection, emitting held back events first.
+    pub fn fail(&mut self, message: impl FnOnce() -> String) {
+        let at = self.start.elapsed();
+        match std::mem::replace(&mut self.state, State::Emit) {
+            State::Buffer(ring) => {
+                if ring.dropped > 0 {
+                    let first = ring.events.front().map_or(at, |(at, _)| *at);
+                    self.emit(
+                        first,
+                        format!("{} earlier events dropped", ring.dropped),
+                        true,
+                    );
+                }
+                for (at, message) in ring.events {
+                    self.emit(at, message, true);
+                }
+                self.emit(at, message(), true);
+            }
+            State::Emit => self.emit(at, message(), false),
+            State::Off => self.state = State::Off,
+        }
+    }
+
+    fn emit(&self, at: Duration, message: String, retroactive: bool) {
+        (self.sink)(&TraceEvent {
+            conn: self.id,
+            at,
+            message,
+            retroactive,
+        });
+    }
+}
+
+/// Stream failing its trace on the first io error and closing it on drop.
+#[derive(Debug)]
+pub struct TracedStream<S> {
+    inner: S,
+    trace: Option<ConnTrace>,
+    failed: bool,
+}
+
+impl<S> TracedStream<S> {
+    pub fn new(inner: S, trace: Option<ConnTrace>) -> Self {
+        Self {
+            inner,
+            trace,
+            failed: false,
+        }
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    fn check<T>(&mut self, res: Poll<std::io::Result<T>>, op: &str) -> Poll<std::io::Result<T>> {
+        if let Poll::Ready(Err(ref e)) = res {
+            if let Some(trace) = self.trace.as_mut().filter(|_| !self.failed) {
+                trace.fail(|| format!("{} failed: {}", op, e));
+            }
+            self.failed = true;
+        }
+        res
+    }
+}
+
+impl<S> Drop for TracedStream<S> {
+    fn drop(&mut self) {
+        if let Some(trace) = self.trace.as_mut().filter(|_| !self.failed) {
+            trace.event(|| "closed".to_owned());
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for TracedStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        let res = Pin::new(&mut this.inner).poll_read(cx, buf);
+        this.check(res, "read")
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for TracedStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        let res = Pin::new(&mut this.inner).poll_write(cx, buf);
+        this.check(res, "write")
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        let res = Pin::new(&mut this.inner).poll_flush(cx);
+        this.check(res, "flush")
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        let res = Pin::new(&mut this.inner).poll_shutdown(cx);
+        this.check(res, "shutdown")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    fn collector() -> (TraceSink, Arc<Mutex<Vec<TraceEvent>>>) {
+        let events = Arc::new(Mutex::new(vec![]));
+        let sink_events = events.clone();
+        let sink: TraceSink = Arc::new(move |e: &TraceEvent| {
+            sink_events.lock().unwrap().push(e.clone());
+        });
+        (sink, events)
+    }
+
+    #[test]
+    fn test_sampling_ratio() {
+        for ratio in [0.0, 0.01, 0.25, 0.5, 1.0] {
+            let sampler = Sampler::new(&SamplingOption {
+                ratio,
+                always_on_error: false,
+            });
+            let n = 100_000;
+            let hits = (1..=n).filter(|&id| sampler.sampled(id)).count();
+            let share = hits as f64 / n as f64;
+            // three standard deviations of a binomial share
+            let tolerance = 3.0 * (ratio * (1.0 - ratio) / n as f64).sqrt();
+            assert!(
+                (share - ratio).abs() <= tolerance + f64::EPSILON,
+                "ratio {} sampled {}",
+                ratio,
+                share
+            );
+        }
+
+        // the same id samples the same way on both ends
+        let a = Sampler::new(&SamplingOption {
+            ratio: 0.3,
+            always_on_error: false,
+        });
+        let b = a.clone();
+        assert!((1..1000).all(|id| a.sampled(id) == b.sampled(id)));
+    }
+
+    #[tokio::test]
+    async fn test_error_always_traced() {
+        let (sink, events) = collector();
+        let sampler = Sampler::with_sink(
+            &SamplingOption {
+                ratio: 0.0,
+                always_on_error: true,
+            },
+            sink,
+        );
+
+        // a clean connection leaves nothing behind
+        let (a, mut b) = duplex(64);
+        let mut trace = sampler.conn(1);
+        trace.event(|| "accepted".to_owned());
+        let mut stream = TracedStream::new(a, Some(trace));
+        b.write_all(b"kapi").await.unwrap();
+        stream.read_exact(&mut [0u8; 4]).await.unwrap();
+        drop(stream);
+        assert!(events.lock().unwrap().is_empty());
+
+        let (a, b) = duplex(64);
+        let mut trace = sampler.conn(2);
+        trace.event(|| "accepted".to_owned());
+        let mut stream = TracedStream::new(a, Some(trace));
+        drop(b);
+        assert!(stream.write_all(b"kapi").await.is_err());
+        drop(stream);
+
+        let events = events.lock().unwrap();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].message, "accepted");
+        assert!(events[1].message.starts_with("write failed"));
+        assert!(events.iter().all(|e| e.conn == 2 && e.retroactive));
+    }
+
+    #[test]
+    fn test_ring_drops_oldest() {
+        let (sink, events) = collector();
+        let sampler = Sampler::with_sink(
+            &SamplingOption {
+                ratio: 0.0,
+                always_on_error: true,
+            },
+            sink,
+        );
+        let mut trace = sampler.conn(7);
+        for i in 0..RING_EVENTS + 8 {
+            trace.event(|| format!("event {}", i));
+        }
+        trace.fail(|| "reset".to_owned());
+
+        {
+            let events = events.lock().unwrap();
+            assert_eq!(events.len(), RING_EVENTS + 2);
+            assert_eq!(events[0].message, "8 earlier events dropped");
+            assert_eq!(events[1].message, "event 8");
+            assert_eq!(events[RING_EVENTS + 1].message, "reset");
+        }
+
+        // large messages are bounded by bytes instead
+        events.lock().unwrap().clear();
+        let mut trace = sampler.conn(8);
+        for _ in 0..4 {
+            trace.event(|| "x".repeat(RING_BYTES / 2));
+        }
+        trace.event(|| "x".repeat(RING_BYTES + 1));
+        trace.fail(|| "reset".to_owned());
+        {
+            let events = events.lock().unwrap();
+            assert_eq!(events[0].message, "3 earlier events dropped");
+            assert_eq!(events.len(), 4);
+        }
+
+        // after the failure the connection traces like a sampled one
+        trace.event(|| "closed".to_owned());
+        let events = events.lock().unwrap();
+        assert_eq!(events.last().unwrap().message, "closed");
+        assert!(!events.last().unwrap().retroactive);
+    }
+}
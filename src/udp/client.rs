@@ -0,0 +1,81 @@
+//! Udp Transport client
+
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    str::FromStr,
+};
+
+use tokio::net::UdpSocket;
+
+use crate::{
+    option::ProbeMode, ClientError, ClientResult, Resolver, TlsClientOption, TransportClientTrait,
+};
+
+use super::{UdpClientOption, UdpStream};
+
+pub struct UdpClient {
+    addr: Vec<SocketAddr>,
+}
+
+impl UdpClient {
+    pub fn init(
+        opt: UdpClientOption,
+        tls_opt: Option<TlsClientOption>,
+        resolver: &Resolver,
+    ) -> ClientResult<Self> {
+        let addr = match IpAddr::from_str(&opt.addr) {
+            Ok(ip) => vec![(ip, opt.port).into()],
+            Err(_) => {
+                let res = resolver.block_resolve(&opt.addr, opt.port)?;
+                res.collect()
+            }
+        };
+
+        Self::with_addrs(opt, tls_opt, addr)
+    }
+
+    /// Init with already resolved addresses of `opt.addr`.
+    pub fn with_addrs(
+        _opt: UdpClientOption,
+        tls_opt: Option<TlsClientOption>,
+        addr: Vec<SocketAddr>,
+    ) -> ClientResult<Self> {
+        if tls_opt.is_some() {
+            return Err(ClientError::Option(
+                "tls is not supported over udp".to_owned(),
+            ));
+        }
+        if addr.is_empty() {
+            return Err(ClientError::Option("unknown address".to_owned()));
+        }
+
+        Ok(Self { addr })
+    }
+
+    /// Udp has no connection to probe, any probe is rejected.
+    pub fn set_post_connect_probe(&mut self, probe: Option<ProbeMode>) -> ClientResult<()> {
+        if probe.is_some() {
+            return Err(ClientError::Option(
+                "post connect probes need a tcp or ws transport".to_owned(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl TransportClientTrait for UdpClient {
+    type Stream = UdpStream;
+
+    /// Bind a fresh socket and connect it to the first address, udp has no
+    /// handshake telling whether anyone listens.
+    async fn connect(&self) -> ClientResult<Self::Stream> {
+        let addr = self.addr[0];
+        let local: SocketAddr = match addr {
+            SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+            SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+        };
+        let socket = UdpSocket::bind(local).await?;
+        socket.connect(addr).await?;
+        Ok(UdpStream::new(socket))
+    }
+}
@@ -0,0 +1,289 @@
+//! Udp Transport
+//!
+//! Every write is one datagram and every read yields at most one, a read
+//! buffer smaller than the datagram gets the rest on the following reads.
+
+pub mod client;
+pub use client::UdpClient;
+
+pub mod server;
+pub use server::UdpServer;
+
+pub mod stream;
+pub use stream::{UdpPeerStream, UdpStream};
+
+pub mod option;
+pub use option::{UdpClientOption, UdpServerOption};
+
+use std::net::SocketAddr;
+
+/// Largest datagram received, the udp payload limit.
+pub const MAX_DATAGRAM_SIZE: usize = 65535;
+
+/// Largest payload of a datagram sent to `peer`, the udp length less the
+/// ip and udp headers. Writes beyond it fail with `InvalidInput`.
+pub fn max_payload(peer: Option<SocketAddr>) -> usize {
+    match peer {
+        Some(SocketAddr::V6(_)) => MAX_DATAGRAM_SIZE - 8,
+        _ => MAX_DATAGRAM_SIZE - 8 - 20,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::SocketAddr, time::Duration};
+
+    use tokio::{
+        io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+        sync::mpsc,
+    };
+
+    use crate::{
+        option::{ClientOption, ServerOption, CONFIG_VERSION},
+        Resolver, TlsCertOption, TlsServerOption, TransportClient, TransportClientOption,
+        TransportClientTrait, TransportServer, TransportServerCallback, TransportServerOption,
+        TransportServerTrait,
+    };
+
+    use super::*;
+
+    #[derive(Clone)]
+    struct EchoCallback;
+
+    impl TransportServerCallback for EchoCallback {
+        async fn handle<S>(&self, mut stream: S, _addr: Option<SocketAddr>)
+        where
+            S: AsyncRead + AsyncWrite + Unpin + Send + Sync,
+        {
+            let mut buf = vec![0; MAX_DATAGRAM_SIZE];
+            loop {
+                match stream.read(&mut buf).await {
+                    Ok(0) | Err(_) => break,
+                    Ok(n) => stream.write_all(&buf[..n]).await.unwrap(),
+                }
+            }
+        }
+    }
+
+    /// Reports each peer stream, then its datagrams and its eof.
+    #[derive(Clone)]
+    struct RecordCallback(mpsc::UnboundedSender<Option<Vec<u8>>>);
+
+    impl TransportServerCallback for RecordCallback {
+        async fn handle<S>(&self, mut stream: S, _addr: Option<SocketAddr>)
+        where
+            S: AsyncRead + AsyncWrite + Unpin + Send + Sync,
+        {
+            let mut buf = vec![0; MAX_DATAGRAM_SIZE];
+            loop {
+                let n = stream.read(&mut buf).await.unwrap();
+                let _ = self.0.send((n > 0).then(|| buf[..n].to_vec()));
+                if n == 0 {
+                    break;
+                }
+            }
+        }
+    }
+
+    fn server_option(port: u16, idle_timeout: Duration) -> TransportServerOption {
+        TransportServerOption {
+            opt: ServerOption::Udp(UdpServerOption {
+                listen: ([127, 0, 0, 1], port).into(),
+                idle_timeout,
+                peer_queue: 16,
+            }),
+            tls: None,
+            latency_profile: Default::default(),
+            policy: Default::default(),
+            version: CONFIG_VERSION,
+        }
+    }
+
+    fn client(port: u16) -> TransportClient {
+        let opt = TransportClientOption {
+            opt: ClientOption::Udp(UdpClientOption {
+                addr: "127.0.0.1".into(),
+                port,
+            }),
+            ..Default::default()
+        };
+        TransportClient::init(opt, &Resolver::default()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_udp_echo() {
+        let srv = TransportServer::init(server_option(9893, Duration::from_secs(60))).unwrap();
+        assert_eq!(srv.name(), "Udp");
+        tokio::spawn(async move { srv.serve(EchoCallback).await });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let cli = client(9893);
+        let mut a = cli.connect().await.unwrap();
+        let mut b = cli.connect().await.unwrap();
+        a.write_all(b"hello").await.unwrap();
+        b.write_all(b"datagram").await.unwrap();
+        // empty writes send nothing
+        assert_eq!(a.write(b"").await.unwrap(), 0);
+        a.write_all(b"world").await.unwrap();
+
+        // a short read keeps the rest of the datagram, reads never span two
+        let mut buf = [0; 3];
+        let n = a.read(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], b"hel");
+        let n = a.read(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], b"lo");
+        let n = a.read(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], b"wor");
+
+        let mut buf = [0; 64];
+        let n = b.read(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], b"datagram");
+        let n = a.read(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], b"ld");
+    }
+
+    async fn recv(rx: &mut mpsc::UnboundedReceiver<Option<Vec<u8>>>) -> Option<Vec<u8>> {
+        tokio::time::timeout(Duration::from_secs(2), rx.recv())
+            .await
+            .unwrap()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_udp_idle_peer() {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let srv = UdpServer::init(
+            UdpServerOption {
+                listen: ([127, 0, 0, 1], 9894).into(),
+                idle_timeout: Duration::from_millis(100),
+                peer_queue: 16,
+            },
+            None,
+        )
+        .unwrap();
+        tokio::spawn(async move { srv.serve(RecordCallback(tx)).await });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let mut stream = client(9894).connect().await.unwrap();
+        stream.write_all(b"one").await.unwrap();
+        assert_eq!(recv(&mut rx).await, Some(b"one".to_vec()));
+        stream.write_all(b"two").await.unwrap();
+        assert_eq!(recv(&mut rx).await, Some(b"two".to_vec()));
+
+        // the reaped peer reads eof, its next datagram opens a new stream
+        assert_eq!(recv(&mut rx).await, None);
+        stream.write_all(b"three").await.unwrap();
+        assert_eq!(recv(&mut rx).await, Some(b"three".to_vec()));
+    }
+
+    /// Sends ticks without reading, reports whether the peer got reaped meanwhile.
+    #[derive(Clone)]
+    struct TickCallback(mpsc::UnboundedSender<Option<Vec<u8>>>);
+
+    impl TransportServerCallback for TickCallback {
+        async fn handle<S>(&self, stream: S, _addr: Option<SocketAddr>)
+        where
+            S: AsyncRead + AsyncWrite + Unpin + Send + Sync,
+        {
+            let (mut rd, mut wr) = tokio::io::split(stream);
+            let ticks = async {
+                for _ in 0..10 {
+                    tokio::time::sleep(Duration::from_millis(30)).await;
+                    wr.write_all(b"tick").await.unwrap();
+                }
+            };
+            let mut buf = vec![0; MAX_DATAGRAM_SIZE];
+            let reaped = async { while rd.read(&mut buf).await.unwrap() > 0 {} };
+            let alive = tokio::select! {
+                _ = ticks => true,
+                _ = reaped => false,
+            };
+            let _ = self.0.send(alive.then(Vec::new));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_udp_send_keeps_peer() {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let srv = UdpServer::init(
+            UdpServerOption {
+                listen: ([127, 0, 0, 1], 9916).into(),
+                idle_timeout: Duration::from_millis(100),
+                peer_queue: 16,
+            },
+            None,
+        )
+        .unwrap();
+        tokio::spawn(async move { srv.serve(TickCallback(tx)).await });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        // the client writes once, then only reads the ticks
+        let mut stream = client(9916).connect().await.unwrap();
+        stream.write_all(b"start").await.unwrap();
+        let mut buf = [0; 16];
+        for _ in 0..10 {
+            let n = stream.read(&mut buf).await.unwrap();
+            assert_eq!(&buf[..n], b"tick");
+        }
+        assert_eq!(recv(&mut rx).await, Some(vec![]));
+
+        // writes the peer family can not carry fail before reaching the socket
+        let err = stream.write(&vec![0; 65_508]).await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+        assert!(err.to_string().contains("65507"), "{}", err);
+        assert_eq!(max_payload(Some("[::1]:53".parse().unwrap())), 65_527);
+    }
+
+    #[tokio::test]
+    async fn test_udp_zero_idle_timeout() {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let srv = UdpServer::init(
+            UdpServerOption {
+                listen: ([127, 0, 0, 1], 9917).into(),
+                idle_timeout: Duration::ZERO,
+                peer_queue: 16,
+            },
+            None,
+        )
+        .unwrap();
+        let serving = tokio::spawn(async move { srv.serve(RecordCallback(tx)).await });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        // every peer is forgotten at the next sweep, the queued datagram is still read
+        let mut stream = client(9917).connect().await.unwrap();
+        stream.write_all(b"one").await.unwrap();
+        assert_eq!(recv(&mut rx).await, Some(b"one".to_vec()));
+        assert_eq!(recv(&mut rx).await, None);
+        assert!(!serving.is_finished());
+    }
+
+    #[test]
+    fn test_udp_rejects_tls() {
+        let opt: TransportClientOption = serde_json::from_str(
+            r#"{"opt": {"udp": {"addr": "127.0.0.1", "port": 53}}, "tls": {}}"#,
+        )
+        .unwrap();
+        assert!(TransportClient::init(opt, &Resolver::default()).is_err());
+
+        let mut opt = server_option(53, Duration::from_secs(60));
+        opt.tls = Some(TlsServerOption {
+            alpn: vec![],
+            require_alpn: false,
+            ticket_keys: None,
+            certificate: TlsCertOption::Text {
+                certs: vec![],
+                key: String::new().into(),
+            },
+            client_ca: None,
+            require_client_cert: false,
+            session_tickets: false,
+            min_version: None,
+            max_version: None,
+            cipher_suites: vec![],
+        });
+        let Err(err) = TransportServer::init(opt) else {
+            panic!("tls accepted over udp")
+        };
+        assert!(err.to_string().contains("udp"));
+    }
+}
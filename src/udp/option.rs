@@ -0,0 +1,31 @@
+//! Transport Udp Option
+
+use std::{net::SocketAddr, time::Duration};
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UdpClientOption {
+    pub addr: String,
+    pub port: u16,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UdpServerOption {
+    pub listen: SocketAddr,
+    /// Forget a peer that sent and was sent nothing for this long, its
+    /// stream reads eof.
+    #[serde(default = "default_idle_timeout")]
+    pub idle_timeout: Duration,
+    /// Datagrams queued per peer ahead of its reader, further ones are dropped.
+    #[serde(default = "default_peer_queue")]
+    pub peer_queue: usize,
+}
+
+fn default_idle_timeout() -> Duration {
+    Duration::from_secs(60)
+}
+
+fn default_peer_queue() -> usize {
+    256
+}
@@ -0,0 +1,240 @@
+//! Transport Udp Server
+
+use std::{
+    collections::HashMap, future::Future, io::ErrorKind, net::SocketAddr, sync::Arc, time::Duration,
+};
+
+use bytes::Bytes;
+use tokio::{
+    net::UdpSocket,
+    sync::mpsc::{self, error::TrySendError},
+    time::Instant,
+};
+
+use crate::{
+    context::{AcceptHook, ConnContext},
+    limit::LimitedStream,
+    net::BoundAddr,
+    observe::{self, ServeObserver},
+    shutdown::{killable, Tasks, DEFAULT_KILL_GRACE},
+    stats::{ServerStats, ServerStatsSnapshot},
+    ServerError, ServerResult, TlsServerOption, TransportServerCallback, TransportServerTrait,
+};
+
+use super::{stream::LastSeen, UdpPeerStream, UdpServerOption, MAX_DATAGRAM_SIZE};
+
+/// Peers are swept at least this often, however long the idle timeout.
+const MAX_SWEEP_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Peers are swept at most this often, however short the idle timeout.
+const MIN_SWEEP_INTERVAL: Duration = Duration::from_millis(10);
+
+pub struct UdpServer {
+    local_addr: SocketAddr,
+    idle_timeout: Duration,
+    peer_queue: usize,
+    stats: Arc<ServerStats>,
+    accept_hooks: Vec<AcceptHook>,
+    bound_addr: BoundAddr,
+    kill_grace: Duration,
+    observer: Option<Arc<dyn ServeObserver>>,
+}
+
+/// What `serve` shares with the peers it accepts.
+struct Conn<'a, C> {
+    socket: &'a Arc<UdpSocket>,
+    local_addr: SocketAddr,
+    /// Start of `serve`, the base of the `LastSeen` timestamps.
+    started: Instant,
+    callback: &'a C,
+    tasks: Option<&'a Arc<Tasks>>,
+}
+
+struct Peer {
+    tx: mpsc::Sender<Bytes>,
+    last_seen: LastSeen,
+}
+
+impl UdpServer {
+    pub fn init(opt: UdpServerOption, tls_opt: Option<TlsServerOption>) -> ServerResult<Self> {
+        if tls_opt.is_some() {
+            return Err(ServerError::Option(
+                "tls is not supported over udp".to_owned(),
+            ));
+        }
+        Ok(Self {
+            local_addr: opt.listen,
+            idle_timeout: opt.idle_timeout,
+            peer_queue: opt.peer_queue.max(1),
+            stats: Arc::new(ServerStats::new(opt.listen)),
+            accept_hooks: vec![],
+            bound_addr: BoundAddr::default(),
+            kill_grace: DEFAULT_KILL_GRACE,
+            observer: None,
+        })
+    }
+
+    /// Time callbacks get to return once `serve_with_shutdown` invalidated
+    /// their stream at the end of the grace period, 1s by default.
+    pub fn set_kill_grace(&mut self, kill_grace: Duration) {
+        self.kill_grace = kill_grace;
+    }
+
+    /// Report accepts, finished callbacks and shutdown exits to `observer`.
+    pub fn set_serve_observer(&mut self, observer: Arc<dyn ServeObserver>) {
+        self.observer = Some(observer);
+    }
+
+    /// Run `hook` on the first datagram of every new peer.
+    pub fn add_accept_hook(&mut self, hook: AcceptHook) {
+        self.accept_hooks.push(hook);
+    }
+
+    pub fn stats(&self) -> ServerStatsSnapshot {
+        self.stats.snapshot()
+    }
+}
+
+impl TransportServerTrait for UdpServer {
+    fn local_addr(&self) -> Option<SocketAddr> {
+        Some(self.bound_addr.get_or(self.local_addr))
+    }
+
+    async fn serve<C: TransportServerCallback>(&self, callback: C) -> ServerResult<()> {
+        self.serve_tracked(callback, None).await
+    }
+
+    /// Peer streams read eof once the signal fired.
+    async fn serve_with_shutdown<C, F>(
+        &self,
+        callback: C,
+        signal: F,
+        grace: Duration,
+    ) -> ServerResult<()>
+    where
+        C: TransportServerCallback,
+        F: Future<Output = ()> + Send + Sync,
+    {
+        let tasks = Tasks::new();
+        tokio::select! {
+            res = self.serve_tracked(callback, Some(&tasks)) => return res,
+            _ = signal => {}
+        }
+        tasks
+            .shutdown(grace, self.kill_grace)
+            .await
+            .finish("udp", self.observer.as_deref());
+        Ok(())
+    }
+}
+
+impl UdpServer {
+    async fn serve_tracked<C: TransportServerCallback>(
+        &self,
+        callback: C,
+        tasks: Option<&Arc<Tasks>>,
+    ) -> ServerResult<()> {
+        let socket = Arc::new(UdpSocket::bind(self.local_addr).await?);
+        let local_addr = socket.local_addr()?;
+        self.bound_addr.set(local_addr);
+        let conn = Conn {
+            socket: &socket,
+            local_addr,
+            started: Instant::now(),
+            callback: &callback,
+            tasks,
+        };
+        let mut peers: HashMap<SocketAddr, Peer> = HashMap::new();
+        let mut sweep = tokio::time::interval(
+            self.idle_timeout
+                .clamp(MIN_SWEEP_INTERVAL, MAX_SWEEP_INTERVAL),
+        );
+        let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+
+        loop {
+            let (n, addr) = tokio::select! {
+                res = socket.recv_from(&mut buf) => match res {
+                    Ok(res) => res,
+                    // an icmp error of an earlier send, not fatal to the socket
+                    Err(e) if e.kind() == ErrorKind::ConnectionReset => continue,
+                    Err(e) => return Err(e.into()),
+                },
+                _ = sweep.tick() => {
+                    // dropping the sender ends the peer stream
+                    peers.retain(|_, peer| {
+                        peer.last_seen.elapsed() < self.idle_timeout && !peer.tx.is_closed()
+                    });
+                    continue;
+                }
+            };
+            if n == 0 {
+                continue;
+            }
+            let datagram = Bytes::copy_from_slice(&buf[..n]);
+
+            if let Some(peer) = peers.get_mut(&addr) {
+                match peer.tx.try_send(datagram) {
+                    Ok(()) => {
+                        peer.last_seen.touch();
+                        continue;
+                    }
+                    Err(TrySendError::Full(_)) => {
+                        log::debug!("udp peer {} queue full, datagram dropped", addr);
+                        continue;
+                    }
+                    // the callback is done with the old stream, start a new one
+                    Err(TrySendError::Closed(datagram)) => {
+                        peers.remove(&addr);
+                        self.accept(&conn, addr, datagram, &mut peers);
+                    }
+                }
+            } else {
+                self.accept(&conn, addr, datagram, &mut peers);
+            }
+        }
+    }
+
+    fn accept<C: TransportServerCallback>(
+        &self,
+        conn: &Conn<'_, C>,
+        addr: SocketAddr,
+        datagram: Bytes,
+        peers: &mut HashMap<SocketAddr, Peer>,
+    ) {
+        let mut ctx = ConnContext::new(Some(addr), Some(conn.local_addr)).with_transport("udp");
+        observe::accepted(self.observer.as_deref(), &ctx, true);
+        if !ctx.run_hooks(&self.accept_hooks) {
+            log::debug!("udp peer {} rejected by accept hook", addr);
+            return;
+        }
+
+        let (tx, rx) = mpsc::channel(self.peer_queue);
+        let _ = tx.try_send(datagram);
+        let last_seen = LastSeen::new(conn.started);
+        peers.insert(
+            addr,
+            Peer {
+                tx,
+                last_seen: last_seen.clone(),
+            },
+        );
+
+        let stream = UdpPeerStream::new(conn.socket.clone(), addr, rx, last_seen);
+        let stream = LimitedStream::new(self.stats.track(stream, &addr), None);
+        ctx.extensions.insert(stream.count());
+        let callback = conn.callback.clone();
+        let observer = self.observer.clone();
+        let id = ctx.id;
+        let guard = conn.tasks.map(|t| t.register(id));
+        let handle = tokio::spawn(async move {
+            let stream = killable(stream, guard.as_ref());
+            observe::callback(observer.as_deref(), ctx, true, |ctx| {
+                callback.handle_ctx(stream, ctx)
+            })
+            .await
+        });
+        if let Some(tasks) = conn.tasks {
+            tasks.set_abort(id, handle.abort_handle());
+        }
+    }
+}
@@ -0,0 +1,229 @@
+//! Udp Transport Streams
+
+use std::{
+    io::{Error, ErrorKind},
+    net::SocketAddr,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+};
+
+use bytes::{Buf, Bytes};
+use futures_util::ready;
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::UdpSocket,
+    sync::mpsc,
+    time::Instant,
+};
+
+use super::{max_payload, MAX_DATAGRAM_SIZE};
+
+/// Fail writes the peer family can not send as one datagram.
+fn check_size(len: usize, peer: Option<SocketAddr>) -> std::io::Result<()> {
+    let max = max_payload(peer);
+    if len > max {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!(
+                "datagram of {} bytes exceeds the udp payload limit of {}",
+                len, max
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// Last datagram received from or sent to a server peer, in milliseconds
+/// since `serve` started.
+#[derive(Debug, Clone)]
+pub(crate) struct LastSeen {
+    base: Instant,
+    millis: Arc<AtomicU64>,
+}
+
+impl LastSeen {
+    pub(crate) fn new(base: Instant) -> Self {
+        let seen = Self {
+            base,
+            millis: Arc::default(),
+        };
+        seen.touch();
+        seen
+    }
+
+    pub(crate) fn touch(&self) {
+        let now = self.base.elapsed().as_millis() as u64;
+        self.millis.fetch_max(now, Ordering::Relaxed);
+    }
+
+    pub(crate) fn elapsed(&self) -> std::time::Duration {
+        let last = std::time::Duration::from_millis(self.millis.load(Ordering::Relaxed));
+        self.base.elapsed().saturating_sub(last)
+    }
+}
+
+/// Hand out the rest of the current datagram, `false` if it is used up.
+fn read_chunk(chunk: &mut Bytes, buf: &mut ReadBuf<'_>) -> bool {
+    if !chunk.has_remaining() {
+        return false;
+    }
+    let n = chunk.len().min(buf.remaining());
+    buf.put_slice(&chunk[..n]);
+    chunk.advance(n);
+    true
+}
+
+/// A connected udp socket, client side.
+#[derive(Debug)]
+pub struct UdpStream {
+    socket: UdpSocket,
+    peer: Option<SocketAddr>,
+    recv_buf: Box<[u8]>,
+    chunk: Bytes,
+}
+
+impl UdpStream {
+    pub fn new(socket: UdpSocket) -> Self {
+        Self {
+            peer: socket.peer_addr().ok(),
+            socket,
+            recv_buf: vec![0; MAX_DATAGRAM_SIZE].into_boxed_slice(),
+            chunk: Bytes::new(),
+        }
+    }
+
+    pub fn get_ref(&self) -> &UdpSocket {
+        &self.socket
+    }
+}
+
+impl AsyncRead for UdpStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        if buf.remaining() == 0 || read_chunk(&mut this.chunk, buf) {
+            return Poll::Ready(Ok(()));
+        }
+
+        // an empty datagram would read as eof, skip it
+        loop {
+            let mut recv = ReadBuf::new(&mut this.recv_buf);
+            ready!(this.socket.poll_recv(cx, &mut recv))?;
+            let datagram = recv.filled();
+            if datagram.is_empty() {
+                continue;
+            }
+            let n = datagram.len().min(buf.remaining());
+            buf.put_slice(&datagram[..n]);
+            this.chunk = Bytes::copy_from_slice(&datagram[n..]);
+            return Poll::Ready(Ok(()));
+        }
+    }
+}
+
+impl AsyncWrite for UdpStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        check_size(buf.len(), self.peer)?;
+        self.socket.poll_send(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// One peer of a `UdpServer`, sharing the server socket.
+///
+/// Reads eof once the server forgot the peer after its idle timeout,
+/// sending to the peer counts as activity like receiving from it.
+#[derive(Debug)]
+pub struct UdpPeerStream {
+    socket: Arc<UdpSocket>,
+    peer: SocketAddr,
+    rx: mpsc::Receiver<Bytes>,
+    chunk: Bytes,
+    last_seen: LastSeen,
+}
+
+impl UdpPeerStream {
+    pub(crate) fn new(
+        socket: Arc<UdpSocket>,
+        peer: SocketAddr,
+        rx: mpsc::Receiver<Bytes>,
+        last_seen: LastSeen,
+    ) -> Self {
+        Self {
+            socket,
+            peer,
+            rx,
+            chunk: Bytes::new(),
+            last_seen,
+        }
+    }
+
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.peer
+    }
+}
+
+impl AsyncRead for UdpPeerStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        if buf.remaining() == 0 || read_chunk(&mut this.chunk, buf) {
+            return Poll::Ready(Ok(()));
+        }
+
+        // the server never queues empty datagrams
+        if let Some(datagram) = ready!(this.rx.poll_recv(cx)) {
+            this.chunk = datagram;
+            read_chunk(&mut this.chunk, buf);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for UdpPeerStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        check_size(buf.len(), Some(self.peer))?;
+        let n = ready!(self.socket.poll_send_to(cx, buf, self.peer))?;
+        self.last_seen.touch();
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
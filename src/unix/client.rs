@@ -0,0 +1,57 @@
+//! Unix Transport client
+
+use std::path::{Path, PathBuf};
+
+use rustls::pki_types::ServerName;
+use tokio::net::UnixStream as TokioUnixStream;
+use tokio_rustls::{TlsConnector, TlsStream};
+
+use crate::{ClientError, ClientResult, TlsClientOption, TransportClientTrait};
+
+use super::{UnixClientOption, UnixStream};
+
+pub struct UnixClient {
+    path: PathBuf,
+    tls_conn: Option<(TlsConnector, ServerName<'static>)>,
+}
+
+impl UnixClient {
+    /// Tls needs the `server_name` of the tls option, a path names no host.
+    pub fn init(opt: UnixClientOption, tls_opt: Option<TlsClientOption>) -> ClientResult<Self> {
+        let tls_conn = if let Some(ref tls_opt) = tls_opt {
+            if tls_opt.server_name.is_empty() {
+                return Err(ClientError::Option(
+                    "tls over a unix socket needs a server_name".to_owned(),
+                ));
+            }
+            let server_name = ServerName::try_from(tls_opt.server_name.clone())
+                .map_err(|e| ClientError::Option(e.to_string()))?;
+            let conn = TlsConnector::from(tls_opt.client_config()?);
+            Some((conn, server_name))
+        } else {
+            None
+        };
+
+        Ok(Self {
+            path: opt.path,
+            tls_conn,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl TransportClientTrait for UnixClient {
+    type Stream = UnixStream;
+
+    async fn connect(&self) -> ClientResult<Self::Stream> {
+        let stream = TokioUnixStream::connect(&self.path).await?;
+        let Some((ref tls_conn, ref server_name)) = self.tls_conn else {
+            return Ok(UnixStream::Raw(stream));
+        };
+        let stream = tls_conn.connect(server_name.clone(), stream).await?;
+        Ok(UnixStream::Tls(TlsStream::Client(stream)))
+    }
+}
@@ -0,0 +1,236 @@
+//! Unix Domain Socket Transport
+//!
+//! Stream sockets at a filesystem path, plaintext or tls over the same
+//! options as tcp. Unix only.
+
+pub mod client;
+pub use client::UnixClient;
+
+pub mod server;
+pub use server::UnixServer;
+
+pub mod stream;
+pub use stream::UnixStream;
+
+pub mod option;
+pub use option::{UnixClientOption, UnixServerOption};
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::SocketAddr,
+        path::PathBuf,
+        sync::{Arc, Mutex},
+    };
+
+    use serde_json::json;
+    use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+    use super::*;
+    use crate::{
+        context::{AlpnProtocol, ConnContext, ServerName},
+        ServerError, TransportClient, TransportClientOption, TransportClientTrait, TransportServer,
+        TransportServerCallback, TransportServerOption, TransportServerTrait,
+    };
+
+    fn socket_path(name: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("kapibara-{}-{}.sock", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    /// Echoes and keeps the sni and alpn of the last connection.
+    #[derive(Clone, Default)]
+    struct Echo(Arc<Mutex<Option<(ServerName, AlpnProtocol)>>>);
+
+    impl TransportServerCallback for Echo {
+        async fn handle<S>(&self, _stream: S, _addr: Option<SocketAddr>)
+        where
+            S: AsyncRead + AsyncWrite + Unpin + Send + Sync,
+        {
+            unreachable!()
+        }
+
+        async fn handle_ctx<S>(&self, mut stream: S, ctx: ConnContext)
+        where
+            S: AsyncRead + AsyncWrite + Unpin + Send + Sync,
+        {
+            *self.0.lock().unwrap() = ctx
+                .extensions
+                .get::<ServerName>()
+                .cloned()
+                .zip(ctx.extensions.get::<AlpnProtocol>().cloned());
+            let mut buf = [0; 64];
+            while let Ok(n @ 1..) = stream.read(&mut buf).await {
+                if stream.write_all(&buf[..n]).await.is_err() {
+                    break;
+                }
+            }
+        }
+    }
+
+    async fn ping<S: AsyncRead + AsyncWrite + Unpin>(s: &mut S) {
+        s.write_all(b"ping").await.unwrap();
+        let mut buf = [0u8; 4];
+        s.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+    }
+
+    #[tokio::test]
+    async fn test_unix_echo() {
+        let path = socket_path("echo");
+        let srv: TransportServerOption = serde_json::from_value(json!({
+            "opt": {"unix": {"path": path}},
+        }))
+        .unwrap();
+        let srv = Arc::new(TransportServer::init(srv).unwrap());
+        assert_eq!(srv.local_addr(), None);
+        let TransportServer::Unix(ref unix) = *srv else {
+            panic!("{}", srv.name());
+        };
+        assert_eq!(unix.path(), path);
+        // bound by init, connects wait for serve in the backlog
+        assert!(path.exists());
+
+        let cli: TransportClientOption = serde_json::from_value(json!({
+            "opt": {"unix": {"path": path}},
+        }))
+        .unwrap();
+        let cli = TransportClient::init_with_default_resolver(cli).unwrap();
+        let mut early = cli.connect().await.unwrap();
+        assert!(matches!(early, crate::TransportClientStream::Unix(_)));
+        assert!(early.alpn_protocol().is_none());
+
+        let serving = tokio::spawn({
+            let srv = srv.clone();
+            async move { srv.serve(Echo::default()).await }
+        });
+        ping(&mut early).await;
+        ping(&mut cli.connect().await.unwrap()).await;
+        assert_eq!(srv.stats().ipv4.accepted, 2);
+
+        serving.abort();
+        let _ = serving.await;
+        drop(srv);
+        assert!(!path.exists());
+    }
+
+    #[tokio::test]
+    async fn test_unix_in_use() {
+        let path = socket_path("in-use");
+        let opt = UnixServerOption {
+            path: path.clone(),
+            unlink_on_bind: false,
+        };
+        let srv = UnixServer::init(opt.clone(), None).unwrap();
+        let err = UnixServer::init(opt.clone(), None).err().unwrap();
+        assert!(matches!(err, ServerError::Option(_)), "{}", err);
+        assert!(err.to_string().contains("is in use"), "{}", err);
+
+        // a live socket is not stale
+        let unlink = UnixServerOption {
+            unlink_on_bind: true,
+            ..opt.clone()
+        };
+        let err = UnixServer::init(unlink.clone(), None).err().unwrap();
+        assert!(err.to_string().contains("is in use"), "{}", err);
+
+        // left behind by a listener that is gone
+        drop(srv);
+        assert!(!path.exists());
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+        let err = UnixServer::init(opt.clone(), None).err().unwrap();
+        assert!(err.to_string().contains("unlink_on_bind"), "{}", err);
+        let srv = UnixServer::init(unlink, None).unwrap();
+        assert!(
+            UnixClient::init(UnixClientOption { path: path.clone() }, None)
+                .unwrap()
+                .connect()
+                .await
+                .is_ok()
+        );
+        drop(srv);
+
+        // never removes what is not a socket
+        std::fs::write(&path, b"").unwrap();
+        let err = UnixServer::init(
+            UnixServerOption {
+                path: path.clone(),
+                unlink_on_bind: true,
+            },
+            None,
+        )
+        .err()
+        .unwrap();
+        assert!(err.to_string().contains("not a unix socket"), "{}", err);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(feature = "insecure-tls")]
+    #[tokio::test]
+    async fn test_unix_tls() {
+        use crate::{TlsCertOption, TlsClientOption, TlsServerOption};
+
+        let path = socket_path("tls");
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+        let tls = TlsServerOption {
+            alpn: vec!["kapibara".into()],
+            require_alpn: false,
+            ticket_keys: None,
+            certificate: TlsCertOption::Text {
+                certs: vec![cert.cert.pem()],
+                key: cert.key_pair.serialize_pem().into(),
+            },
+            client_ca: None,
+            require_client_cert: false,
+            session_tickets: false,
+            min_version: None,
+            max_version: None,
+            cipher_suites: vec![],
+        };
+        let srv = UnixServer::init(
+            UnixServerOption {
+                path: path.clone(),
+                unlink_on_bind: false,
+            },
+            Some(tls),
+        )
+        .unwrap();
+        let echo = Echo::default();
+        let serving = tokio::spawn({
+            let echo = echo.clone();
+            async move { srv.serve(echo).await }
+        });
+
+        let opt = UnixClientOption { path };
+        let tls_opt = TlsClientOption {
+            insecure: true,
+            alpn: vec!["kapibara".into()],
+            ..Default::default()
+        };
+        let err = UnixClient::init(opt.clone(), Some(tls_opt.clone()))
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("server_name"), "{}", err);
+
+        let tls_opt = TlsClientOption {
+            server_name: "localhost".into(),
+            ..tls_opt
+        };
+        let cli = TransportClient::init_with_default_resolver(TransportClientOption {
+            opt: crate::option::ClientOption::Unix(opt),
+            tls: Some(tls_opt),
+            ..Default::default()
+        })
+        .unwrap();
+        let mut s = cli.connect().await.unwrap();
+        assert_eq!(s.alpn_protocol(), Some(&b"kapibara"[..]));
+        ping(&mut s).await;
+
+        let (sni, alpn) = echo.0.lock().unwrap().clone().unwrap();
+        assert_eq!(sni.0, "localhost");
+        assert_eq!(alpn.0, b"kapibara");
+        serving.abort();
+    }
+}
@@ -0,0 +1,20 @@
+//! Transport Unix Option
+
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UnixClientOption {
+    /// Socket the server listens on.
+    pub path: PathBuf,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UnixServerOption {
+    pub path: PathBuf,
+    /// Remove a socket file left behind by a server that is gone before
+    /// binding. A socket still accepting connections is never removed.
+    #[serde(default)]
+    pub unlink_on_bind: bool,
+}
@@ -0,0 +1,299 @@
+//! Transport Unix Server
+
+use std::{
+    future::Future,
+    io::ErrorKind,
+    net::{Ipv4Addr, SocketAddr},
+    os::unix::{
+        fs::{FileTypeExt, MetadataExt},
+        net::{UnixListener as StdUnixListener, UnixStream as StdUnixStream},
+    },
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use tokio::net::{UnixListener, UnixStream as TokioUnixStream};
+use tokio_rustls::{TlsAcceptor, TlsStream};
+
+use crate::{
+    context::{AcceptHook, AlpnProtocol, ConnContext, Security, ServerName},
+    limit::LimitedStream,
+    observe::{self, ServeObserver},
+    shutdown::{killable, Tasks, DEFAULT_KILL_GRACE},
+    stats::{ServerStats, ServerStatsSnapshot},
+    tls::{ExportRequest, TlsExporter},
+    ServerError, ServerResult, TlsServerOption, TransportServerCallback, TransportServerTrait,
+};
+
+use super::{UnixServerOption, UnixStream};
+
+/// Peer address counted in the stats, unix peers have none.
+const UNIX_PEER: SocketAddr = SocketAddr::new(std::net::IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0);
+
+/// Serves the connections of a unix socket.
+///
+/// The socket is bound by `init` and its file removed when the server
+/// drops, unless another server took the path in between.
+pub struct UnixServer {
+    path: PathBuf,
+    listener: StdUnixListener,
+    /// Device and inode of the bound socket file.
+    inode: (u64, u64),
+    tls_acceptor: Option<TlsAcceptor>,
+    stats: Arc<ServerStats>,
+    accept_hooks: Vec<AcceptHook>,
+    exports: Vec<ExportRequest>,
+    kill_grace: Duration,
+    observer: Option<Arc<dyn ServeObserver>>,
+}
+
+impl UnixServer {
+    pub fn init(opt: UnixServerOption, tls_opt: Option<TlsServerOption>) -> ServerResult<Self> {
+        let tls_acceptor = match tls_opt {
+            Some(tls_opt) => Some(TlsAcceptor::from(Arc::new(tls_opt.build()?.0))),
+            None => None,
+        };
+
+        if opt.unlink_on_bind {
+            unlink_stale(&opt.path)?;
+        }
+        let listener = match StdUnixListener::bind(&opt.path) {
+            Ok(listener) => listener,
+            Err(e) if e.kind() == ErrorKind::AddrInUse => {
+                return Err(in_use(&opt.path, opt.unlink_on_bind))
+            }
+            Err(e) => return Err(e.into()),
+        };
+        listener.set_nonblocking(true)?;
+        let meta = std::fs::symlink_metadata(&opt.path)?;
+
+        Ok(Self {
+            path: opt.path,
+            listener,
+            inode: (meta.dev(), meta.ino()),
+            tls_acceptor,
+            stats: Arc::new(ServerStats::new(UNIX_PEER)),
+            accept_hooks: vec![],
+            exports: vec![],
+            kill_grace: DEFAULT_KILL_GRACE,
+            observer: None,
+        })
+    }
+
+    /// Path of the bound socket.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Time callbacks get to return once `serve_with_shutdown` invalidated
+    /// their stream at the end of the grace period, 1s by default.
+    pub fn set_kill_grace(&mut self, kill_grace: Duration) {
+        self.kill_grace = kill_grace;
+    }
+
+    /// Report accepts, failed handshakes, finished callbacks and shutdown
+    /// exits to `observer`.
+    pub fn set_serve_observer(&mut self, observer: Arc<dyn ServeObserver>) {
+        self.observer = Some(observer);
+    }
+
+    /// Run `hook` on every accepted connection after the tls handshake.
+    pub fn add_accept_hook(&mut self, hook: AcceptHook) {
+        self.accept_hooks.push(hook);
+    }
+
+    /// Export `request` from every tls session right after the handshake,
+    /// as `TcpServer::add_keying_material_export`.
+    pub fn add_keying_material_export(&mut self, request: ExportRequest) {
+        self.exports.push(request);
+    }
+
+    pub fn stats(&self) -> ServerStatsSnapshot {
+        self.stats.snapshot()
+    }
+
+    async fn serve_tracked<C>(&self, callback: C, tasks: Option<&Arc<Tasks>>) -> ServerResult<()>
+    where
+        C: TransportServerCallback,
+    {
+        // the socket stays bound between serves
+        let listener = UnixListener::from_std(self.listener.try_clone()?)?;
+        let accept_hooks: Arc<[AcceptHook]> = self.accept_hooks.clone().into();
+        let exports: Arc<[ExportRequest]> = self.exports.clone().into();
+        loop {
+            let s = match listener.accept().await {
+                Ok((s, _)) => s,
+                Err(err) => {
+                    let err: ServerError = err.into();
+                    if err.is_closed() {
+                        return Err(err);
+                    }
+
+                    log::error!("unix server error: {}", err);
+                    continue;
+                }
+            };
+
+            let ctx = ConnContext::new(None, None).with_transport("unix");
+            observe::accepted(self.observer.as_deref(), &ctx, true);
+            let id = ctx.id;
+            let guard = tasks.map(|t| t.register(id));
+            let tls_acceptor = self.tls_acceptor.clone();
+            let accept_hooks = accept_hooks.clone();
+            let exports = exports.clone();
+            let stats = self.stats.clone();
+            let observer = self.observer.clone();
+            let callback = callback.clone();
+            let handle = tokio::spawn(async move {
+                let Some((stream, mut ctx)) = establish(
+                    s,
+                    ctx,
+                    tls_acceptor,
+                    &accept_hooks,
+                    &exports,
+                    observer.as_deref(),
+                )
+                .await
+                else {
+                    return;
+                };
+                let stream = LimitedStream::new(stats.track(stream, &UNIX_PEER), None);
+                ctx.extensions.insert(stream.count());
+                let stream = killable(stream, guard.as_ref());
+                observe::callback(observer.as_deref(), ctx, true, |ctx| {
+                    callback.handle_ctx(stream, ctx)
+                })
+                .await
+            });
+            if let Some(tasks) = tasks {
+                tasks.set_abort(id, handle.abort_handle());
+            }
+        }
+    }
+}
+
+/// Run the tls handshake and the accept hooks, `None` drops the connection.
+/// A failed handshake is told to `observer`.
+async fn establish(
+    s: TokioUnixStream,
+    mut ctx: ConnContext,
+    tls_acceptor: Option<TlsAcceptor>,
+    accept_hooks: &[AcceptHook],
+    exports: &[ExportRequest],
+    observer: Option<&dyn ServeObserver>,
+) -> Option<(UnixStream, ConnContext)> {
+    let s = match tls_acceptor {
+        Some(acceptor) => match acceptor.accept(s).await {
+            Ok(s) => UnixStream::Tls(TlsStream::Server(s)),
+            Err(e) => {
+                log::warn!("tls handshake over unix socket failed {}", e);
+                observe::handshake_failed(observer, None, &e, ctx.accepted_at, true);
+                return None;
+            }
+        },
+        None => UnixStream::Raw(s),
+    };
+
+    if s.is_tls() {
+        ctx.handshake = Some(ctx.accepted_at.elapsed());
+        if let Some(name) = s.server_name() {
+            ctx.extensions.insert(ServerName(name.to_owned()));
+        }
+        if let Some(proto) = s.alpn_protocol() {
+            ctx.extensions.insert(AlpnProtocol(proto.to_vec()));
+        }
+        let exported = TlsExporter::capture(exports, |label, context, len| {
+            s.export_keying_material(label, context, len)
+        });
+        match exported {
+            Ok(exporter) => ctx.extensions.insert(exporter),
+            Err(e) => {
+                log::warn!("keying material export over unix socket failed {}", e);
+                return None;
+            }
+        };
+        ctx.extensions.insert(Security::Tls);
+    } else {
+        ctx.extensions.insert(Security::Plain);
+    }
+
+    if !ctx.run_hooks(accept_hooks) {
+        log::debug!("unix connection rejected by accept hook");
+        return None;
+    }
+    Some((s, ctx))
+}
+
+fn in_use(path: &Path, unlink_on_bind: bool) -> ServerError {
+    let hint = if unlink_on_bind {
+        ""
+    } else {
+        ", unlink_on_bind replaces a stale socket"
+    };
+    ServerError::Option(format!("unix socket {} is in use{}", path.display(), hint))
+}
+
+/// Remove the socket file at `path` unless a server still accepts on it.
+fn unlink_stale(path: &Path) -> ServerResult<()> {
+    let Ok(meta) = std::fs::symlink_metadata(path) else {
+        return Ok(());
+    };
+    if !meta.file_type().is_socket() {
+        return Err(ServerError::Option(format!(
+            "{} exists and is not a unix socket",
+            path.display()
+        )));
+    }
+    if StdUnixStream::connect(path).is_ok() {
+        return Err(in_use(path, true));
+    }
+    match std::fs::remove_file(path) {
+        Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+impl Drop for UnixServer {
+    fn drop(&mut self) {
+        // a later server may have unlinked the file and bound the path again
+        if std::fs::symlink_metadata(&self.path).is_ok_and(|m| (m.dev(), m.ino()) == self.inode) {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+}
+
+impl TransportServerTrait for UnixServer {
+    /// Always `None`, the socket has a path instead, see `UnixServer::path`.
+    fn local_addr(&self) -> Option<SocketAddr> {
+        None
+    }
+
+    async fn serve<C: TransportServerCallback>(&self, callback: C) -> ServerResult<()> {
+        self.serve_tracked(callback, None).await
+    }
+
+    /// The socket stays bound, connects after the signal wait for the next
+    /// `serve`.
+    async fn serve_with_shutdown<C, F>(
+        &self,
+        callback: C,
+        signal: F,
+        grace: Duration,
+    ) -> ServerResult<()>
+    where
+        C: TransportServerCallback,
+        F: Future<Output = ()> + Send + Sync,
+    {
+        let tasks = Tasks::new();
+        tokio::select! {
+            res = self.serve_tracked(callback, Some(&tasks)) => return res,
+            _ = signal => {}
+        }
+        tasks
+            .shutdown(grace, self.kill_grace)
+            .await
+            .finish("unix", self.observer.as_deref());
+        Ok(())
+    }
+}
@@ -0,0 +1,91 @@
+//! Transport Unix Stream
+
+use std::path::Path;
+
+use tokio::net::UnixStream as TokioUnixStream;
+use tokio_rustls::TlsStream;
+
+use crate::{stream_traits_enum, TlsError};
+
+stream_traits_enum! {
+    // one per connection and moved rarely, boxing the tls state would add a
+    // pointer chase to every read
+    #[allow(clippy::large_enum_variant)]
+    pub enum UnixStream {
+        Raw(TokioUnixStream),
+        Tls(TlsStream<TokioUnixStream>),
+    }
+}
+
+impl std::fmt::Debug for UnixStream {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let local = self.socket().local_addr().ok();
+        let mut d = f.debug_struct("UnixStream");
+        d.field("local", &local.as_ref().and_then(|a| a.as_pathname()))
+            .field("tls", &self.is_tls());
+        if let Some(name) = self.server_name() {
+            d.field("server_name", &name);
+        }
+        d.finish()
+    }
+}
+
+impl UnixStream {
+    pub fn is_tls(&self) -> bool {
+        matches!(self, Self::Tls(_))
+    }
+
+    /// The socket under the tls session, if any.
+    pub fn socket(&self) -> &TokioUnixStream {
+        match self {
+            Self::Raw(s) => s,
+            Self::Tls(s) => s.get_ref().0,
+        }
+    }
+
+    /// Path of the socket the client connected to, `None` on the accepting
+    /// side whose peer is unnamed.
+    pub fn peer_path(&self) -> Option<Box<Path>> {
+        let addr = self.socket().peer_addr().ok()?;
+        addr.as_pathname().map(Into::into)
+    }
+
+    /// Server name indicated by the client, only known on the accepting side.
+    pub fn server_name(&self) -> Option<&str> {
+        match self {
+            Self::Tls(TlsStream::Server(s)) => s.get_ref().1.server_name(),
+            _ => None,
+        }
+    }
+
+    /// Negotiated alpn protocol.
+    pub fn alpn_protocol(&self) -> Option<&[u8]> {
+        match self {
+            Self::Raw(_) => None,
+            Self::Tls(s) => s.get_ref().1.alpn_protocol(),
+        }
+    }
+
+    /// Export keying material (RFC 5705) from the established tls session.
+    pub fn export_keying_material(
+        &self,
+        label: &[u8],
+        context: Option<&[u8]>,
+        output_len: usize,
+    ) -> Result<Vec<u8>, TlsError> {
+        let output = vec![0u8; output_len];
+        let output = match self {
+            Self::Raw(_) => return Err(TlsError::NotTls),
+            Self::Tls(TlsStream::Client(s)) => s
+                .get_ref()
+                .1
+                .export_keying_material(output, label, context)?,
+            Self::Tls(TlsStream::Server(s)) => s
+                .get_ref()
+                .1
+                .export_keying_material(output, label, context)?,
+        };
+
+        Ok(output)
+    }
+}
@@ -0,0 +1,550 @@
+//! Option Validation
+//!
+//! Checks for options that can not work, a typo caught when the option is
+//! loaded instead of on the first connection. `TransportClient::init` and
+//! `TransportServer::init` fail on any issue, `validate()` lists all of
+//! them for frontends checking a whole config.
+
+use std::path::Path;
+
+use serde::Serialize;
+
+#[cfg(any(test, feature = "test-util"))]
+use crate::memory::{MemoryClientOption, MemoryServerOption};
+#[cfg(unix)]
+use crate::unix::{UnixClientOption, UnixServerOption};
+use crate::{
+    grpc::{GrpcClientOption, GrpcServerOption},
+    http2::{H2ClientOption, H2ServerOption},
+    io::RateLimitOption,
+    net::CircuitBreakerOption,
+    option::{ClientOption, ServerOption},
+    tcp::{TcpClientOption, TcpServerOption},
+    udp::{UdpClientOption, UdpServerOption},
+    websocket::{WebSocketClientOption, WebSocketServerOption},
+    ClientError, ServerError, TlsCertOption, TlsClientOption, TlsServerOption,
+    TransportClientOption, TransportServerOption,
+};
+
+/// A field of an option that can not work.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct OptionIssue {
+    /// Dotted path of the field, like `opt.path` or `tls.alpn[1]`.
+    pub field: String,
+    pub reason: String,
+}
+
+impl std::fmt::Display for OptionIssue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.field, self.reason)
+    }
+}
+
+#[derive(Default)]
+struct Issues {
+    prefix: &'static str,
+    list: Vec<OptionIssue>,
+}
+
+impl Issues {
+    fn new(prefix: &'static str) -> Self {
+        Self {
+            prefix,
+            list: vec![],
+        }
+    }
+
+    fn push(&mut self, field: impl std::fmt::Display, reason: impl Into<String>) {
+        self.list.push(OptionIssue {
+            field: format!("{}{}", self.prefix, field),
+            reason: reason.into(),
+        });
+    }
+
+    fn addr(&mut self, addr: &str, port: u16) {
+        if addr.trim().is_empty() {
+            self.push("addr", "is empty");
+        }
+        if port == 0 {
+            self.push("port", "is 0");
+        }
+    }
+
+    fn path(&mut self, field: impl std::fmt::Display, path: &str) {
+        if path.is_empty() {
+            self.push(field, "is empty");
+        } else if !path.starts_with('/') {
+            self.push(field, format!("{:?} does not start with /", path));
+        }
+    }
+
+    fn alpn(&mut self, alpn: &[String]) {
+        for (i, proto) in alpn.iter().enumerate() {
+            if proto.is_empty() {
+                self.push(format_args!("alpn[{}]", i), "is empty");
+            } else if !proto.is_ascii() {
+                self.push(
+                    format_args!("alpn[{}]", i),
+                    format!("{:?} is not ascii", proto),
+                );
+            } else if proto.len() > 255 {
+                self.push(format_args!("alpn[{}]", i), "is longer than 255 bytes");
+            }
+        }
+    }
+
+    /// Files of the certificate, its pem is checked when it is loaded.
+    fn certificate(&mut self, field: &str, cert: &TlsCertOption) {
+        if let TlsCertOption::File { cert, key, .. } = cert {
+            self.file(format_args!("{}.cert", field), cert);
+            self.file(format_args!("{}.key", field), key);
+        }
+    }
+
+    fn file(&mut self, field: impl std::fmt::Display, path: &Path) {
+        match std::fs::File::open(path).and_then(|f| f.metadata()) {
+            Ok(meta) if meta.is_file() => {}
+            Ok(_) => self.push(field, format!("{} is not a file", path.display())),
+            Err(e) => self.push(field, format!("can not read {} ({})", path.display(), e)),
+        }
+    }
+
+    /// Socket paths fit the 108 bytes of `sun_path`, nul included.
+    #[cfg(unix)]
+    fn socket_path(&mut self, path: &Path) {
+        let len = path.as_os_str().len();
+        if len == 0 {
+            self.push("path", "is empty");
+        } else if len > 107 {
+            self.push("path", format!("is {} bytes, longer than 107", len));
+        }
+    }
+
+    fn rate_limit(&mut self, rate_limit: Option<&RateLimitOption>) {
+        let Some(rate_limit) = rate_limit else {
+            return;
+        };
+        if rate_limit.rate_bytes_per_sec == 0 {
+            self.push("rate_limit.rate_bytes_per_sec", "is 0");
+        }
+        if rate_limit.burst == Some(0) {
+            self.push("rate_limit.burst", "is 0");
+        }
+        if rate_limit.server_rate_bytes_per_sec == Some(0) {
+            self.push("rate_limit.server_rate_bytes_per_sec", "is 0");
+        }
+        if rate_limit.server_burst == Some(0) {
+            self.push("rate_limit.server_burst", "is 0");
+        }
+    }
+
+    fn join(&mut self, other: Issues) {
+        self.list.extend(other.list);
+    }
+}
+
+/// All issues as one message, for the init errors.
+fn message(issues: &[OptionIssue]) -> String {
+    let issues = issues.iter().map(ToString::to_string).collect::<Vec<_>>();
+    format!("invalid option: {}", issues.join("; "))
+}
+
+impl TcpClientOption {
+    pub fn validate(&self) -> Vec<OptionIssue> {
+        let mut issues = Issues::default();
+        issues.addr(&self.addr, self.port);
+        if self.bind_device.as_ref().is_some_and(|dev| dev.is_empty()) {
+            issues.push("bind_device", "is empty");
+        }
+        issues.list
+    }
+}
+
+impl TcpServerOption {
+    pub fn validate(&self) -> Vec<OptionIssue> {
+        let mut issues = Issues::default();
+        if self.accept_batch == 0 {
+            issues.push("accept_batch", "is 0");
+        }
+        if self.max_connections == Some(0) {
+            issues.push("max_connections", "is 0");
+        }
+        issues.rate_limit(self.rate_limit.as_ref());
+        issues.list
+    }
+}
+
+impl WebSocketClientOption {
+    pub fn validate(&self) -> Vec<OptionIssue> {
+        let mut issues = Issues::default();
+        issues.addr(&self.addr, self.port);
+        issues.path("path", &self.path);
+        if self.write_chunk_size == Some(0) {
+            issues.push("write_chunk_size", "is 0");
+        }
+        issues.list
+    }
+}
+
+impl WebSocketServerOption {
+    pub fn validate(&self) -> Vec<OptionIssue> {
+        let mut issues = Issues::default();
+        issues.path("path", &self.path);
+        for (i, path) in self.paths.iter().enumerate() {
+            issues.path(format_args!("paths[{}]", i), path);
+        }
+        if self.write_chunk_size == Some(0) {
+            issues.push("write_chunk_size", "is 0");
+        }
+        if self.max_connections == Some(0) {
+            issues.push("max_connections", "is 0");
+        }
+        issues.rate_limit(self.rate_limit.as_ref());
+        issues.list
+    }
+}
+
+impl UdpClientOption {
+    pub fn validate(&self) -> Vec<OptionIssue> {
+        let mut issues = Issues::default();
+        issues.addr(&self.addr, self.port);
+        issues.list
+    }
+}
+
+impl UdpServerOption {
+    pub fn validate(&self) -> Vec<OptionIssue> {
+        let mut issues = Issues::default();
+        if self.idle_timeout.is_zero() {
+            issues.push("idle_timeout", "is 0");
+        }
+        if self.peer_queue == 0 {
+            issues.push("peer_queue", "is 0");
+        }
+        issues.list
+    }
+}
+
+impl CircuitBreakerOption {
+    pub fn validate(&self) -> Vec<OptionIssue> {
+        let mut issues = Issues::default();
+        if self.failure_threshold == 0 {
+            issues.push("failure_threshold", "is 0");
+        }
+        if self.open_duration.is_zero() {
+            issues.push("open_duration", "is 0");
+        }
+        issues.list
+    }
+}
+
+impl H2ClientOption {
+    pub fn validate(&self) -> Vec<OptionIssue> {
+        let mut issues = Issues::default();
+        issues.addr(&self.addr, self.port);
+        issues.path("path", &self.path);
+        issues.list
+    }
+}
+
+impl H2ServerOption {
+    pub fn validate(&self) -> Vec<OptionIssue> {
+        let mut issues = Issues::default();
+        issues.path("path", &self.path);
+        issues.list
+    }
+}
+
+impl GrpcClientOption {
+    pub fn validate(&self) -> Vec<OptionIssue> {
+        let mut issues = Issues::default();
+        issues.addr(&self.addr, self.port);
+        grpc_names(&mut issues, &self.service_name, &self.method_name);
+        issues.list
+    }
+}
+
+impl GrpcServerOption {
+    pub fn validate(&self) -> Vec<OptionIssue> {
+        let mut issues = Issues::default();
+        grpc_names(&mut issues, &self.service_name, &self.method_name);
+        issues.list
+    }
+}
+
+fn grpc_names(issues: &mut Issues, service_name: &str, method_name: &str) {
+    for (field, name) in [("service_name", service_name), ("method_name", method_name)] {
+        if name.is_empty() {
+            issues.push(field, "is empty");
+        } else if name.contains(['/', '?', '#']) {
+            issues.push(field, format!("{:?} is not a path segment", name));
+        }
+    }
+}
+
+#[cfg(any(test, feature = "test-util"))]
+impl MemoryClientOption {
+    pub fn validate(&self) -> Vec<OptionIssue> {
+        let mut issues = Issues::default();
+        if self.addr.is_empty() {
+            issues.push("addr", "is empty");
+        }
+        if self.buffer_size == 0 {
+            issues.push("buffer_size", "is 0");
+        }
+        issues.list
+    }
+}
+
+#[cfg(unix)]
+impl UnixClientOption {
+    pub fn validate(&self) -> Vec<OptionIssue> {
+        let mut issues = Issues::default();
+        issues.socket_path(&self.path);
+        issues.list
+    }
+}
+
+#[cfg(any(test, feature = "test-util"))]
+impl MemoryServerOption {
+    pub fn validate(&self) -> Vec<OptionIssue> {
+        let mut issues = Issues::default();
+        if self.addr.is_empty() {
+            issues.push("addr", "is empty");
+        }
+        issues.list
+    }
+}
+
+#[cfg(unix)]
+impl UnixServerOption {
+    pub fn validate(&self) -> Vec<OptionIssue> {
+        let mut issues = Issues::default();
+        issues.socket_path(&self.path);
+        issues.list
+    }
+}
+
+impl TlsClientOption {
+    pub fn validate(&self) -> Vec<OptionIssue> {
+        let mut issues = Issues::default();
+        issues.alpn(&self.alpn);
+        if let Some(ref cert) = self.certificate {
+            issues.certificate("certificate", cert);
+        }
+        issues.list
+    }
+}
+
+impl TlsServerOption {
+    pub fn validate(&self) -> Vec<OptionIssue> {
+        let mut issues = Issues::default();
+        issues.alpn(&self.alpn);
+        issues.certificate("certificate", &self.certificate);
+        if let Some(ref ca) = self.client_ca {
+            issues.certificate("client_ca", ca);
+        }
+        issues.list
+    }
+}
+
+impl ClientOption {
+    pub fn validate(&self) -> Vec<OptionIssue> {
+        match self {
+            ClientOption::Tcp(opt) => opt.validate(),
+            ClientOption::Ws(opt) => opt.validate(),
+            ClientOption::Udp(opt) => opt.validate(),
+            ClientOption::H2(opt) => opt.validate(),
+            ClientOption::Grpc(opt) => opt.validate(),
+            ClientOption::Empty | ClientOption::Blackhole(_) | ClientOption::Generator(_) => {
+                vec![]
+            }
+            #[cfg(any(test, feature = "test-util"))]
+            ClientOption::Memory(opt) => opt.validate(),
+            #[cfg(unix)]
+            ClientOption::Unix(opt) => opt.validate(),
+        }
+    }
+}
+
+impl ServerOption {
+    pub fn validate(&self) -> Vec<OptionIssue> {
+        match self {
+            ServerOption::Tcp(opt) => opt.validate(),
+            ServerOption::Ws(opt) => opt.validate(),
+            ServerOption::H2(opt) => opt.validate(),
+            ServerOption::Grpc(opt) => opt.validate(),
+            ServerOption::Udp(opt) => opt.validate(),
+            ServerOption::Generator(_) => vec![],
+            #[cfg(any(test, feature = "test-util"))]
+            ServerOption::Memory(opt) => opt.validate(),
+            #[cfg(unix)]
+            ServerOption::Unix(opt) => opt.validate(),
+        }
+    }
+}
+
+/// Prefix the fields of `list` with the path of their option.
+fn nested(prefix: &'static str, list: Vec<OptionIssue>) -> Issues {
+    let mut issues = Issues::new(prefix);
+    for issue in list {
+        issues.push(issue.field, issue.reason);
+    }
+    issues
+}
+
+impl TransportClientOption {
+    /// Every issue of the option, fields named from its root.
+    pub fn validate(&self) -> Vec<OptionIssue> {
+        let mut issues = nested("opt.", self.opt.validate());
+        if let Some(ref tls) = self.tls {
+            issues.join(nested("tls.", tls.validate()));
+        }
+        if let Some(ref breaker) = self.breaker {
+            issues.join(nested("breaker.", breaker.validate()));
+        }
+        issues.list
+    }
+
+    /// Fail with all issues of `validate()`.
+    pub(crate) fn ensure_valid(&self) -> Result<(), ClientError> {
+        let issues = self.validate();
+        if issues.is_empty() {
+            return Ok(());
+        }
+        Err(ClientError::Option(message(&issues)))
+    }
+}
+
+impl TransportServerOption {
+    /// Every issue of the option, fields named from its root.
+    pub fn validate(&self) -> Vec<OptionIssue> {
+        let mut issues = nested("opt.", self.opt.validate());
+        if let Some(ref tls) = self.tls {
+            issues.join(nested("tls.", tls.validate()));
+        }
+        issues.list
+    }
+
+    /// Fail with all issues of `validate()`.
+    pub(crate) fn ensure_valid(&self) -> Result<(), ServerError> {
+        let issues = self.validate();
+        if issues.is_empty() {
+            return Ok(());
+        }
+        Err(ServerError::Option(message(&issues)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::{TransportClient, TransportServer};
+
+    use super::*;
+
+    fn fields(issues: &[OptionIssue]) -> Vec<&str> {
+        issues.iter().map(|issue| issue.field.as_str()).collect()
+    }
+
+    #[test]
+    fn test_validate_client() {
+        let opt: TransportClientOption = serde_json::from_value(json!({
+            "opt": {"ws": {"addr": "", "port": 0, "path": "ws"}},
+            "tls": {"alpn": ["h2", "ĥ2", ""]},
+        }))
+        .unwrap();
+        let issues = opt.validate();
+        assert_eq!(
+            fields(&issues),
+            [
+                "opt.addr",
+                "opt.port",
+                "opt.path",
+                "tls.alpn[1]",
+                "tls.alpn[2]"
+            ]
+        );
+        assert_eq!(
+            issues[2].to_string(),
+            r#"opt.path: "ws" does not start with /"#
+        );
+
+        // init fails listing all of them
+        let err = TransportClient::init(opt, &Default::default())
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(
+            err.contains("opt.addr: is empty") && err.contains("tls.alpn[2]"),
+            "{}",
+            err
+        );
+
+        let opt: TransportClientOption = serde_json::from_value(json!({
+            "opt": {"tcp": {"addr": "127.0.0.1", "port": 9000}},
+        }))
+        .unwrap();
+        assert!(opt.validate().is_empty());
+
+        let opt: TransportClientOption = serde_json::from_value(json!({
+            "opt": {"tcp": {"addr": "127.0.0.1", "port": 9000}},
+            "breaker": {
+                "failure_threshold": 0,
+                "open_duration": {"secs": 0, "nanos": 0},
+            },
+        }))
+        .unwrap();
+        assert_eq!(
+            fields(&opt.validate()),
+            ["breaker.failure_threshold", "breaker.open_duration"]
+        );
+    }
+
+    #[test]
+    fn test_validate_server() {
+        let opt: TransportServerOption = serde_json::from_value(json!({
+            "opt": {"ws": {
+                "listen": "127.0.0.1:0",
+                "path": "",
+                "paths": ["/ok", "bad"],
+                "rate_limit": {"rate_bytes_per_sec": 0},
+            }},
+            "tls": {
+                "alpn": [],
+                "certificate": {"file": {"cert": "/nonexistent/cert.pem", "key": "/"}},
+            },
+        }))
+        .unwrap();
+        let issues = opt.validate();
+        assert_eq!(
+            fields(&issues),
+            [
+                "opt.path",
+                "opt.paths[1]",
+                "opt.rate_limit.rate_bytes_per_sec",
+                "tls.certificate.cert",
+                "tls.certificate.key"
+            ]
+        );
+        assert!(issues[3].reason.contains("can not read"), "{}", issues[3]);
+        assert!(issues[4].reason.contains("not a file"), "{}", issues[4]);
+
+        let err = TransportServer::init(opt).err().unwrap().to_string();
+        assert!(err.contains("tls.certificate.cert"), "{}", err);
+
+        let opt: TransportServerOption = serde_json::from_value(json!({
+            "opt": {"udp": {
+                "listen": "127.0.0.1:0",
+                "idle_timeout": {"secs": 0, "nanos": 0},
+                "peer_queue": 0,
+            }},
+        }))
+        .unwrap();
+        assert_eq!(
+            fields(&opt.validate()),
+            ["opt.idle_timeout", "opt.peer_queue"]
+        );
+    }
+}
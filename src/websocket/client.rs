@@ -1,41 +1,127 @@
 //! WebSocket Client
 
 use std::{
-    net::{IpAddr, SocketAddr},
+    collections::VecDeque,
+    net::SocketAddr,
     pin::Pin,
-    str::FromStr,
     sync::Arc,
-    task::Poll,
+    task::{Poll, Waker},
+    time::Duration,
 };
 
+use arc_swap::ArcSwap;
 use bytes::{Buf, Bytes};
 use futures_util::{
     ready,
     stream::{SplitSink, SplitStream},
     SinkExt, StreamExt,
 };
-use http::Uri;
-use rustls::ClientConfig as TlsClientConfig;
+use http::{
+    header::{CONNECTION, HOST, SEC_WEBSOCKET_KEY, SEC_WEBSOCKET_VERSION, UPGRADE},
+    uri::Authority,
+    HeaderMap, HeaderName, HeaderValue, Uri,
+};
+use rustls::pki_types::ServerName;
 use tokio::{
     io::{AsyncBufRead, AsyncRead, AsyncWrite},
     net::TcpStream,
 };
+use tokio_rustls::TlsConnector;
 use tokio_tungstenite::{
-    client_async_tls_with_config, tungstenite::Message, Connector as WsConnector, MaybeTlsStream,
-    WebSocketStream,
+    client_async_with_config,
+    tungstenite::{
+        client::IntoClientRequest,
+        error::TlsError as WsTlsError,
+        handshake::client::Request,
+        protocol::{frame::coding::CloseCode, CloseFrame, WebSocketConfig},
+        Error as WsError, Message,
+    },
+    Connector as WsConnector, MaybeTlsStream, WebSocketStream,
 };
 
 use crate::{
-    ClientError, ClientResult, ResolveError, Resolver, TlsClientOption, TransportClientTrait,
+    io::Budget,
+    net::{
+        proxy::dial_target, BreakerSnapshot, CircuitBreaker, DialAddrs, DialHooks, Dialer,
+        ProxyTunnel,
+    },
+    observe::{self, ConnectObserver, Stage},
+    option::{ClientOption, LatencyProfile, ProbeMode},
+    reload::{changed_paths, AppliedChanges},
+    ClientError, ClientResult, ConnContext, Resolver, TlsClientOption, TransportClientOption,
+    TransportClientTrait,
+};
+
+use super::{
+    duplex_waker,
+    forwarded::ForwardedElement,
+    keepalive::PingKeepalive,
+    option::SlowConsumerOption,
+    slow::SlowConsumer,
+    wire::{self, close},
+    CloseReason, ForwardedChain, ForwardedOption, FrameKind, FrameStats, WebSocketClientOption,
+    MAX_READ_AHEAD_SIZE,
 };
 
-use super::WebSocketClientOption;
+/// Per connect overrides of the client options.
+#[derive(Debug, Clone, Default)]
+pub struct ConnectParams {
+    /// Replaces `forwarded.forward_for` of the option.
+    pub forward_for: Option<SocketAddr>,
+    /// Replaces `forwarded.forwarded_proto` of the option.
+    pub forwarded_proto: Option<String>,
+    /// Replaces `forwarded.forwarded_host` of the option.
+    pub forwarded_host: Option<String>,
+    /// Elements received from earlier hops, sent ahead of this hop's.
+    pub forwarded_chain: Vec<ForwardedElement>,
+}
+
+impl ConnectParams {
+    /// Forward the peer of an accepted connection, keeping the chain it
+    /// arrived with when the server trusts forwarded headers.
+    pub fn forward_from(ctx: &ConnContext) -> Self {
+        Self {
+            forward_for: ctx.peer_addr,
+            forwarded_chain: ctx
+                .extensions
+                .get::<ForwardedChain>()
+                .map(|chain| chain.0.clone())
+                .unwrap_or_default(),
+            ..Default::default()
+        }
+    }
+}
 
 pub struct WebSocketClient {
+    state: ArcSwap<State>,
+}
+
+/// Everything a connect reads, swapped as a whole by `apply`.
+#[derive(Clone)]
+struct State {
     uri: Uri,
-    addrs: Vec<SocketAddr>,
+    addrs: DialAddrs,
+    /// Set when connecting through a proxy, `addrs` are then the proxy's.
+    tunnel: Option<Box<ProxyTunnel>>,
     ws_conn: WsConnector,
-    tcp_nodelay: bool,
+    dialer: Dialer,
+    duplex_fairness: bool,
+    read_buffer_messages: usize,
+    write_chunk_size: Option<usize>,
+    ws_config: WebSocketConfig,
+    validate_text: bool,
+    text_to_bytes: bool,
+    flush_always: bool,
+    /// `headers` and `host` of the option, replacing the upgrade's own.
+    headers: HeaderMap,
+    // boxed, it is rarely set and would dwarf the other client variants
+    forwarded: Box<ForwardedOption>,
+    keepalive_interval: Option<Duration>,
+    keepalive_timeout: Duration,
+    slow_consumer_policy: Option<Box<SlowConsumerOption>>,
+    post_connect_probe: Option<ProbeMode>,
+    /// The option the state was built from, diffed by `apply`.
+    source: Arc<TransportClientOption>,
 }
 
 impl WebSocketClient {
@@ -44,13 +130,32 @@ impl WebSocketClient {
         tls_opt: Option<TlsClientOption>,
         resolver: &Resolver,
     ) -> ClientResult<Self> {
-        let (ws_conn, uri) = if let Some(tls_opt) = tls_opt {
-            let config: TlsClientConfig = tls_opt.try_into()?;
-            let conn = WsConnector::Rustls(Arc::new(config));
+        let (host, port) = dial_target(opt.proxy.as_ref(), &opt.addr, opt.port);
+        let addrs = DialAddrs::new(host, port, opt.resolve_on_connect, resolver)?;
+        Self::with_dial_addrs(opt, tls_opt, addrs)
+    }
+
+    /// Init with already resolved addresses of `opt.addr`, or of the proxy
+    /// when `opt.proxy` is set.
+    pub fn with_addrs(
+        opt: WebSocketClientOption,
+        tls_opt: Option<TlsClientOption>,
+        addrs: Vec<SocketAddr>,
+    ) -> ClientResult<Self> {
+        Self::with_dial_addrs(opt, tls_opt, DialAddrs::Resolved(addrs))
+    }
+
+    fn with_dial_addrs(
+        opt: WebSocketClientOption,
+        tls_opt: Option<TlsClientOption>,
+        addrs: DialAddrs,
+    ) -> ClientResult<Self> {
+        let (ws_conn, uri) = if let Some(ref tls_opt) = tls_opt {
+            let conn = WsConnector::Rustls(tls_opt.client_config()?);
 
             let uri = Uri::builder()
                 .scheme("wss")
-                .path_and_query(opt.path)
+                .path_and_query(opt.path.as_str())
                 .authority(format!("{}:{}", opt.addr, opt.port))
                 .build()
                 .map_err(|e| ClientError::Option(e.to_string()))?;
@@ -61,126 +166,809 @@ impl WebSocketClient {
                 WsConnector::Plain,
                 Uri::builder()
                     .scheme("ws")
-                    .path_and_query(opt.path)
+                    .path_and_query(opt.path.as_str())
                     .authority(format!("{}:{}", opt.addr, opt.port))
                     .build()
                     .map_err(|e| ClientError::Option(e.to_string()))?,
             )
         };
 
-        let addrs = match IpAddr::from_str(&opt.addr) {
-            Ok(ip) => vec![(ip, opt.port).into()],
-            Err(_) => resolver.block_resolve(&opt.addr, opt.port)?.collect(),
+        let headers = upgrade_headers(&opt)?;
+        let tunnel = match opt.proxy {
+            Some(ref proxy) => Some(Box::new(ProxyTunnel::new(
+                proxy.clone(),
+                &opt.addr,
+                opt.port,
+            )?)),
+            None => None,
         };
 
-        Ok(Self {
+        let mut dialer = Dialer::new();
+        dialer.set_tcp_nodelay(opt.tcp_nodelay);
+        dialer.set_connect_timeout(opt.connect_timeout);
+        dialer.set_connect_deadline(opt.connect_deadline);
+        dialer.set_bind(opt.bind_addr, opt.bind_device.clone())?;
+        dialer.set_attempt_delay(opt.attempt_delay);
+
+        let state = State {
             addrs,
+            tunnel,
             uri,
             ws_conn,
-            tcp_nodelay: opt.tcp_nodelay,
+            dialer,
+            duplex_fairness: opt.duplex_fairness,
+            read_buffer_messages: opt.read_buffer_messages,
+            write_chunk_size: opt.write_chunk_size,
+            ws_config: wire::config(opt.max_message_size, opt.max_frame_size),
+            validate_text: opt.validate_text,
+            text_to_bytes: opt.text_to_bytes,
+            flush_always: false,
+            headers,
+            forwarded: Box::new(opt.forwarded.clone()),
+            keepalive_interval: opt.keepalive_interval,
+            keepalive_timeout: opt.keepalive_timeout,
+            slow_consumer_policy: opt.slow_consumer_policy.clone().map(Box::new),
+            post_connect_probe: None,
+            source: Arc::new(TransportClientOption {
+                opt: ClientOption::Ws(opt),
+                tls: tls_opt,
+                ..Default::default()
+            }),
+        };
+        Ok(Self {
+            state: ArcSwap::from_pointee(state),
         })
     }
+
+    fn update(&mut self, f: impl FnOnce(&mut State)) {
+        let mut state = State::clone(&self.state.load());
+        f(&mut state);
+        self.state.store(Arc::new(state));
+    }
+
+    /// The option `apply` diffs against, by default the ws and tls options.
+    pub(crate) fn set_source(&mut self, source: TransportClientOption) {
+        self.update(|state| state.source = Arc::new(source));
+    }
+
+    /// Swap in the state of `next`, built from the new option. Dial hooks
+    /// are set in code and stay, so do the breakers of an unchanged option.
+    pub(crate) fn apply(&self, next: WebSocketClient) -> AppliedChanges {
+        let current = self.state.load_full();
+        let mut next = State::clone(&next.state.load());
+        next.dialer.set_hooks(current.dialer.hooks().clone());
+        if current.source.breaker == next.source.breaker {
+            next.dialer.set_breaker(current.dialer.breaker().cloned());
+        }
+        let paths = changed_paths(&*current.source, &*next.source);
+        self.state.store(Arc::new(next));
+        AppliedChanges::split(paths, &[])
+    }
+
+    /// Probe each new connection before `connect` returns it.
+    pub fn set_post_connect_probe(&mut self, probe: Option<ProbeMode>) {
+        self.update(|state| state.post_connect_probe = probe);
+    }
+
+    pub fn set_dial_hooks(&mut self, hooks: DialHooks) {
+        self.update(|state| state.dialer.set_hooks(hooks));
+    }
+
+    /// Fail connects fast to destinations that keep failing, `breaker` may
+    /// be shared with other clients.
+    pub fn set_breaker(&mut self, breaker: Option<Arc<CircuitBreaker>>) {
+        self.update(|state| state.dialer.set_breaker(breaker));
+    }
+
+    /// The breakers dialed through so far, empty without a breaker.
+    pub fn breaker_state(&self) -> Vec<BreakerSnapshot> {
+        let state = self.state.load();
+        state
+            .dialer
+            .breaker()
+            .map(|breaker| breaker.state())
+            .unwrap_or_default()
+    }
+
+    /// Report the stages of each connect to `observer`, kept in `DialHooks::observer`.
+    pub fn set_connect_observer(&mut self, observer: Arc<dyn ConnectObserver>) {
+        self.update(|state| {
+            let mut hooks = state.dialer.hooks().clone();
+            hooks.observer = Some(observer);
+            state.dialer.set_hooks(hooks);
+        });
+    }
+
+    /// Socket and stream settings of the profile, the options are overridden
+    /// by `apply_latency_profile`.
+    pub fn set_latency_profile(&mut self, profile: LatencyProfile) {
+        self.update(|state| {
+            state
+                .dialer
+                .set_send_buffer_size(profile.send_buffer_size());
+            state.dialer.set_busy_poll(profile.busy_poll());
+            state.flush_always = profile.is_low_latency();
+            state.ws_config.write_buffer_size = if state.flush_always {
+                0
+            } else {
+                WebSocketConfig::default().write_buffer_size
+            };
+        });
+    }
+
+    /// Connect with `params` overriding the options.
+    pub async fn connect_with(
+        &self,
+        params: &ConnectParams,
+    ) -> ClientResult<WebSocketClientStream> {
+        let state = self.state.load_full();
+        observe::connect_span("ws", state.connect_with(params)).await
+    }
+
+    /// Run the tls, upgrade and probe steps over a connected `stream`,
+    /// skipping resolution, dialing and the proxy.
+    pub async fn connect_over<S>(
+        &self,
+        stream: S,
+        params: &ConnectParams,
+    ) -> ClientResult<WebSocketClientStream<MaybeTlsStream<S>>>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let state = self.state.load_full();
+        let request = state.request(params)?;
+        let mut stream = state.upgrade(request, stream).await?;
+        state.probe(&mut stream).await?;
+        Ok(stream)
+    }
 }
 
-impl TransportClientTrait for WebSocketClient {
-    type Stream = WebSocketClientStream;
+/// Handshake headers the upgrade sets itself.
+const HANDSHAKE_HEADERS: [HeaderName; 4] = [
+    CONNECTION,
+    UPGRADE,
+    SEC_WEBSOCKET_KEY,
+    SEC_WEBSOCKET_VERSION,
+];
 
-    async fn connect(&self) -> ClientResult<Self::Stream> {
-        let mut err = None;
-        for addr in self.addrs.iter() {
-            match tokio::net::TcpStream::connect(addr).await {
-                Ok(stream) => {
-                    if self.tcp_nodelay {
-                        let _ = stream.set_nodelay(true);
-                    }
-                    let (socket, _) = client_async_tls_with_config(
-                        &self.uri,
-                        stream,
-                        None,
-                        Some(self.ws_conn.clone()),
-                    )
-                    .await
-                    .map_err(|e| ClientError::Connect(e.to_string()))?;
-                    let stream = WebSocketClientStream::new(socket);
-                    return Ok(stream);
+/// The `headers` and `host` of `opt` as sent on the upgrade request.
+fn upgrade_headers(opt: &WebSocketClientOption) -> ClientResult<HeaderMap> {
+    let mut headers = HeaderMap::new();
+    for (name, value) in &opt.headers {
+        let invalid = |e: &dyn std::fmt::Display| {
+            ClientError::Option(format!("header {:?} is invalid ({})", name, e))
+        };
+        let name = HeaderName::from_bytes(name.as_bytes()).map_err(|e| invalid(&e))?;
+        if name == HOST {
+            return Err(ClientError::Option(
+                "header host is set by the host option".to_owned(),
+            ));
+        }
+        if HANDSHAKE_HEADERS.contains(&name) {
+            return Err(ClientError::Option(format!(
+                "header {} is set by the upgrade",
+                name
+            )));
+        }
+        let value = HeaderValue::from_str(value).map_err(|e| invalid(&e))?;
+        headers.append(name, value);
+    }
+    if let Some(ref host) = opt.host {
+        let value = host
+            .parse::<Authority>()
+            .map_err(|e| e.to_string())
+            .and_then(|_| HeaderValue::from_str(host).map_err(|e| e.to_string()))
+            .map_err(|e| ClientError::Option(format!("host {:?} is invalid ({})", host, e)))?;
+        headers.insert(HOST, value);
+    }
+    Ok(headers)
+}
+
+/// Upgrade `stream` as the client side of a ws connection, sending the
+/// upgrade request of `opt` with its stream settings. For ws over a stream
+/// this crate does not dial, like a unix socket or a tls stream of its own.
+///
+/// No tls, proxy or probe is run, `WebSocketClient::connect_over` adds the
+/// tls of a client.
+pub async fn connect_stream<S>(
+    stream: S,
+    opt: &WebSocketClientOption,
+) -> ClientResult<WebSocketClientStream<S>>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let client = WebSocketClient::with_addrs(opt.clone(), None, vec![])?;
+    let state = client.state.load();
+    let request = state.request(&ConnectParams::default())?;
+    state.handshake(request, stream).await
+}
+
+impl State {
+    async fn connect_with(&self, params: &ConnectParams) -> ClientResult<WebSocketClientStream> {
+        let request = self.request(params)?;
+        let addrs = self
+            .dialer
+            .candidates(&self.dialer.resolve(&self.addrs).await?)?;
+        let mut rest = &addrs[..];
+        loop {
+            let (mut stream, report) = self.dialer.dial(rest).await?;
+            let addr = report.winner().expect("dial reports its winner last");
+            // every address up to the last one started was tried
+            rest = &rest[report.attempts.len()..];
+
+            if let Some(ref tunnel) = self.tunnel {
+                tunnel.open(&mut stream).await?;
+            }
+
+            let mut stream = self.upgrade(request.clone(), stream).await?;
+            if let Err(e) = self.probe(&mut stream).await {
+                log::debug!("ws connection to {} {}", addr, e);
+                if rest.is_empty() {
+                    return Err(e);
                 }
-                Err(e) => err = Some(e),
+                continue;
             }
+
+            return Ok(stream);
         }
+    }
 
-        if let Some(e) = err {
-            Err(e.into())
-        } else {
-            Err(ResolveError::EmptyResolved.into())
+    fn request(&self, params: &ConnectParams) -> ClientResult<Request> {
+        let forwarded = ForwardedOption {
+            forward_for: params.forward_for.or(self.forwarded.forward_for),
+            forwarded_proto: params
+                .forwarded_proto
+                .clone()
+                .or_else(|| self.forwarded.forwarded_proto.clone()),
+            forwarded_host: params
+                .forwarded_host
+                .clone()
+                .or_else(|| self.forwarded.forwarded_host.clone()),
+            ..(*self.forwarded).clone()
+        };
+        let mut request = (&self.uri).into_client_request()?;
+        request.headers_mut().extend(self.headers.clone());
+        forwarded
+            .apply(&params.forwarded_chain, request.headers_mut())
+            .map_err(|e| ClientError::Option(e.to_string()))?;
+        Ok(request)
+    }
+
+    async fn upgrade<S>(
+        &self,
+        request: Request,
+        stream: S,
+    ) -> ClientResult<WebSocketClientStream<MaybeTlsStream<S>>>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let observer = self.dialer.hooks().observer.as_deref();
+        // the tls handshake runs apart from the upgrade to time each of them
+        let stream = match self.ws_conn {
+            WsConnector::Rustls(ref config) => {
+                let host = request.uri().host().unwrap_or_default();
+                // rustls expects ipv6 addresses without the brackets
+                let host = host.trim_start_matches('[').trim_end_matches(']');
+                let server_name = ServerName::try_from(host.to_owned())
+                    .map_err(|_| WsError::Tls(WsTlsError::InvalidDnsName))?;
+                let stage = Stage::start(observer);
+                let res = TlsConnector::from(config.clone())
+                    .connect(server_name, stream)
+                    .await;
+                stage.tls_handshake(res.as_ref().map(|_| ()));
+                MaybeTlsStream::Rustls(res.map_err(WsError::Io)?)
+            }
+            _ => MaybeTlsStream::Plain(stream),
+        };
+        self.handshake(request, stream).await
+    }
+
+    /// The upgrade over `stream`, no tls.
+    async fn handshake<S>(
+        &self,
+        request: Request,
+        stream: S,
+    ) -> ClientResult<WebSocketClientStream<S>>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let path = request.uri().path().to_owned();
+        let stage = Stage::start(self.dialer.hooks().observer.as_deref());
+        let res = client_async_with_config(request, stream, Some(self.ws_config))
+            .await
+            .map_err(ClientError::from);
+        stage.ws_upgrade(res.as_ref().map(|_| ()));
+        let (socket, _) = res?;
+        let mut stream = WebSocketClientStream::new(socket);
+        stream.path = Some(path);
+        stream.set_duplex_fairness(self.duplex_fairness);
+        stream.set_read_buffer_messages(self.read_buffer_messages);
+        stream.set_write_chunk_size(self.write_chunk_size);
+        stream.set_validate_text(self.validate_text);
+        stream.set_text_to_bytes(self.text_to_bytes);
+        stream.set_flush_always(self.flush_always);
+        stream.set_keepalive(self.keepalive_interval, self.keepalive_timeout);
+        stream.set_slow_consumer_policy(self.slow_consumer_policy.as_deref().cloned());
+        Ok(stream)
+    }
+
+    async fn probe<S>(&self, stream: &mut WebSocketClientStream<S>) -> ClientResult<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        match self.post_connect_probe {
+            Some(probe @ ProbeMode::WsPing { .. }) => probe.run(stream.ping()).await,
+            Some(probe @ ProbeMode::ExpectServerBytes { n, .. }) => {
+                probe.run(stream.wait_readable_bytes(n)).await
+            }
+            None => Ok(()),
         }
     }
 }
 
-pub struct WebSocketClientStream {
-    tx: SplitSink<WebSocketStream<MaybeTlsStream<TcpStream>>, Message>,
-    rx: SplitStream<WebSocketStream<MaybeTlsStream<TcpStream>>>,
-    chunk: Option<Bytes>,
+impl TransportClientTrait for WebSocketClient {
+    type Stream = WebSocketClientStream;
+
+    async fn connect(&self) -> ClientResult<Self::Stream> {
+        self.connect_with(&ConnectParams::default()).await
+    }
 }
 
-impl WebSocketClientStream {
-    pub fn new(inner: WebSocketStream<MaybeTlsStream<TcpStream>>) -> Self {
+/// Client side of a WebSocket connection over `S`, a dialed tcp stream
+/// unless it was given to `connect_over`.
+pub struct WebSocketClientStream<S = MaybeTlsStream<TcpStream>> {
+    tx: SplitSink<WebSocketStream<S>, Message>,
+    rx: SplitStream<WebSocketStream<S>>,
+    chunks: VecDeque<Bytes>,
+    rx_err: Option<std::io::Error>,
+    read_buffer_messages: usize,
+    write_chunk_size: Option<usize>,
+    duplex_fairness: bool,
+    read_waker: Option<Waker>,
+    validate_text: bool,
+    text_to_bytes: bool,
+    close_reason: Option<CloseReason>,
+    /// A Close frame was queued by us.
+    closed: bool,
+    pong_received: bool,
+    flush_always: bool,
+    frame_stats: FrameStats,
+    keepalive: Option<PingKeepalive>,
+    slow_consumer: Option<SlowConsumer>,
+    /// Reads and messages answered without waiting on the socket.
+    budget: Budget,
+    /// Path of the upgrade request, when dialed by a `WebSocketClient`.
+    path: Option<String>,
+}
+
+impl<S> std::fmt::Debug for WebSocketClientStream<S> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("WebSocketClientStream")
+            .field("path", &self.path)
+            .field("closed", &self.closed)
+            .field("close_reason", &self.close_reason)
+            .finish()
+    }
+}
+
+/// Payload of the probe Ping, telling its Pong apart from unsolicited ones.
+const PROBE_PING: &[u8] = b"kapibara-probe";
+
+/// The type of `msg`, its payload length is `msg.len()`.
+fn frame_kind(msg: &Message) -> FrameKind {
+    match msg {
+        Message::Binary(_) => FrameKind::Binary,
+        Message::Text(_) => FrameKind::Text,
+        Message::Ping(_) => FrameKind::Ping,
+        Message::Pong(_) => FrameKind::Pong,
+        Message::Close(_) => FrameKind::Close,
+        Message::Frame(_) => FrameKind::Other,
+    }
+}
+
+impl<S> WebSocketClientStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    pub fn new(inner: WebSocketStream<S>) -> Self {
         let (tx, rx) = inner.split();
         Self {
             tx,
             rx,
-            chunk: None,
+            chunks: VecDeque::new(),
+            rx_err: None,
+            read_buffer_messages: 1,
+            write_chunk_size: None,
+            duplex_fairness: false,
+            read_waker: None,
+            validate_text: false,
+            text_to_bytes: true,
+            close_reason: None,
+            closed: false,
+            pong_received: false,
+            flush_always: false,
+            frame_stats: FrameStats::default(),
+            keepalive: None,
+            slow_consumer: None,
+            budget: Budget::default(),
+            path: None,
         }
     }
 
-    fn has_chunk(&self) -> bool {
-        if let Some(ref chunk) = self.chunk {
-            chunk.remaining() > 0
-        } else {
-            false
+    /// Close frame status received from the server, if any.
+    pub fn close_reason(&self) -> Option<&CloseReason> {
+        self.close_reason.as_ref()
+    }
+
+    /// Send a close frame with the given status and flush it, `shutdown`
+    /// sends a normal closure.
+    pub async fn close_with(&mut self, reason: CloseReason) -> std::io::Result<()> {
+        self.closed = true;
+        let frame = CloseFrame {
+            code: CloseCode::from(reason.code),
+            reason: reason.reason.into(),
+        };
+        self.tx
+            .send(Message::Close(Some(frame)))
+            .await
+            .map_err(std::io::Error::other)
+    }
+
+    /// Drive the read side while writing, buffering up to the read buffer limit.
+    pub fn set_duplex_fairness(&mut self, enable: bool) {
+        self.duplex_fairness = enable;
+    }
+
+    /// Number of messages buffered ahead of the reader, at least one, and
+    /// none past the message that reaches `MAX_READ_AHEAD_SIZE` bytes.
+    pub fn set_read_buffer_messages(&mut self, messages: usize) {
+        self.read_buffer_messages = messages.max(1);
+    }
+
+    /// Send at most `size` bytes of a write as one message, `None` sends it whole.
+    pub fn set_write_chunk_size(&mut self, size: Option<usize>) {
+        self.write_chunk_size = size.map(|size| size.max(1));
+    }
+
+    /// Fail the read side with `InvalidData` and a Close(1007) on invalid UTF-8 Text.
+    pub fn set_validate_text(&mut self, enable: bool) {
+        self.validate_text = enable;
+    }
+
+    /// Pass Text messages through as bytes, otherwise reject them with a Close(1003).
+    pub fn set_text_to_bytes(&mut self, enable: bool) {
+        self.text_to_bytes = enable;
+    }
+
+    /// Start a flush after every message instead of waiting for `poll_flush`.
+    pub fn set_flush_always(&mut self, enable: bool) {
+        self.flush_always = enable;
+    }
+
+    /// Bytes received but not yet consumed by the reader.
+    pub fn buffered_len(&self) -> usize {
+        self.chunks.iter().map(|chunk| chunk.remaining()).sum()
+    }
+
+    /// Ping the peer every `interval`, failing the stream when a Pong is
+    /// later than `timeout` or does not echo its Ping. `None` turns it off.
+    pub fn set_keepalive(&mut self, interval: Option<Duration>, timeout: Duration) {
+        self.keepalive = interval.map(|interval| PingKeepalive::new(interval, timeout));
+    }
+
+    /// Give up on a peer that stops reading, see `SlowConsumerOption`.
+    pub fn set_slow_consumer_policy(&mut self, policy: Option<SlowConsumerOption>) {
+        self.slow_consumer = policy.map(SlowConsumer::new);
+    }
+
+    /// Messages received so far by type, including those the reader never saw.
+    pub fn frame_stats(&self) -> FrameStats {
+        self.frame_stats
+    }
+
+    /// Write the start of `data` as one message, at most the write chunk size
+    /// and split off only once the sink is ready, the rest stays in `data`.
+    ///
+    /// Unlike `poll_write` the payload is not copied when `data` is the
+    /// unique owner of a vec backed buffer.
+    pub fn poll_write_bytes(
+        &mut self,
+        cx: &mut std::task::Context<'_>,
+        data: &mut Bytes,
+    ) -> Poll<std::io::Result<usize>> {
+        let chunk_size = self.write_chunk_size;
+        self.poll_send(cx, || wire::take_chunk(data, chunk_size))
+    }
+
+    /// Write all of `data`, one message per write chunk.
+    pub async fn write_bytes(&mut self, mut data: Bytes) -> std::io::Result<usize> {
+        let mut written = 0;
+        loop {
+            written += std::future::poll_fn(|cx| self.poll_write_bytes(cx, &mut data)).await?;
+            if data.is_empty() {
+                return Ok(written);
+            }
+        }
+    }
+
+    /// Send a Ping and wait for its Pong, data received meanwhile stays readable.
+    pub async fn ping(&mut self) -> std::io::Result<()> {
+        self.pong_received = false;
+        self.tx
+            .send(Message::Ping(PROBE_PING.to_vec()))
+            .await
+            .map_err(std::io::Error::other)?;
+        std::future::poll_fn(|cx| self.poll_buffer_until(cx, |s| s.pong_received)).await
+    }
+
+    /// Wait until `n` bytes are buffered for the reader.
+    pub async fn wait_readable_bytes(&mut self, n: usize) -> std::io::Result<()> {
+        std::future::poll_fn(|cx| self.poll_buffer_until(cx, |s| s.buffered_len() >= n)).await
+    }
+
+    /// Buffer messages regardless of the read buffer limit until `done`.
+    fn poll_buffer_until(
+        &mut self,
+        cx: &mut std::task::Context<'_>,
+        done: impl Fn(&Self) -> bool,
+    ) -> Poll<std::io::Result<()>> {
+        loop {
+            if done(self) {
+                return Poll::Ready(Ok(()));
+            }
+            match ready!(self.poll_recv(cx)) {
+                Some(Ok(chunk)) => self.chunks.push_back(chunk),
+                Some(Err(err)) => return Poll::Ready(Err(err)),
+                None => return Poll::Ready(Err(std::io::ErrorKind::UnexpectedEof.into())),
+            }
+        }
+    }
+
+    /// Queue a close frame if the sink has room and fail the read side.
+    fn protocol_error(
+        &mut self,
+        cx: &mut std::task::Context<'_>,
+        code: CloseCode,
+        reason: &'static str,
+    ) -> std::io::Error {
+        if let Poll::Ready(Ok(())) = self.tx.poll_ready_unpin(cx) {
+            let frame = CloseFrame {
+                code,
+                reason: reason.into(),
+            };
+            if self
+                .tx
+                .start_send_unpin(Message::Close(Some(frame)))
+                .is_ok()
+            {
+                let _ = self.tx.poll_flush_unpin(cx);
+            }
+        }
+        std::io::Error::new(std::io::ErrorKind::InvalidData, reason)
+    }
+
+    fn poll_keepalive(&mut self, cx: &mut std::task::Context<'_>) -> std::io::Result<()> {
+        match self.keepalive.as_mut() {
+            Some(keepalive) => keepalive.poll_send(&mut self.tx, Message::Ping, cx),
+            None => Ok(()),
+        }
+    }
+
+    /// Send the message of `payload` once the sink is ready, for the writes
+    /// of `AsyncWrite` and `poll_write_bytes`.
+    fn poll_send(
+        &mut self,
+        cx: &mut std::task::Context<'_>,
+        payload: impl FnOnce() -> Bytes,
+    ) -> Poll<std::io::Result<usize>> {
+        self.poll_keepalive(cx)?;
+        self.poll_duplex(cx);
+
+        ready!(self.poll_write_ready(cx))?;
+
+        let payload = payload();
+        let len = payload.len();
+        match self.tx.start_send_unpin(Message::Binary(payload.into())) {
+            Ok(()) => {
+                if let Some(ref mut slow) = self.slow_consumer {
+                    slow.on_write(len);
+                }
+                if self.flush_always {
+                    // a pending flush goes on with the next write or flush, which report its error
+                    let _ = self.tx.poll_flush_unpin(cx);
+                }
+                Poll::Ready(Ok(len))
+            }
+            Err(e) => Poll::Ready(Err(std::io::Error::other(e))),
+        }
+    }
+
+    fn poll_write_ready(&mut self, cx: &mut std::task::Context<'_>) -> Poll<std::io::Result<()>> {
+        let Some(ref mut slow) = self.slow_consumer else {
+            return self.tx.poll_ready_unpin(cx).map_err(std::io::Error::other);
+        };
+        let close = || {
+            Message::Close(Some(CloseFrame {
+                code: CloseCode::from(close::POLICY),
+                reason: "slow consumer".into(),
+            }))
+        };
+        slow.poll_ready(&mut self.tx, close, cx)
+    }
+
+    fn poll_recv(
+        &mut self,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<Option<std::io::Result<Bytes>>> {
+        loop {
+            // skipped frames are charged too, a flood of them must yield as well
+            ready!(self.budget.poll_proceed(cx));
+            let polled = self.rx.poll_next_unpin(cx);
+            if polled.is_pending() {
+                self.budget.reset();
+            }
+            let msg = match ready!(polled) {
+                None => return Poll::Ready(None),
+                Some(Err(WsError::Utf8)) if self.validate_text => {
+                    let err = self.protocol_error(
+                        cx,
+                        CloseCode::from(close::INVALID),
+                        "invalid utf-8 text",
+                    );
+                    return Poll::Ready(Some(Err(err)));
+                }
+                Some(Err(WsError::Capacity(err))) => {
+                    let _ =
+                        self.protocol_error(cx, CloseCode::from(close::TOO_BIG), "message too big");
+                    return Poll::Ready(Some(Err(wire::too_big(err))));
+                }
+                Some(Err(err)) => return Poll::Ready(Some(Err(std::io::Error::other(err)))),
+                Some(Ok(msg)) => msg,
+            };
+            if let Some(keepalive) = self.keepalive.as_mut() {
+                keepalive.on_traffic();
+            }
+
+            let kind = frame_kind(&msg);
+            self.frame_stats.record(kind, msg.len());
+            let chunk = match msg {
+                Message::Binary(data) => Bytes::from(data),
+                // a String is valid UTF-8 by construction, the frame layer already checked it
+                Message::Text(data) if self.text_to_bytes => Bytes::from(data),
+                Message::Text(_) => {
+                    let err = self.protocol_error(
+                        cx,
+                        CloseCode::from(close::UNSUPPORTED),
+                        "text not accepted",
+                    );
+                    return Poll::Ready(Some(Err(err)));
+                }
+                Message::Close(frame) => {
+                    if let Some(frame) = frame {
+                        self.close_reason = Some(CloseReason {
+                            code: frame.code.into(),
+                            reason: frame.reason.into_owned(),
+                        });
+                    }
+                    // sends the echo tungstenite queued, unless we closed first
+                    let _ = self.tx.poll_flush_unpin(cx);
+                    self.frame_stats.discard("client", kind);
+                    continue;
+                }
+                Message::Pong(data) if data == PROBE_PING => {
+                    self.pong_received = true;
+                    continue;
+                }
+                Message::Pong(data) => {
+                    if let Some(keepalive) = self.keepalive.as_mut() {
+                        if let Err(err) = keepalive.on_pong(&data) {
+                            return Poll::Ready(Some(Err(err)));
+                        }
+                    }
+                    self.frame_stats.discard("client", kind);
+                    continue;
+                }
+                _ => {
+                    self.frame_stats.discard("client", kind);
+                    continue;
+                }
+            };
+
+            if chunk.has_remaining() {
+                return Poll::Ready(Some(Ok(chunk)));
+            }
+        }
+    }
+
+    /// Pull ready messages until the read buffer is full, never blocking.
+    fn poll_prefetch(&mut self, cx: &mut std::task::Context<'_>) {
+        while self.chunks.len() < self.read_buffer_messages
+            && self.buffered_len() < MAX_READ_AHEAD_SIZE
+            && self.rx_err.is_none()
+        {
+            match self.poll_recv(cx) {
+                Poll::Ready(Some(Ok(chunk))) => self.chunks.push_back(chunk),
+                Poll::Ready(Some(Err(err))) => self.rx_err = Some(err),
+                // eof is left for the reader to observe
+                _ => return,
+            }
+        }
+    }
+
+    fn poll_duplex(&mut self, cx: &mut std::task::Context<'_>) {
+        if !self.duplex_fairness {
+            return;
+        }
+
+        let waker = duplex_waker(cx.waker(), self.read_waker.as_ref());
+        let mut cx = std::task::Context::from_waker(&waker);
+        let buffered = self.chunks.len();
+        self.poll_prefetch(&mut cx);
+
+        if self.chunks.len() > buffered || self.rx_err.is_some() {
+            if let Some(waker) = self.read_waker.take() {
+                waker.wake();
+            }
         }
     }
 }
 
-impl AsyncBufRead for WebSocketClientStream {
+impl<S> AsyncBufRead for WebSocketClientStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
     fn poll_fill_buf(
         self: Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
     ) -> Poll<std::io::Result<&[u8]>> {
         let this = self.get_mut();
-        loop {
-            if this.has_chunk() {
-                let chunk = this.chunk.as_ref().unwrap();
-                let buf = chunk.chunk();
-                return Poll::Ready(Ok(buf));
-            } else {
-                let chunk = match this.rx.poll_next_unpin(cx) {
-                    Poll::Pending => return Poll::Pending,
-                    Poll::Ready(None) => return Poll::Ready(Ok(&[])),
-                    Poll::Ready(Some(Err(err))) => {
-                        return Poll::Ready(Err(std::io::Error::other(err)))
-                    }
-                    Poll::Ready(Some(Ok(msg))) => match msg {
-                        Message::Binary(data) => Bytes::from(data),
-                        Message::Text(data) => Bytes::from(data),
-                        _ => continue,
-                    },
-                };
+        this.poll_keepalive(cx)?;
+        if let Some(ref slow) = this.slow_consumer {
+            slow.check_read()?;
+        }
+        if !this.chunks.is_empty() {
+            // buffered data reaches no socket, charge it here
+            ready!(this.budget.poll_proceed(cx));
+        }
+        if this.chunks.is_empty() {
+            if let Some(err) = this.rx_err.take() {
+                return Poll::Ready(Err(err));
+            }
 
-                this.chunk = Some(chunk);
+            match this.poll_recv(cx) {
+                Poll::Pending => {
+                    if this.duplex_fairness {
+                        this.read_waker = Some(cx.waker().clone());
+                    }
+                    return Poll::Pending;
+                }
+                Poll::Ready(None) => return Poll::Ready(Ok(&[])),
+                Poll::Ready(Some(Err(err))) => return Poll::Ready(Err(err)),
+                Poll::Ready(Some(Ok(chunk))) => this.chunks.push_back(chunk),
             }
         }
+
+        this.poll_prefetch(cx);
+        Poll::Ready(Ok(this.chunks.front().map_or(&[], |chunk| chunk.chunk())))
     }
 
     fn consume(self: Pin<&mut Self>, amt: usize) {
         if amt > 0 {
-            if let Some(chunk) = self.get_mut().chunk.as_mut() {
+            let this = self.get_mut();
+            if let Some(chunk) = this.chunks.front_mut() {
                 chunk.advance(amt);
+                if !chunk.has_remaining() {
+                    this.chunks.pop_front();
+                }
             }
         }
     }
 }
 
-impl AsyncRead for WebSocketClientStream {
+impl<S> AsyncRead for WebSocketClientStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
     fn poll_read(
         mut self: Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
@@ -204,42 +992,69 @@ impl AsyncRead for WebSocketClientStream {
     }
 }
 
-impl AsyncWrite for WebSocketClientStream {
+crate::timeout_io_methods!(WebSocketClientStream);
+
+impl<S> AsyncWrite for WebSocketClientStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
     fn poll_write(
         self: std::pin::Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
         buf: &[u8],
     ) -> std::task::Poll<Result<usize, std::io::Error>> {
         let this = self.get_mut();
+        let chunk_size = this.write_chunk_size;
+        this.poll_send(cx, || {
+            Bytes::copy_from_slice(wire::write_chunk(buf, chunk_size))
+        })
+    }
 
-        ready!(this
-            .tx
-            .poll_ready_unpin(cx)
-            .map_err(|e| std::io::Error::other(e)))?;
+    /// The slices go out as one Binary message, copied once.
+    fn poll_write_vectored(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        bufs: &[std::io::IoSlice<'_>],
+    ) -> std::task::Poll<Result<usize, std::io::Error>> {
+        let this = self.get_mut();
+        let chunk_size = this.write_chunk_size;
+        this.poll_send(cx, || wire::gather_chunk(bufs, chunk_size).into())
+    }
 
-        match this.tx.start_send_unpin(Message::binary(buf)) {
-            Ok(()) => Poll::Ready(Ok(buf.len())),
-            Err(e) => Poll::Ready(Err(std::io::Error::other(e))),
-        }
+    fn is_write_vectored(&self) -> bool {
+        true
     }
 
     fn poll_flush(
         self: std::pin::Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
     ) -> std::task::Poll<Result<(), std::io::Error>> {
-        self.get_mut()
-            .tx
-            .poll_flush_unpin(cx)
-            .map_err(|e| std::io::Error::other(e))
+        let this = self.get_mut();
+        this.poll_duplex(cx);
+
+        ready!(this.tx.poll_flush_unpin(cx)).map_err(std::io::Error::other)?;
+        if let Some(ref mut slow) = this.slow_consumer {
+            slow.on_flush();
+        }
+        Poll::Ready(Ok(()))
     }
 
     fn poll_shutdown(
         self: std::pin::Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
     ) -> std::task::Poll<Result<(), std::io::Error>> {
-        self.get_mut()
-            .tx
-            .poll_close_unpin(cx)
-            .map_err(|e| std::io::Error::other(e))
+        let this = self.get_mut();
+        if !this.closed {
+            ready!(this.tx.poll_ready_unpin(cx)).map_err(std::io::Error::other)?;
+            let frame = CloseFrame {
+                code: CloseCode::from(close::NORMAL),
+                reason: "".into(),
+            };
+            this.tx
+                .start_send_unpin(Message::Close(Some(frame)))
+                .map_err(std::io::Error::other)?;
+            this.closed = true;
+        }
+        this.tx.poll_close_unpin(cx).map_err(std::io::Error::other)
     }
 }
@@ -0,0 +1,140 @@
+//! WebSocket Server Draining
+
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    task::Context,
+    time::Duration,
+};
+
+use futures_util::task::AtomicWaker;
+use tokio::task::AbortHandle;
+
+use crate::shutdown::{ShutdownReport, TaskGuard, Tasks};
+
+/// What connected clients are told when the server starts draining.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CloseNotice {
+    /// Close frame status, unused by in-band notices.
+    pub code: u16,
+    pub reason: String,
+    /// Time clients get to leave before their connections are dropped.
+    pub deadline: Duration,
+    pub frame: NoticeFrame,
+}
+
+/// How the notice is delivered.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum NoticeFrame {
+    /// A close frame, the callback can still read until the client answers it.
+    #[default]
+    Close,
+    /// A ping carrying the reason, for clients that handle in-band signals.
+    Ping,
+    /// A text message carrying the reason.
+    Text,
+}
+
+/// Drain state of one connection, polled by its stream.
+#[derive(Debug, Default)]
+pub(crate) struct Control {
+    notice: Mutex<Option<CloseNotice>>,
+    waker: AtomicWaker,
+}
+
+impl Control {
+    /// The notice to send, registering for a wake-up until there is one.
+    pub(crate) fn poll_notice(&self, cx: &mut Context<'_>) -> Option<CloseNotice> {
+        self.waker.register(cx.waker());
+        self.notice
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    fn notify(&self, notice: &CloseNotice) {
+        *self.notice.lock().unwrap_or_else(|e| e.into_inner()) = Some(notice.clone());
+        self.waker.wake();
+    }
+}
+
+#[derive(Default)]
+struct Conns {
+    notice: Option<CloseNotice>,
+    controls: HashMap<u64, Arc<Control>>,
+}
+
+/// Upgraded connections of one server, keyed by connection id. The tasks
+/// are tracked by `Tasks`, so a drain ends like a shutdown past its deadline.
+pub(crate) struct Registry {
+    draining: AtomicBool,
+    conns: Mutex<Conns>,
+    tasks: Arc<Tasks>,
+}
+
+impl Default for Registry {
+    fn default() -> Self {
+        Self {
+            draining: AtomicBool::new(false),
+            conns: Mutex::default(),
+            tasks: Tasks::new(),
+        }
+    }
+}
+
+impl Registry {
+    pub(crate) fn draining(&self) -> bool {
+        self.draining.load(Ordering::Acquire)
+    }
+
+    /// Track a connection task until the returned guard drops, notifying it
+    /// right away if a drain already started.
+    pub(crate) fn insert(&self, id: u64, control: Arc<Control>) -> TaskGuard {
+        let mut conns = self.lock();
+        if let Some(ref notice) = conns.notice {
+            control.notify(notice);
+        }
+        conns.controls.insert(id, control);
+        self.tasks.register(id)
+    }
+
+    pub(crate) fn set_abort(&self, id: u64, abort: AbortHandle) {
+        self.tasks.set_abort(id, abort);
+    }
+
+    pub(crate) fn remove(&self, id: u64) {
+        self.lock().controls.remove(&id);
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.tasks.len()
+    }
+
+    /// Notify every connection, then shut the tasks down with the notice
+    /// deadline as the grace period.
+    pub(crate) async fn drain(&self, notice: CloseNotice, kill_grace: Duration) -> ShutdownReport {
+        let grace = notice.deadline;
+        self.draining.store(true, Ordering::Release);
+        {
+            let mut conns = self.lock();
+            for control in conns.controls.values() {
+                control.notify(&notice);
+            }
+            conns.notice = Some(notice);
+        }
+        self.tasks.shutdown(grace, kill_grace).await
+    }
+
+    /// Refuse new upgrades without notifying anyone, then shut the tasks down.
+    pub(crate) async fn shutdown(&self, grace: Duration, kill_grace: Duration) -> ShutdownReport {
+        self.draining.store(true, Ordering::Release);
+        self.tasks.shutdown(grace, kill_grace).await
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Conns> {
+        self.conns.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
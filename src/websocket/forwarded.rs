@@ -0,0 +1,395 @@
+//! Forwarded Headers (RFC 7239)
+
+use std::{
+    fmt::{self, Write},
+    net::{IpAddr, SocketAddr},
+};
+
+use http::{
+    header::{InvalidHeaderValue, FORWARDED},
+    HeaderMap, HeaderName, HeaderValue,
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+pub const X_FORWARDED_FOR: HeaderName = HeaderName::from_static("x-forwarded-for");
+
+/// A `for` node of a forwarded element.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ForwardedNode {
+    Addr(SocketAddr),
+    /// Address without a port, as sent in `X-Forwarded-For`.
+    Ip(IpAddr),
+    /// Identifier standing in for a hidden address, starts with `_`.
+    Obfuscated(String),
+    Unknown,
+}
+
+impl ForwardedNode {
+    /// A stable identifier of `addr` that does not reveal it.
+    pub fn obfuscate(addr: SocketAddr) -> Self {
+        let digest = Sha256::digest(addr.to_string().as_bytes());
+        let mut id = String::from("_");
+        for byte in &digest[..6] {
+            let _ = write!(id, "{:02x}", byte);
+        }
+        Self::Obfuscated(id)
+    }
+
+    pub fn ip(&self) -> Option<IpAddr> {
+        match self {
+            Self::Addr(addr) => Some(addr.ip()),
+            Self::Ip(ip) => Some(*ip),
+            Self::Obfuscated(_) | Self::Unknown => None,
+        }
+    }
+
+    fn parse(value: &str) -> Option<Self> {
+        if value.eq_ignore_ascii_case("unknown") {
+            return Some(Self::Unknown);
+        }
+        if let Some(id) = value.strip_prefix('_') {
+            let valid = !id.is_empty()
+                && id
+                    .bytes()
+                    .all(|b| b.is_ascii_alphanumeric() || b"._-".contains(&b));
+            return valid.then(|| Self::Obfuscated(value.to_owned()));
+        }
+        if let Ok(addr) = value.parse() {
+            return Some(Self::Addr(addr));
+        }
+        let ip = value
+            .strip_prefix('[')
+            .and_then(|v| v.strip_suffix(']'))
+            .unwrap_or(value);
+        ip.parse().ok().map(Self::Ip)
+    }
+}
+
+impl fmt::Display for ForwardedNode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Addr(addr) => write!(f, "{}", addr),
+            Self::Ip(IpAddr::V6(ip)) => write!(f, "[{}]", ip),
+            Self::Ip(IpAddr::V4(ip)) => write!(f, "{}", ip),
+            Self::Obfuscated(id) => f.write_str(id),
+            Self::Unknown => f.write_str("unknown"),
+        }
+    }
+}
+
+/// One hop of a `Forwarded` header.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ForwardedElement {
+    pub for_node: Option<ForwardedNode>,
+    pub host: Option<String>,
+    pub proto: Option<String>,
+}
+
+impl fmt::Display for ForwardedElement {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let pairs = [
+            ("for", self.for_node.as_ref().map(|n| n.to_string())),
+            ("host", self.host.clone()),
+            ("proto", self.proto.clone()),
+        ];
+        let mut sep = "";
+        for (key, value) in pairs {
+            let Some(value) = value else {
+                continue;
+            };
+            write!(f, "{}{}=", sep, key)?;
+            if !value.is_empty() && value.bytes().all(is_token) {
+                f.write_str(&value)?;
+            } else {
+                write!(
+                    f,
+                    "\"{}\"",
+                    value.replace('\\', "\\\\").replace('"', "\\\"")
+                )?;
+            }
+            sep = ";";
+        }
+        Ok(())
+    }
+}
+
+/// RFC 7230 token characters, anything else is sent quoted.
+fn is_token(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b)
+}
+
+/// Split `value` at `sep` outside of quoted strings.
+fn split_unquoted(value: &str, sep: char) -> Vec<&str> {
+    let (mut parts, mut start, mut quoted, mut escaped) = (vec![], 0, false, false);
+    for (i, c) in value.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            c if c == sep && !quoted => {
+                parts.push(&value[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    parts.push(&value[start..]);
+    parts
+}
+
+fn unquote(value: &str) -> String {
+    match value.strip_prefix('"').and_then(|v| v.strip_suffix('"')) {
+        Some(inner) => {
+            let mut out = String::with_capacity(inner.len());
+            let mut chars = inner.chars();
+            while let Some(c) = chars.next() {
+                out.push(if c == '\\' {
+                    chars.next().unwrap_or(c)
+                } else {
+                    c
+                });
+            }
+            out
+        }
+        None => value.to_owned(),
+    }
+}
+
+/// Elements of a `Forwarded` value in hop order, malformed pairs are skipped.
+pub fn parse_forwarded(value: &str) -> Vec<ForwardedElement> {
+    let mut elements = vec![];
+    for element in split_unquoted(value, ',') {
+        let mut parsed = ForwardedElement::default();
+        for pair in split_unquoted(element, ';') {
+            let Some((key, value)) = pair.trim().split_once('=') else {
+                continue;
+            };
+            let value = unquote(value.trim());
+            match key.trim().to_ascii_lowercase().as_str() {
+                "for" => parsed.for_node = ForwardedNode::parse(&value),
+                "host" => parsed.host = Some(value),
+                "proto" => parsed.proto = Some(value.to_ascii_lowercase()),
+                _ => {}
+            }
+        }
+        if parsed != ForwardedElement::default() {
+            elements.push(parsed);
+        }
+    }
+    elements
+}
+
+pub fn format_forwarded(elements: &[ForwardedElement]) -> String {
+    let mut out = String::new();
+    for (i, element) in elements.iter().enumerate() {
+        if i > 0 {
+            out.push_str(", ");
+        }
+        let _ = write!(out, "{}", element);
+    }
+    out
+}
+
+/// Forwarded header fields a ws client sends on the upgrade request.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, rename_all = "snake_case")]
+pub struct ForwardedOption {
+    /// Original client address, sent as this hop's `for`.
+    pub forward_for: Option<SocketAddr>,
+    /// Send a stable `_` identifier instead of the `forward_for` address.
+    pub obfuscate_for: bool,
+    pub forwarded_proto: Option<String>,
+    pub forwarded_host: Option<String>,
+    /// Also send the plain addresses as `X-Forwarded-For`.
+    pub legacy_xff: bool,
+}
+
+impl ForwardedOption {
+    /// The element this hop appends, `None` when no field is set.
+    pub fn element(&self) -> Option<ForwardedElement> {
+        let element = ForwardedElement {
+            for_node: self.forward_for.map(|addr| {
+                if self.obfuscate_for {
+                    ForwardedNode::obfuscate(addr)
+                } else {
+                    ForwardedNode::Addr(addr)
+                }
+            }),
+            host: self.forwarded_host.clone(),
+            proto: self.forwarded_proto.clone(),
+        };
+        (element != ForwardedElement::default()).then_some(element)
+    }
+
+    /// Set the headers for `chain` received from earlier hops plus this hop.
+    pub fn apply(
+        &self,
+        chain: &[ForwardedElement],
+        headers: &mut HeaderMap,
+    ) -> Result<(), InvalidHeaderValue> {
+        let mut elements = chain.to_vec();
+        elements.extend(self.element());
+        if elements.is_empty() {
+            return Ok(());
+        }
+        headers.insert(
+            FORWARDED,
+            HeaderValue::try_from(format_forwarded(&elements))?,
+        );
+
+        if self.legacy_xff {
+            // hidden and unknown nodes keep their place so hop counts still match
+            let xff = elements
+                .iter()
+                .filter_map(|e| e.for_node.as_ref())
+                .map(|node| match node.ip() {
+                    Some(ip) => ip.to_string(),
+                    None => "unknown".to_owned(),
+                })
+                .collect::<Vec<_>>();
+            if !xff.is_empty() {
+                headers.insert(X_FORWARDED_FOR, HeaderValue::try_from(xff.join(", "))?);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Forwarded elements an accepted connection arrived with, inserted by ws
+/// servers set to `trust_forwarded_headers`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ForwardedChain(pub Vec<ForwardedElement>);
+
+impl ForwardedChain {
+    /// From `Forwarded`, falling back to `X-Forwarded-For` when absent.
+    pub fn from_headers(headers: &HeaderMap) -> Self {
+        let forwarded = headers
+            .get_all(FORWARDED)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(parse_forwarded)
+            .collect::<Vec<_>>();
+        if !forwarded.is_empty() {
+            return Self(forwarded);
+        }
+
+        let xff = headers
+            .get_all(X_FORWARDED_FOR)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .filter_map(|ip| ForwardedNode::parse(ip.trim()))
+            .map(|node| ForwardedElement {
+                for_node: Some(node),
+                ..Default::default()
+            })
+            .collect();
+        Self(xff)
+    }
+
+    /// The original client, the `for` of the first hop.
+    pub fn client(&self) -> Option<&ForwardedNode> {
+        self.0.first().and_then(|e| e.for_node.as_ref())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_forwarded_format() {
+        let elements = [
+            ForwardedElement {
+                for_node: Some(ForwardedNode::Addr(
+                    "[2001:db8:cafe::17]:4711".parse().unwrap(),
+                )),
+                host: None,
+                proto: Some("https".into()),
+            },
+            ForwardedElement {
+                for_node: Some(ForwardedNode::Ip("192.0.2.60".parse().unwrap())),
+                host: Some("example.com".into()),
+                proto: None,
+            },
+            ForwardedElement {
+                for_node: Some(ForwardedNode::Obfuscated("_hidden".into())),
+                ..Default::default()
+            },
+        ];
+        let value = format_forwarded(&elements);
+        assert_eq!(
+            value,
+            r#"for="[2001:db8:cafe::17]:4711";proto=https, for=192.0.2.60;host=example.com, for=_hidden"#
+        );
+        assert_eq!(parse_forwarded(&value), elements);
+    }
+
+    #[test]
+    fn test_forwarded_parse() {
+        let parsed = parse_forwarded(
+            r#"For="[2001:db8:cafe::17]";proto=HTTP, for=unknown;by=10.0.0.1, for=_x;bogus, ;"#,
+        );
+        assert_eq!(
+            parsed
+                .iter()
+                .map(|e| e.for_node.clone())
+                .collect::<Vec<_>>(),
+            vec![
+                Some(ForwardedNode::Ip("2001:db8:cafe::17".parse().unwrap())),
+                Some(ForwardedNode::Unknown),
+                Some(ForwardedNode::Obfuscated("_x".into())),
+            ]
+        );
+        assert_eq!(parsed[0].proto.as_deref(), Some("http"));
+        // a comma inside quotes does not split elements
+        let parsed = parse_forwarded(r#"host="a,b";for=_y"#);
+        assert_eq!(parsed.len(), 1);
+        assert_eq!(parsed[0].host.as_deref(), Some("a,b"));
+        assert!(parse_forwarded("for=_").is_empty());
+    }
+
+    #[test]
+    fn test_forwarded_apply() {
+        let addr: SocketAddr = "192.0.2.43:47011".parse().unwrap();
+        let opt = ForwardedOption {
+            forward_for: Some(addr),
+            forwarded_proto: Some("wss".into()),
+            legacy_xff: true,
+            ..Default::default()
+        };
+        let chain = parse_forwarded("for=198.51.100.17, for=_gw");
+        let mut headers = HeaderMap::new();
+        opt.apply(&chain, &mut headers).unwrap();
+        assert_eq!(
+            headers[FORWARDED],
+            r#"for=198.51.100.17, for=_gw, for="192.0.2.43:47011";proto=wss"#
+        );
+        assert_eq!(
+            headers[X_FORWARDED_FOR],
+            "198.51.100.17, unknown, 192.0.2.43"
+        );
+        assert_eq!(
+            ForwardedChain::from_headers(&headers).client(),
+            Some(&ForwardedNode::Ip("198.51.100.17".parse().unwrap()))
+        );
+
+        // hidden addresses get the same identifier on every connect
+        let hidden = ForwardedOption {
+            obfuscate_for: true,
+            ..opt
+        };
+        let node = hidden.element().unwrap().for_node.unwrap();
+        assert_eq!(node, ForwardedNode::obfuscate(addr));
+        assert!(!node.to_string().contains("192.0.2.43"));
+
+        // without forwarded headers only x-forwarded-for is read
+        let mut headers = HeaderMap::new();
+        headers.insert(X_FORWARDED_FOR, "203.0.113.7, 10.0.0.1".parse().unwrap());
+        let chain = ForwardedChain::from_headers(&headers);
+        assert_eq!(chain.0.len(), 2);
+        assert_eq!(chain.client().unwrap().ip(), "203.0.113.7".parse().ok());
+        assert!(ForwardedOption::default().element().is_none());
+    }
+}
@@ -0,0 +1,88 @@
+//! Received Frame Accounting
+
+use std::fmt;
+
+/// Message types as counted by `FrameStats`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum FrameKind {
+    Binary,
+    Text,
+    Ping,
+    Pong,
+    Close,
+    /// A raw frame surfaced without a message type.
+    Other,
+}
+
+impl fmt::Display for FrameKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Binary => "binary",
+            Self::Text => "text",
+            Self::Ping => "ping",
+            Self::Pong => "pong",
+            Self::Close => "close",
+            Self::Other => "other",
+        })
+    }
+}
+
+/// Messages of one kind and the sum of their payload lengths.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FrameCount {
+    pub messages: u64,
+    pub bytes: u64,
+}
+
+/// Per stream counts of every received message, kept or skipped.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FrameStats {
+    pub binary: FrameCount,
+    pub text: FrameCount,
+    pub ping: FrameCount,
+    pub pong: FrameCount,
+    pub close: FrameCount,
+    pub other: FrameCount,
+    /// Messages the reader never saw.
+    pub discarded: u64,
+}
+
+impl FrameStats {
+    pub fn get(&self, kind: FrameKind) -> FrameCount {
+        match kind {
+            FrameKind::Binary => self.binary,
+            FrameKind::Text => self.text,
+            FrameKind::Ping => self.ping,
+            FrameKind::Pong => self.pong,
+            FrameKind::Close => self.close,
+            FrameKind::Other => self.other,
+        }
+    }
+
+    pub(crate) fn record(&mut self, kind: FrameKind, len: usize) {
+        let count = match kind {
+            FrameKind::Binary => &mut self.binary,
+            FrameKind::Text => &mut self.text,
+            FrameKind::Ping => &mut self.ping,
+            FrameKind::Pong => &mut self.pong,
+            FrameKind::Close => &mut self.close,
+            FrameKind::Other => &mut self.other,
+        };
+        count.messages += 1;
+        count.bytes += len as u64;
+    }
+
+    /// Count a message skipped by the reader, logging the 1st, 2nd, 4th, ... one.
+    pub(crate) fn discard(&mut self, side: &str, kind: FrameKind) {
+        self.discarded += 1;
+        // close ends the stream, the rest may be a proxy injecting frames
+        if kind != FrameKind::Close && self.discarded.is_power_of_two() {
+            log::debug!(
+                "ws {} discarded a {} frame, {} discarded so far",
+                side,
+                kind,
+                self.discarded
+            );
+        }
+    }
+}
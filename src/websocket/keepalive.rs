@@ -0,0 +1,190 @@
+//! WebSocket Ping Keepalive
+//!
+//! Driven from the read and write paths of a stream, with no task of its
+//! own. A stream only read from still sends its Pings, the timer wakes the
+//! reader. Anything received from the peer counts as a sign of life, so
+//! Pings only go out on connections idle for the interval.
+
+use std::{
+    future::Future,
+    io::{Error, ErrorKind},
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use futures_util::{Sink, SinkExt};
+
+use crate::clock::{Clock, TokioClock};
+
+pub(crate) struct PingKeepalive<C: Clock = TokioClock> {
+    interval: Duration,
+    timeout: Duration,
+    clock: C,
+    timer: Pin<Box<C::Sleep>>,
+    seq: u64,
+    /// Payload of the Ping waiting for its Pong.
+    outstanding: Option<Vec<u8>>,
+    /// A Ping not yet taken by the sink.
+    queued: Option<Vec<u8>>,
+    flushing: bool,
+    /// A message arrived since the timer was last armed.
+    traffic: bool,
+}
+
+impl PingKeepalive {
+    pub(crate) fn new(interval: Duration, timeout: Duration) -> Self {
+        Self::with_clock(interval, timeout, TokioClock)
+    }
+}
+
+impl<C: Clock> PingKeepalive<C> {
+    pub(crate) fn with_clock(interval: Duration, timeout: Duration, clock: C) -> Self {
+        Self {
+            interval,
+            timeout,
+            timer: Box::pin(clock.sleep(interval)),
+            clock,
+            seq: 0,
+            outstanding: None,
+            queued: None,
+            flushing: false,
+            traffic: false,
+        }
+    }
+
+    /// Ping payload due now, if any, or the timeout of the outstanding one.
+    fn poll_due(&mut self, cx: &mut Context<'_>) -> std::io::Result<Option<Vec<u8>>> {
+        if let Some(payload) = self.queued.take() {
+            return Ok(Some(payload));
+        }
+        if self.timer.as_mut().poll(cx).is_pending() {
+            return Ok(None);
+        }
+        if std::mem::take(&mut self.traffic) {
+            // the peer is alive, whether or not it answered the Ping
+            self.outstanding = None;
+            self.reset(self.interval);
+            let _ = self.timer.as_mut().poll(cx);
+            return Ok(None);
+        }
+        if self.outstanding.is_some() {
+            return Err(Error::new(
+                ErrorKind::TimedOut,
+                "ws keepalive pong timed out",
+            ));
+        }
+
+        self.seq += 1;
+        let payload = format!("kapibara-keepalive-{}", self.seq).into_bytes();
+        self.outstanding = Some(payload.clone());
+        self.reset(self.timeout);
+        // registers the timer for the pong deadline
+        let _ = self.timer.as_mut().poll(cx);
+        Ok(Some(payload))
+    }
+
+    /// Note a message from the peer, checked lazily when the timer fires.
+    pub(crate) fn on_traffic(&mut self) {
+        self.traffic = true;
+    }
+
+    /// Check a received Pong, `InvalidData` when it does not answer our Ping.
+    ///
+    /// Pongs with no Ping outstanding are unsolicited heartbeats and pass.
+    pub(crate) fn on_pong(&mut self, data: &[u8]) -> std::io::Result<()> {
+        match self.outstanding.as_deref() {
+            None => Ok(()),
+            Some(payload) if payload == data => {
+                self.outstanding = None;
+                self.traffic = false;
+                self.reset(self.interval);
+                Ok(())
+            }
+            Some(_) => Err(Error::new(
+                ErrorKind::InvalidData,
+                "ws keepalive pong payload does not match the ping",
+            )),
+        }
+    }
+
+    /// Send a due Ping through `sink`, polled from both the read and write paths.
+    pub(crate) fn poll_send<S, M>(
+        &mut self,
+        sink: &mut S,
+        ping: impl FnOnce(Vec<u8>) -> M,
+        cx: &mut Context<'_>,
+    ) -> std::io::Result<()>
+    where
+        S: Sink<M> + Unpin,
+        S::Error: std::error::Error + Send + Sync + 'static,
+    {
+        if let Some(payload) = self.poll_due(cx)? {
+            match sink.poll_ready_unpin(cx) {
+                Poll::Ready(Ok(())) => {
+                    sink.start_send_unpin(ping(payload)).map_err(Error::other)?;
+                    self.flushing = true;
+                }
+                Poll::Ready(Err(e)) => return Err(Error::other(e)),
+                // retried on the next poll
+                Poll::Pending => self.queued = Some(payload),
+            }
+        }
+        if self.flushing {
+            match sink.poll_flush_unpin(cx) {
+                Poll::Ready(Ok(())) => self.flushing = false,
+                Poll::Ready(Err(e)) => return Err(Error::other(e)),
+                Poll::Pending => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn reset(&mut self, after: Duration) {
+        self.clock
+            .reset(self.timer.as_mut(), self.clock.now() + after);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::task::noop_waker_ref;
+
+    use super::*;
+    use crate::clock::ManualClock;
+
+    #[test]
+    fn test_keepalive_manual_clock() {
+        let clock = ManualClock::new();
+        let mut keepalive = PingKeepalive::with_clock(
+            Duration::from_secs(10),
+            Duration::from_secs(5),
+            clock.clone(),
+        );
+        let mut cx = Context::from_waker(noop_waker_ref());
+        let mut sent: Vec<Vec<u8>> = vec![];
+        let mut poll = |keepalive: &mut PingKeepalive<ManualClock>, sent: &mut Vec<Vec<u8>>| {
+            keepalive.poll_send(sent, |payload| payload, &mut cx)
+        };
+
+        poll(&mut keepalive, &mut sent).unwrap();
+        assert!(sent.is_empty());
+        clock.advance(Duration::from_secs(10));
+        poll(&mut keepalive, &mut sent).unwrap();
+        assert_eq!(sent.len(), 1);
+
+        // answered, the next Ping is an interval later
+        keepalive.on_pong(&sent[0].clone()).unwrap();
+        clock.advance(Duration::from_secs(9));
+        poll(&mut keepalive, &mut sent).unwrap();
+        assert_eq!(sent.len(), 1);
+        clock.advance(Duration::from_secs(1));
+        poll(&mut keepalive, &mut sent).unwrap();
+        assert_eq!(sent.len(), 2);
+
+        // unanswered, the stream fails a timeout later
+        clock.advance(Duration::from_secs(5));
+        let err = poll(&mut keepalive, &mut sent).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::TimedOut);
+    }
+}
@@ -1,23 +1,111 @@
 //! WebSocket Transport
 
 pub mod option;
-pub use option::{WebSocketClientOption, WebSocketServerOption};
+pub use option::{
+    Fallback, PathMatch, SlowConsumerAction, SlowConsumerOption, WebSocketClientOption,
+    WebSocketServerOption, WsAcceptOption,
+};
+
+pub mod drain;
+pub use drain::{CloseNotice, NoticeFrame};
 
 pub mod server;
-pub use server::{WebSocketServer, WebSocketServerStream};
+pub use server::{accept_stream, WebSocketServer, WebSocketServerStream};
 
 pub mod client;
-pub use client::{WebSocketClient, WebSocketClientStream};
+pub use client::{connect_stream, ConnectParams, WebSocketClient, WebSocketClientStream};
+
+pub mod forwarded;
+pub use forwarded::{ForwardedChain, ForwardedOption};
+
+pub mod frames;
+pub use frames::{FrameCount, FrameKind, FrameStats};
+
+mod keepalive;
+mod slow;
+mod socket;
+
+pub mod wire;
+
+use std::{
+    sync::{Arc, Mutex},
+    task::{Wake, Waker},
+};
+
+/// Bytes a stream buffers for a peer that does not read, a write that
+/// would go over fails instead of growing the buffer.
+pub const MAX_WRITE_BUFFER_SIZE: usize = 64 << 20;
+
+/// Bytes prefetched ahead of the reader, past it messages wait in the socket
+/// until the reader catches up, whatever `read_buffer_messages` allows.
+pub const MAX_READ_AHEAD_SIZE: usize = 1 << 20;
+
+/// Close frame status sent or received on a websocket connection.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CloseReason {
+    pub code: u16,
+    pub reason: String,
+}
+
+tokio::task_local! {
+    static CLOSE_REASON: Arc<Mutex<Option<CloseReason>>>;
+}
+
+/// Set the close frame sent when a ws server callback returns without
+/// shutting the stream down. Only effective on the callback's own task,
+/// returns `false` elsewhere.
+pub fn set_close_reason(code: u16, reason: impl Into<String>) -> bool {
+    CLOSE_REASON
+        .try_with(|slot| {
+            *slot.lock().unwrap_or_else(|e| e.into_inner()) = Some(CloseReason {
+                code,
+                reason: reason.into(),
+            })
+        })
+        .is_ok()
+}
+
+/// Forwards a wake-up to both the writing task and a parked reader.
+struct DuplexWaker {
+    write: Waker,
+    read: Waker,
+}
+
+impl Wake for DuplexWaker {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.write.wake_by_ref();
+        self.read.wake_by_ref();
+    }
+}
+
+fn duplex_waker(write: &Waker, read: Option<&Waker>) -> Waker {
+    match read {
+        Some(read) if !read.will_wake(write) => Arc::new(DuplexWaker {
+            write: write.clone(),
+            read: read.clone(),
+        })
+        .into(),
+        _ => write.clone(),
+    }
+}
 
 #[cfg(test)]
 mod tests {
+    // helpers shared with the tests needing the insecure verifier
+    #![cfg_attr(not(feature = "insecure-tls"), allow(dead_code, unused_imports))]
+
     use std::time::Duration;
 
-    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use bytes::Bytes;
+    use tokio::io::{AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
     use crate::{
-        Resolver, TlsCertOption, TlsClientOption, TlsServerOption, TransportClientTrait,
-        TransportServerCallback, TransportServerTrait,
+        tls::DEFAULT_SESSION_CACHE_SIZE, Resolver, TlsCertOption, TlsClientOption, TlsServerOption,
+        TransportClientTrait, TransportServerCallback, TransportServerTrait,
     };
 
     use super::*;
@@ -46,21 +134,50 @@ mod tests {
         }
     }
 
+    #[cfg(feature = "insecure-tls")]
     #[tokio::test]
     async fn test_ws_client() {
         tokio::spawn(async move {
             let opt = WebSocketServerOption {
                 listen: "127.0.0.1:9876".parse().unwrap(),
                 path: "/test".into(),
+                paths: vec![],
+                path_match: Default::default(),
+                fallback: Default::default(),
                 tcp_nodelay: true,
+                duplex_fairness: false,
+                read_buffer_messages: 1,
+                validate_text: false,
+                text_to_bytes: true,
+                max_message_size: wire::MAX_MESSAGE_SIZE,
+                max_frame_size: wire::MAX_FRAME_SIZE,
+                write_chunk_size: None,
+                max_bytes: None,
+                rate_limit: None,
+                max_connections: None,
+                idle_reap: None,
+                idle_timeout: None,
+                trust_forwarded_headers: false,
+                keepalive_interval: None,
+                keepalive_timeout: Duration::from_secs(30),
+                slow_consumer_policy: None,
             };
 
             let tls_opt = TlsServerOption {
                 alpn: vec![],
+                require_alpn: false,
+                ticket_keys: None,
                 certificate: TlsCertOption::File {
                     cert: "certs/test.crt".into(),
                     key: "certs/test.key".into(),
+                    watch: false,
                 },
+                client_ca: None,
+                require_client_cert: false,
+                session_tickets: false,
+                min_version: None,
+                max_version: None,
+                cipher_suites: vec![],
             };
 
             let srv = WebSocketServer::init(opt, Some(tls_opt)).unwrap();
@@ -69,12 +186,33 @@ mod tests {
                 panic!("{}", err);
             }
         });
+        tokio::time::sleep(Duration::from_millis(50)).await;
 
         let opt = WebSocketClientOption {
             addr: "127.0.0.1".into(),
             port: 9876,
             path: "/test".into(),
             tcp_nodelay: false,
+            duplex_fairness: false,
+            read_buffer_messages: 1,
+            validate_text: false,
+            text_to_bytes: true,
+            max_message_size: wire::MAX_MESSAGE_SIZE,
+            max_frame_size: wire::MAX_FRAME_SIZE,
+            write_chunk_size: None,
+            forwarded: ForwardedOption::default(),
+            headers: vec![],
+            host: None,
+            keepalive_interval: None,
+            keepalive_timeout: Duration::from_secs(30),
+            slow_consumer_policy: None,
+            connect_timeout: None,
+            connect_deadline: None,
+            attempt_delay: None,
+            resolve_on_connect: false,
+            proxy: None,
+            bind_addr: None,
+            bind_device: None,
         };
 
         let tls_opt = TlsClientOption {
@@ -82,6 +220,17 @@ mod tests {
             alpn: vec![],
             enable_sni: false,
             server_name: String::new(),
+            on_first_seen: None,
+            cache: true,
+            certificate: None,
+            session_cache_size: DEFAULT_SESSION_CACHE_SIZE,
+            enable_early_data: false,
+            min_version: None,
+            max_version: None,
+            cipher_suites: vec![],
+            ech_config: None,
+            ech_grease: false,
+            require_ech: false,
         };
 
         let resolver = Resolver::default();
@@ -99,4 +248,2775 @@ mod tests {
 
         tokio::time::sleep(Duration::from_millis(100)).await;
     }
+
+    #[derive(Debug, Clone)]
+    struct ReverseServerCallback;
+
+    impl TransportServerCallback for ReverseServerCallback {
+        async fn handle<S>(&self, mut stream: S, _addr: Option<std::net::SocketAddr>)
+        where
+            S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + Sync,
+        {
+            let mut buf = [0u8; 1024];
+            let mut total = 0;
+            let mut replied = false;
+            while let Ok(n) = stream.read(&mut buf).await {
+                if n == 0 {
+                    break;
+                }
+                total += n;
+                if !replied && total >= 64 * 1024 {
+                    stream.write_all(b"reverse").await.unwrap();
+                    stream.flush().await.unwrap();
+                    replied = true;
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_ws_duplex_fairness() {
+        tokio::spawn(async move {
+            let opt = WebSocketServerOption {
+                listen: "127.0.0.1:9877".parse().unwrap(),
+                path: "/fair".into(),
+                paths: vec![],
+                path_match: Default::default(),
+                fallback: Default::default(),
+                tcp_nodelay: true,
+                duplex_fairness: false,
+                read_buffer_messages: 1,
+                validate_text: false,
+                text_to_bytes: true,
+                max_message_size: wire::MAX_MESSAGE_SIZE,
+                max_frame_size: wire::MAX_FRAME_SIZE,
+                write_chunk_size: None,
+                max_bytes: None,
+                rate_limit: None,
+                max_connections: None,
+                idle_reap: None,
+                idle_timeout: None,
+                trust_forwarded_headers: false,
+                keepalive_interval: None,
+                keepalive_timeout: Duration::from_secs(30),
+                slow_consumer_policy: None,
+            };
+
+            let srv = WebSocketServer::init(opt, None).unwrap();
+            srv.serve(ReverseServerCallback).await.unwrap();
+        });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let opt = WebSocketClientOption {
+            addr: "127.0.0.1".into(),
+            port: 9877,
+            path: "/fair".into(),
+            tcp_nodelay: true,
+            duplex_fairness: true,
+            read_buffer_messages: 1,
+            validate_text: false,
+            text_to_bytes: true,
+            max_message_size: wire::MAX_MESSAGE_SIZE,
+            max_frame_size: wire::MAX_FRAME_SIZE,
+            write_chunk_size: None,
+            forwarded: ForwardedOption::default(),
+            headers: vec![],
+            host: None,
+            keepalive_interval: None,
+            keepalive_timeout: Duration::from_secs(30),
+            slow_consumer_policy: None,
+            connect_timeout: None,
+            connect_deadline: None,
+            attempt_delay: None,
+            resolve_on_connect: false,
+            proxy: None,
+            bind_addr: None,
+            bind_device: None,
+        };
+
+        let resolver = Resolver::default();
+        let cli = WebSocketClient::init(opt, None, &resolver).unwrap();
+        let mut ws_stream = cli.connect().await.unwrap();
+
+        // write-only traffic must still pick up the reverse message
+        let data = b"w".repeat(1024);
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while ws_stream.buffered_len() == 0 {
+                ws_stream.write_all(&data).await.unwrap();
+                ws_stream.flush().await.unwrap();
+                tokio::task::yield_now().await;
+            }
+        })
+        .await
+        .expect("reverse message stalled behind writes");
+
+        let mut buf = [0u8; 16];
+        let n = ws_stream.read(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], b"reverse");
+    }
+
+    #[tokio::test]
+    async fn test_ws_write_buffer_bounded() {
+        use futures_util::FutureExt;
+
+        #[derive(Debug, Clone)]
+        struct StalledCallback;
+
+        impl TransportServerCallback for StalledCallback {
+            async fn handle<S>(&self, _stream: S, _addr: Option<std::net::SocketAddr>)
+            where
+                S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + Sync,
+            {
+                tokio::time::sleep(Duration::from_secs(10)).await;
+            }
+        }
+
+        tokio::spawn(async move {
+            let opt = WebSocketServerOption {
+                listen: "127.0.0.1:9921".parse().unwrap(),
+                path: "/stalled".into(),
+                tcp_nodelay: true,
+                duplex_fairness: true,
+                read_buffer_messages: 1,
+                validate_text: false,
+                text_to_bytes: true,
+                max_message_size: wire::MAX_MESSAGE_SIZE,
+                max_frame_size: wire::MAX_FRAME_SIZE,
+                max_bytes: None,
+                max_connections: None,
+                idle_reap: None,
+                trust_forwarded_headers: false,
+                keepalive_interval: None,
+                keepalive_timeout: Duration::from_secs(30),
+                slow_consumer_policy: None,
+                fallback: Default::default(),
+                path_match: Default::default(),
+                paths: vec![],
+                idle_timeout: None,
+                write_chunk_size: None,
+                rate_limit: None,
+            };
+            let srv = WebSocketServer::init(opt, None).unwrap();
+            srv.serve(StalledCallback).await.unwrap();
+        });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let opt = WebSocketClientOption {
+            addr: "127.0.0.1".into(),
+            port: 9921,
+            path: "/stalled".into(),
+            tcp_nodelay: true,
+            duplex_fairness: true,
+            read_buffer_messages: 1,
+            validate_text: false,
+            text_to_bytes: true,
+            max_message_size: wire::MAX_MESSAGE_SIZE,
+            max_frame_size: wire::MAX_FRAME_SIZE,
+            forwarded: ForwardedOption::default(),
+            keepalive_interval: None,
+            keepalive_timeout: Duration::from_secs(30),
+            slow_consumer_policy: None,
+            connect_deadline: None,
+            connect_timeout: None,
+            attempt_delay: None,
+            headers: vec![],
+            host: None,
+            resolve_on_connect: false,
+            proxy: None,
+            write_chunk_size: None,
+            bind_addr: None,
+            bind_device: None,
+        };
+        let cli = WebSocketClient::init(opt, None, &Resolver::default()).unwrap();
+        let mut ws_stream = cli.connect().await.unwrap();
+
+        // the server never reads, so once the socket is full writes stay pending
+        let data = vec![0u8; 64 * 1024];
+        let mut accepted = 0;
+        while let Some(res) = ws_stream.write(&data).now_or_never() {
+            accepted += res.unwrap();
+            assert!(
+                accepted <= super::MAX_WRITE_BUFFER_SIZE + data.len(),
+                "{} accepted",
+                accepted
+            );
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(ws_stream.write(&data).now_or_never().is_none());
+    }
+
+    const SMALL_MESSAGES: u32 = 2000;
+
+    #[derive(Debug, Clone)]
+    struct SmallMessageCallback;
+
+    impl TransportServerCallback for SmallMessageCallback {
+        async fn handle<S>(&self, mut stream: S, _addr: Option<std::net::SocketAddr>)
+        where
+            S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + Sync,
+        {
+            for i in 0..SMALL_MESSAGES {
+                let mut msg = [0u8; 64];
+                msg[..4].copy_from_slice(&i.to_be_bytes());
+                stream.write_all(&msg).await.unwrap();
+            }
+            stream.flush().await.unwrap();
+            stream.shutdown().await.unwrap();
+        }
+    }
+
+    fn spawn_small_message_server(port: u16) {
+        tokio::spawn(async move {
+            let opt = WebSocketServerOption {
+                listen: ([127, 0, 0, 1], port).into(),
+                path: "/small".into(),
+                paths: vec![],
+                path_match: Default::default(),
+                fallback: Default::default(),
+                tcp_nodelay: true,
+                duplex_fairness: false,
+                read_buffer_messages: 1,
+                validate_text: false,
+                text_to_bytes: true,
+                max_message_size: wire::MAX_MESSAGE_SIZE,
+                max_frame_size: wire::MAX_FRAME_SIZE,
+                write_chunk_size: None,
+                max_bytes: None,
+                rate_limit: None,
+                max_connections: None,
+                idle_reap: None,
+                idle_timeout: None,
+                trust_forwarded_headers: false,
+                keepalive_interval: None,
+                keepalive_timeout: Duration::from_secs(30),
+                slow_consumer_policy: None,
+            };
+
+            let srv = WebSocketServer::init(opt, None).unwrap();
+            srv.serve(SmallMessageCallback).await.unwrap();
+        });
+    }
+
+    async fn read_small_messages(port: u16, read_buffer_messages: usize) -> Duration {
+        let opt = WebSocketClientOption {
+            addr: "127.0.0.1".into(),
+            port,
+            path: "/small".into(),
+            tcp_nodelay: true,
+            duplex_fairness: false,
+            read_buffer_messages,
+            validate_text: false,
+            text_to_bytes: true,
+            max_message_size: wire::MAX_MESSAGE_SIZE,
+            max_frame_size: wire::MAX_FRAME_SIZE,
+            write_chunk_size: None,
+            forwarded: ForwardedOption::default(),
+            headers: vec![],
+            host: None,
+            keepalive_interval: None,
+            keepalive_timeout: Duration::from_secs(30),
+            slow_consumer_policy: None,
+            connect_timeout: None,
+            connect_deadline: None,
+            attempt_delay: None,
+            resolve_on_connect: false,
+            proxy: None,
+            bind_addr: None,
+            bind_device: None,
+        };
+
+        let cli = WebSocketClient::init(opt, None, &Resolver::default()).unwrap();
+        let mut ws_stream = cli.connect().await.unwrap();
+
+        let start = std::time::Instant::now();
+        let mut msg = [0u8; 64];
+        for i in 0..SMALL_MESSAGES {
+            ws_stream.read_exact(&mut msg).await.unwrap();
+            assert_eq!(msg[..4], i.to_be_bytes());
+            tokio::task::yield_now().await;
+        }
+        assert_eq!(ws_stream.read(&mut msg).await.unwrap(), 0);
+
+        start.elapsed()
+    }
+
+    #[tokio::test]
+    async fn test_ws_read_buffer_messages() {
+        spawn_small_message_server(9878);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        read_small_messages(9878, 4).await;
+    }
+
+    #[derive(Debug, Clone)]
+    struct LargeMessageCallback;
+
+    impl TransportServerCallback for LargeMessageCallback {
+        async fn handle<S>(&self, mut stream: S, _addr: Option<std::net::SocketAddr>)
+        where
+            S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + Sync,
+        {
+            let msg = vec![0u8; 64 * 1024];
+            for _ in 0..64 {
+                stream.write_all(&msg).await.unwrap();
+            }
+            stream.flush().await.unwrap();
+            tokio::time::sleep(Duration::from_secs(10)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_ws_read_buffer_bounded() {
+        tokio::spawn(async move {
+            let opt = WebSocketServerOption {
+                listen: "127.0.0.1:9922".parse().unwrap(),
+                path: "/large".into(),
+                tcp_nodelay: true,
+                duplex_fairness: false,
+                read_buffer_messages: 1,
+                validate_text: false,
+                text_to_bytes: true,
+                max_message_size: wire::MAX_MESSAGE_SIZE,
+                max_frame_size: wire::MAX_FRAME_SIZE,
+                max_bytes: None,
+                max_connections: None,
+                idle_reap: None,
+                trust_forwarded_headers: false,
+                keepalive_interval: None,
+                keepalive_timeout: Duration::from_secs(30),
+                slow_consumer_policy: None,
+                fallback: Default::default(),
+                path_match: Default::default(),
+                paths: vec![],
+                idle_timeout: None,
+                write_chunk_size: None,
+                rate_limit: None,
+            };
+            let srv = WebSocketServer::init(opt, None).unwrap();
+            srv.serve(LargeMessageCallback).await.unwrap();
+        });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let opt = WebSocketClientOption {
+            addr: "127.0.0.1".into(),
+            port: 9922,
+            path: "/large".into(),
+            tcp_nodelay: true,
+            duplex_fairness: false,
+            read_buffer_messages: 1000,
+            validate_text: false,
+            text_to_bytes: true,
+            max_message_size: wire::MAX_MESSAGE_SIZE,
+            max_frame_size: wire::MAX_FRAME_SIZE,
+            forwarded: ForwardedOption::default(),
+            keepalive_interval: None,
+            keepalive_timeout: Duration::from_secs(30),
+            slow_consumer_policy: None,
+            connect_deadline: None,
+            connect_timeout: None,
+            attempt_delay: None,
+            headers: vec![],
+            host: None,
+            resolve_on_connect: false,
+            proxy: None,
+            write_chunk_size: None,
+            bind_addr: None,
+            bind_device: None,
+        };
+        let cli = WebSocketClient::init(opt, None, &Resolver::default()).unwrap();
+        let mut ws_stream = cli.connect().await.unwrap();
+
+        // a slow reader, the prefetch stops at the byte cap
+        let mut buf = [0u8; 1];
+        let mut buffered = 0;
+        for _ in 0..20 {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            ws_stream.read_exact(&mut buf).await.unwrap();
+            buffered = ws_stream.buffered_len();
+            assert!(buffered < super::MAX_READ_AHEAD_SIZE + 64 * 1024);
+        }
+        assert!(
+            buffered >= super::MAX_READ_AHEAD_SIZE,
+            "{} buffered",
+            buffered
+        );
+    }
+
+    /// Relays `listen` to `target`, adding latency on the upstream leg.
+    fn spawn_chaos_relay(listen: u16, target: u16, latency: Duration) {
+        use crate::chaos::{ChaosOption, ChaosStream};
+
+        tokio::spawn(async move {
+            let listener = tokio::net::TcpListener::bind(("127.0.0.1", listen))
+                .await
+                .unwrap();
+            loop {
+                let (mut down, _) = listener.accept().await.unwrap();
+                let up = tokio::net::TcpStream::connect(("127.0.0.1", target))
+                    .await
+                    .unwrap();
+                let opt = ChaosOption {
+                    io_latency: latency,
+                    ..Default::default()
+                };
+                let mut up = ChaosStream::new(up, opt, 0);
+                tokio::spawn(async move {
+                    let _ = tokio::io::copy_bidirectional(&mut down, &mut up).await;
+                });
+            }
+        });
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore = "benchmark, run with --ignored --nocapture"]
+    async fn bench_ws_read_buffer_messages() {
+        spawn_small_message_server(9880);
+        spawn_chaos_relay(9881, 9880, Duration::from_millis(1));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        for read_buffer_messages in [1, 4] {
+            let elapsed = read_small_messages(9881, read_buffer_messages).await;
+            println!(
+                "read_buffer_messages={} {} messages in {:?} ({:.0} msg/s)",
+                read_buffer_messages,
+                SMALL_MESSAGES,
+                elapsed,
+                SMALL_MESSAGES as f64 / elapsed.as_secs_f64()
+            );
+        }
+    }
+
+    #[derive(Debug, Clone)]
+    struct EarlyCloseCallback(Option<u16>);
+
+    impl TransportServerCallback for EarlyCloseCallback {
+        async fn handle<S>(&self, _stream: S, _addr: Option<std::net::SocketAddr>)
+        where
+            S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + Sync,
+        {
+            if let Some(code) = self.0 {
+                assert!(set_close_reason(code, "auth failed"));
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_ws_close_reason() {
+        for (port, code) in [(9882, Some(4001)), (9883, None)] {
+            tokio::spawn(async move {
+                let opt = WebSocketServerOption {
+                    listen: ([127, 0, 0, 1], port).into(),
+                    path: "/close".into(),
+                    paths: vec![],
+                    path_match: Default::default(),
+                    fallback: Default::default(),
+                    tcp_nodelay: true,
+                    duplex_fairness: false,
+                    read_buffer_messages: 1,
+                    validate_text: false,
+                    text_to_bytes: true,
+                    max_message_size: wire::MAX_MESSAGE_SIZE,
+                    max_frame_size: wire::MAX_FRAME_SIZE,
+                    write_chunk_size: None,
+                    max_bytes: None,
+                    rate_limit: None,
+                    max_connections: None,
+                    idle_reap: None,
+                    idle_timeout: None,
+                    trust_forwarded_headers: false,
+                    keepalive_interval: None,
+                    keepalive_timeout: Duration::from_secs(30),
+                    slow_consumer_policy: None,
+                };
+
+                let srv = WebSocketServer::init(opt, None).unwrap();
+                srv.serve(EarlyCloseCallback(code)).await.unwrap();
+            });
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(!set_close_reason(4000, "outside callback"));
+
+        for (port, expect) in [(9882, (4001, "auth failed")), (9883, (1011, ""))] {
+            let opt = WebSocketClientOption {
+                addr: "127.0.0.1".into(),
+                port,
+                path: "/close".into(),
+                tcp_nodelay: true,
+                duplex_fairness: false,
+                read_buffer_messages: 1,
+                validate_text: false,
+                text_to_bytes: true,
+                max_message_size: wire::MAX_MESSAGE_SIZE,
+                max_frame_size: wire::MAX_FRAME_SIZE,
+                write_chunk_size: None,
+                forwarded: ForwardedOption::default(),
+                headers: vec![],
+                host: None,
+                keepalive_interval: None,
+                keepalive_timeout: Duration::from_secs(30),
+                slow_consumer_policy: None,
+                connect_timeout: None,
+                connect_deadline: None,
+                attempt_delay: None,
+                resolve_on_connect: false,
+                proxy: None,
+                bind_addr: None,
+                bind_device: None,
+            };
+
+            let cli = WebSocketClient::init(opt, None, &Resolver::default()).unwrap();
+            let mut ws_stream = cli.connect().await.unwrap();
+            let mut buf = [0u8; 16];
+            assert_eq!(ws_stream.read(&mut buf).await.unwrap(), 0);
+            assert_eq!(
+                ws_stream.close_reason(),
+                Some(&CloseReason {
+                    code: expect.0,
+                    reason: expect.1.into(),
+                })
+            );
+        }
+    }
+
+    /// Serve `callback` on streams upgraded by `accept_stream`, shut down
+    /// once it returns, for the server tests to cover both upgrades.
+    async fn serve_accepted<C>(opt: WsAcceptOption, callback: C) -> std::net::SocketAddr
+    where
+        C: TransportServerCallback,
+    {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (io, peer) = listener.accept().await.unwrap();
+                let opt = opt.clone();
+                let callback = callback.clone();
+                tokio::spawn(async move {
+                    let mut stream = accept_stream(io, &opt).await.unwrap();
+                    callback.handle(&mut stream, Some(peer)).await;
+                    let _ = stream.shutdown().await;
+                });
+            }
+        });
+        addr
+    }
+
+    #[derive(Clone)]
+    struct TextErrorCallback(tokio::sync::mpsc::UnboundedSender<std::io::ErrorKind>);
+
+    impl TransportServerCallback for TextErrorCallback {
+        async fn handle<S>(&self, mut stream: S, _addr: Option<std::net::SocketAddr>)
+        where
+            S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + Sync,
+        {
+            let mut buf = vec![];
+            if let Err(err) = stream.read_to_end(&mut buf).await {
+                let _ = self.0.send(err.kind());
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_ws_text_validation() {
+        use futures_util::{SinkExt, StreamExt};
+        use tokio_tungstenite::tungstenite::{
+            protocol::frame::{
+                coding::{Data, OpCode},
+                Frame,
+            },
+            Message,
+        };
+
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let mut accepted = vec![];
+        for (port, validate_text, text_to_bytes) in [(9884, true, true), (9885, false, false)] {
+            let opt = WsAcceptOption {
+                paths: vec!["/text".into()],
+                read_buffer_messages: 1,
+                validate_text,
+                text_to_bytes,
+                ..Default::default()
+            };
+            accepted.push(serve_accepted(opt, TextErrorCallback(tx.clone())).await);
+            let tx = tx.clone();
+            tokio::spawn(async move {
+                let opt = WebSocketServerOption {
+                    listen: ([127, 0, 0, 1], port).into(),
+                    path: "/text".into(),
+                    paths: vec![],
+                    path_match: Default::default(),
+                    fallback: Default::default(),
+                    tcp_nodelay: true,
+                    duplex_fairness: false,
+                    read_buffer_messages: 1,
+                    validate_text,
+                    text_to_bytes,
+                    max_message_size: wire::MAX_MESSAGE_SIZE,
+                    max_frame_size: wire::MAX_FRAME_SIZE,
+                    write_chunk_size: None,
+                    max_bytes: None,
+                    rate_limit: None,
+                    max_connections: None,
+                    idle_reap: None,
+                    idle_timeout: None,
+                    trust_forwarded_headers: false,
+                    keepalive_interval: None,
+                    keepalive_timeout: Duration::from_secs(30),
+                    slow_consumer_policy: None,
+                };
+
+                let srv = WebSocketServer::init(opt, None).unwrap();
+                srv.serve(TextErrorCallback(tx)).await.unwrap();
+            });
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let invalid = Frame::message(vec![0x66, 0xff, 0xfe], OpCode::Data(Data::Text), true);
+        for (port, accepted, msg, code) in [
+            (9884, accepted[0], Message::Frame(invalid), 1007),
+            (9885, accepted[1], Message::Text("text".into()), 1003),
+        ] {
+            for addr in [([127, 0, 0, 1], port).into(), accepted] {
+                let url = format!("ws://{}/text", addr);
+                let (mut ws, _) = tokio_tungstenite::connect_async(url).await.unwrap();
+                ws.send(msg.clone()).await.unwrap();
+
+                let frame = loop {
+                    match ws.next().await.unwrap().unwrap() {
+                        Message::Close(frame) => break frame.unwrap(),
+                        _ => continue,
+                    }
+                };
+                assert_eq!(u16::from(frame.code), code, "{}", addr);
+                assert_eq!(rx.recv().await.unwrap(), std::io::ErrorKind::InvalidData);
+            }
+        }
+    }
+
+    #[derive(Debug, Clone)]
+    struct SizeCallback(tokio::sync::mpsc::UnboundedSender<std::io::Result<usize>>);
+
+    impl TransportServerCallback for SizeCallback {
+        async fn handle<S>(&self, mut stream: S, _addr: Option<std::net::SocketAddr>)
+        where
+            S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + Sync,
+        {
+            let mut buf = vec![];
+            let _ = self.0.send(stream.read_to_end(&mut buf).await);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_ws_message_limits() {
+        let opt = WebSocketServerOption {
+            listen: ([127, 0, 0, 1], 9840).into(),
+            path: "/limits".into(),
+            paths: vec![],
+            path_match: Default::default(),
+            fallback: Default::default(),
+            tcp_nodelay: true,
+            duplex_fairness: false,
+            read_buffer_messages: 1,
+            validate_text: false,
+            text_to_bytes: true,
+            max_message_size: 1024,
+            max_frame_size: 1024,
+            write_chunk_size: None,
+            max_bytes: None,
+            rate_limit: None,
+            max_connections: None,
+            idle_reap: None,
+            idle_timeout: None,
+            trust_forwarded_headers: false,
+            keepalive_interval: None,
+            keepalive_timeout: Duration::from_secs(30),
+            slow_consumer_policy: None,
+        };
+        let srv = WebSocketServer::init(opt, None).unwrap();
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let opt = WsAcceptOption {
+            paths: vec!["/limits".into()],
+            read_buffer_messages: 1,
+            max_message_size: 1024,
+            max_frame_size: 1024,
+            ..Default::default()
+        };
+        let accepted = serve_accepted(opt, SizeCallback(tx.clone())).await;
+        tokio::spawn(async move { srv.serve(SizeCallback(tx)).await });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let client = |port, write_chunk_size| {
+            let opt = WebSocketClientOption {
+                addr: "127.0.0.1".into(),
+                port,
+                path: "/limits".into(),
+                tcp_nodelay: true,
+                duplex_fairness: false,
+                read_buffer_messages: 1,
+                validate_text: false,
+                text_to_bytes: true,
+                max_message_size: wire::MAX_MESSAGE_SIZE,
+                max_frame_size: wire::MAX_FRAME_SIZE,
+                write_chunk_size,
+                forwarded: ForwardedOption::default(),
+                headers: vec![],
+                host: None,
+                keepalive_interval: None,
+                keepalive_timeout: Duration::from_secs(30),
+                slow_consumer_policy: None,
+                connect_timeout: None,
+                connect_deadline: None,
+                attempt_delay: None,
+                resolve_on_connect: false,
+                proxy: None,
+                bind_addr: None,
+                bind_device: None,
+            };
+            WebSocketClient::init(opt, None, &Resolver::default()).unwrap()
+        };
+        let data = vec![3u8; 4096];
+
+        for port in [9840, accepted.port()] {
+            // chunked writes stay under the server limit
+            let mut ws_stream = client(port, Some(1024)).connect().await.unwrap();
+            assert_eq!(ws_stream.write(&data).await.unwrap(), 1024);
+            ws_stream.write_all(&data[1024..]).await.unwrap();
+            ws_stream.shutdown().await.unwrap();
+            assert_eq!(rx.recv().await.unwrap().unwrap(), 4096);
+
+            // one message over it fails the read and closes with 1009
+            let mut ws_stream = client(port, None).connect().await.unwrap();
+            assert_eq!(ws_stream.write(&data).await.unwrap(), 4096);
+            ws_stream.flush().await.unwrap();
+            let err = rx.recv().await.unwrap().unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+            assert!(err.to_string().contains("max_message_size"), "{}", err);
+            let _ = ws_stream.read_to_end(&mut vec![]).await;
+            assert_eq!(ws_stream.close_reason().unwrap().code, wire::close::TOO_BIG);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_ws_write_bytes_chunks() {
+        use futures_util::StreamExt;
+        use tokio_tungstenite::tungstenite::Message;
+
+        /// Lengths of the binary messages `ws` reads until it closes.
+        async fn lengths<S>(mut ws: tokio_tungstenite::WebSocketStream<S>) -> Vec<usize>
+        where
+            S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+        {
+            let mut lengths = vec![];
+            while let Some(Ok(msg)) = ws.next().await {
+                if let Message::Binary(data) = msg {
+                    lengths.push(data.len());
+                }
+            }
+            lengths
+        }
+
+        let data = Bytes::from(vec![5u8; 2500]);
+
+        // the client splits the bytes off in place
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}/", listener.local_addr().unwrap());
+        let peer = tokio::spawn(async move {
+            let (s, _) = listener.accept().await.unwrap();
+            lengths(tokio_tungstenite::accept_async(s).await.unwrap()).await
+        });
+        let (ws, _) = tokio_tungstenite::connect_async(url).await.unwrap();
+        let mut stream = WebSocketClientStream::new(ws);
+        stream.set_write_chunk_size(Some(1024));
+        let mut rest = data.clone();
+        let n = std::future::poll_fn(|cx| stream.poll_write_bytes(cx, &mut rest))
+            .await
+            .unwrap();
+        assert_eq!(n, 1024);
+        assert_eq!(rest.as_ptr(), data[1024..].as_ptr());
+        assert_eq!(stream.write_bytes(rest).await.unwrap(), 1476);
+        stream.shutdown().await.unwrap();
+        assert_eq!(peer.await.unwrap(), [1024, 1024, 452]);
+
+        // and so does the server
+        let app = axum::Router::new().route(
+            "/",
+            axum::routing::get(move |ws: axum::extract::WebSocketUpgrade| async move {
+                ws.on_upgrade(|socket| async move {
+                    let mut stream = WebSocketServerStream::new(socket);
+                    stream.set_write_chunk_size(Some(1024));
+                    assert_eq!(stream.write_bytes(data).await.unwrap(), 2500);
+                    stream.shutdown().await.unwrap();
+                })
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        let (ws, _) = tokio_tungstenite::connect_async(format!("ws://{}/", addr))
+            .await
+            .unwrap();
+        assert_eq!(lengths(ws).await, [1024, 1024, 452]);
+    }
+
+    #[derive(Debug, Clone)]
+    struct InfoCallback;
+
+    impl TransportServerCallback for InfoCallback {
+        async fn handle<S>(&self, _stream: S, _addr: Option<std::net::SocketAddr>)
+        where
+            S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + Sync,
+        {
+            unreachable!("servers call handle_ctx")
+        }
+
+        async fn handle_ctx<S>(&self, mut stream: S, ctx: crate::ConnContext)
+        where
+            S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + Sync,
+        {
+            let info = ctx.info();
+            let http = info.http.unwrap();
+            let line = format!(
+                "{} {} {:?} {:?} {}",
+                info.transport,
+                http.path,
+                http.query,
+                http.headers.get("sec-websocket-version").unwrap(),
+                info.tls.is_none()
+            );
+            stream.write_all(line.as_bytes()).await.unwrap();
+            stream.shutdown().await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_ws_connection_info() {
+        let opt = WebSocketServerOption {
+            listen: ([127, 0, 0, 1], 9846).into(),
+            path: "/info".into(),
+            paths: vec![],
+            path_match: Default::default(),
+            fallback: Default::default(),
+            tcp_nodelay: true,
+            duplex_fairness: false,
+            read_buffer_messages: 1,
+            validate_text: false,
+            text_to_bytes: true,
+            max_message_size: wire::MAX_MESSAGE_SIZE,
+            max_frame_size: wire::MAX_FRAME_SIZE,
+            write_chunk_size: None,
+            max_bytes: None,
+            rate_limit: None,
+            max_connections: None,
+            idle_reap: None,
+            idle_timeout: None,
+            trust_forwarded_headers: false,
+            keepalive_interval: None,
+            keepalive_timeout: Duration::from_secs(30),
+            slow_consumer_policy: None,
+        };
+        let srv = WebSocketServer::init(opt, None).unwrap();
+        tokio::spawn(async move { srv.serve(InfoCallback).await });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let opt = WebSocketClientOption {
+            addr: "127.0.0.1".into(),
+            port: 9846,
+            path: "/info?room=7".into(),
+            tcp_nodelay: true,
+            duplex_fairness: false,
+            read_buffer_messages: 1,
+            validate_text: false,
+            text_to_bytes: true,
+            max_message_size: wire::MAX_MESSAGE_SIZE,
+            max_frame_size: wire::MAX_FRAME_SIZE,
+            write_chunk_size: None,
+            forwarded: ForwardedOption::default(),
+            headers: vec![],
+            host: None,
+            keepalive_interval: None,
+            keepalive_timeout: Duration::from_secs(30),
+            slow_consumer_policy: None,
+            connect_timeout: None,
+            connect_deadline: None,
+            attempt_delay: None,
+            resolve_on_connect: false,
+            proxy: None,
+            bind_addr: None,
+            bind_device: None,
+        };
+        let cli = WebSocketClient::init(opt, None, &Resolver::default()).unwrap();
+        let mut ws_stream = cli.connect().await.unwrap();
+        let mut line = String::new();
+        ws_stream.read_to_string(&mut line).await.unwrap();
+        assert_eq!(line, r#"ws /info Some("room=7") "13" true"#);
+    }
+
+    #[tokio::test]
+    async fn test_ws_fallback() {
+        async fn request(port: u16, method: &str, path: &str) -> String {
+            let mut s = tokio::net::TcpStream::connect(("127.0.0.1", port))
+                .await
+                .unwrap();
+            let head = format!(
+                "{} {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+                method, path
+            );
+            s.write_all(head.as_bytes()).await.unwrap();
+            let mut response = String::new();
+            s.read_to_string(&mut response).await.unwrap();
+            response.to_lowercase()
+        }
+
+        let page = Fallback::Static {
+            status: 200,
+            body: "<html>welcome</html>".into(),
+            content_type: "text/html; charset=utf-8".into(),
+        };
+        let redirect = Fallback::Redirect("https://example.com/".into());
+        let server_option = |port, fallback| WebSocketServerOption {
+            listen: ([127, 0, 0, 1], port).into(),
+            path: "/tunnel".into(),
+            paths: vec!["/alt/".into()],
+            path_match: PathMatch::TrailingSlash,
+            fallback,
+            tcp_nodelay: true,
+            duplex_fairness: false,
+            read_buffer_messages: 1,
+            validate_text: false,
+            text_to_bytes: true,
+            max_message_size: wire::MAX_MESSAGE_SIZE,
+            max_frame_size: wire::MAX_FRAME_SIZE,
+            write_chunk_size: None,
+            max_bytes: None,
+            rate_limit: None,
+            max_connections: None,
+            idle_reap: None,
+            idle_timeout: None,
+            trust_forwarded_headers: false,
+            keepalive_interval: None,
+            keepalive_timeout: Duration::from_secs(30),
+            slow_consumer_policy: None,
+        };
+        for (port, fallback) in [(9845, page.clone()), (9844, redirect)] {
+            let srv = WebSocketServer::init(server_option(port, fallback), None).unwrap();
+            tokio::spawn(async move { srv.serve(InfoCallback).await });
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        for (method, path) in [("GET", "/"), ("GET", "/tunnel/x"), ("POST", "/tunnel")] {
+            let response = request(9845, method, path).await;
+            assert!(response.starts_with("http/1.1 200 ok"), "{}", response);
+            assert!(response.contains("content-type: text/html; charset=utf-8"));
+            assert!(response.ends_with("\r\n\r\n<html>welcome</html>"));
+
+            let response = request(9844, method, path).await;
+            assert!(response.starts_with("http/1.1 307"), "{}", response);
+            assert!(response.contains("location: https://example.com/"));
+        }
+
+        for path in ["/tunnel", "/tunnel/", "/alt", "/alt/"] {
+            let opt = WebSocketClientOption {
+                addr: "127.0.0.1".into(),
+                port: 9845,
+                path: path.into(),
+                tcp_nodelay: true,
+                duplex_fairness: false,
+                read_buffer_messages: 1,
+                validate_text: false,
+                text_to_bytes: true,
+                max_message_size: wire::MAX_MESSAGE_SIZE,
+                max_frame_size: wire::MAX_FRAME_SIZE,
+                write_chunk_size: None,
+                forwarded: ForwardedOption::default(),
+                headers: vec![],
+                host: None,
+                keepalive_interval: None,
+                keepalive_timeout: Duration::from_secs(30),
+                slow_consumer_policy: None,
+                connect_timeout: None,
+                connect_deadline: None,
+                attempt_delay: None,
+                resolve_on_connect: false,
+                proxy: None,
+                bind_addr: None,
+                bind_device: None,
+            };
+            let cli = WebSocketClient::init(opt, None, &Resolver::default()).unwrap();
+            let mut ws_stream = cli.connect().await.unwrap();
+            let mut line = String::new();
+            ws_stream.read_to_string(&mut line).await.unwrap();
+            assert!(line.starts_with(&format!("ws {} ", path)), "{}", line);
+        }
+
+        let invalid_path = WebSocketServerOption {
+            paths: vec!["alt".into()],
+            ..server_option(0, Fallback::NotFound)
+        };
+        let invalid_status = server_option(
+            0,
+            Fallback::Static {
+                status: 1000,
+                body: String::new(),
+                content_type: "text/plain".into(),
+            },
+        );
+        for opt in [invalid_path, invalid_status] {
+            let Err(crate::ServerError::Option(msg)) = WebSocketServer::init(opt, None) else {
+                panic!("invalid option accepted");
+            };
+            assert!(msg.contains("alt") || msg.contains("fallback"), "{}", msg);
+        }
+    }
+
+    #[derive(Debug, Clone)]
+    struct HostCallback;
+
+    #[derive(Debug, Clone)]
+    struct Host(String);
+
+    impl TransportServerCallback for HostCallback {
+        async fn handle<S>(&self, _stream: S, _addr: Option<std::net::SocketAddr>)
+        where
+            S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + Sync,
+        {
+            unreachable!("servers call handle_ctx")
+        }
+
+        async fn handle_ctx<S>(&self, mut stream: S, ctx: crate::ConnContext)
+        where
+            S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + Sync,
+        {
+            let host = ctx.extensions.get::<Host>().unwrap().0.clone();
+            stream.write_all(host.as_bytes()).await.unwrap();
+            stream.shutdown().await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_ws_accept_hook_context() {
+        for (port, accept) in [(9887, true), (9888, false)] {
+            tokio::spawn(async move {
+                let opt = WebSocketServerOption {
+                    listen: ([127, 0, 0, 1], port).into(),
+                    path: "/ctx".into(),
+                    paths: vec![],
+                    path_match: Default::default(),
+                    fallback: Default::default(),
+                    tcp_nodelay: true,
+                    duplex_fairness: false,
+                    read_buffer_messages: 1,
+                    validate_text: false,
+                    text_to_bytes: true,
+                    max_message_size: wire::MAX_MESSAGE_SIZE,
+                    max_frame_size: wire::MAX_FRAME_SIZE,
+                    write_chunk_size: None,
+                    max_bytes: None,
+                    rate_limit: None,
+                    max_connections: None,
+                    idle_reap: None,
+                    idle_timeout: None,
+                    trust_forwarded_headers: false,
+                    keepalive_interval: None,
+                    keepalive_timeout: Duration::from_secs(30),
+                    slow_consumer_policy: None,
+                };
+
+                let mut srv = WebSocketServer::init(opt, None).unwrap();
+                srv.add_accept_hook(Arc::new(move |ctx| {
+                    let headers = ctx.extensions.get::<http::HeaderMap>().unwrap();
+                    let host = headers.get(http::header::HOST).unwrap();
+                    let host = Host(host.to_str().unwrap().to_owned());
+                    ctx.extensions.insert(host);
+                    accept
+                }));
+                srv.serve(HostCallback).await.unwrap();
+            });
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        for (port, accept) in [(9887, true), (9888, false)] {
+            let opt = WebSocketClientOption {
+                addr: "127.0.0.1".into(),
+                port,
+                path: "/ctx".into(),
+                tcp_nodelay: true,
+                duplex_fairness: false,
+                read_buffer_messages: 1,
+                validate_text: false,
+                text_to_bytes: true,
+                max_message_size: wire::MAX_MESSAGE_SIZE,
+                max_frame_size: wire::MAX_FRAME_SIZE,
+                write_chunk_size: None,
+                forwarded: ForwardedOption::default(),
+                headers: vec![],
+                host: None,
+                keepalive_interval: None,
+                keepalive_timeout: Duration::from_secs(30),
+                slow_consumer_policy: None,
+                connect_timeout: None,
+                connect_deadline: None,
+                attempt_delay: None,
+                resolve_on_connect: false,
+                proxy: None,
+                bind_addr: None,
+                bind_device: None,
+            };
+            let cli = WebSocketClient::init(opt, None, &Resolver::default()).unwrap();
+            let Ok(mut ws_stream) = cli.connect().await else {
+                assert!(!accept);
+                continue;
+            };
+            assert!(accept);
+            let mut host = String::new();
+            ws_stream.read_to_string(&mut host).await.unwrap();
+            assert_eq!(host, format!("127.0.0.1:{}", port));
+        }
+    }
+
+    #[derive(Debug, Clone)]
+    struct PeerCallback;
+
+    impl TransportServerCallback for PeerCallback {
+        async fn handle<S>(&self, mut stream: S, addr: Option<std::net::SocketAddr>)
+        where
+            S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + Sync,
+        {
+            stream
+                .write_all(format!("{:?}", addr).as_bytes())
+                .await
+                .unwrap();
+            stream.shutdown().await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_ws_embedded_router() {
+        let opt = WebSocketServerOption {
+            listen: ([127, 0, 0, 1], 0).into(),
+            path: "/unused".into(),
+            paths: vec![],
+            path_match: Default::default(),
+            fallback: Default::default(),
+            tcp_nodelay: true,
+            duplex_fairness: false,
+            read_buffer_messages: 1,
+            validate_text: false,
+            text_to_bytes: true,
+            max_message_size: wire::MAX_MESSAGE_SIZE,
+            max_frame_size: wire::MAX_FRAME_SIZE,
+            write_chunk_size: None,
+            max_bytes: None,
+            rate_limit: None,
+            max_connections: None,
+            idle_reap: None,
+            idle_timeout: None,
+            trust_forwarded_headers: false,
+            keepalive_interval: None,
+            keepalive_timeout: Duration::from_secs(30),
+            slow_consumer_policy: None,
+        };
+        let srv = WebSocketServer::init(opt, None).unwrap();
+        let app = axum::Router::new()
+            .route("/health", axum::routing::get(|| async { "ok" }))
+            .merge(srv.router("/tunnel", PeerCallback));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:9889")
+            .await
+            .unwrap();
+        // served without connect info, so the peer addr is unknown
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let mut http = tokio::net::TcpStream::connect("127.0.0.1:9889")
+            .await
+            .unwrap();
+        http.write_all(b"GET /health HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut resp = String::new();
+        http.read_to_string(&mut resp).await.unwrap();
+        assert!(resp.starts_with("HTTP/1.1 200"));
+        assert!(resp.ends_with("ok"));
+
+        let opt = WebSocketClientOption {
+            addr: "127.0.0.1".into(),
+            port: 9889,
+            path: "/tunnel".into(),
+            tcp_nodelay: true,
+            duplex_fairness: false,
+            read_buffer_messages: 1,
+            validate_text: false,
+            text_to_bytes: true,
+            max_message_size: wire::MAX_MESSAGE_SIZE,
+            max_frame_size: wire::MAX_FRAME_SIZE,
+            write_chunk_size: None,
+            forwarded: ForwardedOption::default(),
+            headers: vec![],
+            host: None,
+            keepalive_interval: None,
+            keepalive_timeout: Duration::from_secs(30),
+            slow_consumer_policy: None,
+            connect_timeout: None,
+            connect_deadline: None,
+            attempt_delay: None,
+            resolve_on_connect: false,
+            proxy: None,
+            bind_addr: None,
+            bind_device: None,
+        };
+        let cli = WebSocketClient::init(opt, None, &Resolver::default()).unwrap();
+        let mut ws_stream = cli.connect().await.unwrap();
+        let mut peer = String::new();
+        ws_stream.read_to_string(&mut peer).await.unwrap();
+        assert_eq!(peer, "None");
+        assert_eq!(srv.stats().ipv4.accepted, 1);
+    }
+
+    #[derive(Debug, Clone)]
+    struct FloodCallback;
+
+    impl TransportServerCallback for FloodCallback {
+        async fn handle<S>(&self, mut stream: S, _addr: Option<std::net::SocketAddr>)
+        where
+            S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + Sync,
+        {
+            while stream.write_all(&[7; 10]).await.is_ok() {}
+        }
+    }
+
+    #[tokio::test]
+    async fn test_ws_max_bytes_close() {
+        use futures_util::StreamExt;
+        use tokio_tungstenite::tungstenite::Message;
+
+        tokio::spawn(async move {
+            let opt = WebSocketServerOption {
+                listen: ([127, 0, 0, 1], 9879).into(),
+                path: "/limit".into(),
+                paths: vec![],
+                path_match: Default::default(),
+                fallback: Default::default(),
+                tcp_nodelay: true,
+                duplex_fairness: false,
+                read_buffer_messages: 1,
+                validate_text: false,
+                text_to_bytes: true,
+                max_message_size: wire::MAX_MESSAGE_SIZE,
+                max_frame_size: wire::MAX_FRAME_SIZE,
+                write_chunk_size: None,
+                max_bytes: Some(crate::limit::MaxBytesOption {
+                    rx: None,
+                    tx: Some(32),
+                    action: crate::limit::LimitAction::Close,
+                }),
+                rate_limit: None,
+                max_connections: None,
+                idle_reap: None,
+                idle_timeout: None,
+                trust_forwarded_headers: false,
+                keepalive_interval: None,
+                keepalive_timeout: Duration::from_secs(30),
+                slow_consumer_policy: None,
+            };
+
+            let srv = WebSocketServer::init(opt, None).unwrap();
+            srv.serve(FloodCallback).await.unwrap();
+        });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let (mut ws, _) = tokio_tungstenite::connect_async("ws://127.0.0.1:9879/limit")
+            .await
+            .unwrap();
+        let mut received = 0;
+        let frame = loop {
+            match ws.next().await.unwrap().unwrap() {
+                Message::Binary(data) => received += data.len(),
+                Message::Close(frame) => break frame.unwrap(),
+                _ => continue,
+            }
+        };
+        assert_eq!(received, 32);
+        assert_eq!(u16::from(frame.code), 1008);
+    }
+
+    #[derive(Debug, Clone, Default)]
+    struct ConcurrencyCallback {
+        active: Arc<std::sync::atomic::AtomicUsize>,
+        peak: Arc<std::sync::atomic::AtomicUsize>,
+    }
+
+    impl TransportServerCallback for ConcurrencyCallback {
+        async fn handle<S>(&self, mut stream: S, _addr: Option<std::net::SocketAddr>)
+        where
+            S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + Sync,
+        {
+            use std::sync::atomic::Ordering;
+
+            let n = self.active.fetch_add(1, Ordering::SeqCst) + 1;
+            self.peak.fetch_max(n, Ordering::SeqCst);
+            // a single byte asks for a panic, the permit must still come back
+            let mut buf = [0u8; 1];
+            let panic = matches!(stream.read(&mut buf).await, Ok(1) if buf[0] == 0xff);
+            self.active.fetch_sub(1, Ordering::SeqCst);
+            assert!(!panic, "requested panic");
+        }
+    }
+
+    fn limited_server_option(port: u16, max_connections: usize) -> WebSocketServerOption {
+        WebSocketServerOption {
+            listen: ([127, 0, 0, 1], port).into(),
+            path: "/limit".into(),
+            paths: vec![],
+            path_match: Default::default(),
+            fallback: Default::default(),
+            tcp_nodelay: true,
+            duplex_fairness: false,
+            read_buffer_messages: 1,
+            validate_text: false,
+            text_to_bytes: true,
+            max_message_size: wire::MAX_MESSAGE_SIZE,
+            max_frame_size: wire::MAX_FRAME_SIZE,
+            write_chunk_size: None,
+            max_bytes: None,
+            rate_limit: None,
+            max_connections: Some(max_connections),
+            idle_reap: None,
+            idle_timeout: None,
+            trust_forwarded_headers: false,
+            keepalive_interval: None,
+            keepalive_timeout: Duration::from_secs(30),
+            slow_consumer_policy: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_ws_max_connections() {
+        use std::sync::atomic::Ordering;
+
+        use futures_util::SinkExt;
+        use tokio_tungstenite::tungstenite::{Error, Message};
+
+        const LIMIT: usize = 4;
+        const CLIENTS: usize = 200;
+
+        let callback = ConcurrencyCallback::default();
+        let srv =
+            Arc::new(WebSocketServer::init(limited_server_option(9875, LIMIT), None).unwrap());
+        tokio::spawn({
+            let srv = srv.clone();
+            let callback = callback.clone();
+            async move { srv.serve(callback).await.unwrap() }
+        });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let clients = (0..CLIENTS).map(|_| {
+            tokio::spawn(async move {
+                match tokio_tungstenite::connect_async("ws://127.0.0.1:9875/limit").await {
+                    Ok((mut ws, _)) => {
+                        tokio::time::sleep(Duration::from_millis(20)).await;
+                        ws.close(None).await.unwrap();
+                        true
+                    }
+                    Err(Error::Http(resp)) => {
+                        assert_eq!(resp.status(), 503);
+                        false
+                    }
+                    Err(e) => panic!("unexpected error {}", e),
+                }
+            })
+        });
+        let results = tokio::time::timeout(
+            Duration::from_secs(10),
+            futures_util::future::join_all(clients),
+        )
+        .await
+        .expect("clients hang");
+        let accepted = results.into_iter().filter(|r| *r.as_ref().unwrap()).count();
+
+        assert!(accepted >= LIMIT);
+        assert!(accepted < CLIENTS);
+        assert!(callback.peak.load(Ordering::SeqCst) <= LIMIT);
+        let stats = srv.stats();
+        assert_eq!(stats.over_limit, (CLIENTS - accepted) as u64);
+        assert_eq!(stats.pending_upgrades, 0);
+
+        // a panicking callback gives its permit back
+        let callback = ConcurrencyCallback::default();
+        tokio::spawn(async move {
+            let srv = WebSocketServer::init(limited_server_option(9874, 1), None).unwrap();
+            srv.serve(callback).await.unwrap()
+        });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        for _ in 0..3 {
+            let (mut ws, _) = tokio_tungstenite::connect_async("ws://127.0.0.1:9874/limit")
+                .await
+                .unwrap();
+            ws.send(Message::Binary(vec![0xff])).await.unwrap();
+            while futures_util::StreamExt::next(&mut ws).await.is_some() {}
+            // the socket closes while the task unwinds, give the permit a moment
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_ws_post_connect_probe() {
+        use crate::{option::ProbeMode, ClientError};
+
+        tokio::spawn(async move {
+            let srv = WebSocketServer::init(limited_server_option(9873, 16), None).unwrap();
+            srv.serve(ConcurrencyCallback::default()).await.unwrap()
+        });
+        // completes the upgrade, then never reads so pings stay unanswered
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let silent = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (s, _) = listener.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let _ws = tokio_tungstenite::accept_async(s).await.unwrap();
+                    std::future::pending::<()>().await;
+                });
+            }
+        });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let opt = WebSocketClientOption {
+            addr: "127.0.0.1".into(),
+            port: 9873,
+            path: "/limit".into(),
+            tcp_nodelay: true,
+            duplex_fairness: false,
+            read_buffer_messages: 1,
+            validate_text: false,
+            text_to_bytes: true,
+            max_message_size: wire::MAX_MESSAGE_SIZE,
+            max_frame_size: wire::MAX_FRAME_SIZE,
+            write_chunk_size: None,
+            forwarded: ForwardedOption::default(),
+            headers: vec![],
+            host: None,
+            keepalive_interval: None,
+            keepalive_timeout: Duration::from_secs(30),
+            slow_consumer_policy: None,
+            connect_timeout: None,
+            connect_deadline: None,
+            attempt_delay: None,
+            resolve_on_connect: false,
+            proxy: None,
+            bind_addr: None,
+            bind_device: None,
+        };
+        let probe = ProbeMode::WsPing {
+            timeout: Duration::from_millis(200),
+        };
+
+        let mut cli = WebSocketClient::with_addrs(opt.clone(), None, vec![silent]).unwrap();
+        cli.set_post_connect_probe(Some(probe));
+        match cli.connect().await {
+            Err(ClientError::Connect(msg)) => assert_eq!(msg, "post-connect probe failed: timeout"),
+            _ => panic!("probe passed on a silent server"),
+        }
+
+        let working = ([127, 0, 0, 1], 9873).into();
+        let mut cli = WebSocketClient::with_addrs(opt, None, vec![silent, working]).unwrap();
+        cli.set_post_connect_probe(Some(probe));
+        let mut ws_stream = cli.connect().await.unwrap();
+        ws_stream.write_all(&[0]).await.unwrap();
+        ws_stream.flush().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_ws_connect_race() {
+        tokio::spawn(async move {
+            let srv = WebSocketServer::init(limited_server_option(9920, 16), None).unwrap();
+            srv.serve(ConcurrencyCallback::default()).await.unwrap()
+        });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let opt = WebSocketClientOption {
+            addr: "localhost".into(),
+            port: 9920,
+            path: "/limit".into(),
+            attempt_delay: Some(Duration::from_millis(50)),
+            ..Default::default()
+        };
+        let working = ([127, 0, 0, 1], 9920).into();
+        let hole = crate::test_util::Blackhole::new();
+        let cli = WebSocketClient::with_addrs(opt, None, vec![hole.addr, working]).unwrap();
+        let connect = tokio::time::timeout(Duration::from_secs(1), cli.connect());
+        let mut ws_stream = connect
+            .await
+            .expect("raced past the hanging address")
+            .unwrap();
+        ws_stream.write_all(&[0]).await.unwrap();
+        ws_stream.flush().await.unwrap();
+    }
+
+    #[derive(Debug, Clone)]
+    struct EchoCallback;
+
+    impl TransportServerCallback for EchoCallback {
+        async fn handle<S>(&self, mut stream: S, _addr: Option<std::net::SocketAddr>)
+        where
+            S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + Sync,
+        {
+            let mut buf = [0u8; 1024];
+            while let Ok(n) = stream.read(&mut buf).await {
+                if n == 0 || stream.write_all(&buf[..n]).await.is_err() {
+                    break;
+                }
+                if stream.flush().await.is_err() {
+                    break;
+                }
+            }
+        }
+    }
+
+    fn spawn_drain_server(
+        port: u16,
+    ) -> (
+        Arc<WebSocketServer>,
+        tokio::task::JoinHandle<crate::ServerResult<()>>,
+    ) {
+        let srv = Arc::new(WebSocketServer::init(limited_server_option(port, 16), None).unwrap());
+        let served = tokio::spawn({
+            let srv = srv.clone();
+            async move { srv.serve(EchoCallback).await }
+        });
+        (srv, served)
+    }
+
+    #[tokio::test]
+    async fn test_ws_drain() {
+        use futures_util::{SinkExt, StreamExt};
+        use tokio_tungstenite::tungstenite::{Error, Message};
+
+        let (srv, served) = spawn_drain_server(9871);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let url = "ws://127.0.0.1:9871/limit";
+        let (mut ws, _) = tokio_tungstenite::connect_async(url).await.unwrap();
+        ws.send(Message::binary(b"before".to_vec())).await.unwrap();
+        assert_eq!(
+            ws.next().await.unwrap().unwrap(),
+            Message::binary(b"before".to_vec())
+        );
+
+        let drain = tokio::spawn({
+            let srv = srv.clone();
+            async move {
+                srv.drain(super::CloseNotice {
+                    code: 1001,
+                    reason: "draining".into(),
+                    deadline: Duration::from_secs(5),
+                    frame: super::NoticeFrame::Text,
+                })
+                .await
+            }
+        });
+        assert_eq!(ws.next().await.unwrap().unwrap(), Message::text("draining"));
+
+        match tokio_tungstenite::connect_async(url).await {
+            Err(Error::Http(resp)) => assert_eq!(resp.status(), 503),
+            other => panic!("upgrade during drain: {:?}", other.map(|_| ())),
+        }
+
+        // the exchange in flight still completes
+        ws.send(Message::binary(b"last".to_vec())).await.unwrap();
+        assert_eq!(
+            ws.next().await.unwrap().unwrap(),
+            Message::binary(b"last".to_vec())
+        );
+        ws.close(None).await.unwrap();
+
+        let aborted = tokio::time::timeout(Duration::from_secs(2), drain)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(aborted, 0);
+        tokio::time::timeout(Duration::from_secs(2), served)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_ws_drain_deadline() {
+        use futures_util::StreamExt;
+        use tokio_tungstenite::tungstenite::Message;
+
+        let (srv, served) = spawn_drain_server(9870);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let (mut ws, _) = tokio_tungstenite::connect_async("ws://127.0.0.1:9870/limit")
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        // the client never answers the close frame
+        let start = std::time::Instant::now();
+        let aborted = srv
+            .drain(super::CloseNotice {
+                code: 1001,
+                reason: "going away".into(),
+                deadline: Duration::from_millis(300),
+                frame: super::NoticeFrame::Close,
+            })
+            .await;
+        assert_eq!(aborted, 1);
+        assert!(start.elapsed() >= Duration::from_millis(300));
+        tokio::time::timeout(Duration::from_secs(2), served)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+
+        match ws.next().await.unwrap().unwrap() {
+            Message::Close(Some(frame)) => {
+                assert_eq!(u16::from(frame.code), 1001);
+                assert_eq!(frame.reason, "going away");
+            }
+            msg => panic!("unexpected {:?}", msg),
+        }
+    }
+
+    fn forwarded_server_option(port: u16) -> WebSocketServerOption {
+        WebSocketServerOption {
+            trust_forwarded_headers: true,
+            max_connections: None,
+            ..limited_server_option(port, 1)
+        }
+    }
+
+    fn forwarded_client_option(port: u16, forwarded: ForwardedOption) -> WebSocketClientOption {
+        WebSocketClientOption {
+            addr: "127.0.0.1".into(),
+            port,
+            path: "/limit".into(),
+            tcp_nodelay: true,
+            duplex_fairness: false,
+            read_buffer_messages: 1,
+            validate_text: false,
+            text_to_bytes: true,
+            max_message_size: wire::MAX_MESSAGE_SIZE,
+            max_frame_size: wire::MAX_FRAME_SIZE,
+            write_chunk_size: None,
+            forwarded,
+            headers: vec![],
+            host: None,
+            keepalive_interval: None,
+            keepalive_timeout: Duration::from_secs(30),
+            slow_consumer_policy: None,
+            connect_timeout: None,
+            connect_deadline: None,
+            attempt_delay: None,
+            resolve_on_connect: false,
+            proxy: None,
+            bind_addr: None,
+            bind_device: None,
+        }
+    }
+
+    /// Reconnects every accepted connection upstream, forwarding its peer.
+    #[derive(Clone)]
+    struct GatewayCallback(Arc<WebSocketClient>);
+
+    impl TransportServerCallback for GatewayCallback {
+        async fn handle<S>(&self, _stream: S, _addr: Option<std::net::SocketAddr>)
+        where
+            S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + Sync,
+        {
+            unreachable!("servers call handle_ctx")
+        }
+
+        async fn handle_ctx<S>(&self, mut stream: S, ctx: crate::ConnContext)
+        where
+            S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + Sync,
+        {
+            let params = ConnectParams::forward_from(&ctx);
+            let mut upstream = self.0.connect_with(&params).await.unwrap();
+            let _ = tokio::io::copy_bidirectional(&mut stream, &mut upstream).await;
+        }
+    }
+
+    #[derive(Clone)]
+    struct ForwardedCallback(tokio::sync::mpsc::UnboundedSender<(ForwardedChain, String)>);
+
+    impl TransportServerCallback for ForwardedCallback {
+        async fn handle<S>(&self, _stream: S, _addr: Option<std::net::SocketAddr>)
+        where
+            S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + Sync,
+        {
+            unreachable!("servers call handle_ctx")
+        }
+
+        async fn handle_ctx<S>(&self, mut stream: S, ctx: crate::ConnContext)
+        where
+            S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + Sync,
+        {
+            let chain = ctx.extensions.get::<ForwardedChain>().cloned().unwrap();
+            let headers = ctx.extensions.get::<http::HeaderMap>().unwrap();
+            let xff = headers[forwarded::X_FORWARDED_FOR]
+                .to_str()
+                .unwrap()
+                .to_owned();
+            let _ = self.0.send((chain, xff));
+            stream.write_all(b"done").await.unwrap();
+            stream.flush().await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_ws_forwarded_chain() {
+        use forwarded::ForwardedNode;
+
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            let srv = WebSocketServer::init(forwarded_server_option(9852), None).unwrap();
+            srv.serve(ForwardedCallback(tx)).await.unwrap();
+        });
+        let gateway_forwarded = ForwardedOption {
+            forwarded_proto: Some("ws".into()),
+            legacy_xff: true,
+            ..Default::default()
+        };
+        let upstream = WebSocketClient::init(
+            forwarded_client_option(9852, gateway_forwarded),
+            None,
+            &Resolver::default(),
+        )
+        .unwrap();
+        tokio::spawn(async move {
+            let srv = WebSocketServer::init(forwarded_server_option(9853), None).unwrap();
+            srv.serve(GatewayCallback(Arc::new(upstream)))
+                .await
+                .unwrap();
+        });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        // the client is itself behind a proxy that saw the original address
+        let original: std::net::SocketAddr = "192.0.2.43:47011".parse().unwrap();
+        let client_forwarded = ForwardedOption {
+            forward_for: Some(original),
+            ..Default::default()
+        };
+        let cli = WebSocketClient::init(
+            forwarded_client_option(9853, client_forwarded),
+            None,
+            &Resolver::default(),
+        )
+        .unwrap();
+        let mut s = cli.connect().await.unwrap();
+        let mut buf = [0u8; 4];
+        s.read_exact(&mut buf).await.unwrap();
+
+        let (chain, xff) = rx.recv().await.unwrap();
+        assert_eq!(chain.client(), Some(&ForwardedNode::Addr(original)));
+        // the gateway appended its peer instead of replacing the entry
+        assert_eq!(chain.0.len(), 2);
+        let ForwardedNode::Addr(peer) = chain.0[1].for_node.clone().unwrap() else {
+            panic!("gateway peer not forwarded as an address");
+        };
+        assert_eq!(peer.ip(), std::net::Ipv4Addr::LOCALHOST);
+        assert_eq!(chain.0[1].proto.as_deref(), Some("ws"));
+        assert_eq!(xff, "192.0.2.43, 127.0.0.1");
+    }
+
+    #[tokio::test]
+    async fn test_ws_frame_stats() {
+        use futures_util::SinkExt;
+        use tokio_tungstenite::tungstenite::{
+            protocol::{frame::coding::CloseCode, CloseFrame},
+            Message,
+        };
+
+        /// Every message type, as a proxy injecting control frames would send them.
+        async fn send_all<S>(ws: &mut tokio_tungstenite::WebSocketStream<S>)
+        where
+            S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+        {
+            ws.send(Message::binary(b"ab".to_vec())).await.unwrap();
+            ws.send(Message::text("cde")).await.unwrap();
+            ws.send(Message::Ping(b"p".to_vec())).await.unwrap();
+            ws.send(Message::Pong(b"qq".to_vec())).await.unwrap();
+            let frame = CloseFrame {
+                code: CloseCode::Normal,
+                reason: "bye".into(),
+            };
+            ws.send(Message::Close(Some(frame))).await.unwrap();
+        }
+
+        let expected = FrameStats {
+            binary: FrameCount {
+                messages: 1,
+                bytes: 2,
+            },
+            text: FrameCount {
+                messages: 1,
+                bytes: 3,
+            },
+            ping: FrameCount {
+                messages: 1,
+                bytes: 1,
+            },
+            pong: FrameCount {
+                messages: 1,
+                bytes: 2,
+            },
+            close: FrameCount {
+                messages: 1,
+                bytes: 3,
+            },
+            other: FrameCount::default(),
+            discarded: 3,
+        };
+
+        // client stream against a raw peer
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (s, _) = listener.accept().await.unwrap();
+            let mut ws = tokio_tungstenite::accept_async(s).await.unwrap();
+            send_all(&mut ws).await;
+            while futures_util::StreamExt::next(&mut ws).await.is_some() {}
+        });
+        let url = format!("ws://{}/", addr);
+        let (ws, _) = tokio_tungstenite::connect_async(url).await.unwrap();
+        let mut stream = WebSocketClientStream::new(ws);
+        let mut data = vec![];
+        stream.read_to_end(&mut data).await.unwrap();
+        assert_eq!(data, b"abcde");
+        assert_eq!(stream.frame_stats(), expected);
+        assert_eq!(stream.frame_stats().get(FrameKind::Pong).bytes, 2);
+
+        // server stream against a raw client
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let app = axum::Router::new().route(
+            "/",
+            axum::routing::get(|ws: axum::extract::WebSocketUpgrade| async move {
+                ws.on_upgrade(|socket| async move {
+                    let mut stream = WebSocketServerStream::new(socket);
+                    let mut data = vec![];
+                    stream.read_to_end(&mut data).await.unwrap();
+                    tx.send((data, stream.frame_stats())).unwrap();
+                })
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        let url = format!("ws://{}/", addr);
+        let (mut ws, _) = tokio_tungstenite::connect_async(url).await.unwrap();
+        send_all(&mut ws).await;
+        let (data, stats) = rx.recv().await.unwrap();
+        assert_eq!(data, b"abcde");
+        assert_eq!(stats, expected);
+    }
+
+    #[tokio::test]
+    async fn test_ws_write_vectored() {
+        use std::io::IoSlice;
+
+        use futures_util::StreamExt;
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let peer = tokio::spawn(async move {
+            let (s, _) = listener.accept().await.unwrap();
+            let mut ws = tokio_tungstenite::accept_async(s).await.unwrap();
+            let mut messages = vec![];
+            while let Some(Ok(msg)) = ws.next().await {
+                if msg.is_binary() {
+                    messages.push(msg.into_data());
+                }
+            }
+            messages
+        });
+        let url = format!("ws://{}/", addr);
+        let (ws, _) = tokio_tungstenite::connect_async(url).await.unwrap();
+        let mut stream = WebSocketClientStream::new(ws);
+        assert!(stream.is_write_vectored());
+
+        // one message per write, cut at the write chunk size
+        let bufs = [
+            IoSlice::new(b"ab"),
+            IoSlice::new(b"cde"),
+            IoSlice::new(b"f"),
+        ];
+        stream.set_write_chunk_size(Some(4));
+        assert_eq!(stream.write_vectored(&bufs).await.unwrap(), 4);
+        stream.set_write_chunk_size(None);
+        assert_eq!(stream.write_vectored(&bufs).await.unwrap(), 6);
+
+        let mut stream = crate::TransportClientStream::Ws(stream);
+        assert!(stream.is_write_vectored());
+        stream.shutdown().await.unwrap();
+        drop(stream);
+
+        let messages = peer.await.unwrap();
+        assert_eq!(messages, [b"abcd".to_vec(), b"abcdef".to_vec()]);
+    }
+
+    #[tokio::test]
+    async fn test_ws_keepalive() {
+        use futures_util::{SinkExt, StreamExt};
+        use tokio_tungstenite::tungstenite::Message;
+
+        /// A raw peer pinging us while we only read, every Ping is answered.
+        async fn assert_pongs<S>(ws: &mut tokio_tungstenite::WebSocketStream<S>)
+        where
+            S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+        {
+            for i in 0..3u8 {
+                ws.send(Message::Ping(vec![i; 4])).await.unwrap();
+                let pong = tokio::time::timeout(Duration::from_secs(1), ws.next())
+                    .await
+                    .unwrap();
+                assert_eq!(pong.unwrap().unwrap(), Message::Pong(vec![i; 4]));
+            }
+        }
+
+        /// Upgrade one connection with a raw peer running `peer`.
+        async fn raw_peer<F, Fut>(peer: F) -> std::net::SocketAddr
+        where
+            F: FnOnce(tokio_tungstenite::WebSocketStream<tokio::net::TcpStream>) -> Fut
+                + Send
+                + 'static,
+            Fut: std::future::Future<Output = ()> + Send,
+        {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            tokio::spawn(async move {
+                let (s, _) = listener.accept().await.unwrap();
+                peer(tokio_tungstenite::accept_async(s).await.unwrap()).await;
+            });
+            addr
+        }
+
+        async fn client_stream(addr: std::net::SocketAddr) -> WebSocketClientStream {
+            let url = format!("ws://{}/", addr);
+            let (ws, _) = tokio_tungstenite::connect_async(url).await.unwrap();
+            WebSocketClientStream::new(ws)
+        }
+
+        // answered while the client only reads
+        let addr = raw_peer(|mut ws| async move { assert_pongs(&mut ws).await }).await;
+        let mut stream = client_stream(addr).await;
+        let mut data = vec![];
+        stream.read_to_end(&mut data).await.unwrap_err();
+        assert_eq!(stream.frame_stats().ping.messages, 3);
+
+        // and while the server only reads
+        let app = axum::Router::new().route(
+            "/",
+            axum::routing::get(|ws: axum::extract::WebSocketUpgrade| async move {
+                ws.on_upgrade(|socket| async move {
+                    let mut stream = WebSocketServerStream::new(socket);
+                    let mut data = vec![];
+                    let _ = stream.read_to_end(&mut data).await;
+                })
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        let (mut ws, _) = tokio_tungstenite::connect_async(format!("ws://{}/", addr))
+            .await
+            .unwrap();
+        assert_pongs(&mut ws).await;
+
+        // a peer that never reads never answers our Pings
+        let addr = raw_peer(|ws| async move {
+            let _ws = ws;
+            std::future::pending().await
+        })
+        .await;
+        let mut stream = client_stream(addr).await;
+        stream.set_keepalive(Some(Duration::from_millis(50)), Duration::from_millis(100));
+        let start = std::time::Instant::now();
+        let mut buf = [0u8; 16];
+        let err = stream.read(&mut buf).await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+        assert!(start.elapsed() < Duration::from_secs(1));
+        // and the stream stays failed
+        let err = stream.write(b"late").await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+
+        // a client that only writes bytes pings too
+        let addr = raw_peer(|ws| async move {
+            let _ws = ws;
+            std::future::pending().await
+        })
+        .await;
+        let mut stream = client_stream(addr).await;
+        stream.set_keepalive(Some(Duration::from_millis(50)), Duration::from_millis(100));
+        let writes = async {
+            loop {
+                match stream.write_bytes(Bytes::from_static(b"tick")).await {
+                    Ok(_) => tokio::time::sleep(Duration::from_millis(10)).await,
+                    Err(err) => return err,
+                }
+            }
+        };
+        let err = tokio::time::timeout(Duration::from_secs(1), writes)
+            .await
+            .unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+
+        // a Pong not echoing the Ping
+        let addr = raw_peer(|mut ws| async move {
+            while let Some(Ok(msg)) = ws.next().await {
+                if let Message::Ping(_) = msg {
+                    // replaces the automatic reply
+                    ws.send(Message::Pong(b"wrong".to_vec())).await.unwrap();
+                }
+            }
+        })
+        .await;
+        let mut stream = client_stream(addr).await;
+        stream.set_keepalive(Some(Duration::from_millis(20)), Duration::from_secs(5));
+        let err = stream.read(&mut buf).await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+
+        // a live peer keeps a short keepalive happy
+        let addr =
+            raw_peer(|mut ws| async move { while let Some(Ok(_)) = ws.next().await {} }).await;
+        let mut stream = client_stream(addr).await;
+        stream.set_keepalive(Some(Duration::from_millis(20)), Duration::from_millis(200));
+        let res = tokio::time::timeout(Duration::from_millis(300), stream.read(&mut buf)).await;
+        assert!(res.is_err(), "{:?}", res);
+        assert!(stream.frame_stats().pong.messages >= 5);
+
+        // data keeps the connection alive without any Pong, no Ping is sent
+        let addr = raw_peer(|mut ws| async move {
+            for _ in 0..20 {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+                if ws.send(Message::Binary(b"tick".to_vec())).await.is_err() {
+                    return;
+                }
+            }
+            let _ = ws.close(None).await;
+        })
+        .await;
+        let mut stream = client_stream(addr).await;
+        stream.set_keepalive(Some(Duration::from_millis(50)), Duration::from_millis(100));
+        let mut data = vec![];
+        stream.read_to_end(&mut data).await.unwrap();
+        assert_eq!(data.len(), 20 * 4);
+        assert_eq!(stream.frame_stats().pong.messages, 0);
+    }
+
+    #[tokio::test]
+    async fn test_ws_close_handshake() {
+        use futures_util::{SinkExt, StreamExt};
+        use tokio_tungstenite::tungstenite::{
+            protocol::{frame::coding::CloseCode, CloseFrame},
+            Message,
+        };
+
+        fn close_code(msg: Option<Result<Message, tokio_tungstenite::tungstenite::Error>>) -> u16 {
+            match msg {
+                Some(Ok(Message::Close(Some(frame)))) => frame.code.into(),
+                other => panic!("expected a close frame, got {:?}", other),
+            }
+        }
+
+        // server shutdown sends a normal closure, seen as a clean close
+        let app = axum::Router::new().route(
+            "/",
+            axum::routing::get(|ws: axum::extract::WebSocketUpgrade| async move {
+                ws.on_upgrade(|socket| async move {
+                    let mut stream = WebSocketServerStream::new(socket);
+                    stream.write_all(b"bye").await.unwrap();
+                    stream.shutdown().await.unwrap();
+                    let _ = stream.read_to_end(&mut vec![]).await;
+                })
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        let (mut ws, _) = tokio_tungstenite::connect_async(format!("ws://{}/", addr))
+            .await
+            .unwrap();
+        assert_eq!(
+            ws.next().await.unwrap().unwrap(),
+            Message::Binary(b"bye".to_vec())
+        );
+        assert_eq!(close_code(ws.next().await), wire::close::NORMAL);
+        assert!(ws.next().await.is_none());
+
+        // the client sends the given status
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let peer = tokio::spawn(async move {
+            let (s, _) = listener.accept().await.unwrap();
+            let mut ws = tokio_tungstenite::accept_async(s).await.unwrap();
+            let code = close_code(ws.next().await);
+            assert!(ws.next().await.is_none());
+            code
+        });
+        let (ws, _) = tokio_tungstenite::connect_async(format!("ws://{}/", addr))
+            .await
+            .unwrap();
+        let mut stream = WebSocketClientStream::new(ws);
+        let reason = CloseReason {
+            code: 4000,
+            reason: "done".into(),
+        };
+        stream.close_with(reason).await.unwrap();
+        let _ = stream.read_to_end(&mut vec![]).await;
+        assert_eq!(peer.await.unwrap(), 4000);
+
+        // a close from the peer is echoed and ends the reads
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let peer = tokio::spawn(async move {
+            let (s, _) = listener.accept().await.unwrap();
+            let mut ws = tokio_tungstenite::accept_async(s).await.unwrap();
+            ws.send(Message::Binary(b"last".to_vec())).await.unwrap();
+            ws.close(Some(CloseFrame {
+                code: CloseCode::Away,
+                reason: "restart".into(),
+            }))
+            .await
+            .unwrap();
+            let code = close_code(ws.next().await);
+            assert!(ws.next().await.is_none());
+            code
+        });
+        let (ws, _) = tokio_tungstenite::connect_async(format!("ws://{}/", addr))
+            .await
+            .unwrap();
+        let mut stream = WebSocketClientStream::new(ws);
+        let mut data = vec![];
+        stream.read_to_end(&mut data).await.unwrap();
+        assert_eq!(data, b"last");
+        assert_eq!(stream.read(&mut [0u8; 4]).await.unwrap(), 0);
+        assert_eq!(stream.close_reason().unwrap().code, wire::close::AWAY);
+        assert_eq!(peer.await.unwrap(), wire::close::AWAY);
+    }
+
+    /// Writes until the stream fails, returning the error and how long after
+    /// the last accepted write it came.
+    async fn write_until_error<S: AsyncWrite + Unpin>(
+        stream: &mut S,
+    ) -> (std::io::Error, Duration) {
+        let chunk = vec![0x5a; 16 << 10];
+        let mut last = std::time::Instant::now();
+        loop {
+            match stream.write(&chunk).await {
+                Ok(_) => last = std::time::Instant::now(),
+                Err(err) => return (err, last.elapsed()),
+            }
+        }
+    }
+
+    #[derive(Clone)]
+    struct StallCallback(tokio::sync::mpsc::UnboundedSender<(std::io::Error, Duration)>);
+
+    impl TransportServerCallback for StallCallback {
+        async fn handle<S>(&self, mut stream: S, _addr: Option<std::net::SocketAddr>)
+        where
+            S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + Sync,
+        {
+            let _ = self.0.send(write_until_error(&mut stream).await);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_ws_slow_consumer() {
+        let max_stall = Duration::from_millis(200);
+        let policy = |action| SlowConsumerOption {
+            max_pending: 256 << 10,
+            max_stall,
+            action,
+        };
+
+        // the server gives up on a client that never reads
+        let mut opt = limited_server_option(9898, 16);
+        opt.slow_consumer_policy = Some(policy(SlowConsumerAction::Disconnect));
+        let srv = Arc::new(WebSocketServer::init(opt, None).unwrap());
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let serving = srv.clone();
+        tokio::spawn(async move { serving.serve(StallCallback(tx)).await });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let (_ws, _) = tokio_tungstenite::connect_async("ws://127.0.0.1:9898/limit")
+            .await
+            .unwrap();
+        let (err, stalled) = tokio::time::timeout(Duration::from_secs(10), rx.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::ConnectionAborted);
+        assert!(
+            stalled >= max_stall && stalled < max_stall * 5,
+            "{:?}",
+            stalled
+        );
+        assert_eq!(srv.stats().slow_consumers, 1);
+
+        // a client writing to a server that never reads gets write errors
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (s, _) = listener.accept().await.unwrap();
+            let _ws = tokio_tungstenite::accept_async(s).await.unwrap();
+            std::future::pending::<()>().await;
+        });
+        let (ws, _) = tokio_tungstenite::connect_async(format!("ws://{}/", addr))
+            .await
+            .unwrap();
+        let mut stream = WebSocketClientStream::new(ws);
+        stream.set_slow_consumer_policy(Some(policy(SlowConsumerAction::ErrorWrites)));
+        let (err, stalled) =
+            tokio::time::timeout(Duration::from_secs(10), write_until_error(&mut stream))
+                .await
+                .unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+        assert!(
+            stalled >= max_stall && stalled < max_stall * 5,
+            "{:?}",
+            stalled
+        );
+        // writes stay failed, reads go on
+        let err = stream.write(b"late").await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+        let mut buf = [0u8; 16];
+        let res = tokio::time::timeout(Duration::from_millis(50), stream.read(&mut buf)).await;
+        assert!(res.is_err(), "{:?}", res);
+    }
+
+    #[tokio::test]
+    async fn test_ws_bind_at_init() {
+        let mut opt = limited_server_option(0, 16);
+        opt.path = "/".into();
+        let srv = Arc::new(WebSocketServer::init(opt, None).unwrap());
+        let addr = srv.local_addr().unwrap();
+        assert_ne!(addr.port(), 0);
+
+        // an upgrade sent before serve is answered once it runs
+        let connect = tokio::spawn(tokio_tungstenite::connect_async(format!("ws://{}/", addr)));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!connect.is_finished());
+        tokio::spawn({
+            let srv = srv.clone();
+            async move { srv.serve(EchoCallback).await }
+        });
+        connect.await.unwrap().unwrap();
+        assert_eq!(srv.local_addr(), Some(addr));
+    }
+
+    #[tokio::test]
+    async fn test_ws_serve_with_shutdown() {
+        use futures_util::{SinkExt, StreamExt};
+        use tokio_tungstenite::tungstenite::Message;
+
+        let mut opt = limited_server_option(0, 16);
+        opt.path = "/".into();
+        let srv = Arc::new(WebSocketServer::init(opt, None).unwrap());
+        let (tx, rx) = tokio::sync::oneshot::channel::<()>();
+        let serving = srv.clone();
+        let handle = tokio::spawn(async move {
+            let signal = async {
+                let _ = rx.await;
+            };
+            serving
+                .serve_with_shutdown(EchoCallback, signal, Duration::from_secs(5))
+                .await
+        });
+        let url = format!("ws://{}/", srv.local_addr().unwrap());
+        let (mut ws, _) = tokio_tungstenite::connect_async(&url).await.unwrap();
+
+        tx.send(()).unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(tokio_tungstenite::connect_async(&url).await.is_err());
+        assert!(!handle.is_finished());
+
+        // the tunnel outlives the listener
+        ws.send(Message::Binary(b"still here".to_vec()))
+            .await
+            .unwrap();
+        assert_eq!(
+            ws.next().await.unwrap().unwrap(),
+            Message::Binary(b"still here".to_vec())
+        );
+        ws.close(None).await.unwrap();
+        while ws.next().await.is_some() {}
+        let res = tokio::time::timeout(Duration::from_secs(1), handle).await;
+        assert!(res.unwrap().unwrap().is_ok());
+    }
+
+    /// Acks the first message, then behaves as it names: `c` returns soon,
+    /// `s` reads until the stream dies and `p` never returns.
+    #[derive(Clone)]
+    struct ShutdownKinds(Arc<std::sync::atomic::AtomicBool>);
+
+    impl TransportServerCallback for ShutdownKinds {
+        async fn handle<S>(&self, mut stream: S, _addr: Option<std::net::SocketAddr>)
+        where
+            S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + Sync,
+        {
+            let mut kind = [0u8; 1];
+            stream.read_exact(&mut kind).await.unwrap();
+            stream.write_all(b"ok").await.unwrap();
+            stream.flush().await.unwrap();
+            match &kind {
+                b"c" => tokio::time::sleep(Duration::from_millis(100)).await,
+                b"s" => {
+                    let mut buf = vec![];
+                    let _ = stream.read_to_end(&mut buf).await;
+                    let err = stream.write_all(b"bye").await.unwrap_err();
+                    assert_eq!(err.kind(), std::io::ErrorKind::BrokenPipe);
+                    self.0.store(true, std::sync::atomic::Ordering::SeqCst);
+                }
+                _ => std::future::pending().await,
+            }
+        }
+    }
+
+    #[derive(Default)]
+    struct Exits(std::sync::Mutex<Vec<crate::observe::ShutdownExit>>);
+
+    impl crate::observe::ServeObserver for Exits {
+        fn on_shutdown_exit(&self, _id: u64, exit: crate::observe::ShutdownExit) {
+            self.0.lock().unwrap().push(exit);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_ws_shutdown_kill() {
+        use futures_util::{SinkExt, StreamExt};
+        use tokio_tungstenite::tungstenite::Message;
+
+        use crate::observe::ShutdownExit;
+
+        let mut opt = limited_server_option(0, 16);
+        opt.path = "/".into();
+        let mut srv = WebSocketServer::init(opt, None).unwrap();
+        let exits = Arc::new(Exits::default());
+        srv.set_serve_observer(exits.clone());
+        srv.set_kill_grace(Duration::from_millis(300));
+        let url = format!("ws://{}/", srv.local_addr().unwrap());
+        let cleaned_up = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let (tx, rx) = tokio::sync::oneshot::channel::<()>();
+        let callback = ShutdownKinds(cleaned_up.clone());
+        let handle = tokio::spawn(async move {
+            let signal = async {
+                let _ = rx.await;
+            };
+            srv.serve_with_shutdown(callback, signal, Duration::from_millis(300))
+                .await
+        });
+
+        let mut peers = vec![];
+        for kind in [b"c", b"s", b"p"] {
+            let (mut ws, _) = tokio_tungstenite::connect_async(&url).await.unwrap();
+            ws.send(Message::binary(kind.to_vec())).await.unwrap();
+            assert_eq!(
+                ws.next().await.unwrap().unwrap(),
+                Message::binary(b"ok".to_vec())
+            );
+            peers.push(ws);
+        }
+
+        let start = std::time::Instant::now();
+        tx.send(()).unwrap();
+        let res = tokio::time::timeout(Duration::from_secs(2), handle).await;
+        assert!(res.unwrap().unwrap().is_ok());
+        assert!(start.elapsed() >= Duration::from_millis(600));
+        let mut seen = exits.0.lock().unwrap().clone();
+        seen.sort();
+        assert_eq!(
+            seen,
+            [
+                ShutdownExit::Drained,
+                ShutdownExit::Invalidated,
+                ShutdownExit::Aborted
+            ]
+        );
+        assert!(cleaned_up.load(std::sync::atomic::Ordering::SeqCst));
+
+        // invalidating closed the stubborn one with a normal closure
+        match peers[1].next().await.unwrap().unwrap() {
+            Message::Close(Some(frame)) => assert_eq!(u16::from(frame.code), 1000),
+            msg => panic!("unexpected {:?}", msg),
+        }
+    }
+
+    #[cfg(feature = "insecure-tls")]
+    #[tokio::test]
+    async fn test_ws_connect_over_duplex() {
+        use futures_util::{SinkExt, StreamExt};
+        use tokio_rustls::TlsAcceptor;
+        use tokio_tungstenite::tungstenite::Message;
+
+        let (cli_io, srv_io) = tokio::io::duplex(64 * 1024);
+
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+        let tls_opt = TlsServerOption {
+            alpn: vec![],
+            require_alpn: false,
+            ticket_keys: None,
+            certificate: TlsCertOption::Text {
+                certs: vec![cert.cert.pem()],
+                key: cert.key_pair.serialize_pem().into(),
+            },
+            client_ca: None,
+            require_client_cert: false,
+            session_tickets: false,
+            min_version: None,
+            max_version: None,
+            cipher_suites: vec![],
+        };
+        let config: rustls::ServerConfig = tls_opt.try_into().unwrap();
+        let server = tokio::spawn(async move {
+            let tls = TlsAcceptor::from(std::sync::Arc::new(config))
+                .accept(srv_io)
+                .await
+                .unwrap();
+            let mut ws = tokio_tungstenite::accept_async(tls).await.unwrap();
+            while let Some(Ok(msg)) = ws.next().await {
+                if msg.is_binary() {
+                    ws.send(Message::binary([b"echo ", &msg.into_data()[..]].concat()))
+                        .await
+                        .unwrap();
+                }
+            }
+        });
+
+        // no address to dial, the stream is all there is
+        let opt = WebSocketClientOption {
+            addr: "localhost".into(),
+            ..forwarded_client_option(443, ForwardedOption::default())
+        };
+        let tls_opt = TlsClientOption {
+            insecure: true,
+            server_name: "localhost".into(),
+            ..Default::default()
+        };
+        let cli = WebSocketClient::with_addrs(opt, Some(tls_opt), vec![]).unwrap();
+        assert!(cli.connect().await.is_err());
+
+        let mut stream = cli
+            .connect_over(cli_io, &ConnectParams::default())
+            .await
+            .unwrap();
+        stream.write_all(b"over duplex").await.unwrap();
+        stream.flush().await.unwrap();
+        let mut buf = [0u8; 16];
+        let n = stream.read(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], b"echo over duplex");
+
+        stream.shutdown().await.unwrap();
+        drop(stream);
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_ws_flood_yields() {
+        use futures_util::{SinkExt, StreamExt};
+        use tokio_tungstenite::tungstenite::Message;
+
+        const FLOOD: usize = 300_000;
+
+        async fn raw_peer<F, Fut>(peer: F) -> WebSocketClientStream
+        where
+            F: FnOnce(tokio_tungstenite::WebSocketStream<tokio::net::TcpStream>) -> Fut
+                + Send
+                + 'static,
+            Fut: std::future::Future<Output = ()> + Send,
+        {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let url = format!("ws://{}/", listener.local_addr().unwrap());
+            tokio::spawn(async move {
+                let (s, _) = listener.accept().await.unwrap();
+                peer(tokio_tungstenite::accept_async(s).await.unwrap()).await;
+            });
+            let (ws, _) = tokio_tungstenite::connect_async(url).await.unwrap();
+            WebSocketClientStream::new(ws)
+        }
+
+        let mut flooded = raw_peer(|mut ws| async move {
+            for _ in 0..FLOOD {
+                ws.feed(Message::Binary(vec![b'x'])).await.unwrap();
+            }
+            ws.flush().await.unwrap();
+            while let Some(Ok(_)) = ws.next().await {}
+        })
+        .await;
+        let mut pinger = raw_peer(|mut ws| async move {
+            while let Some(Ok(msg)) = ws.next().await {
+                if msg.is_binary() {
+                    ws.send(msg).await.unwrap();
+                }
+            }
+        })
+        .await;
+
+        // both on the one thread of the test runtime
+        let reader = tokio::spawn(async move {
+            let mut buf = [0u8; 64];
+            let mut read = 0;
+            while read < FLOOD {
+                read += flooded.read(&mut buf).await.unwrap();
+            }
+        });
+
+        // without yields the reader holds the thread until the flood is read
+        let mut rounds = 0;
+        while !reader.is_finished() {
+            pinger.write_all(b"ping").await.unwrap();
+            pinger.flush().await.unwrap();
+            let mut buf = [0u8; 4];
+            pinger.read_exact(&mut buf).await.unwrap();
+            rounds += 1;
+        }
+        reader.await.unwrap();
+        assert!(rounds > 10, "{} rounds", rounds);
+    }
+
+    /// Accept one upgrade over `io`, returning its request headers.
+    async fn upgrade_headers<S>(io: S) -> http::HeaderMap
+    where
+        S: tokio::io::AsyncRead + AsyncWrite + Unpin,
+    {
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        #[allow(clippy::result_large_err)]
+        let callback = |req: &http::Request<()>, resp| {
+            let _ = tx.send(req.headers().clone());
+            Ok(resp)
+        };
+        let _ws = tokio_tungstenite::accept_hdr_async(io, callback)
+            .await
+            .unwrap();
+        rx.await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_ws_upgrade_headers() {
+        let opt = WebSocketClientOption {
+            headers: vec![
+                ("Authorization".into(), "Bearer abc".into()),
+                ("X-Tag".into(), "a".into()),
+                ("x-tag".into(), "b".into()),
+            ],
+            host: Some("cdn.example:8443".into()),
+            ..forwarded_client_option(80, ForwardedOption::default())
+        };
+        let cli = WebSocketClient::with_addrs(opt.clone(), None, vec![]).unwrap();
+        let (cli_io, srv_io) = tokio::io::duplex(4096);
+        let server = tokio::spawn(upgrade_headers(srv_io));
+        let _stream = cli
+            .connect_over(cli_io, &ConnectParams::default())
+            .await
+            .unwrap();
+        let headers = server.await.unwrap();
+        assert_eq!(headers["host"], "cdn.example:8443");
+        assert_eq!(headers["authorization"], "Bearer abc");
+        assert_eq!(
+            headers.get_all("x-tag").iter().collect::<Vec<_>>(),
+            ["a", "b"]
+        );
+        assert_eq!(headers["upgrade"], "websocket");
+
+        // rejected at init
+        let invalid = [
+            (vec![("bad name".into(), "v".into())], None),
+            (vec![("x-tag".into(), "a\nb".into())], None),
+            (vec![("Host".into(), "other".into())], None),
+            (vec![("Sec-WebSocket-Key".into(), "k".into())], None),
+            (vec![], Some("bad host".into())),
+        ];
+        for (headers, host) in invalid {
+            let opt = WebSocketClientOption {
+                headers,
+                host,
+                ..opt.clone()
+            };
+            let Err(err) = WebSocketClient::with_addrs(opt, None, vec![]) else {
+                panic!("invalid header accepted");
+            };
+            assert!(matches!(err, crate::ClientError::Option(_)), "{}", err);
+        }
+    }
+
+    #[cfg(feature = "insecure-tls")]
+    #[tokio::test]
+    async fn test_ws_upgrade_headers_tls() {
+        use tokio_rustls::TlsAcceptor;
+
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+        let tls_opt = TlsServerOption {
+            alpn: vec![],
+            require_alpn: false,
+            ticket_keys: None,
+            certificate: TlsCertOption::Text {
+                certs: vec![cert.cert.pem()],
+                key: cert.key_pair.serialize_pem().into(),
+            },
+            client_ca: None,
+            require_client_cert: false,
+            session_tickets: false,
+            min_version: None,
+            max_version: None,
+            cipher_suites: vec![],
+        };
+        let config: rustls::ServerConfig = tls_opt.try_into().unwrap();
+        let (cli_io, srv_io) = tokio::io::duplex(64 * 1024);
+        let server = tokio::spawn(async move {
+            let tls = TlsAcceptor::from(Arc::new(config))
+                .accept(srv_io)
+                .await
+                .unwrap();
+            let server_name = tls.get_ref().1.server_name().map(str::to_owned);
+            (server_name, upgrade_headers(tls).await)
+        });
+
+        // the server name stays the address, only the Host header changes
+        let opt = WebSocketClientOption {
+            addr: "localhost".into(),
+            headers: vec![("X-Auth".into(), "token".into())],
+            host: Some("cdn.example".into()),
+            ..forwarded_client_option(443, ForwardedOption::default())
+        };
+        let tls_opt = TlsClientOption {
+            insecure: true,
+            ..Default::default()
+        };
+        let cli = WebSocketClient::with_addrs(opt, Some(tls_opt), vec![]).unwrap();
+        let _stream = cli
+            .connect_over(cli_io, &ConnectParams::default())
+            .await
+            .unwrap();
+        let (server_name, headers) = server.await.unwrap();
+        assert_eq!(server_name.as_deref(), Some("localhost"));
+        assert_eq!(headers["host"], "cdn.example");
+        assert_eq!(headers["x-auth"], "token");
+    }
+
+    #[cfg(feature = "insecure-tls")]
+    #[tokio::test]
+    async fn test_ws_client_pinned() {
+        use tokio_rustls::TlsAcceptor;
+
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+        let tls_opt = TlsServerOption {
+            alpn: vec![],
+            require_alpn: false,
+            ticket_keys: None,
+            certificate: TlsCertOption::Text {
+                certs: vec![cert.cert.pem()],
+                key: cert.key_pair.serialize_pem().into(),
+            },
+            client_ca: None,
+            require_client_cert: false,
+            session_tickets: false,
+            min_version: None,
+            max_version: None,
+            cipher_suites: vec![],
+        };
+        let config: rustls::ServerConfig = tls_opt.try_into().unwrap();
+        let acceptor = TlsAcceptor::from(Arc::new(config));
+
+        // the pinned certificate passes, any other is refused in the handshake
+        let other = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+        for (pinned, accepted) in [
+            (cert.cert.der().clone(), true),
+            (other.cert.der().clone(), false),
+        ] {
+            let (cli_io, srv_io) = tokio::io::duplex(64 * 1024);
+            let acceptor = acceptor.clone();
+            let server = tokio::spawn(async move {
+                let tls = acceptor.accept(srv_io).await.ok()?;
+                Some(upgrade_headers(tls).await)
+            });
+
+            let opt = WebSocketClientOption {
+                addr: "localhost".into(),
+                ..forwarded_client_option(443, ForwardedOption::default())
+            };
+            let tls_opt = TlsClientOption {
+                insecure: true,
+                on_first_seen: Some(Arc::new(move |cert| {
+                    crate::tls::cert_sha256(cert) == crate::tls::cert_sha256(&pinned)
+                })),
+                ..Default::default()
+            };
+            let cli = WebSocketClient::with_addrs(opt, Some(tls_opt), vec![]).unwrap();
+            let connected = cli.connect_over(cli_io, &ConnectParams::default()).await;
+            assert_eq!(connected.is_ok(), accepted);
+            assert_eq!(server.await.unwrap().is_some(), accepted);
+        }
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_ws_over_stream() {
+        let opt = WsAcceptOption {
+            paths: vec!["/limit".into()],
+            write_chunk_size: Some(4),
+            ..Default::default()
+        };
+        let client_opt = forwarded_client_option(80, ForwardedOption::default());
+
+        // over a unix socket, which no server of the crate listens on
+        let (cli_io, srv_io) = tokio::net::UnixStream::pair().unwrap();
+        let server = tokio::spawn({
+            let opt = opt.clone();
+            async move {
+                let mut stream = accept_stream(srv_io, &opt).await.unwrap();
+                let mut buf = [0u8; 16];
+                let n = stream.read(&mut buf).await.unwrap();
+                stream.write_all(&buf[..n]).await.unwrap();
+                stream.shutdown().await.unwrap();
+                (
+                    stream.path().map(str::to_owned),
+                    stream.frame_stats().binary.messages,
+                )
+            }
+        });
+        let mut stream = connect_stream(cli_io, &client_opt).await.unwrap();
+        stream.write_all(b"over unix").await.unwrap();
+        stream.flush().await.unwrap();
+        let mut echoed = vec![];
+        stream.read_to_end(&mut echoed).await.unwrap();
+        assert_eq!(echoed, b"over unix");
+        // the echo is cut at the accept option's write_chunk_size
+        assert_eq!(stream.frame_stats().binary.messages, 3);
+        let (path, received) = server.await.unwrap();
+        assert_eq!(path.as_deref(), Some("/limit"));
+        assert_eq!(received, 1);
+
+        // close_with takes the shutdown path the server closes through
+        let (cli_io, srv_io) = tokio::io::duplex(4096);
+        let server = tokio::spawn({
+            let opt = opt.clone();
+            async move {
+                let mut stream = accept_stream(srv_io, &opt).await.unwrap();
+                let reason = CloseReason {
+                    code: 4000,
+                    reason: "done".into(),
+                };
+                stream.close_with(reason.clone()).await.unwrap();
+                // a second close sends nothing more
+                stream.close_with(reason).await.unwrap();
+            }
+        });
+        let mut stream = connect_stream(cli_io, &client_opt).await.unwrap();
+        stream.read_to_end(&mut vec![]).await.unwrap();
+        assert_eq!(stream.close_reason().map(|r| r.code), Some(4000));
+        server.await.unwrap();
+
+        // a path outside the accepted ones fails both sides
+        let (cli_io, srv_io) = tokio::io::duplex(4096);
+        let server = tokio::spawn(async move { accept_stream(srv_io, &opt).await.err() });
+        let client_opt = WebSocketClientOption {
+            path: "/other".into(),
+            ..client_opt
+        };
+        let err = connect_stream(cli_io, &client_opt).await.err().unwrap();
+        assert!(err.to_string().contains("404"), "{}", err);
+        assert!(server.await.unwrap().is_some());
+    }
 }
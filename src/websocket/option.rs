@@ -1,15 +1,81 @@
 //! WebSocket Transport Option
 
-use std::net::SocketAddr;
+use std::{net::SocketAddr, time::Duration};
 
 use serde::{Deserialize, Serialize};
 
+use crate::{io::RateLimitOption, limit::MaxBytesOption, option::ProxyOption};
+
+use super::{
+    wire::{default_max_frame_size, default_max_message_size},
+    ForwardedOption,
+};
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct WebSocketServerOption {
     pub listen: SocketAddr,
     pub path: String,
+    /// Further paths upgraded like `path`.
+    #[serde(default)]
+    pub paths: Vec<String>,
+    #[serde(default)]
+    pub path_match: PathMatch,
+    /// Answer to requests for any other path.
+    #[serde(default)]
+    pub fallback: Fallback,
     #[serde(default)]
     pub tcp_nodelay: bool,
+    #[serde(default)]
+    pub duplex_fairness: bool,
+    #[serde(default = "default_read_buffer_messages")]
+    pub read_buffer_messages: usize,
+    /// Reject Text messages with invalid UTF-8 with a Close(1007).
+    #[serde(default)]
+    pub validate_text: bool,
+    /// Pass Text messages through as bytes, otherwise reject them with a Close(1003).
+    #[serde(default = "default_text_to_bytes")]
+    pub text_to_bytes: bool,
+    /// Largest message accepted, see `wire::MAX_MESSAGE_SIZE`.
+    #[serde(default = "default_max_message_size")]
+    pub max_message_size: usize,
+    /// Largest frame accepted, see `wire::MAX_FRAME_SIZE`.
+    #[serde(default = "default_max_frame_size")]
+    pub max_frame_size: usize,
+    /// Split writes larger than this into several messages, `None` sends
+    /// every write as one message.
+    #[serde(default)]
+    pub write_chunk_size: Option<usize>,
+    /// Per connection byte limits, a breach with the close action sends a Close(1008).
+    #[serde(default)]
+    pub max_bytes: Option<MaxBytesOption>,
+    /// Bandwidth of each upgraded connection, and of all of them together.
+    #[serde(default)]
+    pub rate_limit: Option<RateLimitOption>,
+    /// Upgraded connections served at once, further upgrades get a 503.
+    #[serde(default)]
+    pub max_connections: Option<usize>,
+    /// Close connections that moved no data for this long.
+    #[serde(default)]
+    pub idle_reap: Option<Duration>,
+    /// Fail reads and writes of the callback stream with `TimedOut` when
+    /// they make no progress for this long, see `TimeoutStream`.
+    #[serde(default)]
+    pub idle_timeout: Option<Duration>,
+    /// Read `Forwarded` or `X-Forwarded-For` into a `ForwardedChain`, only
+    /// for listeners behind proxies that set them.
+    #[serde(default)]
+    pub trust_forwarded_headers: bool,
+    /// Ping the peer once nothing was received for this long, `None`
+    /// disables keepalive.
+    #[serde(default)]
+    pub keepalive_interval: Option<Duration>,
+    /// Fail the connection when neither the Pong nor anything else arrives
+    /// within this long of a Ping.
+    #[serde(default = "default_keepalive_timeout")]
+    pub keepalive_timeout: Duration,
+    /// Give up on a peer that stops reading, `None` lets writes block.
+    #[serde(default)]
+    pub slow_consumer_policy: Option<SlowConsumerOption>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -19,4 +85,211 @@ pub struct WebSocketClientOption {
     pub path: String,
     #[serde(default)]
     pub tcp_nodelay: bool,
+    #[serde(default)]
+    pub duplex_fairness: bool,
+    #[serde(default = "default_read_buffer_messages")]
+    pub read_buffer_messages: usize,
+    /// Reject Text messages with invalid UTF-8 with a Close(1007).
+    #[serde(default)]
+    pub validate_text: bool,
+    /// Pass Text messages through as bytes, otherwise reject them with a Close(1003).
+    #[serde(default = "default_text_to_bytes")]
+    pub text_to_bytes: bool,
+    /// Largest message accepted, see `wire::MAX_MESSAGE_SIZE`.
+    #[serde(default = "default_max_message_size")]
+    pub max_message_size: usize,
+    /// Largest frame accepted, see `wire::MAX_FRAME_SIZE`.
+    #[serde(default = "default_max_frame_size")]
+    pub max_frame_size: usize,
+    /// Split writes larger than this into several messages, `None` sends
+    /// every write as one message.
+    #[serde(default)]
+    pub write_chunk_size: Option<usize>,
+    /// `Forwarded` header sent on the upgrade request.
+    #[serde(default)]
+    pub forwarded: ForwardedOption,
+    /// Further headers of the upgrade request, in order. A name given
+    /// more than once is sent with every value.
+    #[serde(default)]
+    pub headers: Vec<(String, String)>,
+    /// Host header of the upgrade request instead of `addr:port`, the tls
+    /// server name stays `addr`.
+    #[serde(default)]
+    pub host: Option<String>,
+    /// Ping the server once nothing was received for this long, `None`
+    /// disables keepalive.
+    #[serde(default)]
+    pub keepalive_interval: Option<Duration>,
+    /// Fail the connection when neither the Pong nor anything else arrives
+    /// within this long of a Ping.
+    #[serde(default = "default_keepalive_timeout")]
+    pub keepalive_timeout: Duration,
+    /// Give up on a peer that stops reading, `None` lets writes block.
+    #[serde(default)]
+    pub slow_consumer_policy: Option<SlowConsumerOption>,
+    /// Give up on one resolved address after this long and try the next.
+    #[serde(default)]
+    pub connect_timeout: Option<Duration>,
+    /// Bound on the whole connect across every resolved address.
+    #[serde(default)]
+    pub connect_deadline: Option<Duration>,
+    /// Race the resolved addresses, starting the next one when the last
+    /// did not connect within this long, see `Dialer::set_attempt_delay`.
+    #[serde(default)]
+    pub attempt_delay: Option<Duration>,
+    /// Resolve `addr` on every connect instead of once at init.
+    #[serde(default)]
+    pub resolve_on_connect: bool,
+    /// Connect through this proxy, which resolves `addr` itself.
+    #[serde(default)]
+    pub proxy: Option<ProxyOption>,
+    /// Source address of outbound connections, resolved addresses of the
+    /// other family are skipped.
+    #[serde(default)]
+    pub bind_addr: Option<SocketAddr>,
+    /// Interface outbound connections go out of, with `SO_BINDTODEVICE`.
+    /// Linux only.
+    #[serde(default)]
+    pub bind_device: Option<String>,
+}
+
+impl Default for WebSocketClientOption {
+    /// Every field at its serde default, with no address to connect to.
+    fn default() -> Self {
+        Self {
+            addr: String::new(),
+            port: 0,
+            path: "/".to_owned(),
+            tcp_nodelay: false,
+            duplex_fairness: false,
+            read_buffer_messages: default_read_buffer_messages(),
+            validate_text: false,
+            text_to_bytes: default_text_to_bytes(),
+            max_message_size: default_max_message_size(),
+            max_frame_size: default_max_frame_size(),
+            write_chunk_size: None,
+            forwarded: ForwardedOption::default(),
+            headers: vec![],
+            host: None,
+            keepalive_interval: None,
+            keepalive_timeout: default_keepalive_timeout(),
+            slow_consumer_policy: None,
+            connect_timeout: None,
+            connect_deadline: None,
+            resolve_on_connect: false,
+            proxy: None,
+            bind_addr: None,
+            bind_device: None,
+            attempt_delay: None,
+        }
+    }
+}
+
+/// Upgrade and stream settings of `accept_stream`, the part of a
+/// `WebSocketServerOption` that does not listen.
+#[derive(Debug, Clone)]
+pub struct WsAcceptOption {
+    /// Paths upgraded, others get a 404. Empty upgrades every path.
+    pub paths: Vec<String>,
+    pub duplex_fairness: bool,
+    pub read_buffer_messages: usize,
+    /// Reject Text messages with invalid UTF-8 with a Close(1007).
+    pub validate_text: bool,
+    /// Pass Text messages through as bytes, otherwise reject them with a Close(1003).
+    pub text_to_bytes: bool,
+    pub max_message_size: usize,
+    pub max_frame_size: usize,
+    /// Split writes larger than this into several messages.
+    pub write_chunk_size: Option<usize>,
+    pub keepalive_interval: Option<Duration>,
+    pub keepalive_timeout: Duration,
+    pub slow_consumer_policy: Option<SlowConsumerOption>,
+    /// Flush after every message with no write buffer, as `LatencyProfile::LowLatency` does.
+    pub flush_always: bool,
+}
+
+impl Default for WsAcceptOption {
+    fn default() -> Self {
+        Self {
+            paths: vec![],
+            duplex_fairness: false,
+            read_buffer_messages: default_read_buffer_messages(),
+            validate_text: false,
+            text_to_bytes: default_text_to_bytes(),
+            max_message_size: default_max_message_size(),
+            max_frame_size: default_max_frame_size(),
+            write_chunk_size: None,
+            keepalive_interval: None,
+            keepalive_timeout: default_keepalive_timeout(),
+            slow_consumer_policy: None,
+            flush_always: false,
+        }
+    }
+}
+
+/// How request paths are matched against `path` and `paths`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PathMatch {
+    /// Only the path as written, `/tunnel/` does not match `/tunnel`.
+    #[default]
+    Exact,
+    /// The path with or without a trailing slash.
+    TrailingSlash,
+}
+
+/// Response to requests outside the upgrade paths.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Fallback {
+    /// 404 with an empty body.
+    #[default]
+    NotFound,
+    /// 307 to the url.
+    Redirect(String),
+    /// A fixed page, to look like an ordinary web server.
+    Static {
+        status: u16,
+        #[serde(default)]
+        body: String,
+        #[serde(default = "default_content_type")]
+        content_type: String,
+    },
+}
+
+fn default_content_type() -> String {
+    "text/html; charset=utf-8".to_owned()
+}
+
+/// What a stream does once its writes stalled for `max_stall`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SlowConsumerAction {
+    /// Send a Close(1008) if there is room and fail both directions.
+    #[default]
+    Disconnect,
+    /// Fail writes with `TimedOut`, reads go on.
+    ErrorWrites,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SlowConsumerOption {
+    /// Bytes written without a completed flush before writes wait for one.
+    pub max_pending: usize,
+    /// How long writes may stay blocked before `action` is taken.
+    pub max_stall: Duration,
+    #[serde(default)]
+    pub action: SlowConsumerAction,
+}
+
+fn default_read_buffer_messages() -> usize {
+    1
+}
+
+fn default_text_to_bytes() -> bool {
+    true
+}
+
+fn default_keepalive_timeout() -> Duration {
+    Duration::from_secs(30)
 }
@@ -1,18 +1,30 @@
 //! WebSocket Transport Server
 
-use std::{net::SocketAddr, pin::Pin, sync::Arc, task::Poll};
+use std::{
+    collections::VecDeque,
+    future::Future,
+    net::SocketAddr,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Poll, Waker},
+    time::Duration,
+};
 
+use arc_swap::ArcSwap;
 use axum::{
     extract::{
-        ws::{Message, WebSocket},
+        ws::{CloseFrame, Message, WebSocket},
         ConnectInfo, State, WebSocketUpgrade,
     },
-    routing::get,
+    http::{header, HeaderMap, HeaderValue, StatusCode, Uri},
+    response::{IntoResponse, Redirect, Response},
+    routing::{get, MethodRouter},
     Router,
 };
 use axum_server::{
     accept::NoDelayAcceptor,
     tls_rustls::{RustlsAcceptor, RustlsConfig},
+    Handle,
 };
 use bytes::{Buf, Bytes};
 use futures_util::{
@@ -20,82 +32,669 @@ use futures_util::{
     stream::{SplitSink, SplitStream},
     SinkExt, StreamExt,
 };
-use tokio::io::{AsyncBufRead, AsyncRead, AsyncWrite};
+use tokio::{
+    io::{AsyncBufRead, AsyncRead, AsyncWrite, AsyncWriteExt},
+    sync::Semaphore,
+    time::Instant,
+};
+use tokio_tungstenite::{
+    accept_hdr_async_with_config,
+    tungstenite::{
+        self,
+        handshake::server::{ErrorResponse, Request, Response as UpgradeResponse},
+    },
+};
 
-use crate::{ServerResult, TlsServerOption, TransportServerCallback, TransportServerTrait};
+use crate::{
+    context::{AcceptHook, ConnContext},
+    io::{throttle::throttle, Budget, RateLimits, TimeoutStream},
+    limit::{LimitAction, LimitedStream, MaxBytesOption},
+    net::{bind_listener, BoundAddr, EarlyListener},
+    observe::{self, ServeObserver, ShutdownExit},
+    option::{LatencyProfile, ServerOption, CONFIG_VERSION},
+    reap::{IdleStream, Reaper},
+    reload::{changed_paths, AppliedChanges},
+    shutdown::{killable, DEFAULT_KILL_GRACE},
+    stats::{CountedStream, ServerStats, ServerStatsSnapshot},
+    tls::{
+        watch::{self, WatchTask},
+        TicketKeys, WATCH_INTERVAL,
+    },
+    ServerError, ServerResult, TlsError, TlsServerOption, TransportServerCallback,
+    TransportServerOption, TransportServerTrait,
+};
 
-use super::WebSocketServerOption;
+use super::{
+    drain::{CloseNotice, Control, NoticeFrame, Registry},
+    duplex_waker,
+    keepalive::PingKeepalive,
+    option::{Fallback, PathMatch, SlowConsumerOption},
+    slow::SlowConsumer,
+    socket::{AcceptIo, Socket},
+    wire::{self, close},
+    CloseReason, ForwardedChain, FrameKind, FrameStats, WebSocketServerOption, WsAcceptOption,
+    CLOSE_REASON, MAX_READ_AHEAD_SIZE,
+};
 
 pub struct WebSocketServer {
-    path: String,
+    /// Upgrade paths, with the trailing slash variants of `PathMatch::TrailingSlash`.
+    paths: Vec<String>,
+    fallback: Fallback,
     listen: SocketAddr,
     tls_cfg: Option<RustlsConfig>,
     tcp_nodelay: bool,
+    live: Arc<ArcSwap<Live>>,
+    stats: Arc<ServerStats>,
+    accept_hooks: Vec<AcceptHook>,
+    observer: Option<Arc<dyn ServeObserver>>,
+    connection_limit: Option<Arc<Semaphore>>,
+    registry: Arc<Registry>,
+    kill_grace: Duration,
+    reaper: Option<Arc<Reaper>>,
+    send_buffer_size: Option<u32>,
+    busy_poll: Option<u32>,
+    flush_always: bool,
+    handle: Handle,
+    bound_addr: BoundAddr,
+    /// Listener bound by `init`, taken by the first `serve`.
+    early: EarlyListener,
 }
 
+/// Settings read per upgrade, swapped as a whole by `apply`.
+#[derive(Clone)]
+struct Live {
+    ticket_keys: Option<Arc<TicketKeys>>,
+    /// Upgrade and stream settings, shared with `accept_stream`.
+    accept: WsAcceptOption,
+    trust_forwarded_headers: bool,
+    max_bytes: Option<MaxBytesOption>,
+    rate_limit: Option<RateLimits>,
+    idle_timeout: Option<Duration>,
+    /// The option the settings were built from, diffed by `apply`.
+    source: Arc<TransportServerOption>,
+}
+
+/// Option paths bound when `serve` starts.
+const RESTART_PATHS: &[&str] = &[
+    "opt.ws.listen",
+    "opt.ws.path",
+    "opt.ws.paths",
+    "opt.ws.path_match",
+    "opt.ws.fallback",
+    "opt.ws.tcp_nodelay",
+    "opt.ws.max_connections",
+    "opt.ws.idle_reap",
+    "latency_profile",
+];
+
 impl WebSocketServer {
+    /// Build the server and bind `opt.listen`, `local_addr` then tells the
+    /// port of a listen address with port 0.
     pub fn init(
         opt: WebSocketServerOption,
         tls_opt: Option<TlsServerOption>,
     ) -> ServerResult<Self> {
+        let mut srv = Self::unbound(opt, tls_opt)?;
+        srv.early = EarlyListener::bind(srv.listen)?;
+        if let Some(addr) = srv.early.local_addr() {
+            srv.bound_addr.set(addr);
+        }
+        Ok(srv)
+    }
+
+    /// Build the server without binding, `serve` binds `opt.listen` when it
+    /// starts. For `apply`, which only takes the settings of the new server.
+    pub(crate) fn unbound(
+        opt: WebSocketServerOption,
+        tls_opt: Option<TlsServerOption>,
+    ) -> ServerResult<Self> {
+        let accept = WsAcceptOption::try_from(&opt)?;
+        validate_fallback(&opt.fallback)?;
+        let source = TransportServerOption {
+            opt: ServerOption::Ws(opt.clone()),
+            tls: tls_opt.clone(),
+            latency_profile: LatencyProfile::default(),
+            policy: Default::default(),
+            version: CONFIG_VERSION,
+        };
+        let mut ticket_keys = None;
         let tls_cfg = if let Some(tls_opt) = tls_opt {
-            Some(RustlsConfig::from_config(Arc::new(tls_opt.try_into()?)))
+            let (config, keys) = tls_opt.build()?;
+            ticket_keys = keys;
+            Some(RustlsConfig::from_config(Arc::new(config)))
         } else {
             None
         };
 
+        let live = Live {
+            ticket_keys,
+            accept: accept.clone(),
+            trust_forwarded_headers: opt.trust_forwarded_headers,
+            max_bytes: opt.max_bytes,
+            rate_limit: opt.rate_limit.map(RateLimits::new),
+            idle_timeout: opt.idle_timeout,
+            source: Arc::new(source),
+        };
+
+        let stats = Arc::new(ServerStats::new(opt.listen));
         Ok(Self {
-            path: opt.path,
+            paths: accept.paths,
+            fallback: opt.fallback,
             listen: opt.listen,
             tls_cfg,
             tcp_nodelay: opt.tcp_nodelay,
+            live: Arc::new(ArcSwap::from_pointee(live)),
+            reaper: opt.idle_reap.map(|idle| Reaper::new(idle, stats.clone())),
+            stats,
+            accept_hooks: vec![],
+            observer: None,
+            connection_limit: opt.max_connections.map(|n| Arc::new(Semaphore::new(n))),
+            registry: Arc::default(),
+            kill_grace: DEFAULT_KILL_GRACE,
+            send_buffer_size: None,
+            busy_poll: None,
+            flush_always: false,
+            handle: Handle::new(),
+            bound_addr: BoundAddr::default(),
+            early: EarlyListener::default(),
+        })
+    }
+
+    /// The option `apply` diffs against, by default the ws and tls options.
+    pub(crate) fn set_source(&mut self, source: TransportServerOption) {
+        let mut live = Live::clone(&self.live.load());
+        live.source = Arc::new(source);
+        self.live.store(Arc::new(live));
+    }
+
+    /// Swap in the per upgrade settings and the tls config of `next`, built
+    /// from the new option. Changes to what `serve` binds at start, and
+    /// turning tls on or off, are reported once and left out, the listener
+    /// keeps running.
+    pub(crate) fn apply(&self, next: WebSocketServer) -> AppliedChanges {
+        let live = self.live.load_full();
+        let next_live = next.live.load_full();
+        let mut changes = AppliedChanges::split(
+            changed_paths(&*live.source, &*next_live.source),
+            RESTART_PATHS,
+        );
+        match (&self.tls_cfg, &next.tls_cfg) {
+            (Some(cfg), Some(next_cfg)) => cfg.reload_from_config(next_cfg.get_inner()),
+            (None, None) => {}
+            _ => {
+                // the acceptor is picked when serve starts
+                changes.applied.retain(|path| !path.starts_with("tls"));
+                changes.requires_restart.push("tls".into());
+            }
+        }
+        self.live.store(next_live);
+        changes
+    }
+
+    /// Time callbacks get to return once a drain or `serve_with_shutdown`
+    /// invalidated their stream at the deadline, 1s by default. Callbacks
+    /// still running after it are aborted.
+    pub fn set_kill_grace(&mut self, kill_grace: Duration) {
+        self.kill_grace = kill_grace;
+    }
+
+    /// Run `hook` on every upgrade request, the request headers are in the extensions.
+    pub fn add_accept_hook(&mut self, hook: AcceptHook) {
+        self.accept_hooks.push(hook);
+    }
+
+    /// Report upgrade requests, failed upgrades and finished callbacks to `observer`.
+    pub fn set_serve_observer(&mut self, observer: Arc<dyn ServeObserver>) {
+        self.observer = Some(observer);
+    }
+
+    /// Session ticket keys from `ticket_keys` in the tls option, to swap at
+    /// runtime. A tls change applied live brings new keys.
+    pub fn ticket_keys(&self) -> Option<Arc<TicketKeys>> {
+        self.live.load().ticket_keys.clone()
+    }
+
+    /// Rebuild the tls config from `tls_opt` and use it from the next
+    /// handshake on, open connections keep theirs. A failing option leaves
+    /// the running config in place, a server without tls can not turn it on.
+    pub fn reload_tls(&self, tls_opt: TlsServerOption) -> Result<(), TlsError> {
+        let Some(ref tls_cfg) = self.tls_cfg else {
+            return Err(TlsError::reload_off());
+        };
+        reload_tls(tls_cfg, &self.live, tls_opt)
+    }
+
+    /// Reload the certificate while serving when its files are watched.
+    fn watch_tls(&self) -> Option<WatchTask> {
+        let tls_cfg = self.tls_cfg.clone()?;
+        let (current, live) = (self.live.clone(), self.live.clone());
+        watch::spawn(
+            WATCH_INTERVAL,
+            Arc::new(move || current.load().source.tls.clone()),
+            Arc::new(move |tls_opt| reload_tls(&tls_cfg, &live, tls_opt)),
+        )
+    }
+
+    pub fn stats(&self) -> ServerStatsSnapshot {
+        self.stats.snapshot()
+    }
+
+    /// Upgraded connections whose callback has not returned yet.
+    pub fn active_connections(&self) -> usize {
+        self.registry.len()
+    }
+
+    /// Socket and stream settings of the profile, the options are overridden
+    /// by `apply_latency_profile`.
+    pub fn set_latency_profile(&mut self, profile: LatencyProfile) {
+        self.send_buffer_size = profile.send_buffer_size();
+        self.busy_poll = profile.busy_poll();
+        self.flush_always = profile.is_low_latency();
+        if let Some(size) = self.send_buffer_size {
+            if let Err(e) = self.early.set_send_buffer_size(size) {
+                log::warn!("send buffer size of {} not set ({})", self.listen, e);
+            }
+        }
+        if let Some(usecs) = self.busy_poll {
+            self.early.set_busy_poll(usecs);
+        }
+    }
+
+    /// Tell connected clients the server is going away and refuse new upgrades
+    /// with 503, then stop `serve` once every client left or the notice
+    /// deadline passed. The streams of the connections still open then are
+    /// invalidated like in `serve_with_shutdown`. Returns the number of
+    /// connections invalidated or aborted at the deadline.
+    ///
+    /// A drained server does not accept upgrades again.
+    pub async fn drain(&self, notice: CloseNotice) -> usize {
+        let report = self.registry.drain(notice, self.kill_grace).await;
+        let dropped = report.count(ShutdownExit::Invalidated) + report.count(ShutdownExit::Aborted);
+        report.finish("ws", self.observer.as_deref());
+        self.handle.shutdown();
+        dropped
+    }
+
+    /// The upgrade route `serve` runs, for merging into an existing axum app.
+    ///
+    /// Peer addresses are only known when the app is served with
+    /// `into_make_service_with_connect_info::<SocketAddr>()`, otherwise they are `None`.
+    /// `init` binds `listen` all the same, port 0 keeps it off the app's port.
+    pub fn router<C: TransportServerCallback>(&self, path: &str, callback: C) -> Router {
+        Router::new()
+            .route(path, self.upgrade_handler())
+            .with_state(callback)
+    }
+
+    fn upgrade_handler<C: TransportServerCallback>(&self) -> MethodRouter<C> {
+        let live = self.live.clone();
+        let flush_always = self.flush_always;
+        let stats = self.stats.clone();
+        let local_addr = self.bound_addr.get_or(self.listen);
+        let connection_limit = self.connection_limit.clone();
+        let registry = self.registry.clone();
+        let reaper = self.reaper.clone();
+        let hooks: Arc<[AcceptHook]> = self.accept_hooks.clone().into();
+        let observer = self.observer.clone();
+        get(
+            move |ws: WebSocketUpgrade,
+                  connect_info: Option<ConnectInfo<SocketAddr>>,
+                  uri: Uri,
+                  headers: HeaderMap,
+                  State(c): State<C>| async move {
+                let addr = connect_info.map(|ConnectInfo(addr)| addr);
+                let live = live.load_full();
+                if registry.draining() {
+                    log::debug!("ws upgrade from {:?} refused while draining", addr);
+                    return StatusCode::SERVICE_UNAVAILABLE.into_response();
+                }
+
+                let mut ctx = ConnContext::new(addr, Some(local_addr)).with_transport("ws");
+                if live.trust_forwarded_headers {
+                    ctx.extensions
+                        .insert(ForwardedChain::from_headers(&headers));
+                }
+                let path = uri.path().to_owned();
+                ctx.extensions.insert(uri);
+                ctx.extensions.insert(headers);
+                observe::accepted(observer.as_deref(), &ctx, true);
+                if !ctx.run_hooks(&hooks) {
+                    log::debug!("ws upgrade from {:?} rejected by accept hook", addr);
+                    return StatusCode::FORBIDDEN.into_response();
+                }
+
+                // never wait for a permit, a queue would only grow under a storm
+                let permit = match connection_limit {
+                    Some(ref limit) => match limit.clone().try_acquire_owned() {
+                        Ok(permit) => Some(permit),
+                        Err(_) => {
+                            stats.record_over_limit();
+                            log::debug!("ws upgrade from {:?} over connection limit", addr);
+                            return StatusCode::SERVICE_UNAVAILABLE.into_response();
+                        }
+                    },
+                    None => None,
+                };
+
+                // both guards also release when the upgrade fails or the callback panics
+                let pending = stats.pending_upgrade();
+                let stats = stats.clone();
+                let registry = registry.clone();
+                let reaper = reaper.clone();
+                let mut ws = ws
+                    .max_message_size(live.accept.max_message_size)
+                    .max_frame_size(live.accept.max_frame_size)
+                    .max_write_buffer_size(super::MAX_WRITE_BUFFER_SIZE);
+                if flush_always {
+                    ws = ws.write_buffer_size(0);
+                }
+                let accepted_at = ctx.accepted_at;
+                let failed = observer.clone();
+                let ws = ws.on_failed_upgrade(move |e| {
+                    log::debug!("ws upgrade from {:?} failed {}", addr, e);
+                    observe::handshake_failed(failed.as_deref(), addr, &e, accepted_at, true);
+                });
+                let observer = observer.clone();
+                ws.on_upgrade(move |socket| async move {
+                    let _permit = permit;
+                    drop(pending);
+                    ctx.handshake = Some(ctx.accepted_at.elapsed());
+                    let id = ctx.id;
+                    let control = Arc::new(Control::default());
+                    let mut stream = WebSocketServerStream::new(socket);
+                    stream.control = Some(control.clone());
+                    stream.path = Some(path);
+                    stream.peer = addr;
+                    let registration = reaper.as_ref().map(|r| r.register(id, addr));
+                    let guard = registry.insert(id, control);
+                    // a task of its own so a drain can drop it past the kill grace
+                    let task =
+                        tokio::spawn(observe::serve_span("ws", id, addr, true, async move {
+                            stream.configure(&live.accept);
+                            // the profile is set on the server, not in the option
+                            stream.set_flush_always(flush_always);
+                            if let Some(ref mut slow) = stream.slow_consumer {
+                                slow.set_stats(stats.clone());
+                            }
+                            // peers without connect info are counted under the listen family
+                            let family = addr.unwrap_or(local_addr);
+                            let activity = registration.as_ref().map(|r| r.activity());
+                            let max_bytes = live.max_bytes;
+                            let throttled = throttle(
+                                IdleStream::new(&mut stream, activity),
+                                live.rate_limit.as_ref(),
+                            );
+                            let limited =
+                                LimitedStream::new(stats.track(throttled, &family), max_bytes);
+                            let count = limited.count();
+                            ctx.extensions.insert(count.clone());
+                            let limited = CountedStream::new(limited);
+                            ctx.extensions.insert(limited.stats_handle());
+                            let limited = TimeoutStream::idle(limited, live.idle_timeout);
+                            let limited = killable(limited, Some(&guard));
+                            let reason = Arc::new(Mutex::new(None));
+                            observe::callback(observer.as_deref(), ctx, true, |ctx| {
+                                CLOSE_REASON.scope(reason.clone(), c.handle_ctx(limited, ctx))
+                            })
+                            .await;
+
+                            stream
+                                .close_early(|| {
+                                    let close =
+                                        max_bytes.is_some_and(|m| m.action == LimitAction::Close);
+                                    (close && count.exceeded())
+                                        .then(|| CloseReason {
+                                            code: close::POLICY,
+                                            reason: "byte limit exceeded".into(),
+                                        })
+                                        .or_else(|| {
+                                            reason.lock().unwrap_or_else(|e| e.into_inner()).take()
+                                        })
+                                        .unwrap_or(CloseReason {
+                                            code: close::ERROR,
+                                            reason: String::new(),
+                                        })
+                                })
+                                .await;
+                            drop(registration);
+                            drop(guard);
+                        }));
+                    if let Some(ref reaper) = reaper {
+                        reaper.set_abort(id, task.abort_handle());
+                    }
+                    registry.set_abort(id, task.abort_handle());
+                    let _ = task.await;
+                    registry.remove(id);
+                })
+            },
+        )
+    }
+}
+
+impl TryFrom<&WebSocketServerOption> for WsAcceptOption {
+    type Error = ServerError;
+
+    fn try_from(opt: &WebSocketServerOption) -> ServerResult<Self> {
+        Ok(Self {
+            paths: upgrade_paths(opt)?,
+            duplex_fairness: opt.duplex_fairness,
+            read_buffer_messages: opt.read_buffer_messages,
+            validate_text: opt.validate_text,
+            text_to_bytes: opt.text_to_bytes,
+            max_message_size: opt.max_message_size,
+            max_frame_size: opt.max_frame_size,
+            write_chunk_size: opt.write_chunk_size,
+            keepalive_interval: opt.keepalive_interval,
+            keepalive_timeout: opt.keepalive_timeout,
+            slow_consumer_policy: opt.slow_consumer_policy.clone(),
+            flush_always: false,
         })
     }
 }
 
+/// Upgrade `stream` as the server side of a ws connection, answering the
+/// upgrade request itself. For ws over a stream this crate does not
+/// listen on, like a unix socket or a tls stream accepted by a `TcpServer`.
+///
+/// Requests for a path outside `opt.paths` get a 404 and fail. What the
+/// `WebSocketServer` does around the upgrade, like accept hooks, limits
+/// and drains, is up to the caller. Shutting the stream down after a read
+/// failed on an invalid message closes with the same status as the server.
+pub async fn accept_stream<S>(
+    stream: S,
+    opt: &WsAcceptOption,
+) -> ServerResult<WebSocketServerStream>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let mut path = String::new();
+    // the signature of a tungstenite callback
+    #[allow(clippy::result_large_err)]
+    let check_path = |request: &Request, response: UpgradeResponse| {
+        path = request.uri().path().to_owned();
+        if opt.paths.is_empty() || opt.paths.contains(&path) {
+            return Ok(response);
+        }
+        let mut refused = ErrorResponse::new(None);
+        *refused.status_mut() = tungstenite::http::StatusCode::NOT_FOUND;
+        Err(refused)
+    };
+    let mut config = wire::config(opt.max_message_size, opt.max_frame_size);
+    if opt.flush_always {
+        config.write_buffer_size = 0;
+    }
+    let io: Box<dyn AcceptIo> = Box::new(stream);
+    let socket = accept_hdr_async_with_config(io, check_path, Some(config))
+        .await
+        .map_err(|e| match e {
+            tungstenite::Error::Io(e) => ServerError::Io(e),
+            e => ServerError::Serve(format!("ws upgrade failed ({})", e)),
+        })?;
+
+    let mut stream = WebSocketServerStream::with_socket(Socket::Accepted(socket));
+    stream.path = Some(path);
+    stream.configure(opt);
+    Ok(stream)
+}
+
+/// `path` and `paths` of `opt`, each also with or without its trailing
+/// slash under `PathMatch::TrailingSlash`.
+pub(crate) fn upgrade_paths(opt: &WebSocketServerOption) -> ServerResult<Vec<String>> {
+    let mut paths = vec![];
+    for path in std::iter::once(&opt.path).chain(&opt.paths) {
+        if !path.starts_with('/') {
+            return Err(ServerError::Option(format!(
+                "ws path {:?} does not start with /",
+                path
+            )));
+        }
+        let mut variants = vec![path.clone()];
+        if opt.path_match == PathMatch::TrailingSlash && path != "/" {
+            variants.push(match path.strip_suffix('/') {
+                Some(bare) => bare.to_owned(),
+                None => format!("{}/", path),
+            });
+        }
+        for variant in variants {
+            if !paths.contains(&variant) {
+                paths.push(variant);
+            }
+        }
+    }
+    Ok(paths)
+}
+
+fn validate_fallback(fallback: &Fallback) -> ServerResult<()> {
+    let invalid = |e: &dyn std::fmt::Display| ServerError::Option(format!("ws fallback {}", e));
+    match fallback {
+        Fallback::NotFound => {}
+        Fallback::Redirect(url) => {
+            HeaderValue::try_from(url.as_str()).map_err(|e| invalid(&e))?;
+        }
+        Fallback::Static {
+            status,
+            content_type,
+            ..
+        } => {
+            StatusCode::from_u16(*status).map_err(|e| invalid(&e))?;
+            HeaderValue::try_from(content_type.as_str()).map_err(|e| invalid(&e))?;
+        }
+    }
+    Ok(())
+}
+
+/// Answer of `fallback`, checked by `validate_fallback` at init.
+fn fallback_response(fallback: &Fallback) -> Response {
+    match fallback {
+        Fallback::NotFound => StatusCode::NOT_FOUND.into_response(),
+        Fallback::Redirect(url) => Redirect::temporary(url).into_response(),
+        Fallback::Static {
+            status,
+            body,
+            content_type,
+        } => (
+            StatusCode::from_u16(*status).unwrap_or(StatusCode::NOT_FOUND),
+            [(header::CONTENT_TYPE, content_type.clone())],
+            body.clone(),
+        )
+            .into_response(),
+    }
+}
+
 impl TransportServerTrait for WebSocketServer {
     fn local_addr(&self) -> Option<SocketAddr> {
-        Some(self.listen)
+        Some(self.bound_addr.get_or(self.listen))
     }
 
-    async fn serve<C: TransportServerCallback>(&self, callback: C) -> ServerResult<()> {
-        let svc = Router::new()
-            .route(
-                &self.path,
-                get(
-                    |ws: WebSocketUpgrade,
-                     ConnectInfo(addr): ConnectInfo<SocketAddr>,
-                     State(c): State<C>| async move {
-                        ws.on_upgrade(move |socket| async move {
-                            let stream = WebSocketServerStream::new(socket);
-                            let _ = c.handle(stream, Some(addr)).await;
-                        })
-                    },
-                ),
+    /// Stops accepting through `Handle::graceful_shutdown`, upgraded
+    /// connections are waited for like a `drain` without a notice, the grace
+    /// period counted from the signal. The server does not accept upgrades again.
+    async fn serve_with_shutdown<C, F>(
+        &self,
+        callback: C,
+        signal: F,
+        grace: Duration,
+    ) -> ServerResult<()>
+    where
+        C: TransportServerCallback,
+        F: Future<Output = ()> + Send + Sync,
+    {
+        let serve = self.serve(callback);
+        tokio::pin!(serve);
+        tokio::select! {
+            res = &mut serve => return res,
+            _ = signal => {}
+        }
+
+        let deadline = Instant::now() + grace;
+        self.handle.graceful_shutdown(Some(grace));
+        serve.await?;
+        self.registry
+            .shutdown(
+                deadline.saturating_duration_since(Instant::now()),
+                self.kill_grace,
             )
+            .await
+            .finish("ws", self.observer.as_deref());
+        Ok(())
+    }
+
+    async fn serve<C: TransportServerCallback>(&self, callback: C) -> ServerResult<()> {
+        let fallback = Arc::new(self.fallback.clone());
+        let answer = move || {
+            let fallback = fallback.clone();
+            async move { fallback_response(&fallback) }
+        };
+        // other methods on an upgrade path look like any other missing page
+        let upgrade = self.upgrade_handler().fallback(answer.clone());
+        let svc = self
+            .paths
+            .iter()
+            .fold(Router::new(), |router, path| {
+                router.route(path, upgrade.clone())
+            })
+            .fallback(answer)
             .with_state(callback);
+        let _reaper = self.reaper.as_ref().map(|r| r.spawn());
+        let _watch = self.watch_tls();
 
+        let listener = match self.early.take() {
+            Some(listener) => listener,
+            // served before or built unbound, bind the port it listened on
+            None => bind_listener(
+                self.bound_addr.get_or(self.listen),
+                self.send_buffer_size,
+                self.busy_poll,
+            )
+            .await?
+            .into_std()?,
+        };
+        self.bound_addr.set(listener.local_addr()?);
         if let Some(ref tls_cfg) = self.tls_cfg {
             if self.tcp_nodelay {
                 let acceptor =
                     RustlsAcceptor::new(tls_cfg.clone()).acceptor(NoDelayAcceptor::new());
-                axum_server::bind(self.listen)
+                axum_server::from_tcp(listener)
                     .acceptor(acceptor)
+                    .handle(self.handle.clone())
                     .serve(svc.into_make_service_with_connect_info::<SocketAddr>())
                     .await?;
             } else {
-                axum_server::bind_rustls(self.listen, tls_cfg.clone())
+                axum_server::from_tcp_rustls(listener, tls_cfg.clone())
+                    .handle(self.handle.clone())
                     .serve(svc.into_make_service_with_connect_info::<SocketAddr>())
                     .await?
             }
         } else {
             if self.tcp_nodelay {
-                axum_server::bind(self.listen)
+                axum_server::from_tcp(listener)
                     .acceptor(NoDelayAcceptor::new())
+                    .handle(self.handle.clone())
                     .serve(svc.into_make_service_with_connect_info::<SocketAddr>())
                     .await?
             } else {
-                axum_server::bind(self.listen)
+                axum_server::from_tcp(listener)
+                    .handle(self.handle.clone())
                     .serve(svc.into_make_service_with_connect_info::<SocketAddr>())
                     .await?
             }
@@ -105,28 +704,442 @@ impl TransportServerTrait for WebSocketServer {
     }
 }
 
+fn reload_tls(
+    tls_cfg: &RustlsConfig,
+    live: &ArcSwap<Live>,
+    tls_opt: TlsServerOption,
+) -> Result<(), TlsError> {
+    let (config, ticket_keys) = tls_opt.clone().build()?;
+    tls_cfg.reload_from_config(Arc::new(config));
+    live.rcu(|current| {
+        let mut source = TransportServerOption::clone(&current.source);
+        source.tls = Some(tls_opt.clone());
+        Live {
+            ticket_keys: ticket_keys.clone(),
+            source: Arc::new(source),
+            ..Live::clone(current)
+        }
+    });
+    Ok(())
+}
+
+/// The type and payload length of `msg`.
+fn frame_kind(msg: &Message) -> (FrameKind, usize) {
+    match msg {
+        Message::Binary(data) => (FrameKind::Binary, data.len()),
+        Message::Text(data) => (FrameKind::Text, data.len()),
+        Message::Ping(data) => (FrameKind::Ping, data.len()),
+        Message::Pong(data) => (FrameKind::Pong, data.len()),
+        Message::Close(frame) => (
+            FrameKind::Close,
+            frame.as_ref().map_or(0, |f| f.reason.len()),
+        ),
+    }
+}
+
 pub struct WebSocketServerStream {
-    tx: SplitSink<WebSocket, Message>,
-    rx: SplitStream<WebSocket>,
-    chunk: Option<Bytes>,
+    tx: SplitSink<Socket, Message>,
+    rx: SplitStream<Socket>,
+    chunks: VecDeque<Bytes>,
+    rx_err: Option<std::io::Error>,
+    read_buffer_messages: usize,
+    write_chunk_size: Option<usize>,
+    duplex_fairness: bool,
+    read_waker: Option<Waker>,
+    validate_text: bool,
+    text_to_bytes: bool,
+    /// Status of the close frame `poll_shutdown` sends, set by a protocol
+    /// error or `close_with`.
+    protocol_close: Option<CloseReason>,
+    close_reason: Option<CloseReason>,
+    /// A Close frame was queued by us.
+    closed: bool,
+    control: Option<Arc<Control>>,
+    notice_sent: bool,
+    notice_flushed: bool,
+    flush_always: bool,
+    frame_stats: FrameStats,
+    keepalive: Option<PingKeepalive>,
+    slow_consumer: Option<SlowConsumer>,
+    /// Reads and messages answered without waiting on the socket.
+    budget: Budget,
+    /// Path of the upgrade request, and the peer when upgraded by a `WebSocketServer`.
+    path: Option<String>,
+    peer: Option<SocketAddr>,
+}
+
+impl std::fmt::Debug for WebSocketServerStream {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("WebSocketServerStream")
+            .field("peer", &self.peer)
+            .field("path", &self.path)
+            .field("closed", &self.closed)
+            .field("close_reason", &self.close_reason)
+            .finish()
+    }
 }
 
 impl WebSocketServerStream {
     pub fn new(socket: WebSocket) -> Self {
-        let (tx, rx) = socket.split();
+        Self::with_socket(Socket::Upgraded(socket))
+    }
 
+    fn with_socket(socket: Socket) -> Self {
+        let (tx, rx) = socket.split();
         Self {
             tx,
             rx,
-            chunk: None,
+            chunks: VecDeque::new(),
+            rx_err: None,
+            read_buffer_messages: 1,
+            write_chunk_size: None,
+            duplex_fairness: false,
+            read_waker: None,
+            validate_text: false,
+            text_to_bytes: true,
+            protocol_close: None,
+            close_reason: None,
+            closed: false,
+            control: None,
+            notice_sent: false,
+            notice_flushed: false,
+            flush_always: false,
+            frame_stats: FrameStats::default(),
+            keepalive: None,
+            slow_consumer: None,
+            budget: Budget::default(),
+            path: None,
+            peer: None,
         }
     }
 
-    fn has_chunk(&self) -> bool {
-        if let Some(ref chunk) = self.chunk {
-            chunk.remaining() > 0
-        } else {
-            false
+    /// Path of the upgrade request.
+    pub fn path(&self) -> Option<&str> {
+        self.path.as_deref()
+    }
+
+    /// Close frame status received from the client, if any.
+    pub fn close_reason(&self) -> Option<&CloseReason> {
+        self.close_reason.as_ref()
+    }
+
+    /// Send a close frame with the given status, unless one was sent
+    /// already, and close the stream. `shutdown` sends a normal closure, or
+    /// the status of the protocol error that failed a read.
+    pub async fn close_with(&mut self, reason: CloseReason) -> std::io::Result<()> {
+        if !self.closed {
+            self.protocol_close = Some(reason);
+        }
+        self.shutdown().await
+    }
+
+    /// Close a stream its callback left open, with the status of the
+    /// protocol error that failed a read or else the one of `fallback`.
+    /// Goes through `poll_shutdown` like the streams of `accept_stream`.
+    async fn close_early(&mut self, fallback: impl FnOnce() -> CloseReason) {
+        if self.closed {
+            return;
+        }
+        let reason = self.protocol_close.take().unwrap_or_else(fallback);
+        log::debug!(
+            "ws connection {:?} closed early ({} {})",
+            self.peer,
+            reason.code,
+            reason.reason
+        );
+        let _ = self.close_with(reason).await;
+    }
+
+    /// Apply the stream settings of `opt`.
+    fn configure(&mut self, opt: &WsAcceptOption) {
+        self.set_duplex_fairness(opt.duplex_fairness);
+        self.set_read_buffer_messages(opt.read_buffer_messages);
+        self.set_write_chunk_size(opt.write_chunk_size);
+        self.set_validate_text(opt.validate_text);
+        self.set_text_to_bytes(opt.text_to_bytes);
+        self.set_flush_always(opt.flush_always);
+        self.set_keepalive(opt.keepalive_interval, opt.keepalive_timeout);
+        self.set_slow_consumer_policy(opt.slow_consumer_policy.clone());
+    }
+
+    /// Drive the read side while writing, buffering up to the read buffer limit.
+    pub fn set_duplex_fairness(&mut self, enable: bool) {
+        self.duplex_fairness = enable;
+    }
+
+    /// Number of messages buffered ahead of the reader, at least one, and
+    /// none past the message that reaches `MAX_READ_AHEAD_SIZE` bytes.
+    pub fn set_read_buffer_messages(&mut self, messages: usize) {
+        self.read_buffer_messages = messages.max(1);
+    }
+
+    /// Send at most `size` bytes of a write as one message, `None` sends it whole.
+    pub fn set_write_chunk_size(&mut self, size: Option<usize>) {
+        self.write_chunk_size = size.map(|size| size.max(1));
+    }
+
+    /// Fail the read side with `InvalidData` and a Close(1007) on invalid UTF-8 Text.
+    pub fn set_validate_text(&mut self, enable: bool) {
+        self.validate_text = enable;
+    }
+
+    /// Pass Text messages through as bytes, otherwise reject them with a Close(1003).
+    pub fn set_text_to_bytes(&mut self, enable: bool) {
+        self.text_to_bytes = enable;
+    }
+
+    /// Start a flush after every message instead of waiting for `poll_flush`.
+    pub fn set_flush_always(&mut self, enable: bool) {
+        self.flush_always = enable;
+    }
+
+    /// Bytes received but not yet consumed by the reader.
+    pub fn buffered_len(&self) -> usize {
+        self.chunks.iter().map(|chunk| chunk.remaining()).sum()
+    }
+
+    /// Ping the peer every `interval`, failing the stream when a Pong is
+    /// later than `timeout` or does not echo its Ping. `None` turns it off.
+    pub fn set_keepalive(&mut self, interval: Option<Duration>, timeout: Duration) {
+        self.keepalive = interval.map(|interval| PingKeepalive::new(interval, timeout));
+    }
+
+    /// Give up on a peer that stops reading, see `SlowConsumerOption`.
+    pub fn set_slow_consumer_policy(&mut self, policy: Option<SlowConsumerOption>) {
+        self.slow_consumer = policy.map(SlowConsumer::new);
+    }
+
+    /// Messages received so far by type, including those the reader never saw.
+    pub fn frame_stats(&self) -> FrameStats {
+        self.frame_stats
+    }
+
+    /// Write the start of `data` as one message, at most the write chunk size
+    /// and split off only once the sink is ready, the rest stays in `data`.
+    ///
+    /// Unlike `poll_write` the payload is not copied when `data` is the
+    /// unique owner of a vec backed buffer.
+    pub fn poll_write_bytes(
+        &mut self,
+        cx: &mut std::task::Context<'_>,
+        data: &mut Bytes,
+    ) -> Poll<std::io::Result<usize>> {
+        let chunk_size = self.write_chunk_size;
+        self.poll_send(cx, || wire::take_chunk(data, chunk_size))
+    }
+
+    /// Write all of `data`, one message per write chunk.
+    pub async fn write_bytes(&mut self, mut data: Bytes) -> std::io::Result<usize> {
+        let mut written = 0;
+        loop {
+            written += std::future::poll_fn(|cx| self.poll_write_bytes(cx, &mut data)).await?;
+            if data.is_empty() {
+                return Ok(written);
+            }
+        }
+    }
+
+    /// Remember the close status for the serve loop and fail the read side.
+    fn protocol_error(&mut self, code: u16, reason: &str) -> std::io::Error {
+        self.protocol_close = Some(CloseReason {
+            code,
+            reason: reason.to_owned(),
+        });
+        std::io::Error::new(std::io::ErrorKind::InvalidData, reason)
+    }
+
+    /// Send a pending drain notice ahead of anything the callback writes.
+    ///
+    /// Errors are left for the next read or write to report.
+    fn poll_notice(&mut self, cx: &mut std::task::Context<'_>) {
+        if self.notice_sent {
+            if !self.notice_flushed {
+                self.notice_flushed = self.tx.poll_flush_unpin(cx).is_ready();
+            }
+            return;
+        }
+        if self.closed {
+            return;
+        }
+        let Some(notice) = self.control.as_ref().and_then(|c| c.poll_notice(cx)) else {
+            return;
+        };
+        if !matches!(self.tx.poll_ready_unpin(cx), Poll::Ready(Ok(()))) {
+            return;
+        }
+
+        let msg = match notice.frame {
+            NoticeFrame::Close => {
+                // the callback may still read until the client answers
+                self.closed = true;
+                Message::Close(Some(CloseFrame {
+                    code: notice.code,
+                    reason: notice.reason.into(),
+                }))
+            }
+            NoticeFrame::Ping => Message::Ping(notice.reason.into_bytes()),
+            NoticeFrame::Text => Message::Text(notice.reason),
+        };
+        self.notice_sent = true;
+        if self.tx.start_send_unpin(msg).is_ok() {
+            self.notice_flushed = self.tx.poll_flush_unpin(cx).is_ready();
+        }
+    }
+
+    fn poll_keepalive(&mut self, cx: &mut std::task::Context<'_>) -> std::io::Result<()> {
+        match self.keepalive.as_mut() {
+            Some(keepalive) => keepalive.poll_send(&mut self.tx, Message::Ping, cx),
+            None => Ok(()),
+        }
+    }
+
+    /// Send the message of `payload` once the sink is ready, for the writes
+    /// of `AsyncWrite` and `poll_write_bytes`.
+    fn poll_send(
+        &mut self,
+        cx: &mut std::task::Context<'_>,
+        payload: impl FnOnce() -> Bytes,
+    ) -> Poll<std::io::Result<usize>> {
+        self.poll_keepalive(cx)?;
+        self.poll_notice(cx);
+        self.poll_duplex(cx);
+
+        ready!(self.poll_write_ready(cx))?;
+
+        let payload = payload();
+        let len = payload.len();
+        match self.tx.start_send_unpin(Message::Binary(payload.into())) {
+            Ok(()) => {
+                if let Some(ref mut slow) = self.slow_consumer {
+                    slow.on_write(len);
+                }
+                if self.flush_always {
+                    // a pending flush goes on with the next write or flush, which report its error
+                    let _ = self.tx.poll_flush_unpin(cx);
+                }
+                Poll::Ready(Ok(len))
+            }
+            Err(e) => Poll::Ready(Err(std::io::Error::other(e))),
+        }
+    }
+
+    fn poll_write_ready(&mut self, cx: &mut std::task::Context<'_>) -> Poll<std::io::Result<()>> {
+        let Some(ref mut slow) = self.slow_consumer else {
+            return self.tx.poll_ready_unpin(cx).map_err(std::io::Error::other);
+        };
+        let close = || {
+            Message::Close(Some(CloseFrame {
+                code: close::POLICY,
+                reason: "slow consumer".into(),
+            }))
+        };
+        let res = slow.poll_ready(&mut self.tx, close, cx);
+        if slow.tripped() {
+            // a close after the callback would block on the same peer
+            self.closed = true;
+        }
+        res
+    }
+
+    fn poll_recv(
+        &mut self,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<Option<std::io::Result<Bytes>>> {
+        loop {
+            // skipped frames are charged too, a flood of them must yield as well
+            ready!(self.budget.poll_proceed(cx));
+            let polled = self.rx.poll_next_unpin(cx);
+            if polled.is_pending() {
+                self.budget.reset();
+            }
+            let msg = match ready!(polled) {
+                None => return Poll::Ready(None),
+                Some(Err(err)) if self.validate_text && wire::is_utf8_error(&err) => {
+                    let err = self.protocol_error(close::INVALID, "invalid utf-8 text");
+                    return Poll::Ready(Some(Err(err)));
+                }
+                Some(Err(err)) if wire::is_capacity_error(&err) => {
+                    let _ = self.protocol_error(close::TOO_BIG, "message too big");
+                    return Poll::Ready(Some(Err(wire::too_big(err))));
+                }
+                Some(Err(err)) => return Poll::Ready(Some(Err(std::io::Error::other(err)))),
+                Some(Ok(msg)) => msg,
+            };
+            if let Some(keepalive) = self.keepalive.as_mut() {
+                keepalive.on_traffic();
+            }
+
+            let (kind, len) = frame_kind(&msg);
+            self.frame_stats.record(kind, len);
+            let chunk = match msg {
+                Message::Binary(data) => Bytes::from(data),
+                // a String is valid UTF-8 by construction, the frame layer already checked it
+                Message::Text(data) if self.text_to_bytes => Bytes::from(data),
+                Message::Text(_) => {
+                    let err = self.protocol_error(close::UNSUPPORTED, "text not accepted");
+                    return Poll::Ready(Some(Err(err)));
+                }
+                Message::Pong(data) => {
+                    if let Some(keepalive) = self.keepalive.as_mut() {
+                        if let Err(err) = keepalive.on_pong(&data) {
+                            return Poll::Ready(Some(Err(err)));
+                        }
+                    }
+                    self.frame_stats.discard("server", kind);
+                    continue;
+                }
+                Message::Close(frame) => {
+                    self.close_reason = frame.map(|frame| CloseReason {
+                        code: frame.code,
+                        reason: frame.reason.into_owned(),
+                    });
+                    // the echo tungstenite queued answers it, nothing more to send
+                    self.closed = true;
+                    let _ = self.tx.poll_flush_unpin(cx);
+                    self.frame_stats.discard("server", kind);
+                    continue;
+                }
+                _ => {
+                    self.frame_stats.discard("server", kind);
+                    continue;
+                }
+            };
+
+            if chunk.has_remaining() {
+                return Poll::Ready(Some(Ok(chunk)));
+            }
+        }
+    }
+
+    /// Pull ready messages until the read buffer is full, never blocking.
+    fn poll_prefetch(&mut self, cx: &mut std::task::Context<'_>) {
+        while self.chunks.len() < self.read_buffer_messages
+            && self.buffered_len() < MAX_READ_AHEAD_SIZE
+            && self.rx_err.is_none()
+        {
+            match self.poll_recv(cx) {
+                Poll::Ready(Some(Ok(chunk))) => self.chunks.push_back(chunk),
+                Poll::Ready(Some(Err(err))) => self.rx_err = Some(err),
+                // eof is left for the reader to observe
+                _ => return,
+            }
+        }
+    }
+
+    fn poll_duplex(&mut self, cx: &mut std::task::Context<'_>) {
+        if !self.duplex_fairness {
+            return;
+        }
+
+        let waker = duplex_waker(cx.waker(), self.read_waker.as_ref());
+        let mut cx = std::task::Context::from_waker(&waker);
+        let buffered = self.chunks.len();
+        self.poll_prefetch(&mut cx);
+
+        if self.chunks.len() > buffered || self.rx_err.is_some() {
+            if let Some(waker) = self.read_waker.take() {
+                waker.wake();
+            }
         }
     }
 }
@@ -137,34 +1150,45 @@ impl AsyncBufRead for WebSocketServerStream {
         cx: &mut std::task::Context<'_>,
     ) -> Poll<std::io::Result<&[u8]>> {
         let this = self.get_mut();
-        loop {
-            if this.has_chunk() {
-                let chunk = this.chunk.as_ref().unwrap();
-                let buf = chunk.chunk();
-                return Poll::Ready(Ok(buf));
-            } else {
-                let chunk = match this.rx.poll_next_unpin(cx) {
-                    Poll::Pending => return Poll::Pending,
-                    Poll::Ready(None) => return Poll::Ready(Ok(&[])),
-                    Poll::Ready(Some(Err(err))) => {
-                        return Poll::Ready(Err(std::io::Error::other(err)))
-                    }
-                    Poll::Ready(Some(Ok(msg))) => match msg {
-                        Message::Binary(data) => Bytes::from(data),
-                        Message::Text(data) => Bytes::from(data),
-                        _ => continue,
-                    },
-                };
+        this.poll_keepalive(cx)?;
+        this.poll_notice(cx);
+        if let Some(ref slow) = this.slow_consumer {
+            slow.check_read()?;
+        }
+        if !this.chunks.is_empty() {
+            // buffered data reaches no socket, charge it here
+            ready!(this.budget.poll_proceed(cx));
+        }
+        if this.chunks.is_empty() {
+            if let Some(err) = this.rx_err.take() {
+                return Poll::Ready(Err(err));
+            }
 
-                this.chunk = Some(chunk);
+            match this.poll_recv(cx) {
+                Poll::Pending => {
+                    if this.duplex_fairness {
+                        this.read_waker = Some(cx.waker().clone());
+                    }
+                    return Poll::Pending;
+                }
+                Poll::Ready(None) => return Poll::Ready(Ok(&[])),
+                Poll::Ready(Some(Err(err))) => return Poll::Ready(Err(err)),
+                Poll::Ready(Some(Ok(chunk))) => this.chunks.push_back(chunk),
             }
         }
+
+        this.poll_prefetch(cx);
+        Poll::Ready(Ok(this.chunks.front().map_or(&[], |chunk| chunk.chunk())))
     }
 
     fn consume(self: Pin<&mut Self>, amt: usize) {
         if amt > 0 {
-            if let Some(chunk) = self.get_mut().chunk.as_mut() {
+            let this = self.get_mut();
+            if let Some(chunk) = this.chunks.front_mut() {
                 chunk.advance(amt);
+                if !chunk.has_remaining() {
+                    this.chunks.pop_front();
+                }
             }
         }
     }
@@ -194,6 +1218,8 @@ impl AsyncRead for WebSocketServerStream {
     }
 }
 
+crate::timeout_io_methods!(WebSocketServerStream);
+
 impl AsyncWrite for WebSocketServerStream {
     fn poll_write(
         self: std::pin::Pin<&mut Self>,
@@ -201,35 +1227,65 @@ impl AsyncWrite for WebSocketServerStream {
         buf: &[u8],
     ) -> std::task::Poll<Result<usize, std::io::Error>> {
         let this = self.get_mut();
+        let chunk_size = this.write_chunk_size;
+        this.poll_send(cx, || {
+            Bytes::copy_from_slice(wire::write_chunk(buf, chunk_size))
+        })
+    }
 
-        ready!(this
-            .tx
-            .poll_ready_unpin(cx)
-            .map_err(|e| std::io::Error::other(e)))?;
+    /// The slices go out as one Binary message, copied once.
+    fn poll_write_vectored(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        bufs: &[std::io::IoSlice<'_>],
+    ) -> std::task::Poll<Result<usize, std::io::Error>> {
+        let this = self.get_mut();
+        let chunk_size = this.write_chunk_size;
+        this.poll_send(cx, || wire::gather_chunk(bufs, chunk_size).into())
+    }
 
-        match this.tx.start_send_unpin(Message::Binary(buf.into())) {
-            Ok(()) => Poll::Ready(Ok(buf.len())),
-            Err(e) => Poll::Ready(Err(std::io::Error::other(e))),
-        }
+    fn is_write_vectored(&self) -> bool {
+        true
     }
 
     fn poll_flush(
         self: std::pin::Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
     ) -> std::task::Poll<Result<(), std::io::Error>> {
-        self.get_mut()
-            .tx
-            .poll_flush_unpin(cx)
-            .map_err(|e| std::io::Error::other(e))
+        let this = self.get_mut();
+        this.poll_notice(cx);
+        this.poll_duplex(cx);
+
+        ready!(this.tx.poll_flush_unpin(cx)).map_err(std::io::Error::other)?;
+        if let Some(ref mut slow) = this.slow_consumer {
+            slow.on_flush();
+        }
+        Poll::Ready(Ok(()))
     }
 
     fn poll_shutdown(
         self: std::pin::Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
     ) -> std::task::Poll<Result<(), std::io::Error>> {
-        self.get_mut()
-            .tx
-            .poll_close_unpin(cx)
-            .map_err(|e| std::io::Error::other(e))
+        let this = self.get_mut();
+        if !this.closed {
+            ready!(this.tx.poll_ready_unpin(cx)).map_err(std::io::Error::other)?;
+            // a read failed by a protocol error closes with its status
+            let frame = match this.protocol_close.take() {
+                Some(reason) => CloseFrame {
+                    code: reason.code,
+                    reason: reason.reason.into(),
+                },
+                None => CloseFrame {
+                    code: close::NORMAL,
+                    reason: "".into(),
+                },
+            };
+            this.tx
+                .start_send_unpin(Message::Close(Some(frame)))
+                .map_err(std::io::Error::other)?;
+            this.closed = true;
+        }
+        this.tx.poll_close_unpin(cx).map_err(std::io::Error::other)
     }
 }
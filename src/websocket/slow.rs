@@ -0,0 +1,144 @@
+//! Slow Consumer Policy
+//!
+//! Driven from the write path like the keepalive. Past `max_pending` bytes
+//! without a completed flush a write first waits for one, so a peer that
+//! stopped reading blocks writes instead of growing the buffers, and the
+//! block is timed.
+
+use std::{
+    future::Future,
+    io::{Error, ErrorKind},
+    pin::Pin,
+    sync::Arc,
+    task::{ready, Context, Poll},
+};
+
+use futures_util::{Sink, SinkExt};
+use tokio::time::Sleep;
+
+use crate::stats::ServerStats;
+
+use super::option::{SlowConsumerAction, SlowConsumerOption};
+
+pub(crate) struct SlowConsumer {
+    opt: SlowConsumerOption,
+    /// Bytes written since the last completed flush.
+    pending: usize,
+    /// Deadline of the current block, unset while writes get through.
+    stall: Option<Pin<Box<Sleep>>>,
+    tripped: bool,
+    stats: Option<Arc<ServerStats>>,
+}
+
+impl SlowConsumer {
+    pub(crate) fn new(opt: SlowConsumerOption) -> Self {
+        Self {
+            opt,
+            pending: 0,
+            stall: None,
+            tripped: false,
+            stats: None,
+        }
+    }
+
+    /// Count trips in the `slow_consumers` of `stats`.
+    pub(crate) fn set_stats(&mut self, stats: Arc<ServerStats>) {
+        self.stats = Some(stats);
+    }
+
+    pub(crate) fn tripped(&self) -> bool {
+        self.tripped
+    }
+
+    /// Wait for room to write, failing once writes stayed blocked for `max_stall`.
+    pub(crate) fn poll_ready<S, M>(
+        &mut self,
+        sink: &mut S,
+        close: impl FnOnce() -> M,
+        cx: &mut Context<'_>,
+    ) -> Poll<std::io::Result<()>>
+    where
+        S: Sink<M> + Unpin,
+        S::Error: std::error::Error + Send + Sync + 'static,
+    {
+        if self.tripped {
+            return Poll::Ready(Err(self.error()));
+        }
+
+        let ready = if self.pending >= self.opt.max_pending {
+            match sink.poll_flush_unpin(cx) {
+                Poll::Ready(Ok(())) => {
+                    self.pending = 0;
+                    sink.poll_ready_unpin(cx)
+                }
+                other => other,
+            }
+        } else {
+            sink.poll_ready_unpin(cx)
+        };
+        if let Poll::Ready(res) = ready {
+            self.stall = None;
+            return Poll::Ready(res.map_err(Error::other));
+        }
+
+        let max_stall = self.opt.max_stall;
+        let stall = self
+            .stall
+            .get_or_insert_with(|| Box::pin(tokio::time::sleep(max_stall)));
+        ready!(stall.as_mut().poll(cx));
+        self.trip(sink, close, cx);
+        Poll::Ready(Err(self.error()))
+    }
+
+    pub(crate) fn on_write(&mut self, len: usize) {
+        self.pending += len;
+    }
+
+    pub(crate) fn on_flush(&mut self) {
+        self.pending = 0;
+    }
+
+    /// Reads fail as well once a `Disconnect` policy tripped.
+    pub(crate) fn check_read(&self) -> std::io::Result<()> {
+        if self.tripped && self.opt.action == SlowConsumerAction::Disconnect {
+            return Err(self.error());
+        }
+        Ok(())
+    }
+
+    fn trip<S, M>(&mut self, sink: &mut S, close: impl FnOnce() -> M, cx: &mut Context<'_>)
+    where
+        S: Sink<M> + Unpin,
+    {
+        self.tripped = true;
+        self.stall = None;
+        if let Some(ref stats) = self.stats {
+            stats.record_slow_consumer();
+        }
+        log::debug!(
+            "ws peer read nothing for {:?}, {:?}",
+            self.opt.max_stall,
+            self.opt.action
+        );
+
+        // the close only fits when the block cleared meanwhile
+        if self.opt.action == SlowConsumerAction::Disconnect
+            && matches!(sink.poll_ready_unpin(cx), Poll::Ready(Ok(())))
+            && sink.start_send_unpin(close()).is_ok()
+        {
+            let _ = sink.poll_flush_unpin(cx);
+        }
+    }
+
+    fn error(&self) -> Error {
+        match self.opt.action {
+            SlowConsumerAction::Disconnect => Error::new(
+                ErrorKind::ConnectionAborted,
+                "ws slow consumer disconnected",
+            ),
+            SlowConsumerAction::ErrorWrites => {
+                Error::new(ErrorKind::TimedOut, "ws slow consumer, writes stalled")
+            }
+        }
+    }
+}
@@ -0,0 +1,116 @@
+//! Server Socket
+//!
+//! The socket under a `WebSocketServerStream`, either upgraded by axum or
+//! accepted by tungstenite over any stream, both speaking axum messages.
+
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use axum::extract::ws::{CloseFrame, Message, WebSocket};
+use futures_util::{ready, Sink, SinkExt, Stream, StreamExt};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_tungstenite::{
+    tungstenite::{
+        protocol::{frame::coding::CloseCode, CloseFrame as WsCloseFrame},
+        Message as WsMessage,
+    },
+    WebSocketStream,
+};
+
+/// The stream under an accepted socket, boxed to keep the server stream
+/// a single type.
+pub(crate) trait AcceptIo: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<S: AsyncRead + AsyncWrite + Unpin + Send> AcceptIo for S {}
+
+pub(crate) enum Socket {
+    Upgraded(WebSocket),
+    Accepted(WebSocketStream<Box<dyn AcceptIo>>),
+}
+
+/// Axum links its own tungstenite, the messages are moved across.
+fn from_tungstenite(msg: WsMessage) -> Option<Message> {
+    Some(match msg {
+        WsMessage::Text(data) => Message::Text(data),
+        WsMessage::Binary(data) => Message::Binary(data),
+        WsMessage::Ping(data) => Message::Ping(data),
+        WsMessage::Pong(data) => Message::Pong(data),
+        WsMessage::Close(frame) => Message::Close(frame.map(|frame| CloseFrame {
+            code: frame.code.into(),
+            reason: frame.reason,
+        })),
+        // raw frames are only seen when writing them
+        WsMessage::Frame(_) => return None,
+    })
+}
+
+fn into_tungstenite(msg: Message) -> WsMessage {
+    match msg {
+        Message::Text(data) => WsMessage::Text(data),
+        Message::Binary(data) => WsMessage::Binary(data),
+        Message::Ping(data) => WsMessage::Ping(data),
+        Message::Pong(data) => WsMessage::Pong(data),
+        Message::Close(frame) => WsMessage::Close(frame.map(|frame| WsCloseFrame {
+            code: CloseCode::from(frame.code),
+            reason: frame.reason,
+        })),
+    }
+}
+
+impl Stream for Socket {
+    type Item = Result<Message, axum::Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let ws = match self.get_mut() {
+            Socket::Upgraded(ws) => return ws.poll_next_unpin(cx),
+            Socket::Accepted(ws) => ws,
+        };
+        loop {
+            match ready!(ws.poll_next_unpin(cx)) {
+                Some(Ok(msg)) => {
+                    if let Some(msg) = from_tungstenite(msg) {
+                        return Poll::Ready(Some(Ok(msg)));
+                    }
+                }
+                Some(Err(e)) => return Poll::Ready(Some(Err(axum::Error::new(e)))),
+                None => return Poll::Ready(None),
+            }
+        }
+    }
+}
+
+impl Sink<Message> for Socket {
+    type Error = axum::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        match self.get_mut() {
+            Socket::Upgraded(ws) => ws.poll_ready_unpin(cx),
+            Socket::Accepted(ws) => ws.poll_ready_unpin(cx).map_err(axum::Error::new),
+        }
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: Message) -> Result<(), Self::Error> {
+        match self.get_mut() {
+            Socket::Upgraded(ws) => ws.start_send_unpin(item),
+            Socket::Accepted(ws) => ws
+                .start_send_unpin(into_tungstenite(item))
+                .map_err(axum::Error::new),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        match self.get_mut() {
+            Socket::Upgraded(ws) => ws.poll_flush_unpin(cx),
+            Socket::Accepted(ws) => ws.poll_flush_unpin(cx).map_err(axum::Error::new),
+        }
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        match self.get_mut() {
+            Socket::Upgraded(ws) => ws.poll_close_unpin(cx),
+            Socket::Accepted(ws) => ws.poll_close_unpin(cx).map_err(axum::Error::new),
+        }
+    }
+}
@@ -0,0 +1,419 @@
+//! WebSocket Wire Profile
+//!
+//! Everything a peer of another version can observe, pinned here instead of
+//! following the defaults of axum and tungstenite. Changing any of it needs a
+//! fixture update under `fixtures/ws` and a CHANGELOG entry.
+
+use std::io::IoSlice;
+
+use bytes::Bytes;
+use tokio_tungstenite::tungstenite::{self, protocol::WebSocketConfig};
+
+/// Data goes out as Binary messages, Text is only accepted (see `text_to_bytes`).
+pub const DATA_MESSAGE: &str = "binary";
+
+/// Largest message accepted, default of `max_message_size` in the options.
+pub const MAX_MESSAGE_SIZE: usize = 64 << 20;
+
+/// Largest frame accepted, default of `max_frame_size` in the options.
+pub const MAX_FRAME_SIZE: usize = 16 << 20;
+
+/// No subprotocol and no extension is offered or accepted, so no
+/// `Sec-WebSocket-Protocol` or `Sec-WebSocket-Extensions` header is sent.
+pub const EXTENSIONS: &[&str] = &[];
+
+/// Close codes sent by this crate and what they mean.
+pub mod close {
+    /// The callback returned and the stream was shut down.
+    pub const NORMAL: u16 = 1000;
+    /// The server is draining, see `CloseNotice`.
+    pub const AWAY: u16 = 1001;
+    /// A Text message arrived while `text_to_bytes` is off.
+    pub const UNSUPPORTED: u16 = 1003;
+    /// A Text message with invalid UTF-8 arrived while `validate_text` is on.
+    pub const INVALID: u16 = 1007;
+    /// The byte limit of `max_bytes` was reached.
+    pub const POLICY: u16 = 1008;
+    /// A message or frame over `max_message_size` or `max_frame_size` arrived.
+    pub const TOO_BIG: u16 = 1009;
+    /// The callback returned without closing and gave no reason.
+    pub const ERROR: u16 = 1011;
+}
+
+/// Request line and headers of the client upgrade request, in order.
+pub const CLIENT_REQUEST_HEADERS: &[&str] = &[
+    "host",
+    "connection",
+    "upgrade",
+    "sec-websocket-version",
+    "sec-websocket-key",
+];
+
+/// Headers of the server upgrade response, in order.
+pub const SERVER_RESPONSE_HEADERS: &[&str] =
+    &["connection", "upgrade", "sec-websocket-accept", "date"];
+
+/// Tungstenite config with the profile sizes.
+pub(crate) fn config(max_message_size: usize, max_frame_size: usize) -> WebSocketConfig {
+    WebSocketConfig {
+        max_message_size: Some(max_message_size),
+        max_frame_size: Some(max_frame_size),
+        max_write_buffer_size: super::MAX_WRITE_BUFFER_SIZE,
+        ..Default::default()
+    }
+}
+
+/// The part of `buf` sent as one message.
+pub(crate) fn write_chunk(buf: &[u8], chunk_size: Option<usize>) -> &[u8] {
+    match chunk_size {
+        Some(size) => &buf[..buf.len().min(size)],
+        None => buf,
+    }
+}
+
+/// The part of `data` sent as one message, split off without a copy.
+pub(crate) fn take_chunk(data: &mut Bytes, chunk_size: Option<usize>) -> Bytes {
+    match chunk_size {
+        Some(size) if size < data.len() => data.split_to(size),
+        _ => std::mem::take(data),
+    }
+}
+
+/// The start of `bufs` sent as one message, gathered with a single copy.
+pub(crate) fn gather_chunk(bufs: &[IoSlice<'_>], chunk_size: Option<usize>) -> Vec<u8> {
+    let total = bufs.iter().map(|buf| buf.len()).sum::<usize>();
+    let len = chunk_size.map_or(total, |size| total.min(size));
+    let mut payload = Vec::with_capacity(len);
+    for buf in bufs {
+        let take = buf.len().min(len - payload.len());
+        payload.extend_from_slice(&buf[..take]);
+        if payload.len() == len {
+            break;
+        }
+    }
+    payload
+}
+
+/// Read error of a message or frame over the size limits.
+pub(crate) fn too_big(err: impl std::fmt::Display) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        format!(
+            "ws message over max_message_size or max_frame_size: {}",
+            err
+        ),
+    )
+}
+
+/// Invalid UTF-8 in a Text message.
+pub(crate) fn is_utf8_error(err: &axum::Error) -> bool {
+    find_ws_error(
+        err,
+        |e| matches!(e, tungstenite::Error::Utf8),
+        |e| matches!(e, axum_tungstenite::Error::Utf8),
+    )
+}
+
+/// A message or frame over the size limits.
+pub(crate) fn is_capacity_error(err: &axum::Error) -> bool {
+    find_ws_error(
+        err,
+        |e| matches!(e, tungstenite::Error::Capacity(_)),
+        |e| matches!(e, axum_tungstenite::Error::Capacity(_)),
+    )
+}
+
+/// Walk the sources of `err` to the tungstenite error it wraps, which is
+/// of axum's tungstenite for upgraded sockets and ours for accepted ones.
+fn find_ws_error(
+    err: &axum::Error,
+    ours: impl Fn(&tungstenite::Error) -> bool,
+    axums: impl Fn(&axum_tungstenite::Error) -> bool,
+) -> bool {
+    let mut source = std::error::Error::source(err);
+    while let Some(e) = source {
+        if let Some(e) = e.downcast_ref::<tungstenite::Error>() {
+            return ours(e);
+        }
+        if let Some(e) = e.downcast_ref::<axum_tungstenite::Error>() {
+            return axums(e);
+        }
+        source = e.source();
+    }
+    false
+}
+
+pub(crate) fn default_max_message_size() -> usize {
+    MAX_MESSAGE_SIZE
+}
+
+pub(crate) fn default_max_frame_size() -> usize {
+    MAX_FRAME_SIZE
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::SocketAddr, time::Duration};
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+    use tokio_tungstenite::tungstenite::handshake::derive_accept_key;
+
+    use crate::{
+        websocket::{
+            ForwardedOption, WebSocketClient, WebSocketClientOption, WebSocketServer,
+            WebSocketServerOption,
+        },
+        TransportClientTrait, TransportServerCallback, TransportServerTrait,
+    };
+
+    use super::*;
+
+    /// Headers whose presence and position may vary between runs, none so far.
+    const VARIANCE_ALLOWLIST: &[&str] = &[];
+
+    /// Key sent in the recorded server handshake, from RFC 6455.
+    const SAMPLE_KEY: &str = "dGhlIHNhbXBsZSBub25jZQ==";
+
+    async fn read_head(stream: &mut TcpStream) -> String {
+        let mut head = vec![];
+        while !head.ends_with(b"\r\n\r\n") {
+            let mut byte = [0u8; 1];
+            if stream.read(&mut byte).await.unwrap() == 0 {
+                break;
+            }
+            head.push(byte[0]);
+        }
+        String::from_utf8(head).unwrap()
+    }
+
+    /// Lines of a transcript, header names lowercased and incidental values masked.
+    fn normalize(transcript: &str) -> Vec<String> {
+        let mut lines = transcript
+            .lines()
+            .map(|line| line.trim_end_matches('\r'))
+            .filter(|line| !line.is_empty() && !line.starts_with('#'));
+        let mut out = vec![lines.next().unwrap_or_default().to_owned()];
+        for line in lines {
+            let (name, value) = line.split_once(':').unwrap_or((line, ""));
+            let name = name.trim().to_ascii_lowercase();
+            if VARIANCE_ALLOWLIST.contains(&name.as_str()) {
+                continue;
+            }
+            let value = match name.as_str() {
+                "host" => "<host>",
+                "sec-websocket-key" => "<key>",
+                "date" => "<date>",
+                _ => value.trim(),
+            };
+            out.push(format!("{}: {}", name, value));
+        }
+        out
+    }
+
+    /// Compare with the checked in fixture, or rewrite it with `KAPIBARA_UPDATE_FIXTURES=1`.
+    fn check_fixture(name: &str, transcript: &str, headers: &[&str]) {
+        let path = format!("{}/fixtures/ws/{}", env!("CARGO_MANIFEST_DIR"), name);
+        let actual = normalize(transcript);
+        if std::env::var_os("KAPIBARA_UPDATE_FIXTURES").is_some() {
+            let text = format!(
+                "# Recorded by websocket::wire tests, update together with a CHANGELOG entry.\n{}\n",
+                actual.join("\n")
+            );
+            std::fs::write(&path, text).unwrap();
+        }
+
+        let fixture = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(
+            actual,
+            normalize(&fixture),
+            "the ws handshake changed, peers of other versions may break. If intended, rerun \
+             with KAPIBARA_UPDATE_FIXTURES=1, update the wire profile and add a CHANGELOG entry"
+        );
+        let names = actual[1..]
+            .iter()
+            .map(|line| line.split(':').next().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(names, headers, "{} and the wire profile disagree", name);
+    }
+
+    #[tokio::test]
+    async fn test_client_handshake_transcript() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (mut s, _) = listener.accept().await.unwrap();
+            let head = read_head(&mut s).await;
+            let key = request_key(&head);
+            let response = format!(
+                "HTTP/1.1 101 Switching Protocols\r\nConnection: Upgrade\r\n\
+                 Upgrade: websocket\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+                derive_accept_key(key.as_bytes())
+            );
+            s.write_all(response.as_bytes()).await.unwrap();
+            head
+        });
+
+        let opt = WebSocketClientOption {
+            addr: "127.0.0.1".into(),
+            port: addr.port(),
+            path: "/kapi".into(),
+            tcp_nodelay: false,
+            duplex_fairness: false,
+            read_buffer_messages: 1,
+            validate_text: false,
+            text_to_bytes: true,
+            max_message_size: MAX_MESSAGE_SIZE,
+            max_frame_size: MAX_FRAME_SIZE,
+            write_chunk_size: None,
+            forwarded: ForwardedOption::default(),
+            headers: vec![],
+            host: None,
+            keepalive_interval: None,
+            keepalive_timeout: Duration::from_secs(30),
+            slow_consumer_policy: None,
+            connect_timeout: None,
+            connect_deadline: None,
+            attempt_delay: None,
+            resolve_on_connect: false,
+            proxy: None,
+            bind_addr: None,
+            bind_device: None,
+        };
+        let cli = WebSocketClient::with_addrs(opt, None, vec![addr]).unwrap();
+        cli.connect().await.unwrap();
+
+        let head = server.await.unwrap();
+        check_fixture("client_upgrade.txt", &head, CLIENT_REQUEST_HEADERS);
+    }
+
+    /// `Sec-WebSocket-Key` of a recorded request.
+    fn request_key(head: &str) -> String {
+        head.lines()
+            .find_map(|line| {
+                let (name, value) = line.split_once(':')?;
+                name.eq_ignore_ascii_case("sec-websocket-key")
+                    .then(|| value.trim().to_owned())
+            })
+            .unwrap()
+    }
+
+    #[derive(Clone)]
+    struct IdleCallback;
+
+    impl TransportServerCallback for IdleCallback {
+        async fn handle<S>(&self, _stream: S, _addr: Option<SocketAddr>)
+        where
+            S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + Sync,
+        {
+        }
+    }
+
+    #[tokio::test]
+    async fn test_server_handshake_transcript() {
+        let opt = WebSocketServerOption {
+            listen: "127.0.0.1:9863".parse().unwrap(),
+            path: "/kapi".into(),
+            paths: vec![],
+            path_match: Default::default(),
+            fallback: Default::default(),
+            tcp_nodelay: false,
+            duplex_fairness: false,
+            read_buffer_messages: 1,
+            validate_text: false,
+            text_to_bytes: true,
+            max_message_size: MAX_MESSAGE_SIZE,
+            max_frame_size: MAX_FRAME_SIZE,
+            write_chunk_size: None,
+            max_bytes: None,
+            rate_limit: None,
+            max_connections: None,
+            idle_reap: None,
+            idle_timeout: None,
+            trust_forwarded_headers: false,
+            keepalive_interval: None,
+            keepalive_timeout: Duration::from_secs(30),
+            slow_consumer_policy: None,
+        };
+        let srv = WebSocketServer::init(opt, None).unwrap();
+        tokio::spawn(async move { srv.serve(IdleCallback).await });
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+
+        let mut s = TcpStream::connect("127.0.0.1:9863").await.unwrap();
+        let request = format!(
+            "GET /kapi HTTP/1.1\r\nHost: 127.0.0.1:9863\r\nConnection: Upgrade\r\n\
+             Upgrade: websocket\r\nSec-WebSocket-Version: 13\r\nSec-WebSocket-Key: {}\r\n\r\n",
+            SAMPLE_KEY
+        );
+        s.write_all(request.as_bytes()).await.unwrap();
+        let head = read_head(&mut s).await;
+
+        check_fixture("server_upgrade.txt", &head, SERVER_RESPONSE_HEADERS);
+    }
+
+    #[test]
+    fn test_option_defaults() {
+        let server: WebSocketServerOption =
+            serde_json::from_str(r#"{"listen": "127.0.0.1:0", "path": "/"}"#).unwrap();
+        let client: WebSocketClientOption =
+            serde_json::from_str(r#"{"addr": "localhost", "port": 80, "path": "/"}"#).unwrap();
+        assert_eq!(server.max_message_size, MAX_MESSAGE_SIZE);
+        assert_eq!(server.max_frame_size, MAX_FRAME_SIZE);
+        assert_eq!(client.max_message_size, MAX_MESSAGE_SIZE);
+        assert_eq!(client.max_frame_size, MAX_FRAME_SIZE);
+
+        let config = config(client.max_message_size, client.max_frame_size);
+        assert_eq!(config.max_message_size, Some(MAX_MESSAGE_SIZE));
+        assert_eq!(config.max_frame_size, Some(MAX_FRAME_SIZE));
+    }
+
+    #[test]
+    fn test_close_codes() {
+        use axum::extract::ws::close_code;
+
+        assert_eq!(close::NORMAL, close_code::NORMAL);
+        assert_eq!(close::AWAY, close_code::AWAY);
+        assert_eq!(close::UNSUPPORTED, close_code::UNSUPPORTED);
+        assert_eq!(close::INVALID, close_code::INVALID);
+        assert_eq!(close::POLICY, close_code::POLICY);
+        assert_eq!(close::TOO_BIG, close_code::SIZE);
+        assert_eq!(close::ERROR, close_code::ERROR);
+        assert!(EXTENSIONS.is_empty());
+        assert_eq!(DATA_MESSAGE, "binary");
+    }
+
+    #[test]
+    fn test_error_kinds() {
+        use tungstenite::error::CapacityError;
+
+        let too_long = CapacityError::MessageTooLong {
+            size: 2,
+            max_size: 1,
+        };
+        let axum_too_long = axum_tungstenite::error::CapacityError::MessageTooLong {
+            size: 2,
+            max_size: 1,
+        };
+        assert!(is_utf8_error(&axum::Error::new(tungstenite::Error::Utf8)));
+        assert!(is_utf8_error(&axum::Error::new(
+            axum_tungstenite::Error::Utf8
+        )));
+        assert!(is_capacity_error(&axum::Error::new(
+            tungstenite::Error::Capacity(too_long)
+        )));
+        assert!(is_capacity_error(&axum::Error::new(
+            axum_tungstenite::Error::Capacity(axum_too_long)
+        )));
+
+        // told apart by type, not by message
+        let utf8 = std::io::Error::other(tungstenite::Error::Utf8.to_string());
+        assert!(!is_utf8_error(&axum::Error::new(utf8)));
+        let capacity = std::io::Error::other(tungstenite::Error::Capacity(too_long).to_string());
+        assert!(!is_capacity_error(&axum::Error::new(capacity)));
+        assert!(!is_capacity_error(&axum::Error::new(
+            tungstenite::Error::Utf8
+        )));
+    }
+}
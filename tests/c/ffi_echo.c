@@ -0,0 +1,78 @@
+/* Echo round trip through the C bindings, run by ffi::tests::test_c_program
+ * with the port of an echo server and of a closed port. */
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+
+#include "kapibara.h"
+
+#define CHECK(cond)                                                          \
+    do {                                                                     \
+        if (!(cond)) {                                                       \
+            char msg[256];                                                   \
+            int32_t code = kapibara_last_error(msg, sizeof msg);             \
+            fprintf(stderr, "%s:%d: %s failed, last error %d (%s)\n",        \
+                    __FILE__, __LINE__, #cond, code, msg);                   \
+            return 1;                                                        \
+        }                                                                    \
+    } while (0)
+
+static int client_for(const char *port, kapibara_client_t *client) {
+    char config[256];
+    snprintf(config, sizeof config,
+             "{\"opt\": {\"tcp\": {\"addr\": \"127.0.0.1\", \"port\": %s}}}", port);
+    return kapibara_client_new(config, client);
+}
+
+int main(int argc, char **argv) {
+    if (argc != 3) {
+        fprintf(stderr, "usage: %s <echo port> <closed port>\n", argv[0]);
+        return 2;
+    }
+
+    kapibara_client_t client;
+    kapibara_stream_t stream;
+    CHECK(client_for(argv[1], &client) == KAPIBARA_OK);
+    CHECK(kapibara_client_connect(client, &stream) == KAPIBARA_OK);
+
+    const char *msg = "hello kapibara";
+    size_t len = strlen(msg);
+    size_t sent = 0;
+    while (sent < len) {
+        ssize_t n = kapibara_stream_write(stream, (const uint8_t *)msg + sent, len - sent, 5000);
+        CHECK(n > 0);
+        sent += (size_t)n;
+    }
+    char buf[64];
+    size_t received = 0;
+    while (received < len) {
+        ssize_t n = kapibara_stream_read(stream, (uint8_t *)buf + received,
+                                         sizeof buf - received, 5000);
+        CHECK(n > 0);
+        received += (size_t)n;
+    }
+    CHECK(received == len && memcmp(buf, msg, len) == 0);
+
+    /* nothing more is coming */
+    CHECK(kapibara_stream_read(stream, (uint8_t *)buf, sizeof buf, 50) == KAPIBARA_ERR_TIMEOUT);
+    CHECK(kapibara_stream_read(stream, NULL, 1, 0) == KAPIBARA_ERR_INVALID_ARGUMENT);
+
+    /* a closed handle stays invalid */
+    CHECK(kapibara_stream_close(stream) == KAPIBARA_OK);
+    CHECK(kapibara_stream_close(stream) == KAPIBARA_ERR_INVALID_HANDLE);
+    CHECK(kapibara_stream_read(stream, (uint8_t *)buf, sizeof buf, 0) ==
+          KAPIBARA_ERR_INVALID_HANDLE);
+    CHECK(kapibara_client_free(client) == KAPIBARA_OK);
+    CHECK(kapibara_client_connect(client, &stream) == KAPIBARA_ERR_INVALID_HANDLE);
+
+    /* transport errors keep their code */
+    CHECK(client_for(argv[2], &client) == KAPIBARA_OK);
+    CHECK(kapibara_client_connect(client, &stream) == KAPIBARA_ERR_CONNECT_REFUSED);
+    char err[256];
+    CHECK(kapibara_last_error(err, sizeof err) == KAPIBARA_ERR_CONNECT_REFUSED && err[0] != 0);
+    CHECK(kapibara_client_free(client) == KAPIBARA_OK);
+    CHECK(kapibara_client_new("{", &client) == KAPIBARA_ERR_OPTION_INVALID);
+
+    printf("ffi echo ok\n");
+    return 0;
+}
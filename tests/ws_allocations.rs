@@ -0,0 +1,193 @@
+//! Allocation counts of the websocket write paths, in a binary of its own as
+//! the counting global allocator would otherwise sit under every unit test.
+
+use std::{pin::Pin, time::Duration};
+
+use bytes::Bytes;
+use kapibara_transport::{
+    websocket::{
+        wire, ForwardedOption, WebSocketClient, WebSocketClientOption, WebSocketServer,
+        WebSocketServerOption,
+    },
+    Resolver, TransportClientTrait, TransportServerCallback, TransportServerTrait,
+};
+use tokio::io::{AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Counts allocations made on the current thread while enabled.
+struct CountingAlloc;
+
+thread_local! {
+    static ALLOC_COUNT: std::cell::Cell<Option<usize>> = const { std::cell::Cell::new(None) };
+}
+
+unsafe impl std::alloc::GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: std::alloc::Layout) -> *mut u8 {
+        let _ = ALLOC_COUNT.try_with(|c| c.set(c.get().map(|n| n + 1)));
+        std::alloc::System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: std::alloc::Layout) {
+        std::alloc::System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+fn count_allocs<T>(f: impl FnOnce() -> T) -> (T, usize) {
+    ALLOC_COUNT.with(|c| c.set(Some(0)));
+    let out = f();
+    let n = ALLOC_COUNT.with(|c| c.replace(None)).unwrap();
+    (out, n)
+}
+
+const ALLOC_MESSAGES: usize = 256;
+const ALLOC_SIZES: [usize; 4] = [1, 100, 4096, 70000];
+
+fn alloc_total() -> usize {
+    2 * (0..ALLOC_MESSAGES)
+        .map(|i| ALLOC_SIZES[i % ALLOC_SIZES.len()])
+        .sum::<usize>()
+}
+
+#[derive(Debug, Clone)]
+struct ChecksumCallback;
+
+impl TransportServerCallback for ChecksumCallback {
+    async fn handle<S>(&self, mut stream: S, _addr: Option<std::net::SocketAddr>)
+    where
+        S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + Sync,
+    {
+        let mut buf = vec![0u8; alloc_total()];
+        stream.read_exact(&mut buf).await.unwrap();
+        let sum = buf.iter().map(|b| *b as u64).sum::<u64>();
+        stream.write_all(&sum.to_be_bytes()).await.unwrap();
+        stream.flush().await.unwrap();
+    }
+}
+
+/// Allocations per message, measured on this machine:
+///
+/// | path                    | before | after |
+/// |-------------------------|--------|-------|
+/// | poll_write(&[u8])       | 1      | 1     |
+/// | poll_write_bytes(Bytes) | -      | ~0    |
+/// | Text -> Bytes (len=cap) | 0      | 0     |
+/// | Text -> Bytes (len<cap) | 1      | 1     |
+///
+/// The occasional write side allocation is the sink growing its frame
+/// buffer. The read side one is the small shared header bytes needs to
+/// keep spare capacity, the payload itself is never copied.
+#[tokio::test]
+async fn test_ws_write_allocations() {
+    tokio::spawn(async move {
+        let opt = WebSocketServerOption {
+            listen: ([127, 0, 0, 1], 9886).into(),
+            path: "/alloc".into(),
+            tcp_nodelay: true,
+            duplex_fairness: false,
+            read_buffer_messages: 1,
+            validate_text: false,
+            text_to_bytes: true,
+            max_message_size: wire::MAX_MESSAGE_SIZE,
+            max_frame_size: wire::MAX_FRAME_SIZE,
+            max_bytes: None,
+            max_connections: None,
+            idle_reap: None,
+            trust_forwarded_headers: false,
+            keepalive_interval: None,
+            keepalive_timeout: Duration::from_secs(30),
+            slow_consumer_policy: None,
+            fallback: Default::default(),
+            path_match: Default::default(),
+            paths: vec![],
+            idle_timeout: None,
+            write_chunk_size: None,
+            rate_limit: None,
+        };
+
+        let srv = WebSocketServer::init(opt, None).unwrap();
+        srv.serve(ChecksumCallback).await.unwrap();
+    });
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let opt = WebSocketClientOption {
+        addr: "127.0.0.1".into(),
+        port: 9886,
+        path: "/alloc".into(),
+        tcp_nodelay: true,
+        duplex_fairness: false,
+        read_buffer_messages: 1,
+        validate_text: false,
+        text_to_bytes: true,
+        max_message_size: wire::MAX_MESSAGE_SIZE,
+        max_frame_size: wire::MAX_FRAME_SIZE,
+        forwarded: ForwardedOption::default(),
+        keepalive_interval: None,
+        keepalive_timeout: Duration::from_secs(30),
+        slow_consumer_policy: None,
+        connect_deadline: None,
+        connect_timeout: None,
+        attempt_delay: None,
+        headers: vec![],
+        host: None,
+        resolve_on_connect: false,
+        proxy: None,
+        write_chunk_size: None,
+        bind_addr: None,
+        bind_device: None,
+    };
+    let cli = WebSocketClient::init(opt, None, &Resolver::default()).unwrap();
+    let mut ws_stream = cli.connect().await.unwrap();
+
+    let payloads = (0..ALLOC_MESSAGES)
+        .map(|i| {
+            (0..ALLOC_SIZES[i % ALLOC_SIZES.len()])
+                .map(|j| (i + j) as u8)
+                .collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>();
+    let expect = payloads.iter().flatten().map(|b| *b as u64).sum::<u64>() * 2;
+
+    let (mut slice_allocs, mut bytes_allocs) = (0, 0);
+    for payload in payloads.iter() {
+        std::future::poll_fn(|cx| {
+            let (res, n) =
+                count_allocs(|| AsyncWrite::poll_write(Pin::new(&mut ws_stream), cx, payload));
+            slice_allocs += n;
+            res
+        })
+        .await
+        .unwrap();
+
+        let mut data = Bytes::from(payload.clone());
+        std::future::poll_fn(|cx| {
+            let (res, n) = count_allocs(|| ws_stream.poll_write_bytes(cx, &mut data));
+            bytes_allocs += n;
+            res
+        })
+        .await
+        .unwrap();
+    }
+    ws_stream.flush().await.unwrap();
+
+    let mut sum = [0u8; 8];
+    ws_stream.read_exact(&mut sum).await.unwrap();
+    assert_eq!(u64::from_be_bytes(sum), expect);
+
+    println!(
+        "allocations per message: poll_write {:.2}, poll_write_bytes {:.2}",
+        slice_allocs as f64 / ALLOC_MESSAGES as f64,
+        bytes_allocs as f64 / ALLOC_MESSAGES as f64
+    );
+    assert!(slice_allocs >= ALLOC_MESSAGES);
+    assert!(bytes_allocs < slice_allocs);
+
+    let text = String::from("exact");
+    let (_, n) = count_allocs(|| Bytes::from(text));
+    assert_eq!(n, 0);
+    let mut text = String::with_capacity(64);
+    text.push_str("spare");
+    let (_, n) = count_allocs(|| Bytes::from(text));
+    assert!(n <= 1);
+}